@@ -0,0 +1,46 @@
+//! Embeds the loop and prints a column whenever its value changes, e.g.:
+//!
+//!     OGC_CONFIG=defaults.toml cargo run --example watch -- owa_temperature
+
+use std::env;
+use std::sync::mpsc;
+use std::thread;
+
+use open_green_compute::{clock, config, snapshot};
+
+fn main() {
+    let column = match env::args().nth(1) {
+        Some(column) => column,
+        None => {
+            println!("usage: watch <column>");
+            return;
+        }
+    };
+    let cfg_file = env::var("OGC_CONFIG").unwrap_or_else(|_| String::from("defaults.toml"));
+
+    // the loop runs in its own thread; the collector is shared with it.
+    let collector = snapshot::Collector::new();
+    let handle = collector.clone();
+    let (_shutdown, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let cfg = config::load_config(&cfg_file);
+        open_green_compute::run_loop(&cfg, &clock::SystemClock {}, rx, &handle);
+    });
+
+    let mut subscription = collector.subscribe();
+    let mut last: Option<f64> = None;
+    loop {
+        let snapshot = subscription.changed();
+        match snapshot.get(&column) {
+            Some(value) if last != Some(value) => {
+                println!("{}: {}", column, value);
+                last = Some(value);
+            }
+            Some(_) => {}
+            None => {
+                println!("no column {}; have: {}", column, snapshot.names.join(", "));
+                return;
+            }
+        }
+    }
+}
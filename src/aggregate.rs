@@ -0,0 +1,240 @@
+/// Statistics over the samples of a column in a window.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Stat {
+    Min,
+    Max,
+    Avg,
+}
+
+impl Stat {
+    pub(crate) fn parse(name: &str) -> Stat {
+        match name {
+            "min" => Stat::Min,
+            "max" => Stat::Max,
+            "avg" => Stat::Avg,
+            other => panic!(
+                "stats must be one of 'min', 'max' or 'avg'; got: {}.",
+                other
+            ),
+        }
+    }
+
+    fn suffix(&self) -> &'static str {
+        match self {
+            Stat::Min => "min",
+            Stat::Max => "max",
+            Stat::Avg => "avg",
+        }
+    }
+}
+
+/// The samples of a column so far; failed ones (NaN) are left out.
+#[derive(Clone, Copy)]
+struct Acc {
+    min: f64,
+    max: f64,
+    sum: f64,
+    count: usize,
+}
+
+impl Acc {
+    const EMPTY: Acc = Acc {
+        min: f64::INFINITY,
+        max: f64::NEG_INFINITY,
+        sum: 0.0,
+        count: 0,
+    };
+
+    fn add(&mut self, value: f64) {
+        if value.is_nan() {
+            return;
+        }
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+        self.sum += value;
+        self.count += 1;
+    }
+
+    fn get(&self, stat: Stat) -> f64 {
+        if self.count == 0 {
+            return f64::NAN;
+        }
+        match stat {
+            Stat::Min => self.min,
+            Stat::Max => self.max,
+            Stat::Avg => self.sum / self.count as f64,
+        }
+    }
+}
+
+/// Turns the rows of the loop into one row per window.
+///
+/// Windows are aligned to multiples of their length; a row is timestamped with the start of its window and emitted once
+/// a sample of a later window comes in. Each aggregated column is replaced by one column per stat, e.g. foo_power_min;
+/// all other columns keep the last value of the window.
+pub(crate) struct Aggregator {
+    window: f64,
+    stats: Vec<Stat>,
+    aggregated: Vec<bool>,
+    start: Option<f64>,
+    accs: Vec<Acc>,
+    last: Vec<f64>,
+}
+
+impl Aggregator {
+    /// Aggregates the columns flagged in aggregated; the first column is the timestamp.
+    pub(crate) fn new(window: f64, stats: Vec<Stat>, aggregated: Vec<bool>) -> Aggregator {
+        Aggregator {
+            window,
+            stats,
+            accs: vec![Acc::EMPTY; aggregated.len()],
+            aggregated,
+            start: None,
+            last: Vec::new(),
+        }
+    }
+
+    /// The names of the columns of the rows it emits.
+    pub(crate) fn headers(&self, headers: &[String]) -> Vec<String> {
+        let mut res = Vec::new();
+        for (i, name) in headers.iter().enumerate() {
+            if self.aggregated[i] {
+                res.extend(
+                    self.stats
+                        .iter()
+                        .map(|s| format!("{}_{}", name, s.suffix())),
+                );
+            } else {
+                res.push(name.clone());
+            }
+        }
+        res
+    }
+
+    /// Repeats what describes an aggregated column (unit, privacy, ...) for each of its stats.
+    pub(crate) fn expand<T: Clone>(&self, items: &[T]) -> Vec<T> {
+        let mut res = Vec::new();
+        for (i, item) in items.iter().enumerate() {
+            let times = if self.aggregated[i] {
+                self.stats.len()
+            } else {
+                1
+            };
+            res.extend(std::iter::repeat_n(item.clone(), times));
+        }
+        res
+    }
+
+    /// Adds a row of the loop; returns the row of the previous window if this one starts a new one.
+    pub(crate) fn add(&mut self, row: &[f64]) -> Option<Vec<f64>> {
+        let start = (row[0] / self.window).floor() * self.window;
+        let res = match self.start {
+            Some(current) if current != start => self.flush(),
+            _ => None,
+        };
+        self.start = Some(start);
+        for (acc, value) in self.accs.iter_mut().zip(row) {
+            acc.add(*value);
+        }
+        self.last = row.to_vec();
+        res
+    }
+
+    /// The row of the window so far, if it has any samples; e.g. once the loop stops.
+    pub(crate) fn flush(&mut self) -> Option<Vec<f64>> {
+        let start = self.start.take()?;
+        let mut res = vec![start];
+        for (i, value) in self.last.iter().enumerate().skip(1) {
+            if self.aggregated[i] {
+                res.extend(self.stats.iter().map(|s| self.accs[i].get(*s)));
+            } else {
+                res.push(*value);
+            }
+        }
+        self.accs = vec![Acc::EMPTY; self.aggregated.len()];
+        Some(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|n| n.to_string()).collect()
+    }
+
+    fn aggregator() -> Aggregator {
+        Aggregator::new(
+            60.0,
+            vec![Stat::Min, Stat::Max, Stat::Avg],
+            vec![false, true, false],
+        )
+    }
+
+    // Tests for success.
+
+    #[test]
+    fn test_add_for_success() {
+        let mut aggregator = aggregator();
+        assert_eq!(aggregator.add(&[1020.0, 2.0, 21.0]), None);
+        assert_eq!(aggregator.add(&[1030.0, 6.0, 21.0]), None);
+        assert_eq!(
+            aggregator.add(&[1080.0, 1.0, 22.0]),
+            Some(vec![1020.0, 2.0, 6.0, 4.0, 21.0])
+        );
+        assert_eq!(aggregator.flush(), Some(vec![1080.0, 1.0, 1.0, 1.0, 22.0]));
+        assert_eq!(aggregator.flush(), None);
+    }
+
+    // Tests for failure.
+
+    #[test]
+    #[should_panic(expected = "stats must be one of 'min', 'max' or 'avg'; got: median.")]
+    fn test_parse_for_failure() {
+        Stat::parse("median");
+    }
+
+    #[test]
+    fn test_add_for_failure() {
+        // failed samples are left out; a window of failures is a failure.
+        let mut aggregator = aggregator();
+        aggregator.add(&[1020.0, f64::NAN, 21.0]);
+        aggregator.add(&[1030.0, 4.0, 21.0]);
+        let row = aggregator.add(&[1080.0, f64::NAN, f64::NAN]).unwrap();
+        assert_eq!(row, vec![1020.0, 4.0, 4.0, 4.0, 21.0]);
+        let row = aggregator.flush().unwrap();
+        assert!(row[1..].iter().all(|v| v.is_nan()));
+    }
+
+    // Tests for sanity.
+
+    #[test]
+    fn test_headers_for_sanity() {
+        let aggregator = aggregator();
+        let headers = names(&["timestamp", "foo_power", "bar_temperature"]);
+        assert_eq!(
+            aggregator.headers(&headers),
+            vec![
+                "timestamp",
+                "foo_power_min",
+                "foo_power_max",
+                "foo_power_avg",
+                "bar_temperature"
+            ]
+        );
+        assert_eq!(
+            aggregator.expand(&names(&["s", "W", "°C"])),
+            vec!["s", "W", "W", "W", "°C"]
+        );
+    }
+
+    #[test]
+    fn test_gap_for_sanity() {
+        // windows w/o samples are skipped.
+        let mut aggregator = Aggregator::new(60.0, vec![Stat::Max], vec![false, true]);
+        aggregator.add(&[1020.0, 2.0]);
+        assert_eq!(aggregator.add(&[1500.0, 3.0]), Some(vec![1020.0, 2.0]));
+        assert_eq!(aggregator.flush(), Some(vec![1500.0, 3.0]));
+    }
+}
@@ -0,0 +1,580 @@
+use std::error::Error;
+use std::time;
+
+use serde::Serialize;
+
+use crate::common;
+use crate::journal;
+use crate::tz;
+
+/// Something that can deliver a notification.
+pub(crate) trait Notifier {
+    fn notify(&self, title: &str, message: &str) -> Result<(), Box<dyn Error>>;
+}
+
+fn client() -> reqwest::blocking::Client {
+    // never let a slow notification backend stall the loop for long.
+    reqwest::blocking::ClientBuilder::new()
+        .timeout(time::Duration::from_secs(10))
+        .build()
+        .unwrap()
+}
+
+/// Sends notifications to a ntfy topic.
+pub struct NtfyNotifier {
+    url: String,
+    priority: Option<String>,
+    tags: Vec<String>,
+    client: reqwest::blocking::Client,
+}
+
+impl NtfyNotifier {
+    pub fn new(url: String, priority: Option<String>, tags: Vec<String>) -> NtfyNotifier {
+        NtfyNotifier {
+            url,
+            priority,
+            tags,
+            client: client(),
+        }
+    }
+}
+
+impl Notifier for NtfyNotifier {
+    fn notify(&self, title: &str, message: &str) -> Result<(), Box<dyn Error>> {
+        let mut req = self
+            .client
+            .post(&self.url)
+            .header("Title", title)
+            .body(message.to_string());
+        if let Some(priority) = &self.priority {
+            req = req.header("Priority", priority);
+        }
+        if !self.tags.is_empty() {
+            req = req.header("Tags", self.tags.join(","));
+        }
+        let res = req.send()?;
+        if res.status() != 200 {
+            return Err(Box::from(format!(
+                "Status code was not 200; but: {}.",
+                res.status()
+            )));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize)]
+struct TelegramMessage<'a> {
+    chat_id: &'a str,
+    text: String,
+}
+
+/// Sends notifications through a Telegram bot.
+pub struct TelegramNotifier {
+    url: String,
+    token: String,
+    chat_id: String,
+    client: reqwest::blocking::Client,
+}
+
+impl TelegramNotifier {
+    pub fn new(url: String, token: String, chat_id: String) -> TelegramNotifier {
+        TelegramNotifier {
+            url,
+            token,
+            chat_id,
+            client: client(),
+        }
+    }
+}
+
+impl Notifier for TelegramNotifier {
+    fn notify(&self, title: &str, message: &str) -> Result<(), Box<dyn Error>> {
+        let url = format!("{}/bot{}/sendMessage", self.url, self.token);
+        let msg = TelegramMessage {
+            chat_id: &self.chat_id,
+            text: format!("{}\n{}", title, message),
+        };
+        let res = self.client.post(url).json(&msg).send()?;
+        if res.status() != 200 {
+            return Err(Box::from(format!(
+                "Status code was not 200; but: {}.",
+                res.status()
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Sends notifications by mail.
+#[cfg(feature = "email")]
+pub struct EmailNotifier<T: lettre::Transport> {
+    from: String,
+    to: String,
+    transport: T,
+}
+
+#[cfg(feature = "email")]
+impl<T: lettre::Transport> EmailNotifier<T> {
+    pub fn new(from: String, to: String, transport: T) -> EmailNotifier<T> {
+        EmailNotifier {
+            from,
+            to,
+            transport,
+        }
+    }
+}
+
+#[cfg(feature = "email")]
+impl<T: lettre::Transport> Notifier for EmailNotifier<T>
+where
+    T::Error: Error + 'static,
+{
+    fn notify(&self, title: &str, message: &str) -> Result<(), Box<dyn Error>> {
+        let email = lettre::Message::builder()
+            .from(self.from.parse()?)
+            .to(self.to.parse()?)
+            .subject(title)
+            .body(message.to_string())?;
+        self.transport.send(&email)?;
+        Ok(())
+    }
+}
+
+/// Creates a notifier from its config table.
+pub(crate) fn create_notifier(cfg: &toml::Value) -> Box<dyn Notifier> {
+    let get = |key: &str| {
+        cfg.get(key)
+            .and_then(|v| v.as_str())
+            .unwrap_or_else(|| panic!("a notifier requires the field {} to be set.", key))
+            .to_string()
+    };
+    match get("type").as_str() {
+        "ntfy" => Box::new(NtfyNotifier::new(
+            get("url"),
+            cfg.get("priority")
+                .and_then(|v| v.as_str())
+                .map(|v| v.to_string()),
+            cfg.get("tags")
+                .and_then(|v| v.as_array())
+                .unwrap_or(&Vec::new())
+                .iter()
+                .filter_map(|v| v.as_str())
+                .map(|v| v.to_string())
+                .collect(),
+        )),
+        "telegram" => Box::new(TelegramNotifier::new(
+            cfg.get("url")
+                .and_then(|v| v.as_str())
+                .unwrap_or("https://api.telegram.org")
+                .to_string(),
+            get("token"),
+            get("chat_id"),
+        )),
+        "journald" => Box::new(journal::JournalNotifier::new(journal::Journal::new(
+            cfg.get("socket")
+                .and_then(|v| v.as_str())
+                .unwrap_or(journal::SOCKET)
+                .to_string(),
+        ))),
+        #[cfg(feature = "email")]
+        "email" => {
+            let transport = lettre::SmtpTransport::relay(&get("host"))
+                .expect("could not set up SMTP transport.")
+                .credentials(lettre::transport::smtp::authentication::Credentials::new(
+                    get("user"),
+                    get("password"),
+                ))
+                .build();
+            Box::new(EmailNotifier::new(get("from"), get("to"), transport))
+        }
+        other => panic!("unknown notifier type: {}.", other),
+    }
+}
+
+/// A condition on a column that triggers a notification.
+pub(crate) struct Rule {
+    name: String,
+    column: String,
+    operator: String,
+    threshold: f64,
+    template: String,
+    notifier: usize,
+    min_interval: f64,
+    firing: bool,
+    last_sent: Option<f64>,
+}
+
+impl Rule {
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn new(
+        name: String,
+        column: String,
+        operator: String,
+        threshold: f64,
+        template: String,
+        notifier: usize,
+        min_interval: f64,
+    ) -> Rule {
+        if !["<", "<=", ">", ">=", "==", "!="].contains(&operator.as_str()) {
+            panic!("unknown operator in alert rule {}: {}.", name, operator);
+        }
+        Rule {
+            name,
+            column,
+            operator,
+            threshold,
+            template,
+            notifier,
+            min_interval,
+            firing: false,
+            last_sent: None,
+        }
+    }
+
+    fn holds(&self, value: f64) -> bool {
+        match self.operator.as_str() {
+            "<" => value < self.threshold,
+            "<=" => value <= self.threshold,
+            ">" => value > self.threshold,
+            ">=" => value >= self.threshold,
+            "==" => value == self.threshold,
+            _ => !value.is_nan() && value != self.threshold,
+        }
+    }
+
+    fn render(&self, value: f64) -> String {
+        self.template
+            .replace("{rule}", &self.name)
+            .replace("{column}", &self.column)
+            .replace("{value}", &value.to_string())
+            .replace("{threshold}", &self.threshold.to_string())
+    }
+}
+
+/// Evaluates alert rules each iteration and notifies when they start to hold.
+pub struct AlertsComponent {
+    notifiers: Vec<Box<dyn Notifier>>,
+    rules: Vec<Rule>,
+    summary_notifier: Option<usize>,
+    zone: tz::Zone,
+    active_hours: Option<tz::ActiveHours>,
+    day: i64,
+    fired_today: usize,
+}
+
+impl AlertsComponent {
+    pub(crate) fn new(
+        notifiers: Vec<Box<dyn Notifier>>,
+        rules: Vec<Rule>,
+        summary_notifier: Option<usize>,
+        zone: tz::Zone,
+        active_hours: Option<tz::ActiveHours>,
+    ) -> AlertsComponent {
+        AlertsComponent {
+            notifiers,
+            rules,
+            summary_notifier,
+            zone,
+            active_hours,
+            day: -1,
+            fired_today: 0,
+        }
+    }
+
+    fn send(&self, notifier: usize, title: &str, message: &str) {
+        if let Err(err) = self.notifiers[notifier].notify(title, message) {
+            eprintln!("Could not send notification '{}': {}", title, err);
+        }
+    }
+}
+
+impl common::Component for AlertsComponent {
+    fn get_names(&self) -> Vec<String> {
+        Vec::new()
+    }
+
+    fn update(&mut self, names: &[String], values: &[f64]) -> Vec<f64> {
+        let now = values[0];
+
+        // daily summary on day change.
+        let day = self.zone.day(now);
+        if day != self.day {
+            if let (Some(notifier), true) = (self.summary_notifier, self.day >= 0) {
+                let msg = format!("{} alerts fired today.", self.fired_today);
+                self.send(notifier, "Daily alert summary", &msg);
+            }
+            self.day = day;
+            self.fired_today = 0;
+        }
+
+        let mut to_send: Vec<(usize, String, String)> = Vec::new();
+        for rule in &mut self.rules {
+            let value = match names.iter().position(|n| n == &rule.column) {
+                Some(i) => values[i],
+                None => continue,
+            };
+            let holds = rule.holds(value);
+            if holds && !rule.firing {
+                let limited = rule
+                    .last_sent
+                    .map(|t| now - t < rule.min_interval)
+                    .unwrap_or(false);
+                if limited {
+                    eprintln!("Alert {} rate limited.", rule.name);
+                } else {
+                    rule.last_sent = Some(now);
+                    to_send.push((rule.notifier, rule.name.clone(), rule.render(value)));
+                }
+                self.fired_today += 1;
+            }
+            rule.firing = holds;
+        }
+        let active = self
+            .active_hours
+            .map(|hours| hours.contains(&self.zone, now))
+            .unwrap_or(true);
+        for (notifier, title, message) in to_send {
+            if active {
+                self.send(notifier, &title, &message);
+            } else {
+                eprintln!("Alert {} outside of active hours.", title);
+            }
+        }
+        Vec::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::common::Component;
+
+    /// The title and message of each notification sent.
+    type Sent = Rc<RefCell<Vec<(String, String)>>>;
+
+    /// Remembers all notifications.
+    struct DummyNotifier {
+        sent: Sent,
+    }
+
+    impl Notifier for DummyNotifier {
+        fn notify(&self, title: &str, message: &str) -> Result<(), Box<dyn Error>> {
+            self.sent
+                .borrow_mut()
+                .push((title.to_string(), message.to_string()));
+            Ok(())
+        }
+    }
+
+    fn setup(min_interval: f64, summary: bool) -> (AlertsComponent, Sent) {
+        let sent = Rc::new(RefCell::new(Vec::new()));
+        let notifier = DummyNotifier { sent: sent.clone() };
+        let rule = Rule::new(
+            "battery low".to_string(),
+            "soc".to_string(),
+            "<".to_string(),
+            10.0,
+            "{rule}: {column} is {value}".to_string(),
+            0,
+            min_interval,
+        );
+        let summary = if summary { Some(0) } else { None };
+        (
+            AlertsComponent::new(
+                vec![Box::new(notifier)],
+                vec![rule],
+                summary,
+                tz::Zone::Utc,
+                None,
+            ),
+            sent,
+        )
+    }
+
+    // Tests for success.
+
+    #[test]
+    fn test_ntfy_notify_for_success() {
+        let mut server = mockito::Server::new();
+        let mock = server
+            .mock("POST", "/alerts")
+            .match_header("Title", "battery low")
+            .match_header("Priority", "high")
+            .match_header("Tags", "warning,battery")
+            .match_body("soc is 5")
+            .create();
+        let notifier = NtfyNotifier::new(
+            server.url() + "/alerts",
+            Some("high".to_string()),
+            vec!["warning".to_string(), "battery".to_string()],
+        );
+        notifier.notify("battery low", "soc is 5").unwrap();
+        mock.assert();
+    }
+
+    #[test]
+    fn test_telegram_notify_for_success() {
+        let mut server = mockito::Server::new();
+        let mock = server
+            .mock("POST", "/bot123:abc/sendMessage")
+            .match_body(mockito::Matcher::Json(serde_json::json!({
+                "chat_id": "42",
+                "text": "battery low\nsoc is 5"
+            })))
+            .with_body("{\"ok\": true}")
+            .create();
+        let notifier = TelegramNotifier::new(server.url(), "123:abc".to_string(), "42".to_string());
+        notifier.notify("battery low", "soc is 5").unwrap();
+        mock.assert();
+    }
+
+    #[cfg(feature = "email")]
+    #[test]
+    fn test_email_notify_for_success() {
+        let notifier = EmailNotifier::new(
+            "ogc@example.com".to_string(),
+            "me@example.com".to_string(),
+            lettre::transport::stub::StubTransport::new_ok(),
+        );
+        notifier.notify("battery low", "soc is 5").unwrap();
+    }
+
+    // Tests for failure.
+
+    #[test]
+    fn test_notify_for_failure() {
+        let mut server = mockito::Server::new();
+        server.mock("POST", "/alerts").with_status(500).create();
+        let notifier = NtfyNotifier::new(server.url() + "/alerts", None, Vec::new());
+        assert!(notifier.notify("foo", "bar").is_err());
+
+        server
+            .mock("POST", "/botabc/sendMessage")
+            .with_status(401)
+            .create();
+        let notifier = TelegramNotifier::new(server.url(), "abc".to_string(), "1".to_string());
+        assert!(notifier.notify("foo", "bar").is_err());
+    }
+
+    #[cfg(feature = "email")]
+    #[test]
+    fn test_email_notify_for_failure() {
+        let notifier = EmailNotifier::new(
+            "ogc@example.com".to_string(),
+            "me@example.com".to_string(),
+            lettre::transport::stub::StubTransport::new_error(),
+        );
+        assert!(notifier.notify("battery low", "soc is 5").is_err());
+    }
+
+    #[test]
+    fn test_update_for_failure() {
+        // failing notifier must not affect the loop.
+        let mut server = mockito::Server::new();
+        server.mock("POST", "/alerts").with_status(500).create();
+        let notifier = NtfyNotifier::new(server.url() + "/alerts", None, Vec::new());
+        let rule = Rule::new(
+            "hot".to_string(),
+            "temp".to_string(),
+            ">".to_string(),
+            30.0,
+            "{value}".to_string(),
+            0,
+            0.0,
+        );
+        let mut comp = AlertsComponent::new(
+            vec![Box::new(notifier)],
+            vec![rule],
+            None,
+            tz::Zone::Utc,
+            None,
+        );
+        let res = comp.update(&["timestamp".to_string(), "temp".to_string()], &[0.0, 35.0]);
+        assert_eq!(res, Vec::<f64>::new());
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_rule_new_for_failure() {
+        Rule::new(
+            "foo".to_string(),
+            "bar".to_string(),
+            "=>".to_string(),
+            1.0,
+            "".to_string(),
+            0,
+            0.0,
+        );
+    }
+
+    // Tests for sanity.
+
+    #[test]
+    fn test_update_for_sanity() {
+        let (mut comp, sent) = setup(0.0, false);
+        let names = vec!["timestamp".to_string(), "soc".to_string()];
+        comp.update(&names, &[0.0, 50.0]);
+        comp.update(&names, &[60.0, 5.0]);
+        // only fires on the transition.
+        comp.update(&names, &[120.0, 4.0]);
+        comp.update(&names, &[180.0, f64::NAN]);
+        assert_eq!(
+            *sent.borrow(),
+            vec![(
+                "battery low".to_string(),
+                "battery low: soc is 5".to_string()
+            )]
+        );
+    }
+
+    #[test]
+    fn test_rate_limit_for_sanity() {
+        let (mut comp, sent) = setup(3600.0, false);
+        let names = vec!["timestamp".to_string(), "soc".to_string()];
+        comp.update(&names, &[0.0, 5.0]);
+        comp.update(&names, &[60.0, 50.0]);
+        comp.update(&names, &[120.0, 5.0]);
+        assert_eq!(sent.borrow().len(), 1);
+        comp.update(&names, &[3700.0, 50.0]);
+        comp.update(&names, &[3760.0, 5.0]);
+        assert_eq!(sent.borrow().len(), 2);
+    }
+
+    #[test]
+    fn test_daily_summary_for_sanity() {
+        let (mut comp, sent) = setup(3600.0, true);
+        let names = vec!["timestamp".to_string(), "soc".to_string()];
+        comp.update(&names, &[0.0, 5.0]);
+        comp.update(&names, &[60.0, 50.0]);
+        comp.update(&names, &[120.0, 5.0]);
+        comp.update(&names, &[180.0, 50.0]);
+        comp.update(&names, &[240.0, 5.0]);
+        comp.update(&names, &[86400.0, 50.0]);
+        let sent = sent.borrow();
+        assert_eq!(sent.len(), 2);
+        assert_eq!(
+            sent[1],
+            (
+                "Daily alert summary".to_string(),
+                "3 alerts fired today.".to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn test_active_hours_for_sanity() {
+        let (mut comp, sent) = setup(0.0, false);
+        comp.zone = tz::Zone::parse("Europe/Berlin");
+        comp.active_hours = Some(tz::ActiveHours::parse("07:00-22:00"));
+        let names = vec!["timestamp".to_string(), "soc".to_string()];
+        // 2023-11-14 06:30 UTC is 07:30 in Berlin; 21:30 UTC is 22:30.
+        comp.update(&names, &[1699943400.0, 5.0]);
+        comp.update(&names, &[1699943460.0, 50.0]);
+        comp.update(&names, &[1699997400.0, 5.0]);
+        assert_eq!(sent.borrow().len(), 1);
+    }
+}
@@ -0,0 +1,324 @@
+use std::fs;
+use std::path;
+
+use crate::common;
+use crate::common::{ConfigError, Reading, SensorError};
+
+/// Where the kernel exposes the graphics cards.
+const DRM_DIR: &str = "/sys/class/drm";
+
+/// A Radeon card driven by amdgpu.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Card {
+    /// The name of the card, e.g. card0.
+    pub(crate) name: String,
+    /// Its PCI address, e.g. 0000:03:00.0.
+    pub(crate) address: String,
+    /// Its device directory.
+    dir: path::PathBuf,
+}
+
+impl Card {
+    fn read(&self, file: &path::Path) -> Result<f64, SensorError> {
+        let content = fs::read_to_string(file)
+            .map_err(|e| SensorError::Io(format!("cannot read {}: {}", file.display(), e)))?;
+        content.trim().parse().map_err(|e| {
+            SensorError::Parse(format!("{} of {}: {}", content.trim(), file.display(), e))
+        })
+    }
+
+    /// The hwmon directory of the card; looked up each time as it is gone during a mode switch.
+    fn hwmon_dir(&self) -> Result<path::PathBuf, SensorError> {
+        let dir = self.dir.join("hwmon");
+        let mut entries: Vec<path::PathBuf> = fs::read_dir(&dir)
+            .map_err(|e| SensorError::Io(format!("cannot read {}: {}", dir.display(), e)))?
+            .filter_map(|e| e.ok())
+            .map(|e| e.path())
+            .collect();
+        entries.sort();
+        entries
+            .into_iter()
+            .next()
+            .ok_or_else(|| SensorError::Io(format!("no hwmon device in {}.", dir.display())))
+    }
+
+    /// The average power in W; older kernels only have power1_average, newer ones power1_input.
+    fn power(&self) -> Result<f64, SensorError> {
+        let dir = self.hwmon_dir()?;
+        let file = match dir.join("power1_average") {
+            average if average.exists() => average,
+            _ => dir.join("power1_input"),
+        };
+        Ok(self.read(&file)? / 1e6)
+    }
+
+    fn busy(&self) -> Result<f64, SensorError> {
+        self.read(&self.dir.join("gpu_busy_percent"))
+    }
+
+    /// The edge temperature in °C; temp1 if none is labelled so.
+    fn temperature(&self) -> Result<f64, SensorError> {
+        let dir = self.hwmon_dir()?;
+        let channel = (1..=3)
+            .find(|i| {
+                fs::read_to_string(dir.join(format!("temp{}_label", i)))
+                    .map(|l| l.trim() == "edge")
+                    .unwrap_or(false)
+            })
+            .unwrap_or(1);
+        Ok(self.read(&dir.join(format!("temp{}_input", channel)))? / 1000.0)
+    }
+}
+
+/// Finds the amdgpu cards in the given drm directory, ordered by number.
+pub(crate) fn discover(root: &str) -> Vec<Card> {
+    let entries = match fs::read_dir(root) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+    let mut cards: Vec<(u32, Card)> = entries
+        .filter_map(|e| e.ok())
+        .filter_map(|e| {
+            let name = e.file_name().to_string_lossy().to_string();
+            // not the connectors like card0-DP-1.
+            let number = name.strip_prefix("card")?.parse().ok()?;
+            let dir = e.path().join("device");
+            let uevent = fs::read_to_string(dir.join("uevent")).ok()?;
+            let field = |key: &str| {
+                uevent
+                    .lines()
+                    .find_map(|l| l.strip_prefix(key)?.strip_prefix('='))
+                    .map(|v| v.trim().to_string())
+            };
+            if field("DRIVER")? != "amdgpu" {
+                return None;
+            }
+            let address = field("PCI_SLOT_NAME")?;
+            Some((number, Card { name, address, dir }))
+        })
+        .collect();
+    cards.sort_by_key(|(number, _)| *number);
+    cards.into_iter().map(|(_, card)| card).collect()
+}
+
+/// Reports the average power, busy percentage and edge temperature of Radeon cards.
+///
+/// A value that cannot be read, e.g. during a mode switch, is missing from that measurement only.
+pub struct AmdgpuSensor {
+    name: String,
+    cards: Vec<Card>,
+}
+
+impl AmdgpuSensor {
+    pub(crate) fn new(name: String, cards: Vec<Card>) -> AmdgpuSensor {
+        AmdgpuSensor { name, cards }
+    }
+}
+
+impl common::Sensor for AmdgpuSensor {
+    fn get_names(&self) -> Vec<String> {
+        self.cards
+            .iter()
+            .flat_map(|card| {
+                ["power", "busy_percent", "temperature"]
+                    .iter()
+                    .map(move |metric| format!("{}_{}_{}", self.name, card.name, metric))
+            })
+            .collect()
+    }
+
+    fn get_units(&self) -> Vec<String> {
+        self.cards
+            .iter()
+            .flat_map(|_| ["W", "%", "°C"].iter().map(|u| u.to_string()))
+            .collect()
+    }
+
+    fn measure(&self) -> Result<Vec<Reading>, SensorError> {
+        let names = self.get_names();
+        let mut res = Vec::new();
+        let mut error = None;
+        for (i, card) in self.cards.iter().enumerate() {
+            for (j, value) in [card.power(), card.busy(), card.temperature()]
+                .into_iter()
+                .enumerate()
+            {
+                match value {
+                    Ok(value) => res.push(Reading::new(names[3 * i + j].clone(), value)),
+                    Err(err) => error = Some(err),
+                }
+            }
+        }
+        match error {
+            Some(err) if res.is_empty() => Err(err),
+            Some(err) => {
+                eprintln!("Could not read all values of {}: {}", self.name, err);
+                Ok(res)
+            }
+            None => Ok(res),
+        }
+    }
+}
+
+/// Power, load and temperature of the host's Radeon cards.
+pub(crate) fn sensor_type() -> common::SensorType {
+    common::SensorType {
+        name: "amdgpu",
+        required: &[],
+        optional: &["path", "cards"],
+        create: |name, sensor_cfg, _| {
+            let root = match sensor_cfg.get("path") {
+                Some(v) => v
+                    .as_str()
+                    .ok_or_else(|| ConfigError::Invalid("path must be a string.".to_string()))?,
+                None => DRM_DIR,
+            };
+            let mut cards = discover(root);
+            if cards.is_empty() {
+                return Err(ConfigError::Invalid(format!(
+                    "no amdgpu cards found in {}.",
+                    root
+                )));
+            }
+            if let Some(v) = sensor_cfg.get("cards") {
+                let wanted = v
+                    .as_array()
+                    .and_then(|a| a.iter().map(|c| c.as_str()).collect::<Option<Vec<_>>>())
+                    .ok_or_else(|| {
+                        ConfigError::Invalid("cards must be an array of strings.".to_string())
+                    })?;
+                // the PCI domain may be left out, e.g. 03:00.0.
+                let is = |card: &Card, address: &str| {
+                    card.address == address || card.address.ends_with(&format!(":{}", address))
+                };
+                if let Some(unknown) = wanted.iter().find(|w| !cards.iter().any(|c| is(c, w))) {
+                    let available: Vec<String> = cards.iter().map(|c| c.address.clone()).collect();
+                    return Err(ConfigError::Invalid(format!(
+                        "no amdgpu card at {}; available are: {}.",
+                        unknown,
+                        available.join(", ")
+                    )));
+                }
+                cards.retain(|c| wanted.iter().any(|w| is(c, w)));
+            }
+            Ok(Box::new(AmdgpuSensor::new(name.to_string(), cards)))
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::values;
+    use crate::common::Sensor;
+
+    /// A drm directory with two Radeon cards, a connector and an Intel iGPU.
+    fn drm(root: &str) {
+        let _ = fs::remove_dir_all(root);
+        for (card, driver, address) in [
+            ("card0", "i915", "0000:00:02.0"),
+            ("card1", "amdgpu", "0000:03:00.0"),
+            ("card2", "amdgpu", "0000:0a:00.0"),
+        ] {
+            let dir = path::Path::new(root).join(card).join("device");
+            let hwmon = dir.join("hwmon").join(format!("hwmon{}", &card[4..]));
+            fs::create_dir_all(&hwmon).unwrap();
+            fs::write(
+                dir.join("uevent"),
+                format!(
+                    "DRIVER={}\nPCI_CLASS=30000\nPCI_SLOT_NAME={}\n",
+                    driver, address
+                ),
+            )
+            .unwrap();
+            fs::write(dir.join("gpu_busy_percent"), "37\n").unwrap();
+            fs::write(hwmon.join("power1_average"), "85000000\n").unwrap();
+            fs::write(hwmon.join("temp1_label"), "edge\n").unwrap();
+            fs::write(hwmon.join("temp1_input"), "52000\n").unwrap();
+            fs::write(hwmon.join("temp2_label"), "junction\n").unwrap();
+            fs::write(hwmon.join("temp2_input"), "61000\n").unwrap();
+        }
+        fs::create_dir_all(path::Path::new(root).join("card1-DP-1")).unwrap();
+    }
+
+    // Tests for success.
+
+    #[test]
+    fn test_measure_for_success() {
+        let root = "amdgpu_test0";
+        drm(root);
+        let cards = discover(root);
+        assert_eq!(
+            cards.iter().map(|c| c.address.as_str()).collect::<Vec<_>>(),
+            vec!["0000:03:00.0", "0000:0a:00.0"]
+        );
+        let sensor = AmdgpuSensor::new("gpu".to_string(), cards[..1].to_vec());
+        assert_eq!(sensor.get_units(), vec!["W", "%", "°C"]);
+        assert_eq!(
+            values(sensor.measure()),
+            vec![
+                ("gpu_card1_power".to_string(), 85.0),
+                ("gpu_card1_busy_percent".to_string(), 37.0),
+                ("gpu_card1_temperature".to_string(), 52.0)
+            ]
+        );
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    // Tests for failure.
+
+    #[test]
+    fn test_measure_for_failure() {
+        let root = "amdgpu_test1";
+        drm(root);
+        let sensor = AmdgpuSensor::new("gpu".to_string(), discover(root));
+        // in a mode switch.
+        fs::remove_dir_all(path::Path::new(root).join("card2/device/hwmon")).unwrap();
+        let res = values(sensor.measure());
+        assert_eq!(res.len(), 4);
+        assert_eq!(res[3], ("gpu_card2_busy_percent".to_string(), 37.0));
+        fs::remove_dir_all(root).unwrap();
+        assert!(matches!(sensor.measure(), Err(SensorError::Io(_))));
+    }
+
+    #[test]
+    fn test_sensor_type_for_failure() {
+        let sensor_cfg: toml::value::Table = toml::from_str("path='amdgpu_missing'").unwrap();
+        assert_eq!(
+            (sensor_type().create)("gpu", &sensor_cfg, "state").err(),
+            Some(ConfigError::Invalid(
+                "no amdgpu cards found in amdgpu_missing.".to_string()
+            ))
+        );
+        let root = "amdgpu_test2";
+        drm(root);
+        let sensor_cfg: toml::value::Table =
+            toml::from_str("path='amdgpu_test2'\ncards=['00:02.0']").unwrap();
+        assert_eq!(
+            (sensor_type().create)("gpu", &sensor_cfg, "state").err(),
+            Some(ConfigError::Invalid(
+                "no amdgpu card at 00:02.0; available are: 0000:03:00.0, 0000:0a:00.0.".to_string()
+            ))
+        );
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    // Tests for sanity.
+
+    #[test]
+    fn test_sensor_type_for_sanity() {
+        let root = "amdgpu_test3";
+        drm(root);
+        // newer kernels.
+        let hwmon = path::Path::new(root).join("card2/device/hwmon/hwmon2");
+        fs::rename(hwmon.join("power1_average"), hwmon.join("power1_input")).unwrap();
+        let sensor_cfg: toml::value::Table =
+            toml::from_str("path='amdgpu_test3'\ncards=['0a:00.0']").unwrap();
+        let sensor = (sensor_type().create)("gpu", &sensor_cfg, "state").unwrap();
+        assert_eq!(
+            values(sensor.measure())[0],
+            ("gpu_card2_power".to_string(), 85.0)
+        );
+        fs::remove_dir_all(root).unwrap();
+    }
+}
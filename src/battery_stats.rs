@@ -0,0 +1,351 @@
+use serde::{Deserialize, Serialize};
+
+use crate::common;
+use crate::state;
+use crate::tz;
+
+const METRICS: [&str; 4] = ["cycles", "dod", "hours_above_90", "hours_below_10"];
+
+/// How often (in iterations) the counters are written to the state store.
+const PERSIST_EVERY: u32 = 10;
+
+/// Long term battery counters.
+#[derive(Serialize, Deserialize, Default, Debug, PartialEq)]
+pub(crate) struct BatteryStats {
+    discharged_wh: f64,
+    secs_above_90: f64,
+    secs_below_10: f64,
+    day: i64,
+    soc_min: f64,
+    soc_max: f64,
+    last_dod: f64,
+    // not persisted; NaN does not survive JSON and a restart is a gap anyway.
+    #[serde(skip)]
+    last: Option<(f64, f64, f64)>,
+}
+
+impl BatteryStats {
+    /// Adds a sample; power is positive while discharging. Intervals longer than max_gap or with
+    /// a NaN on either side are skipped. The depth of discharge resets at midnight in the zone.
+    pub(crate) fn add(
+        &mut self,
+        timestamp: f64,
+        power: f64,
+        soc: f64,
+        max_gap: f64,
+        zone: &tz::Zone,
+    ) {
+        if let Some((t0, p0, s0)) = self.last {
+            let dt = timestamp - t0;
+            if dt > 0.0 && dt <= max_gap && !power.is_nan() && !p0.is_nan() {
+                // trapezoid over the discharging part only.
+                self.discharged_wh += (p0.max(0.0) + power.max(0.0)) / 2.0 * dt / 3600.0;
+            }
+            if dt > 0.0 && dt <= max_gap && !s0.is_nan() {
+                if s0 > 90.0 {
+                    self.secs_above_90 += dt;
+                } else if s0 < 10.0 {
+                    self.secs_below_10 += dt;
+                }
+            }
+        }
+        if !soc.is_nan() {
+            let day = zone.day(timestamp);
+            if day != self.day {
+                if self.day != 0 {
+                    self.last_dod = self.soc_max - self.soc_min;
+                }
+                self.day = day;
+                self.soc_min = soc;
+                self.soc_max = soc;
+            }
+            self.soc_min = self.soc_min.min(soc);
+            self.soc_max = self.soc_max.max(soc);
+        }
+        self.last = Some((timestamp, power, soc));
+    }
+
+    /// Equivalent full cycles for a given nominal capacity.
+    pub(crate) fn cycles(&self, capacity_wh: f64) -> f64 {
+        self.discharged_wh / capacity_wh
+    }
+
+    /// Depth of discharge of the current day so far.
+    pub(crate) fn dod(&self) -> f64 {
+        self.soc_max - self.soc_min
+    }
+
+    pub(crate) fn values(&self, capacity_wh: f64) -> Vec<f64> {
+        vec![
+            self.cycles(capacity_wh),
+            self.dod(),
+            self.secs_above_90 / 3600.0,
+            self.secs_below_10 / 3600.0,
+        ]
+    }
+
+    /// Human readable summary, e.g. for reporting.
+    pub(crate) fn summary(&self, capacity_wh: f64) -> String {
+        format!(
+            "{:.2} equivalent full cycles, {:.1}% depth of discharge today ({:.1}% yesterday), \
+            {:.1}h above 90% and {:.1}h below 10% SoC.",
+            self.cycles(capacity_wh),
+            self.dod(),
+            self.last_dod,
+            self.secs_above_90 / 3600.0,
+            self.secs_below_10 / 3600.0
+        )
+    }
+}
+
+/// Maintains battery statistics from a power & a state of charge column.
+pub struct BatteryStatsComponent {
+    name: String,
+    power_column: String,
+    soc_column: String,
+    capacity_wh: f64,
+    discharge_positive: bool,
+    max_gap: f64,
+    state_dir: String,
+    zone: tz::Zone,
+    stats: BatteryStats,
+    updates: u32,
+}
+
+impl BatteryStatsComponent {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        name: String,
+        power_column: String,
+        soc_column: String,
+        capacity_wh: f64,
+        discharge_positive: bool,
+        max_gap: f64,
+        state_dir: String,
+        zone: tz::Zone,
+    ) -> BatteryStatsComponent {
+        let stats: BatteryStats = state::load(&state_dir, &name).unwrap_or_default();
+        BatteryStatsComponent {
+            name,
+            power_column,
+            soc_column,
+            capacity_wh,
+            discharge_positive,
+            max_gap,
+            state_dir,
+            zone,
+            stats,
+            updates: 0,
+        }
+    }
+}
+
+impl common::Component for BatteryStatsComponent {
+    fn get_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = Vec::new();
+        for metric in METRICS {
+            names.push(format!("{}_{}", self.name, metric));
+        }
+        names
+    }
+
+    fn update(&mut self, names: &[String], values: &[f64]) -> Vec<f64> {
+        let lookup = |column: &str| match names.iter().position(|n| n == column) {
+            Some(i) => values[i],
+            None => f64::NAN,
+        };
+        let mut power = lookup(&self.power_column);
+        if !self.discharge_positive {
+            power = -power;
+        }
+        self.stats.add(
+            values[0],
+            power,
+            lookup(&self.soc_column),
+            self.max_gap,
+            &self.zone,
+        );
+
+        self.updates += 1;
+        if self.updates.is_multiple_of(PERSIST_EVERY) {
+            if let Err(err) = state::save(&self.state_dir, &self.name, &self.stats) {
+                eprintln!("Could not persist battery stats: {}.", err);
+            }
+        }
+        self.stats.values(self.capacity_wh)
+    }
+
+    fn get_inputs(&self) -> Option<Vec<String>> {
+        Some(vec![self.power_column.clone(), self.soc_column.clone()])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::Component;
+
+    /// Feeds a profile of (power, soc) samples, one per minute.
+    fn run(profile: &[(f64, f64)]) -> BatteryStats {
+        let mut stats = BatteryStats::default();
+        for (i, (power, soc)) in profile.iter().enumerate() {
+            stats.add(
+                1700000000.0 + i as f64 * 60.0,
+                *power,
+                *soc,
+                300.0,
+                &tz::Zone::Utc,
+            );
+        }
+        stats
+    }
+
+    // Tests for success.
+
+    #[test]
+    fn test_update_for_success() {
+        let mut comp = BatteryStatsComponent::new(
+            "bat".to_string(),
+            "p".to_string(),
+            "soc".to_string(),
+            1000.0,
+            true,
+            300.0,
+            "battery_test0".to_string(),
+            tz::Zone::Utc,
+        );
+        let names = vec!["timestamp".to_string(), "p".to_string(), "soc".to_string()];
+        for i in 0..PERSIST_EVERY {
+            comp.update(&names, &[1700000000.0 + i as f64 * 60.0, 100.0, 50.0]);
+        }
+        let stats: BatteryStats = state::load("battery_test0", "bat").unwrap();
+        assert_eq!(stats.values(1000.0), comp.stats.values(1000.0));
+        std::fs::remove_dir_all("battery_test0").unwrap();
+    }
+
+    // Tests for failure.
+
+    #[test]
+    fn test_add_for_failure() {
+        // NaN gap: the two intervals touching the NaN sample are not counted.
+        let stats = run(&[
+            (600.0, 50.0),
+            (600.0, 50.0),
+            (f64::NAN, 50.0),
+            (600.0, 50.0),
+        ]);
+        assert_eq!(stats.discharged_wh, 10.0);
+
+        // gap too long.
+        let mut stats = BatteryStats::default();
+        stats.add(0.0, 600.0, 50.0, 300.0, &tz::Zone::Utc);
+        stats.add(3600.0, 600.0, 50.0, 300.0, &tz::Zone::Utc);
+        assert_eq!(stats.discharged_wh, 0.0);
+    }
+
+    #[test]
+    fn test_update_for_failure() {
+        let mut comp = BatteryStatsComponent::new(
+            "bat".to_string(),
+            "p".to_string(),
+            "soc".to_string(),
+            1000.0,
+            true,
+            300.0,
+            "battery_test1".to_string(),
+            tz::Zone::Utc,
+        );
+        // missing columns.
+        let res = comp.update(&["timestamp".to_string()], &[0.0]);
+        assert_eq!(res[0], 0.0);
+        assert_eq!(res[1], 0.0);
+    }
+
+    // Tests for sanity.
+
+    #[test]
+    fn test_get_names_for_sanity() {
+        let comp = BatteryStatsComponent::new(
+            "bat".to_string(),
+            "p".to_string(),
+            "soc".to_string(),
+            1000.0,
+            true,
+            300.0,
+            "battery_test2".to_string(),
+            tz::Zone::Utc,
+        );
+        assert_eq!(
+            comp.get_names(),
+            vec![
+                "bat_cycles",
+                "bat_dod",
+                "bat_hours_above_90",
+                "bat_hours_below_10"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_cycles_for_sanity() {
+        // 2h at 500 W discharge = 1000 Wh = 1 cycle of a 1 kWh battery; charging does not count.
+        // each switch between charging & discharging contributes a minute at 250 W on average.
+        let mut profile = vec![(500.0, 50.0); 121];
+        profile.extend(vec![(-500.0, 50.0); 120]);
+        let stats = run(&profile);
+        let expected = (1000.0 + 250.0 / 60.0) / 1000.0;
+        assert!((stats.cycles(1000.0) - expected).abs() < 1e-9);
+
+        // two partial half cycles add up to one.
+        let mut profile = vec![(500.0, 50.0); 61];
+        profile.extend(vec![(-500.0, 50.0); 60]);
+        profile.extend(vec![(500.0, 50.0); 61]);
+        let stats = run(&profile);
+        let expected = (500.0 + 500.0 + 2.0 * 250.0 / 60.0) / 1000.0;
+        assert!((stats.cycles(1000.0) - expected).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_soc_for_sanity() {
+        // 30 min above 90%, then 60 min in between and 15 min below 10%.
+        let mut profile = vec![(0.0, 95.0); 30];
+        profile.extend(vec![(0.0, 50.0); 60]);
+        profile.extend(vec![(0.0, 5.0); 16]);
+        let stats = run(&profile);
+        assert_eq!(stats.secs_above_90, 30.0 * 60.0);
+        assert_eq!(stats.secs_below_10, 15.0 * 60.0);
+        assert_eq!(stats.dod(), 90.0);
+    }
+
+    #[test]
+    fn test_dod_for_sanity() {
+        let mut stats = BatteryStats::default();
+        // 2023-11-14 22:13:20 UTC.
+        stats.add(1700000000.0, 0.0, 80.0, 300.0, &tz::Zone::Utc);
+        stats.add(1700000060.0, 0.0, 30.0, 300.0, &tz::Zone::Utc);
+        assert_eq!(stats.dod(), 50.0);
+        // next day resets the running value.
+        stats.add(1700000000.0 + 86400.0, 0.0, 60.0, 300.0, &tz::Zone::Utc);
+        assert_eq!(stats.dod(), 0.0);
+        assert_eq!(stats.last_dod, 50.0);
+    }
+
+    #[test]
+    fn test_dod_dst_for_sanity() {
+        let berlin = tz::Zone::parse("Europe/Berlin");
+        let mut stats = BatteryStats::default();
+        // 2024-03-30 23:59 CET; then the 31st only has 23 hours.
+        stats.add(1711839540.0, 0.0, 80.0, 300.0, &berlin);
+        stats.add(1711839600.0, 0.0, 70.0, 300.0, &berlin);
+        assert_eq!(stats.last_dod, 0.0);
+        assert_eq!(stats.dod(), 0.0);
+        stats.add(1711839660.0, 0.0, 30.0, 300.0, &berlin);
+        // 2024-03-31 23:59 CEST is still the same day...
+        stats.add(1711922340.0, 0.0, 50.0, 300.0, &berlin);
+        assert_eq!(stats.dod(), 40.0);
+        // ... local midnight resets; in UTC this would have been two hours later.
+        stats.add(1711922400.0, 0.0, 60.0, 300.0, &berlin);
+        assert_eq!(stats.last_dod, 40.0);
+        assert_eq!(stats.dod(), 0.0);
+    }
+}
@@ -0,0 +1,603 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time;
+
+use aes::cipher::{KeyIvInit, StreamCipher};
+
+use crate::common;
+use crate::common::{ConfigError, Reading, SensorError};
+
+const ATC_METRICS: [&str; 4] = ["temperature", "humidity", "battery", "voltage"];
+const BTHOME_METRICS: [&str; 6] = [
+    "temperature",
+    "humidity",
+    "battery",
+    "voltage",
+    "power",
+    "energy",
+];
+const VICTRON_METRICS: [&str; 5] = ["voltage", "current", "soc", "consumed_ah", "remaining_mins"];
+// the units of the metrics above, as decoded.
+const ATC_UNITS: [&str; 4] = ["°C", "%", "%", "V"];
+const BTHOME_UNITS: [&str; 6] = ["°C", "%", "%", "V", "W", "kWh"];
+const VICTRON_UNITS: [&str; 5] = ["V", "A", "%", "Ah", "min"];
+
+type Ctr128LE = ctr::Ctr128LE<aes::Aes128>;
+
+/// Latest decoded values per MAC address & when they were received.
+type Cache = Arc<Mutex<HashMap<String, (time::Instant, Vec<f64>)>>>;
+
+/// Advertisement formats we know how to decode.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Format {
+    Atc,
+    BtHome,
+    Victron([u8; 16]),
+}
+
+/// A device we listen for.
+#[derive(Clone)]
+pub(crate) struct Device {
+    pub(crate) mac: String,
+    pub(crate) name: String,
+    pub(crate) format: Format,
+}
+
+impl Device {
+    pub(crate) fn new(
+        mac: &str,
+        name: &str,
+        format: &str,
+        key: Option<&str>,
+    ) -> Result<Device, ConfigError> {
+        let format = match format {
+            "atc" => Format::Atc,
+            "bthome" => Format::BtHome,
+            "victron" => {
+                let key = key.ok_or_else(|| {
+                    ConfigError::Invalid(
+                        "a victron BLE device requires an advertisement key.".to_string(),
+                    )
+                })?;
+                Format::Victron(parse_key(key).ok_or_else(|| {
+                    ConfigError::Invalid("advertisement key must be 32 hex digits.".to_string())
+                })?)
+            }
+            other => {
+                return Err(ConfigError::Invalid(format!(
+                    "unknown BLE advertisement format: {}.",
+                    other
+                )))
+            }
+        };
+        Ok(Device {
+            mac: mac.to_uppercase(),
+            name: name.to_string(),
+            format,
+        })
+    }
+
+    fn metrics(&self) -> &'static [&'static str] {
+        match self.format {
+            Format::Atc => &ATC_METRICS,
+            Format::BtHome => &BTHOME_METRICS,
+            Format::Victron(_) => &VICTRON_METRICS,
+        }
+    }
+
+    fn units(&self) -> &'static [&'static str] {
+        match self.format {
+            Format::Atc => &ATC_UNITS,
+            Format::BtHome => &BTHOME_UNITS,
+            Format::Victron(_) => &VICTRON_UNITS,
+        }
+    }
+
+    /// Decode the payload advertised under this device's service/manufacturer id.
+    #[cfg_attr(not(feature = "ble"), allow(dead_code))]
+    pub(crate) fn decode(&self, payload: &[u8]) -> Option<Vec<f64>> {
+        match &self.format {
+            Format::Atc => decode_atc(payload),
+            Format::BtHome => decode_bthome(payload),
+            Format::Victron(key) => decode_victron(payload, key),
+        }
+    }
+}
+
+pub struct BleSensor {
+    name: String,
+    devices: Vec<Device>,
+    stale_after: time::Duration,
+    cache: Cache,
+}
+
+impl BleSensor {
+    pub fn new(name: String, devices: Vec<Device>, stale_secs: u64) -> BleSensor {
+        let cache: Cache = Arc::new(Mutex::new(HashMap::new()));
+        #[cfg(feature = "ble")]
+        scanner::spawn(devices.clone(), cache.clone());
+        #[cfg(not(feature = "ble"))]
+        eprintln!(
+            "Compiled without the ble feature; BLE sensor {} will only report NaN.",
+            name
+        );
+        BleSensor {
+            name,
+            devices,
+            stale_after: time::Duration::from_secs(stale_secs),
+            cache,
+        }
+    }
+}
+
+impl common::Sensor for BleSensor {
+    fn get_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = Vec::new();
+        for device in &self.devices {
+            for metric in device.metrics() {
+                names.push(format!("{}_{}_{}", self.name, device.name, metric));
+            }
+        }
+        names
+    }
+
+    fn get_units(&self) -> Vec<String> {
+        self.devices
+            .iter()
+            .flat_map(|device| device.units().iter().map(|u| u.to_string()))
+            .collect()
+    }
+
+    fn measure(&self) -> Result<Vec<Reading>, SensorError> {
+        let cache = self.cache.lock().expect("BLE cache lock poisoned.");
+        let mut res: Vec<Reading> = Vec::new();
+        for device in &self.devices {
+            // stale devices are left out.
+            if let Some((seen, values)) = cache.get(&device.mac) {
+                if seen.elapsed() <= self.stale_after {
+                    for (metric, value) in device.metrics().iter().zip(values) {
+                        let name = format!("{}_{}_{}", self.name, device.name, metric);
+                        res.push(Reading::new(name, *value));
+                    }
+                }
+            }
+        }
+        if res.is_empty() && !self.devices.is_empty() {
+            return Err(SensorError::Protocol(format!(
+                "no advertisement from any device within the last {}s.",
+                self.stale_after.as_secs()
+            )));
+        }
+        Ok(res)
+    }
+}
+
+/// Parses a 128 bit key given as hex string.
+fn parse_key(key: &str) -> Option<[u8; 16]> {
+    if key.len() != 32 || !key.is_ascii() {
+        return None;
+    }
+    let mut res = [0_u8; 16];
+    for (i, item) in res.iter_mut().enumerate() {
+        *item = u8::from_str_radix(&key[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(res)
+}
+
+/// Decodes the ATC1441 (13 bytes, big endian) or pvvx (15 bytes, little endian) custom format.
+pub(crate) fn decode_atc(data: &[u8]) -> Option<Vec<f64>> {
+    match data.len() {
+        13 => {
+            let temperature = i16::from_be_bytes([data[6], data[7]]) as f64 / 10.0;
+            let humidity = data[8] as f64;
+            let battery = data[9] as f64;
+            let voltage = u16::from_be_bytes([data[10], data[11]]) as f64 / 1000.0;
+            Some(vec![temperature, humidity, battery, voltage])
+        }
+        15 => {
+            let temperature = i16::from_le_bytes([data[6], data[7]]) as f64 / 100.0;
+            let humidity = u16::from_le_bytes([data[8], data[9]]) as f64 / 100.0;
+            let voltage = u16::from_le_bytes([data[10], data[11]]) as f64 / 1000.0;
+            let battery = data[12] as f64;
+            Some(vec![temperature, humidity, battery, voltage])
+        }
+        _ => None,
+    }
+}
+
+/// Decodes an unencrypted BTHome v2 service data payload.
+pub(crate) fn decode_bthome(data: &[u8]) -> Option<Vec<f64>> {
+    let info = *data.first()?;
+    if info & 0x01 != 0 || info >> 5 != 2 {
+        // encrypted or not v2.
+        return None;
+    }
+    let mut res = vec![f64::NAN; BTHOME_METRICS.len()];
+    let mut i = 1;
+    while i < data.len() {
+        let id = data[i];
+        // (length, signed, divisor, index into BTHOME_METRICS)
+        let (len, signed, divisor, index): (usize, bool, f64, Option<usize>) = match id {
+            0x00 => (1, false, 1.0, None),          // packet id
+            0x01 => (1, false, 1.0, Some(2)),       // battery %
+            0x02 => (2, true, 100.0, Some(0)),      // temperature
+            0x03 => (2, false, 100.0, Some(1)),     // humidity
+            0x04 | 0x05 => (3, false, 100.0, None), // pressure, illuminance
+            0x08 => (2, true, 100.0, None),         // dew point
+            0x09 => (1, false, 1.0, None),          // count
+            0x0a => (3, false, 1000.0, Some(5)),    // energy kWh
+            0x0b => (3, false, 100.0, Some(4)),     // power W
+            0x0c => (2, false, 1000.0, Some(3)),    // voltage
+            0x0d | 0x0e | 0x12 | 0x13 => (2, false, 1.0, None),
+            0x0f..=0x11 => (1, false, 1.0, None), // binary states
+            0x2e => (1, false, 1.0, Some(1)),     // humidity %
+            0x45 => (2, true, 10.0, Some(0)),     // temperature
+            _ => break,                           // unknown length; stop here.
+        };
+        let raw = data.get(i + 1..i + 1 + len)?;
+        let mut value: i64 = 0;
+        for (shift, byte) in raw.iter().enumerate() {
+            value |= (*byte as i64) << (8 * shift);
+        }
+        if signed && raw[len - 1] & 0x80 != 0 {
+            value -= 1 << (8 * len);
+        }
+        if let Some(index) = index {
+            res[index] = value as f64 / divisor;
+        }
+        i += 1 + len;
+    }
+    Some(res)
+}
+
+/// Decrypts and decodes a Victron battery monitor (e.g. SmartShunt) instant readout record.
+pub(crate) fn decode_victron(data: &[u8], key: &[u8; 16]) -> Option<Vec<f64>> {
+    // prefix (2), model id (2), record type (1), iv (2), first key byte (1), encrypted data.
+    if data.len() < 9 || data[4] != 0x02 || data[7] != key[0] {
+        return None;
+    }
+    let mut nonce = [0_u8; 16];
+    nonce[0] = data[5];
+    nonce[1] = data[6];
+    let mut plain = [0_u8; 16];
+    let len = (data.len() - 8).min(16);
+    plain[..len].copy_from_slice(&data[8..8 + len]);
+    let mut cipher = Ctr128LE::new(&(*key).into(), &nonce.into());
+    cipher.apply_keystream(&mut plain);
+
+    let remaining = u16::from_le_bytes([plain[0], plain[1]]);
+    let voltage = i16::from_le_bytes([plain[2], plain[3]]);
+    let mut bits = [0_u8; 8];
+    bits.copy_from_slice(&plain[8..16]);
+    let bits = u64::from_le_bytes(bits);
+    let mut current = ((bits >> 2) & 0x3f_ffff) as i64;
+    if current & 0x20_0000 != 0 {
+        current -= 0x40_0000;
+    }
+    let consumed = (bits >> 24) & 0xf_ffff;
+    let soc = (bits >> 44) & 0x3ff;
+
+    let or_nan = |valid: bool, value: f64| if valid { value } else { f64::NAN };
+    Some(vec![
+        or_nan(voltage != 0x7fff, voltage as f64 / 100.0),
+        or_nan(current != 0x1f_ffff, current as f64 / 1000.0),
+        or_nan(soc != 0x3ff, soc as f64 / 10.0),
+        or_nan(consumed != 0xf_ffff, -(consumed as f64) / 10.0),
+        or_nan(remaining != 0xffff, remaining as f64),
+    ])
+}
+
+#[cfg(feature = "ble")]
+mod scanner {
+    use std::error::Error;
+    use std::thread;
+    use std::time;
+
+    use btleplug::api::{
+        bleuuid, Central, CentralEvent, Manager as _, Peripheral as _, ScanFilter,
+    };
+    use btleplug::platform::Manager;
+    use futures::stream::StreamExt;
+
+    use super::{Cache, Device, Format};
+
+    /// 16 bit service UUID used by the ATC/pvvx custom firmware.
+    const ATC_SERVICE: u16 = 0x181a;
+    /// 16 bit service UUID used by BTHome v2.
+    const BTHOME_SERVICE: u16 = 0xfcd2;
+    /// Manufacturer id of Victron Energy.
+    const VICTRON_MANUFACTURER: u16 = 0x02e1;
+
+    /// Runs a passive scan on a background thread, feeding decoded values into the cache.
+    pub(super) fn spawn(devices: Vec<Device>, cache: Cache) {
+        thread::spawn(move || {
+            let rt = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .expect("could not create runtime for the BLE scanner.");
+            if let Err(err) = rt.block_on(scan(&devices, &cache)) {
+                eprintln!("BLE scanner stopped: {}.", err);
+            }
+        });
+    }
+
+    async fn scan(devices: &[Device], cache: &Cache) -> Result<(), Box<dyn Error>> {
+        let manager = Manager::new().await?;
+        let central = manager
+            .adapters()
+            .await?
+            .into_iter()
+            .next()
+            .ok_or("no bluetooth adapter found")?;
+        let mut events = central.events().await?;
+        central.start_scan(ScanFilter::default()).await?;
+        while let Some(event) = events.next().await {
+            let id = match &event {
+                CentralEvent::ManufacturerDataAdvertisement { id, .. } => id,
+                CentralEvent::ServiceDataAdvertisement { id, .. } => id,
+                _ => continue,
+            };
+            let mac = central.peripheral(id).await?.address().to_string();
+            let device = match devices.iter().find(|d| d.mac == mac) {
+                Some(device) => device,
+                None => continue,
+            };
+            let payload = match (&event, &device.format) {
+                (
+                    CentralEvent::ManufacturerDataAdvertisement {
+                        manufacturer_data, ..
+                    },
+                    Format::Victron(_),
+                ) => manufacturer_data.get(&VICTRON_MANUFACTURER),
+                (CentralEvent::ServiceDataAdvertisement { service_data, .. }, Format::Atc) => {
+                    service_data.get(&bleuuid::uuid_from_u16(ATC_SERVICE))
+                }
+                (CentralEvent::ServiceDataAdvertisement { service_data, .. }, Format::BtHome) => {
+                    service_data.get(&bleuuid::uuid_from_u16(BTHOME_SERVICE))
+                }
+                _ => None,
+            };
+            if let Some(values) = payload.and_then(|p| device.decode(p)) {
+                cache
+                    .lock()
+                    .expect("BLE cache lock poisoned.")
+                    .insert(mac, (time::Instant::now(), values));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Thermometers and the like broadcasting their readings via BLE.
+pub(crate) fn sensor_type() -> common::SensorType {
+    common::SensorType {
+        name: "ble",
+        required: &["devices"],
+        optional: &["stale_secs"],
+        create: |name, sensor_cfg, _| {
+            let mut devices = Vec::new();
+            for d in sensor_cfg["devices"].as_array().unwrap_or(&Vec::new()) {
+                let field = |key: &str| {
+                    d.get(key).and_then(|v| v.as_str()).ok_or_else(|| {
+                        ConfigError::Invalid(format!("a BLE device requires a {}.", key))
+                    })
+                };
+                devices.push(Device::new(
+                    field("mac")?,
+                    field("name")?,
+                    d.get("format").and_then(|f| f.as_str()).unwrap_or("atc"),
+                    d.get("key").and_then(|k| k.as_str()),
+                )?);
+            }
+            Ok(Box::new(BleSensor::new(
+                name.to_string(),
+                devices,
+                sensor_cfg
+                    .get("stale_secs")
+                    .and_then(|v| v.as_integer())
+                    .unwrap_or(300) as u64,
+            )))
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::Sensor;
+
+    const VICTRON_KEY: &str = "aff4d0995b7d1e176c0c33ecb9e70dcd";
+    // captured from a SmartShunt.
+    const VICTRON_PAYLOAD: [u8; 23] = [
+        0x10, 0x02, 0x89, 0xa3, 0x02, 0xb0, 0x40, 0xaf, 0x92, 0x5d, 0x09, 0xa4, 0xd8, 0x9a, 0xa0,
+        0x12, 0x8b, 0xde, 0xf4, 0x8c, 0x62, 0x98, 0xa9,
+    ];
+
+    fn hex(data: &str) -> Vec<u8> {
+        (0..data.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&data[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    // Tests for success.
+
+    #[test]
+    fn test_get_names_for_success() {
+        let sensor = BleSensor::new(
+            "ble".to_string(),
+            vec![Device::new("a4:c1:38:aa:bb:cc", "kitchen", "atc", None).unwrap()],
+            300,
+        );
+        sensor.get_names();
+    }
+
+    #[test]
+    fn test_decode_victron_for_success() {
+        let key = parse_key(VICTRON_KEY).unwrap();
+        decode_victron(&VICTRON_PAYLOAD, &key).unwrap();
+    }
+
+    // Tests for failure.
+
+    #[test]
+    fn test_decode_atc_for_failure() {
+        assert_eq!(decode_atc(&hex("a4c138aabbcc00eb")), None);
+    }
+
+    #[test]
+    fn test_decode_bthome_for_failure() {
+        // encrypted flag set.
+        assert_eq!(decode_bthome(&hex("4102c409")), None);
+        // truncated object.
+        assert_eq!(decode_bthome(&hex("4002c4")), None);
+    }
+
+    #[test]
+    fn test_decode_victron_for_failure() {
+        let mut key = parse_key(VICTRON_KEY).unwrap();
+        key[0] = 0x00;
+        assert_eq!(decode_victron(&VICTRON_PAYLOAD, &key), None);
+        assert_eq!(parse_key("abc"), None);
+    }
+
+    #[test]
+    fn test_device_new_for_failure() {
+        assert_eq!(
+            Device::new("a4:c1:38:aa:bb:cc", "shunt", "victron", None).err(),
+            Some(ConfigError::Invalid(
+                "a victron BLE device requires an advertisement key.".to_string()
+            ))
+        );
+        assert_eq!(
+            Device::new("a4:c1:38:aa:bb:cc", "shunt", "victron", Some("abc")).err(),
+            Some(ConfigError::Invalid(
+                "advertisement key must be 32 hex digits.".to_string()
+            ))
+        );
+        assert_eq!(
+            Device::new("a4:c1:38:aa:bb:cc", "kitchen", "ruuvi", None).err(),
+            Some(ConfigError::Invalid(
+                "unknown BLE advertisement format: ruuvi.".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_measure_for_failure() {
+        let sensor = BleSensor::new(
+            "ble".to_string(),
+            vec![Device::new("a4:c1:38:aa:bb:cc", "kitchen", "atc", None).unwrap()],
+            0,
+        );
+        sensor.cache.lock().unwrap().insert(
+            "A4:C1:38:AA:BB:CC".to_string(),
+            (
+                time::Instant::now() - time::Duration::from_secs(1),
+                vec![1.0, 2.0, 3.0, 4.0],
+            ),
+        );
+        assert!(matches!(sensor.measure(), Err(SensorError::Protocol(_))));
+    }
+
+    // Tests for sanity.
+
+    #[test]
+    fn test_get_names_for_sanity() {
+        let sensor = BleSensor::new(
+            "ble".to_string(),
+            vec![
+                Device::new("a4:c1:38:aa:bb:cc", "kitchen", "atc", None).unwrap(),
+                Device::new("c0:ff:ee:00:00:01", "shunt", "victron", Some(VICTRON_KEY)).unwrap(),
+            ],
+            300,
+        );
+        assert_eq!(
+            sensor.get_names(),
+            vec![
+                "ble_kitchen_temperature",
+                "ble_kitchen_humidity",
+                "ble_kitchen_battery",
+                "ble_kitchen_voltage",
+                "ble_shunt_voltage",
+                "ble_shunt_current",
+                "ble_shunt_soc",
+                "ble_shunt_consumed_ah",
+                "ble_shunt_remaining_mins",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_get_units_for_sanity() {
+        let sensor = BleSensor::new(
+            "ble".to_string(),
+            vec![
+                Device::new("a4:c1:38:aa:bb:cc", "kitchen", "atc", None).unwrap(),
+                Device::new("c0:ff:ee:00:00:01", "shunt", "victron", Some(VICTRON_KEY)).unwrap(),
+            ],
+            300,
+        );
+        assert_eq!(
+            sensor.get_units(),
+            vec!["°C", "%", "%", "V", "V", "A", "%", "Ah", "min"]
+        );
+    }
+
+    #[test]
+    fn test_decode_atc_for_sanity() {
+        assert_eq!(
+            decode_atc(&hex("a4c138aabbcc00eb2d550b8a12")).unwrap(),
+            vec![23.5, 45.0, 85.0, 2.954]
+        );
+        assert_eq!(
+            decode_atc(&hex("ccbbaa38c1a42909a0118a0b551204")).unwrap(),
+            vec![23.45, 45.12, 85.0, 2.954]
+        );
+    }
+
+    #[test]
+    fn test_decode_bthome_for_sanity() {
+        let res = decode_bthome(&hex("400001016402ca0903bf13")).unwrap();
+        assert_eq!(res[0], 25.06);
+        assert_eq!(res[1], 50.55);
+        assert_eq!(res[2], 100.0);
+        assert!(res[3].is_nan());
+        // negative temperature in 0.1 resolution.
+        let res = decode_bthome(&hex("4045f6ff")).unwrap();
+        assert_eq!(res[0], -1.0);
+    }
+
+    #[test]
+    fn test_decode_victron_for_sanity() {
+        let key = parse_key(VICTRON_KEY).unwrap();
+        let res = decode_victron(&VICTRON_PAYLOAD, &key).unwrap();
+        assert_eq!(res[0], 12.53);
+        assert_eq!(res[1], 0.0);
+        assert_eq!(res[2], 50.0);
+        assert_eq!(res[3], -50.0);
+        assert!(res[4].is_nan());
+    }
+
+    #[test]
+    fn test_measure_for_sanity() {
+        let sensor = BleSensor::new(
+            "ble".to_string(),
+            vec![
+                Device::new("a4:c1:38:aa:bb:cc", "kitchen", "atc", None).unwrap(),
+                Device::new("a4:c1:38:00:00:01", "garden", "atc", None).unwrap(),
+            ],
+            300,
+        );
+        sensor.cache.lock().unwrap().insert(
+            "A4:C1:38:AA:BB:CC".to_string(),
+            (time::Instant::now(), vec![23.5, 45.0, 85.0, 2.954]),
+        );
+        let res = sensor.measure().unwrap();
+        assert_eq!(res.len(), 4);
+        assert_eq!(res[0].name, "ble_kitchen_temperature");
+        let (values, _) = common::align(&sensor.get_names(), &res);
+        assert_eq!(values[..4], [23.5, 45.0, 85.0, 2.954]);
+        assert!(values[4..].iter().all(|v| v.is_nan()));
+    }
+}
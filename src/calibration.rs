@@ -0,0 +1,224 @@
+use std::error::Error;
+
+use crate::common;
+use crate::common::{Reading, SensorError};
+
+/// Whether a value represents a failed measurement (NaN); those are never corrected.
+pub(crate) fn is_failure(value: f64) -> bool {
+    value.is_nan()
+}
+
+/// Correction of the raw values of a single metric.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Correction {
+    /// corrected = raw * gain + offset.
+    Linear { offset: f64, gain: f64 },
+    /// Pairs of (raw, corrected); interpolated linearly in between, extrapolated beyond.
+    Points(Vec<(f64, f64)>),
+}
+
+impl Correction {
+    /// Two-point (or more) correction; the points are sorted by their raw values.
+    pub(crate) fn points(mut points: Vec<(f64, f64)>) -> Correction {
+        if points.len() < 2 {
+            panic!("a calibration requires at least two points.");
+        }
+        points.sort_by(|a, b| a.0.total_cmp(&b.0));
+        if points.windows(2).any(|w| w[0].0 == w[1].0) {
+            panic!("the raw values of calibration points must differ.");
+        }
+        Correction::Points(points)
+    }
+
+    pub(crate) fn apply(&self, raw: f64) -> f64 {
+        match self {
+            Correction::Linear { offset, gain } => raw * gain + offset,
+            Correction::Points(points) => {
+                // the segment containing the value; the first or last one when beyond the points.
+                let i = points
+                    .iter()
+                    .skip(1)
+                    .position(|p| raw < p.0)
+                    .unwrap_or(points.len() - 2);
+                let ((x0, y0), (x1, y1)) = (points[i], points[i + 1]);
+                y0 + (raw - x0) * (y1 - y0) / (x1 - x0)
+            }
+        }
+    }
+}
+
+/// Per column corrections of a sensor.
+pub(crate) struct Calibration {
+    corrections: Vec<Option<Correction>>,
+}
+
+impl Calibration {
+    pub(crate) fn new(corrections: Vec<Option<Correction>>) -> Calibration {
+        Calibration { corrections }
+    }
+
+    /// Corrects the values of all calibrated columns, leaving failure values as they are.
+    pub(crate) fn correct(&self, values: &[f64]) -> Vec<f64> {
+        values
+            .iter()
+            .enumerate()
+            .map(|(i, value)| self.correct_one(i, *value))
+            .collect()
+    }
+
+    /// Corrects the value of the i-th column.
+    pub(crate) fn correct_one(&self, i: usize, value: f64) -> f64 {
+        match self.corrections.get(i) {
+            Some(Some(correction)) if !is_failure(value) => correction.apply(value),
+            _ => value,
+        }
+    }
+}
+
+/// Applies the calibration to the values of the sensor it wraps.
+pub struct CalibratedSensor {
+    inner: Box<dyn common::Sensor>,
+    calibration: Calibration,
+}
+
+impl CalibratedSensor {
+    pub(crate) fn new(
+        inner: Box<dyn common::Sensor>,
+        calibration: Calibration,
+    ) -> CalibratedSensor {
+        CalibratedSensor { inner, calibration }
+    }
+}
+
+impl common::Sensor for CalibratedSensor {
+    fn get_names(&self) -> Vec<String> {
+        self.inner.get_names()
+    }
+
+    fn get_units(&self) -> Vec<String> {
+        self.inner.get_units()
+    }
+
+    fn init(&mut self) -> Result<(), SensorError> {
+        self.inner.init()
+    }
+
+    fn shutdown(&mut self) {
+        self.inner.shutdown()
+    }
+
+    fn measure(&self) -> Result<Vec<Reading>, SensorError> {
+        let names = self.inner.get_names();
+        let mut readings = self.inner.measure()?;
+        for reading in readings.iter_mut() {
+            if let Some(i) = names.iter().position(|n| n == &reading.name) {
+                reading.value = self.calibration.correct_one(i, reading.value);
+            }
+        }
+        Ok(readings)
+    }
+
+    fn validate(&self) -> Result<(), Box<dyn Error>> {
+        self.inner.validate()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::Sensor;
+    use crate::dummy;
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-9
+    }
+
+    // Tests for success.
+
+    #[test]
+    fn test_offset_for_success() {
+        let correction = Correction::Linear {
+            offset: -1.8,
+            gain: 1.0,
+        };
+        assert!(close(correction.apply(10.0), 8.2));
+        assert!(close(correction.apply(0.0), -1.8));
+    }
+
+    #[test]
+    fn test_gain_for_success() {
+        let correction = Correction::Linear {
+            offset: -1.8,
+            gain: 0.985,
+        };
+        assert!(close(correction.apply(1000.0), 983.2));
+    }
+
+    #[test]
+    fn test_points_for_success() {
+        let correction = Correction::points(vec![(2000.0, 1985.0), (0.0, 0.0)]);
+        assert!(close(correction.apply(0.0), 0.0));
+        assert!(close(correction.apply(1000.0), 992.5));
+        assert!(close(correction.apply(2000.0), 1985.0));
+    }
+
+    // Tests for failure.
+
+    #[test]
+    #[should_panic]
+    fn test_points_for_failure() {
+        Correction::points(vec![(0.0, 0.0)]);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_duplicate_points_for_failure() {
+        Correction::points(vec![(10.0, 0.0), (10.0, 5.0)]);
+    }
+
+    #[test]
+    fn test_correct_for_failure() {
+        // failure values stay failure values; a reading of -1 is a reading like any other.
+        let calibration = Calibration::new(vec![Some(Correction::Linear {
+            offset: -1.8,
+            gain: 0.985,
+        })]);
+        assert!(calibration.correct(&[f64::NAN])[0].is_nan());
+        assert_ne!(calibration.correct(&[-1.0]), vec![-1.0]);
+    }
+
+    // Tests for sanity.
+
+    #[test]
+    fn test_extrapolation_for_sanity() {
+        let correction = Correction::points(vec![(0.0, 0.5), (100.0, 98.5), (2000.0, 1985.0)]);
+        // within the points the nearest segment is used...
+        assert!(close(correction.apply(50.0), 49.5));
+        assert!(close(correction.apply(1050.0), 1041.75));
+        // ... and beyond them the outermost ones.
+        assert!(close(correction.apply(-100.0), -97.5));
+        assert!(close(correction.apply(3900.0), 3871.5));
+    }
+
+    #[test]
+    fn test_calibrated_sensor_for_sanity() {
+        let inner = dummy::DummySensor::new(
+            "plug".to_string(),
+            vec![("power".to_string(), 10.0), ("voltage".to_string(), 230.0)],
+        );
+        let sensor = CalibratedSensor::new(
+            Box::new(inner),
+            Calibration::new(vec![
+                Some(Correction::Linear {
+                    offset: -1.8,
+                    gain: 1.0,
+                }),
+                None,
+            ]),
+        );
+        assert_eq!(sensor.get_names(), vec!["plug_power", "plug_voltage"]);
+        let readings = sensor.measure().unwrap();
+        assert!(close(readings[0].value, 8.2));
+        assert_eq!(readings[1].value, 230.0);
+    }
+}
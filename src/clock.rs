@@ -0,0 +1,101 @@
+use std::sync::Mutex;
+use std::thread;
+use std::time;
+
+/// Source of the time for everything time dependent; allows tests to step through time.
+pub trait Clock {
+    /// The current time.
+    fn now(&self) -> time::SystemTime;
+    /// Blocks until the given time has come.
+    fn sleep_until(&self, deadline: time::SystemTime);
+}
+
+/// Seconds since the epoch for a given time.
+pub fn epoch_secs(t: time::SystemTime) -> f64 {
+    t.duration_since(time::UNIX_EPOCH)
+        .expect("should be a duration.")
+        .as_secs_f64()
+}
+
+/// The wall clock.
+pub struct SystemClock {}
+
+impl Clock for SystemClock {
+    fn now(&self) -> time::SystemTime {
+        time::SystemTime::now()
+    }
+
+    fn sleep_until(&self, deadline: time::SystemTime) {
+        if let Ok(duration) = deadline.duration_since(time::SystemTime::now()) {
+            thread::sleep(duration);
+        }
+    }
+}
+
+/// A simulated clock; sleeping just moves the time forward.
+pub struct SimClock {
+    now: Mutex<time::SystemTime>,
+}
+
+impl SimClock {
+    /// A clock starting at the given seconds since the epoch.
+    pub fn new(epoch_secs: u64) -> SimClock {
+        SimClock {
+            now: Mutex::new(time::UNIX_EPOCH + time::Duration::from_secs(epoch_secs)),
+        }
+    }
+
+    /// Move the time forward by the given duration.
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub(crate) fn advance(&self, duration: time::Duration) {
+        let mut now = self.now.lock().expect("clock lock poisoned.");
+        *now += duration;
+    }
+}
+
+impl Clock for SimClock {
+    fn now(&self) -> time::SystemTime {
+        *self.now.lock().expect("clock lock poisoned.")
+    }
+
+    fn sleep_until(&self, deadline: time::SystemTime) {
+        let mut now = self.now.lock().expect("clock lock poisoned.");
+        if deadline > *now {
+            *now = deadline;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Tests for success.
+
+    #[test]
+    fn test_system_clock_for_success() {
+        let clock = SystemClock {};
+        clock.sleep_until(clock.now() + time::Duration::from_millis(1));
+    }
+
+    // Tests for failure.
+
+    #[test]
+    fn test_sleep_until_for_failure() {
+        // sleeping into the past does not turn back time.
+        let clock = SimClock::new(100);
+        clock.sleep_until(time::UNIX_EPOCH + time::Duration::from_secs(50));
+        assert_eq!(epoch_secs(clock.now()), 100.0);
+    }
+
+    // Tests for sanity.
+
+    #[test]
+    fn test_sim_clock_for_sanity() {
+        let clock = SimClock::new(100);
+        clock.sleep_until(clock.now() + time::Duration::from_secs(30));
+        assert_eq!(epoch_secs(clock.now()), 130.0);
+        clock.advance(time::Duration::from_secs(2 * 86400));
+        assert_eq!(epoch_secs(clock.now()), 130.0 + 2.0 * 86400.0);
+    }
+}
@@ -0,0 +1,227 @@
+use serde::{Deserialize, Serialize};
+
+use crate::common;
+use crate::state;
+
+const METRICS: [&str; 2] = ["co2_avoided_g", "co2_avoided_total_g"];
+
+/// How often (in iterations) the total is written to the state store.
+const PERSIST_EVERY: u32 = 10;
+
+/// The carbon intensity of the grid in gCO2/kWh.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Intensity {
+    /// Read from a column, e.g. of a carbon intensity sensor.
+    Column(String),
+    /// A fixed value, e.g. the yearly average of the grid.
+    Static(f64),
+}
+
+/// The CO2 avoided by generating power instead of drawing it from the grid.
+#[derive(Serialize, Deserialize, Default, Debug, PartialEq)]
+pub(crate) struct Co2Savings {
+    total_g: f64,
+    // not persisted; a restart is a gap anyway.
+    #[serde(skip)]
+    last: Option<(f64, f64)>,
+}
+
+impl Co2Savings {
+    /// Adds a sample of the power (W) and the carbon intensity (gCO2/kWh); returns the grams avoided since the previous
+    /// one. Intervals longer than max_gap or with a missing value on either side are skipped.
+    pub(crate) fn add(&mut self, timestamp: f64, power: f64, intensity: f64, max_gap: f64) -> f64 {
+        // g/h while generating.
+        let rate = power.max(0.0) * intensity / 1000.0;
+        let res = match self.last {
+            Some((t0, r0)) if !r0.is_nan() && !rate.is_nan() => {
+                let dt = timestamp - t0;
+                if dt > 0.0 && dt <= max_gap {
+                    // trapezoid.
+                    (r0 + rate) / 2.0 * dt / 3600.0
+                } else {
+                    f64::NAN
+                }
+            }
+            _ => f64::NAN,
+        };
+        if !res.is_nan() {
+            self.total_g += res;
+        }
+        self.last = Some((timestamp, rate));
+        res
+    }
+}
+
+/// Estimates the CO2 avoided by a generation power column, per interval and in total; the total survives restarts.
+pub struct Co2Component {
+    name: String,
+    power_column: String,
+    intensity: Intensity,
+    max_gap: f64,
+    state_dir: String,
+    savings: Co2Savings,
+    updates: u32,
+}
+
+impl Co2Component {
+    pub(crate) fn new(
+        name: String,
+        power_column: String,
+        intensity: Intensity,
+        max_gap: f64,
+        state_dir: String,
+    ) -> Co2Component {
+        let savings: Co2Savings = state::load(&state_dir, &name).unwrap_or_default();
+        Co2Component {
+            name,
+            power_column,
+            intensity,
+            max_gap,
+            state_dir,
+            savings,
+            updates: 0,
+        }
+    }
+}
+
+impl common::Component for Co2Component {
+    fn get_names(&self) -> Vec<String> {
+        METRICS
+            .iter()
+            .map(|metric| format!("{}_{}", self.name, metric))
+            .collect()
+    }
+
+    fn update(&mut self, names: &[String], values: &[f64]) -> Vec<f64> {
+        let lookup = |column: &str| match names.iter().position(|n| n == column) {
+            Some(i) => values[i],
+            None => f64::NAN,
+        };
+        let intensity = match &self.intensity {
+            Intensity::Column(column) => lookup(column),
+            Intensity::Static(value) => *value,
+        };
+        let avoided = self.savings.add(
+            values[0],
+            lookup(&self.power_column),
+            intensity,
+            self.max_gap,
+        );
+
+        self.updates += 1;
+        if self.updates.is_multiple_of(PERSIST_EVERY) {
+            if let Err(err) = state::save(&self.state_dir, &self.name, &self.savings) {
+                eprintln!("Could not persist the avoided CO2: {}.", err);
+            }
+        }
+        vec![avoided, self.savings.total_g]
+    }
+
+    fn get_inputs(&self) -> Option<Vec<String>> {
+        let mut res = vec![self.power_column.clone()];
+        if let Intensity::Column(column) = &self.intensity {
+            res.push(column.clone());
+        }
+        Some(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::common::Component;
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-9
+    }
+
+    fn names() -> Vec<String> {
+        vec![
+            "timestamp".to_string(),
+            "pv_power".to_string(),
+            "grid_intensity".to_string(),
+        ]
+    }
+
+    // Tests for success.
+
+    #[test]
+    fn test_add_for_success() {
+        let mut savings = Co2Savings::default();
+        // nothing to integrate yet.
+        assert!(savings.add(0.0, 1000.0, 400.0, 900.0).is_nan());
+        // 1 kW at 400 g/kWh for half an hour.
+        assert!(close(
+            savings.add(1800.0, 1000.0, 400.0, 900.0 * 4.0),
+            200.0
+        ));
+        // uneven: 10 secs ramping to 2 kW, ...
+        assert!(close(
+            savings.add(1810.0, 2000.0, 400.0, 900.0),
+            10.0 * 600.0 / 3600.0
+        ));
+        // ... then 50 secs at 2 kW while the intensity drops to 200 g/kWh.
+        assert!(close(
+            savings.add(1860.0, 2000.0, 200.0, 900.0),
+            50.0 * 600.0 / 3600.0
+        ));
+        assert!(close(savings.total_g, 210.0));
+    }
+
+    // Tests for failure.
+
+    #[test]
+    fn test_add_for_failure() {
+        let mut savings = Co2Savings::default();
+        savings.add(0.0, 1000.0, 400.0, 900.0);
+        // missing intensity: skipped, on both sides.
+        assert!(savings.add(60.0, 1000.0, f64::NAN, 900.0).is_nan());
+        assert!(savings.add(120.0, 1000.0, 400.0, 900.0).is_nan());
+        // a gap.
+        assert!(savings.add(3600.0, 1000.0, 400.0, 900.0).is_nan());
+        assert_eq!(savings.total_g, 0.0);
+        // counting on.
+        assert!(close(savings.add(3636.0, 1000.0, 400.0, 900.0), 4.0));
+    }
+
+    // Tests for sanity.
+
+    #[test]
+    fn test_update_for_sanity() {
+        let dir = "co2_test0";
+        let mut component = Co2Component::new(
+            "pv".to_string(),
+            "pv_power".to_string(),
+            Intensity::Column("grid_intensity".to_string()),
+            900.0,
+            dir.to_string(),
+        );
+        assert_eq!(
+            component.get_names(),
+            vec!["pv_co2_avoided_g", "pv_co2_avoided_total_g"]
+        );
+        for i in 0..10 {
+            component.update(&names(), &[i as f64 * 36.0, 500.0, 400.0]);
+        }
+        // 9 intervals of 36 secs at 200 g/h.
+        assert!(close(component.savings.total_g, 18.0));
+
+        // persisted; and picked up again.
+        let mut component = Co2Component::new(
+            "pv".to_string(),
+            "pv_power".to_string(),
+            Intensity::Static(400.0),
+            900.0,
+            dir.to_string(),
+        );
+        let res = component.update(&names()[..2], &[1000.0, -50.0]);
+        assert!(res[0].is_nan());
+        assert!(close(res[1], 18.0));
+        // at night.
+        let res = component.update(&names()[..2], &[1036.0, -50.0]);
+        assert_eq!(res[0], 0.0);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
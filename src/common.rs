@@ -1,5 +1,895 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path;
+use std::sync::{mpsc, Arc, Mutex, TryLockError};
+use std::thread;
+use std::time::Duration;
+
+use crate::http;
+use crate::keys;
+
+/// Why a sensor could not be measured at all.
+#[derive(Debug)]
+pub enum SensorError {
+    /// The device (file, bus, ...) could not be read.
+    Io(String),
+    /// The service could not be reached or answered with an error.
+    Http(String),
+    /// The answer could not be understood.
+    Parse(String),
+    /// The device or service did not behave as expected, e.g. rejected a login.
+    Protocol(String),
+    /// The sensor did not answer in time.
+    Timeout(String),
+}
+
+impl fmt::Display for SensorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SensorError::Io(msg) => write!(f, "I/O error: {}", msg),
+            SensorError::Http(msg) => write!(f, "HTTP error: {}", msg),
+            SensorError::Parse(msg) => write!(f, "could not parse: {}", msg),
+            SensorError::Protocol(msg) => write!(f, "protocol error: {}", msg),
+            SensorError::Timeout(msg) => write!(f, "timed out: {}", msg),
+        }
+    }
+}
+
+impl SensorError {
+    /// The message without the kind of error.
+    pub(crate) fn message(&self) -> &str {
+        match self {
+            SensorError::Io(msg)
+            | SensorError::Http(msg)
+            | SensorError::Parse(msg)
+            | SensorError::Protocol(msg)
+            | SensorError::Timeout(msg) => msg,
+        }
+    }
+}
+
+impl Error for SensorError {}
+
+impl From<io::Error> for SensorError {
+    fn from(err: io::Error) -> SensorError {
+        SensorError::Io(err.to_string())
+    }
+}
+
+impl From<reqwest::Error> for SensorError {
+    fn from(err: reqwest::Error) -> SensorError {
+        SensorError::Http(err.to_string())
+    }
+}
+
+impl From<Box<dyn Error>> for SensorError {
+    /// Recovers the error sensors pass through e.g. a key pool; anything else is taken as protocol error.
+    fn from(err: Box<dyn Error>) -> SensorError {
+        if err.is::<keys::QuotaError>()
+            || err.is::<http::StatusError>()
+            || err.is::<reqwest::Error>()
+        {
+            return SensorError::Http(err.to_string());
+        }
+        if err.is::<io::Error>() {
+            return SensorError::Io(err.to_string());
+        }
+        match err.downcast::<SensorError>() {
+            Ok(err) => *err,
+            Err(err) => SensorError::Protocol(err.to_string()),
+        }
+    }
+}
+
+/// Why a sensor could not be created from its config.
+#[derive(Debug, PartialEq)]
+pub enum ConfigError {
+    /// Fields its type requires are not set; all of them.
+    Missing {
+        /// The type of the sensor.
+        kind: String,
+        /// The fields that are not set.
+        fields: Vec<String>,
+    },
+    /// A field is set to something that cannot be used.
+    Invalid(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Missing { kind, fields } => write!(
+                f,
+                "a {} sensor requires the following fields to be set: {}.",
+                kind,
+                fields.join(", ")
+            ),
+            ConfigError::Invalid(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+impl Error for ConfigError {}
+
+/// Creates a sensor from its name, config and the directory to keep state in.
+pub type Factory =
+    fn(&str, &toml::value::Table, &str) -> Result<Box<dyn Sensor + Send>, ConfigError>;
+
+/// A type of sensor as named by the type field of its config.
+pub struct SensorType {
+    /// The value of the type field, e.g. "dummy".
+    pub name: &'static str,
+    /// Fields that must be set; checked before the factory runs.
+    pub required: &'static [&'static str],
+    /// Fields that may be set; the factory picks defaults for them.
+    pub optional: &'static [&'static str],
+    /// Creates the sensor once its config has all required fields.
+    pub create: Factory,
+}
+
+/// The known types of sensors.
+#[derive(Default)]
+pub(crate) struct Registry {
+    types: HashMap<&'static str, SensorType>,
+}
+
+impl Registry {
+    pub(crate) fn register(&mut self, sensor_type: SensorType) {
+        if self.types.contains_key(sensor_type.name) {
+            panic!("sensor type {} registered twice.", sensor_type.name);
+        }
+        self.types.insert(sensor_type.name, sensor_type);
+    }
+
+    /// The fields of a sensor's type that are missing in its config; as an error if there are any.
+    fn missing(
+        kind: &str,
+        sensor_type: &SensorType,
+        sensor_cfg: &toml::value::Table,
+    ) -> Result<(), ConfigError> {
+        let fields: Vec<String> = sensor_type
+            .required
+            .iter()
+            .filter(|f| !sensor_cfg.contains_key(**f))
+            .map(|f| f.to_string())
+            .collect();
+        if fields.is_empty() {
+            return Ok(());
+        }
+        Err(ConfigError::Missing {
+            kind: kind.to_string(),
+            fields,
+        })
+    }
+
+    /// What is wrong with the config of a sensor: missing fields, and fields neither its type nor common_fields know
+    /// about - likely typos; nothing for unknown types.
+    pub(crate) fn check(
+        &self,
+        sensor_cfg: &toml::value::Table,
+        common_fields: &[&str],
+    ) -> Vec<String> {
+        let kind = sensor_cfg
+            .get("type")
+            .and_then(|v| v.as_str())
+            .unwrap_or_default();
+        let sensor_type = match self.types.get(kind) {
+            Some(sensor_type) => sensor_type,
+            None => return Vec::new(),
+        };
+        let mut res = Vec::new();
+        if let Err(err) = Registry::missing(kind, sensor_type, sensor_cfg) {
+            res.push(err.to_string());
+        }
+        for field in sensor_cfg.keys().map(|k| k.as_str()) {
+            if !sensor_type.required.contains(&field)
+                && !sensor_type.optional.contains(&field)
+                && !common_fields.contains(&field)
+            {
+                res.push(format!("unknown field {}.", field));
+            }
+        }
+        res
+    }
+
+    /// Creates a sensor of the type its config names; None for unknown types.
+    pub(crate) fn create(
+        &self,
+        name: &str,
+        sensor_cfg: &toml::value::Table,
+        state_dir: &str,
+    ) -> Result<Option<Box<dyn Sensor + Send>>, ConfigError> {
+        let kind = sensor_cfg
+            .get("type")
+            .and_then(|v| v.as_str())
+            .ok_or_else(|| {
+                ConfigError::Invalid("missing type information for a sensor.".to_string())
+            })?;
+        let sensor_type = match self.types.get(kind) {
+            Some(sensor_type) => sensor_type,
+            None => return Ok(None),
+        };
+        Registry::missing(kind, sensor_type, sensor_cfg)?;
+        (sensor_type.create)(name, sensor_cfg, state_dir).map(Some)
+    }
+}
+
+/// A value measured by a sensor, named after its column.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Reading {
+    /// The column, e.g. "fritz0_power".
+    pub name: String,
+    /// The measured value; NaN if it failed.
+    pub value: f64,
+    /// When it was actually measured (epoch secs) if the sensor knows; now otherwise.
+    pub time: Option<f64>,
+    /// How it came about; a combination of the quality flags below, 0 for a plain measurement.
+    pub quality: u8,
+}
+
+/// Quality flag of a value measured in an earlier iteration, e.g. from the cache of the slow loop.
+pub(crate) const CACHED: u8 = 1;
+/// Quality flag of a value reported instead of a failed measurement, e.g. the last one held.
+pub(crate) const HELD: u8 = 2;
+/// Quality flag of a value measured after retrying.
+pub(crate) const RETRIED: u8 = 4;
+/// Quality flag of a value that could not be measured at all.
+pub(crate) const FAILED: u8 = 8;
+
+impl Reading {
+    /// A plain measurement of now.
+    pub fn new(name: String, value: f64) -> Reading {
+        Reading {
+            name,
+            value,
+            time: None,
+            quality: 0,
+        }
+    }
+}
+
+/// The readings of a sensor measuring a fixed set of metrics, given in the order of its names.
+pub(crate) fn readings(names: Vec<String>, values: Vec<f64>) -> Vec<Reading> {
+    names
+        .into_iter()
+        .zip(values)
+        .map(|(name, value)| Reading::new(name, value))
+        .collect()
+}
+
+/// The name and value of each reading of a successful measurement; for the tests of the sensors.
+#[cfg(test)]
+pub(crate) fn values(res: Result<Vec<Reading>, SensorError>) -> Vec<(String, f64)> {
+    res.unwrap()
+        .into_iter()
+        .map(|r| (r.name, r.value))
+        .collect()
+}
+
+/// The values and times of the readings in the order of the given columns.
+///
+/// Columns w/o a reading are NaN; readings w/o a column are dropped.
+pub(crate) fn align(names: &[String], readings: &[Reading]) -> (Vec<f64>, Vec<Option<f64>>) {
+    names
+        .iter()
+        .map(|name| match readings.iter().find(|r| &r.name == name) {
+            Some(reading) => (reading.value, reading.time),
+            None => (f64::NAN, None),
+        })
+        .unzip()
+}
+
+/// Reads a small sysfs file, e.g. the name of a device; None if it is not there.
+pub(crate) fn read_sysfs(dir: &path::Path, file: &str) -> Option<String> {
+    fs::read_to_string(dir.join(file))
+        .ok()
+        .map(|c| c.trim().to_string())
+}
+
 /// Defines a basic sensor.
-pub(crate) trait Sensor {
+///
+/// A sensor that cannot be measured at all returns an error; the loop logs it and records its columns as NaN. Metrics it
+/// could not get this time are simply left out of its readings.
+pub trait Sensor {
+    /// The columns of the sensor, each prefixed with its name.
+    fn get_names(&self) -> Vec<String>;
+    /// The unit of each of the names, e.g. "W" or "0.1°C"; "unknown" where the sensor cannot tell.
+    fn get_units(&self) -> Vec<String>;
+    /// Measures now; a reading for each of the names it could get.
+    fn measure(&self) -> Result<Vec<Reading>, SensorError>;
+    /// Measures at the given time of the loop, in seconds since the epoch; for sensors deriving values over time.
+    fn measure_at(&self, _now: f64) -> Result<Vec<Reading>, SensorError> {
+        self.measure()
+    }
+    /// One-time setup before the loop starts, e.g. logging in or calibrating a chip.
+    fn init(&mut self) -> Result<(), SensorError> {
+        Ok(())
+    }
+    /// Cleanup once the loop stopped.
+    fn shutdown(&mut self) {}
+    /// Checks whether the sensor can be measured at all, e.g. whether its device is present.
+    fn validate(&self) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+}
+
+type Answer = Result<Vec<Reading>, SensorError>;
+
+/// Measures another sensor on a worker thread and fails if it does not answer within the timeout.
+///
+/// The worker of a measurement that timed out is left running - a blocking request cannot be cancelled - and holds the
+/// sensor until it finishes; until then measurements fail right away instead of starting another worker, so a sensor
+/// that keeps hanging costs one thread at most. Its late answer is dropped, as it belongs to an earlier row.
+pub(crate) struct TimeoutSensor {
+    names: Vec<String>,
+    units: Vec<String>,
+    timeout: Duration,
+    inner: Arc<Mutex<Box<dyn Sensor + Send>>>,
+    // the answer of the worker that timed out.
+    pending: RefCell<Option<mpsc::Receiver<Answer>>>,
+}
+
+impl TimeoutSensor {
+    pub(crate) fn new(inner: Box<dyn Sensor + Send>, timeout: Duration) -> TimeoutSensor {
+        TimeoutSensor {
+            names: inner.get_names(),
+            units: inner.get_units(),
+            timeout,
+            inner: Arc::new(Mutex::new(inner)),
+            pending: RefCell::new(None),
+        }
+    }
+
+    fn timed_out(&self) -> SensorError {
+        SensorError::Timeout(format!("no answer within {:?}.", self.timeout))
+    }
+}
+
+impl Sensor for TimeoutSensor {
+    fn get_names(&self) -> Vec<String> {
+        self.names.clone()
+    }
+
+    fn get_units(&self) -> Vec<String> {
+        self.units.clone()
+    }
+
+    fn measure(&self) -> Result<Vec<Reading>, SensorError> {
+        let mut pending = self.pending.borrow_mut();
+        if let Some(rx) = pending.as_ref() {
+            if let Err(mpsc::TryRecvError::Empty) = rx.try_recv() {
+                return Err(SensorError::Timeout(
+                    "still waiting for an earlier measurement.".to_string(),
+                ));
+            }
+            *pending = None;
+        }
+        let (tx, rx) = mpsc::channel();
+        let inner = Arc::clone(&self.inner);
+        thread::spawn(move || {
+            // a sensor that panicked before is as good as any other.
+            let sensor = inner.lock().unwrap_or_else(|e| e.into_inner());
+            let _ = tx.send(sensor.measure());
+        });
+        match rx.recv_timeout(self.timeout) {
+            Ok(answer) => answer,
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                *pending = Some(rx);
+                Err(self.timed_out())
+            }
+            Err(mpsc::RecvTimeoutError::Disconnected) => Err(SensorError::Protocol(
+                "the sensor panicked while measuring.".to_string(),
+            )),
+        }
+    }
+
+    fn init(&mut self) -> Result<(), SensorError> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner()).init()
+    }
+
+    fn shutdown(&mut self) {
+        match self.inner.try_lock() {
+            Ok(mut sensor) => sensor.shutdown(),
+            Err(TryLockError::Poisoned(e)) => e.into_inner().shutdown(),
+            // still hanging; the worker ends with the process.
+            Err(TryLockError::WouldBlock) => {}
+        }
+    }
+
+    fn validate(&self) -> Result<(), Box<dyn Error>> {
+        match self.inner.try_lock() {
+            Ok(sensor) => sensor.validate(),
+            Err(TryLockError::Poisoned(e)) => e.into_inner().validate(),
+            Err(TryLockError::WouldBlock) => Err(Box::new(self.timed_out())),
+        }
+    }
+}
+
+/// Measures another sensor again after transient failures - HTTP errors and timeouts - waiting the delay in between.
+pub(crate) struct RetrySensor {
+    inner: Box<dyn Sensor>,
+    retries: u32,
+    delay: Duration,
+}
+
+impl RetrySensor {
+    pub(crate) fn new(inner: Box<dyn Sensor>, retries: u32, delay: Duration) -> RetrySensor {
+        RetrySensor {
+            inner,
+            retries,
+            delay,
+        }
+    }
+}
+
+impl Sensor for RetrySensor {
+    fn get_names(&self) -> Vec<String> {
+        self.inner.get_names()
+    }
+
+    fn get_units(&self) -> Vec<String> {
+        self.inner.get_units()
+    }
+
+    fn measure(&self) -> Result<Vec<Reading>, SensorError> {
+        let mut attempt = 0;
+        loop {
+            match self.inner.measure() {
+                Err(SensorError::Http(_) | SensorError::Timeout(_)) if attempt < self.retries => {
+                    attempt += 1;
+                    thread::sleep(self.delay);
+                }
+                Ok(readings) if attempt > 0 => {
+                    return Ok(readings
+                        .into_iter()
+                        .map(|r| Reading {
+                            quality: r.quality | RETRIED,
+                            ..r
+                        })
+                        .collect())
+                }
+                res => return res,
+            }
+        }
+    }
+
+    fn init(&mut self) -> Result<(), SensorError> {
+        self.inner.init()
+    }
+
+    fn shutdown(&mut self) {
+        self.inner.shutdown()
+    }
+
+    fn validate(&self) -> Result<(), Box<dyn Error>> {
+        self.inner.validate()
+    }
+}
+
+/// Defines a component that works on the (named) values of an iteration after all sensors ran.
+pub(crate) trait Component {
     fn get_names(&self) -> Vec<String>;
-    fn measure(&self) -> Vec<f64>;
+    fn update(&mut self, names: &[String], values: &[f64]) -> Vec<f64>;
+    /// Columns the outputs are computed from; None if that might be any of the columns it sees.
+    fn get_inputs(&self) -> Option<Vec<String>> {
+        None
+    }
+}
+
+/// Something rules switch on and off, e.g. a smart plug in front of a load.
+pub(crate) trait Actuator {
+    fn set_state(&mut self, on: bool) -> Result<(), SensorError>;
+    fn get_state(&self) -> Result<bool, SensorError>;
+}
+
+/// Unit suffixes devices append to values; longer ones first so e.g. 'kWh' is not taken for 'Wh'.
+const UNITS: [&str; 15] = [
+    "kWh", "Wh", "kW", "mW", "W", "mV", "V", "mA", "A", "Hz", "°C", "°F", "%", "ppm", "lx",
+];
+
+/// Characters used to group digits that are dropped right away.
+const GROUPING: [char; 4] = [' ', '\u{a0}', '\u{202f}', '\''];
+
+/// Parses a number as devices send it. Strict parsing only accepts what Rust does.
+///
+/// Otherwise known units are dropped, as is a leading '+'. Both '.' and ',' are accepted as
+/// decimal separator: when both appear the last one is, and a separator appearing more than once
+/// groups thousands. A single separator is always taken as decimal one - so "1,234" is 1.234 - as
+/// devices sending decimal commas are far more common than ones grouping thousands.
+pub(crate) fn parse_number(text: &str, strict: bool) -> Result<f64, Box<dyn Error>> {
+    let invalid = || Box::<dyn Error>::from(format!("not a number: '{}'.", text.trim()));
+    if strict {
+        return text.trim().parse().map_err(|_| invalid());
+    }
+    let mut value = text.trim();
+    if let Some(unit) = UNITS.iter().find(|u| value.ends_with(*u)) {
+        value = value[..value.len() - unit.len()].trim_end();
+    }
+    let value: String = value.chars().filter(|c| !GROUPING.contains(c)).collect();
+    let value = value.strip_prefix('+').unwrap_or(&value);
+
+    let split = |i: usize| (&value[..i], Some(&value[i + 1..]));
+    let (whole, fraction) = match (value.rfind(','), value.rfind('.')) {
+        (Some(c), Some(d)) => split(c.max(d)),
+        (Some(i), None) | (None, Some(i)) if value.matches(&value[i..i + 1]).count() == 1 => {
+            split(i)
+        }
+        _ => (value, None),
+    };
+    let whole = ungroup(whole).ok_or_else(invalid)?;
+    let number = match fraction {
+        Some(fraction) => format!("{}.{}", whole, fraction),
+        None => whole,
+    };
+    if !number
+        .chars()
+        .all(|c| c.is_ascii_digit() || "+-.eE".contains(c))
+    {
+        return Err(invalid());
+    }
+    number.parse().map_err(|_| invalid())
+}
+
+/// Removes the thousands separators; groups after the first one must have three digits.
+fn ungroup(whole: &str) -> Option<String> {
+    let separator = match (whole.contains(','), whole.contains('.')) {
+        (false, false) => return Some(whole.to_string()),
+        (true, true) => return None,
+        (true, false) => ',',
+        (false, true) => '.',
+    };
+    let groups: Vec<&str> = whole.split(separator).collect();
+    let first = groups[0].trim_start_matches('-');
+    if first.is_empty() || first.len() > 3 || groups[1..].iter().any(|g| g.len() != 3) {
+        return None;
+    }
+    Some(groups.concat())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A sensor taking the given time to measure; counts how often it was measured.
+    struct SlowSensor {
+        delay: Duration,
+        count: Arc<std::sync::atomic::AtomicU32>,
+    }
+
+    impl Sensor for SlowSensor {
+        fn get_names(&self) -> Vec<String> {
+            vec!["slow_power".to_string()]
+        }
+
+        fn get_units(&self) -> Vec<String> {
+            vec!["W".to_string()]
+        }
+
+        fn measure(&self) -> Result<Vec<Reading>, SensorError> {
+            let count = self.count.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            thread::sleep(self.delay);
+            Ok(readings(self.get_names(), vec![count as f64]))
+        }
+    }
+
+    fn slow(delay_ms: u64) -> (TimeoutSensor, Arc<std::sync::atomic::AtomicU32>) {
+        let count = Arc::new(std::sync::atomic::AtomicU32::new(0));
+        let sensor = SlowSensor {
+            delay: Duration::from_millis(delay_ms),
+            count: Arc::clone(&count),
+        };
+        (
+            TimeoutSensor::new(Box::new(sensor), Duration::from_millis(100)),
+            count,
+        )
+    }
+
+    /// A sensor failing with the given errors before it succeeds.
+    struct FlakySensor {
+        errors: RefCell<Vec<SensorError>>,
+        count: Arc<std::sync::atomic::AtomicU32>,
+    }
+
+    impl Sensor for FlakySensor {
+        fn get_names(&self) -> Vec<String> {
+            vec!["flaky_power".to_string()]
+        }
+
+        fn get_units(&self) -> Vec<String> {
+            vec!["W".to_string()]
+        }
+
+        fn measure(&self) -> Result<Vec<Reading>, SensorError> {
+            self.count.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            match self.errors.borrow_mut().pop() {
+                Some(err) => Err(err),
+                None => Ok(readings(self.get_names(), vec![42.0])),
+            }
+        }
+    }
+
+    fn flaky(
+        errors: Vec<SensorError>,
+        retries: u32,
+    ) -> (RetrySensor, Arc<std::sync::atomic::AtomicU32>) {
+        let count = Arc::new(std::sync::atomic::AtomicU32::new(0));
+        let sensor = FlakySensor {
+            errors: RefCell::new(errors),
+            count: Arc::clone(&count),
+        };
+        (
+            RetrySensor::new(Box::new(sensor), retries, Duration::from_millis(1)),
+            count,
+        )
+    }
+
+    fn registry() -> Registry {
+        let mut registry = Registry::default();
+        registry.register(SensorType {
+            name: "slow",
+            required: &["delay_ms"],
+            optional: &["label"],
+            create: |_, sensor_cfg, _| {
+                let delay = sensor_cfg["delay_ms"].as_integer().ok_or_else(|| {
+                    ConfigError::Invalid("delay_ms must be an integer.".to_string())
+                })?;
+                Ok(Box::new(SlowSensor {
+                    delay: Duration::from_millis(delay as u64),
+                    count: Arc::new(std::sync::atomic::AtomicU32::new(0)),
+                }))
+            },
+        });
+        registry
+    }
+
+    fn lenient(text: &str) -> f64 {
+        parse_number(text, false).unwrap()
+    }
+
+    // Tests for success.
+
+    #[test]
+    fn test_registry_for_success() {
+        let sensor_cfg: toml::value::Table = toml::from_str("type='slow'\ndelay_ms=0").unwrap();
+        let sensor = registry()
+            .create("foo", &sensor_cfg, "state")
+            .unwrap()
+            .unwrap();
+        assert_eq!(sensor.get_names(), vec!["slow_power"]);
+        // unknown types are left to the caller.
+        let sensor_cfg: toml::value::Table = toml::from_str("type='fast'").unwrap();
+        assert!(registry()
+            .create("foo", &sensor_cfg, "state")
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn test_retry_for_success() {
+        let http = || SensorError::Http("Status code was not 200; but: 502.".to_string());
+        let (sensor, count) = flaky(vec![http(), http()], 2);
+        assert_eq!(sensor.measure().unwrap()[0].value, 42.0);
+        assert_eq!(count.load(std::sync::atomic::Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn test_timeout_for_success() {
+        let (sensor, _) = slow(0);
+        assert_eq!(sensor.get_names(), vec!["slow_power"]);
+        assert_eq!(sensor.get_units(), vec!["W"]);
+        assert_eq!(sensor.measure().unwrap()[0].value, 0.0);
+        assert_eq!(sensor.measure().unwrap()[0].value, 1.0);
+    }
+
+    #[test]
+    fn test_parse_number_for_success() {
+        assert_eq!(lenient("42"), 42.0);
+        assert_eq!(lenient("  42\n"), 42.0);
+        assert_eq!(lenient("-3.5"), -3.5);
+        assert_eq!(lenient("1e3"), 1000.0);
+        assert_eq!(parse_number("12.5", true).unwrap(), 12.5);
+        assert_eq!(parse_number("+12.5\n", true).unwrap(), 12.5);
+    }
+
+    // Tests for failure.
+
+    #[test]
+    fn test_registry_for_failure() {
+        let sensor_cfg: toml::value::Table = toml::from_str("type='slow'").unwrap();
+        let err = registry()
+            .create("foo", &sensor_cfg, "state")
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.to_string(),
+            "a slow sensor requires the following fields to be set: delay_ms."
+        );
+        let sensor_cfg: toml::value::Table = toml::from_str("type='slow'\ndelay_ms='1s'").unwrap();
+        assert!(matches!(
+            registry().create("foo", &sensor_cfg, "state"),
+            Err(ConfigError::Invalid(_))
+        ));
+        assert!(registry()
+            .create("foo", &toml::value::Table::new(), "state")
+            .is_err());
+    }
+
+    #[test]
+    #[should_panic(expected = "sensor type slow registered twice.")]
+    fn test_register_for_failure() {
+        let mut registry = registry();
+        registry.register(SensorType {
+            name: "slow",
+            required: &[],
+            optional: &[],
+            create: |_, _, _| Err(ConfigError::Invalid("unused.".to_string())),
+        });
+    }
+
+    #[test]
+    fn test_retry_for_failure() {
+        let http = || SensorError::Http("Status code was not 200; but: 502.".to_string());
+        let (sensor, count) = flaky(vec![http(), http(), http()], 2);
+        assert!(matches!(sensor.measure(), Err(SensorError::Http(_))));
+        assert_eq!(count.load(std::sync::atomic::Ordering::SeqCst), 3);
+        // the next measurement gets its own retries.
+        assert_eq!(sensor.measure().unwrap()[0].value, 42.0);
+        assert_eq!(count.load(std::sync::atomic::Ordering::SeqCst), 4);
+    }
+
+    #[test]
+    fn test_timeout_for_failure() {
+        let (sensor, _) = slow(300);
+        assert!(matches!(sensor.measure(), Err(SensorError::Timeout(_))));
+    }
+
+    #[test]
+    fn test_parse_number_for_failure() {
+        for text in [
+            "",
+            " ",
+            "V",
+            "-",
+            "abc",
+            "12 apples",
+            "NaN",
+            "inf",
+            "1,2,3",
+            "1.2.3,4",
+            "12,5.3",
+            "1.234.56",
+            "1,234.567,8",
+            "0x1F",
+            "12 V V",
+        ] {
+            assert!(parse_number(text, false).is_err(), "{:?}", text);
+        }
+        // strict parsing fails loudly on anything messy.
+        for text in ["230 V", "12,5", "1.234,5", "1 234"] {
+            assert!(parse_number(text, true).is_err(), "{:?}", text);
+        }
+    }
+
+    // Tests for sanity.
+
+    #[test]
+    fn test_registry_check_for_sanity() {
+        let sensor_cfg: toml::value::Table =
+            toml::from_str("type='slow'\nlabel='x'\nretries=2\ndelay=1").unwrap();
+        assert_eq!(
+            registry().check(&sensor_cfg, &["type", "retries"]),
+            vec![
+                "a slow sensor requires the following fields to be set: delay_ms.",
+                "unknown field delay."
+            ]
+        );
+        let sensor_cfg: toml::value::Table = toml::from_str("type='fast'\nfoo=1").unwrap();
+        assert!(registry().check(&sensor_cfg, &["type"]).is_empty());
+    }
+
+    #[test]
+    fn test_retry_for_sanity() {
+        // answers that will not change are not retried.
+        let (sensor, count) = flaky(vec![SensorError::Parse("not a number.".to_string())], 2);
+        assert!(matches!(sensor.measure(), Err(SensorError::Parse(_))));
+        assert_eq!(count.load(std::sync::atomic::Ordering::SeqCst), 1);
+        // timeouts are.
+        let (sensor, count) = flaky(vec![SensorError::Timeout("no answer.".to_string())], 2);
+        assert_eq!(sensor.measure().unwrap()[0].value, 42.0);
+        assert_eq!(count.load(std::sync::atomic::Ordering::SeqCst), 2);
+        // no retries configured.
+        let http = || SensorError::Http("Status code was not 200; but: 502.".to_string());
+        let (sensor, count) = flaky(vec![http()], 0);
+        assert!(sensor.measure().is_err());
+        assert_eq!(count.load(std::sync::atomic::Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_timeout_for_sanity() {
+        let (sensor, count) = slow(300);
+        let start = std::time::Instant::now();
+        assert!(matches!(sensor.measure(), Err(SensorError::Timeout(_))));
+        // while the worker hangs, measurements fail right away; w/o starting more workers.
+        for _ in 0..5 {
+            assert!(matches!(sensor.measure(), Err(SensorError::Timeout(_))));
+        }
+        assert!(start.elapsed() < Duration::from_millis(300));
+        assert_eq!(count.load(std::sync::atomic::Ordering::SeqCst), 1);
+        assert!(sensor.validate().is_err());
+        // once it is done, its late answer is dropped and the next measurement starts afresh.
+        thread::sleep(Duration::from_millis(400));
+        assert!(matches!(sensor.measure(), Err(SensorError::Timeout(_))));
+        assert_eq!(count.load(std::sync::atomic::Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_align_for_sanity() {
+        let names = vec![
+            "fritz_power".to_string(),
+            "fritz_energy".to_string(),
+            "fritz_temperature".to_string(),
+        ];
+        // in any order; missing and unknown ones are fine.
+        let readings = vec![
+            Reading {
+                name: "fritz_temperature".to_string(),
+                value: 21.5,
+                time: Some(960.0),
+                quality: 0,
+            },
+            Reading::new("fritz_voltage".to_string(), 230.0),
+            Reading::new("fritz_power".to_string(), 50.0),
+        ];
+        let (values, times) = align(&names, &readings);
+        assert_eq!(values[0], 50.0);
+        assert!(values[1].is_nan());
+        assert_eq!(values[2], 21.5);
+        assert_eq!(times, vec![None, None, Some(960.0)]);
+    }
+
+    #[test]
+    fn test_units_for_sanity() {
+        assert_eq!(lenient("230 V"), 230.0);
+        assert_eq!(lenient("230V"), 230.0);
+        assert_eq!(lenient("12.5 kWh"), 12.5);
+        assert_eq!(lenient("800 Wh"), 800.0);
+        assert_eq!(lenient("1500mW"), 1500.0);
+        assert_eq!(lenient("0,45 A"), 0.45);
+        assert_eq!(lenient("21.5 °C"), 21.5);
+        assert_eq!(lenient("+12,5°C"), 12.5);
+        assert_eq!(lenient("55 %"), 55.0);
+        assert_eq!(lenient("49,98 Hz"), 49.98);
+    }
+
+    #[test]
+    fn test_separators_for_sanity() {
+        // decimal commas.
+        assert_eq!(lenient("1234,5"), 1234.5);
+        assert_eq!(lenient("-0,5"), -0.5);
+        assert_eq!(lenient("+7"), 7.0);
+        // both separators; the last one is the decimal one.
+        assert_eq!(lenient("1.234,5"), 1234.5);
+        assert_eq!(lenient("1,234.5"), 1234.5);
+        assert_eq!(lenient("-1.234.567,89"), -1234567.89);
+        // repeated separators group thousands.
+        assert_eq!(lenient("1,234,567"), 1234567.0);
+        assert_eq!(lenient("1.000.000"), 1000000.0);
+        // spaces, no-break spaces and apostrophes group thousands too.
+        assert_eq!(lenient("1 234,5"), 1234.5);
+        assert_eq!(lenient("1\u{a0}234"), 1234.0);
+        assert_eq!(lenient("1'234.5"), 1234.5);
+    }
+
+    #[test]
+    fn test_ambiguous_for_sanity() {
+        // deliberate: a single separator is always a decimal one, whatever follows.
+        assert_eq!(lenient("1,234"), 1.234);
+        assert_eq!(lenient("1.234"), 1.234);
+        assert_eq!(lenient("1,234 W"), 1.234);
+        assert_eq!(lenient("0,5"), lenient("0.5"));
+    }
 }
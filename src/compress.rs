@@ -0,0 +1,82 @@
+use std::fs;
+use std::io;
+use std::thread;
+
+use flate2::write::GzEncoder;
+use flate2::Compression;
+
+/// Gzips a file into <path>.gz; the original is only removed once the compressed file is complete.
+///
+/// The compressed file is written under a temporary name first, so a crash leaves the original untouched.
+pub(crate) fn compress(path: &str) -> io::Result<String> {
+    let target = format!("{}.gz", path);
+    let tmp = format!("{}.tmp", target);
+    let res = (|| {
+        let mut input = fs::File::open(path)?;
+        let mut encoder = GzEncoder::new(fs::File::create(&tmp)?, Compression::default());
+        io::copy(&mut input, &mut encoder)?;
+        encoder.finish()?.sync_all()?;
+        fs::rename(&tmp, &target)
+    })();
+    if let Err(err) = res {
+        let _ = fs::remove_file(&tmp);
+        return Err(err);
+    }
+    fs::remove_file(path)?;
+    Ok(target)
+}
+
+/// Compresses a file in the background; the loop never waits for it.
+pub(crate) fn spawn(path: String) -> thread::JoinHandle<()> {
+    thread::spawn(move || match compress(&path) {
+        Ok(target) => eprintln!("Compressed {} to {}.", path, target),
+        Err(err) => eprintln!("Could not compress {}; keeping it: {}", path, err),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use flate2::read::GzDecoder;
+
+    use super::*;
+
+    // Tests for success.
+
+    #[test]
+    fn test_compress_for_success() {
+        let content = "timestamp,pv_power\n1,100\n".repeat(100);
+        fs::write("compress_test0.csv", &content).unwrap();
+        spawn("compress_test0.csv".to_string()).join().unwrap();
+
+        assert!(fs::metadata("compress_test0.csv").is_err());
+        assert!(fs::metadata("compress_test0.csv.gz.tmp").is_err());
+        let mut decompressed = String::new();
+        GzDecoder::new(fs::File::open("compress_test0.csv.gz").unwrap())
+            .read_to_string(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, content);
+        fs::remove_file("compress_test0.csv.gz").unwrap();
+    }
+
+    // Tests for failure.
+
+    #[test]
+    fn test_compress_for_failure() {
+        // the compressed file cannot be written; the original stays.
+        fs::write("compress_test1.csv", "timestamp\n1\n").unwrap();
+        fs::create_dir("compress_test1.csv.gz.tmp").unwrap();
+        assert!(compress("compress_test1.csv").is_err());
+        assert_eq!(
+            fs::read_to_string("compress_test1.csv").unwrap(),
+            "timestamp\n1\n"
+        );
+        assert!(fs::metadata("compress_test1.csv.gz").is_err());
+
+        // nothing to compress.
+        assert!(compress("compress_test2.csv").is_err());
+        fs::remove_dir("compress_test1.csv.gz.tmp").unwrap();
+        fs::remove_file("compress_test1.csv").unwrap();
+    }
+}
@@ -2,12 +2,13 @@ use std::collections;
 use std::fs;
 
 /// Struct holding the config info.
-pub(crate) struct Config {
-    pub(crate) data: collections::HashMap<String, toml::Value>,
+pub struct Config {
+    /// The sections of the configuration by name.
+    pub data: collections::HashMap<String, toml::Value>,
 }
 
 /// Load the configuration.
-pub(crate) fn load_config(filename: &str) -> Config {
+pub fn load_config(filename: &str) -> Config {
     let contents: String = read_config(filename);
     let data: collections::HashMap<String, toml::Value> = get_config(contents);
     Config { data }
@@ -75,21 +76,15 @@ mod tests {
     #[test]
     fn test_load_config_for_sanity() {
         let cfg: Config = load_config("defaults.toml");
-        assert_eq!(cfg.data.contains_key("general"), true);
-        assert_eq!(
-            cfg.data["general"]
-                .as_table()
-                .unwrap()
-                .contains_key("fast_loop"),
-            true
-        );
-        assert_eq!(
-            cfg.data["general"]
-                .as_table()
-                .unwrap()
-                .contains_key("slow_loop"),
-            true
-        );
+        assert!(cfg.data.contains_key("general"));
+        assert!(cfg.data["general"]
+            .as_table()
+            .unwrap()
+            .contains_key("fast_loop"));
+        assert!(cfg.data["general"]
+            .as_table()
+            .unwrap()
+            .contains_key("slow_loop"));
     }
 
     #[test]
@@ -0,0 +1,258 @@
+use serde::{Deserialize, Serialize};
+
+use crate::common;
+use crate::state;
+
+/// How often (in iterations) the total is written to the state store.
+const PERSIST_EVERY: u32 = 10;
+
+/// Whether the power is drawn from the grid (and costs) or fed into it (and earns).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Direction {
+    Import,
+    Export,
+}
+
+impl Direction {
+    pub(crate) fn parse(name: &str) -> Direction {
+        match name {
+            "import" => Direction::Import,
+            "export" => Direction::Export,
+            other => panic!(
+                "direction must be one of 'import' or 'export'; got: {}.",
+                other
+            ),
+        }
+    }
+
+    fn metric(&self) -> &'static str {
+        match self {
+            Direction::Import => "cost",
+            Direction::Export => "revenue",
+        }
+    }
+}
+
+/// The money spent or earned on the energy flowing in one direction.
+#[derive(Serialize, Deserialize, Default, Debug, PartialEq)]
+pub(crate) struct Bill {
+    total: f64,
+    // not persisted; a restart is a gap anyway.
+    #[serde(skip)]
+    last: Option<(f64, f64, f64)>,
+}
+
+impl Bill {
+    /// Adds a sample of the power (W) and the price (per kWh); returns what the interval since the previous one cost or
+    /// earned. The energy of the interval is billed at the price at its start, which is the one that was in effect.
+    /// Intervals longer than max_gap or with a missing value on either side are skipped.
+    pub(crate) fn add(&mut self, timestamp: f64, power: f64, price: f64, max_gap: f64) -> f64 {
+        let power = power.max(0.0);
+        let res = match self.last {
+            Some((t0, p0, price0))
+                if !p0.is_nan() && !price0.is_nan() && !power.is_nan() && !price.is_nan() =>
+            {
+                let dt = timestamp - t0;
+                if dt > 0.0 && dt <= max_gap {
+                    // trapezoid; Wh to kWh.
+                    (p0 + power) / 2.0 * dt / 3600.0 / 1000.0 * price0
+                } else {
+                    f64::NAN
+                }
+            }
+            _ => f64::NAN,
+        };
+        if !res.is_nan() {
+            self.total += res;
+        }
+        self.last = Some((timestamp, power, price));
+        res
+    }
+}
+
+/// Bills a power column at the price from another one, per interval and in total; the total survives restarts.
+///
+/// The intervals are taken from the timestamps of the rows, so a late or skipped iteration is billed for as long as it
+/// really took.
+pub struct CostComponent {
+    name: String,
+    power_column: String,
+    price_column: String,
+    direction: Direction,
+    max_gap: f64,
+    state_dir: String,
+    bill: Bill,
+    updates: u32,
+}
+
+impl CostComponent {
+    pub(crate) fn new(
+        name: String,
+        power_column: String,
+        price_column: String,
+        direction: Direction,
+        max_gap: f64,
+        state_dir: String,
+    ) -> CostComponent {
+        let bill: Bill = state::load(&state_dir, &name).unwrap_or_default();
+        CostComponent {
+            name,
+            power_column,
+            price_column,
+            direction,
+            max_gap,
+            state_dir,
+            bill,
+            updates: 0,
+        }
+    }
+}
+
+impl common::Component for CostComponent {
+    fn get_names(&self) -> Vec<String> {
+        let metric = self.direction.metric();
+        vec![
+            format!("{}_{}", self.name, metric),
+            format!("{}_{}_total", self.name, metric),
+        ]
+    }
+
+    fn update(&mut self, names: &[String], values: &[f64]) -> Vec<f64> {
+        let lookup = |column: &str| match names.iter().position(|n| n == column) {
+            Some(i) => values[i],
+            None => f64::NAN,
+        };
+        let amount = self.bill.add(
+            values[0],
+            lookup(&self.power_column),
+            lookup(&self.price_column),
+            self.max_gap,
+        );
+
+        self.updates += 1;
+        if self.updates.is_multiple_of(PERSIST_EVERY) {
+            if let Err(err) = state::save(&self.state_dir, &self.name, &self.bill) {
+                eprintln!(
+                    "Could not persist the {}: {}.",
+                    self.direction.metric(),
+                    err
+                );
+            }
+        }
+        vec![amount, self.bill.total]
+    }
+
+    fn get_inputs(&self) -> Option<Vec<String>> {
+        Some(vec![self.power_column.clone(), self.price_column.clone()])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::common::Component;
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-9
+    }
+
+    fn names() -> Vec<String> {
+        vec![
+            "timestamp".to_string(),
+            "grid_import".to_string(),
+            "tibber_price".to_string(),
+        ]
+    }
+
+    // Tests for success.
+
+    #[test]
+    fn test_add_for_success() {
+        let mut bill = Bill::default();
+        // nothing to bill yet.
+        assert!(bill.add(0.0, 2000.0, 0.25, 900.0).is_nan());
+        // 2 kW for 15 mins at 0.25.
+        assert!(close(bill.add(900.0, 2000.0, 0.40, 900.0), 0.125));
+        // the price changed at the start of this interval.
+        assert!(close(bill.add(1800.0, 2000.0, 0.40, 900.0), 0.2));
+        // uneven intervals are billed as long as they took.
+        assert!(close(bill.add(1836.0, 2000.0, 0.40, 900.0), 0.008));
+        assert!(close(bill.total, 0.333));
+    }
+
+    // Tests for failure.
+
+    #[test]
+    #[should_panic(expected = "direction must be one of 'import' or 'export'; got: both.")]
+    fn test_parse_for_failure() {
+        Direction::parse("both");
+    }
+
+    #[test]
+    fn test_add_for_failure() {
+        let mut bill = Bill::default();
+        bill.add(0.0, 2000.0, 0.25, 900.0);
+        // no price: skipped, on both sides.
+        assert!(bill.add(900.0, 2000.0, f64::NAN, 900.0).is_nan());
+        assert!(bill.add(1800.0, 2000.0, 0.25, 900.0).is_nan());
+        // a gap.
+        assert!(bill.add(3600.0, 2000.0, 0.25, 900.0).is_nan());
+        assert_eq!(bill.total, 0.0);
+        // switching to exporting, which costs nothing.
+        assert_eq!(bill.add(4500.0, -2000.0, 0.25, 900.0), 0.0625);
+        assert_eq!(bill.add(5400.0, -2000.0, 0.25, 900.0), 0.0);
+    }
+
+    // Tests for sanity.
+
+    #[test]
+    fn test_update_for_sanity() {
+        let dir = "cost_test0";
+        let mut component = CostComponent::new(
+            "grid".to_string(),
+            "grid_import".to_string(),
+            "tibber_price".to_string(),
+            Direction::Import,
+            900.0,
+            dir.to_string(),
+        );
+        assert_eq!(component.get_names(), vec!["grid_cost", "grid_cost_total"]);
+        for i in 0..10 {
+            component.update(&names(), &[i as f64 * 360.0, 1000.0, 0.30]);
+        }
+        // 9 intervals of 0.1 kWh.
+        assert!(close(component.bill.total, 0.27));
+
+        // persisted; and restored after a restart.
+        let mut component = CostComponent::new(
+            "grid".to_string(),
+            "grid_import".to_string(),
+            "tibber_price".to_string(),
+            Direction::Import,
+            900.0,
+            dir.to_string(),
+        );
+        let res = component.update(&names(), &[3600.0, 1000.0, 0.30]);
+        assert!(res[0].is_nan());
+        assert!(close(res[1], 0.27));
+        let res = component.update(&names(), &[3960.0, 1000.0, 0.30]);
+        assert!(close(res[0], 0.03));
+        assert!(close(res[1], 0.3));
+        fs::remove_dir_all(dir).unwrap();
+
+        let component = CostComponent::new(
+            "feedin".to_string(),
+            "grid_export".to_string(),
+            "tibber_price".to_string(),
+            Direction::Export,
+            900.0,
+            "cost_test1".to_string(),
+        );
+        assert_eq!(
+            component.get_names(),
+            vec!["feedin_revenue", "feedin_revenue_total"]
+        );
+    }
+}
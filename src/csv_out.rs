@@ -0,0 +1,226 @@
+use crate::tz;
+
+/// How rows are written to CSV files; the default is plain comma separated values.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Format {
+    delimiter: char,
+    /// Fixed number of decimal places; None for the shortest representation of each value.
+    decimals: Option<usize>,
+    quote: bool,
+    /// Adds a datetime column after the timestamp (the first column), in the given zone.
+    datetime: Option<tz::Zone>,
+    /// Written for missing values (NaN); an empty cell by default.
+    missing: String,
+}
+
+impl Default for Format {
+    fn default() -> Self {
+        Format {
+            delimiter: ',',
+            decimals: None,
+            quote: false,
+            datetime: None,
+            missing: String::new(),
+        }
+    }
+}
+
+impl Format {
+    /// Delimiters can be ',', ';' or a tab (written as 'tab' or "\t").
+    pub(crate) fn new(
+        delimiter: &str,
+        decimals: Option<usize>,
+        quote: bool,
+        datetime: Option<tz::Zone>,
+        missing: String,
+    ) -> Format {
+        let delimiter = match delimiter {
+            "," => ',',
+            ";" => ';',
+            "\t" | "tab" => '\t',
+            _ => panic!(
+                "the CSV delimiter must be one of ',', ';' or 'tab'; got: {}.",
+                delimiter
+            ),
+        };
+        Format {
+            delimiter,
+            decimals,
+            quote,
+            datetime,
+            missing,
+        }
+    }
+
+    fn field(&self, text: &str) -> String {
+        if self.quote {
+            format!("\"{}\"", text.replace('"', "\"\""))
+        } else {
+            text.to_string()
+        }
+    }
+
+    pub(crate) fn delimiter(&self) -> char {
+        self.delimiter
+    }
+
+    /// A missing value as written to the file.
+    pub(crate) fn missing(&self) -> String {
+        self.field(&self.missing)
+    }
+
+    /// The fields of a line written in this format; unquoted if quoting is on.
+    pub(crate) fn split(&self, line: &str) -> Vec<String> {
+        line.split(self.delimiter)
+            .map(
+                |f| match f.strip_prefix('"').and_then(|f| f.strip_suffix('"')) {
+                    Some(unquoted) if self.quote => unquoted.replace("\"\"", "\""),
+                    _ => f.to_string(),
+                },
+            )
+            .collect()
+    }
+
+    /// The column names of a header written in this format; without the datetime column it adds.
+    pub(crate) fn columns(&self, header: &str) -> Vec<String> {
+        let mut names = self.split(header);
+        if self.datetime.is_some() && names.len() > 1 {
+            names.remove(1);
+        }
+        names
+    }
+
+    pub(crate) fn header(&self, names: &[String]) -> String {
+        let mut fields: Vec<String> = names.iter().map(|n| self.field(n)).collect();
+        if self.datetime.is_some() && !fields.is_empty() {
+            fields.insert(1, self.field("datetime"));
+        }
+        fields.join(&self.delimiter.to_string())
+    }
+
+    /// The line of units below the header; the datetime column has none.
+    pub(crate) fn units(&self, units: &[String]) -> String {
+        let mut fields: Vec<String> = units.iter().map(|u| self.field(u)).collect();
+        if self.datetime.is_some() && !fields.is_empty() {
+            fields.insert(1, self.field(""));
+        }
+        fields.join(&self.delimiter.to_string())
+    }
+
+    pub(crate) fn row(&self, values: &[f64]) -> String {
+        let mut fields: Vec<String> = values
+            .iter()
+            .map(|v| match self.decimals {
+                _ if v.is_nan() => self.field(&self.missing),
+                Some(decimals) => self.field(&format!("{:.*}", decimals, v)),
+                None => self.field(&v.to_string()),
+            })
+            .collect();
+        if let (Some(zone), Some(timestamp)) = (self.datetime, values.first()) {
+            fields.insert(1, self.field(&zone.rfc3339(*timestamp)));
+        }
+        fields.join(&self.delimiter.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names() -> Vec<String> {
+        vec!["timestamp".to_string(), "pv_power".to_string()]
+    }
+
+    // Tests for success.
+
+    #[test]
+    fn test_format_for_success() {
+        let format = Format::new(";", Some(2), true, None, String::new());
+        assert_eq!(format.header(&names()), "\"timestamp\";\"pv_power\"");
+        assert_eq!(
+            format.row(&[1700000000.0, 0.1 + 0.2]),
+            "\"1700000000.00\";\"0.30\""
+        );
+
+        let format = Format::new("tab", Some(0), false, None, String::new());
+        assert_eq!(format.row(&[1.0, 2.5, -1.0]), "1\t2\t-1");
+    }
+
+    // Tests for failure.
+
+    #[test]
+    #[should_panic]
+    fn test_delimiter_for_failure() {
+        Format::new("|", None, false, None, String::new());
+    }
+
+    #[test]
+    fn test_missing_values_for_failure() {
+        // missing values are told apart from legitimate negative readings.
+        let format = Format::new(",", Some(1), false, None, String::new());
+        assert_eq!(format.row(&[1.0, -1.0, f64::NAN]), "1.0,-1.0,");
+        let format = Format::new(";", None, true, None, "NaN".to_string());
+        assert_eq!(format.row(&[1.0, f64::NAN]), "\"1\";\"NaN\"");
+        let format = Format::new(",", None, false, None, "n/a".to_string());
+        assert_eq!(format.row(&[1.0, f64::NAN]), "1,n/a");
+    }
+
+    // Tests for sanity.
+
+    #[test]
+    fn test_default_for_sanity() {
+        // exactly what was written before there were options.
+        let format = Format::default();
+        assert_eq!(format.header(&names()), "timestamp,pv_power");
+        assert_eq!(
+            format.row(&[1700000000.0, 0.1 + 0.2, 50.5]),
+            "1700000000,0.30000000000000004,50.5"
+        );
+        assert_eq!(Format::new(",", None, false, None, String::new()), format);
+    }
+
+    #[test]
+    fn test_datetime_for_sanity() {
+        let format = Format::new(
+            ",",
+            None,
+            false,
+            Some(tz::Zone::parse("Europe/Berlin")),
+            String::new(),
+        );
+        let header = format.header(&names());
+        let row = format.row(&[1699920000.5, 100.0]);
+        assert_eq!(header, "timestamp,datetime,pv_power");
+        assert_eq!(row, "1699920000.5,2023-11-14T01:00:00.500+01:00,100");
+
+        // both columns refer to the same instant.
+        let fields: Vec<&str> = row.split(',').collect();
+        let datetime = chrono::DateTime::parse_from_rfc3339(fields[1]).unwrap();
+        let epoch: f64 = fields[0].parse().unwrap();
+        assert_eq!(datetime.timestamp_millis() as f64, epoch * 1000.0);
+    }
+
+    #[test]
+    fn test_split_for_sanity() {
+        let format = Format::new(";", None, true, None, String::new());
+        let header = format.header(&["timestamp".to_string(), "say \"hi\"".to_string()]);
+        assert_eq!(format.split(&header), vec!["timestamp", "say \"hi\""]);
+        assert_eq!(Format::default().split("timestamp,pv_power"), names());
+    }
+
+    #[test]
+    fn test_columns_for_sanity() {
+        let format = Format::new(";", None, true, Some(tz::Zone::Utc), String::new());
+        assert_eq!(format.columns(&format.header(&names())), names());
+        assert_eq!(Format::default().columns("timestamp,pv_power"), names());
+    }
+
+    #[test]
+    fn test_quote_for_sanity() {
+        let format = Format::new(",", None, true, None, String::new());
+        assert_eq!(
+            format.header(&["say \"hi\"".to_string()]),
+            "\"say \"\"hi\"\"\""
+        );
+    }
+}
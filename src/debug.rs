@@ -0,0 +1,221 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time;
+
+use serde::Serialize;
+
+use crate::clock;
+use crate::keys;
+use crate::state;
+
+/// Number of exchanges kept per sensor.
+const CAPACITY: usize = 20;
+/// Bytes of a body kept per exchange.
+const MAX_BODY: usize = 2048;
+
+/// Summary of a request and its response.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub(crate) struct Exchange {
+    pub(crate) time: f64,
+    /// With secrets masked.
+    pub(crate) url: String,
+    /// None if there was no response at all; the body is the error then.
+    pub(crate) status: Option<u16>,
+    pub(crate) body: String,
+    pub(crate) latency: f64,
+}
+
+/// The last exchanges of a sensor; bounded and free of allocations until something is recorded.
+pub(crate) struct Ring {
+    capacity: usize,
+    entries: Mutex<VecDeque<Exchange>>,
+}
+
+impl Ring {
+    pub(crate) fn new(capacity: usize) -> Ring {
+        Ring {
+            capacity,
+            entries: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    pub(crate) fn record(
+        &self,
+        url: &str,
+        status: Option<u16>,
+        body: &str,
+        started: time::Instant,
+    ) {
+        let exchange = Exchange {
+            time: clock::epoch_secs(time::SystemTime::now()),
+            url: keys::mask_url(url),
+            status,
+            body: truncate(body),
+            latency: started.elapsed().as_secs_f64(),
+        };
+        let mut entries = self.entries.lock().expect("debug ring lock poisoned.");
+        if entries.len() == self.capacity {
+            entries.pop_front();
+        }
+        entries.push_back(exchange);
+    }
+
+    pub(crate) fn entries(&self) -> Vec<Exchange> {
+        let entries = self.entries.lock().expect("debug ring lock poisoned.");
+        entries.iter().cloned().collect()
+    }
+}
+
+/// The first MAX_BODY bytes of a body; never cutting through a character.
+fn truncate(body: &str) -> String {
+    body.char_indices()
+        .take_while(|(i, c)| i + c.len_utf8() <= MAX_BODY)
+        .map(|(_, c)| c)
+        .collect()
+}
+
+fn rings() -> &'static Mutex<HashMap<String, Arc<Ring>>> {
+    static RINGS: OnceLock<Mutex<HashMap<String, Arc<Ring>>>> = OnceLock::new();
+    RINGS.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/// The ring of a sensor; created on first use.
+pub(crate) fn ring(name: &str) -> Arc<Ring> {
+    let mut rings = rings().lock().expect("debug rings lock poisoned.");
+    rings
+        .entry(name.to_string())
+        .or_insert_with(|| Arc::new(Ring::new(CAPACITY)))
+        .clone()
+}
+
+/// Dumps the ring of a sensor as debug_<name>_<time>.json into dir; None if there is nothing to dump.
+pub(crate) fn dump(dir: &str, name: &str, now: f64) -> Option<String> {
+    let ring = rings()
+        .lock()
+        .expect("debug rings lock poisoned.")
+        .get(name)
+        .cloned()?;
+    let entries = ring.entries();
+    if entries.is_empty() {
+        return None;
+    }
+    let file = format!("debug_{}_{}", name, now as u64);
+    match state::save(dir, &file, &entries) {
+        Ok(()) => {
+            eprintln!(
+                "Dumped the last {} exchanges of {} to {}.",
+                entries.len(),
+                name,
+                dir
+            );
+            Some(format!("{}/{}.json", dir, file))
+        }
+        Err(err) => {
+            eprintln!("Could not dump the exchanges of {}: {}", name, err);
+            None
+        }
+    }
+}
+
+/// Dumps the rings of all sensors.
+pub(crate) fn dump_all(dir: &str, now: f64) -> Vec<String> {
+    let mut names: Vec<String> = rings()
+        .lock()
+        .expect("debug rings lock poisoned.")
+        .keys()
+        .cloned()
+        .collect();
+    names.sort();
+    names
+        .iter()
+        .filter_map(|name| dump(dir, name, now))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    // Tests for success.
+
+    #[test]
+    fn test_record_for_success() {
+        let ring = Ring::new(3);
+        ring.record(
+            "http://localhost/data?appid=aff4d0995b7d1e17",
+            Some(200),
+            "{}",
+            time::Instant::now(),
+        );
+        let entries = ring.entries();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].url, "http://localhost/data?appid=aff4****");
+        assert_eq!(entries[0].status, Some(200));
+        assert_eq!(entries[0].body, "{}");
+    }
+
+    // Tests for failure.
+
+    #[test]
+    fn test_dump_for_failure() {
+        // unknown sensors and empty rings are not dumped.
+        assert_eq!(dump("debug_test0", "debug_unknown", 0.0), None);
+        ring("debug_empty");
+        assert_eq!(dump("debug_test0", "debug_empty", 0.0), None);
+        assert!(!std::path::Path::new("debug_test0").exists());
+    }
+
+    // Tests for sanity.
+
+    #[test]
+    fn test_ring_for_sanity() {
+        let ring = Ring::new(3);
+        for i in 0..5 {
+            ring.record(
+                &format!("http://localhost/{}", i),
+                Some(500),
+                "oops",
+                time::Instant::now(),
+            );
+        }
+        // bounded; the oldest ones go first.
+        let urls: Vec<String> = ring.entries().into_iter().map(|e| e.url).collect();
+        assert_eq!(
+            urls,
+            vec![
+                "http://localhost/2",
+                "http://localhost/3",
+                "http://localhost/4"
+            ]
+        );
+
+        // bodies are cut at 2 kB, but not within a character.
+        ring.record(
+            "http://localhost/",
+            None,
+            &"ä".repeat(2000),
+            time::Instant::now(),
+        );
+        let body = ring.entries().pop().unwrap().body;
+        assert_eq!(body.len(), MAX_BODY);
+        assert_eq!(body.chars().count(), 1024);
+    }
+
+    #[test]
+    fn test_dump_for_sanity() {
+        let ring = ring("debug_sensor");
+        ring.record(
+            "http://localhost/",
+            None,
+            "connection refused",
+            time::Instant::now(),
+        );
+        let file = dump("debug_test1", "debug_sensor", 1700000000.0).unwrap();
+        assert_eq!(file, "debug_test1/debug_debug_sensor_1700000000.json");
+        let content = fs::read_to_string(&file).unwrap();
+        assert!(content.contains("connection refused"));
+        fs::remove_dir_all("debug_test1").unwrap();
+    }
+}
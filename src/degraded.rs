@@ -0,0 +1,161 @@
+use crate::common;
+
+/// Status of a sensor that measures as usual.
+pub(crate) const STATUS_ACTIVE: f64 = 0.0;
+/// Status of a sensor that failed validation; its columns are NaN until a retry succeeds.
+pub(crate) const STATUS_DEGRADED: f64 = 5.0;
+
+/// Keeps track of whether a sensor is active or degraded, and when to retry validating it.
+pub(crate) struct Supervisor {
+    name: String,
+    retry_every: u32,
+    degraded: bool,
+    refreshes: u32,
+}
+
+impl Supervisor {
+    /// Validates the sensor at startup; degraded sensors are retried every retry_every refreshes.
+    pub(crate) fn start(name: String, retry_every: u32, sensor: &dyn common::Sensor) -> Supervisor {
+        let mut res = Supervisor {
+            name,
+            retry_every: retry_every.max(1),
+            degraded: false,
+            refreshes: 0,
+        };
+        if let Err(err) = sensor.validate() {
+            eprintln!(
+                "Sensor {} failed validation; running degraded: {}",
+                res.name, err
+            );
+            res.degraded = true;
+        }
+        res
+    }
+
+    /// Called on every slow loop refresh; retries the validation of degraded sensors on schedule.
+    pub(crate) fn refresh(&mut self, sensor: &dyn common::Sensor) {
+        if !self.degraded {
+            return;
+        }
+        self.refreshes += 1;
+        if self.refreshes < self.retry_every {
+            return;
+        }
+        self.refreshes = 0;
+        match sensor.validate() {
+            Ok(()) => {
+                eprintln!("Sensor {} passed validation; active again.", self.name);
+                self.degraded = false;
+            }
+            Err(err) => eprintln!("Sensor {} is still degraded: {}", self.name, err),
+        }
+    }
+
+    pub(crate) fn is_active(&self) -> bool {
+        !self.degraded
+    }
+
+    pub(crate) fn status(&self) -> f64 {
+        if self.degraded {
+            STATUS_DEGRADED
+        } else {
+            STATUS_ACTIVE
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::error::Error;
+
+    use super::*;
+
+    /// A sensor whose board is plugged in after the given number of validations.
+    struct FlakySensor {
+        validations: Cell<u32>,
+        plugged_after: u32,
+    }
+
+    impl common::Sensor for FlakySensor {
+        fn get_names(&self) -> Vec<String> {
+            vec!["flaky_power".to_string()]
+        }
+
+        fn get_units(&self) -> Vec<String> {
+            vec!["W".to_string()]
+        }
+
+        fn measure(&self) -> Result<Vec<common::Reading>, common::SensorError> {
+            Ok(common::readings(self.get_names(), vec![10.0]))
+        }
+
+        fn validate(&self) -> Result<(), Box<dyn Error>> {
+            self.validations.set(self.validations.get() + 1);
+            if self.validations.get() > self.plugged_after {
+                Ok(())
+            } else {
+                Err(Box::from("no device at 0x40."))
+            }
+        }
+    }
+
+    fn sensor(plugged_after: u32) -> FlakySensor {
+        FlakySensor {
+            validations: Cell::new(0),
+            plugged_after,
+        }
+    }
+
+    // Tests for success.
+
+    #[test]
+    fn test_start_for_success() {
+        let supervisor = Supervisor::start("flaky".to_string(), 3, &sensor(0));
+        assert!(supervisor.is_active());
+        assert_eq!(supervisor.status(), STATUS_ACTIVE);
+    }
+
+    // Tests for failure.
+
+    #[test]
+    fn test_start_for_failure() {
+        let sensor = sensor(100);
+        let mut supervisor = Supervisor::start("flaky".to_string(), 3, &sensor);
+        assert!(!supervisor.is_active());
+        assert_eq!(supervisor.status(), STATUS_DEGRADED);
+        for _ in 0..30 {
+            supervisor.refresh(&sensor);
+        }
+        assert!(!supervisor.is_active());
+        // validated at startup and every third refresh.
+        assert_eq!(sensor.validations.get(), 11);
+    }
+
+    // Tests for sanity.
+
+    #[test]
+    fn test_promotion_for_sanity() {
+        let sensor = sensor(2);
+        let mut supervisor = Supervisor::start("flaky".to_string(), 3, &sensor);
+        // first retry fails...
+        for _ in 0..3 {
+            supervisor.refresh(&sensor);
+        }
+        assert!(!supervisor.is_active());
+        assert_eq!(sensor.validations.get(), 2);
+        // ... the second one promotes the sensor.
+        for _ in 0..2 {
+            supervisor.refresh(&sensor);
+        }
+        assert!(!supervisor.is_active());
+        supervisor.refresh(&sensor);
+        assert!(supervisor.is_active());
+        assert_eq!(supervisor.status(), STATUS_ACTIVE);
+        // active sensors are not validated again.
+        for _ in 0..10 {
+            supervisor.refresh(&sensor);
+        }
+        assert_eq!(sensor.validations.get(), 3);
+    }
+}
@@ -0,0 +1,95 @@
+use crate::common;
+use crate::expr;
+
+/// A column computed from the other columns of a row, e.g. `fox0_generationPower - fox0_feedinPower`.
+///
+/// Sees the columns of all sensors and of the components before it; missing columns and divisions by zero make for a
+/// missing value.
+pub struct DerivedComponent {
+    name: String,
+    expr: expr::Expr,
+}
+
+impl DerivedComponent {
+    pub(crate) fn new(name: String, expression: &str) -> DerivedComponent {
+        let expr = expr::parse(expression)
+            .unwrap_or_else(|err| panic!("invalid expression of {}: {}", name, err));
+        DerivedComponent { name, expr }
+    }
+}
+
+impl common::Component for DerivedComponent {
+    fn get_names(&self) -> Vec<String> {
+        vec![self.name.clone()]
+    }
+
+    fn update(&mut self, names: &[String], values: &[f64]) -> Vec<f64> {
+        let value = self.expr.eval(names, values);
+        // e.g. overflows; not worth a value either.
+        if value.is_finite() {
+            vec![value]
+        } else {
+            vec![f64::NAN]
+        }
+    }
+
+    fn get_inputs(&self) -> Option<Vec<String>> {
+        Some(self.expr.columns().iter().map(|c| c.to_string()).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::Component;
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|n| n.to_string()).collect()
+    }
+
+    // Tests for success.
+
+    #[test]
+    fn test_update_for_success() {
+        let mut component = DerivedComponent::new(
+            "self_consumption".to_string(),
+            "fox0_generationPower - fox0_feedinPower",
+        );
+        let names = names(&["timestamp", "fox0_generationPower", "fox0_feedinPower"]);
+        assert_eq!(component.get_names(), vec!["self_consumption"]);
+        assert_eq!(component.update(&names, &[1000.0, 3.5, 1.25]), vec![2.25]);
+    }
+
+    // Tests for failure.
+
+    #[test]
+    #[should_panic(expected = "invalid expression of house_net: unexpected end of expression.")]
+    fn test_new_for_failure() {
+        DerivedComponent::new("house_net".to_string(), "grid_power +");
+    }
+
+    #[test]
+    fn test_update_for_failure() {
+        let mut component =
+            DerivedComponent::new("efficiency".to_string(), "ac_power / dc_power * 100");
+        let names = names(&["timestamp", "ac_power", "dc_power"]);
+        // dark.
+        assert!(component.update(&names, &[1000.0, 0.0, 0.0])[0].is_nan());
+        // failed sensor.
+        assert!(component.update(&names, &[1000.0, f64::NAN, 500.0])[0].is_nan());
+        // not configured at all.
+        assert!(component.update(&names[..2], &[1000.0, 450.0])[0].is_nan());
+    }
+
+    // Tests for sanity.
+
+    #[test]
+    fn test_get_inputs_for_sanity() {
+        let component =
+            DerivedComponent::new("house_net".to_string(), "grid_power + max(pv_power, 0)");
+        assert_eq!(
+            component.get_inputs(),
+            Some(vec!["grid_power".to_string(), "pv_power".to_string()])
+        );
+    }
+}
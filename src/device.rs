@@ -0,0 +1,145 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+
+use crate::common;
+use crate::common::{Reading, SensorError};
+
+fn switches() -> &'static Mutex<HashMap<String, Arc<AtomicBool>>> {
+    static SWITCHES: OnceLock<Mutex<HashMap<String, Arc<AtomicBool>>>> = OnceLock::new();
+    SWITCHES.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/// The switch of a device, shared by all its sensors; created on first use with the given state.
+pub(crate) fn switch(name: &str, enabled: bool) -> Arc<AtomicBool> {
+    let mut switches = switches().lock().expect("device switches lock poisoned.");
+    switches
+        .entry(name.to_string())
+        .or_insert_with(|| Arc::new(AtomicBool::new(enabled)))
+        .clone()
+}
+
+/// Enables or disables all sensors of a device while the loop runs; false if there is no such device.
+pub fn set_enabled(name: &str, enabled: bool) -> bool {
+    let switches = switches().lock().expect("device switches lock poisoned.");
+    match switches.get(name) {
+        Some(switch) => {
+            if switch.swap(enabled, Ordering::Relaxed) != enabled {
+                let state = if enabled { "enabled" } else { "disabled" };
+                eprintln!("Device {} {}.", name, state);
+            }
+            true
+        }
+        None => false,
+    }
+}
+
+/// A sensor that is part of a device; its columns are NaN while the device is disabled.
+pub struct DeviceSensor {
+    inner: Box<dyn common::Sensor>,
+    enabled: Arc<AtomicBool>,
+}
+
+impl DeviceSensor {
+    pub(crate) fn new(inner: Box<dyn common::Sensor>, enabled: Arc<AtomicBool>) -> DeviceSensor {
+        DeviceSensor { inner, enabled }
+    }
+
+    fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+}
+
+impl common::Sensor for DeviceSensor {
+    fn get_names(&self) -> Vec<String> {
+        self.inner.get_names()
+    }
+
+    fn get_units(&self) -> Vec<String> {
+        self.inner.get_units()
+    }
+
+    fn init(&mut self) -> Result<(), SensorError> {
+        self.inner.init()
+    }
+
+    fn shutdown(&mut self) {
+        self.inner.shutdown()
+    }
+
+    fn measure(&self) -> Result<Vec<Reading>, SensorError> {
+        // disabled on purpose; not a failure.
+        if !self.is_enabled() {
+            return Ok(Vec::new());
+        }
+        self.inner.measure()
+    }
+
+    fn measure_at(&self, now: f64) -> Result<Vec<Reading>, SensorError> {
+        if !self.is_enabled() {
+            return Ok(Vec::new());
+        }
+        self.inner.measure_at(now)
+    }
+
+    fn validate(&self) -> Result<(), Box<dyn Error>> {
+        // a device switched off for the winter is not broken.
+        if !self.is_enabled() {
+            return Ok(());
+        }
+        self.inner.validate()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::Sensor;
+    use crate::dummy;
+
+    fn member(name: &str, enabled: Arc<AtomicBool>) -> DeviceSensor {
+        let inner = dummy::DummySensor::new(name.to_string(), vec![("power".to_string(), 10.0)]);
+        DeviceSensor::new(Box::new(inner), enabled)
+    }
+
+    // Tests for success.
+
+    #[test]
+    fn test_switch_for_success() {
+        let switch = switch("device_test0", false);
+        // later members share the state of the first one.
+        assert!(!super::switch("device_test0", true).load(Ordering::Relaxed));
+        assert!(set_enabled("device_test0", true));
+        assert!(switch.load(Ordering::Relaxed));
+    }
+
+    // Tests for failure.
+
+    #[test]
+    fn test_set_enabled_for_failure() {
+        assert!(!set_enabled("device_unknown", false));
+    }
+
+    // Tests for sanity.
+
+    #[test]
+    fn test_group_disable_for_sanity() {
+        let shared = switch("device_test1", true);
+        let (soc, temp) = (member("soc", shared.clone()), member("temp", shared));
+        assert_eq!(soc.measure().unwrap()[0].value, 10.0);
+
+        // all members go NaN together; their columns stay.
+        set_enabled("device_test1", false);
+        for sensor in [&soc, &temp] {
+            let (values, times) = common::align(&sensor.get_names(), &sensor.measure().unwrap());
+            assert!(values[0].is_nan());
+            assert_eq!(times, vec![None]);
+            assert_eq!(sensor.get_names().len(), 1);
+            assert!(sensor.validate().is_ok());
+        }
+
+        set_enabled("device_test1", true);
+        assert_eq!(temp.measure().unwrap()[0].value, 10.0);
+    }
+}
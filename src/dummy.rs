@@ -0,0 +1,350 @@
+use std::f64::consts::PI;
+use std::time;
+
+use crate::clock;
+use crate::common;
+use crate::common::{Reading, SensorError};
+
+/// The shape of a simulated signal.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Pattern {
+    /// Always 1.
+    Constant,
+    /// Rising from 0 to 1 over a period, then starting over.
+    Ramp,
+    /// Between -1 and 1, starting at 0 at the beginning of each period.
+    Sine,
+    /// Between 0 and 1, changing every second; the same for the same seed and time.
+    Random,
+}
+
+impl Pattern {
+    pub(crate) fn parse(name: &str) -> Option<Pattern> {
+        match name {
+            "constant" => Some(Pattern::Constant),
+            "ramp" => Some(Pattern::Ramp),
+            "sine" => Some(Pattern::Sine),
+            "random" => Some(Pattern::Random),
+            _ => None,
+        }
+    }
+}
+
+/// A synthetic signal; a function of the time only, so runs can be reproduced.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Signal {
+    pub(crate) pattern: Pattern,
+    pub(crate) amplitude: f64,
+    pub(crate) period: f64,
+    pub(crate) seed: u64,
+}
+
+impl Signal {
+    /// The value of the index-th metric at the given time.
+    fn value(&self, index: usize, now: f64) -> f64 {
+        let shape = match self.pattern {
+            Pattern::Constant => 1.0,
+            Pattern::Ramp => now.rem_euclid(self.period) / self.period,
+            Pattern::Sine => (2.0 * PI * now / self.period).sin(),
+            Pattern::Random => {
+                let step = now.floor() as i64 as u64;
+                let bits = splitmix64(self.seed ^ splitmix64(step ^ ((index as u64) << 48)));
+                // the upper 53 bits make for a uniform f64 in [0, 1).
+                (bits >> 11) as f64 / (1u64 << 53) as f64
+            }
+        };
+        self.amplitude * shape
+    }
+}
+
+/// Scrambles the bits of x; see <https://prng.di.unimi.it/splitmix64.c>.
+fn splitmix64(x: u64) -> u64 {
+    let mut z = x.wrapping_add(0x9e3779b97f4a7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
+
+/// A sensor reporting fixed or simulated values; handy for trying out a setup without any hardware.
+pub struct DummySensor {
+    name: String,
+    // the names of the metrics and the values the signal is added to.
+    metrics: Vec<(String, f64)>,
+    signal: Option<Signal>,
+}
+
+impl DummySensor {
+    pub fn new(name: String, metrics: Vec<(String, f64)>) -> DummySensor {
+        DummySensor {
+            name,
+            metrics,
+            signal: None,
+        }
+    }
+
+    pub(crate) fn simulated(
+        name: String,
+        metrics: Vec<(String, f64)>,
+        signal: Signal,
+    ) -> DummySensor {
+        DummySensor {
+            name,
+            metrics,
+            signal: Some(signal),
+        }
+    }
+
+    fn measure_at(&self, now: f64) -> Result<Vec<Reading>, SensorError> {
+        let values = self
+            .metrics
+            .iter()
+            .enumerate()
+            .map(|(i, (_, value))| match &self.signal {
+                Some(signal) => value + signal.value(i, now),
+                None => *value,
+            })
+            .collect();
+        Ok(common::readings(common::Sensor::get_names(self), values))
+    }
+}
+
+impl common::Sensor for DummySensor {
+    fn get_names(&self) -> Vec<String> {
+        self.metrics
+            .iter()
+            .map(|(metric, _)| format!("{}_{}", self.name, metric))
+            .collect()
+    }
+
+    fn get_units(&self) -> Vec<String> {
+        vec!["unknown".to_string(); self.metrics.len()]
+    }
+
+    fn measure(&self) -> Result<Vec<Reading>, SensorError> {
+        self.measure_at(clock::epoch_secs(time::SystemTime::now()))
+    }
+}
+
+/// Sensors reporting fixed *values*, or simulated ones for *names*, e.g. for testing.
+pub(crate) fn sensor_type() -> common::SensorType {
+    common::SensorType {
+        name: "dummy",
+        required: &[],
+        optional: &["values", "names", "pattern", "amplitude", "period", "seed"],
+        create: |name, sensor_cfg, _| {
+            let invalid = |msg: &str| common::ConfigError::Invalid(msg.to_string());
+            let number = |key: &str, default: f64| match sensor_cfg.get(key) {
+                Some(v) => v
+                    .as_float()
+                    .or_else(|| v.as_integer().map(|i| i as f64))
+                    .ok_or_else(|| {
+                        common::ConfigError::Invalid(format!("{} must be a number.", key))
+                    }),
+                None => Ok(default),
+            };
+            let mut metrics = Vec::new();
+            if let Some(values) = sensor_cfg.get("values") {
+                let values = values
+                    .as_table()
+                    .ok_or_else(|| invalid("the values of a dummy sensor must be a table."))?;
+                for (metric, v) in values {
+                    let value = v
+                        .as_float()
+                        .or_else(|| v.as_integer().map(|i| i as f64))
+                        .ok_or_else(|| invalid("the values of a dummy sensor must be numbers."))?;
+                    metrics.push((metric.to_string(), value));
+                }
+            }
+            if let Some(names) = sensor_cfg.get("names") {
+                if !metrics.is_empty() {
+                    return Err(invalid("a dummy sensor sets either values or names."));
+                }
+                for metric in names
+                    .as_array()
+                    .ok_or_else(|| invalid("names must be an array of strings."))?
+                {
+                    let metric = metric
+                        .as_str()
+                        .ok_or_else(|| invalid("names must be an array of strings."))?;
+                    metrics.push((metric.to_string(), 0.0));
+                }
+            } else if sensor_cfg.get("values").is_none() {
+                return Err(common::ConfigError::Missing {
+                    kind: "dummy".to_string(),
+                    fields: vec!["values".to_string()],
+                });
+            }
+            // w/o a pattern the values are reported as they are.
+            if sensor_cfg.get("pattern").is_none() && sensor_cfg.get("names").is_none() {
+                return Ok(Box::new(DummySensor::new(name.to_string(), metrics)));
+            }
+            let pattern = match sensor_cfg.get("pattern") {
+                Some(v) => v.as_str().and_then(Pattern::parse).ok_or_else(|| {
+                    invalid("pattern must be one of 'constant', 'ramp', 'sine' or 'random'.")
+                })?,
+                None => Pattern::Constant,
+            };
+            let period = number("period", 60.0)?;
+            if period <= 0.0 {
+                return Err(invalid("period must be positive."));
+            }
+            let seed = match sensor_cfg.get("seed") {
+                Some(v) => v
+                    .as_integer()
+                    .filter(|s| *s >= 0)
+                    .ok_or_else(|| invalid("seed must be a non-negative integer."))?
+                    as u64,
+                None => 0,
+            };
+            let signal = Signal {
+                pattern,
+                amplitude: number("amplitude", 1.0)?,
+                period,
+                seed,
+            };
+            Ok(Box::new(DummySensor::simulated(
+                name.to_string(),
+                metrics,
+                signal,
+            )))
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::Sensor;
+
+    fn simulated(pattern: Pattern, seed: u64) -> DummySensor {
+        DummySensor::simulated(
+            "sim".to_string(),
+            vec![("power".to_string(), 0.0), ("voltage".to_string(), 230.0)],
+            Signal {
+                pattern,
+                amplitude: 10.0,
+                period: 60.0,
+                seed,
+            },
+        )
+    }
+
+    fn values(sensor: &DummySensor, now: f64) -> Vec<f64> {
+        sensor
+            .measure_at(now)
+            .unwrap()
+            .iter()
+            .map(|r| r.value)
+            .collect()
+    }
+
+    fn create(cfg: &str) -> Result<Box<dyn Sensor + Send>, common::ConfigError> {
+        let sensor_cfg: toml::value::Table = toml::from_str(cfg).unwrap();
+        (sensor_type().create)("sim", &sensor_cfg, "state")
+    }
+
+    // Tests for success.
+
+    #[test]
+    fn test_measure_for_success() {
+        let sensor = DummySensor::new("dummy".to_string(), vec![("power".to_string(), 42.0)]);
+        assert_eq!(sensor.get_names(), vec!["dummy_power"]);
+        assert_eq!(
+            sensor.measure().unwrap(),
+            vec![Reading::new("dummy_power".to_string(), 42.0)]
+        );
+    }
+
+    #[test]
+    fn test_patterns_for_success() {
+        let sensor = simulated(Pattern::Constant, 0);
+        assert_eq!(values(&sensor, 1000.0), vec![10.0, 240.0]);
+
+        let sensor = simulated(Pattern::Ramp, 0);
+        assert_eq!(values(&sensor, 1200.0), vec![0.0, 230.0]);
+        assert_eq!(values(&sensor, 1215.0), vec![2.5, 232.5]);
+        assert_eq!(values(&sensor, 1230.0), vec![5.0, 235.0]);
+        // starts over.
+        assert_eq!(values(&sensor, 1260.0), vec![0.0, 230.0]);
+
+        let sensor = simulated(Pattern::Sine, 0);
+        for (now, expected) in [
+            (1200.0, 0.0),
+            (1215.0, 10.0),
+            (1230.0, 0.0),
+            (1245.0, -10.0),
+        ] {
+            assert!((values(&sensor, now)[0] - expected).abs() < 1e-9);
+        }
+
+        let sensor = simulated(Pattern::Random, 7);
+        let samples: Vec<f64> = (0..100).map(|i| values(&sensor, i as f64)[0]).collect();
+        assert!(samples.iter().all(|v| (0.0..10.0).contains(v)));
+        assert!(samples.iter().any(|v| *v < 5.0) && samples.iter().any(|v| *v >= 5.0));
+    }
+
+    #[test]
+    fn test_sensor_type_for_success() {
+        let sensor = create(
+            "type='dummy'\nnames=['power', 'soc']\npattern='ramp'\namplitude=100\nperiod=10",
+        )
+        .unwrap();
+        assert_eq!(sensor.get_names(), vec!["sim_power", "sim_soc"]);
+        let sensor =
+            create("type='dummy'\nvalues={ power=1500 }\npattern='sine'\namplitude=200").unwrap();
+        let value = sensor.measure().unwrap()[0].value;
+        assert!((1300.0..=1700.0).contains(&value));
+    }
+
+    // Tests for failure.
+
+    #[test]
+    fn test_measure_for_failure() {
+        let sensor = DummySensor::new("dummy".to_string(), Vec::new());
+        assert!(sensor.get_names().is_empty());
+        assert!(sensor.measure().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_sensor_type_for_failure() {
+        assert!(matches!(
+            create("type='dummy'"),
+            Err(common::ConfigError::Missing { .. })
+        ));
+        assert!(create("type='dummy'\nnames=['power']\npattern='square'").is_err());
+        assert!(create("type='dummy'\nnames=['power']\npattern='sine'\nperiod=0").is_err());
+        assert!(create("type='dummy'\nnames=['power']\nseed=-1").is_err());
+        assert!(create("type='dummy'\nnames=['power']\nvalues={ power=1 }").is_err());
+    }
+
+    // Tests for sanity.
+
+    #[test]
+    fn test_names_for_sanity() {
+        let sensor = DummySensor::new(
+            "dummy".to_string(),
+            vec![("power".to_string(), 1.5), ("voltage".to_string(), 230.0)],
+        );
+        assert_eq!(sensor.get_names(), vec!["dummy_power", "dummy_voltage"]);
+        assert_eq!(sensor.get_units(), vec!["unknown", "unknown"]);
+        let values: Vec<f64> = sensor.measure().unwrap().iter().map(|r| r.value).collect();
+        assert_eq!(values, vec![1.5, 230.0]);
+    }
+
+    #[test]
+    fn test_seed_for_sanity() {
+        let first: Vec<Vec<f64>> = (0..20)
+            .map(|i| values(&simulated(Pattern::Random, 42), i as f64))
+            .collect();
+        let again: Vec<Vec<f64>> = (0..20)
+            .map(|i| values(&simulated(Pattern::Random, 42), i as f64))
+            .collect();
+        let other: Vec<Vec<f64>> = (0..20)
+            .map(|i| values(&simulated(Pattern::Random, 43), i as f64))
+            .collect();
+        assert_eq!(first, again);
+        assert_ne!(first, other);
+        // metrics do not move in lockstep.
+        assert!(first.iter().any(|v| v[0] != v[1] - 230.0));
+    }
+}
@@ -0,0 +1,910 @@
+use std::collections::HashMap;
+use std::net::TcpStream;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time;
+
+use crate::common;
+use crate::common::{ConfigError, Reading, SensorError};
+
+/// Port of the native API.
+const PORT: u16 = 6053;
+
+/// Latest state per object id; only of entities seen since the last (re)connect.
+type Cache = Arc<Mutex<HashMap<String, f64>>>;
+
+/// A sensor entity of the device.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Entity {
+    pub(crate) name: String,
+    /// As in the device's configuration, e.g. power_meter_power.
+    pub(crate) object_id: String,
+    pub(crate) unit: String,
+}
+
+/// How to reach the device.
+#[derive(Clone)]
+#[cfg_attr(not(feature = "esphome"), allow(dead_code))]
+pub(crate) struct Device {
+    pub(crate) address: String,
+    /// The pre-shared key of the encrypted API, if the device uses one.
+    pub(crate) key: Option<Vec<u8>>,
+    pub(crate) password: String,
+}
+
+/// The background thread connected to the device, and how to stop it.
+struct Listener {
+    stop: Arc<AtomicBool>,
+    /// The current connection; shut down to stop waiting for the device.
+    stream: Arc<Mutex<Option<TcpStream>>>,
+    worker: thread::JoinHandle<()>,
+}
+
+impl Listener {
+    /// Stops the thread; waits up to 5 secs for it to end.
+    fn stop(self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(stream) = &*self.stream.lock().unwrap_or_else(|e| e.into_inner()) {
+            let _ = stream.shutdown(std::net::Shutdown::Both);
+        }
+        for _ in 0..50 {
+            if self.worker.is_finished() {
+                if self.worker.join().is_err() {
+                    eprintln!("The ESPHome connection ended with a panic.");
+                }
+                return;
+            }
+            thread::sleep(time::Duration::from_millis(100));
+        }
+        eprintln!("The ESPHome connection did not stop in time; leaving it.");
+    }
+}
+
+/// Reports sensor entities of an ESPHome device, e.g. a DIY power meter, over the native API.
+///
+/// A background thread stays connected, subscribed to state updates, and reconnects when the connection drops; an
+/// entity without a state since the last connect is missing.
+pub struct EspHomeSensor {
+    name: String,
+    entities: Vec<Entity>,
+    cache: Cache,
+    listener: Option<Listener>,
+}
+
+impl EspHomeSensor {
+    pub(crate) fn new(
+        name: String,
+        entities: Vec<Entity>,
+        device: Option<Device>,
+    ) -> EspHomeSensor {
+        let cache: Cache = Arc::new(Mutex::new(HashMap::new()));
+        let listener = device.map(|device| {
+            let stop = Arc::new(AtomicBool::new(false));
+            let stream = Arc::new(Mutex::new(None));
+            let object_ids = entities.iter().map(|e| e.object_id.clone()).collect();
+            let worker = spawn(
+                device,
+                object_ids,
+                cache.clone(),
+                stop.clone(),
+                stream.clone(),
+            );
+            Listener {
+                stop,
+                stream,
+                worker,
+            }
+        });
+        EspHomeSensor {
+            name,
+            entities,
+            cache,
+            listener,
+        }
+    }
+}
+
+impl common::Sensor for EspHomeSensor {
+    fn get_names(&self) -> Vec<String> {
+        self.entities
+            .iter()
+            .map(|entity| format!("{}_{}", self.name, entity.name))
+            .collect()
+    }
+
+    fn get_units(&self) -> Vec<String> {
+        self.entities.iter().map(|e| e.unit.clone()).collect()
+    }
+
+    fn measure(&self) -> Result<Vec<Reading>, SensorError> {
+        let cache = self.cache.lock().unwrap_or_else(|e| e.into_inner());
+        let res: Vec<Reading> = self
+            .get_names()
+            .into_iter()
+            .zip(&self.entities)
+            .filter_map(|(name, entity)| {
+                cache
+                    .get(&entity.object_id)
+                    .map(|value| Reading::new(name, *value))
+            })
+            .collect();
+        if res.is_empty() {
+            return Err(SensorError::Protocol(
+                "no state from any entity since connecting.".to_string(),
+            ));
+        }
+        Ok(res)
+    }
+
+    fn shutdown(&mut self) {
+        if let Some(listener) = self.listener.take() {
+            listener.stop();
+        }
+    }
+}
+
+#[cfg(feature = "esphome")]
+mod api {
+    use std::collections::HashMap;
+    use std::io::{self, BufReader, Read, Write};
+    use std::net::TcpStream;
+
+    use super::Cache;
+
+    const PATTERN: &str = "Noise_NNpsk0_25519_ChaChaPoly_SHA256";
+    const PROLOGUE: &[u8] = b"NoiseAPIInit\x00\x00";
+
+    // Message types of api.proto.
+    pub(super) const HELLO_REQUEST: u16 = 1;
+    pub(super) const HELLO_RESPONSE: u16 = 2;
+    pub(super) const CONNECT_REQUEST: u16 = 3;
+    pub(super) const CONNECT_RESPONSE: u16 = 4;
+    pub(super) const DISCONNECT_REQUEST: u16 = 5;
+    pub(super) const DISCONNECT_RESPONSE: u16 = 6;
+    pub(super) const PING_REQUEST: u16 = 7;
+    pub(super) const PING_RESPONSE: u16 = 8;
+    pub(super) const LIST_ENTITIES_REQUEST: u16 = 11;
+    pub(super) const LIST_ENTITIES_SENSOR_RESPONSE: u16 = 16;
+    pub(super) const LIST_ENTITIES_DONE_RESPONSE: u16 = 19;
+    pub(super) const SUBSCRIBE_STATES_REQUEST: u16 = 20;
+    pub(super) const SENSOR_STATE_RESPONSE: u16 = 25;
+
+    /// A protobuf field value.
+    #[derive(Debug, PartialEq)]
+    pub(super) enum Value {
+        Varint(u64),
+        Fixed64(u64),
+        Bytes(Vec<u8>),
+        Fixed32(u32),
+    }
+
+    pub(super) fn varint(buf: &mut Vec<u8>, number: u64, value: u64) {
+        raw_varint(buf, number << 3);
+        raw_varint(buf, value);
+    }
+
+    pub(super) fn bytes(buf: &mut Vec<u8>, number: u64, data: &[u8]) {
+        raw_varint(buf, (number << 3) | 2);
+        raw_varint(buf, data.len() as u64);
+        buf.extend_from_slice(data);
+    }
+
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub(super) fn fixed32(buf: &mut Vec<u8>, number: u64, value: u32) {
+        raw_varint(buf, (number << 3) | 5);
+        buf.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn raw_varint(buf: &mut Vec<u8>, mut value: u64) {
+        while value >= 0x80 {
+            buf.push((value as u8) | 0x80);
+            value >>= 7;
+        }
+        buf.push(value as u8);
+    }
+
+    fn read_varint(buf: &mut &[u8]) -> Option<u64> {
+        let mut value = 0;
+        for shift in (0..64).step_by(7) {
+            let (byte, rest) = buf.split_first()?;
+            *buf = rest;
+            value |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Some(value);
+            }
+        }
+        None
+    }
+
+    /// The fields of a message by number.
+    pub(super) fn decode(mut buf: &[u8]) -> io::Result<HashMap<u64, Value>> {
+        let malformed = || invalid("malformed message.");
+        let mut res = HashMap::new();
+        while !buf.is_empty() {
+            let tag = read_varint(&mut buf).ok_or_else(malformed)?;
+            let value = match tag & 7 {
+                0 => Value::Varint(read_varint(&mut buf).ok_or_else(malformed)?),
+                1 if buf.len() >= 8 => {
+                    let (value, rest) = buf.split_at(8);
+                    buf = rest;
+                    Value::Fixed64(u64::from_le_bytes(value.try_into().unwrap()))
+                }
+                2 => {
+                    let len = read_varint(&mut buf).ok_or_else(malformed)? as usize;
+                    if buf.len() < len {
+                        return Err(malformed());
+                    }
+                    let (value, rest) = buf.split_at(len);
+                    buf = rest;
+                    Value::Bytes(value.to_vec())
+                }
+                5 if buf.len() >= 4 => {
+                    let (value, rest) = buf.split_at(4);
+                    buf = rest;
+                    Value::Fixed32(u32::from_le_bytes(value.try_into().unwrap()))
+                }
+                _ => return Err(malformed()),
+            };
+            res.insert(tag >> 3, value);
+        }
+        Ok(res)
+    }
+
+    fn text(fields: &HashMap<u64, Value>, number: u64) -> String {
+        match fields.get(&number) {
+            Some(Value::Bytes(data)) => String::from_utf8_lossy(data).to_string(),
+            _ => String::new(),
+        }
+    }
+
+    fn flag(fields: &HashMap<u64, Value>, number: u64) -> bool {
+        matches!(fields.get(&number), Some(Value::Varint(v)) if *v != 0)
+    }
+
+    pub(super) fn invalid(msg: &str) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+    }
+
+    /// Sends and receives messages as type and protobuf encoded body.
+    pub(super) trait Transport {
+        fn send(&mut self, kind: u16, msg: &[u8]) -> io::Result<()>;
+
+        fn receive(&mut self) -> io::Result<(u16, Vec<u8>)>;
+    }
+
+    /// Messages framed as a zero byte, the length and type as varints, and the body.
+    pub(super) struct Plain {
+        reader: BufReader<TcpStream>,
+        writer: TcpStream,
+    }
+
+    impl Plain {
+        pub(super) fn new(stream: TcpStream) -> io::Result<Plain> {
+            Ok(Plain {
+                writer: stream.try_clone()?,
+                reader: BufReader::new(stream),
+            })
+        }
+
+        fn read_varint(&mut self) -> io::Result<u64> {
+            let mut value = 0;
+            for shift in (0..64).step_by(7) {
+                let mut byte = [0];
+                self.reader.read_exact(&mut byte)?;
+                value |= ((byte[0] & 0x7f) as u64) << shift;
+                if byte[0] & 0x80 == 0 {
+                    return Ok(value);
+                }
+            }
+            Err(invalid("malformed frame."))
+        }
+    }
+
+    impl Transport for Plain {
+        fn send(&mut self, kind: u16, msg: &[u8]) -> io::Result<()> {
+            let mut frame = vec![0];
+            raw_varint(&mut frame, msg.len() as u64);
+            raw_varint(&mut frame, kind as u64);
+            frame.extend_from_slice(msg);
+            self.writer.write_all(&frame)
+        }
+
+        fn receive(&mut self) -> io::Result<(u16, Vec<u8>)> {
+            let mut preamble = [0];
+            self.reader.read_exact(&mut preamble)?;
+            match preamble[0] {
+                0 => {}
+                1 => {
+                    return Err(invalid(
+                        "the device requires encryption; set encryption_key.",
+                    ))
+                }
+                _ => return Err(invalid("malformed frame.")),
+            }
+            let len = self.read_varint()? as usize;
+            let kind = self.read_varint()? as u16;
+            let mut msg = vec![0; len];
+            self.reader.read_exact(&mut msg)?;
+            Ok((kind, msg))
+        }
+    }
+
+    /// Messages encrypted with the Noise protocol; framed as a one byte, the length as 2 bytes and the ciphertext.
+    pub(super) struct Noise {
+        reader: BufReader<TcpStream>,
+        writer: TcpStream,
+        state: snow::TransportState,
+    }
+
+    pub(super) fn read_frame(reader: &mut impl Read) -> io::Result<Vec<u8>> {
+        let mut header = [0; 3];
+        reader.read_exact(&mut header)?;
+        match header[0] {
+            1 => {}
+            0 => {
+                return Err(invalid(
+                    "the device does not use encryption; remove encryption_key.",
+                ))
+            }
+            _ => return Err(invalid("malformed frame.")),
+        }
+        let mut frame = vec![0; u16::from_be_bytes([header[1], header[2]]) as usize];
+        reader.read_exact(&mut frame)?;
+        Ok(frame)
+    }
+
+    pub(super) fn write_frame(writer: &mut impl Write, frame: &[u8]) -> io::Result<()> {
+        let len = u16::try_from(frame.len()).map_err(|_| invalid("message too long."))?;
+        let mut buf = vec![1];
+        buf.extend_from_slice(&len.to_be_bytes());
+        buf.extend_from_slice(frame);
+        writer.write_all(&buf)
+    }
+
+    fn noise_error(err: snow::Error) -> io::Error {
+        invalid(&format!("encryption failed: {}", err))
+    }
+
+    pub(super) fn builder(key: &[u8]) -> snow::Builder<'_> {
+        snow::Builder::new(PATTERN.parse().unwrap())
+            .psk(0, key)
+            .prologue(PROLOGUE)
+    }
+
+    impl Noise {
+        /// Does the handshake as initiator.
+        pub(super) fn connect(stream: TcpStream, key: &[u8]) -> io::Result<Noise> {
+            let mut writer = stream.try_clone()?;
+            let mut reader = BufReader::new(stream);
+            let mut handshake = builder(key).build_initiator().map_err(noise_error)?;
+            let mut buf = vec![0; 65535];
+            let len = handshake
+                .write_message(&[], &mut buf)
+                .map_err(noise_error)?;
+            // the client hello, then the handshake.
+            write_frame(&mut writer, &[])?;
+            write_frame(&mut writer, &[&[0], &buf[..len]].concat())?;
+            let hello = read_frame(&mut reader)?;
+            if hello.first() != Some(&1) {
+                return Err(invalid("the device chose an unknown protocol."));
+            }
+            let answer = read_frame(&mut reader)?;
+            match answer.split_first() {
+                Some((0, msg)) => {
+                    handshake
+                        .read_message(msg, &mut buf)
+                        .map_err(|_| invalid("handshake failed; check encryption_key."))?;
+                }
+                Some((_, reason)) => {
+                    return Err(invalid(&format!(
+                        "handshake failed: {}",
+                        String::from_utf8_lossy(reason)
+                    )))
+                }
+                None => return Err(invalid("malformed frame.")),
+            }
+            Ok(Noise {
+                reader,
+                writer,
+                state: handshake.into_transport_mode().map_err(noise_error)?,
+            })
+        }
+
+        /// Wraps a finished handshake, e.g. a responder's in tests.
+        #[cfg_attr(not(test), allow(dead_code))]
+        pub(super) fn new(stream: TcpStream, state: snow::TransportState) -> io::Result<Noise> {
+            Ok(Noise {
+                writer: stream.try_clone()?,
+                reader: BufReader::new(stream),
+                state,
+            })
+        }
+    }
+
+    impl Transport for Noise {
+        fn send(&mut self, kind: u16, msg: &[u8]) -> io::Result<()> {
+            let len = u16::try_from(msg.len()).map_err(|_| invalid("message too long."))?;
+            let plain = [&kind.to_be_bytes()[..], &len.to_be_bytes(), msg].concat();
+            let mut buf = vec![0; plain.len() + 16];
+            let len = self
+                .state
+                .write_message(&plain, &mut buf)
+                .map_err(noise_error)?;
+            write_frame(&mut self.writer, &buf[..len])
+        }
+
+        fn receive(&mut self) -> io::Result<(u16, Vec<u8>)> {
+            let frame = read_frame(&mut self.reader)?;
+            let mut buf = vec![0; frame.len()];
+            let len = self
+                .state
+                .read_message(&frame, &mut buf)
+                .map_err(noise_error)?;
+            if len < 4 {
+                return Err(invalid("malformed message."));
+            }
+            let kind = u16::from_be_bytes([buf[0], buf[1]]);
+            let size = u16::from_be_bytes([buf[2], buf[3]]) as usize;
+            if len < 4 + size {
+                return Err(invalid("malformed message."));
+            }
+            Ok((kind, buf[4..4 + size].to_vec()))
+        }
+    }
+
+    /// Waits for a message of the given type; answers pings meanwhile.
+    fn expect(transport: &mut dyn Transport, kind: u16) -> io::Result<HashMap<u64, Value>> {
+        loop {
+            match transport.receive()? {
+                (k, msg) if k == kind => return decode(&msg),
+                (PING_REQUEST, _) => transport.send(PING_RESPONSE, &[])?,
+                _ => {}
+            }
+        }
+    }
+
+    /// Logs in, subscribes to the states of the entities and keeps them in the cache until the connection drops.
+    pub(super) fn session(
+        transport: &mut dyn Transport,
+        password: &str,
+        object_ids: &[String],
+        cache: &Cache,
+    ) -> io::Result<()> {
+        let mut hello = Vec::new();
+        bytes(&mut hello, 1, b"open_green_compute");
+        varint(&mut hello, 2, 1);
+        varint(&mut hello, 3, 9);
+        transport.send(HELLO_REQUEST, &hello)?;
+        let hello = expect(transport, HELLO_RESPONSE)?;
+        if !matches!(hello.get(&1), Some(Value::Varint(1))) {
+            return Err(invalid("unsupported API version."));
+        }
+
+        let mut connect = Vec::new();
+        if !password.is_empty() {
+            bytes(&mut connect, 1, password.as_bytes());
+        }
+        transport.send(CONNECT_REQUEST, &connect)?;
+        if flag(&expect(transport, CONNECT_RESPONSE)?, 1) {
+            return Err(invalid("invalid password."));
+        }
+
+        // the keys of the entities, which the states refer to.
+        transport.send(LIST_ENTITIES_REQUEST, &[])?;
+        let mut keys = HashMap::new();
+        loop {
+            match transport.receive()? {
+                (LIST_ENTITIES_SENSOR_RESPONSE, msg) => {
+                    let entity = decode(&msg)?;
+                    let object_id = text(&entity, 1);
+                    match entity.get(&2) {
+                        Some(Value::Fixed32(key)) if object_ids.contains(&object_id) => {
+                            keys.insert(*key, object_id);
+                        }
+                        _ => {}
+                    }
+                }
+                (LIST_ENTITIES_DONE_RESPONSE, _) => break,
+                (PING_REQUEST, _) => transport.send(PING_RESPONSE, &[])?,
+                _ => {}
+            }
+        }
+        for object_id in object_ids {
+            if !keys.values().any(|id| id == object_id) {
+                eprintln!("The device has no sensor {}.", object_id);
+            }
+        }
+
+        transport.send(SUBSCRIBE_STATES_REQUEST, &[])?;
+        loop {
+            match transport.receive()? {
+                (SENSOR_STATE_RESPONSE, msg) => {
+                    let state = decode(&msg)?;
+                    let object_id = match state.get(&1) {
+                        Some(Value::Fixed32(key)) => match keys.get(key) {
+                            Some(object_id) => object_id,
+                            None => continue,
+                        },
+                        _ => continue,
+                    };
+                    let mut cache = cache.lock().unwrap_or_else(|e| e.into_inner());
+                    match state.get(&2) {
+                        Some(Value::Fixed32(bits)) if !flag(&state, 3) => {
+                            cache.insert(object_id.clone(), f32::from_bits(*bits) as f64)
+                        }
+                        // a default value is not sent.
+                        None if !flag(&state, 3) => cache.insert(object_id.clone(), 0.0),
+                        _ => cache.remove(object_id),
+                    };
+                }
+                (PING_REQUEST, _) => transport.send(PING_RESPONSE, &[])?,
+                (DISCONNECT_REQUEST, _) => {
+                    transport.send(DISCONNECT_RESPONSE, &[])?;
+                    return Err(io::Error::new(
+                        io::ErrorKind::ConnectionAborted,
+                        "the device disconnected.",
+                    ));
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Keeps the device connected in the background; reconnects with backoff when the connection drops.
+#[cfg(feature = "esphome")]
+fn spawn(
+    device: Device,
+    object_ids: Vec<String>,
+    cache: Cache,
+    stop: Arc<AtomicBool>,
+    current: Arc<Mutex<Option<TcpStream>>>,
+) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        let mut backoff = time::Duration::from_secs(1);
+        while !stop.load(Ordering::Relaxed) {
+            let connected = time::Instant::now();
+            let res = TcpStream::connect(&device.address).and_then(|stream| {
+                *current.lock().unwrap_or_else(|e| e.into_inner()) = Some(stream.try_clone()?);
+                // a shutdown while connecting.
+                if stop.load(Ordering::Relaxed) {
+                    return Ok(());
+                }
+                // the device pings when idle for a minute; no message for longer means the connection is gone.
+                stream.set_read_timeout(Some(time::Duration::from_secs(150)))?;
+                let mut transport: Box<dyn api::Transport> = match &device.key {
+                    Some(key) => Box::new(api::Noise::connect(stream, key)?),
+                    None => Box::new(api::Plain::new(stream)?),
+                };
+                api::session(transport.as_mut(), &device.password, &object_ids, &cache)
+            });
+            // states from before are not current.
+            cache.lock().unwrap_or_else(|e| e.into_inner()).clear();
+            if stop.load(Ordering::Relaxed) {
+                return;
+            }
+            if let Err(err) = res {
+                eprintln!(
+                    "Lost connection to ESPHome device {}; reconnecting in {:?}: {}",
+                    device.address, backoff, err
+                );
+            }
+            // in steps, so a shutdown does not wait long.
+            for _ in 0..backoff.as_millis() / 100 {
+                if stop.load(Ordering::Relaxed) {
+                    return;
+                }
+                thread::sleep(time::Duration::from_millis(100));
+            }
+            backoff = if connected.elapsed() > time::Duration::from_secs(60) {
+                time::Duration::from_secs(1)
+            } else {
+                (backoff * 2).min(time::Duration::from_secs(60))
+            };
+        }
+    })
+}
+
+// sensor_type refuses ESPHome devices w/o the feature; nothing to connect to.
+#[cfg(not(feature = "esphome"))]
+fn spawn(
+    _: Device,
+    _: Vec<String>,
+    _: Cache,
+    _: Arc<AtomicBool>,
+    _: Arc<Mutex<Option<TcpStream>>>,
+) -> thread::JoinHandle<()> {
+    thread::spawn(|| {})
+}
+
+/// Sensor entities of ESPHome devices.
+pub(crate) fn sensor_type() -> common::SensorType {
+    common::SensorType {
+        name: "esphome",
+        required: &["host", "entities"],
+        optional: &["port", "encryption_key", "password"],
+        create: |name, sensor_cfg, _| {
+            let invalid = |msg: &str| ConfigError::Invalid(msg.to_string());
+            let host = sensor_cfg["host"]
+                .as_str()
+                .ok_or_else(|| invalid("host must be a string."))?;
+            let port = match sensor_cfg.get("port").map(|v| v.as_integer()) {
+                None => PORT,
+                Some(Some(port)) if (1..=65535).contains(&port) => port as u16,
+                Some(_) => return Err(invalid("port must be between 1 and 65535.")),
+            };
+            let key = match sensor_cfg.get("encryption_key") {
+                Some(v) => Some(
+                    v.as_str()
+                        .and_then(|key| openssl::base64::decode_block(key.trim()).ok())
+                        .filter(|key| key.len() == 32)
+                        .ok_or_else(|| {
+                            invalid("encryption_key must be 32 bytes, base64 encoded.")
+                        })?,
+                ),
+                None => None,
+            };
+            let password = match sensor_cfg.get("password") {
+                Some(v) => v
+                    .as_str()
+                    .ok_or_else(|| invalid("password must be a string."))?,
+                None => "",
+            };
+            let entities = sensor_cfg["entities"]
+                .as_array()
+                .filter(|a| !a.is_empty())
+                .ok_or_else(|| invalid("entities must be a non-empty array."))?
+                .iter()
+                .map(|entity| {
+                    let field = |key: &str| entity.get(key).and_then(|v| v.as_str());
+                    match (field("name"), field("object_id")) {
+                        (Some(name), Some(object_id)) => Ok(Entity {
+                            name: name.to_string(),
+                            object_id: object_id.to_string(),
+                            unit: field("unit").unwrap_or("unknown").to_string(),
+                        }),
+                        _ => Err(invalid("each entity must have a name and an object_id.")),
+                    }
+                })
+                .collect::<Result<_, _>>()?;
+            if cfg!(not(feature = "esphome")) {
+                return Err(ConfigError::Invalid(format!(
+                    "esphome sensor {} requires the esphome feature.",
+                    name
+                )));
+            }
+            Ok(Box::new(EspHomeSensor::new(
+                name.to_string(),
+                entities,
+                Some(Device {
+                    address: format!("{}:{}", host, port),
+                    key,
+                    password: password.to_string(),
+                }),
+            )))
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::Sensor;
+
+    fn entities() -> Vec<Entity> {
+        ["power", "voltage", "temperature"]
+            .iter()
+            .map(|name| Entity {
+                name: name.to_string(),
+                object_id: format!("meter_{}", name),
+                unit: "unknown".to_string(),
+            })
+            .collect()
+    }
+
+    // Tests for success.
+
+    #[test]
+    fn test_measure_for_success() {
+        let sensor = EspHomeSensor::new("esp".to_string(), entities(), None);
+        sensor
+            .cache
+            .lock()
+            .unwrap()
+            .insert("meter_power".to_string(), 1450.5);
+        let res = sensor.measure().unwrap();
+        assert_eq!(res.len(), 1);
+        assert_eq!((res[0].name.as_str(), res[0].value), ("esp_power", 1450.5));
+    }
+
+    // Tests for failure.
+
+    #[test]
+    fn test_measure_for_failure() {
+        let sensor = EspHomeSensor::new("esp".to_string(), entities(), None);
+        assert!(matches!(sensor.measure(), Err(SensorError::Protocol(_))));
+    }
+
+    #[test]
+    fn test_sensor_type_for_failure() {
+        let sensor_cfg: toml::value::Table = toml::from_str(
+            "host='meter.local'\nencryption_key='c2hvcnQ='\nentities=[{ name='power', object_id='power' }]",
+        )
+        .unwrap();
+        assert_eq!(
+            (sensor_type().create)("esp", &sensor_cfg, "state").err(),
+            Some(ConfigError::Invalid(
+                "encryption_key must be 32 bytes, base64 encoded.".to_string()
+            ))
+        );
+    }
+
+    #[cfg(feature = "esphome")]
+    mod api {
+        use std::net::TcpListener;
+
+        use super::super::api::*;
+        use super::super::*;
+        use super::entities;
+        use crate::common::Sensor;
+
+        const KEY: [u8; 32] = [7; 32];
+
+        /// Plays the device: lists the entities and sends a state for power and voltage, the latter missing.
+        fn serve(transport: &mut dyn Transport, password_ok: bool) {
+            assert_eq!(transport.receive().unwrap().0, HELLO_REQUEST);
+            let mut hello = Vec::new();
+            varint(&mut hello, 1, 1);
+            varint(&mut hello, 2, 9);
+            bytes(&mut hello, 3, b"meter (esphome v2024.5.0)");
+            transport.send(HELLO_RESPONSE, &hello).unwrap();
+            let (kind, connect) = transport.receive().unwrap();
+            assert_eq!(kind, CONNECT_REQUEST);
+            assert_eq!(decode(&connect).unwrap().get(&1), None);
+            let mut connected = Vec::new();
+            if !password_ok {
+                varint(&mut connected, 1, 1);
+            }
+            transport.send(CONNECT_RESPONSE, &connected).unwrap();
+            if !password_ok {
+                return;
+            }
+            assert_eq!(transport.receive().unwrap().0, LIST_ENTITIES_REQUEST);
+            for (i, object_id) in ["meter_power", "meter_voltage", "meter_wifi_signal"]
+                .iter()
+                .enumerate()
+            {
+                let mut entity = Vec::new();
+                bytes(&mut entity, 1, object_id.as_bytes());
+                fixed32(&mut entity, 2, 0x1000 + i as u32);
+                bytes(&mut entity, 6, b"W");
+                transport
+                    .send(LIST_ENTITIES_SENSOR_RESPONSE, &entity)
+                    .unwrap();
+            }
+            transport.send(LIST_ENTITIES_DONE_RESPONSE, &[]).unwrap();
+            assert_eq!(transport.receive().unwrap().0, SUBSCRIBE_STATES_REQUEST);
+            for (key, state, missing) in [(0x1000, 1450.5, false), (0x1001, 0.0, true)] {
+                let mut msg = Vec::new();
+                fixed32(&mut msg, 1, key);
+                fixed32(&mut msg, 2, f32::to_bits(state));
+                if missing {
+                    varint(&mut msg, 3, 1);
+                }
+                transport.send(SENSOR_STATE_RESPONSE, &msg).unwrap();
+            }
+            transport.send(PING_REQUEST, &[]).unwrap();
+            assert_eq!(transport.receive().unwrap().0, PING_RESPONSE);
+            // until the client hangs up.
+            while transport.receive().is_ok() {}
+        }
+
+        fn wait_for(sensor: &EspHomeSensor) -> Vec<(String, f64)> {
+            for _ in 0..50 {
+                if let Ok(res) = sensor.measure() {
+                    return res.into_iter().map(|r| (r.name, r.value)).collect();
+                }
+                thread::sleep(time::Duration::from_millis(100));
+            }
+            panic!("no states within 5s.");
+        }
+
+        fn device(listener: &TcpListener, key: Option<Vec<u8>>) -> Option<Device> {
+            Some(Device {
+                address: listener.local_addr().unwrap().to_string(),
+                key,
+                password: String::new(),
+            })
+        }
+
+        // Tests for success.
+
+        #[test]
+        fn test_plain_for_success() {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let mut sensor =
+                EspHomeSensor::new("esp".to_string(), entities(), device(&listener, None));
+            let (stream, _) = listener.accept().unwrap();
+            let server = thread::spawn(move || serve(&mut Plain::new(stream).unwrap(), true));
+            // voltage is missing; temperature never sent.
+            assert_eq!(wait_for(&sensor), vec![("esp_power".to_string(), 1450.5)]);
+            sensor.shutdown();
+            server.join().unwrap();
+            assert!(sensor.measure().is_err());
+        }
+
+        #[test]
+        fn test_noise_for_success() {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let mut sensor = EspHomeSensor::new(
+                "esp".to_string(),
+                entities(),
+                device(&listener, Some(KEY.to_vec())),
+            );
+            let (stream, _) = listener.accept().unwrap();
+            let server = thread::spawn(move || {
+                let mut buf = vec![0; 65535];
+                let mut handshake = builder(&KEY).build_responder().unwrap();
+                let mut frames = stream.try_clone().unwrap();
+                // the client hello, then the handshake.
+                assert!(read_frame(&mut frames).unwrap().is_empty());
+                let msg = read_frame(&mut frames).unwrap();
+                handshake.read_message(&msg[1..], &mut buf).unwrap();
+                write_frame(&mut frames, b"\x01meter\x00").unwrap();
+                let len = handshake.write_message(&[], &mut buf).unwrap();
+                write_frame(&mut frames, &[&[0], &buf[..len]].concat()).unwrap();
+                let mut noise =
+                    Noise::new(stream, handshake.into_transport_mode().unwrap()).unwrap();
+                serve(&mut noise, true);
+            });
+            assert_eq!(wait_for(&sensor), vec![("esp_power".to_string(), 1450.5)]);
+            sensor.shutdown();
+            server.join().unwrap();
+        }
+
+        // Tests for failure.
+
+        #[test]
+        fn test_session_for_failure() {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let address = listener.local_addr().unwrap();
+            let server = thread::spawn(move || {
+                let (stream, _) = listener.accept().unwrap();
+                serve(&mut Plain::new(stream).unwrap(), false);
+                // an encrypted device answers plaintext with a noise frame.
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut hello = [0; 64];
+                std::io::Read::read(&mut stream, &mut hello).unwrap();
+                std::io::Write::write_all(&mut stream, b"\x01\x00\x00").unwrap();
+            });
+            let cache: Cache = Arc::new(Mutex::new(HashMap::new()));
+            let ids = ["meter_power".to_string()];
+            let mut plain = Plain::new(TcpStream::connect(address).unwrap()).unwrap();
+            let err = session(&mut plain, "", &ids, &cache).unwrap_err();
+            assert_eq!(err.to_string(), "invalid password.");
+            let mut plain = Plain::new(TcpStream::connect(address).unwrap()).unwrap();
+            let err = session(&mut plain, "", &ids, &cache).unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                "the device requires encryption; set encryption_key."
+            );
+            server.join().unwrap();
+        }
+
+        // Tests for sanity.
+
+        #[test]
+        fn test_decode_for_sanity() {
+            let mut msg = Vec::new();
+            bytes(&mut msg, 1, b"meter_power");
+            fixed32(&mut msg, 2, 0xdeadbeef);
+            varint(&mut msg, 7, 300);
+            let fields = decode(&msg).unwrap();
+            assert_eq!(fields[&1], Value::Bytes(b"meter_power".to_vec()));
+            assert_eq!(fields[&2], Value::Fixed32(0xdeadbeef));
+            assert_eq!(fields[&7], Value::Varint(300));
+            assert!(decode(&msg[..msg.len() - 1]).is_err());
+        }
+    }
+}
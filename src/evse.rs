@@ -0,0 +1,953 @@
+use std::error::Error;
+use std::io::{self, Read};
+use std::net;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time;
+
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::clock;
+use crate::common;
+use crate::common::{Reading, SensorError};
+use crate::debug;
+use crate::interlock;
+use crate::tz;
+use crate::websocket;
+
+const METRICS: [&str; 4] = ["power", "session_energy", "connected", "charging"];
+// connected & charging are flags.
+const UNITS: [&str; 4] = ["W", "Wh", "", ""];
+
+/// Subset of the go-e (API v2) status.
+#[derive(Deserialize)]
+struct Status {
+    car: u8,
+    nrg: Vec<f64>,
+    wh: f64,
+}
+
+/// Reads the state of a go-e charger via its local HTTP API.
+pub struct GoeSensor {
+    name: String,
+    url: String,
+    client: reqwest::blocking::Client,
+    ring: Arc<debug::Ring>,
+}
+
+impl GoeSensor {
+    pub fn new(name: String, url: String) -> GoeSensor {
+        GoeSensor {
+            ring: debug::ring(&name),
+            name,
+            url,
+            client: reqwest::blocking::Client::new(),
+        }
+    }
+
+    fn get_status(&self) -> Result<Status, SensorError> {
+        let query = format!("{}/api/status?filter=car,nrg,wh", self.url);
+        let started = time::Instant::now();
+        let mut res = match self.client.get(&query).send() {
+            Ok(res) => res,
+            Err(err) => {
+                self.ring.record(&query, None, &err.to_string(), started);
+                return Err(SensorError::from(err));
+            }
+        };
+        let mut body: String = String::new();
+        res.read_to_string(&mut body)?;
+        self.ring
+            .record(&query, Some(res.status().as_u16()), &body, started);
+        if res.status() != 200 {
+            return Err(SensorError::Http(format!(
+                "Status code was not 200; but: {}.",
+                res.status()
+            )));
+        }
+        let status: Status =
+            serde_json::from_str(&body).map_err(|e| SensorError::Parse(e.to_string()))?;
+        if status.nrg.len() < 12 {
+            return Err(SensorError::Protocol(
+                "Energy array is too short.".to_string(),
+            ));
+        }
+        Ok(status)
+    }
+}
+
+impl common::Sensor for GoeSensor {
+    fn get_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = Vec::new();
+        for metric in METRICS {
+            names.push(format!("{}_{}", self.name, metric));
+        }
+        names
+    }
+
+    fn get_units(&self) -> Vec<String> {
+        UNITS.iter().map(|u| u.to_string()).collect()
+    }
+
+    fn measure(&self) -> Result<Vec<Reading>, SensorError> {
+        let status = self.get_status()?;
+        // car: 1 = idle, 2 = charging, 3 = waiting for car, 4 = complete, 5 = error.
+        let connected = if status.car > 1 { 1.0 } else { 0.0 };
+        let charging = if status.car == 2 { 1.0 } else { 0.0 };
+        let values = vec![status.nrg[11], status.wh, connected, charging];
+        Ok(common::readings(self.get_names(), values))
+    }
+}
+
+/// Measurands of OCPP 1.6 MeterValues; a sample without one is an energy meter reading.
+const POWER: &str = "Power.Active.Import";
+const ENERGY: &str = "Energy.Active.Import.Register";
+
+/// What a charge point reported last via OCPP 1.6J.
+#[derive(Debug, Default, PartialEq)]
+struct Reported {
+    /// Whether the charge point is connected to us right now.
+    online: bool,
+    /// In W.
+    power: Option<f64>,
+    /// The reading of its energy meter in Wh.
+    register: Option<f64>,
+    /// The reading of the energy meter when the current (or last) transaction started.
+    meter_start: Option<f64>,
+    /// The status of the connector, e.g. Charging.
+    status: Option<String>,
+    transactions: i64,
+}
+
+impl Reported {
+    /// Handles a call of the charge point; returns the answer to send, if any.
+    fn handle(&mut self, message: &str, now: f64, heartbeat: u64) -> Option<String> {
+        let call: Vec<serde_json::Value> = serde_json::from_str(message).ok()?;
+        // [2, id, action, payload]; we never call the charge point, so there is nothing else to expect.
+        let (id, action, payload) = match call.as_slice() {
+            [kind, id, action, payload] if kind == 2 => (id, action.as_str()?, payload),
+            _ => return None,
+        };
+        let accepted = json!({"idTagInfo": {"status": "Accepted"}});
+        let answer = match action {
+            "BootNotification" => json!({
+                "status": "Accepted",
+                "currentTime": tz::Zone::Utc.rfc3339(now),
+                "interval": heartbeat
+            }),
+            "Heartbeat" => json!({"currentTime": tz::Zone::Utc.rfc3339(now)}),
+            "StatusNotification" => {
+                // connector 0 is the charge point itself.
+                if payload["connectorId"].as_i64() != Some(0) {
+                    self.status = payload["status"].as_str().map(String::from);
+                }
+                json!({})
+            }
+            "MeterValues" => {
+                self.meter_values(payload);
+                json!({})
+            }
+            "Authorize" => accepted,
+            "StartTransaction" => {
+                self.meter_start = payload["meterStart"].as_f64();
+                self.register = self.meter_start.or(self.register);
+                self.transactions += 1;
+                json!({"transactionId": self.transactions, "idTagInfo": {"status": "Accepted"}})
+            }
+            "StopTransaction" => {
+                self.register = payload["meterStop"].as_f64().or(self.register);
+                self.power = Some(0.0);
+                accepted
+            }
+            other => {
+                let error = json!([
+                    4,
+                    id,
+                    "NotImplemented",
+                    format!("{} is not supported.", other),
+                    {}
+                ]);
+                return Some(error.to_string());
+            }
+        };
+        Some(json!([3, id, answer]).to_string())
+    }
+
+    /// Takes power and meter reading from the latest sampled values; power given per phase only is summed up.
+    fn meter_values(&mut self, payload: &serde_json::Value) {
+        let samples = match payload["meterValue"]
+            .as_array()
+            .and_then(|v| v.last())
+            .and_then(|v| v["sampledValue"].as_array())
+        {
+            Some(samples) => samples,
+            None => return,
+        };
+        let (mut total, mut phases) = (None, None);
+        for sample in samples {
+            let value = match sample["value"]
+                .as_str()
+                .and_then(|v| v.trim().parse::<f64>().ok())
+            {
+                Some(value) => value,
+                None => continue,
+            };
+            // W and Wh unless given in kW or kWh.
+            let value = match sample["unit"].as_str() {
+                Some(unit) if unit.starts_with('k') => value * 1000.0,
+                _ => value,
+            };
+            let measurand = sample["measurand"].as_str().unwrap_or(ENERGY);
+            match (measurand, sample.get("phase").is_some()) {
+                (POWER, false) => total = Some(value),
+                (POWER, true) => *phases.get_or_insert(0.0) += value,
+                (ENERGY, false) => self.register = Some(value),
+                _ => {}
+            }
+        }
+        if let Some(power) = total.or(phases) {
+            self.power = Some(power);
+        }
+    }
+
+    fn readings(&self, names: Vec<String>) -> Vec<Reading> {
+        let session = match (self.register, self.meter_start) {
+            (Some(register), Some(start)) => Some(register - start),
+            _ => None,
+        };
+        let connected = self.status.as_deref().map(|status| match status {
+            "Preparing" | "Charging" | "SuspendedEVSE" | "SuspendedEV" | "Finishing" => 1.0,
+            _ => 0.0,
+        });
+        let charging = self
+            .status
+            .as_deref()
+            .map(|status| if status == "Charging" { 1.0 } else { 0.0 });
+        names
+            .into_iter()
+            .zip([self.power, session, connected, charging])
+            .filter_map(|(name, value)| Some(Reading::new(name, value?)))
+            .collect()
+    }
+}
+
+/// A central system (OCPP 1.6J) a charge point connects to, via websocket on listen; reports what it sends.
+///
+/// Meant for a single charge point; a second one connecting would report into the same columns.
+pub struct OcppSensor {
+    name: String,
+    listen: String,
+    /// Seconds between the heartbeats of the charge point.
+    heartbeat: u64,
+    reported: Arc<Mutex<Reported>>,
+    stop: Arc<AtomicBool>,
+    addr: Option<net::SocketAddr>,
+    handle: Option<thread::JoinHandle<()>>,
+}
+
+impl OcppSensor {
+    pub(crate) fn new(name: String, listen: String, heartbeat: u64) -> OcppSensor {
+        OcppSensor {
+            name,
+            listen,
+            heartbeat,
+            reported: Arc::new(Mutex::new(Reported::default())),
+            stop: Arc::new(AtomicBool::new(false)),
+            addr: None,
+            handle: None,
+        }
+    }
+
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub(crate) fn addr(&self) -> Option<net::SocketAddr> {
+        self.addr
+    }
+
+    fn stop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+impl Drop for OcppSensor {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+fn serve(
+    listener: net::TcpListener,
+    reported: Arc<Mutex<Reported>>,
+    stop: Arc<AtomicBool>,
+    heartbeat: u64,
+) {
+    while !stop.load(Ordering::Relaxed) {
+        match listener.accept() {
+            Ok((stream, peer)) => {
+                let (reported, stop) = (reported.clone(), stop.clone());
+                thread::spawn(move || {
+                    if let Err(err) = converse(stream, &reported, &stop, heartbeat) {
+                        eprintln!("Lost the charge point at {}: {}", peer, err);
+                    }
+                    reported.lock().expect("OCPP lock poisoned.").online = false;
+                });
+            }
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                thread::sleep(time::Duration::from_millis(50));
+            }
+            Err(err) => eprintln!("Could not accept a connection: {}", err),
+        }
+    }
+}
+
+/// Answers the calls of a charge point until it disconnects or the sensor shuts down.
+fn converse(
+    mut stream: net::TcpStream,
+    reported: &Mutex<Reported>,
+    stop: &AtomicBool,
+    heartbeat: u64,
+) -> io::Result<()> {
+    stream.set_nonblocking(false)?;
+    // polled, so the thread notices when to stop.
+    stream.set_read_timeout(Some(time::Duration::from_secs(1)))?;
+    let path = websocket::handshake(&mut stream, "ocpp1.6")?;
+    eprintln!("Charge point {} connected.", path.trim_start_matches('/'));
+    reported.lock().expect("OCPP lock poisoned.").online = true;
+    while !stop.load(Ordering::Relaxed) {
+        match stream.peek(&mut [0; 1]) {
+            Ok(0) => return Ok(()),
+            Ok(_) => {}
+            Err(err)
+                if matches!(
+                    err.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                continue
+            }
+            Err(err) => return Err(err),
+        }
+        let message = match websocket::read_message(&mut stream)? {
+            Some(message) => message,
+            None => return Ok(()),
+        };
+        let now = clock::epoch_secs(time::SystemTime::now());
+        let answer = reported
+            .lock()
+            .expect("OCPP lock poisoned.")
+            .handle(&message, now, heartbeat);
+        if let Some(answer) = answer {
+            websocket::write_frame(&mut stream, websocket::TEXT, answer.as_bytes(), None)?;
+        }
+    }
+    Ok(())
+}
+
+impl common::Sensor for OcppSensor {
+    fn get_names(&self) -> Vec<String> {
+        METRICS
+            .iter()
+            .map(|metric| format!("{}_{}", self.name, metric))
+            .collect()
+    }
+
+    fn get_units(&self) -> Vec<String> {
+        UNITS.iter().map(|u| u.to_string()).collect()
+    }
+
+    fn init(&mut self) -> Result<(), SensorError> {
+        let listener = net::TcpListener::bind(&self.listen)?;
+        listener.set_nonblocking(true)?;
+        self.addr = Some(listener.local_addr()?);
+        let (reported, stop, heartbeat) =
+            (self.reported.clone(), self.stop.clone(), self.heartbeat);
+        self.handle = Some(thread::spawn(move || {
+            serve(listener, reported, stop, heartbeat)
+        }));
+        eprintln!("Waiting for charge points on ws://{}.", self.listen);
+        Ok(())
+    }
+
+    fn shutdown(&mut self) {
+        self.stop();
+    }
+
+    fn measure(&self) -> Result<Vec<Reading>, SensorError> {
+        let reported = self.reported.lock().expect("OCPP lock poisoned.");
+        if !reported.online {
+            return Err(SensorError::Protocol(
+                "no charge point connected.".to_string(),
+            ));
+        }
+        Ok(reported.readings(self.get_names()))
+    }
+}
+
+/// Decides on the charge current given the available surplus power.
+///
+/// Once charging it will hold the minimum current for `hold` iterations before pausing, so the
+/// charger is not switched on and off for every passing cloud.
+pub(crate) struct CurrentLimiter {
+    /// Whole amps, as chargers take them.
+    min_current: u8,
+    max_current: u8,
+    watts_per_amp: f64,
+    hold: u32,
+    below: u32,
+    current: Option<u8>,
+}
+
+impl CurrentLimiter {
+    pub(crate) fn new(
+        min_current: u8,
+        max_current: u8,
+        phases: f64,
+        voltage: f64,
+        hold: u32,
+    ) -> CurrentLimiter {
+        CurrentLimiter {
+            min_current,
+            max_current,
+            watts_per_amp: phases * voltage,
+            hold,
+            below: 0,
+            current: None,
+        }
+    }
+
+    /// Given the surplus (exported) power, returns the new current limit; None means paused.
+    pub(crate) fn next(&mut self, surplus: f64) -> Option<u8> {
+        if surplus.is_nan() {
+            // no (valid) data - keep on doing what we do.
+            return self.current;
+        }
+        // what we draw ourselves is available to us as well.
+        let own = self.current.unwrap_or(0) as f64 * self.watts_per_amp;
+        let amps = ((surplus + own) / self.watts_per_amp).floor();
+        if amps >= self.min_current as f64 {
+            self.below = 0;
+            self.current = Some(amps.min(self.max_current as f64) as u8);
+        } else if self.current.is_some() {
+            self.below += 1;
+            if self.below >= self.hold {
+                self.current = None;
+            } else {
+                self.current = Some(self.min_current);
+            }
+        }
+        self.current
+    }
+
+    /// Pauses charging; e.g. while the data can't be trusted.
+    pub(crate) fn pause(&mut self) -> Option<u8> {
+        self.below = 0;
+        self.current = None;
+        self.current
+    }
+}
+
+/// Drives the current limit of a go-e charger from a surplus power column.
+pub struct GoeController {
+    name: String,
+    url: String,
+    surplus_column: String,
+    limiter: CurrentLimiter,
+    last: Option<Option<u8>>,
+    client: reqwest::blocking::Client,
+}
+
+impl GoeController {
+    pub(crate) fn new(
+        name: String,
+        url: String,
+        surplus_column: String,
+        limiter: CurrentLimiter,
+    ) -> GoeController {
+        GoeController {
+            name,
+            url,
+            surplus_column,
+            limiter,
+            last: None,
+            client: reqwest::blocking::Client::new(),
+        }
+    }
+
+    fn set(&self, key: &str, value: u8) -> Result<(), Box<dyn Error>> {
+        let query = format!("{}/api/set?{}={}", self.url, key, value);
+        let res = self.client.get(query).send()?;
+        if res.status() != 200 {
+            return Err(Box::from(format!(
+                "Status code was not 200 when setting {}; but: {}.",
+                key,
+                res.status()
+            )));
+        }
+        Ok(())
+    }
+
+    fn apply(&self, current: Option<u8>) -> Result<(), Box<dyn Error>> {
+        match current {
+            // frc: 0 = neutral, 1 = off.
+            Some(amps) => {
+                self.set("amp", amps)?;
+                self.set("frc", 0)
+            }
+            None => self.set("frc", 1),
+        }
+    }
+}
+
+impl common::Component for GoeController {
+    fn get_names(&self) -> Vec<String> {
+        vec![format!("{}_current_limit", self.name)]
+    }
+
+    fn update(&mut self, names: &[String], values: &[f64]) -> Vec<f64> {
+        let surplus = match names.iter().position(|n| n == &self.surplus_column) {
+            Some(i) => values[i],
+            None => f64::NAN,
+        };
+        // fail-safe: don't act on implausible data.
+        let current = if interlock::is_suspect(names, values) {
+            self.limiter.pause()
+        } else {
+            self.limiter.next(surplus)
+        };
+        if self.last != Some(current) {
+            match self.apply(current) {
+                Ok(_) => self.last = Some(current),
+                Err(err) => eprintln!("Could not set charge current: {}", err),
+            }
+        }
+        vec![current.unwrap_or(0) as f64]
+    }
+}
+
+/// Charge points speaking OCPP 1.6J.
+pub(crate) fn ocpp_sensor_type() -> common::SensorType {
+    common::SensorType {
+        name: "ocpp",
+        required: &["listen"],
+        optional: &["heartbeat_secs"],
+        create: |name, sensor_cfg, _| {
+            let heartbeat = match sensor_cfg.get("heartbeat_secs") {
+                None => 300,
+                Some(v) => v.as_integer().filter(|i| *i > 0).ok_or_else(|| {
+                    common::ConfigError::Invalid(
+                        "heartbeat_secs must be a positive integer.".to_string(),
+                    )
+                })?,
+            };
+            let listen = sensor_cfg["listen"].as_str().ok_or_else(|| {
+                common::ConfigError::Invalid(
+                    "listen must be an address, e.g. '0.0.0.0:8887'.".to_string(),
+                )
+            })?;
+            Ok(Box::new(OcppSensor::new(
+                name.to_string(),
+                listen.to_string(),
+                heartbeat as u64,
+            )))
+        },
+    }
+}
+
+/// go-e chargers.
+pub(crate) fn sensor_type() -> common::SensorType {
+    common::SensorType {
+        name: "goe",
+        required: &["url"],
+        optional: &[],
+        create: |name, sensor_cfg, _| {
+            Ok(Box::new(GoeSensor::new(
+                name.to_string(),
+                sensor_cfg["url"]
+                    .as_str()
+                    .unwrap_or("http://192.168.178.2")
+                    .to_string(),
+            )))
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+    use crate::common::{Component, Sensor};
+
+    /// What a charge point sends while charging: its status, the start of a session and its meter.
+    const CHARGING: [&str; 3] = [
+        r#"[2, "2", "StatusNotification", {"connectorId": 1, "errorCode": "NoError", "status": "Charging"}]"#,
+        r#"[2, "3", "StartTransaction", {"connectorId": 1, "idTag": "ogc", "meterStart": 1000, "timestamp": "2023-11-14T00:00:00Z"}]"#,
+        r#"[2, "4", "MeterValues", {"connectorId": 1, "transactionId": 1, "meterValue": [{"timestamp": "2023-11-14T00:01:00Z", "sampledValue": [
+            {"value": "4.2", "measurand": "Power.Active.Import", "unit": "kW"},
+            {"value": "2234.5", "measurand": "Energy.Active.Import.Register", "unit": "Wh"}]}]}]"#,
+    ];
+
+    /// Sends a call as the charge point would - masked - and returns the answer.
+    fn call(stream: &mut net::TcpStream, message: &str) -> serde_json::Value {
+        websocket::write_frame(
+            stream,
+            websocket::TEXT,
+            message.as_bytes(),
+            Some([7, 3, 5, 1]),
+        )
+        .unwrap();
+        let (opcode, _, payload) = websocket::read_frame(stream).unwrap();
+        assert_eq!(opcode, websocket::TEXT);
+        serde_json::from_slice(&payload).unwrap()
+    }
+
+    const STATUS: &str = "{\"car\": 2, \"wh\": 1234.5, \"nrg\": [230, 231, 229, 0, 6.1, 6.0, 6.2, \
+    1400, 1380, 1420, 0, 4200, 99, 99, 99, 0]}";
+
+    // Tests for success.
+
+    #[test]
+    fn test_get_names_for_success() {
+        let sensor = GoeSensor::new("wallbox".to_string(), "".to_string());
+        sensor.get_names();
+    }
+
+    #[test]
+    fn test_update_for_success() {
+        let mut server = mockito::Server::new();
+        let set = server
+            .mock("GET", "/api/set")
+            .match_query(mockito::Matcher::Any)
+            .with_body("{}")
+            .expect(2)
+            .create();
+        let mut ctrl = GoeController::new(
+            "wallbox".to_string(),
+            server.url(),
+            "surplus".to_string(),
+            CurrentLimiter::new(6, 16, 1.0, 230.0, 3),
+        );
+        let res = ctrl.update(&["surplus".to_string()], &[2000.0]);
+        assert_eq!(res, vec![8.0]);
+        // unchanged limit does not hit the charger again.
+        ctrl.update(&["surplus".to_string()], &[0.0]);
+        set.assert();
+    }
+
+    #[test]
+    fn test_handle_for_success() {
+        let mut reported = Reported::default();
+        let answer = reported
+            .handle(r#"[2, "1", "Heartbeat", {}]"#, 1699920000.0, 300)
+            .unwrap();
+        assert_eq!(answer, r#"[3,"1",{"currentTime":"2023-11-14T00:00:00Z"}]"#);
+        for message in CHARGING {
+            reported.handle(message, 1699920000.0, 300).unwrap();
+        }
+        let names = OcppSensor::new("wallbox".to_string(), String::new(), 300).get_names();
+        let values: Vec<f64> = reported.readings(names).iter().map(|r| r.value).collect();
+        assert_eq!(values, vec![4200.0, 1234.5, 1.0, 1.0]);
+    }
+
+    // Tests for failure.
+
+    #[test]
+    fn test_handle_for_failure() {
+        let mut reported = Reported::default();
+        let answer = reported
+            .handle(r#"[2, "1", "DataTransfer", {"vendorId": "foo"}]"#, 0.0, 300)
+            .unwrap();
+        assert!(answer.starts_with(r#"[4,"1","NotImplemented","#));
+        // answers & garbage go unanswered.
+        assert_eq!(reported.handle(r#"[3, "1", {}]"#, 0.0, 300), None);
+        assert_eq!(reported.handle("ohno", 0.0, 300), None);
+        assert_eq!(reported, Reported::default());
+        // nothing known yet; nothing to report.
+        assert!(reported.readings(vec!["foo".to_string(); 4]).is_empty());
+    }
+
+    #[test]
+    fn test_ocpp_for_failure() {
+        let mut sensor = OcppSensor::new("wallbox".to_string(), "127.0.0.1:0".to_string(), 300);
+        // nobody connected.
+        assert!(matches!(sensor.measure(), Err(SensorError::Protocol(_))));
+        sensor.init().unwrap();
+        assert!(matches!(sensor.measure(), Err(SensorError::Protocol(_))));
+        // the address is taken.
+        let addr = sensor.addr().unwrap().to_string();
+        let mut other = OcppSensor::new("wallbox".to_string(), addr, 300);
+        assert!(matches!(other.init(), Err(SensorError::Io(_))));
+    }
+
+    #[test]
+    fn test_measure_for_failure() {
+        let mut server = mockito::Server::new();
+        server
+            .mock("GET", "/api/status")
+            .match_query(mockito::Matcher::Any)
+            .with_status(500)
+            .create();
+        let sensor = GoeSensor::new("wallbox".to_string(), server.url());
+        assert!(matches!(sensor.measure(), Err(SensorError::Http(_))));
+
+        server
+            .mock("GET", "/api/status")
+            .match_query(mockito::Matcher::Any)
+            .with_body("{\"car\": 1, \"wh\": 0, \"nrg\": [230]}")
+            .create();
+        assert!(matches!(sensor.measure(), Err(SensorError::Protocol(_))));
+    }
+
+    #[test]
+    fn test_update_for_failure() {
+        let mut server = mockito::Server::new();
+        server
+            .mock("GET", "/api/set")
+            .match_query(mockito::Matcher::Any)
+            .with_status(500)
+            .create();
+        let mut ctrl = GoeController::new(
+            "wallbox".to_string(),
+            server.url(),
+            "surplus".to_string(),
+            CurrentLimiter::new(6, 16, 1.0, 230.0, 3),
+        );
+        // missing column means no data.
+        assert_eq!(ctrl.update(&["foo".to_string()], &[2000.0]), vec![0.0]);
+        // charger not reachable: decision is still reported; retried next time.
+        assert_eq!(ctrl.update(&["surplus".to_string()], &[2000.0]), vec![8.0]);
+        assert_eq!(ctrl.last, None);
+    }
+
+    // Tests for sanity.
+
+    #[test]
+    fn test_get_names_for_sanity() {
+        let sensor = GoeSensor::new("wallbox".to_string(), "".to_string());
+        assert_eq!(
+            sensor.get_names(),
+            vec![
+                "wallbox_power",
+                "wallbox_session_energy",
+                "wallbox_connected",
+                "wallbox_charging"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_get_units_for_sanity() {
+        let sensor = GoeSensor::new("wallbox".to_string(), "".to_string());
+        assert_eq!(sensor.get_units(), vec!["W", "Wh", "", ""]);
+    }
+
+    #[test]
+    fn test_measure_for_sanity() {
+        let mut server = mockito::Server::new();
+        server
+            .mock("GET", "/api/status")
+            .match_query(mockito::Matcher::UrlEncoded(
+                "filter".into(),
+                "car,nrg,wh".into(),
+            ))
+            .with_body(STATUS)
+            .create();
+        let sensor = GoeSensor::new("wallbox".to_string(), server.url());
+        let values: Vec<f64> = sensor.measure().unwrap().iter().map(|r| r.value).collect();
+        assert_eq!(values, vec![4200.0, 1234.5, 1.0, 1.0]);
+    }
+
+    #[test]
+    fn test_meter_values_for_sanity() {
+        let mut reported = Reported::default();
+        // per phase only, and a meter reading without measurand.
+        reported.handle(
+            r#"[2, "1", "MeterValues", {"connectorId": 1, "meterValue": [{"timestamp": "2023-11-14T00:01:00Z", "sampledValue": [
+                {"value": "1400", "measurand": "Power.Active.Import", "phase": "L1"},
+                {"value": "1380", "measurand": "Power.Active.Import", "phase": "L2"},
+                {"value": "1.5", "unit": "kWh"},
+                {"value": "16", "measurand": "Current.Import", "phase": "L1"}]}]}]"#,
+            0.0,
+            300,
+        );
+        assert_eq!(reported.power, Some(2780.0));
+        assert_eq!(reported.register, Some(1500.0));
+        // the charge point itself is no connector.
+        reported.handle(
+            r#"[2, "2", "StatusNotification", {"connectorId": 0, "errorCode": "NoError", "status": "Available"}]"#,
+            0.0,
+            300,
+        );
+        assert_eq!(reported.status, None);
+        // a finished session keeps its energy.
+        reported.handle(
+            r#"[2, "3", "StartTransaction", {"connectorId": 1, "idTag": "ogc", "meterStart": 1500, "timestamp": "2023-11-14T00:00:00Z"}]"#,
+            0.0,
+            300,
+        );
+        reported.handle(
+            r#"[2, "4", "StopTransaction", {"transactionId": 1, "meterStop": 3500, "timestamp": "2023-11-14T01:00:00Z"}]"#,
+            0.0,
+            300,
+        );
+        assert_eq!(reported.power, Some(0.0));
+        let values: Vec<f64> = reported
+            .readings(vec!["foo".to_string(); 4])
+            .iter()
+            .map(|r| r.value)
+            .collect();
+        assert_eq!(values, vec![0.0, 2000.0]);
+    }
+
+    #[test]
+    fn test_ocpp_for_sanity() {
+        let mut sensor = OcppSensor::new("wallbox".to_string(), "127.0.0.1:0".to_string(), 300);
+        sensor.init().unwrap();
+        let mut stream = net::TcpStream::connect(sensor.addr().unwrap()).unwrap();
+        write!(
+            stream,
+            "GET /ocpp/CP1 HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+            Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\
+            Sec-WebSocket-Protocol: ocpp1.6\r\n\r\n"
+        )
+        .unwrap();
+        let mut response = Vec::new();
+        let mut byte = [0; 1];
+        while !response.ends_with(b"\r\n\r\n") {
+            stream.read_exact(&mut byte).unwrap();
+            response.push(byte[0]);
+        }
+        let response = String::from_utf8(response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 101 "));
+        assert!(response.contains("Sec-WebSocket-Protocol: ocpp1.6\r\n"));
+
+        let answer = call(
+            &mut stream,
+            r#"[2, "1", "BootNotification", {"chargePointVendor": "go-e", "chargePointModel": "HOME"}]"#,
+        );
+        assert_eq!(answer[0], 3);
+        assert_eq!(answer[1], "1");
+        assert_eq!(answer[2]["status"], "Accepted");
+        assert_eq!(answer[2]["interval"], 300);
+        assert_eq!(call(&mut stream, CHARGING[0]), json!([3, "2", {}]));
+        assert_eq!(call(&mut stream, CHARGING[1])[2]["transactionId"], 1);
+        assert_eq!(call(&mut stream, CHARGING[2]), json!([3, "4", {}]));
+        let values: Vec<f64> = sensor.measure().unwrap().iter().map(|r| r.value).collect();
+        assert_eq!(values, vec![4200.0, 1234.5, 1.0, 1.0]);
+
+        // gone; its values are not reported any longer.
+        websocket::write_frame(&mut stream, websocket::CLOSE, &[], Some([1, 2, 3, 4])).unwrap();
+        assert_eq!(
+            websocket::read_frame(&mut stream).unwrap().0,
+            websocket::CLOSE
+        );
+        let mut tries = 0;
+        while sensor.measure().is_ok() && tries < 100 {
+            thread::sleep(time::Duration::from_millis(10));
+            tries += 1;
+        }
+        assert!(matches!(sensor.measure(), Err(SensorError::Protocol(_))));
+        sensor.shutdown();
+    }
+
+    #[test]
+    fn test_next_for_sanity() {
+        let mut limiter = CurrentLimiter::new(6, 16, 1.0, 230.0, 2);
+        // not enough to start.
+        assert_eq!(limiter.next(1000.0), None);
+        // start & cap at max.
+        assert_eq!(limiter.next(1500.0), Some(6));
+        assert_eq!(limiter.next(10000.0), Some(16));
+        // we use 16 * 230 W ourselves; only slightly importing now.
+        assert_eq!(limiter.next(-100.0), Some(15));
+        // drop below the minimum: hold at minimum current, then pause.
+        assert_eq!(limiter.next(-3000.0), Some(6));
+        assert_eq!(limiter.next(-500.0), None);
+        // no data keeps the state.
+        assert_eq!(limiter.next(f64::NAN), None);
+        // resume.
+        assert_eq!(limiter.next(1400.0), Some(6));
+    }
+
+    #[test]
+    fn test_update_for_sanity() {
+        let mut server = mockito::Server::new();
+        let amp = server
+            .mock("GET", "/api/set")
+            .match_query(mockito::Matcher::UrlEncoded("amp".into(), "8".into()))
+            .with_body("{\"amp\": true}")
+            .expect(1)
+            .create();
+        let resume = server
+            .mock("GET", "/api/set")
+            .match_query(mockito::Matcher::UrlEncoded("frc".into(), "0".into()))
+            .with_body("{\"frc\": true}")
+            .expect(1)
+            .create();
+        let pause = server
+            .mock("GET", "/api/set")
+            .match_query(mockito::Matcher::UrlEncoded("frc".into(), "1".into()))
+            .with_body("{\"frc\": true}")
+            .expect(1)
+            .create();
+        let mut ctrl = GoeController::new(
+            "wallbox".to_string(),
+            server.url(),
+            "surplus".to_string(),
+            CurrentLimiter::new(6, 16, 1.0, 230.0, 1),
+        );
+        let names = vec!["timestamp".to_string(), "surplus".to_string()];
+        assert_eq!(ctrl.update(&names, &[0.0, 1900.0]), vec![8.0]);
+        assert_eq!(ctrl.update(&names, &[0.0, -1900.0]), vec![0.0]);
+        amp.assert();
+        resume.assert();
+        pause.assert();
+    }
+
+    #[test]
+    fn test_fail_safe_for_sanity() {
+        let mut server = mockito::Server::new();
+        let amp = server
+            .mock("GET", "/api/set")
+            .match_query(mockito::Matcher::UrlEncoded("amp".into(), "8".into()))
+            .with_body("{\"amp\": true}")
+            .expect(2)
+            .create();
+        let resume = server
+            .mock("GET", "/api/set")
+            .match_query(mockito::Matcher::UrlEncoded("frc".into(), "0".into()))
+            .with_body("{\"frc\": true}")
+            .expect(2)
+            .create();
+        let pause = server
+            .mock("GET", "/api/set")
+            .match_query(mockito::Matcher::UrlEncoded("frc".into(), "1".into()))
+            .with_body("{\"frc\": true}")
+            .expect(1)
+            .create();
+        let mut ctrl = GoeController::new(
+            "wallbox".to_string(),
+            server.url(),
+            "surplus".to_string(),
+            CurrentLimiter::new(6, 16, 1.0, 230.0, 10),
+        );
+        let names = vec![
+            "timestamp".to_string(),
+            "surplus".to_string(),
+            "data_suspect".to_string(),
+        ];
+        assert_eq!(ctrl.update(&names, &[0.0, 1900.0, 0.0]), vec![8.0]);
+        // suspect data pauses right away - despite the hold time.
+        assert_eq!(ctrl.update(&names, &[30.0, 1900.0, 1.0]), vec![0.0]);
+        assert_eq!(ctrl.update(&names, &[60.0, 1900.0, 1.0]), vec![0.0]);
+        // and resumes once the interlock cleared.
+        assert_eq!(ctrl.update(&names, &[90.0, 1900.0, 0.0]), vec![8.0]);
+        amp.assert();
+        resume.assert();
+        pause.assert();
+    }
+}
@@ -0,0 +1,425 @@
+use std::collections::HashMap;
+use std::io::Read;
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::thread;
+use std::time;
+
+use crate::common;
+use crate::common::{ConfigError, Reading, SensorError};
+
+/// How often to look whether the command is done.
+const POLL: time::Duration = time::Duration::from_millis(10);
+
+/// Runs a command and parses what it prints: numbers separated by whitespace or commas - one per name, in order - or a
+/// flat JSON object keyed by the names.
+pub struct ExecSensor {
+    name: String,
+    command: String,
+    args: Vec<String>,
+    timeout: time::Duration,
+    names: Vec<String>,
+}
+
+impl ExecSensor {
+    pub(crate) fn new(
+        name: String,
+        command: String,
+        args: Vec<String>,
+        timeout: time::Duration,
+        names: Vec<String>,
+    ) -> ExecSensor {
+        ExecSensor {
+            name,
+            command,
+            args,
+            timeout,
+            names,
+        }
+    }
+
+    /// The values of the names in the output of the command.
+    fn parse(&self, output: &str) -> Result<Vec<Reading>, SensorError> {
+        let output = output.trim();
+        if output.starts_with('{') {
+            let object: HashMap<String, serde_json::Value> = serde_json::from_str(output)
+                .map_err(|e| SensorError::Parse(format!("not a flat JSON object: {}", e)))?;
+            // names missing from the object are left out.
+            return self
+                .names
+                .iter()
+                .filter_map(|n| Some((n, object.get(n)?)))
+                .map(|(n, v)| match v.as_f64() {
+                    Some(value) => Ok(Reading::new(format!("{}_{}", self.name, n), value)),
+                    None => Err(SensorError::Parse(format!("{} is not a number: {}", n, v))),
+                })
+                .collect();
+        }
+        let values: Vec<f64> = output
+            .split(|c: char| c.is_whitespace() || c == ',')
+            .filter(|v| !v.is_empty())
+            .map(|v| common::parse_number(v, true).map_err(|e| SensorError::Parse(e.to_string())))
+            .collect::<Result<_, _>>()?;
+        if values.len() != self.names.len() {
+            return Err(SensorError::Parse(format!(
+                "expected {} values; got: {}.",
+                self.names.len(),
+                values.len()
+            )));
+        }
+        Ok(common::readings(common::Sensor::get_names(self), values))
+    }
+}
+
+/// Runs a command to completion; killed - and reaped - once it runs out of time.
+pub(crate) fn run(
+    command: &str,
+    args: &[String],
+    timeout: time::Duration,
+) -> Result<String, SensorError> {
+    let (status, stdout, stderr) = output(command, args, timeout)?;
+    if !status.success() {
+        return Err(SensorError::Protocol(format!(
+            "{} failed ({}): {}",
+            command,
+            status,
+            stderr.trim()
+        )));
+    }
+    Ok(stdout)
+}
+
+/// Like run, but returns the exit status and both outputs whether it succeeded or not.
+pub(crate) fn output(
+    command: &str,
+    args: &[String],
+    timeout: time::Duration,
+) -> Result<(ExitStatus, String, String), SensorError> {
+    let mut child = Command::new(command)
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| SensorError::Io(format!("could not run {}: {}", command, e)))?;
+    // read while it runs; a full pipe would block it.
+    let stdout = drain(child.stdout.take());
+    let stderr = drain(child.stderr.take());
+    let start = time::Instant::now();
+    let status = loop {
+        match child.try_wait()? {
+            Some(status) => break status,
+            None if start.elapsed() >= timeout => {
+                kill(&mut child);
+                return Err(SensorError::Timeout(format!(
+                    "{} did not finish within {:?}; killed it.",
+                    command, timeout
+                )));
+            }
+            None => thread::sleep(POLL),
+        }
+    };
+    let stdout = stdout.join().unwrap_or_default();
+    let stderr = stderr.join().unwrap_or_default();
+    Ok((status, stdout, stderr))
+}
+
+/// Reads a pipe of the child to its end on a thread of its own.
+fn drain<R: Read + Send + 'static>(pipe: Option<R>) -> thread::JoinHandle<String> {
+    thread::spawn(move || {
+        let mut res = String::new();
+        if let Some(mut pipe) = pipe {
+            let _ = pipe.read_to_string(&mut res);
+        }
+        res
+    })
+}
+
+/// Kills the child and waits for it, so it does not linger as a zombie.
+fn kill(child: &mut Child) {
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+impl common::Sensor for ExecSensor {
+    fn get_names(&self) -> Vec<String> {
+        self.names
+            .iter()
+            .map(|n| format!("{}_{}", self.name, n))
+            .collect()
+    }
+
+    fn get_units(&self) -> Vec<String> {
+        vec!["unknown".to_string(); self.names.len()]
+    }
+
+    fn measure(&self) -> Result<Vec<Reading>, SensorError> {
+        let output = run(&self.command, &self.args, self.timeout)?;
+        self.parse(&output)
+    }
+}
+
+/// Switches something by running commands, e.g. `systemctl start batch.service`.
+///
+/// Commands are given as the program followed by its arguments. The state command prints 1 when on and 0 when off;
+/// without one the state is the one last set - off until then.
+pub struct ExecActuator {
+    on: Vec<String>,
+    off: Vec<String>,
+    state: Option<Vec<String>>,
+    timeout: time::Duration,
+    last: Option<bool>,
+}
+
+impl ExecActuator {
+    pub(crate) fn new(
+        on: Vec<String>,
+        off: Vec<String>,
+        state: Option<Vec<String>>,
+        timeout: time::Duration,
+    ) -> ExecActuator {
+        if on.is_empty() || off.is_empty() || state.as_ref().map(|s| s.is_empty()) == Some(true) {
+            panic!("the commands of an exec actuator must not be empty.");
+        }
+        ExecActuator {
+            on,
+            off,
+            state,
+            timeout,
+            last: None,
+        }
+    }
+}
+
+impl common::Actuator for ExecActuator {
+    fn set_state(&mut self, on: bool) -> Result<(), SensorError> {
+        let command = if on { &self.on } else { &self.off };
+        run(&command[0], &command[1..], self.timeout)?;
+        self.last = Some(on);
+        Ok(())
+    }
+
+    fn get_state(&self) -> Result<bool, SensorError> {
+        match &self.state {
+            Some(command) => {
+                let output = run(&command[0], &command[1..], self.timeout)?;
+                let state = common::parse_number(&output, true)
+                    .map_err(|e| SensorError::Parse(e.to_string()))?;
+                Ok(state != 0.0)
+            }
+            None => Ok(self.last.unwrap_or(false)),
+        }
+    }
+}
+
+/// Commands printing values; the timeout defaults to 10 secs.
+pub(crate) fn sensor_type() -> common::SensorType {
+    common::SensorType {
+        name: "exec",
+        required: &["command", "names"],
+        optional: &["args"],
+        create: |name, sensor_cfg, _| {
+            let strings = |field: &str| -> Result<Vec<String>, ConfigError> {
+                let invalid =
+                    || ConfigError::Invalid(format!("{} must be an array of strings.", field));
+                match sensor_cfg.get(field) {
+                    None => Ok(Vec::new()),
+                    Some(v) => v
+                        .as_array()
+                        .ok_or_else(invalid)?
+                        .iter()
+                        .map(|s| s.as_str().map(|s| s.to_string()).ok_or_else(invalid))
+                        .collect(),
+                }
+            };
+            let timeout = sensor_cfg
+                .get("timeout_secs")
+                .map(|v| v.as_float().or_else(|| v.as_integer().map(|i| i as f64)))
+                .unwrap_or(Some(10.0))
+                .filter(|secs| *secs > 0.0)
+                .ok_or_else(|| {
+                    ConfigError::Invalid("timeout_secs must be positive.".to_string())
+                })?;
+            Ok(Box::new(ExecSensor::new(
+                name.to_string(),
+                sensor_cfg["command"]
+                    .as_str()
+                    .ok_or_else(|| ConfigError::Invalid("command must be a string.".to_string()))?
+                    .to_string(),
+                strings("args")?,
+                time::Duration::from_secs_f64(timeout),
+                strings("names")?,
+            )))
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::common::{Actuator, Sensor};
+
+    fn exec(command: &str, args: &[&str], names: &[&str]) -> ExecSensor {
+        ExecSensor::new(
+            "heatpump".to_string(),
+            command.to_string(),
+            args.iter().map(|a| a.to_string()).collect(),
+            time::Duration::from_millis(500),
+            names.iter().map(|n| n.to_string()).collect(),
+        )
+    }
+
+    fn values(readings: Vec<Reading>) -> Vec<(String, f64)> {
+        readings.into_iter().map(|r| (r.name, r.value)).collect()
+    }
+
+    // Tests for success.
+
+    #[test]
+    fn test_measure_for_success() {
+        let sensor = exec("/bin/echo", &["42.5 7,-1"], &["flow", "return", "defrost"]);
+        assert_eq!(
+            sensor.get_names(),
+            vec!["heatpump_flow", "heatpump_return", "heatpump_defrost"]
+        );
+        assert_eq!(
+            values(sensor.measure().unwrap()),
+            vec![
+                ("heatpump_flow".to_string(), 42.5),
+                ("heatpump_return".to_string(), 7.0),
+                ("heatpump_defrost".to_string(), -1.0)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_json_for_success() {
+        let sensor = exec(
+            "/bin/echo",
+            &["{\"flow\": 42.5, \"cop\": 3, \"mode\": \"heating\"}"],
+            &["flow", "return", "cop"],
+        );
+        // unknown keys are ignored, missing ones left out.
+        assert_eq!(
+            values(sensor.measure().unwrap()),
+            vec![
+                ("heatpump_flow".to_string(), 42.5),
+                ("heatpump_cop".to_string(), 3.0)
+            ]
+        );
+    }
+
+    // Tests for failure.
+
+    #[test]
+    fn test_measure_for_failure() {
+        fs::write(
+            "exec_test0.sh",
+            "echo 1 2\necho 'no heat pump on /dev/ttyUSB0' >&2\nexit 3\n",
+        )
+        .unwrap();
+        let sensor = exec("/bin/sh", &["exec_test0.sh"], &["flow", "return"]);
+        match sensor.measure() {
+            Err(SensorError::Protocol(msg)) => {
+                assert!(msg.contains("no heat pump on /dev/ttyUSB0"), "{}", msg)
+            }
+            other => panic!("unexpected: {:?}", other),
+        }
+        fs::remove_file("exec_test0.sh").unwrap();
+
+        let sensor = exec("/bin/missing_command", &[], &["flow"]);
+        assert!(matches!(sensor.measure(), Err(SensorError::Io(_))));
+    }
+
+    #[test]
+    fn test_parse_for_failure() {
+        // too few, or no numbers.
+        let sensor = exec("/bin/echo", &["42.5"], &["flow", "return"]);
+        assert!(matches!(sensor.measure(), Err(SensorError::Parse(_))));
+        let sensor = exec("/bin/echo", &["42.5 warm"], &["flow", "return"]);
+        assert!(matches!(sensor.measure(), Err(SensorError::Parse(_))));
+        let sensor = exec("/bin/echo", &["{\"flow\": \"warm\"}"], &["flow"]);
+        assert!(matches!(sensor.measure(), Err(SensorError::Parse(_))));
+        let sensor = exec("/bin/echo", &["{\"flow\": 42.5"], &["flow"]);
+        assert!(matches!(sensor.measure(), Err(SensorError::Parse(_))));
+    }
+
+    #[test]
+    fn test_actuator_for_failure() {
+        let mut actuator = ExecActuator::new(
+            vec![
+                "/bin/sh".to_string(),
+                "-c".to_string(),
+                "echo busy >&2; exit 1".to_string(),
+            ],
+            vec!["/bin/true".to_string()],
+            None,
+            time::Duration::from_millis(500),
+        );
+        match actuator.set_state(true) {
+            Err(SensorError::Protocol(msg)) => assert!(msg.contains("busy"), "{}", msg),
+            other => panic!("unexpected: {:?}", other),
+        }
+        // still off.
+        assert!(!actuator.get_state().unwrap());
+    }
+
+    // Tests for sanity.
+
+    #[test]
+    fn test_timeout_for_sanity() {
+        let sensor = exec("/bin/sleep", &["10"], &["flow"]);
+        let start = time::Instant::now();
+        // every time; the command is killed each time rather than piling up.
+        for _ in 0..3 {
+            assert!(matches!(sensor.measure(), Err(SensorError::Timeout(_))));
+        }
+        assert!(start.elapsed() < time::Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_actuator_for_sanity() {
+        let flag = "exec_test1.state";
+        let command = |script: &str| {
+            vec![
+                "/bin/sh".to_string(),
+                "-c".to_string(),
+                script.replace("FLAG", flag),
+            ]
+        };
+        let mut actuator = ExecActuator::new(
+            command("echo 1 > FLAG"),
+            command("echo 0 > FLAG"),
+            Some(command("cat FLAG")),
+            time::Duration::from_millis(500),
+        );
+        actuator.set_state(true).unwrap();
+        assert!(actuator.get_state().unwrap());
+        actuator.set_state(false).unwrap();
+        assert!(!actuator.get_state().unwrap());
+        fs::remove_file(flag).unwrap();
+    }
+
+    #[test]
+    fn test_sensor_type_for_sanity() {
+        let sensor_cfg: toml::value::Table =
+            toml::from_str("type='exec'\ncommand='/bin/echo'\nargs=['1', '2']\nnames=['a', 'b']")
+                .unwrap();
+        let sensor = (sensor_type().create)("cli", &sensor_cfg, "state").unwrap();
+        assert_eq!(sensor.get_names(), vec!["cli_a", "cli_b"]);
+        assert_eq!(
+            sensor
+                .measure()
+                .unwrap()
+                .iter()
+                .map(|r| r.value)
+                .collect::<Vec<_>>(),
+            vec![1.0, 2.0]
+        );
+        let sensor_cfg: toml::value::Table =
+            toml::from_str("type='exec'\ncommand='/bin/echo'\nnames=['a']\ntimeout_secs=0")
+                .unwrap();
+        assert!((sensor_type().create)("cli", &sensor_cfg, "state").is_err());
+    }
+}
@@ -0,0 +1,410 @@
+/// Binary operators; in order of increasing precedence per group.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Op {
+    Or,
+    And,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Eq,
+    Ne,
+    Add,
+    Sub,
+    Mul,
+    Div,
+}
+
+/// Functions taking any number of arguments.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Func {
+    Min,
+    Max,
+}
+
+/// An expression over the (named) columns of a row.
+///
+/// Booleans are represented as 1.0 and 0.0; NaN means unknown (e.g. a missing column or a division by zero) and
+/// propagates unless the outcome of a logical operator is already decided by the other side.
+#[derive(Debug, PartialEq)]
+pub(crate) enum Expr {
+    Num(f64),
+    Column(String),
+    Neg(Box<Expr>),
+    Not(Box<Expr>),
+    Binary(Box<Expr>, Op, Box<Expr>),
+    Call(Func, Vec<Expr>),
+}
+
+fn truth(value: f64) -> f64 {
+    if value.is_nan() {
+        f64::NAN
+    } else if value != 0.0 {
+        1.0
+    } else {
+        0.0
+    }
+}
+
+impl Expr {
+    pub(crate) fn eval(&self, names: &[String], values: &[f64]) -> f64 {
+        match self {
+            Expr::Num(n) => *n,
+            Expr::Column(name) => match names.iter().position(|n| n == name) {
+                Some(i) => values[i],
+                None => f64::NAN,
+            },
+            Expr::Neg(e) => -e.eval(names, values),
+            Expr::Not(e) => 1.0 - truth(e.eval(names, values)),
+            Expr::Call(func, args) => {
+                let args: Vec<f64> = args.iter().map(|a| a.eval(names, values)).collect();
+                if args.iter().any(|a| a.is_nan()) {
+                    return f64::NAN;
+                }
+                let pick = match func {
+                    Func::Min => f64::min,
+                    Func::Max => f64::max,
+                };
+                args.into_iter().reduce(pick).unwrap_or(f64::NAN)
+            }
+            Expr::Binary(l, Op::And, r) => {
+                let l = truth(l.eval(names, values));
+                if l == 0.0 {
+                    return 0.0;
+                }
+                let r = truth(r.eval(names, values));
+                if r == 0.0 {
+                    0.0
+                } else {
+                    l * r
+                }
+            }
+            Expr::Binary(l, Op::Or, r) => {
+                let l = truth(l.eval(names, values));
+                if l == 1.0 {
+                    return 1.0;
+                }
+                let r = truth(r.eval(names, values));
+                if r == 1.0 {
+                    1.0
+                } else {
+                    l + r
+                }
+            }
+            Expr::Binary(l, op, r) => {
+                let (l, r) = (l.eval(names, values), r.eval(names, values));
+                let cmp = |res: bool| {
+                    if l.is_nan() || r.is_nan() {
+                        f64::NAN
+                    } else {
+                        res as u8 as f64
+                    }
+                };
+                match op {
+                    Op::Add => l + r,
+                    Op::Sub => l - r,
+                    Op::Mul => l * r,
+                    Op::Div if r == 0.0 => f64::NAN,
+                    Op::Div => l / r,
+                    Op::Lt => cmp(l < r),
+                    Op::Le => cmp(l <= r),
+                    Op::Gt => cmp(l > r),
+                    Op::Ge => cmp(l >= r),
+                    Op::Eq => cmp(l == r),
+                    _ => cmp(l != r),
+                }
+            }
+        }
+    }
+
+    /// Names of the columns the expression refers to.
+    pub(crate) fn columns(&self) -> Vec<&str> {
+        match self {
+            Expr::Num(_) => Vec::new(),
+            Expr::Column(name) => vec![name.as_str()],
+            Expr::Neg(e) | Expr::Not(e) => e.columns(),
+            Expr::Binary(l, _, r) => {
+                let mut res = l.columns();
+                res.extend(r.columns());
+                res
+            }
+            Expr::Call(_, args) => args.iter().flat_map(|a| a.columns()).collect(),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Token {
+    Num(f64),
+    Ident(String),
+    Sym(&'static str),
+}
+
+const SYMBOLS: [&str; 16] = [
+    "<=", ">=", "==", "!=", "&&", "||", "<", ">", "+", "-", "*", "/", "!", "(", ")", ",",
+];
+
+fn tokenize(input: &str) -> Result<Vec<Token>, String> {
+    let mut res = Vec::new();
+    let mut rest = input.trim_start();
+    while !rest.is_empty() {
+        let c = rest.chars().next().unwrap();
+        if c.is_ascii_digit() || c == '.' {
+            let end = rest
+                .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+                .unwrap_or(rest.len());
+            let num = rest[..end]
+                .parse()
+                .map_err(|_| format!("invalid number: {}.", &rest[..end]))?;
+            res.push(Token::Num(num));
+            rest = &rest[end..];
+        } else if c.is_alphabetic() || c == '_' {
+            let end = rest
+                .find(|c: char| !(c.is_alphanumeric() || c == '_'))
+                .unwrap_or(rest.len());
+            res.push(Token::Ident(rest[..end].to_string()));
+            rest = &rest[end..];
+        } else {
+            let sym = SYMBOLS
+                .iter()
+                .find(|s| rest.starts_with(*s))
+                .ok_or(format!("unexpected character: {}.", c))?;
+            res.push(Token::Sym(sym));
+            rest = &rest[sym.len()..];
+        }
+        rest = rest.trim_start();
+    }
+    Ok(res)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek_op(&self, ops: &[(&str, Op)]) -> Option<Op> {
+        match self.tokens.get(self.pos) {
+            Some(Token::Sym(s)) => ops.iter().find(|(sym, _)| sym == s).map(|(_, op)| *op),
+            _ => None,
+        }
+    }
+
+    fn binary(
+        &mut self,
+        ops: &[(&str, Op)],
+        next: fn(&mut Parser) -> Result<Expr, String>,
+    ) -> Result<Expr, String> {
+        let mut left = next(self)?;
+        while let Some(op) = self.peek_op(ops) {
+            self.pos += 1;
+            left = Expr::Binary(Box::new(left), op, Box::new(next(self)?));
+        }
+        Ok(left)
+    }
+
+    fn or(&mut self) -> Result<Expr, String> {
+        self.binary(&[("||", Op::Or)], Parser::and)
+    }
+
+    fn and(&mut self) -> Result<Expr, String> {
+        self.binary(&[("&&", Op::And)], Parser::comparison)
+    }
+
+    fn comparison(&mut self) -> Result<Expr, String> {
+        let ops = [
+            ("<", Op::Lt),
+            ("<=", Op::Le),
+            (">", Op::Gt),
+            (">=", Op::Ge),
+            ("==", Op::Eq),
+            ("!=", Op::Ne),
+        ];
+        self.binary(&ops, Parser::additive)
+    }
+
+    fn additive(&mut self) -> Result<Expr, String> {
+        self.binary(&[("+", Op::Add), ("-", Op::Sub)], Parser::multiplicative)
+    }
+
+    fn multiplicative(&mut self) -> Result<Expr, String> {
+        self.binary(&[("*", Op::Mul), ("/", Op::Div)], Parser::unary)
+    }
+
+    fn unary(&mut self) -> Result<Expr, String> {
+        match self.tokens.get(self.pos) {
+            Some(Token::Sym("-")) => {
+                self.pos += 1;
+                Ok(Expr::Neg(Box::new(self.unary()?)))
+            }
+            Some(Token::Sym("!")) => {
+                self.pos += 1;
+                Ok(Expr::Not(Box::new(self.unary()?)))
+            }
+            _ => self.primary(),
+        }
+    }
+
+    fn primary(&mut self) -> Result<Expr, String> {
+        self.pos += 1;
+        match self.tokens.get(self.pos - 1) {
+            Some(Token::Num(n)) => Ok(Expr::Num(*n)),
+            Some(Token::Ident(name)) if self.tokens.get(self.pos) == Some(&Token::Sym("(")) => {
+                let func = match name.as_str() {
+                    "min" => Func::Min,
+                    "max" => Func::Max,
+                    _ => return Err(format!("unknown function: {}.", name)),
+                };
+                self.pos += 1;
+                let mut args = vec![self.or()?];
+                loop {
+                    match self.tokens.get(self.pos) {
+                        Some(Token::Sym(",")) => {
+                            self.pos += 1;
+                            args.push(self.or()?);
+                        }
+                        Some(Token::Sym(")")) => {
+                            self.pos += 1;
+                            return Ok(Expr::Call(func, args));
+                        }
+                        _ => return Err("missing closing parenthesis.".to_string()),
+                    }
+                }
+            }
+            Some(Token::Ident(name)) => Ok(Expr::Column(name.clone())),
+            Some(Token::Sym("(")) => {
+                let expr = self.or()?;
+                match self.tokens.get(self.pos) {
+                    Some(Token::Sym(")")) => {
+                        self.pos += 1;
+                        Ok(expr)
+                    }
+                    _ => Err("missing closing parenthesis.".to_string()),
+                }
+            }
+            Some(token) => Err(format!("unexpected token: {:?}.", token)),
+            None => Err("unexpected end of expression.".to_string()),
+        }
+    }
+}
+
+/// Parses an expression like `solar_elevation >= 0 || pv_power < 50` or `max(grid_power, 0) + pv_power`.
+pub(crate) fn parse(input: &str) -> Result<Expr, String> {
+    let mut parser = Parser {
+        tokens: tokenize(input)?,
+        pos: 0,
+    };
+    let expr = parser.or()?;
+    if parser.pos < parser.tokens.len() {
+        return Err(format!(
+            "unexpected token: {:?}.",
+            parser.tokens[parser.pos]
+        ));
+    }
+    Ok(expr)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(input: &str, names: &[&str], values: &[f64]) -> f64 {
+        let names: Vec<String> = names.iter().map(|n| n.to_string()).collect();
+        parse(input).unwrap().eval(&names, values)
+    }
+
+    // Tests for success.
+
+    #[test]
+    fn test_parse_for_success() {
+        parse("1").unwrap();
+        parse("pv_power < 50").unwrap();
+        parse("!(a > 100 && b > 100) || -c * 2.5 / (d - 1) != 0").unwrap();
+    }
+
+    // Tests for failure.
+
+    #[test]
+    fn test_parse_for_failure() {
+        assert!(parse("").is_err());
+        assert!(parse("a <").is_err());
+        assert!(parse("(a < 1").is_err());
+        assert!(parse("a < 1)").is_err());
+        assert!(parse("a = 1").is_err());
+        assert!(parse("1.2.3").is_err());
+        assert!(parse("a b").is_err());
+        assert!(parse("min()").is_err());
+        assert!(parse("min(a, b").is_err());
+        assert!(parse("min(a,)").is_err());
+        assert!(parse("avg(a, b)").is_err());
+        assert!(parse("a, b").is_err());
+    }
+
+    #[test]
+    fn test_eval_for_failure() {
+        // unknown columns are unknown values.
+        assert!(eval("foo < 1", &[], &[]).is_nan());
+        assert!(eval("!foo", &[], &[]).is_nan());
+        assert!(eval("foo < 1 && 1", &[], &[]).is_nan());
+        assert!(eval("max(foo, 1)", &[], &[]).is_nan());
+        // as are divisions by zero.
+        assert!(eval("a / b", &["a", "b"], &[3.0, 0.0]).is_nan());
+        assert!(eval("a / (b - 1)", &["a", "b"], &[3.0, 1.0]).is_nan());
+    }
+
+    // Tests for sanity.
+
+    #[test]
+    fn test_eval_for_sanity() {
+        assert_eq!(eval("1 + 2 * 3", &[], &[]), 7.0);
+        assert_eq!(eval("(1 + 2) * 3", &[], &[]), 9.0);
+        assert_eq!(eval("10 - 4 - 3", &[], &[]), 3.0);
+        assert_eq!(eval("-2 * 3", &[], &[]), -6.0);
+        assert_eq!(eval("a / b", &["a", "b"], &[3.0, 2.0]), 1.5);
+        assert_eq!(eval("a >= 1 && a <= 2", &["a"], &[1.5]), 1.0);
+        assert_eq!(eval("a < 1 || a > 2", &["a"], &[1.5]), 0.0);
+        assert_eq!(eval("!(a == 1.5)", &["a"], &[1.5]), 0.0);
+        assert_eq!(eval("a != 1", &["a"], &[1.5]), 1.0);
+    }
+
+    #[test]
+    fn test_functions_for_sanity() {
+        let names = ["grid_power", "pv_power"];
+        assert_eq!(
+            eval("min(grid_power, pv_power)", &names, &[-200.0, 800.0]),
+            -200.0
+        );
+        assert_eq!(eval("max(grid_power, 0)", &names, &[-200.0, 800.0]), 0.0);
+        assert_eq!(
+            eval("max(grid_power, 0) + pv_power", &names, &[300.0, 800.0]),
+            1100.0
+        );
+        assert_eq!(eval("min(3, max(1, 2), 5)", &[], &[]), 2.0);
+        assert_eq!(eval("-max(1, 2) * 2", &[], &[]), -4.0);
+        assert_eq!(eval("min(4)", &[], &[]), 4.0);
+        // a column named like a function.
+        assert_eq!(eval("max + 1", &["max"], &[1.0]), 2.0);
+        assert_eq!(
+            parse("max(grid_power, 0) - pv_power").unwrap().columns(),
+            vec!["grid_power", "pv_power"]
+        );
+    }
+
+    #[test]
+    fn test_unknown_for_sanity() {
+        // logical operators decide if one side is enough.
+        assert_eq!(eval("foo < 1 && 0", &[], &[]), 0.0);
+        assert_eq!(eval("foo < 1 || 1", &[], &[]), 1.0);
+        assert!(eval("foo < 1 || 0", &[], &[]).is_nan());
+        assert!(eval("a < 1", &["a"], &[f64::NAN]).is_nan());
+    }
+
+    #[test]
+    fn test_columns_for_sanity() {
+        let expr = parse("solar_elevation >= 0 || pv_power < 50 + -offset").unwrap();
+        assert_eq!(
+            expr.columns(),
+            vec!["solar_elevation", "pv_power", "offset"]
+        );
+    }
+}
@@ -0,0 +1,220 @@
+use crate::common;
+
+/// Horizons (in seconds) of the forecasts and the suffixes of their columns.
+const HORIZONS: [(f64, &str); 2] = [(300.0, "forecast_5m"), (900.0, "forecast_15m")];
+
+/// Double exponential (Holt) smoothing for irregularly spaced samples; the trend is per second.
+#[derive(Debug, PartialEq)]
+pub(crate) struct Holt {
+    alpha: f64,
+    beta: f64,
+    level: f64,
+    trend: Option<f64>,
+    last: f64,
+}
+
+impl Holt {
+    /// Starts from a first sample x at time t.
+    pub(crate) fn new(alpha: f64, beta: f64, t: f64, x: f64) -> Holt {
+        Holt {
+            alpha,
+            beta,
+            level: x,
+            trend: None,
+            last: t,
+        }
+    }
+
+    pub(crate) fn update(&mut self, t: f64, x: f64) {
+        let dt = t - self.last;
+        if dt <= 0.0 {
+            return;
+        }
+        match self.trend {
+            // the second sample determines the initial trend.
+            None => {
+                self.trend = Some((x - self.level) / dt);
+                self.level = x;
+            }
+            Some(trend) => {
+                let level = self.alpha * x + (1.0 - self.alpha) * (self.level + trend * dt);
+                self.trend =
+                    Some(self.beta * (level - self.level) / dt + (1.0 - self.beta) * trend);
+                self.level = level;
+            }
+        }
+        self.last = t;
+    }
+
+    /// Forecast for the time t.
+    pub(crate) fn forecast(&self, t: f64) -> f64 {
+        self.level + self.trend.unwrap_or(0.0) * (t - self.last)
+    }
+}
+
+/// Forecasts a column using double exponential smoothing.
+///
+/// Components work on the raw row of each iteration, so the smoothing sees every sample. After
+/// a gap of more than max_gap secs, or more than max_nan missing samples in a row, it starts over.
+pub struct ForecastComponent {
+    column: String,
+    alpha: f64,
+    beta: f64,
+    max_gap: f64,
+    max_nan: u32,
+    nan_run: u32,
+    holt: Option<Holt>,
+}
+
+impl ForecastComponent {
+    pub fn new(
+        column: String,
+        alpha: f64,
+        beta: f64,
+        max_gap: f64,
+        max_nan: u32,
+    ) -> ForecastComponent {
+        ForecastComponent {
+            column,
+            alpha,
+            beta,
+            max_gap,
+            max_nan,
+            nan_run: 0,
+            holt: None,
+        }
+    }
+}
+
+impl common::Component for ForecastComponent {
+    fn get_names(&self) -> Vec<String> {
+        HORIZONS
+            .iter()
+            .map(|(_, suffix)| format!("{}_{}", self.column, suffix))
+            .collect()
+    }
+
+    fn update(&mut self, names: &[String], values: &[f64]) -> Vec<f64> {
+        let now = values[0];
+        let x = match names.iter().position(|n| n == &self.column) {
+            Some(i) => values[i],
+            None => f64::NAN,
+        };
+
+        if x.is_nan() {
+            self.nan_run += 1;
+            if self.nan_run > self.max_nan {
+                self.holt = None;
+            }
+        } else {
+            self.nan_run = 0;
+            match &mut self.holt {
+                Some(holt) if now - holt.last <= self.max_gap => holt.update(now, x),
+                _ => self.holt = Some(Holt::new(self.alpha, self.beta, now, x)),
+            }
+        }
+
+        match &self.holt {
+            Some(holt) => HORIZONS
+                .iter()
+                .map(|(h, _)| holt.forecast(now + h))
+                .collect(),
+            None => vec![f64::NAN; HORIZONS.len()],
+        }
+    }
+
+    fn get_inputs(&self) -> Option<Vec<String>> {
+        Some(vec![self.column.clone()])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::Component;
+
+    fn names() -> Vec<String> {
+        vec!["timestamp".to_string(), "pv_power".to_string()]
+    }
+
+    // Tests for success.
+
+    #[test]
+    fn test_get_names_for_success() {
+        let comp = ForecastComponent::new("pv_power".to_string(), 0.5, 0.1, 300.0, 10);
+        assert_eq!(
+            comp.get_names(),
+            vec!["pv_power_forecast_5m", "pv_power_forecast_15m"]
+        );
+    }
+
+    // Tests for failure.
+
+    #[test]
+    fn test_update_for_failure() {
+        // unknown column.
+        let mut comp = ForecastComponent::new("foo".to_string(), 0.5, 0.1, 300.0, 10);
+        let res = comp.update(&names(), &[0.0, 100.0]);
+        assert!(res.iter().all(|v| v.is_nan()));
+    }
+
+    #[test]
+    fn test_holt_for_failure() {
+        // samples back in time are ignored.
+        let mut holt = Holt::new(0.5, 0.1, 100.0, 10.0);
+        holt.update(50.0, 20.0);
+        assert_eq!(holt, Holt::new(0.5, 0.1, 100.0, 10.0));
+    }
+
+    // Tests for sanity.
+
+    #[test]
+    fn test_holt_for_sanity() {
+        // reference computed by hand for alpha = 0.5, beta = 0.5 and steps of 10s.
+        let mut holt = Holt::new(0.5, 0.5, 0.0, 100.0);
+        assert_eq!(holt.forecast(300.0), 100.0);
+        holt.update(10.0, 110.0);
+        // level 110, trend 1/s.
+        assert_eq!(holt.forecast(10.0), 110.0);
+        assert_eq!(holt.forecast(310.0), 410.0);
+        holt.update(20.0, 130.0);
+        // level = 0.5 * 130 + 0.5 * (110 + 10) = 125; trend = 0.5 * 15 / 10 + 0.5 * 1 = 1.25.
+        assert_eq!(holt.forecast(20.0), 125.0);
+        assert_eq!(holt.forecast(320.0), 125.0 + 1.25 * 300.0);
+        holt.update(30.0, 130.0);
+        // level = 0.5 * 130 + 0.5 * (125 + 12.5) = 133.75; trend = 0.5 * 0.875 + 0.5 * 1.25.
+        assert_eq!(holt.forecast(30.0), 133.75);
+        assert_eq!(holt.forecast(930.0), 133.75 + 1.0625 * 900.0);
+    }
+
+    #[test]
+    fn test_constant_for_sanity() {
+        let mut comp = ForecastComponent::new("pv_power".to_string(), 0.25, 0.125, 300.0, 10);
+        for i in 0..100 {
+            let res = comp.update(&names(), &[i as f64 * 30.0, 500.0]);
+            assert_eq!(res, vec![500.0, 500.0]);
+        }
+    }
+
+    #[test]
+    fn test_reinit_for_sanity() {
+        let mut comp = ForecastComponent::new("pv_power".to_string(), 0.5, 0.5, 300.0, 2);
+        comp.update(&names(), &[0.0, 100.0]);
+        assert_eq!(comp.update(&names(), &[30.0, 130.0]), vec![430.0, 1030.0]);
+
+        // a short run of missing values keeps projecting the trend.
+        assert_eq!(
+            comp.update(&names(), &[60.0, f64::NAN]),
+            vec![130.0 + 330.0, 130.0 + 930.0]
+        );
+        comp.update(&names(), &[90.0, f64::NAN]);
+        // a longer one starts over.
+        let res = comp.update(&names(), &[120.0, f64::NAN]);
+        assert!(res.iter().all(|v| v.is_nan()));
+        assert_eq!(comp.update(&names(), &[150.0, 200.0]), vec![200.0, 200.0]);
+
+        // so does a long gap.
+        comp.update(&names(), &[180.0, 230.0]);
+        assert_eq!(comp.update(&names(), &[1000.0, 50.0]), vec![50.0, 50.0]);
+    }
+}
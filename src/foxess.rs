@@ -1,24 +1,68 @@
 use crate::common;
+use crate::common::{Reading, SensorError};
+use crate::debug;
+use crate::keys;
 use md5::{Digest, Md5};
 use reqwest::header::{HeaderMap, HeaderValue};
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::error::Error;
 use std::io::Read;
+use std::sync::Arc;
+use std::time;
 
 pub struct FoxEssOpenAPISensor {
     name: String,
-    api_key: String,
+    api_keys: keys::KeyPool,
     inverter_id: String,
     variables: Vec<String>,
     url: String,
+    api_version: ApiVersion,
+    // per variable; the API reports units, but only along with the values.
+    units: HashMap<String, String>,
     client: reqwest::blocking::Client,
+    ring: Arc<debug::Ring>,
+}
+
+/// Revision of the OpenAPI; they differ in path and request shape.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum ApiVersion {
+    /// Asks for a single inverter by "sn".
+    V0,
+    /// Asks for a list of inverters by "sns"; always answers with an array.
+    V1,
+}
+
+impl ApiVersion {
+    pub(crate) fn parse(value: &str) -> ApiVersion {
+        match value {
+            "v0" => ApiVersion::V0,
+            "v1" => ApiVersion::V1,
+            _ => panic!("api_version must be either 'v0' or 'v1'; got: {}.", value),
+        }
+    }
+
+    fn path(&self) -> &'static str {
+        match self {
+            ApiVersion::V0 => "/op/v0/device/real/query",
+            ApiVersion::V1 => "/op/v1/device/real/query",
+        }
+    }
 }
 
 #[derive(Serialize)]
-struct DataRequest {
-    #[serde(rename = "sn")]
-    serial_number: String,
-    variables: Vec<String>,
+#[serde(untagged)]
+enum DataRequest {
+    V0 {
+        #[serde(rename = "sn")]
+        serial_number: String,
+        variables: Vec<String>,
+    },
+    V1 {
+        #[serde(rename = "sns")]
+        serial_numbers: Vec<String>,
+        variables: Vec<String>,
+    },
 }
 #[derive(Deserialize)]
 struct DataEntry {
@@ -33,33 +77,57 @@ struct ResultSet {
     data: Vec<DataEntry>,
 }
 
+/// Error codes FoxESS uses when the request limit of a key is reached.
+const QUOTA_ERRNOS: [usize; 2] = [40400, 40401];
+
+/// Some deployments return a single object instead of a one-element array.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ResultShape {
+    Array(Vec<ResultSet>),
+    Object(ResultSet),
+}
+
+impl Default for ResultShape {
+    fn default() -> Self {
+        ResultShape::Array(Vec::new())
+    }
+}
+
 #[derive(Deserialize)]
 struct DataResponse {
     errno: usize,
-    result: Vec<ResultSet>,
+    // errors come without a result.
+    #[serde(default)]
+    result: ResultShape,
 }
 
 impl FoxEssOpenAPISensor {
     pub fn new(
         name: String,
-        api_key: String,
+        api_keys: keys::KeyPool,
         inverter_id: String,
         variables: Vec<String>,
         url: String,
+        api_version: ApiVersion,
+        units: HashMap<String, String>,
     ) -> FoxEssOpenAPISensor {
         let builder: reqwest::blocking::ClientBuilder = reqwest::blocking::ClientBuilder::new();
         let client = builder.danger_accept_invalid_certs(true).build().unwrap();
         FoxEssOpenAPISensor {
+            ring: debug::ring(&name),
             name,
-            api_key,
+            api_keys,
             inverter_id,
             variables,
             url,
+            api_version,
+            units,
             client,
         }
     }
 
-    pub fn do_query(&self, path: &str, token: &str) -> Result<Vec<f64>, Box<dyn Error>> {
+    pub fn do_query(&self, path: &str, token: &str) -> Result<Vec<Reading>, Box<dyn Error>> {
         let url = format!("{}{}", self.url, path);
 
         // create signature
@@ -79,53 +147,91 @@ impl FoxEssOpenAPISensor {
         headers.insert("Lang", HeaderValue::from_static("en"));
 
         // payload
-        let data_req = DataRequest {
-            serial_number: self.inverter_id.clone(),
-            variables: self.variables.clone(),
+        let data_req = match self.api_version {
+            ApiVersion::V0 => DataRequest::V0 {
+                serial_number: self.inverter_id.clone(),
+                variables: self.variables.clone(),
+            },
+            ApiVersion::V1 => DataRequest::V1 {
+                serial_numbers: vec![self.inverter_id.clone()],
+                variables: self.variables.clone(),
+            },
         };
 
         // post request
-        let mut response = self
+        let started = time::Instant::now();
+        let res = self
             .client
-            .post(url)
+            .post(&url)
             .headers(headers)
             .json(&data_req)
-            .send()?;
+            .send();
+        let mut response = match res {
+            Ok(response) => response,
+            Err(err) => {
+                self.ring.record(&url, None, &err.to_string(), started);
+                return Err(Box::new(SensorError::from(err)));
+            }
+        };
+        let mut body: String = String::new();
+        response
+            .read_to_string(&mut body)
+            .map_err(SensorError::from)?;
+        self.ring
+            .record(&url, Some(response.status().as_u16()), &body, started);
+        if [401, 403, 429].contains(&response.status().as_u16()) {
+            return Err(Box::new(keys::QuotaError(response.status().to_string())));
+        }
         if response.status() != 200 {
-            return Err(Box::from(format!(
+            return Err(Box::new(SensorError::Http(format!(
                 "Status code was not 200; but: {}.",
                 response.status()
-            )));
+            ))));
         }
 
         // parse the result...
-        let mut body: String = String::new();
-        response.read_to_string(&mut body)?;
-        let doc: DataResponse = serde_json::from_str(&body)?;
+        let doc: DataResponse =
+            serde_json::from_str(&body).map_err(|e| SensorError::Parse(e.to_string()))?;
+        if QUOTA_ERRNOS.contains(&doc.errno) {
+            return Err(Box::new(keys::QuotaError(format!("errno {}", doc.errno))));
+        }
         if doc.errno != 0 {
-            return Err(Box::from(format!(
+            return Err(Box::new(SensorError::Protocol(format!(
                 "Error code was not 0; but: {}.",
                 doc.errno
-            )));
+            ))));
         }
 
-        // we ask for 1 inverter atm; expect equal amount of elements to be returned as we request.
-        if doc.result.len() != 1 || doc.result[0].data.len() != self.variables.len() {
-            return Err(Box::from(
-                "Number of data entries does not match number of requested entries.",
-            ));
-        }
-
-        let mut res = Vec::new();
-        for (i, data_entry) in doc.result[0].data.iter().enumerate() {
-            if data_entry.variable != self.variables[i] {
-                // result is ordered; first one we asked for is the first one we should get...
-                return Err(Box::from(format!(
-                    "Expected variable {} got {}.",
-                    self.variables[i], data_entry.variable,
+        let result = match (doc.result, self.api_version) {
+            (ResultShape::Array(result), _) => result,
+            (ResultShape::Object(result), ApiVersion::V0) => vec![result],
+            (ResultShape::Object(_), ApiVersion::V1) => {
+                return Err(Box::new(SensorError::Protocol(
+                    "api_version is v1, but the result is an object instead of an array; try v0."
+                        .to_string(),
                 )));
             }
-            res.push(data_entry.value);
+        };
+
+        // we ask for 1 inverter atm.
+        if result.len() != 1 {
+            return Err(Box::new(SensorError::Protocol(format!(
+                "Expected the data of 1 inverter; got: {}.",
+                result.len()
+            ))));
+        }
+
+        // entries are matched by their variable; whatever order they come in.
+        let res: Vec<Reading> = result[0]
+            .data
+            .iter()
+            .filter(|entry| self.variables.contains(&entry.variable))
+            .map(|entry| Reading::new(format!("{}_{}", self.name, entry.variable), entry.value))
+            .collect();
+        if res.is_empty() {
+            return Err(Box::new(SensorError::Protocol(
+                "None of the requested variables were returned.".to_string(),
+            )));
         }
         Ok(res)
     }
@@ -140,14 +246,69 @@ impl common::Sensor for FoxEssOpenAPISensor {
         names
     }
 
-    fn measure(&self) -> Vec<f64> {
-        match self.do_query("/op/v0/device/real/query", &self.api_key) {
-            Ok(res) => res,
-            Err(err) => {
-                println!("Could not retrieve values: {}", err);
-                vec![-1.0; self.variables.len()]
+    fn get_units(&self) -> Vec<String> {
+        self.variables
+            .iter()
+            .map(|v| {
+                self.units
+                    .get(v)
+                    .cloned()
+                    .unwrap_or_else(|| "unknown".to_string())
+            })
+            .collect()
+    }
+
+    fn measure(&self) -> Result<Vec<Reading>, SensorError> {
+        self.api_keys
+            .with_key(|key| self.do_query(self.api_version.path(), key))
+            .map_err(SensorError::from)
+    }
+}
+
+/// Inverters in the FoxESS cloud; the api_key may be an array of keys to rotate through.
+pub(crate) fn sensor_type() -> common::SensorType {
+    common::SensorType {
+        name: "foxess",
+        required: &["api_key", "inverter_id", "variables"],
+        optional: &["url", "api_version", "units", "key_cooldown_secs"],
+        create: |name, sensor_cfg, state_dir| {
+            let variables: Vec<String> = sensor_cfg["variables"]
+                .as_array()
+                .unwrap_or(&Vec::new())
+                .iter()
+                .map(|c| c.as_str().to_owned().unwrap().to_string())
+                .collect();
+            let mut units = HashMap::new();
+            if let Some(table) = sensor_cfg.get("units").and_then(|v| v.as_table()) {
+                for (variable, unit) in table {
+                    let unit = unit.as_str().ok_or_else(|| {
+                        common::ConfigError::Invalid("units must be strings.".to_string())
+                    })?;
+                    units.insert(variable.to_string(), unit.to_string());
+                }
             }
-        }
+            Ok(Box::new(FoxEssOpenAPISensor::new(
+                name.to_string(),
+                keys::from_config(name, sensor_cfg, "api_key", "bar", state_dir)?,
+                sensor_cfg["inverter_id"]
+                    .as_str()
+                    .unwrap_or("123")
+                    .to_string(),
+                variables,
+                sensor_cfg
+                    .get("url")
+                    .and_then(|v| v.as_str())
+                    .unwrap_or("https://www.foxesscloud.com")
+                    .to_string(),
+                ApiVersion::parse(
+                    sensor_cfg
+                        .get("api_version")
+                        .and_then(|v| v.as_str())
+                        .unwrap_or("v0"),
+                ),
+                units,
+            )))
+        },
     }
 }
 
@@ -156,6 +317,15 @@ mod tests {
     use super::*;
     use crate::common::Sensor;
 
+    fn pool(api_keys: &[&str]) -> keys::KeyPool {
+        keys::KeyPool::new(
+            "fox0".to_string(),
+            api_keys.iter().map(|k| k.to_string()).collect(),
+            60.0,
+            None,
+        )
+    }
+
     macro_rules! test_post_request {
         ($name:ident, $($status:expr, $body:expr, $expected:expr),+) => {
             #[test]
@@ -170,35 +340,127 @@ mod tests {
                         .create();
                     let sensor = FoxEssOpenAPISensor::new(
                         "fox0".to_string(),
-                        "123".to_string(),
+                        pool(&["123"]),
                         "abc".to_string(),
                         vec!["foo".to_string(), "bar".to_string()],
                         url,
+                        ApiVersion::V0,
+                        HashMap::new(),
                     );
-                    let data: Vec<f64> = sensor.measure();
-                    assert_eq!(data, $expected);
+                    assert_eq!(outcome(sensor.measure()), $expected);
                 )*
             }
         }
     }
 
+    const OBJECT_RESULT: &str = "{\"errno\": 0, \"result\": {\"datas\": [\
+        {\"variable\": \"foo\", \"value\": 0.5}, {\"variable\": \"bar\", \"value\": 0.4}]}}";
+    const ARRAY_RESULT: &str = "{\"errno\": 0, \"result\": [{\"datas\": [\
+        {\"variable\": \"foo\", \"value\": 0.5}, {\"variable\": \"bar\", \"value\": 0.4}]}]}";
+
+    /// The readings, or the kind of error.
+    fn outcome(res: Result<Vec<Reading>, SensorError>) -> Result<Vec<Reading>, &'static str> {
+        res.map_err(|err| match err {
+            SensorError::Io(_) => "io",
+            SensorError::Http(_) => "http",
+            SensorError::Parse(_) => "parse",
+            SensorError::Protocol(_) => "protocol",
+            SensorError::Timeout(_) => "timeout",
+        })
+    }
+
+    fn readings(values: &[(&str, f64)]) -> Vec<Reading> {
+        values
+            .iter()
+            .map(|(name, value)| Reading::new(name.to_string(), *value))
+            .collect()
+    }
+
+    fn versioned_sensor(url: String, api_version: ApiVersion) -> FoxEssOpenAPISensor {
+        FoxEssOpenAPISensor::new(
+            "fox0".to_string(),
+            pool(&["123"]),
+            "abc".to_string(),
+            vec!["foo".to_string(), "bar".to_string()],
+            url,
+            api_version,
+            HashMap::new(),
+        )
+    }
+
     // Tests for success.
 
+    test_post_request!(
+        object_result,
+        200,
+        OBJECT_RESULT,
+        Ok(readings(&[("fox0_foo", 0.5), ("fox0_bar", 0.4)]))
+    );
+
+    #[test]
+    fn test_v1_for_success() {
+        let mut server = mockito::Server::new();
+        let mock = server
+            .mock("POST", "/op/v1/device/real/query")
+            .match_body(mockito::Matcher::Json(serde_json::json!({
+                "sns": ["abc"],
+                "variables": ["foo", "bar"]
+            })))
+            .with_status(200)
+            .with_body(ARRAY_RESULT)
+            .create();
+        let sensor = versioned_sensor(server.url(), ApiVersion::V1);
+        assert_eq!(
+            sensor.measure().unwrap(),
+            readings(&[("fox0_foo", 0.5), ("fox0_bar", 0.4)])
+        );
+        mock.assert();
+    }
+
     // Tests for failure.
 
-    test_post_request!(status_not_ok, 406, "", vec![-1.0, -1.0]);
+    #[test]
+    fn test_v1_shape_for_failure() {
+        let mut server = mockito::Server::new();
+        server
+            .mock("POST", "/op/v1/device/real/query")
+            .with_status(200)
+            .with_body(OBJECT_RESULT)
+            .create();
+        let sensor = versioned_sensor(server.url(), ApiVersion::V1);
+        let err = sensor
+            .do_query("/op/v1/device/real/query", "123")
+            .unwrap_err();
+        assert!(err.to_string().contains("api_version is v1"));
+        assert_eq!(outcome(sensor.measure()), Err("protocol"));
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_api_version_for_failure() {
+        ApiVersion::parse("v2");
+    }
+
+    test_post_request!(status_not_ok, 406, "", Err("http"));
+    test_post_request!(quota_exceeded, 429, "", Err("http"));
+    test_post_request!(not_json, 200, "<html>", Err("parse"));
     test_post_request!(
         errno_not_zero,
         200,
         "{\"errno\": 1, \"result\": []}",
-        vec![-1.0, -1.0]
+        Err("protocol")
     );
-    test_post_request!(wrong_order, 200, "{\"errno\": 0, \"result\": [{\"datas\": [{\"variable\": \"bar\", \"value\": 0.5},{\"variable\": \"foo\", \"value\": 0.5}]}]}", vec![-1.0, -1.0]);
     test_post_request!(
-        missing_variable,
+        no_inverter,
         200,
-        "{\"errno\": 0, \"result\": [{\"datas\": [{\"variable\": \"foo\", \"value\": 0.5}]}]}",
-        vec![-1.0, -1.0]
+        "{\"errno\": 0, \"result\": []}",
+        Err("protocol")
+    );
+    test_post_request!(
+        no_variable,
+        200,
+        "{\"errno\": 0, \"result\": [{\"datas\": [{\"variable\": \"baz\", \"value\": 0.5}]}]}",
+        Err("protocol")
     );
 
     // Tests for sanity.
@@ -207,15 +469,31 @@ mod tests {
     fn test_get_names_for_sanity() {
         let sensor = FoxEssOpenAPISensor::new(
             "fox0".to_string(),
-            "123".to_string(),
+            pool(&["123"]),
             "abc".to_string(),
             vec!["foo".to_string(), "bar".to_string()],
             "".to_string(),
+            ApiVersion::V0,
+            HashMap::new(),
         );
         let data: Vec<String> = sensor.get_names();
         assert_eq!(data, vec!["fox0_foo", "fox0_bar"]);
     }
 
+    #[test]
+    fn test_get_units_for_sanity() {
+        let sensor = FoxEssOpenAPISensor::new(
+            "fox0".to_string(),
+            pool(&["123"]),
+            "abc".to_string(),
+            vec!["foo".to_string(), "bar".to_string()],
+            "".to_string(),
+            ApiVersion::V0,
+            HashMap::from([("foo".to_string(), "kW".to_string())]),
+        );
+        assert_eq!(sensor.get_units(), vec!["kW", "unknown"]);
+    }
+
     test_post_request!(
         sanity_check,
         200,
@@ -223,6 +501,66 @@ mod tests {
                 {\"unit\": \"kW\", \"name\": \"Blah\", \"variable\": \"foo\", \"value\": 0.5},\
                 {\"unit\": \"kW\", \"name\": \"Blub\", \"variable\": \"bar\", \"value\": 0.4}],\
                 \"time\": \"2024-02-21 12:34:36 CET+0100\", \"deviceSN\": \"abc\"}]}",
-        vec![0.5, 0.4]
+        Ok(readings(&[("fox0_foo", 0.5), ("fox0_bar", 0.4)]))
     );
+    // matched by name; in any order.
+    test_post_request!(other_order, 200, "{\"errno\": 0, \"result\": [{\"datas\": [{\"variable\": \"bar\", \"value\": 0.4},{\"variable\": \"foo\", \"value\": 0.5}]}]}", Ok(readings(&[("fox0_bar", 0.4), ("fox0_foo", 0.5)])));
+    // missing ones are simply not there.
+    test_post_request!(
+        missing_variable,
+        200,
+        "{\"errno\": 0, \"result\": [{\"datas\": [{\"variable\": \"foo\", \"value\": 0.5}]}]}",
+        Ok(readings(&[("fox0_foo", 0.5)]))
+    );
+
+    #[test]
+    fn test_v0_for_sanity() {
+        let mut server = mockito::Server::new();
+        let mock = server
+            .mock("POST", "/op/v0/device/real/query")
+            .match_body(mockito::Matcher::Json(serde_json::json!({
+                "sn": "abc",
+                "variables": ["foo", "bar"]
+            })))
+            .with_status(200)
+            .with_body(ARRAY_RESULT)
+            .create();
+        let sensor = versioned_sensor(server.url(), ApiVersion::parse("v0"));
+        assert_eq!(
+            sensor.measure().unwrap(),
+            readings(&[("fox0_foo", 0.5), ("fox0_bar", 0.4)])
+        );
+        mock.assert();
+    }
+
+    #[test]
+    fn test_key_rotation_for_sanity() {
+        let mut server = mockito::Server::new();
+        let exhausted = server
+            .mock("POST", "/op/v0/device/real/query")
+            .match_header("token", "123")
+            .with_status(200)
+            .with_body("{\"errno\": 40400, \"msg\": \"request too frequently\"}")
+            .expect(1)
+            .create();
+        let fallback = server
+            .mock("POST", "/op/v0/device/real/query")
+            .match_header("token", "456")
+            .with_status(200)
+            .with_body("{\"errno\": 0, \"result\": [{\"datas\": [{\"variable\": \"foo\", \"value\": 0.5}]}]}")
+            .expect(1)
+            .create();
+        let sensor = FoxEssOpenAPISensor::new(
+            "fox0".to_string(),
+            pool(&["123", "456"]),
+            "abc".to_string(),
+            vec!["foo".to_string()],
+            server.url(),
+            ApiVersion::V0,
+            HashMap::new(),
+        );
+        assert_eq!(sensor.measure().unwrap(), readings(&[("fox0_foo", 0.5)]));
+        exhausted.assert();
+        fallback.assert();
+    }
 }
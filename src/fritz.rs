@@ -1,12 +1,18 @@
-use std::error::Error;
+use std::cell::RefCell;
 use std::io::Read;
+use std::sync::Arc;
+use std::time;
 
 use md5::Digest;
 use serde::Deserialize;
 
 use crate::common;
+use crate::common::{Reading, SensorError};
+use crate::debug;
 
 const METRICS: [&str; 3] = ["power", "energy", "temperature"];
+// as the AHA interface reports them.
+const UNITS: [&str; 3] = ["mW", "Wh", "0.1°C"];
 
 pub struct FritzSensor {
     name: String,
@@ -14,7 +20,12 @@ pub struct FritzSensor {
     user: String,
     password: String,
     ain: String,
+    // fail on values with units or decimal commas instead of parsing them leniently.
+    strict: bool,
     client: reqwest::blocking::Client,
+    ring: Arc<debug::Ring>,
+    // the session from init; logged in again once it stops working.
+    sid: RefCell<Option<String>>,
 }
 
 #[derive(Deserialize)]
@@ -32,31 +43,36 @@ impl FritzSensor {
         user: String,
         password: String,
         ain: String,
+        strict: bool,
     ) -> FritzSensor {
         let builder: reqwest::blocking::ClientBuilder = reqwest::blocking::ClientBuilder::new();
         let client = builder.danger_accept_invalid_certs(true).build().unwrap();
         FritzSensor {
+            ring: debug::ring(&name),
             name,
             url,
             user,
             password,
             ain,
+            strict,
             client,
+            sid: RefCell::new(None),
         }
     }
 
-    fn get_token(&self) -> Result<String, Box<dyn Error>> {
+    fn get_token(&self) -> Result<String, SensorError> {
         // retrieve a token.
         let url = format!("{}/login_sid.lua", self.url);
         let mut res = self.client.get(url).send()?;
         if res.status() != 200 {
-            return Err(Box::from(
-                "Status code was not 200 when retrieving the challenge.",
+            return Err(SensorError::Http(
+                "Status code was not 200 when retrieving the challenge.".to_string(),
             ));
         }
         let mut body: String = String::new();
         res.read_to_string(&mut body)?;
-        let doc: LoginResponse = serde_xml_rs::from_str(&body)?;
+        let doc: LoginResponse =
+            serde_xml_rs::from_str(&body).map_err(|e| SensorError::Parse(e.to_string()))?;
 
         // get challenge - and create response.
         let s = format!("{}-{}", doc.challenge, self.password);
@@ -75,33 +91,42 @@ impl FritzSensor {
         );
         let mut res = self.client.get(query).send()?;
         if res.status() != 200 {
-            return Err(Box::from(
-                "Status code was not 200 when retrieving the SID.",
+            return Err(SensorError::Protocol(
+                "Status code was not 200 when retrieving the SID.".to_string(),
             ));
         }
         let mut body: String = String::new();
         res.read_to_string(&mut body)?;
-        let doc: LoginResponse = serde_xml_rs::from_str(&body)?;
+        let doc: LoginResponse =
+            serde_xml_rs::from_str(&body).map_err(|e| SensorError::Parse(e.to_string()))?;
 
         Ok(doc.sid)
     }
 
-    fn get_value(&self, command: &str, sid: &str) -> Result<f64, Box<dyn Error>> {
+    fn get_value(&self, command: &str, sid: &str) -> Result<f64, SensorError> {
         let query = format!(
             "{}/webservices/homeautoswitch.lua?switchcmd={}&ain={}&sid={}",
             self.url, command, self.ain, sid
         );
-        let mut res = self.client.get(query).send()?;
+        let started = time::Instant::now();
+        let mut res = match self.client.get(&query).send() {
+            Ok(res) => res,
+            Err(err) => {
+                self.ring.record(&query, None, &err.to_string(), started);
+                return Err(SensorError::from(err));
+            }
+        };
+        let mut body: String = String::new();
+        res.read_to_string(&mut body)?;
+        self.ring
+            .record(&query, Some(res.status().as_u16()), &body, started);
         if res.status() != 200 {
-            return Err(Box::from(format!(
+            return Err(SensorError::Http(format!(
                 "Status code was not 200 when retrieving data for: {}",
                 command
             )));
         }
-        let mut body: String = String::new();
-        res.read_to_string(&mut body)?;
-        let val: f64 = body.trim().parse()?;
-        Ok(val)
+        common::parse_number(&body, self.strict).map_err(|e| SensorError::Parse(e.to_string()))
     }
 }
 
@@ -114,30 +139,126 @@ impl common::Sensor for FritzSensor {
         names
     }
 
-    fn measure(&self) -> Vec<f64> {
-        match self.get_token() {
-            Ok(sid) => {
-                let mut res = Vec::new();
-                for op in &["getswitchpower", "getswitchenergy", "gettemperature"] {
-                    let tmp: f64 = match self.get_value(op, &sid) {
-                        Ok(res) => res,
-                        Err(err) => {
-                            println!("Could not retrieve val: {}.", err);
-                            -1.0
-                        }
-                    };
-                    res.push(tmp)
+    fn get_units(&self) -> Vec<String> {
+        UNITS.iter().map(|u| u.to_string()).collect()
+    }
+
+    fn measure(&self) -> Result<Vec<Reading>, SensorError> {
+        let cached = self.sid.borrow().clone();
+        let sid = match cached {
+            Some(sid) => sid,
+            None => self.get_token()?,
+        };
+        let mut res = Vec::new();
+        let mut first_err = None;
+        let ops = ["getswitchpower", "getswitchenergy", "gettemperature"];
+        for (op, metric) in ops.iter().zip(METRICS) {
+            match self.get_value(op, &sid) {
+                Ok(val) => res.push(Reading::new(format!("{}_{}", self.name, metric), val)),
+                Err(err) => {
+                    first_err.get_or_insert(err);
                 }
-                res
+            }
+        }
+        // single values may be unsupported by the device; only nothing at all is a failure - and might be an expired
+        // session.
+        match first_err {
+            Some(err) if res.is_empty() => {
+                *self.sid.borrow_mut() = None;
+                Err(err)
+            }
+            _ => {
+                *self.sid.borrow_mut() = Some(sid);
+                Ok(res)
+            }
+        }
+    }
+
+    fn init(&mut self) -> Result<(), SensorError> {
+        *self.sid.get_mut() = Some(self.get_token()?);
+        Ok(())
+    }
+
+    fn shutdown(&mut self) {
+        if let Some(sid) = self.sid.get_mut().take() {
+            let query = format!("{}/login_sid.lua?logout=1&sid={}", self.url, sid);
+            if let Err(err) = self.client.get(query).send() {
+                eprintln!("Could not log out of {}: {}", self.url, err);
+            }
+        }
+    }
+}
+
+impl FritzSensor {
+    /// Runs a switch command in the current session; logged in again first if there is none.
+    fn switch(&self, command: &str) -> Result<f64, SensorError> {
+        let cached = self.sid.borrow().clone();
+        let sid = match cached {
+            Some(sid) => sid,
+            None => self.get_token()?,
+        };
+        match self.get_value(command, &sid) {
+            Ok(value) => {
+                *self.sid.borrow_mut() = Some(sid);
+                Ok(value)
             }
             Err(err) => {
-                println!("Could not retrieve SID: {:?}.", err);
-                vec![-1.0, -1.0, -1.0]
+                *self.sid.borrow_mut() = None;
+                Err(err)
             }
         }
     }
 }
 
+/// Switches the plug; it answers with its new state.
+impl common::Actuator for FritzSensor {
+    fn set_state(&mut self, on: bool) -> Result<(), SensorError> {
+        let command = if on { "setswitchon" } else { "setswitchoff" };
+        match self.switch(command)? {
+            state if (state == 1.0) == on => Ok(()),
+            state => Err(SensorError::Protocol(format!(
+                "{} answered with state {}.",
+                command, state
+            ))),
+        }
+    }
+
+    fn get_state(&self) -> Result<bool, SensorError> {
+        Ok(self.switch("getswitchstate")? == 1.0)
+    }
+}
+
+/// Smart plugs behind a Fritz!Box.
+pub(crate) fn sensor_type() -> common::SensorType {
+    common::SensorType {
+        name: "fritz",
+        required: &["url", "user", "password", "ain"],
+        optional: &["strict_parsing"],
+        create: |name, sensor_cfg, _| {
+            Ok(Box::new(FritzSensor::new(
+                name.to_string(),
+                sensor_cfg["url"]
+                    .as_str()
+                    .unwrap_or("https://192.168.178.1")
+                    .to_string(),
+                sensor_cfg["user"].as_str().unwrap_or("admin").to_string(),
+                sensor_cfg["password"]
+                    .as_str()
+                    .unwrap_or("admin")
+                    .to_string(),
+                sensor_cfg["ain"]
+                    .as_str()
+                    .unwrap_or("1122334455")
+                    .to_string(),
+                sensor_cfg
+                    .get("strict_parsing")
+                    .and_then(|v| v.as_bool())
+                    .unwrap_or(false),
+            )))
+        },
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use crate::common::Sensor;
@@ -154,12 +275,32 @@ mod tests {
             "foo".to_string(),
             "bar".to_string(),
             "aabbccddeeff".to_string(),
+            false,
         );
         sensor.get_names();
     }
 
     // Tests for failure.
 
+    #[test]
+    fn test_init_for_failure() {
+        let mut server = mockito::Server::new();
+        server
+            .mock("GET", "/login_sid.lua")
+            .with_status(406)
+            .create();
+        let mut sensor = FritzSensor::new(
+            "test".to_string(),
+            server.url(),
+            "foo".to_string(),
+            "bar".to_string(),
+            "abc".to_string(),
+            false,
+        );
+        assert!(matches!(sensor.init(), Err(SensorError::Http(_))));
+        assert!(sensor.sid.borrow().is_none());
+    }
+
     #[test]
     fn test_measure_for_failure() {
         let mut server = mockito::Server::new();
@@ -178,9 +319,9 @@ mod tests {
             "foo".to_string(),
             "bar".to_string(),
             "abc".to_string(),
+            false,
         );
-        let data: Vec<f64> = sensor.measure();
-        assert_eq!(data, vec![-1.0, -1.0, -1.0]);
+        assert!(matches!(sensor.measure(), Err(SensorError::Http(_))));
 
         server
             .mock("GET", "/login_sid.lua")
@@ -202,8 +343,7 @@ mod tests {
             .create();
         let url: String = server.url();
         sensor.url = url;
-        let data: Vec<f64> = sensor.measure();
-        assert_eq!(data, vec![-1.0, -1.0, -1.0]);
+        assert!(matches!(sensor.measure(), Err(SensorError::Protocol(_))));
 
         server
             .mock("GET", "/login_sid.lua")
@@ -233,8 +373,7 @@ mod tests {
             .create();
         let url: String = server.url();
         sensor.url = url;
-        let data: Vec<f64> = sensor.measure();
-        assert_eq!(data, vec![-1.0, -1.0, -1.0]);
+        assert!(matches!(sensor.measure(), Err(SensorError::Http(_))));
     }
 
     // Tests for sanity.
@@ -247,6 +386,7 @@ mod tests {
             "foo".to_string(),
             "bar".to_string(),
             "abc".to_string(),
+            false,
         );
         assert_eq!(
             sensor.get_names(),
@@ -254,6 +394,19 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_get_units_for_sanity() {
+        let sensor: FritzSensor = FritzSensor::new(
+            "fritz".to_string(),
+            "".to_string(),
+            "foo".to_string(),
+            "bar".to_string(),
+            "abc".to_string(),
+            false,
+        );
+        assert_eq!(sensor.get_units(), vec!["mW", "Wh", "0.1°C"]);
+    }
+
     #[test]
     fn test_measure_for_sanity() {
         let mut server = mockito::Server::new();
@@ -305,8 +458,151 @@ mod tests {
             "foo".to_string(),
             "bar".to_string(),
             "abc".to_string(),
+            false,
         );
-        let data: Vec<f64> = sensor.measure();
+        let data: Vec<f64> = sensor.measure().unwrap().iter().map(|r| r.value).collect();
         assert_eq!(data, vec![10000.0, 1200.0, 100.0]);
     }
+
+    #[test]
+    fn test_session_for_sanity() {
+        let mut server = mockito::Server::new();
+        // challenge & response; once.
+        let login = server
+            .mock("GET", "/login_sid.lua")
+            .match_query(mockito::Matcher::Any)
+            .with_body(
+                "<SessionInfo><Challenge>1234abcd</Challenge><SID>000000000001</SID></SessionInfo>",
+            )
+            .expect(2)
+            .create();
+        let values = server
+            .mock("GET", "/webservices/homeautoswitch.lua")
+            .match_query(mockito::Matcher::UrlEncoded(
+                "sid".into(),
+                "000000000001".into(),
+            ))
+            .with_body("100")
+            .expect(6)
+            .create();
+
+        let mut sensor = FritzSensor::new(
+            "test".to_string(),
+            server.url(),
+            "foo".to_string(),
+            "bar".to_string(),
+            "abc".to_string(),
+            false,
+        );
+        sensor.init().unwrap();
+        sensor.measure().unwrap();
+        sensor.measure().unwrap();
+        login.assert();
+        values.assert();
+    }
+
+    #[test]
+    fn test_decimal_comma_for_sanity() {
+        let mut server = mockito::Server::new();
+        server
+            .mock("GET", "/login_sid.lua")
+            .match_query(mockito::Matcher::Any)
+            .with_body(
+                "<SessionInfo><Challenge>1234abcd</Challenge><SID>000000000001</SID></SessionInfo>",
+            )
+            .create();
+        for (op, body) in [
+            ("getswitchpower", "10000"),
+            ("getswitchenergy", "1.200,5"),
+            ("gettemperature", "21,5 °C"),
+        ] {
+            server
+                .mock("GET", "/webservices/homeautoswitch.lua")
+                .match_query(mockito::Matcher::UrlEncoded("switchcmd".into(), op.into()))
+                .with_body(body)
+                .create();
+        }
+
+        // lenient by default...
+        let url: String = server.url();
+        let sensor = FritzSensor::new(
+            "test".to_string(),
+            url.clone(),
+            "foo".to_string(),
+            "bar".to_string(),
+            "abc".to_string(),
+            false,
+        );
+        let data: Vec<f64> = sensor.measure().unwrap().iter().map(|r| r.value).collect();
+        assert_eq!(data, vec![10000.0, 1200.5, 21.5]);
+
+        // ... unless asked to fail loudly.
+        let sensor = FritzSensor::new(
+            "test".to_string(),
+            url,
+            "foo".to_string(),
+            "bar".to_string(),
+            "abc".to_string(),
+            true,
+        );
+        // the power still counts.
+        assert_eq!(
+            sensor.measure().unwrap(),
+            vec![Reading::new("test_power".to_string(), 10000.0)]
+        );
+    }
+
+    #[test]
+    fn test_actuator_for_sanity() {
+        let mut server = mockito::Server::new();
+        server
+            .mock("GET", "/login_sid.lua")
+            .match_query(mockito::Matcher::Any)
+            .with_body(
+                "<SessionInfo><Challenge>1234abcd</Challenge><SID>000000000001</SID></SessionInfo>",
+            )
+            .create();
+        let on = server
+            .mock("GET", "/webservices/homeautoswitch.lua")
+            .match_query(mockito::Matcher::UrlEncoded(
+                "switchcmd".into(),
+                "setswitchon".into(),
+            ))
+            .with_body("1\n")
+            .expect(1)
+            .create();
+        server
+            .mock("GET", "/webservices/homeautoswitch.lua")
+            .match_query(mockito::Matcher::UrlEncoded(
+                "switchcmd".into(),
+                "setswitchoff".into(),
+            ))
+            // e.g. locked on the device itself.
+            .with_body("1\n")
+            .create();
+        server
+            .mock("GET", "/webservices/homeautoswitch.lua")
+            .match_query(mockito::Matcher::UrlEncoded(
+                "switchcmd".into(),
+                "getswitchstate".into(),
+            ))
+            .with_body("1\n")
+            .create();
+
+        let mut plug = FritzSensor::new(
+            "heater".to_string(),
+            server.url(),
+            "foo".to_string(),
+            "bar".to_string(),
+            "abc".to_string(),
+            false,
+        );
+        common::Actuator::set_state(&mut plug, true).unwrap();
+        assert!(common::Actuator::get_state(&plug).unwrap());
+        assert!(matches!(
+            common::Actuator::set_state(&mut plug, false),
+            Err(SensorError::Protocol(_))
+        ));
+        on.assert();
+    }
 }
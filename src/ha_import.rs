@@ -0,0 +1,521 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::error::Error;
+use std::fs;
+use std::io::Write;
+use std::path;
+
+use crate::csv_out;
+
+/// One (hourly) long-term statistic as recorded by Home Assistant.
+#[derive(Debug, PartialEq)]
+pub(crate) struct Statistic {
+    start: i64,
+    state: Option<f64>,
+    sum: Option<f64>,
+    mean: Option<f64>,
+    min: Option<f64>,
+    max: Option<f64>,
+}
+
+impl Statistic {
+    fn field(&self, name: &str) -> Option<f64> {
+        match name {
+            "state" => self.state,
+            "sum" => self.sum,
+            "mean" => self.mean,
+            "min" => self.min,
+            "max" => self.max,
+            _ => None,
+        }
+    }
+}
+
+/// Maps a statistic_id (and one of its fields) onto a column of this collector.
+pub struct Mapping {
+    statistic_id: String,
+    column: String,
+    field: String,
+    factor: f64,
+}
+
+impl Mapping {
+    pub fn new(
+        statistic_id: String,
+        column: String,
+        field: String,
+        from_unit: &str,
+        to_unit: &str,
+    ) -> Mapping {
+        let factor = unit_factor(from_unit, to_unit)
+            .unwrap_or_else(|| panic!("cannot convert from {} to {}.", from_unit, to_unit));
+        Mapping {
+            statistic_id,
+            column,
+            field,
+            factor,
+        }
+    }
+}
+
+/// Creates a mapping from its config; Home Assistant stores energy in kWh by default.
+pub(crate) fn create_mapping(cfg: &toml::Value) -> Mapping {
+    let get = |key: &str, default: Option<&str>| {
+        cfg.get(key)
+            .and_then(|v| v.as_str())
+            .or(default)
+            .unwrap_or_else(|| panic!("a mapping requires the field {} to be set.", key))
+            .to_string()
+    };
+    Mapping::new(
+        get("statistic_id", None),
+        get("column", None),
+        get("field", Some("sum")),
+        &get("from_unit", Some("kWh")),
+        &get("to_unit", Some("Wh")),
+    )
+}
+
+/// Factor to convert a value from one unit to another; None if they are not compatible.
+pub(crate) fn unit_factor(from: &str, to: &str) -> Option<f64> {
+    let scale = |unit: &str| match unit {
+        "W" | "Wh" => Some((unit.ends_with('h'), 1.0)),
+        "kW" | "kWh" => Some((unit.ends_with('h'), 1e3)),
+        "MW" | "MWh" => Some((unit.ends_with('h'), 1e6)),
+        _ => None,
+    };
+    if from == to {
+        return Some(1.0);
+    }
+    match (scale(from)?, scale(to)?) {
+        ((energy_a, a), (energy_b, b)) if energy_a == energy_b => Some(a / b),
+        _ => None,
+    }
+}
+
+fn parse_start(value: &serde_json::Value) -> Result<i64, Box<dyn Error>> {
+    match value {
+        // newer versions send milliseconds since the epoch.
+        serde_json::Value::Number(n) => {
+            let start = n.as_f64().ok_or("invalid start.")?;
+            Ok(if start > 1e11 { start / 1000.0 } else { start } as i64)
+        }
+        serde_json::Value::String(s) => Ok(chrono::DateTime::parse_from_rfc3339(s)?.timestamp()),
+        _ => Err(Box::from("missing start of the statistic.")),
+    }
+}
+
+/// Parses the result of a recorder/statistics_during_period call; with or without its envelope.
+pub(crate) fn parse_export(data: &str) -> Result<HashMap<String, Vec<Statistic>>, Box<dyn Error>> {
+    let value: serde_json::Value = serde_json::from_str(data)?;
+    let result = value.get("result").unwrap_or(&value);
+    let ids = result
+        .as_object()
+        .ok_or("expected an object of statistic ids.")?;
+    let mut res = HashMap::new();
+    for (id, rows) in ids {
+        let mut stats = Vec::new();
+        for row in rows
+            .as_array()
+            .ok_or(format!("expected a list of statistics for {}.", id))?
+        {
+            let get = |key: &str| row.get(key).and_then(|v| v.as_f64());
+            stats.push(Statistic {
+                start: parse_start(&row["start"])?,
+                state: get("state"),
+                sum: get("sum"),
+                mean: get("mean"),
+                min: get("min"),
+                max: get("max"),
+            });
+        }
+        res.insert(id.to_string(), stats);
+    }
+    Ok(res)
+}
+
+/// Reads the statistics table of a Home Assistant database (schema of 2023.3 or later).
+#[cfg(feature = "sqlite")]
+pub(crate) fn read_sqlite(
+    filename: &str,
+    ids: &[&str],
+) -> Result<HashMap<String, Vec<Statistic>>, Box<dyn Error>> {
+    let conn = rusqlite::Connection::open_with_flags(
+        filename,
+        rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY,
+    )?;
+    let mut query = conn.prepare(
+        "SELECT s.start_ts, s.state, s.sum, s.mean, s.min, s.max FROM statistics s \
+        JOIN statistics_meta m ON s.metadata_id = m.id WHERE m.statistic_id = ?1 \
+        ORDER BY s.start_ts",
+    )?;
+    let mut res = HashMap::new();
+    for id in ids {
+        let stats = query
+            .query_map([id], |row| {
+                Ok(Statistic {
+                    start: row.get::<_, f64>(0)? as i64,
+                    state: row.get(1)?,
+                    sum: row.get(2)?,
+                    mean: row.get(3)?,
+                    min: row.get(4)?,
+                    max: row.get(5)?,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        res.insert(id.to_string(), stats);
+    }
+    Ok(res)
+}
+
+#[cfg(not(feature = "sqlite"))]
+pub(crate) fn read_sqlite(
+    _filename: &str,
+    _ids: &[&str],
+) -> Result<HashMap<String, Vec<Statistic>>, Box<dyn Error>> {
+    Err(Box::from(
+        "reading a Home Assistant database requires the sqlite feature.",
+    ))
+}
+
+/// The hours (as epoch secs of their start) for which the CSV file already has rows; a line of
+/// units below the header is no row.
+pub(crate) fn existing_periods(content: &str, format: &csv_out::Format) -> HashSet<i64> {
+    content
+        .lines()
+        .skip(1)
+        .filter_map(|l| format.split(l).first()?.parse::<f64>().ok())
+        .filter(|t| t.is_finite())
+        .map(|t| (t as i64).div_euclid(3600) * 3600)
+        .collect()
+}
+
+/// Builds one row per hour for the given header; periods already present are skipped.
+pub(crate) fn build_rows(
+    stats: &HashMap<String, Vec<Statistic>>,
+    mappings: &[Mapping],
+    headers: &[String],
+    existing: &HashSet<i64>,
+) -> Result<Vec<Vec<f64>>, Box<dyn Error>> {
+    let mut rows: BTreeMap<i64, Vec<f64>> = BTreeMap::new();
+    for mapping in mappings {
+        let index = headers
+            .iter()
+            .position(|h| h == &mapping.column)
+            .ok_or(format!("unknown column: {}.", mapping.column))?;
+        for stat in stats.get(&mapping.statistic_id).unwrap_or(&Vec::new()) {
+            if existing.contains(&(stat.start.div_euclid(3600) * 3600)) {
+                continue;
+            }
+            if let Some(value) = stat.field(&mapping.field) {
+                let row = rows.entry(stat.start).or_insert_with(|| {
+                    let mut row = vec![f64::NAN; headers.len()];
+                    row[0] = stat.start as f64;
+                    row
+                });
+                row[index] = value * mapping.factor;
+            }
+        }
+    }
+    Ok(rows.into_values().collect())
+}
+
+/// Imports a Home Assistant export (JSON) or database (SQLite) into the CSV file; returns the
+/// number of rows written. Hours without a statistic are written as missing. A new file starts
+/// with the header and - if given - the line of units.
+pub(crate) fn import(
+    source: &str,
+    mappings: &[Mapping],
+    headers: &[String],
+    units: Option<&[String]>,
+    filename: &str,
+    format: &csv_out::Format,
+) -> Result<usize, Box<dyn Error>> {
+    let stats = if source.ends_with(".db") || source.ends_with(".sqlite") {
+        let ids: Vec<&str> = mappings.iter().map(|m| m.statistic_id.as_str()).collect();
+        read_sqlite(source, &ids)?
+    } else {
+        parse_export(&fs::read_to_string(source)?)?
+    };
+
+    let existing = if path::Path::new(filename).exists() {
+        existing_periods(&fs::read_to_string(filename)?, format)
+    } else {
+        let mut output = fs::File::create(filename)?;
+        writeln!(output, "{}", format.header(headers))?;
+        if let Some(units) = units {
+            writeln!(output, "{}", format.units(units))?;
+        }
+        HashSet::new()
+    };
+    let rows = build_rows(&stats, mappings, headers, &existing)?;
+    let mut file = fs::OpenOptions::new().append(true).open(filename)?;
+    for row in &rows {
+        writeln!(file, "{}", format.row(row))?;
+    }
+    Ok(rows.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Trimmed result of a recorder/statistics_during_period call.
+    const EXPORT: &str = r#"{"id": 2, "type": "result", "success": true, "result": {
+        "sensor.grid_import": [
+            {"start": 1699999200000, "end": 1700002800000, "state": 1200.5, "sum": 10.5},
+            {"start": 1700002800000, "end": 1700006400000, "state": 1201.0, "sum": 11.0}
+        ],
+        "sensor.outside_temperature": [
+            {"start": "2023-11-14T22:00:00+00:00", "mean": 4.5, "min": 3.0, "max": 6.0}
+        ]
+    }}"#;
+
+    fn headers() -> Vec<String> {
+        vec!["timestamp", "grid_energy", "temp", "other"]
+            .into_iter()
+            .map(String::from)
+            .collect()
+    }
+
+    fn mappings() -> Vec<Mapping> {
+        vec![
+            Mapping::new(
+                "sensor.grid_import".to_string(),
+                "grid_energy".to_string(),
+                "sum".to_string(),
+                "kWh",
+                "Wh",
+            ),
+            Mapping::new(
+                "sensor.outside_temperature".to_string(),
+                "temp".to_string(),
+                "mean".to_string(),
+                "°C",
+                "°C",
+            ),
+        ]
+    }
+
+    // Tests for success.
+
+    #[test]
+    fn test_parse_export_for_success() {
+        parse_export(EXPORT).unwrap();
+        // without the envelope.
+        parse_export("{\"sensor.foo\": []}").unwrap();
+    }
+
+    #[test]
+    fn test_create_mapping_for_success() {
+        let cfg: toml::Value =
+            toml::from_str("statistic_id='sensor.grid_import'\ncolumn='grid_energy'").unwrap();
+        let mapping = create_mapping(&cfg);
+        assert_eq!(mapping.field, "sum");
+        assert_eq!(mapping.factor, 1000.0);
+    }
+
+    // Tests for failure.
+
+    #[test]
+    fn test_parse_export_for_failure() {
+        assert!(parse_export("ohno").is_err());
+        assert!(parse_export("[]").is_err());
+        assert!(parse_export("{\"sensor.foo\": 1}").is_err());
+        assert!(parse_export("{\"sensor.foo\": [{\"sum\": 1}]}").is_err());
+        assert!(parse_export("{\"sensor.foo\": [{\"start\": \"yesterday\"}]}").is_err());
+    }
+
+    #[test]
+    fn test_build_rows_for_failure() {
+        let stats = parse_export(EXPORT).unwrap();
+        let mapping = vec![Mapping::new(
+            "sensor.grid_import".to_string(),
+            "foo".to_string(),
+            "sum".to_string(),
+            "kWh",
+            "Wh",
+        )];
+        assert!(build_rows(&stats, &mapping, &headers(), &HashSet::new()).is_err());
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_mapping_for_failure() {
+        Mapping::new(
+            "sensor.grid_import".to_string(),
+            "grid_energy".to_string(),
+            "sum".to_string(),
+            "kWh",
+            "W",
+        );
+    }
+
+    #[test]
+    fn test_import_for_failure() {
+        assert!(import(
+            "ha_import_missing.json",
+            &mappings(),
+            &headers(),
+            None,
+            "foo.csv",
+            &csv_out::Format::default()
+        )
+        .is_err());
+        assert!(!path::Path::new("foo.csv").exists());
+    }
+
+    // Tests for sanity.
+
+    #[test]
+    fn test_unit_factor_for_sanity() {
+        assert_eq!(unit_factor("kWh", "Wh"), Some(1000.0));
+        assert_eq!(unit_factor("Wh", "kWh"), Some(0.001));
+        assert_eq!(unit_factor("MWh", "kWh"), Some(1000.0));
+        assert_eq!(unit_factor("kW", "W"), Some(1000.0));
+        assert_eq!(unit_factor("m³", "m³"), Some(1.0));
+        assert_eq!(unit_factor("kWh", "W"), None);
+        assert_eq!(unit_factor("m³", "Wh"), None);
+    }
+
+    #[test]
+    fn test_parse_export_for_sanity() {
+        let stats = parse_export(EXPORT).unwrap();
+        assert_eq!(stats["sensor.grid_import"].len(), 2);
+        assert_eq!(
+            stats["sensor.grid_import"][1],
+            Statistic {
+                start: 1700002800,
+                state: Some(1201.0),
+                sum: Some(11.0),
+                mean: None,
+                min: None,
+                max: None
+            }
+        );
+        // ISO timestamps as used by older versions.
+        assert_eq!(stats["sensor.outside_temperature"][0].start, 1699999200);
+        assert_eq!(stats["sensor.outside_temperature"][0].mean, Some(4.5));
+    }
+
+    #[test]
+    fn test_existing_periods_for_sanity() {
+        let content = "timestamp,foo\n1700000000.5,1\n1700000030,2\n1700003600,3\nNaN,4\n";
+        assert_eq!(
+            existing_periods(content, &csv_out::Format::default()),
+            HashSet::from([1699999200, 1700002800])
+        );
+        // the units line is skipped, quoted fields are read.
+        let format = csv_out::Format::new(";", None, true, None, String::new());
+        let content = "\"timestamp\";\"foo\"\n\"s\";\"W\"\n\"1700000000\";\"1\"\n";
+        assert_eq!(
+            existing_periods(content, &format),
+            HashSet::from([1699999200])
+        );
+    }
+
+    #[test]
+    fn test_build_rows_for_sanity() {
+        let stats = parse_export(EXPORT).unwrap();
+        let rows = build_rows(&stats, &mappings(), &headers(), &HashSet::new()).unwrap();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0][..3], [1699999200.0, 10500.0, 4.5]);
+        assert!(rows[0][3].is_nan());
+        assert_eq!(rows[1][..2], [1700002800.0, 11000.0]);
+        assert!(rows[1][2].is_nan());
+
+        // hours already present are skipped.
+        let rows = build_rows(
+            &stats,
+            &mappings(),
+            &headers(),
+            &HashSet::from([1699999200]),
+        )
+        .unwrap();
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0][0], 1700002800.0);
+    }
+
+    #[test]
+    fn test_import_for_sanity() {
+        fs::write("ha_import_test0.json", EXPORT).unwrap();
+        let res = import(
+            "ha_import_test0.json",
+            &mappings(),
+            &headers(),
+            None,
+            "ha_import_test0.csv",
+            &csv_out::Format::default(),
+        );
+        assert_eq!(res.unwrap(), 2);
+        // importing again does not duplicate anything.
+        let res = import(
+            "ha_import_test0.json",
+            &mappings(),
+            &headers(),
+            None,
+            "ha_import_test0.csv",
+            &csv_out::Format::default(),
+        );
+        assert_eq!(res.unwrap(), 0);
+        let content = fs::read_to_string("ha_import_test0.csv").unwrap();
+        assert_eq!(
+            content,
+            "timestamp,grid_energy,temp,other\n1699999200,10500,4.5,\n1700002800,11000,,\n"
+        );
+        fs::remove_file("ha_import_test0.json").unwrap();
+        fs::remove_file("ha_import_test0.csv").unwrap();
+    }
+
+    #[test]
+    fn test_import_format_for_sanity() {
+        fs::write("ha_import_test2.json", EXPORT).unwrap();
+        let format = csv_out::Format::new(";", None, false, None, "NA".to_string());
+        let units: Vec<String> = vec!["s", "Wh", "°C", ""]
+            .into_iter()
+            .map(String::from)
+            .collect();
+        for _ in 0..2 {
+            import(
+                "ha_import_test2.json",
+                &mappings(),
+                &headers(),
+                Some(&units),
+                "ha_import_test2.csv",
+                &format,
+            )
+            .unwrap();
+        }
+        // the second import finds the hours of the first one.
+        let content = fs::read_to_string("ha_import_test2.csv").unwrap();
+        assert_eq!(
+            content,
+            "timestamp;grid_energy;temp;other\ns;Wh;°C;\n1699999200;10500;4.5;NA\n1700002800;11000;NA;NA\n"
+        );
+        fs::remove_file("ha_import_test2.json").unwrap();
+        fs::remove_file("ha_import_test2.csv").unwrap();
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn test_read_sqlite_for_sanity() {
+        let conn = rusqlite::Connection::open("ha_import_test1.db").unwrap();
+        conn.execute_batch(
+            "CREATE TABLE statistics_meta (id INTEGER PRIMARY KEY, statistic_id TEXT);
+            CREATE TABLE statistics (id INTEGER PRIMARY KEY, metadata_id INTEGER, start_ts FLOAT,
+                state FLOAT, sum FLOAT, mean FLOAT, min FLOAT, max FLOAT);
+            INSERT INTO statistics_meta VALUES (1, 'sensor.grid_import');
+            INSERT INTO statistics VALUES (1, 1, 1700002800.0, 1201.0, 11.0, NULL, NULL, NULL);
+            INSERT INTO statistics VALUES (2, 1, 1699999200.0, 1200.5, 10.5, NULL, NULL, NULL);",
+        )
+        .unwrap();
+        drop(conn);
+
+        let stats =
+            read_sqlite("ha_import_test1.db", &["sensor.grid_import", "sensor.foo"]).unwrap();
+        assert_eq!(stats["sensor.grid_import"].len(), 2);
+        assert_eq!(stats["sensor.grid_import"][0].start, 1699999200);
+        assert_eq!(stats["sensor.grid_import"][0].sum, Some(10.5));
+        assert!(stats["sensor.foo"].is_empty());
+        fs::remove_file("ha_import_test1.db").unwrap();
+    }
+}
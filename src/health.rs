@@ -0,0 +1,149 @@
+use std::fmt::Write;
+use std::fs;
+
+use serde::Serialize;
+
+/// How a sensor has been doing; updated by the loop after each measurement.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub(crate) struct Health {
+    pub(crate) sensor: String,
+    /// Epoch secs of the last measurement that did not fail.
+    pub(crate) last_success: Option<f64>,
+    pub(crate) last_error: Option<String>,
+    pub(crate) consecutive_failures: u64,
+    pub(crate) total_failures: u64,
+}
+
+impl Health {
+    pub(crate) fn new(sensor: String) -> Health {
+        Health {
+            sensor,
+            last_success: None,
+            last_error: None,
+            consecutive_failures: 0,
+            total_failures: 0,
+        }
+    }
+
+    pub(crate) fn success(&mut self, now: f64) {
+        self.last_success = Some(now);
+        self.consecutive_failures = 0;
+    }
+
+    pub(crate) fn failure(&mut self, err: String) {
+        self.last_error = Some(err);
+        self.consecutive_failures += 1;
+        self.total_failures += 1;
+    }
+
+    /// Whether the last measurement worked; sensors not measured yet count as working.
+    pub(crate) fn is_up(&self) -> bool {
+        self.consecutive_failures == 0
+    }
+}
+
+/// One line per sensor; e.g. for the log.
+pub(crate) fn summary(health: &[Health], now: f64) -> String {
+    let mut res = String::new();
+    for item in health {
+        let since = match item.last_success {
+            Some(t) => format!("{:.0}s ago", now - t),
+            None => "never".to_string(),
+        };
+        let _ = write!(
+            res,
+            "{}: {}; last success: {}; failures: {} in a row, {} in total",
+            item.sensor,
+            if item.is_up() { "up" } else { "down" },
+            since,
+            item.consecutive_failures,
+            item.total_failures
+        );
+        if let Some(err) = &item.last_error {
+            let _ = write!(res, "; last error: {}", err);
+        }
+        res.push('\n');
+    }
+    res
+}
+
+/// Writes the health of all sensors as JSON; replaced as a whole so readers never see half a file.
+pub(crate) fn save(path: &str, health: &[Health]) -> Result<(), String> {
+    let tmp = format!("{}.tmp", path);
+    let json = serde_json::to_string_pretty(health).map_err(|e| e.to_string())?;
+    fs::write(&tmp, json)
+        .and_then(|_| fs::rename(&tmp, path))
+        .map_err(|e| format!("could not write {}: {}", path, e))
+}
+
+/// The health of all sensors as Prometheus metrics; failures in total and the last success come with the latencies.
+pub(crate) fn render(health: &[Health]) -> String {
+    let mut res = String::new();
+    res.push_str("# HELP ogc_sensor_up Whether the last measurement of a sensor worked.\n");
+    res.push_str("# TYPE ogc_sensor_up gauge\n");
+    for item in health {
+        let _ = writeln!(
+            res,
+            "ogc_sensor_up{{sensor=\"{}\"}} {}",
+            item.sensor,
+            item.is_up() as u8
+        );
+    }
+    res.push_str(
+        "# HELP ogc_sensor_consecutive_failures Measurements of a sensor that failed in a row.\n",
+    );
+    res.push_str("# TYPE ogc_sensor_consecutive_failures gauge\n");
+    for item in health {
+        let _ = writeln!(
+            res,
+            "ogc_sensor_consecutive_failures{{sensor=\"{}\"}} {}",
+            item.sensor, item.consecutive_failures
+        );
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Tests for success.
+
+    #[test]
+    fn test_save_for_success() {
+        let mut health = Health::new("fritz0".to_string());
+        health.success(1000.0);
+        save("health_test0.json", &[health]).unwrap();
+        let content = fs::read_to_string("health_test0.json").unwrap();
+        assert!(content.contains("\"last_success\": 1000.0"));
+        fs::remove_file("health_test0.json").unwrap();
+    }
+
+    // Tests for failure.
+
+    #[test]
+    fn test_save_for_failure() {
+        assert!(save("health_missing/health.json", &[]).is_err());
+    }
+
+    // Tests for sanity.
+
+    #[test]
+    fn test_health_for_sanity() {
+        let mut health = Health::new("fritz0".to_string());
+        assert!(health.is_up());
+        health.failure("HTTP error: timeout".to_string());
+        health.failure("HTTP error: timeout".to_string());
+        assert!(!health.is_up());
+        assert_eq!((health.consecutive_failures, health.total_failures), (2, 2));
+        health.success(1000.0);
+        assert!(health.is_up());
+        assert_eq!((health.consecutive_failures, health.total_failures), (0, 2));
+        // the last error is kept around.
+        assert_eq!(
+            summary(&[health.clone()], 1030.0),
+            "fritz0: up; last success: 30s ago; failures: 0 in a row, 2 in total; last error: HTTP error: timeout\n"
+        );
+        assert!(render(&[health]).contains("ogc_sensor_up{sensor=\"fritz0\"} 1\n"));
+    }
+}
@@ -0,0 +1,233 @@
+use std::cell::RefCell;
+use std::error::Error;
+use std::time;
+
+use crate::clock;
+use crate::common;
+use crate::common::{Reading, SensorError};
+
+/// What a sensor reports instead of failing.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Fallback {
+    /// All of its metrics as 0.
+    Zero,
+    /// The readings of its last successful measurement; for up to max_age secs if given.
+    HoldLast { max_age: Option<f64> },
+}
+
+/// Replaces the failed measurements of the sensor it wraps by its fallback.
+///
+/// Held readings keep the time they were actually measured at, so age columns and the cache of the slow loop see how
+/// old they are; the age is only checked when measuring, so a slow loop sensor may show them for one refresh longer.
+pub struct HoldLastValueSensor {
+    name: String,
+    inner: Box<dyn common::Sensor>,
+    fallback: Fallback,
+    // the readings of the last successful measurement and when it happened.
+    last: RefCell<Option<(f64, Vec<Reading>)>>,
+}
+
+impl HoldLastValueSensor {
+    pub(crate) fn new(
+        name: String,
+        inner: Box<dyn common::Sensor>,
+        fallback: Fallback,
+    ) -> HoldLastValueSensor {
+        HoldLastValueSensor {
+            name,
+            inner,
+            fallback,
+            last: RefCell::new(None),
+        }
+    }
+}
+
+impl common::Sensor for HoldLastValueSensor {
+    fn get_names(&self) -> Vec<String> {
+        self.inner.get_names()
+    }
+
+    fn get_units(&self) -> Vec<String> {
+        self.inner.get_units()
+    }
+
+    fn measure(&self) -> Result<Vec<Reading>, SensorError> {
+        self.measure_at(clock::epoch_secs(time::SystemTime::now()))
+    }
+
+    fn measure_at(&self, now: f64) -> Result<Vec<Reading>, SensorError> {
+        let err = match self.inner.measure_at(now) {
+            Ok(readings) => {
+                *self.last.borrow_mut() = Some((now, readings.clone()));
+                return Ok(readings);
+            }
+            Err(err) => err,
+        };
+        match &self.fallback {
+            Fallback::Zero => {
+                eprintln!("Could not measure {}: {}; reporting 0.", self.name, err);
+                Ok(self
+                    .inner
+                    .get_names()
+                    .into_iter()
+                    .map(|name| Reading {
+                        quality: common::HELD,
+                        ..Reading::new(name, 0.0)
+                    })
+                    .collect())
+            }
+            Fallback::HoldLast { max_age } => match self.last.borrow().as_ref() {
+                Some((time, readings)) if max_age.map(|m| now - time <= m).unwrap_or(true) => {
+                    eprintln!(
+                        "Could not measure {}: {}; holding its last values.",
+                        self.name, err
+                    );
+                    Ok(readings
+                        .iter()
+                        .map(|r| Reading {
+                            time: r.time.or(Some(*time)),
+                            quality: r.quality | common::HELD,
+                            ..r.clone()
+                        })
+                        .collect())
+                }
+                _ => Err(err),
+            },
+        }
+    }
+
+    fn init(&mut self) -> Result<(), SensorError> {
+        self.inner.init()
+    }
+
+    fn shutdown(&mut self) {
+        self.inner.shutdown()
+    }
+
+    fn validate(&self) -> Result<(), Box<dyn Error>> {
+        self.inner.validate()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::common::Sensor;
+
+    /// A sensor counting up from 1; failing while down.
+    struct UpDownSensor {
+        count: Cell<u32>,
+        down: Rc<Cell<bool>>,
+    }
+
+    impl common::Sensor for UpDownSensor {
+        fn get_names(&self) -> Vec<String> {
+            vec!["fox_pvPower".to_string(), "fox_loadsPower".to_string()]
+        }
+
+        fn get_units(&self) -> Vec<String> {
+            vec!["kW".to_string(), "kW".to_string()]
+        }
+
+        fn measure(&self) -> Result<Vec<Reading>, SensorError> {
+            if self.down.get() {
+                return Err(SensorError::Http(
+                    "Status code was not 200; but: 502.".to_string(),
+                ));
+            }
+            self.count.set(self.count.get() + 1);
+            let value = self.count.get() as f64;
+            Ok(common::readings(self.get_names(), vec![value, value * 2.0]))
+        }
+    }
+
+    fn fox(fallback: Fallback) -> (HoldLastValueSensor, Rc<Cell<bool>>) {
+        let down = Rc::new(Cell::new(false));
+        let sensor = UpDownSensor {
+            count: Cell::new(0),
+            down: down.clone(),
+        };
+        (
+            HoldLastValueSensor::new("fox".to_string(), Box::new(sensor), fallback),
+            down,
+        )
+    }
+
+    fn values(res: Result<Vec<Reading>, SensorError>) -> Vec<f64> {
+        res.unwrap().iter().map(|r| r.value).collect()
+    }
+
+    // Tests for success.
+
+    #[test]
+    fn test_measure_for_success() {
+        let (sensor, down) = fox(Fallback::HoldLast { max_age: None });
+        assert_eq!(values(sensor.measure_at(1000.0)), vec![1.0, 2.0]);
+        down.set(true);
+        let held = sensor.measure_at(1030.0).unwrap();
+        assert_eq!(held[1].value, 2.0);
+        // measured back then.
+        assert_eq!(held[1].time, Some(1000.0));
+        assert_eq!(held[1].quality, common::HELD);
+        assert_eq!(values(sensor.measure_at(100000.0)), vec![1.0, 2.0]);
+        down.set(false);
+        assert_eq!(values(sensor.measure_at(100030.0)), vec![2.0, 4.0]);
+    }
+
+    // Tests for failure.
+
+    #[test]
+    fn test_measure_for_failure() {
+        // nothing to hold yet.
+        let (sensor, down) = fox(Fallback::HoldLast {
+            max_age: Some(60.0),
+        });
+        down.set(true);
+        assert!(matches!(
+            sensor.measure_at(1000.0),
+            Err(SensorError::Http(_))
+        ));
+    }
+
+    #[test]
+    fn test_expiry_for_failure() {
+        let (sensor, down) = fox(Fallback::HoldLast {
+            max_age: Some(60.0),
+        });
+        assert_eq!(values(sensor.measure_at(1000.0)), vec![1.0, 2.0]);
+        down.set(true);
+        assert_eq!(values(sensor.measure_at(1030.0)), vec![1.0, 2.0]);
+        assert_eq!(values(sensor.measure_at(1060.0)), vec![1.0, 2.0]);
+        // held values do not extend their own life.
+        assert!(matches!(
+            sensor.measure_at(1090.0),
+            Err(SensorError::Http(_))
+        ));
+        assert!(sensor.measure_at(1120.0).is_err());
+        // a success starts over.
+        down.set(false);
+        assert_eq!(values(sensor.measure_at(1150.0)), vec![2.0, 4.0]);
+        down.set(true);
+        assert_eq!(values(sensor.measure_at(1180.0)), vec![2.0, 4.0]);
+    }
+
+    // Tests for sanity.
+
+    #[test]
+    fn test_zero_for_sanity() {
+        let (sensor, down) = fox(Fallback::Zero);
+        assert_eq!(values(sensor.measure_at(1000.0)), vec![1.0, 2.0]);
+        down.set(true);
+        let zero = sensor.measure_at(1030.0).unwrap();
+        assert_eq!(zero[0].name, "fox_pvPower");
+        assert_eq!(
+            zero.iter().map(|r| r.value).collect::<Vec<_>>(),
+            vec![0.0, 0.0]
+        );
+        assert_eq!(zero[0].time, None);
+        assert_eq!(zero[0].quality, common::HELD);
+    }
+}
@@ -0,0 +1,165 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::io::Read;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+/// Query parameters that change with every request and hence are ignored when coalescing.
+const VOLATILE_PARAMS: [&str; 6] = ["_", "t", "ts", "time", "timestamp", "nonce"];
+
+/// Error for responses other than 200; lets callers classify e.g. quota errors per provider.
+#[derive(Debug)]
+pub(crate) struct StatusError {
+    pub status: u16,
+    pub body: String,
+}
+
+impl fmt::Display for StatusError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "unexpected status code: {}.", self.status)
+    }
+}
+
+impl Error for StatusError {}
+
+struct Entry {
+    fetched: Instant,
+    expires: Instant,
+    body: String,
+}
+
+fn cache() -> &'static Mutex<HashMap<String, Entry>> {
+    static CACHE: OnceLock<Mutex<HashMap<String, Entry>>> = OnceLock::new();
+    CACHE.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/// Normalizes a URL for use as cache key: volatile query parameters are dropped, the rest sorted.
+pub(crate) fn normalize(uri: &str) -> String {
+    let (base, query) = match uri.split_once('?') {
+        Some(parts) => parts,
+        None => return uri.to_string(),
+    };
+    let mut params: Vec<&str> = query
+        .split('&')
+        .filter(|p| !p.is_empty())
+        .filter(|p| !VOLATILE_PARAMS.contains(&p.split('=').next().unwrap_or("")))
+        .collect();
+    params.sort();
+    if params.is_empty() {
+        base.to_string()
+    } else {
+        format!("{}?{}", base, params.join("&"))
+    }
+}
+
+/// GETs the body of a URL; identical requests within coalesce_secs are served from memory.
+pub(crate) fn get(uri: &str, coalesce_secs: f64) -> Result<String, Box<dyn Error>> {
+    if coalesce_secs <= 0.0 {
+        return fetch(uri);
+    }
+    let key = normalize(uri);
+    let window = Duration::from_secs_f64(coalesce_secs);
+    {
+        let mut entries = cache().lock().expect("http cache lock poisoned.");
+        let now = Instant::now();
+        entries.retain(|_, entry| entry.expires > now);
+        if let Some(entry) = entries.get(&key) {
+            if now.duration_since(entry.fetched) < window {
+                return Ok(entry.body.clone());
+            }
+        }
+    }
+
+    let body = fetch(uri)?;
+    let now = Instant::now();
+    cache().lock().expect("http cache lock poisoned.").insert(
+        key,
+        Entry {
+            fetched: now,
+            expires: now + window,
+            body: body.clone(),
+        },
+    );
+    Ok(body)
+}
+
+fn fetch(uri: &str) -> Result<String, Box<dyn Error>> {
+    let mut res = reqwest::blocking::get(uri)?;
+    let mut body = String::new();
+    res.read_to_string(&mut body)?;
+    if res.status() != 200 {
+        return Err(Box::new(StatusError {
+            status: res.status().as_u16(),
+            body,
+        }));
+    }
+    Ok(body)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    // Tests for success.
+
+    #[test]
+    fn test_get_for_success() {
+        let mut server = mockito::Server::new();
+        server.mock("GET", "/foo").with_body("bar").create();
+        get(&(server.url() + "/foo"), 0.0).unwrap();
+        get(&(server.url() + "/foo"), 10.0).unwrap();
+    }
+
+    // Tests for failure.
+
+    #[test]
+    fn test_get_for_failure() {
+        let mut server = mockito::Server::new();
+        let mock = server
+            .mock("GET", "/foo")
+            .with_status(500)
+            .expect(2)
+            .create();
+        // errors are never cached.
+        assert!(get(&(server.url() + "/foo"), 10.0).is_err());
+        let err = get(&(server.url() + "/foo"), 10.0).unwrap_err();
+        assert_eq!(err.downcast_ref::<StatusError>().unwrap().status, 500);
+        mock.assert();
+    }
+
+    // Tests for sanity.
+
+    #[test]
+    fn test_normalize_for_sanity() {
+        assert_eq!(normalize("http://a/b"), "http://a/b");
+        assert_eq!(normalize("http://a/b?"), "http://a/b");
+        assert_eq!(normalize("http://a/b?y=2&x=1"), "http://a/b?x=1&y=2");
+        assert_eq!(
+            normalize("http://a/b?x=1&timestamp=1700000000&_=42"),
+            "http://a/b?x=1"
+        );
+        assert_eq!(normalize("http://a/b?ts=1"), normalize("http://a/b?ts=2"));
+    }
+
+    #[test]
+    fn test_get_for_sanity() {
+        let mut server = mockito::Server::new();
+        let mock = server
+            .mock("GET", mockito::Matcher::Any)
+            .with_body("bar")
+            .expect(2)
+            .create();
+        let url = server.url() + "/foo?x=1";
+
+        // coalesced, even if the timestamp differs.
+        assert_eq!(get(&(url.clone() + "&ts=1"), 0.2).unwrap(), "bar");
+        assert_eq!(get(&(url.clone() + "&ts=2"), 0.2).unwrap(), "bar");
+        // entries do not outlive the window.
+        thread::sleep(Duration::from_millis(300));
+        assert_eq!(get(&url, 0.2).unwrap(), "bar");
+        mock.assert();
+    }
+}
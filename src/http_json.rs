@@ -0,0 +1,309 @@
+use std::io::Read;
+use std::sync::Arc;
+use std::time;
+
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+
+use crate::common;
+use crate::common::{ConfigError, Reading, SensorError};
+use crate::debug;
+
+/// Reads values out of the JSON some device serves, e.g. the status of a Tasmota plug.
+///
+/// Each metric is found by its RFC 6901 JSON pointer, e.g. `/StatusSNS/ENERGY/Power`; numbers, numeric strings and
+/// booleans (as 0/1) are accepted. A metric that is missing or not a number is left out, so only its column is missing.
+pub struct HttpJsonSensor {
+    name: String,
+    url: String,
+    headers: HeaderMap,
+    auth: Option<(String, Option<String>)>,
+    // names and pointers of the metrics.
+    metrics: Vec<(String, String)>,
+    client: reqwest::blocking::Client,
+    ring: Arc<debug::Ring>,
+}
+
+impl HttpJsonSensor {
+    pub(crate) fn new(
+        name: String,
+        url: String,
+        headers: HeaderMap,
+        auth: Option<(String, Option<String>)>,
+        timeout: time::Duration,
+        metrics: Vec<(String, String)>,
+    ) -> HttpJsonSensor {
+        HttpJsonSensor {
+            ring: debug::ring(&name),
+            name,
+            url,
+            headers,
+            auth,
+            metrics,
+            client: reqwest::blocking::Client::builder()
+                .timeout(timeout)
+                .build()
+                .unwrap(),
+        }
+    }
+
+    fn get_body(&self) -> Result<serde_json::Value, SensorError> {
+        let mut request = self.client.get(&self.url).headers(self.headers.clone());
+        if let Some((username, password)) = &self.auth {
+            request = request.basic_auth(username, password.as_ref());
+        }
+        let started = time::Instant::now();
+        let mut res = match request.send() {
+            Ok(res) => res,
+            Err(err) => {
+                self.ring.record(&self.url, None, &err.to_string(), started);
+                return Err(SensorError::from(err));
+            }
+        };
+        let mut body: String = String::new();
+        res.read_to_string(&mut body)?;
+        self.ring
+            .record(&self.url, Some(res.status().as_u16()), &body, started);
+        if res.status() != 200 {
+            return Err(SensorError::Http(format!(
+                "Status code was not 200; but: {}.",
+                res.status()
+            )));
+        }
+        serde_json::from_str(&body).map_err(|e| SensorError::Parse(e.to_string()))
+    }
+}
+
+/// The value a pointer points to in the body.
+pub(crate) fn extract(body: &serde_json::Value, pointer: &str) -> Result<f64, String> {
+    match body.pointer(pointer) {
+        None => Err(format!("{} not found.", pointer)),
+        Some(serde_json::Value::Bool(flag)) => Ok(if *flag { 1.0 } else { 0.0 }),
+        Some(serde_json::Value::String(text)) => {
+            common::parse_number(text, false).map_err(|e| format!("{} is {}", pointer, e))
+        }
+        Some(value) => value
+            .as_f64()
+            .ok_or_else(|| format!("{} is not a number: {}.", pointer, value)),
+    }
+}
+
+impl common::Sensor for HttpJsonSensor {
+    fn get_names(&self) -> Vec<String> {
+        self.metrics
+            .iter()
+            .map(|(metric, _)| format!("{}_{}", self.name, metric))
+            .collect()
+    }
+
+    fn get_units(&self) -> Vec<String> {
+        vec!["unknown".to_string(); self.metrics.len()]
+    }
+
+    fn measure(&self) -> Result<Vec<Reading>, SensorError> {
+        let body = self.get_body()?;
+        let mut res = Vec::new();
+        for (name, (_, pointer)) in self.get_names().into_iter().zip(&self.metrics) {
+            match extract(&body, pointer) {
+                Ok(value) => res.push(Reading::new(name, value)),
+                Err(err) => eprintln!("Could not read {}: {}", name, err),
+            }
+        }
+        Ok(res)
+    }
+}
+
+/// Any JSON endpoint; the timeout defaults to 10 secs.
+pub(crate) fn sensor_type() -> common::SensorType {
+    common::SensorType {
+        name: "http_json",
+        required: &["url", "metrics"],
+        optional: &["headers", "username", "password"],
+        create: |name, sensor_cfg, _| {
+            let invalid = |msg: &str| ConfigError::Invalid(msg.to_string());
+            let url = sensor_cfg["url"]
+                .as_str()
+                .ok_or_else(|| invalid("url must be a string."))?;
+            let mut headers = HeaderMap::new();
+            if let Some(table) = sensor_cfg.get("headers") {
+                let table = table
+                    .as_table()
+                    .ok_or_else(|| invalid("headers must be a table."))?;
+                for (key, value) in table {
+                    let header = HeaderName::from_bytes(key.as_bytes())
+                        .map_err(|_| ConfigError::Invalid(format!("invalid header: {}.", key)))?;
+                    let value = value
+                        .as_str()
+                        .and_then(|v| HeaderValue::from_str(v).ok())
+                        .ok_or_else(|| {
+                            ConfigError::Invalid(format!("invalid value of header {}.", key))
+                        })?;
+                    headers.insert(header, value);
+                }
+            }
+            let auth = match sensor_cfg.get("username") {
+                Some(username) => Some((
+                    username
+                        .as_str()
+                        .ok_or_else(|| invalid("username must be a string."))?
+                        .to_string(),
+                    sensor_cfg
+                        .get("password")
+                        .and_then(|p| p.as_str())
+                        .map(|p| p.to_string()),
+                )),
+                None => None,
+            };
+            let timeout = sensor_cfg
+                .get("timeout_secs")
+                .map(|v| v.as_float().or_else(|| v.as_integer().map(|i| i as f64)))
+                .unwrap_or(Some(10.0))
+                .filter(|secs| *secs > 0.0)
+                .ok_or_else(|| invalid("timeout_secs must be positive."))?;
+            let metrics = sensor_cfg["metrics"]
+                .as_array()
+                .ok_or_else(|| invalid("metrics must be an array."))?
+                .iter()
+                .map(|metric| {
+                    let field = |key: &str| metric.get(key).and_then(|v| v.as_str());
+                    match (field("name"), field("pointer")) {
+                        (Some(name), Some(pointer))
+                            if pointer.is_empty() || pointer.starts_with('/') =>
+                        {
+                            Ok((name.to_string(), pointer.to_string()))
+                        }
+                        _ => Err(invalid(
+                            "each metric must have a name and a pointer starting with '/'.",
+                        )),
+                    }
+                })
+                .collect::<Result<_, _>>()?;
+            Ok(Box::new(HttpJsonSensor::new(
+                name.to_string(),
+                url.to_string(),
+                headers,
+                auth,
+                time::Duration::from_secs_f64(timeout),
+                metrics,
+            )))
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::Sensor;
+
+    const STATUS: &str = "{\"StatusSNS\": {\"Time\": \"2024-05-01T12:00:00\", \"ENERGY\": {\"Power\": 42, \
+    \"Voltage\": \"231.5\", \"Current\": [0.18, 0.02]}}, \"StatusSTS\": {\"POWER\": true, \"Wifi\": {\"RSSI\": \
+    \"weak\"}}}";
+
+    fn plug(url: String, auth: Option<(String, Option<String>)>) -> HttpJsonSensor {
+        let metrics = [
+            ("power", "/StatusSNS/ENERGY/Power"),
+            ("voltage", "/StatusSNS/ENERGY/Voltage"),
+            ("current_l2", "/StatusSNS/ENERGY/Current/1"),
+            ("on", "/StatusSTS/POWER"),
+            ("rssi", "/StatusSTS/Wifi/RSSI"),
+            ("total", "/StatusSNS/ENERGY/Total"),
+        ];
+        HttpJsonSensor::new(
+            "plug".to_string(),
+            format!("{}/cm/status", url),
+            HeaderMap::new(),
+            auth,
+            time::Duration::from_secs(5),
+            metrics
+                .iter()
+                .map(|(n, p)| (n.to_string(), p.to_string()))
+                .collect(),
+        )
+    }
+
+    // Tests for success.
+
+    #[test]
+    fn test_measure_for_success() {
+        let mut server = mockito::Server::new();
+        let mock = server
+            .mock("GET", "/cm/status")
+            .with_body(STATUS)
+            .expect(1)
+            .create();
+        let sensor = plug(server.url(), None);
+        let readings = sensor.measure().unwrap();
+        // rssi is not a number and total missing; both are left out.
+        assert_eq!(
+            readings
+                .iter()
+                .map(|r| (r.name.as_str(), r.value))
+                .collect::<Vec<_>>(),
+            vec![
+                ("plug_power", 42.0),
+                ("plug_voltage", 231.5),
+                ("plug_current_l2", 0.02),
+                ("plug_on", 1.0)
+            ]
+        );
+        mock.assert();
+    }
+
+    #[test]
+    fn test_auth_for_success() {
+        let mut server = mockito::Server::new();
+        let mock = server
+            .mock("GET", "/cm/status")
+            // admin:secret
+            .match_header("authorization", "Basic YWRtaW46c2VjcmV0")
+            .match_header("x-api-key", "abc")
+            .with_body(STATUS)
+            .create();
+        let sensor_cfg: toml::value::Table = toml::from_str(&format!(
+            "type='http_json'\nurl='{}/cm/status'\nusername='admin'\npassword='secret'\n\
+            headers={{ x-api-key='abc' }}\nmetrics=[{{ name='power', pointer='/StatusSNS/ENERGY/Power' }}]",
+            server.url()
+        ))
+        .unwrap();
+        let sensor = (sensor_type().create)("plug", &sensor_cfg, "state").unwrap();
+        assert_eq!(sensor.measure().unwrap()[0].value, 42.0);
+        mock.assert();
+    }
+
+    // Tests for failure.
+
+    #[test]
+    fn test_measure_for_failure() {
+        let mut server = mockito::Server::new();
+        server.mock("GET", "/cm/status").with_status(401).create();
+        let sensor = plug(server.url(), Some(("admin".to_string(), None)));
+        assert!(matches!(sensor.measure(), Err(SensorError::Http(_))));
+
+        let mut server = mockito::Server::new();
+        server
+            .mock("GET", "/cm/status")
+            .with_body("<html>")
+            .create();
+        let sensor = plug(server.url(), None);
+        assert!(matches!(sensor.measure(), Err(SensorError::Parse(_))));
+    }
+
+    #[test]
+    fn test_sensor_type_for_failure() {
+        let sensor_cfg: toml::value::Table = toml::from_str(
+            "type='http_json'\nurl='http://localhost'\nmetrics=[{ name='power', pointer='StatusSNS' }]",
+        )
+        .unwrap();
+        assert!((sensor_type().create)("plug", &sensor_cfg, "state").is_err());
+    }
+
+    // Tests for sanity.
+
+    #[test]
+    fn test_extract_for_sanity() {
+        let body: serde_json::Value = serde_json::from_str(STATUS).unwrap();
+        assert_eq!(extract(&body, "/StatusSNS/ENERGY/Current/0"), Ok(0.18));
+        assert!(extract(&body, "/StatusSNS/ENERGY/Current/2").is_err());
+        assert!(extract(&body, "/StatusSNS/Time").is_err());
+        assert!(extract(&body, "/StatusSNS/ENERGY").is_err());
+    }
+}
@@ -0,0 +1,621 @@
+use std::fs;
+use std::io::{Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::time;
+
+use openssl::ssl::{SslConnector, SslMethod, SslStream, SslVerifyMode};
+
+use crate::common;
+use crate::common::{ConfigError, Reading, SensorError};
+use crate::debug;
+use crate::shelly;
+
+/// How to trust the bridge, whose certificate is self-signed or signed by the Hue root CA and names the bridge id
+/// rather than its address.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Trust {
+    /// The usual verification; e.g. for a bridge behind a proxy with a proper certificate.
+    System,
+    /// The PEM of the bridge's certificate or of the Hue root CA; the only one trusted.
+    Certificate(Vec<u8>),
+    /// The SHA-256 fingerprint of the bridge's certificate, as lowercase hex w/o colons.
+    Fingerprint(String),
+}
+
+/// The fingerprint as compared: lowercase hex w/o colons, e.g. as copied from a browser.
+pub(crate) fn normalize(fingerprint: &str) -> String {
+    fingerprint
+        .chars()
+        .filter(|c| *c != ':' && !c.is_whitespace())
+        .collect::<String>()
+        .to_lowercase()
+}
+
+/// The fingerprint of a DER encoded certificate.
+pub(crate) fn fingerprint(der: &[u8]) -> String {
+    openssl::sha::sha256(der)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// The on state and battery level of each device given the light and device_power resources; each if present.
+///
+/// A device is found by its id or by the id of one of its services.
+pub(crate) fn parse_resources(
+    lights: &serde_json::Value,
+    powers: &serde_json::Value,
+    ids: &[String],
+) -> Vec<[Option<f64>; 2]> {
+    let find = |resources: &serde_json::Value, id: &str| -> Option<serde_json::Value> {
+        resources["data"]
+            .as_array()?
+            .iter()
+            .find(|r| r["owner"]["rid"].as_str() == Some(id) || r["id"].as_str() == Some(id))
+            .cloned()
+    };
+    ids.iter()
+        .map(|id| {
+            let on = find(lights, id)
+                .and_then(|l| l["on"]["on"].as_bool())
+                .map(|on| if on { 1.0 } else { 0.0 });
+            let battery = find(powers, id).and_then(|p| p["power_state"]["battery_level"].as_f64());
+            [on, battery]
+        })
+        .collect()
+}
+
+/// Reads devices through a Philips Hue bridge via its CLIP v2 API: whether smart plugs and lights are on, and the
+/// battery level of battery devices like switches and motion sensors.
+pub struct HueSensor {
+    name: String,
+    url: String,
+    key: String,
+    trust: Trust,
+    // names and ids of the devices.
+    devices: Vec<(String, String)>,
+    timeout: time::Duration,
+    client: reqwest::blocking::Client,
+    ring: Arc<debug::Ring>,
+}
+
+impl HueSensor {
+    pub(crate) fn new(
+        name: String,
+        url: String,
+        key: String,
+        trust: Trust,
+        devices: Vec<(String, String)>,
+        timeout: time::Duration,
+    ) -> Result<HueSensor, SensorError> {
+        let mut builder = reqwest::blocking::Client::builder().timeout(timeout);
+        match &trust {
+            Trust::System => {}
+            Trust::Certificate(pem) => {
+                let certificate = reqwest::Certificate::from_pem(pem)
+                    .map_err(|e| SensorError::Parse(format!("invalid certificate: {}", e)))?;
+                builder = builder
+                    .tls_built_in_root_certs(false)
+                    .add_root_certificate(certificate)
+                    .danger_accept_invalid_hostnames(true);
+            }
+            // not used for the bridge; see connect.
+            Trust::Fingerprint(_) => {}
+        }
+        Ok(HueSensor {
+            ring: debug::ring(&name),
+            name,
+            url,
+            key,
+            trust,
+            devices,
+            timeout,
+            client: builder.build().map_err(SensorError::from)?,
+        })
+    }
+
+    /// A TLS connection to the bridge whose handshake only succeeds if its certificate has the expected fingerprint;
+    /// so nothing is ever sent to anyone else.
+    fn connect(&self, expected: &str) -> Result<SslStream<TcpStream>, SensorError> {
+        let host = self
+            .url
+            .trim_start_matches("https://")
+            .split('/')
+            .next()
+            .unwrap_or_default();
+        let address = if host.contains(':') {
+            host.to_string()
+        } else {
+            format!("{}:443", host)
+        };
+        let socket = address
+            .to_socket_addrs()
+            .ok()
+            .and_then(|mut a| a.next())
+            .ok_or_else(|| SensorError::Io(format!("could not resolve {}.", address)))?;
+        let stream = TcpStream::connect_timeout(&socket, self.timeout)
+            .map_err(|e| SensorError::Io(format!("{}: {}", address, e)))?;
+        for res in [
+            stream.set_read_timeout(Some(self.timeout)),
+            stream.set_write_timeout(Some(self.timeout)),
+        ] {
+            res.map_err(|e| SensorError::Io(e.to_string()))?;
+        }
+        // the fingerprint of the certificate presented; to tell what was got instead.
+        let presented = Arc::new(Mutex::new(None));
+        let seen = presented.clone();
+        let expected_fingerprint = expected.to_string();
+        let mut connector = SslConnector::builder(SslMethod::tls())
+            .map_err(|e| SensorError::Protocol(e.to_string()))?;
+        // the pin decides on the bridge's own certificate; whoever signed it does not matter.
+        connector.set_verify_callback(SslVerifyMode::PEER, move |_, ctx| {
+            if ctx.error_depth() != 0 {
+                return true;
+            }
+            let actual = ctx
+                .current_cert()
+                .and_then(|c| c.to_der().ok())
+                .map(|der| fingerprint(&der));
+            let matches = actual.as_deref() == Some(expected_fingerprint.as_str());
+            *seen.lock().expect("fingerprint lock poisoned.") = actual;
+            matches
+        });
+        connector
+            .build()
+            .configure()
+            .map_err(|e| SensorError::Protocol(e.to_string()))?
+            .verify_hostname(false)
+            .connect(host.split(':').next().unwrap_or_default(), stream)
+            .map_err(|e| {
+                match presented
+                    .lock()
+                    .expect("fingerprint lock poisoned.")
+                    .as_ref()
+                {
+                    Some(actual) if actual != expected => SensorError::Protocol(format!(
+                        "the bridge's certificate does not match the fingerprint {}; got: {}.",
+                        expected, actual
+                    )),
+                    _ => SensorError::Protocol(format!(
+                        "TLS handshake with {} failed: {}",
+                        address, e
+                    )),
+                }
+            })
+    }
+
+    /// Gets the given path from the bridge over a pinned connection; returns the status code and body.
+    fn get_pinned(&self, expected: &str, path: &str) -> Result<(u16, String), SensorError> {
+        let mut stream = self.connect(expected)?;
+        let host = self.url.trim_start_matches("https://");
+        // HTTP/1.0, so the answer is neither chunked nor kept alive.
+        let request = format!(
+            "GET {} HTTP/1.0\r\nHost: {}\r\nhue-application-key: {}\r\nAccept: application/json\r\n\r\n",
+            path, host, self.key
+        );
+        stream.write_all(request.as_bytes())?;
+        let mut answer = String::new();
+        stream.read_to_string(&mut answer)?;
+        let (head, body) = answer
+            .split_once("\r\n\r\n")
+            .ok_or_else(|| SensorError::Parse("incomplete answer from the bridge.".to_string()))?;
+        let status = head
+            .split_whitespace()
+            .nth(1)
+            .and_then(|code| code.parse().ok())
+            .ok_or_else(|| SensorError::Parse(format!("invalid status line: {}", head)))?;
+        Ok((status, body.to_string()))
+    }
+
+    /// The resource as JSON, given the status code and body of the answer.
+    fn parse(&self, status: u16, body: &str) -> Result<serde_json::Value, SensorError> {
+        let status =
+            reqwest::StatusCode::from_u16(status).map_err(|e| SensorError::Parse(e.to_string()))?;
+        match status.as_u16() {
+            200 => serde_json::from_str(body).map_err(|e| SensorError::Parse(e.to_string())),
+            401 | 403 => Err(SensorError::Http(format!(
+                "the bridge rejected the application key ({}); press its link button and within 30 secs create a key \
+                 with: curl -k -X POST {}/api -d '{{\"devicetype\":\"ogc#{}\",\"generateclientkey\":true}}'",
+                status, self.url, self.name
+            ))),
+            _ => Err(SensorError::Http(format!(
+                "Status code was not 200; but: {}.",
+                status
+            ))),
+        }
+    }
+
+    fn get(&self, resource: &str) -> Result<serde_json::Value, SensorError> {
+        let path = format!("/clip/v2/resource/{}", resource);
+        let query = format!("{}{}", self.url, path);
+        let started = time::Instant::now();
+        if let Trust::Fingerprint(expected) = &self.trust {
+            let (status, body) = match self.get_pinned(expected, &path) {
+                Ok(answer) => answer,
+                Err(err) => {
+                    self.ring.record(&query, None, &err.to_string(), started);
+                    return Err(err);
+                }
+            };
+            self.ring.record(&query, Some(status), &body, started);
+            return self.parse(status, &body);
+        }
+        let request = self
+            .client
+            .get(&query)
+            .header("hue-application-key", &self.key);
+        let mut res = match request.send() {
+            Ok(res) => res,
+            Err(err) => {
+                self.ring.record(&query, None, &err.to_string(), started);
+                return Err(SensorError::from(err));
+            }
+        };
+        let mut body: String = String::new();
+        res.read_to_string(&mut body)?;
+        self.ring
+            .record(&query, Some(res.status().as_u16()), &body, started);
+        self.parse(res.status().as_u16(), &body)
+    }
+}
+
+impl common::Sensor for HueSensor {
+    fn get_names(&self) -> Vec<String> {
+        self.devices
+            .iter()
+            .flat_map(|(device, _)| {
+                ["on", "battery_percent"]
+                    .iter()
+                    .map(move |metric| format!("{}_{}_{}", self.name, device, metric))
+            })
+            .collect()
+    }
+
+    fn get_units(&self) -> Vec<String> {
+        self.devices
+            .iter()
+            .flat_map(|_| ["", "%"].iter().map(|u| u.to_string()))
+            .collect()
+    }
+
+    fn measure(&self) -> Result<Vec<Reading>, SensorError> {
+        let lights = self.get("light")?;
+        // bridges w/o battery devices may not have the resource.
+        let powers = self.get("device_power").unwrap_or_default();
+        let ids: Vec<String> = self.devices.iter().map(|(_, id)| id.clone()).collect();
+        Ok(self
+            .get_names()
+            .into_iter()
+            .zip(
+                parse_resources(&lights, &powers, &ids)
+                    .into_iter()
+                    .flatten(),
+            )
+            .filter_map(|(name, value)| value.map(|v| Reading::new(name, v)))
+            .collect())
+    }
+
+    fn init(&mut self) -> Result<(), SensorError> {
+        // fail at startup rather than with the first measurement.
+        if let Trust::Fingerprint(expected) = &self.trust {
+            self.connect(expected)?;
+        }
+        Ok(())
+    }
+}
+
+/// Devices behind a Philips Hue bridge.
+pub(crate) fn sensor_type() -> common::SensorType {
+    common::SensorType {
+        name: "hue",
+        required: &["host", "key", "devices"],
+        optional: &["certificate", "fingerprint", "timeout_secs"],
+        create: |name, sensor_cfg, _| {
+            let invalid = |msg: &str| ConfigError::Invalid(msg.to_string());
+            let host = sensor_cfg["host"]
+                .as_str()
+                .ok_or_else(|| invalid("host must be a string."))?;
+            let url = if host.starts_with("http://") || host.starts_with("https://") {
+                host.trim_end_matches('/').to_string()
+            } else {
+                format!("https://{}", host)
+            };
+            let key = sensor_cfg["key"]
+                .as_str()
+                .ok_or_else(|| invalid("key must be a string."))?;
+            let devices = sensor_cfg["devices"]
+                .as_array()
+                .ok_or_else(|| invalid("devices must be an array."))?
+                .iter()
+                .map(|device| {
+                    let field = |key: &str| device.get(key).and_then(|v| v.as_str());
+                    match (field("name"), field("id")) {
+                        (Some(name), Some(id)) => Ok((name.to_string(), id.to_string())),
+                        _ => Err(invalid("each device must have a name and an id.")),
+                    }
+                })
+                .collect::<Result<_, _>>()?;
+            let trust = match (sensor_cfg.get("certificate"), sensor_cfg.get("fingerprint")) {
+                (None, None) => Trust::System,
+                (Some(path), None) => {
+                    let path = path
+                        .as_str()
+                        .ok_or_else(|| invalid("certificate must be a path."))?;
+                    Trust::Certificate(fs::read(path).map_err(|e| {
+                        ConfigError::Invalid(format!("cannot read certificate {}: {}.", path, e))
+                    })?)
+                }
+                (None, Some(fingerprint)) => {
+                    let fingerprint = fingerprint
+                        .as_str()
+                        .map(normalize)
+                        .filter(|f| f.len() == 64 && f.chars().all(|c| c.is_ascii_hexdigit()))
+                        .ok_or_else(|| {
+                            invalid("fingerprint must be the SHA-256 of the certificate in hex.")
+                        })?;
+                    Trust::Fingerprint(fingerprint)
+                }
+                (Some(_), Some(_)) => {
+                    return Err(invalid("set either certificate or fingerprint; not both."))
+                }
+            };
+            let sensor = HueSensor::new(
+                name.to_string(),
+                url,
+                key.to_string(),
+                trust,
+                devices,
+                shelly::get_timeout(sensor_cfg)?,
+            )
+            .map_err(|e| ConfigError::Invalid(e.message().to_string()))?;
+            Ok(Box::new(sensor))
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+    use std::thread;
+
+    use openssl::asn1::Asn1Time;
+    use openssl::hash::MessageDigest;
+    use openssl::pkey::PKey;
+    use openssl::rsa::Rsa;
+    use openssl::ssl::SslAcceptor;
+    use openssl::x509::{X509NameBuilder, X509};
+
+    use super::*;
+    use crate::common::values;
+    use crate::common::Sensor;
+
+    const PLUG: &str = "3f2c7f0e-8d6a-4c1c-9b1e-5a0e2f1d7c11";
+    const DIAL: &str = "b1d5e4a2-0c3f-4e8b-a6d7-9f2e1c0b3a44";
+
+    /// As served for a smart plug and a bulb; the tap dial has no light.
+    const LIGHTS: &str = r#"{"errors":[],"data":[{"id":"6c1b0a9e-2d4f-4b3a-8e7c-1f0d2c3b4a55",
+    "owner":{"rid":"3f2c7f0e-8d6a-4c1c-9b1e-5a0e2f1d7c11","rtype":"device"},"metadata":{"name":"Dishwasher",
+    "archetype":"plug"},"on":{"on":true},"mode":"normal","type":"light"},{"id":"0a1b2c3d-4e5f-4a6b-8c7d-9e0f1a2b3c66",
+    "owner":{"rid":"7e6d5c4b-3a2f-4e1d-9c0b-8a7f6e5d4c77","rtype":"device"},"on":{"on":false},
+    "dimming":{"brightness":100.0},"type":"light"}]}"#;
+
+    const POWERS: &str = r#"{"errors":[],"data":[{"id":"9d8c7b6a-5f4e-4d3c-ab2a-1f0e9d8c7b88",
+    "owner":{"rid":"b1d5e4a2-0c3f-4e8b-a6d7-9f2e1c0b3a44","rtype":"device"},
+    "power_state":{"battery_state":"normal","battery_level":87},"type":"device_power"}]}"#;
+
+    fn hue(url: String) -> HueSensor {
+        HueSensor::new(
+            "hue".to_string(),
+            url,
+            "secret-key".to_string(),
+            Trust::System,
+            vec![
+                ("dishwasher".to_string(), PLUG.to_string()),
+                ("dial".to_string(), DIAL.to_string()),
+            ],
+            time::Duration::from_secs(1),
+        )
+        .unwrap()
+    }
+
+    /// A bridge serving the lights and no battery devices over TLS, to the given number of connections; returns its
+    /// url, the fingerprint of its certificate and the requests it got.
+    fn bridge(connections: usize) -> (String, String, mpsc::Receiver<String>) {
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_text("CN", "001788fffe000000").unwrap();
+        let name = name.build();
+        let mut cert = X509::builder().unwrap();
+        cert.set_version(2).unwrap();
+        cert.set_subject_name(&name).unwrap();
+        cert.set_issuer_name(&name).unwrap();
+        cert.set_pubkey(&key).unwrap();
+        cert.set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        cert.set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        cert.sign(&key, MessageDigest::sha256()).unwrap();
+        let cert = cert.build();
+        let mut acceptor = SslAcceptor::mozilla_intermediate(SslMethod::tls()).unwrap();
+        acceptor.set_private_key(&key).unwrap();
+        acceptor.set_certificate(&cert).unwrap();
+        let acceptor = acceptor.build();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("https://{}", listener.local_addr().unwrap());
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            for stream in listener.incoming().take(connections) {
+                let mut ssl = match acceptor.accept(stream.unwrap()) {
+                    Ok(ssl) => ssl,
+                    // refused by the client.
+                    Err(_) => continue,
+                };
+                let mut request = Vec::new();
+                let mut buf = [0; 1024];
+                while !String::from_utf8_lossy(&request).contains("\r\n\r\n") {
+                    match ssl.read(&mut buf) {
+                        Ok(0) | Err(_) => break,
+                        Ok(n) => request.extend_from_slice(&buf[..n]),
+                    }
+                }
+                let request = String::from_utf8_lossy(&request).to_string();
+                let (status, body) = if request.starts_with("GET /clip/v2/resource/light ") {
+                    ("200 OK", LIGHTS)
+                } else {
+                    ("404 Not Found", "")
+                };
+                let answer = format!(
+                    "HTTP/1.1 {}\r\nContent-Length: {}\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    body
+                );
+                ssl.write_all(answer.as_bytes()).unwrap();
+                let _ = ssl.shutdown();
+                tx.send(request).unwrap();
+            }
+        });
+        (url, fingerprint(&cert.to_der().unwrap()), rx)
+    }
+
+    fn pinned(url: String, fingerprint: String) -> HueSensor {
+        HueSensor::new(
+            "hue".to_string(),
+            url,
+            "secret-key".to_string(),
+            Trust::Fingerprint(fingerprint),
+            vec![("dishwasher".to_string(), PLUG.to_string())],
+            time::Duration::from_secs(5),
+        )
+        .unwrap()
+    }
+
+    // Tests for success.
+
+    #[test]
+    fn test_measure_for_success() {
+        let mut server = mockito::Server::new();
+        for (resource, body) in [("light", LIGHTS), ("device_power", POWERS)] {
+            server
+                .mock("GET", format!("/clip/v2/resource/{}", resource).as_str())
+                .match_header("hue-application-key", "secret-key")
+                .with_body(body)
+                .create();
+        }
+        let sensor = hue(server.url());
+        assert_eq!(sensor.get_units(), vec!["", "%", "", "%"]);
+        assert_eq!(
+            values(sensor.measure()),
+            vec![
+                ("hue_dishwasher_on".to_string(), 1.0),
+                ("hue_dial_battery_percent".to_string(), 87.0)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_pinned_for_success() {
+        let (url, fingerprint, requests) = bridge(3);
+        let mut sensor = pinned(url, fingerprint);
+        sensor.init().unwrap();
+        assert_eq!(
+            values(sensor.measure()),
+            vec![("hue_dishwasher_on".to_string(), 1.0)]
+        );
+        let requests: Vec<String> = requests.iter().take(3).collect();
+        // init only checks the certificate.
+        assert_eq!(requests[0], "");
+        assert!(requests[1].starts_with("GET /clip/v2/resource/light HTTP/1.0\r\n"));
+        assert!(requests[1].contains("hue-application-key: secret-key\r\n"));
+    }
+
+    #[test]
+    fn test_fingerprint_for_success() {
+        let expected = fingerprint(b"not really a certificate");
+        assert_eq!(expected.len(), 64);
+        let copied: Vec<String> = (0..32)
+            .map(|i| expected[2 * i..2 * i + 2].to_uppercase())
+            .collect();
+        assert_eq!(normalize(&copied.join(":")), expected);
+    }
+
+    // Tests for failure.
+
+    #[test]
+    fn test_measure_for_failure() {
+        let mut server = mockito::Server::new();
+        server
+            .mock("GET", "/clip/v2/resource/light")
+            .with_status(403)
+            .with_body(r#"{"errors":[{"description":"unauthorized user"}],"data":[]}"#)
+            .create();
+        let sensor = hue(server.url());
+        match sensor.measure() {
+            Err(SensorError::Http(msg)) => {
+                assert!(msg.starts_with(
+                    "the bridge rejected the application key (403 Forbidden); press its link"
+                ))
+            }
+            _ => panic!("rejected key not reported."),
+        }
+    }
+
+    #[test]
+    fn test_pinned_for_failure() {
+        // e.g. someone in between presenting a certificate of their own.
+        let (url, _, requests) = bridge(2);
+        let mut sensor = pinned(url, fingerprint(b"the bridge's certificate"));
+        match sensor.init() {
+            Err(SensorError::Protocol(msg)) => assert!(
+                msg.starts_with("the bridge's certificate does not match the fingerprint"),
+                "{}",
+                msg
+            ),
+            _ => panic!("certificate not refused."),
+        }
+        assert!(sensor.measure().is_err());
+        // the key never went out.
+        assert!(requests
+            .recv_timeout(time::Duration::from_millis(500))
+            .is_err());
+    }
+
+    #[test]
+    fn test_sensor_type_for_failure() {
+        let sensor_cfg: toml::value::Table =
+            toml::from_str("host='192.168.178.43'\nkey='k'\ndevices=[]\nfingerprint='AB:CD'")
+                .unwrap();
+        assert_eq!(
+            (sensor_type().create)("hue", &sensor_cfg, "state").err(),
+            Some(ConfigError::Invalid(
+                "fingerprint must be the SHA-256 of the certificate in hex.".to_string()
+            ))
+        );
+        let sensor_cfg: toml::value::Table = toml::from_str(
+            "host='192.168.178.43'\nkey='k'\ndevices=[]\ncertificate='hue_missing.pem'",
+        )
+        .unwrap();
+        assert!((sensor_type().create)("hue", &sensor_cfg, "state").is_err());
+    }
+
+    // Tests for sanity.
+
+    #[test]
+    fn test_parse_resources_for_sanity() {
+        let lights: serde_json::Value = serde_json::from_str(LIGHTS).unwrap();
+        // e.g. a bridge w/o battery devices.
+        let res = parse_resources(
+            &lights,
+            &serde_json::Value::Null,
+            &[
+                // by the id of the light service.
+                "0a1b2c3d-4e5f-4a6b-8c7d-9e0f1a2b3c66".to_string(),
+                "unknown".to_string(),
+            ],
+        );
+        assert_eq!(res, vec![[Some(0.0), None], [None, None]]);
+    }
+}
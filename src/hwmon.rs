@@ -0,0 +1,464 @@
+use std::cell::RefCell;
+use std::fs;
+use std::path;
+
+use crate::common;
+use crate::common::{ConfigError, Reading, SensorError};
+use crate::privacy;
+use crate::prometheus;
+
+/// Where the kernel exposes the hwmon devices.
+const HWMON_DIR: &str = "/sys/class/hwmon";
+
+/// The kinds of inputs reported; others like in*_input or curr*_input are left out.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum Kind {
+    Temp,
+    Fan,
+    Power,
+}
+
+impl Kind {
+    /// The kind and index of an attribute like temp1_input.
+    fn parse(attribute: &str) -> Option<(Kind, u32)> {
+        let channel = attribute.strip_suffix("_input")?;
+        [Kind::Temp, Kind::Fan, Kind::Power]
+            .into_iter()
+            .find_map(|kind| {
+                let index = channel.strip_prefix(kind.prefix())?.parse().ok()?;
+                Some((kind, index))
+            })
+    }
+
+    fn prefix(&self) -> &'static str {
+        match self {
+            Kind::Temp => "temp",
+            Kind::Fan => "fan",
+            Kind::Power => "power",
+        }
+    }
+
+    fn unit(&self) -> &'static str {
+        match self {
+            Kind::Temp => "°C",
+            Kind::Fan => "RPM",
+            Kind::Power => "W",
+        }
+    }
+
+    /// Temperatures come in m°C and power in µW.
+    fn scale(&self) -> f64 {
+        match self {
+            Kind::Temp => 1e-3,
+            Kind::Fan => 1.0,
+            Kind::Power => 1e-6,
+        }
+    }
+}
+
+/// An input of a hwmon device, e.g. the Tctl temperature of k10temp.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Metric {
+    /// The name of its column, e.g. k10temp_tctl_temp or nvme_temp1.
+    pub(crate) name: String,
+    kind: Kind,
+    path: path::PathBuf,
+}
+
+impl Metric {
+    fn value(&self) -> Result<f64, SensorError> {
+        let content = fs::read_to_string(&self.path)
+            .map_err(|e| SensorError::Io(format!("cannot read {}: {}", self.path.display(), e)))?;
+        let value: f64 = content.trim().parse().map_err(|e| {
+            SensorError::Parse(format!(
+                "{} of {}: {}",
+                content.trim(),
+                self.path.display(),
+                e
+            ))
+        })?;
+        Ok(value * self.kind.scale())
+    }
+}
+
+/// Whether the text matches any of the patterns; no patterns match everything.
+fn selected(patterns: &[String], texts: &[&str]) -> bool {
+    patterns.is_empty()
+        || patterns
+            .iter()
+            .any(|p| texts.iter().any(|t| privacy::matches(p, t)))
+}
+
+/// Finds the temperature, fan and power inputs of the hwmon devices in the given directory, ordered by device.
+///
+/// Devices are selected by their chip name and inputs by their label or attribute name, e.g. temp1; chips that are
+/// present more than once are numbered, e.g. nvme_0 and nvme_1.
+pub(crate) fn discover(root: &str, chips: &[String], labels: &[String]) -> Vec<Metric> {
+    let entries = match fs::read_dir(root) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+    let mut devices: Vec<(u32, path::PathBuf, String)> = entries
+        .filter_map(|e| e.ok())
+        .filter_map(|e| {
+            let file_name = e.file_name().to_string_lossy().to_string();
+            let id = file_name.strip_prefix("hwmon")?.parse().ok()?;
+            let chip = common::read_sysfs(&e.path(), "name")?;
+            Some((id, e.path(), chip))
+        })
+        .collect();
+    devices.sort();
+    let mut res = Vec::new();
+    for (id, dir, chip) in &devices {
+        let same: Vec<u32> = devices
+            .iter()
+            .filter(|(_, _, c)| c == chip)
+            .map(|(i, _, _)| *i)
+            .collect();
+        let chip_name = if same.len() > 1 {
+            let n = same.iter().position(|i| i == id).unwrap_or(0);
+            format!("{}_{}", chip, n)
+        } else {
+            chip.clone()
+        };
+        if !selected(chips, &[chip, &chip_name]) {
+            continue;
+        }
+        let mut inputs: Vec<(Kind, u32, path::PathBuf)> = match fs::read_dir(dir) {
+            Ok(entries) => entries
+                .filter_map(|e| e.ok())
+                .filter_map(|e| {
+                    let (kind, index) = Kind::parse(&e.file_name().to_string_lossy())?;
+                    Some((kind, index, e.path()))
+                })
+                .collect(),
+            Err(_) => continue,
+        };
+        inputs.sort();
+        for (kind, index, path) in inputs {
+            let channel = format!("{}{}", kind.prefix(), index);
+            let name = match common::read_sysfs(dir, &format!("{}_label", channel)) {
+                Some(label) => {
+                    if !selected(labels, &[&label, &channel]) {
+                        continue;
+                    }
+                    format!("{}_{}_{}", chip_name, label, kind.prefix())
+                }
+                None if selected(labels, &[&channel]) => format!("{}_{}", chip_name, channel),
+                None => continue,
+            };
+            res.push(Metric {
+                name: prometheus::sanitize(&name).to_lowercase(),
+                kind,
+                path,
+            });
+        }
+    }
+    res
+}
+
+/// Reports temperatures, fan speeds and power rails of the host's hwmon devices, e.g. of its CPU, NVMe drives or PSUs.
+///
+/// Inputs that cannot be read, e.g. of a USB sensor that was unplugged, are left out of a measurement; the devices are
+/// looked up again then, as they may be back under another number.
+pub struct HwmonSensor {
+    name: String,
+    root: String,
+    chips: Vec<String>,
+    labels: Vec<String>,
+    metrics: RefCell<Vec<Metric>>,
+}
+
+impl HwmonSensor {
+    pub(crate) fn new(
+        name: String,
+        root: String,
+        chips: Vec<String>,
+        labels: Vec<String>,
+    ) -> HwmonSensor {
+        let metrics = discover(&root, &chips, &labels);
+        HwmonSensor {
+            name,
+            root,
+            chips,
+            labels,
+            metrics: RefCell::new(metrics),
+        }
+    }
+}
+
+impl common::Sensor for HwmonSensor {
+    fn get_names(&self) -> Vec<String> {
+        self.metrics
+            .borrow()
+            .iter()
+            .map(|m| format!("{}_{}", self.name, m.name))
+            .collect()
+    }
+
+    fn get_units(&self) -> Vec<String> {
+        self.metrics
+            .borrow()
+            .iter()
+            .map(|m| m.kind.unit().to_string())
+            .collect()
+    }
+
+    fn measure(&self) -> Result<Vec<Reading>, SensorError> {
+        let names = self.get_names();
+        let mut metrics = self.metrics.borrow_mut();
+        let mut rediscovered: Option<Vec<Metric>> = None;
+        let mut res = Vec::new();
+        let mut error = None;
+        for (i, metric) in metrics.iter_mut().enumerate() {
+            let mut value = metric.value();
+            if value.is_err() {
+                // the device may have come back under another number; the names stay the same.
+                let found = rediscovered
+                    .get_or_insert_with(|| discover(&self.root, &self.chips, &self.labels));
+                if let Some(found) = found.iter().find(|m| m.name == metric.name) {
+                    if found.path != metric.path {
+                        metric.path = found.path.clone();
+                        value = metric.value();
+                    }
+                }
+            }
+            match value {
+                Ok(value) => res.push(Reading::new(names[i].clone(), value)),
+                Err(err) => error = Some(err),
+            }
+        }
+        match error {
+            Some(err) if res.is_empty() => Err(err),
+            Some(err) => {
+                eprintln!("Could not read all hwmon inputs of {}: {}", self.name, err);
+                Ok(res)
+            }
+            None => Ok(res),
+        }
+    }
+}
+
+/// Reads an optional array of glob patterns.
+fn patterns(sensor_cfg: &toml::value::Table, key: &str) -> Result<Vec<String>, ConfigError> {
+    match sensor_cfg.get(key) {
+        Some(v) => v
+            .as_array()
+            .and_then(|a| {
+                a.iter()
+                    .map(|p| p.as_str().map(|p| p.to_string()))
+                    .collect::<Option<Vec<_>>>()
+            })
+            .ok_or_else(|| ConfigError::Invalid(format!("{} must be an array of strings.", key))),
+        None => Ok(Vec::new()),
+    }
+}
+
+/// Temperatures, fan speeds and power of the host's hwmon devices.
+pub(crate) fn sensor_type() -> common::SensorType {
+    common::SensorType {
+        name: "hwmon",
+        required: &[],
+        optional: &["path", "chips", "labels"],
+        create: |name, sensor_cfg, _| {
+            let root = match sensor_cfg.get("path") {
+                Some(v) => v
+                    .as_str()
+                    .ok_or_else(|| ConfigError::Invalid("path must be a string.".to_string()))?,
+                None => HWMON_DIR,
+            };
+            let chips = patterns(sensor_cfg, "chips")?;
+            let labels = patterns(sensor_cfg, "labels")?;
+            let sensor = HwmonSensor::new(name.to_string(), root.to_string(), chips, labels);
+            if sensor.metrics.borrow().is_empty() {
+                return Err(ConfigError::Invalid(format!(
+                    "no matching hwmon inputs found in {}.",
+                    root
+                )));
+            }
+            Ok(Box::new(sensor))
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::values;
+    use crate::common::Sensor;
+
+    /// A hwmon directory with a CPU, two NVMe drives and a PSU.
+    fn hwmon(root: &str) {
+        let _ = fs::remove_dir_all(root);
+        for (device, chip, files) in [
+            (
+                "hwmon0",
+                "k10temp",
+                vec![
+                    ("temp1_input", "48250"),
+                    ("temp1_label", "Tctl"),
+                    ("temp3_input", "41000"),
+                    ("temp3_label", "Tccd1"),
+                ],
+            ),
+            (
+                "hwmon1",
+                "nvme",
+                vec![("temp1_input", "38850"), ("temp1_label", "Composite")],
+            ),
+            (
+                "hwmon2",
+                "nvme",
+                vec![("temp1_input", "35850"), ("temp1_label", "Composite")],
+            ),
+            (
+                "hwmon3",
+                "corsairpsu",
+                vec![
+                    ("fan1_input", "1200"),
+                    ("power1_input", "152000000"),
+                    ("power1_label", "power total"),
+                    ("in0_input", "230000"),
+                ],
+            ),
+        ] {
+            let dir = path::Path::new(root).join(device);
+            fs::create_dir_all(&dir).unwrap();
+            fs::write(dir.join("name"), format!("{}\n", chip)).unwrap();
+            for (file, content) in files {
+                fs::write(dir.join(file), format!("{}\n", content)).unwrap();
+            }
+        }
+    }
+
+    fn strings(values: &[&str]) -> Vec<String> {
+        values.iter().map(|v| v.to_string()).collect()
+    }
+
+    // Tests for success.
+
+    #[test]
+    fn test_measure_for_success() {
+        let root = "hwmon_test0";
+        hwmon(root);
+        let sensor = HwmonSensor::new("host".to_string(), root.to_string(), vec![], vec![]);
+        assert_eq!(
+            sensor.get_names(),
+            vec![
+                "host_k10temp_tctl_temp",
+                "host_k10temp_tccd1_temp",
+                "host_nvme_0_composite_temp",
+                "host_nvme_1_composite_temp",
+                "host_corsairpsu_fan1",
+                "host_corsairpsu_power_total_power"
+            ]
+        );
+        assert_eq!(sensor.get_units(), vec!["°C", "°C", "°C", "°C", "RPM", "W"]);
+        assert_eq!(
+            values(sensor.measure()),
+            vec![
+                ("host_k10temp_tctl_temp".to_string(), 48.25),
+                ("host_k10temp_tccd1_temp".to_string(), 41.0),
+                ("host_nvme_0_composite_temp".to_string(), 38.85),
+                ("host_nvme_1_composite_temp".to_string(), 35.85),
+                ("host_corsairpsu_fan1".to_string(), 1200.0),
+                ("host_corsairpsu_power_total_power".to_string(), 152.0)
+            ]
+        );
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_discover_for_success() {
+        let root = "hwmon_test1";
+        hwmon(root);
+        let names = |chips: &[&str], labels: &[&str]| -> Vec<String> {
+            discover(root, &strings(chips), &strings(labels))
+                .into_iter()
+                .map(|m| m.name)
+                .collect()
+        };
+        assert_eq!(names(&["k10temp"], &["Tctl"]), vec!["k10temp_tctl_temp"]);
+        assert_eq!(
+            names(&["nvme*"], &[]),
+            vec!["nvme_0_composite_temp", "nvme_1_composite_temp"]
+        );
+        assert_eq!(names(&["nvme_1"], &[]), vec!["nvme_1_composite_temp"]);
+        // by label or attribute.
+        assert_eq!(
+            names(&[], &["power*", "fan1"]),
+            vec!["corsairpsu_fan1", "corsairpsu_power_total_power"]
+        );
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    // Tests for failure.
+
+    #[test]
+    fn test_measure_for_failure() {
+        let root = "hwmon_test2";
+        hwmon(root);
+        let sensor = HwmonSensor::new(
+            "host".to_string(),
+            root.to_string(),
+            strings(&["k10temp", "corsairpsu"]),
+            vec![],
+        );
+        // the PSU was unplugged; the CPU is still reported.
+        fs::remove_dir_all(path::Path::new(root).join("hwmon3")).unwrap();
+        assert_eq!(
+            values(sensor.measure()),
+            vec![
+                ("host_k10temp_tctl_temp".to_string(), 48.25),
+                ("host_k10temp_tccd1_temp".to_string(), 41.0)
+            ]
+        );
+        fs::remove_dir_all(root).unwrap();
+        assert!(matches!(sensor.measure(), Err(SensorError::Io(_))));
+    }
+
+    #[test]
+    fn test_sensor_type_for_failure() {
+        let sensor_cfg: toml::value::Table = toml::from_str("path='hwmon_missing'").unwrap();
+        assert_eq!(
+            (sensor_type().create)("host", &sensor_cfg, "state").err(),
+            Some(ConfigError::Invalid(
+                "no matching hwmon inputs found in hwmon_missing.".to_string()
+            ))
+        );
+        let sensor_cfg: toml::value::Table =
+            toml::from_str("path='hwmon_missing'\nchips='k10temp'").unwrap();
+        assert_eq!(
+            (sensor_type().create)("host", &sensor_cfg, "state").err(),
+            Some(ConfigError::Invalid(
+                "chips must be an array of strings.".to_string()
+            ))
+        );
+    }
+
+    // Tests for sanity.
+
+    #[test]
+    fn test_replug_for_sanity() {
+        let root = "hwmon_test3";
+        hwmon(root);
+        let sensor_cfg: toml::value::Table =
+            toml::from_str("path='hwmon_test3'\nchips=['corsairpsu']").unwrap();
+        let sensor = (sensor_type().create)("host", &sensor_cfg, "state").unwrap();
+        assert_eq!(values(sensor.measure()).len(), 2);
+        // back under another number.
+        fs::rename(
+            path::Path::new(root).join("hwmon3"),
+            path::Path::new(root).join("hwmon4"),
+        )
+        .unwrap();
+        assert_eq!(
+            values(sensor.measure()),
+            vec![
+                ("host_corsairpsu_fan1".to_string(), 1200.0),
+                ("host_corsairpsu_power_total_power".to_string(), 152.0)
+            ]
+        );
+        fs::remove_dir_all(root).unwrap();
+    }
+}
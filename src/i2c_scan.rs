@@ -0,0 +1,242 @@
+use embedded_hal::blocking::i2c;
+
+/// Addresses that are not reserved by the I2C specification.
+const FIRST: u8 = 0x08;
+const LAST: u8 = 0x77;
+
+/// Chips that can be identified.
+#[derive(Debug, PartialEq)]
+pub(crate) enum Chip {
+    Ina219,
+    Ina226,
+    Ina3221,
+    Bme280,
+    Bmp280,
+    Unknown,
+}
+
+/// Addresses that respond to a single byte read; reads are safe for (almost) all devices.
+pub(crate) fn probe<I2C, E>(i2c: &mut I2C) -> Vec<u8>
+where
+    I2C: i2c::Read<Error = E>,
+{
+    let mut buf = [0_u8; 1];
+    (FIRST..=LAST)
+        .filter(|address| i2c.read(*address, &mut buf).is_ok())
+        .collect()
+}
+
+fn read_u16<I2C, E>(i2c: &mut I2C, address: u8, register: u8) -> Option<u16>
+where
+    I2C: i2c::WriteRead<Error = E>,
+{
+    let mut buf = [0_u8; 2];
+    i2c.write_read(address, &[register], &mut buf).ok()?;
+    Some(u16::from_be_bytes(buf))
+}
+
+/// Identifies a chip by its identifiable registers; only addresses of known chips are touched.
+pub(crate) fn identify<I2C, E>(i2c: &mut I2C, address: u8) -> Chip
+where
+    I2C: i2c::WriteRead<Error = E>,
+{
+    match address {
+        0x40..=0x4f => {
+            // INA226 & INA3221 have a manufacturer ("TI") and a die id.
+            if read_u16(i2c, address, 0xfe) == Some(0x5449) {
+                match read_u16(i2c, address, 0xff) {
+                    Some(0x2260) => Chip::Ina226,
+                    Some(0x3220) => Chip::Ina3221,
+                    _ => Chip::Unknown,
+                }
+            } else if read_u16(i2c, address, 0x00).is_some() {
+                // the INA219 has no id; but it is the most common chip at these addresses.
+                Chip::Ina219
+            } else {
+                Chip::Unknown
+            }
+        }
+        0x76 | 0x77 => {
+            let mut buf = [0_u8; 1];
+            match i2c.write_read(address, &[0xd0], &mut buf) {
+                Ok(_) if buf[0] == 0x60 => Chip::Bme280,
+                Ok(_) if buf[0] == 0x58 => Chip::Bmp280,
+                _ => Chip::Unknown,
+            }
+        }
+        _ => Chip::Unknown,
+    }
+}
+
+/// Config snippet for a chip, if there is a sensor type for it.
+pub(crate) fn snippet(chip: &Chip, bus: &str, address: u8) -> Option<String> {
+    match chip {
+        Chip::Ina219 => Some(format!(
+            "[power_{:02x}]\ntype='power'\nbus='{}'\naddress=0x{:02x}\nexpected_amps=1.0\n",
+            address, bus, address
+        )),
+        _ => None,
+    }
+}
+
+/// Scans the bus and reports the responding addresses, their likely chips and config snippets.
+pub(crate) fn scan<I2C, E>(i2c: &mut I2C, bus: &str) -> String
+where
+    I2C: i2c::Read<Error = E> + i2c::WriteRead<Error = E>,
+{
+    let addresses = probe(i2c);
+    if addresses.is_empty() {
+        return format!("No devices found on {}.\n", bus);
+    }
+    let mut res = String::new();
+    for address in addresses {
+        let chip = identify(i2c, address);
+        res.push_str(&format!("0x{:02x}: {:?}\n", address, chip));
+        if let Some(cfg) = snippet(&chip, bus, address) {
+            res.push_str(&cfg);
+            res.push('\n');
+        }
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    /// The registers of a device and their contents.
+    type Registers = Vec<(u8, Vec<u8>)>;
+
+    /// A bus with devices given by their registers.
+    struct MockBus {
+        devices: HashMap<u8, HashMap<u8, Vec<u8>>>,
+        touched: Vec<u8>,
+    }
+
+    impl MockBus {
+        fn new(devices: Vec<(u8, Registers)>) -> MockBus {
+            MockBus {
+                devices: devices
+                    .into_iter()
+                    .map(|(a, regs)| (a, regs.into_iter().collect()))
+                    .collect(),
+                touched: Vec::new(),
+            }
+        }
+    }
+
+    impl i2c::Read for MockBus {
+        type Error = ();
+
+        fn read(&mut self, address: u8, buffer: &mut [u8]) -> Result<(), ()> {
+            match self.devices.get(&address) {
+                Some(_) => {
+                    buffer.fill(0);
+                    Ok(())
+                }
+                None => Err(()),
+            }
+        }
+    }
+
+    impl i2c::WriteRead for MockBus {
+        type Error = ();
+
+        fn write_read(&mut self, address: u8, bytes: &[u8], buffer: &mut [u8]) -> Result<(), ()> {
+            self.touched.push(address);
+            let value = self
+                .devices
+                .get(&address)
+                .ok_or(())?
+                .get(&bytes[0])
+                .ok_or(())?;
+            buffer.copy_from_slice(&value[..buffer.len()]);
+            Ok(())
+        }
+    }
+
+    fn bus() -> MockBus {
+        MockBus::new(vec![
+            (0x40, vec![(0x00, vec![0x39, 0x9f])]),
+            (
+                0x41,
+                vec![
+                    (0x00, vec![0x41, 0x27]),
+                    (0xfe, vec![0x54, 0x49]),
+                    (0xff, vec![0x22, 0x60]),
+                ],
+            ),
+            (
+                0x42,
+                vec![
+                    (0x00, vec![0x71, 0x27]),
+                    (0xfe, vec![0x54, 0x49]),
+                    (0xff, vec![0x32, 0x20]),
+                ],
+            ),
+            (0x68, vec![(0x00, vec![0x00])]),
+            (0x76, vec![(0xd0, vec![0x60])]),
+            (0x77, vec![(0xd0, vec![0x58])]),
+        ])
+    }
+
+    // Tests for success.
+
+    #[test]
+    fn test_probe_for_success() {
+        assert_eq!(probe(&mut bus()), vec![0x40, 0x41, 0x42, 0x68, 0x76, 0x77]);
+    }
+
+    // Tests for failure.
+
+    #[test]
+    fn test_scan_for_failure() {
+        let mut bus = MockBus::new(Vec::new());
+        assert_eq!(
+            scan(&mut bus, "/dev/i2c-1"),
+            "No devices found on /dev/i2c-1.\n"
+        );
+        // reserved addresses are never probed.
+        let mut bus = MockBus::new(vec![(0x03, Vec::new()), (0x7a, Vec::new())]);
+        assert!(probe(&mut bus).is_empty());
+    }
+
+    #[test]
+    fn test_identify_for_failure() {
+        let mut bus = MockBus::new(vec![(0x44, Vec::new()), (0x76, Vec::new())]);
+        assert_eq!(identify(&mut bus, 0x44), Chip::Unknown);
+        assert_eq!(identify(&mut bus, 0x76), Chip::Unknown);
+    }
+
+    // Tests for sanity.
+
+    #[test]
+    fn test_identify_for_sanity() {
+        let mut bus = bus();
+        assert_eq!(identify(&mut bus, 0x40), Chip::Ina219);
+        assert_eq!(identify(&mut bus, 0x41), Chip::Ina226);
+        assert_eq!(identify(&mut bus, 0x42), Chip::Ina3221);
+        assert_eq!(identify(&mut bus, 0x68), Chip::Unknown);
+        assert_eq!(identify(&mut bus, 0x76), Chip::Bme280);
+        assert_eq!(identify(&mut bus, 0x77), Chip::Bmp280);
+        // unknown addresses only see the probe.
+        assert!(!bus.touched.contains(&0x68));
+    }
+
+    #[test]
+    fn test_scan_for_sanity() {
+        let mut bus = bus();
+        assert_eq!(
+            scan(&mut bus, "/dev/i2c-1"),
+            "0x40: Ina219\n\
+            [power_40]\ntype='power'\nbus='/dev/i2c-1'\naddress=0x40\nexpected_amps=1.0\n\n\
+            0x41: Ina226\n\
+            0x42: Ina3221\n\
+            0x68: Unknown\n\
+            0x76: Bme280\n\
+            0x77: Bmp280\n"
+        );
+    }
+}
@@ -0,0 +1,290 @@
+use std::collections::VecDeque;
+use std::error::Error;
+use std::time;
+
+use crate::output;
+
+/// Where and how to write to an InfluxDB v2 bucket.
+pub(crate) struct Target {
+    pub(crate) url: String,
+    pub(crate) org: String,
+    pub(crate) bucket: String,
+    pub(crate) token: String,
+    pub(crate) measurement: String,
+}
+
+/// Writes each row as line protocol to InfluxDB; one line per sensor, its metrics as fields.
+///
+/// Each line carries the time its values were measured; a sensor reporting values measured at different times gets a
+/// line per time.
+/// Lines that could not be written are kept (up to max_lines, dropping the oldest) and retried with the next row.
+pub(crate) struct InfluxOutput {
+    target: Target,
+    sensors: Vec<String>,
+    max_lines: usize,
+    // (tag, field) of each column.
+    keys: Vec<(String, String)>,
+    pending: VecDeque<String>,
+    client: reqwest::blocking::Client,
+}
+
+impl InfluxOutput {
+    pub(crate) fn new(target: Target, sensors: Vec<String>, max_lines: usize) -> InfluxOutput {
+        let client = reqwest::blocking::ClientBuilder::new()
+            // never let a slow database stall the loop for long.
+            .timeout(time::Duration::from_secs(10))
+            .build()
+            .unwrap();
+        InfluxOutput {
+            target,
+            sensors,
+            max_lines: max_lines.max(1),
+            keys: Vec::new(),
+            pending: VecDeque::new(),
+            client,
+        }
+    }
+
+    /// The lines of a row, given the time each value was measured; failed (NaN) values are left out as line protocol
+    /// knows no NaN.
+    fn lines(&self, values: &[f64], times: &[f64]) -> Vec<String> {
+        // (tag, timestamp) of each line.
+        let mut points: Vec<(&str, i64)> = Vec::new();
+        let mut fields: Vec<Vec<String>> = Vec::new();
+        for (((tag, field), value), time) in self.keys.iter().zip(values).zip(times).skip(1) {
+            if !value.is_finite() {
+                continue;
+            }
+            let point = (tag.as_str(), (time * 1e9).round() as i64);
+            let i = match points.iter().position(|p| *p == point) {
+                Some(i) => i,
+                None => {
+                    points.push(point);
+                    fields.push(Vec::new());
+                    points.len() - 1
+                }
+            };
+            fields[i].push(format!("{}={}", escape(field), value));
+        }
+        points
+            .iter()
+            .zip(fields)
+            .map(|((tag, timestamp), fields)| {
+                format!(
+                    "{},sensor={} {} {}",
+                    escape(&self.target.measurement),
+                    escape(tag),
+                    fields.join(","),
+                    timestamp
+                )
+            })
+            .collect()
+    }
+
+    fn send(&self, body: String) -> Result<(), Box<dyn Error>> {
+        let res = self
+            .client
+            .post(format!("{}/api/v2/write", self.target.url))
+            .query(&[
+                ("org", self.target.org.as_str()),
+                ("bucket", self.target.bucket.as_str()),
+                ("precision", "ns"),
+            ])
+            .header("Authorization", format!("Token {}", self.target.token))
+            .header("Content-Type", "text/plain; charset=utf-8")
+            .body(body)
+            .send()?;
+        let status = res.status().as_u16();
+        if !(200..300).contains(&status) {
+            return Err(Box::from(format!(
+                "Status code was not 2xx; but: {}.",
+                status
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Escapes commas, spaces and equal signs in measurements, tags and field keys.
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace(',', "\\,")
+        .replace(' ', "\\ ")
+        .replace('=', "\\=")
+}
+
+impl output::Output for InfluxOutput {
+    fn write_header(&mut self, names: &[String]) {
+        self.keys = names
+            .iter()
+            .map(|n| output::split(&self.sensors, n))
+            .collect();
+    }
+
+    fn write(&mut self, values: &[f64]) -> Result<(), String> {
+        let times = vec![values.first().copied().unwrap_or_default(); values.len()];
+        self.write_at(values, &times)
+    }
+
+    fn write_at(&mut self, values: &[f64], times: &[f64]) -> Result<(), String> {
+        let lines = self.lines(values, times);
+        self.pending.extend(lines);
+        while self.pending.len() > self.max_lines {
+            self.pending.pop_front();
+        }
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        let body: Vec<&str> = self.pending.iter().map(|l| l.as_str()).collect();
+        match self.send(body.join("\n")) {
+            Ok(()) => {
+                self.pending.clear();
+                Ok(())
+            }
+            Err(err) => Err(format!(
+                "could not write to InfluxDB; retrying {} lines later: {}",
+                self.pending.len(),
+                err
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output::Output;
+
+    fn influx(url: String, max_lines: usize) -> InfluxOutput {
+        let target = Target {
+            url,
+            org: "home".to_string(),
+            bucket: "solar".to_string(),
+            token: "abc".to_string(),
+            measurement: "ogc".to_string(),
+        };
+        let mut output = InfluxOutput::new(
+            target,
+            vec!["fox".to_string(), "fox_2".to_string()],
+            max_lines,
+        );
+        output.write_header(&[
+            "timestamp".to_string(),
+            "fox_pvPower".to_string(),
+            "fox_loadsPower".to_string(),
+            "fox_2_pvPower".to_string(),
+            "bat_cycles".to_string(),
+        ]);
+        output
+    }
+
+    // Tests for success.
+
+    #[test]
+    fn test_write_for_success() {
+        let mut server = mockito::Server::new();
+        let mock = server
+            .mock("POST", "/api/v2/write")
+            .match_query(mockito::Matcher::AllOf(vec![
+                mockito::Matcher::UrlEncoded("org".into(), "home".into()),
+                mockito::Matcher::UrlEncoded("bucket".into(), "solar".into()),
+                mockito::Matcher::UrlEncoded("precision".into(), "ns".into()),
+            ]))
+            .match_header("Authorization", "Token abc")
+            .match_body(
+                "ogc,sensor=fox pvPower=1.5,loadsPower=0.5 1699920000000000000\n\
+                ogc,sensor=fox_2 pvPower=2 1699920000000000000\n\
+                ogc,sensor=bat cycles=12.25 1699920000000000000",
+            )
+            .with_status(204)
+            .create();
+        let mut output = influx(server.url(), 100);
+        output.write(&[1699920000.0, 1.5, 0.5, 2.0, 12.25]).unwrap();
+        mock.assert();
+        assert!(output.pending.is_empty());
+    }
+
+    // Tests for failure.
+
+    #[test]
+    fn test_write_for_failure() {
+        // nowhere to write to; lines are kept, but only so many.
+        let mut output = influx("http://localhost:1".to_string(), 4);
+        assert!(output.write(&[1699920000.0, 1.5, 0.5, 2.0, 12.25]).is_err());
+        assert_eq!(output.pending.len(), 3);
+        assert!(output.write(&[1699920030.0, 1.5, 0.5, 2.0, 12.25]).is_err());
+        assert_eq!(output.pending.len(), 4);
+        assert!(output.pending[0].starts_with("ogc,sensor=bat "));
+    }
+
+    // Tests for sanity.
+
+    #[test]
+    fn test_retry_for_sanity() {
+        let mut server = mockito::Server::new();
+        let down = server
+            .mock("POST", "/api/v2/write")
+            .match_query(mockito::Matcher::Any)
+            .with_status(503)
+            .expect(1)
+            .create();
+        let mut output = influx(server.url(), 100);
+        // failed values are left out.
+        assert!(output
+            .write(&[1699920000.0, 1.5, f64::NAN, f64::NAN, f64::NAN])
+            .is_err());
+        down.assert();
+        down.remove();
+
+        let up = server
+            .mock("POST", "/api/v2/write")
+            .match_query(mockito::Matcher::Any)
+            .match_body(
+                "ogc,sensor=fox pvPower=1.5 1699920000000000000\n\
+                ogc,sensor=fox pvPower=1.6 1699920030000000000",
+            )
+            .with_status(204)
+            .expect(1)
+            .create();
+        output
+            .write(&[1699920030.0, 1.6, f64::NAN, f64::NAN, f64::NAN])
+            .unwrap();
+        up.assert();
+        assert!(output.pending.is_empty());
+    }
+
+    #[test]
+    fn test_times_for_sanity() {
+        let mut server = mockito::Server::new();
+        // the late value of fox gets a line of its own, at the time it was measured.
+        let mock = server
+            .mock("POST", "/api/v2/write")
+            .match_query(mockito::Matcher::Any)
+            .match_body(
+                "ogc,sensor=fox pvPower=1.5 1699919960000000000\n\
+                ogc,sensor=fox loadsPower=0.5 1699920000000000000\n\
+                ogc,sensor=fox_2 pvPower=2 1699920000000000000",
+            )
+            .with_status(204)
+            .create();
+        let mut output = influx(server.url(), 100);
+        output
+            .write_at(
+                &[1699920000.0, 1.5, 0.5, 2.0, f64::NAN],
+                &[
+                    1699920000.0,
+                    1699919960.0,
+                    1699920000.0,
+                    1699920000.0,
+                    1699920000.0,
+                ],
+            )
+            .unwrap();
+        mock.assert();
+    }
+
+    #[test]
+    fn test_escape_for_sanity() {
+        assert_eq!(escape("living room,1=a"), "living\\ room\\,1\\=a");
+    }
+}
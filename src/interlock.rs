@@ -0,0 +1,198 @@
+use crate::common;
+use crate::expr;
+
+/// Column flagging rows with implausible data; actuators fall back to their fail-safe state.
+pub(crate) const SUSPECT_COLUMN: &str = "data_suspect";
+
+/// A named condition between columns that must hold for the data to be plausible.
+pub(crate) struct Invariant {
+    name: String,
+    expr: expr::Expr,
+}
+
+impl Invariant {
+    pub(crate) fn new(name: String, expression: &str) -> Invariant {
+        let expr = expr::parse(expression)
+            .unwrap_or_else(|err| panic!("invalid invariant {}: {}", name, err));
+        Invariant { name, expr }
+    }
+
+    /// Whether the invariant is violated; unknown values never violate an invariant.
+    fn violated(&self, names: &[String], values: &[f64]) -> bool {
+        self.expr.eval(names, values) == 0.0
+    }
+
+    fn describe(&self, names: &[String], values: &[f64]) -> String {
+        let columns: Vec<String> = self
+            .expr
+            .columns()
+            .iter()
+            .map(|c| match names.iter().position(|n| n == c) {
+                Some(i) => format!("{}={}", c, values[i]),
+                None => format!("{}=?", c),
+            })
+            .collect();
+        format!("{} ({})", self.name, columns.join(", "))
+    }
+}
+
+/// Cross-checks the columns of each row against invariants.
+///
+/// A violation marks the row as suspect; this latches until all invariants held again for
+/// clear_secs.
+pub struct InterlockComponent {
+    invariants: Vec<Invariant>,
+    clear_secs: f64,
+    suspect: bool,
+    clean_since: Option<f64>,
+}
+
+impl InterlockComponent {
+    pub(crate) fn new(invariants: Vec<Invariant>, clear_secs: f64) -> InterlockComponent {
+        InterlockComponent {
+            invariants,
+            clear_secs,
+            suspect: false,
+            clean_since: None,
+        }
+    }
+}
+
+impl common::Component for InterlockComponent {
+    fn get_names(&self) -> Vec<String> {
+        vec![SUSPECT_COLUMN.to_string()]
+    }
+
+    fn update(&mut self, names: &[String], values: &[f64]) -> Vec<f64> {
+        let now = values[0];
+        let violated: Vec<&Invariant> = self
+            .invariants
+            .iter()
+            .filter(|i| i.violated(names, values))
+            .collect();
+        if !violated.is_empty() {
+            if !self.suspect {
+                for invariant in violated {
+                    eprintln!(
+                        "Data suspect; invariant violated: {}.",
+                        invariant.describe(names, values)
+                    );
+                }
+            }
+            self.suspect = true;
+            self.clean_since = None;
+        } else if self.suspect {
+            let since = *self.clean_since.get_or_insert(now);
+            if now - since >= self.clear_secs {
+                eprintln!("Data plausible again.");
+                self.suspect = false;
+                self.clean_since = None;
+            }
+        }
+        vec![self.suspect as u8 as f64]
+    }
+
+    fn get_inputs(&self) -> Option<Vec<String>> {
+        Some(
+            self.invariants
+                .iter()
+                .flat_map(|i| i.expr.columns())
+                .map(String::from)
+                .collect(),
+        )
+    }
+}
+
+/// Whether the row was flagged as suspect by an interlock component earlier in the chain.
+pub(crate) fn is_suspect(names: &[String], values: &[f64]) -> bool {
+    match names.iter().position(|n| n == SUSPECT_COLUMN) {
+        Some(i) => values[i] == 1.0,
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::Component;
+
+    fn names() -> Vec<String> {
+        vec!["timestamp", "solar_elevation", "pv_power", "bat_soc"]
+            .into_iter()
+            .map(String::from)
+            .collect()
+    }
+
+    fn component() -> InterlockComponent {
+        InterlockComponent::new(
+            vec![
+                Invariant::new(
+                    "no pv at night".to_string(),
+                    "solar_elevation >= 0 || pv_power < 50",
+                ),
+                Invariant::new("soc".to_string(), "bat_soc >= 0 && bat_soc <= 100"),
+            ],
+            60.0,
+        )
+    }
+
+    // Tests for success.
+
+    #[test]
+    fn test_update_for_success() {
+        let mut comp = component();
+        assert_eq!(comp.get_names(), vec!["data_suspect"]);
+        assert_eq!(comp.update(&names(), &[0.0, 10.0, 3000.0, 50.0]), vec![0.0]);
+    }
+
+    // Tests for failure.
+
+    #[test]
+    #[should_panic]
+    fn test_invariant_for_failure() {
+        Invariant::new("broken".to_string(), "pv_power <");
+    }
+
+    #[test]
+    fn test_update_for_failure() {
+        // missing columns or values never flag a row.
+        let mut comp = component();
+        assert_eq!(comp.update(&names()[..1], &[0.0]), vec![0.0]);
+        assert_eq!(
+            comp.update(&names(), &[30.0, f64::NAN, 3000.0, f64::NAN]),
+            vec![0.0]
+        );
+    }
+
+    // Tests for sanity.
+
+    #[test]
+    fn test_latch_for_sanity() {
+        let mut comp = component();
+        // 3 kW at night.
+        assert_eq!(
+            comp.update(&names(), &[0.0, -10.0, 3000.0, 50.0]),
+            vec![1.0]
+        );
+        // plausible again, but only cleared after 60s.
+        assert_eq!(comp.update(&names(), &[30.0, -10.0, 0.0, 50.0]), vec![1.0]);
+        assert_eq!(comp.update(&names(), &[60.0, -10.0, 0.0, 50.0]), vec![1.0]);
+        // another violation restarts the clear time.
+        assert_eq!(comp.update(&names(), &[70.0, -10.0, 0.0, 101.0]), vec![1.0]);
+        assert_eq!(comp.update(&names(), &[100.0, -10.0, 0.0, 50.0]), vec![1.0]);
+        assert_eq!(comp.update(&names(), &[130.0, -10.0, 0.0, 50.0]), vec![1.0]);
+        assert_eq!(comp.update(&names(), &[160.0, -10.0, 0.0, 50.0]), vec![0.0]);
+        assert_eq!(
+            comp.update(&names(), &[190.0, 10.0, 3000.0, 50.0]),
+            vec![0.0]
+        );
+    }
+
+    #[test]
+    fn test_is_suspect_for_sanity() {
+        let names = vec!["timestamp".to_string(), "data_suspect".to_string()];
+        assert!(is_suspect(&names, &[0.0, 1.0]));
+        assert!(!is_suspect(&names, &[0.0, 0.0]));
+        assert!(!is_suspect(&names[..1], &[0.0]));
+    }
+}
@@ -0,0 +1,339 @@
+use std::process;
+use std::time;
+
+use crate::common;
+use crate::exec;
+use crate::interlock;
+use crate::rules;
+
+/// How long the command or kill stopping a job may take.
+const STOP_TIMEOUT: time::Duration = time::Duration::from_secs(10);
+
+/// How a running job is stopped once its condition no longer holds.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Stop {
+    /// Not at all; it runs to completion.
+    Never,
+    /// By sending SIGTERM, so it can clean up.
+    Term,
+    /// By sending SIGKILL.
+    Kill,
+    /// By running a command, e.g. to stop a service the job started.
+    Command(Vec<String>),
+}
+
+/// Where a job stands.
+enum State {
+    /// Waiting for the condition.
+    Idle,
+    /// The condition holds since the given time.
+    Pending(f64),
+    /// Started; still running.
+    Running(process::Child),
+    /// Asked to stop; still running.
+    Stopping(process::Child),
+    /// Ran to completion while the condition held; waits for it to end before starting again.
+    Done,
+}
+
+/// Starts a command once a condition on a column held for a while, e.g. a backup while the house exports more than
+/// 800 W for 5 minutes.
+pub(crate) struct Job {
+    name: String,
+    column: String,
+    operator: String,
+    threshold: f64,
+    sustain: f64,
+    command: Vec<String>,
+    stop: Stop,
+    state: State,
+}
+
+impl Job {
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn new(
+        name: String,
+        column: String,
+        operator: String,
+        threshold: f64,
+        sustain: f64,
+        command: Vec<String>,
+        stop: Stop,
+    ) -> Job {
+        if !rules::OPERATORS.contains(&operator.as_str()) {
+            panic!("unknown operator in job {}: {}.", name, operator);
+        }
+        if sustain < 0.0 {
+            panic!("sustain_secs of job {} must not be negative.", name);
+        }
+        if command.is_empty() {
+            panic!("command of job {} must not be empty.", name);
+        }
+        Job {
+            name,
+            column,
+            operator,
+            threshold,
+            sustain,
+            command,
+            stop,
+            state: State::Idle,
+        }
+    }
+
+    fn is_running(&self) -> bool {
+        matches!(self.state, State::Running(_) | State::Stopping(_))
+    }
+
+    fn start(&self) -> Result<process::Child, String> {
+        process::Command::new(&self.command[0])
+            .args(&self.command[1..])
+            .stdin(process::Stdio::null())
+            // stdout may well be the CSV.
+            .stdout(process::Stdio::null())
+            .spawn()
+            .map_err(|e| format!("could not start {}: {}", self.command[0], e))
+    }
+
+    fn send_stop(&self, child: &mut process::Child) -> Result<(), String> {
+        match &self.stop {
+            Stop::Never => Ok(()),
+            Stop::Term => exec::run(
+                "kill",
+                &["-TERM".to_string(), child.id().to_string()],
+                STOP_TIMEOUT,
+            )
+            .map(|_| ())
+            .map_err(|e| e.to_string()),
+            Stop::Kill => child.kill().map_err(|e| e.to_string()),
+            Stop::Command(command) => exec::run(&command[0], &command[1..], STOP_TIMEOUT)
+                .map(|_| ())
+                .map_err(|e| e.to_string()),
+        }
+    }
+
+    /// Moves the job along given the value of its column; logs each transition.
+    fn step(&mut self, value: f64, suspect: bool, now: f64) {
+        // unknown or suspect values do not count as holding.
+        let holds = !suspect && rules::compare(&self.operator, value, self.threshold);
+        let finished = match &mut self.state {
+            State::Running(child) | State::Stopping(child) => exited(&self.name, child),
+            _ => false,
+        };
+        if finished {
+            self.state = if holds { State::Done } else { State::Idle };
+        }
+        let state = std::mem::replace(&mut self.state, State::Idle);
+        self.state = match state {
+            State::Idle | State::Pending(_) if !holds => State::Idle,
+            State::Idle => State::Pending(now),
+            State::Pending(since) if now - since < self.sustain => State::Pending(since),
+            State::Pending(since) => match self.start() {
+                Ok(child) => {
+                    eprintln!(
+                        "Job {}: {} held for {}s; started {} (pid {}).",
+                        self.name,
+                        self.column,
+                        now - since,
+                        self.command[0],
+                        child.id()
+                    );
+                    State::Running(child)
+                }
+                Err(err) => {
+                    // tried again with the next value.
+                    eprintln!("Job {}: {}", self.name, err);
+                    State::Pending(since)
+                }
+            },
+            State::Running(mut child) if !holds && self.stop != Stop::Never => {
+                match self.send_stop(&mut child) {
+                    Ok(()) => {
+                        eprintln!(
+                            "Job {}: {} is {}; stopping it.",
+                            self.name, self.column, value
+                        );
+                        State::Stopping(child)
+                    }
+                    Err(err) => {
+                        eprintln!("Job {}: could not stop it: {}", self.name, err);
+                        State::Running(child)
+                    }
+                }
+            }
+            State::Done if !holds => State::Idle,
+            other => other,
+        };
+    }
+}
+
+/// Whether the child exited; logs how.
+fn exited(name: &str, child: &mut process::Child) -> bool {
+    match child.try_wait() {
+        Ok(Some(status)) => {
+            eprintln!("Job {}: finished with {}.", name, status);
+            true
+        }
+        Ok(None) => false,
+        Err(err) => {
+            eprintln!("Job {}: lost track of it: {}", name, err);
+            true
+        }
+    }
+}
+
+/// Runs jobs while their conditions hold; never more than one instance of each.
+///
+/// Each job adds a column <job>_running that is 1 while its command runs.
+pub struct JobsComponent {
+    jobs: Vec<Job>,
+}
+
+impl JobsComponent {
+    pub(crate) fn new(jobs: Vec<Job>) -> JobsComponent {
+        JobsComponent { jobs }
+    }
+}
+
+impl common::Component for JobsComponent {
+    fn get_names(&self) -> Vec<String> {
+        self.jobs
+            .iter()
+            .map(|j| format!("{}_running", j.name))
+            .collect()
+    }
+
+    fn update(&mut self, names: &[String], values: &[f64]) -> Vec<f64> {
+        let now = values[0];
+        let value_of = |column: &str| {
+            names
+                .iter()
+                .position(|n| n == column)
+                .map(|i| values[i])
+                .unwrap_or(f64::NAN)
+        };
+        let suspect = value_of(interlock::SUSPECT_COLUMN) == 1.0;
+        for job in self.jobs.iter_mut() {
+            let value = value_of(&job.column);
+            job.step(value, suspect, now);
+        }
+        self.jobs
+            .iter()
+            .map(|j| if j.is_running() { 1.0 } else { 0.0 })
+            .collect()
+    }
+
+    fn get_inputs(&self) -> Option<Vec<String>> {
+        let mut res: Vec<String> = self.jobs.iter().map(|j| j.column.clone()).collect();
+        res.push(interlock::SUSPECT_COLUMN.to_string());
+        Some(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+    use crate::common::Component;
+
+    fn names() -> Vec<String> {
+        vec!["timestamp".to_string(), "grid_feedin".to_string()]
+    }
+
+    fn transcode(command: &[&str], stop: Stop) -> JobsComponent {
+        JobsComponent::new(vec![Job::new(
+            "transcode".to_string(),
+            "grid_feedin".to_string(),
+            ">".to_string(),
+            800.0,
+            300.0,
+            command.iter().map(|c| c.to_string()).collect(),
+            stop,
+        )])
+    }
+
+    /// Updates until the job is reported as no longer running.
+    fn wait_for_exit(jobs: &mut JobsComponent, now: f64, value: f64) -> Vec<f64> {
+        for _ in 0..250 {
+            let res = jobs.update(&names(), &[now, value]);
+            if res[0] == 0.0 {
+                return res;
+            }
+            thread::sleep(time::Duration::from_millis(20));
+        }
+        panic!("job did not exit.");
+    }
+
+    // Tests for success.
+
+    #[test]
+    fn test_update_for_success() {
+        let mut jobs = transcode(&["/bin/sleep", "10"], Stop::Kill);
+        assert_eq!(jobs.get_names(), vec!["transcode_running"]);
+        assert_eq!(jobs.update(&names(), &[1000.0, 1000.0]), vec![0.0]);
+        // not long enough yet.
+        assert_eq!(jobs.update(&names(), &[1299.0, 1000.0]), vec![0.0]);
+        assert_eq!(jobs.update(&names(), &[1300.0, 1000.0]), vec![1.0]);
+        // no second instance.
+        assert_eq!(jobs.update(&names(), &[1330.0, 1000.0]), vec![1.0]);
+        assert_eq!(jobs.jobs.len(), 1);
+        // a cloud; stopped.
+        assert_eq!(jobs.update(&names(), &[1360.0, 200.0]), vec![1.0]);
+        assert_eq!(wait_for_exit(&mut jobs, 1390.0, 200.0), vec![0.0]);
+    }
+
+    // Tests for failure.
+
+    #[test]
+    #[should_panic(expected = "command of job transcode must not be empty.")]
+    fn test_new_for_failure() {
+        transcode(&[], Stop::Never);
+    }
+
+    #[test]
+    fn test_update_for_failure() {
+        // interrupted before the duration was sustained: starts counting over.
+        let mut jobs = transcode(&["/bin/sleep", "10"], Stop::Kill);
+        jobs.update(&names(), &[1000.0, 1000.0]);
+        jobs.update(&names(), &[1200.0, f64::NAN]);
+        assert_eq!(jobs.update(&names(), &[1300.0, 1000.0]), vec![0.0]);
+        assert_eq!(jobs.update(&names(), &[1599.0, 1000.0]), vec![0.0]);
+        assert_eq!(jobs.update(&names(), &[1600.0, 1000.0]), vec![1.0]);
+        jobs.update(&names(), &[1630.0, 0.0]);
+        wait_for_exit(&mut jobs, 1660.0, 0.0);
+
+        // a command that cannot be started.
+        let mut jobs = transcode(&["/nonexistent/transcode"], Stop::Never);
+        jobs.update(&names(), &[1000.0, 1000.0]);
+        assert_eq!(jobs.update(&names(), &[1300.0, 1000.0]), vec![0.0]);
+    }
+
+    // Tests for sanity.
+
+    #[test]
+    fn test_done_for_sanity() {
+        let mut jobs = transcode(&["/bin/true"], Stop::Never);
+        jobs.update(&names(), &[1000.0, 1000.0]);
+        assert_eq!(jobs.update(&names(), &[1300.0, 1000.0]), vec![1.0]);
+        wait_for_exit(&mut jobs, 1330.0, 1000.0);
+        // ran once; not again while the condition holds.
+        assert_eq!(jobs.update(&names(), &[1700.0, 1000.0]), vec![0.0]);
+        assert_eq!(jobs.update(&names(), &[2000.0, 1000.0]), vec![0.0]);
+        // but once it held anew.
+        jobs.update(&names(), &[2030.0, 0.0]);
+        jobs.update(&names(), &[2060.0, 1000.0]);
+        assert_eq!(jobs.update(&names(), &[2360.0, 1000.0]), vec![1.0]);
+        wait_for_exit(&mut jobs, 2390.0, 1000.0);
+    }
+
+    #[test]
+    fn test_never_stop_for_sanity() {
+        let mut jobs = transcode(&["/bin/sleep", "1"], Stop::Never);
+        jobs.update(&names(), &[1000.0, 1000.0]);
+        jobs.update(&names(), &[1300.0, 1000.0]);
+        // left running to completion.
+        assert_eq!(jobs.update(&names(), &[1330.0, 0.0]), vec![1.0]);
+        assert_eq!(wait_for_exit(&mut jobs, 1360.0, 0.0), vec![0.0]);
+    }
+}
@@ -0,0 +1,337 @@
+use std::collections::HashSet;
+use std::error::Error;
+use std::io;
+use std::os::unix::net::UnixDatagram;
+
+use crate::alerts;
+use crate::common;
+use crate::output;
+
+/// Where journald listens for native protocol datagrams.
+pub(crate) const SOCKET: &str = "/run/systemd/journal/socket";
+
+/// Identifier for all entries; allows for `journalctl -t ogc`.
+const IDENTIFIER: &str = "ogc";
+
+/// Turns a column name into a valid journal field name.
+pub(crate) fn field_name(column: &str) -> String {
+    let name: String = column
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_uppercase()
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    // fields must not start with an underscore or a digit.
+    match name.chars().next() {
+        Some(c) if c.is_ascii_alphabetic() => name,
+        _ => format!("F{}", name),
+    }
+}
+
+/// Encodes fields using journald's native protocol; values with newlines use the binary format.
+pub(crate) fn encode(fields: &[(&str, String)]) -> Vec<u8> {
+    let mut res = Vec::new();
+    for (name, value) in fields {
+        res.extend_from_slice(name.as_bytes());
+        if value.contains('\n') {
+            res.push(b'\n');
+            res.extend_from_slice(&(value.len() as u64).to_le_bytes());
+        } else {
+            res.push(b'=');
+        }
+        res.extend_from_slice(value.as_bytes());
+        res.push(b'\n');
+    }
+    res
+}
+
+/// Writes entries to the journal.
+pub(crate) struct Journal {
+    path: String,
+    socket: UnixDatagram,
+}
+
+impl Journal {
+    pub(crate) fn new(path: String) -> Journal {
+        Journal {
+            path,
+            socket: UnixDatagram::unbound().expect("could not create a socket."),
+        }
+    }
+
+    /// Sends an entry with the given priority (syslog levels) and additional fields.
+    pub(crate) fn send(
+        &self,
+        priority: u8,
+        message: &str,
+        fields: &[(&str, String)],
+    ) -> io::Result<()> {
+        let mut all = vec![
+            ("MESSAGE", message.to_string()),
+            ("PRIORITY", priority.to_string()),
+            ("SYSLOG_IDENTIFIER", IDENTIFIER.to_string()),
+        ];
+        all.extend(fields.iter().map(|(n, v)| (*n, v.clone())));
+        self.socket.send_to(&encode(&all), &self.path).map(|_| ())
+    }
+}
+
+/// Sends alerts to the journal.
+pub struct JournalNotifier {
+    journal: Journal,
+}
+
+impl JournalNotifier {
+    pub(crate) fn new(journal: Journal) -> JournalNotifier {
+        JournalNotifier { journal }
+    }
+}
+
+impl alerts::Notifier for JournalNotifier {
+    fn notify(&self, title: &str, message: &str) -> Result<(), Box<dyn Error>> {
+        self.journal
+            .send(4, message, &[("ALERT", title.to_string())])
+            .map_err(Box::from)
+    }
+}
+
+/// Logs failed readings of sensors to the journal.
+pub struct JournalComponent {
+    journal: Journal,
+    sensors: Vec<String>,
+    failing: HashSet<String>,
+}
+
+impl JournalComponent {
+    pub(crate) fn new(journal: Journal, sensors: Vec<String>) -> JournalComponent {
+        JournalComponent {
+            journal,
+            sensors,
+            failing: HashSet::new(),
+        }
+    }
+}
+
+impl common::Component for JournalComponent {
+    fn get_names(&self) -> Vec<String> {
+        Vec::new()
+    }
+
+    fn update(&mut self, names: &[String], values: &[f64]) -> Vec<f64> {
+        for (name, value) in names.iter().zip(values) {
+            // e.g. the columns of components are no concern of ours.
+            let (sensor, metric) = output::split(&self.sensors, name);
+            if !self.sensors.contains(&sensor) {
+                continue;
+            }
+            if !value.is_nan() {
+                self.failing.remove(name);
+                continue;
+            }
+            // only log when a metric starts failing.
+            if !self.failing.insert(name.to_string()) {
+                continue;
+            }
+            let res = self.journal.send(
+                3,
+                &format!("Could not measure {} of {}.", metric, sensor),
+                &[
+                    ("SENSOR", sensor.to_string()),
+                    ("METRIC", metric.to_string()),
+                    ("VALUE", value.to_string()),
+                    ("ERROR_KIND", "missing".to_string()),
+                ],
+            );
+            if let Err(err) = res {
+                eprintln!("Could not write to the journal: {}", err);
+            }
+        }
+        Vec::new()
+    }
+}
+
+/// Writes one entry per row to the journal, with a field per column.
+pub(crate) struct JournalOutput {
+    journal: Journal,
+    fields: Vec<String>,
+}
+
+impl JournalOutput {
+    pub(crate) fn new(journal: Journal) -> JournalOutput {
+        JournalOutput {
+            journal,
+            fields: Vec::new(),
+        }
+    }
+}
+
+impl output::Output for JournalOutput {
+    fn write_header(&mut self, names: &[String]) {
+        self.fields = names.iter().map(|n| field_name(n)).collect();
+    }
+
+    fn write(&mut self, values: &[f64]) -> Result<(), String> {
+        let fields: Vec<(&str, String)> = self
+            .fields
+            .iter()
+            .zip(values)
+            .map(|(n, v)| (n.as_str(), v.to_string()))
+            .collect();
+        self.journal
+            .send(6, "measurement", &fields)
+            .map_err(|e| format!("could not write to the journal: {}", e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path;
+    use std::time;
+
+    use super::*;
+    use crate::alerts::Notifier;
+    use crate::common::Component;
+    use crate::privacy;
+
+    fn listen(path: &str) -> UnixDatagram {
+        let _ = fs::remove_file(path);
+        let socket = UnixDatagram::bind(path).unwrap();
+        socket
+            .set_read_timeout(Some(time::Duration::from_secs(1)))
+            .unwrap();
+        socket
+    }
+
+    fn receive(socket: &UnixDatagram) -> Vec<u8> {
+        let mut buf = [0; 4096];
+        let n = socket.recv(&mut buf).unwrap();
+        buf[..n].to_vec()
+    }
+
+    // Tests for success.
+
+    #[test]
+    fn test_send_for_success() {
+        let socket = listen("journal_test0.sock");
+        let journal = Journal::new("journal_test0.sock".to_string());
+        journal.send(6, "hello", &[]).unwrap();
+        assert_eq!(
+            receive(&socket),
+            b"MESSAGE=hello\nPRIORITY=6\nSYSLOG_IDENTIFIER=ogc\n".to_vec()
+        );
+        fs::remove_file("journal_test0.sock").unwrap();
+    }
+
+    #[test]
+    fn test_journald_for_success() {
+        // only runs on machines with a journal.
+        if !path::Path::new(SOCKET).exists() {
+            return;
+        }
+        let journal = Journal::new(SOCKET.to_string());
+        journal
+            .send(
+                7,
+                "open green compute test.",
+                &[("SENSOR", "test".to_string())],
+            )
+            .unwrap();
+    }
+
+    // Tests for failure.
+
+    #[test]
+    fn test_send_for_failure() {
+        let journal = Journal::new("journal_missing.sock".to_string());
+        assert!(journal.send(6, "hello", &[]).is_err());
+        let notifier = JournalNotifier::new(journal);
+        assert!(notifier.notify("foo", "bar").is_err());
+    }
+
+    // Tests for sanity.
+
+    #[test]
+    fn test_encode_for_sanity() {
+        assert_eq!(
+            encode(&[
+                ("MESSAGE", "hello".to_string()),
+                ("SENSOR", "fritz".to_string())
+            ]),
+            b"MESSAGE=hello\nSENSOR=fritz\n".to_vec()
+        );
+        // binary safe format for values with newlines.
+        assert_eq!(
+            encode(&[("MESSAGE", "a\nb".to_string())]),
+            vec![
+                0x4d, 0x45, 0x53, 0x53, 0x41, 0x47, 0x45, 0x0a, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00,
+                0x00, 0x00, 0x61, 0x0a, 0x62, 0x0a
+            ]
+        );
+        assert_eq!(encode(&[("EMPTY", String::new())]), b"EMPTY=\n".to_vec());
+    }
+
+    #[test]
+    fn test_field_name_for_sanity() {
+        assert_eq!(field_name("fritz_power"), "FRITZ_POWER");
+        assert_eq!(field_name("owa-temp.1"), "OWA_TEMP_1");
+        assert_eq!(field_name("_private"), "F_PRIVATE");
+        assert_eq!(field_name("1st"), "F1ST");
+    }
+
+    #[test]
+    fn test_update_for_sanity() {
+        let socket = listen("journal_test1.sock");
+        let mut comp = JournalComponent::new(
+            Journal::new("journal_test1.sock".to_string()),
+            vec!["fritz".to_string()],
+        );
+        let names = vec!["timestamp".to_string(), "fritz_power".to_string()];
+
+        comp.update(&names, &[1.0, f64::NAN]);
+        assert_eq!(
+            receive(&socket),
+            b"MESSAGE=Could not measure power of fritz.\nPRIORITY=3\nSYSLOG_IDENTIFIER=ogc\n\
+            SENSOR=fritz\nMETRIC=power\nVALUE=NaN\nERROR_KIND=missing\n"
+                .to_vec()
+        );
+
+        // still failing - nothing new.
+        comp.update(&names, &[2.0, f64::NAN]);
+        // recovered and failing again; a negative reading is no failure.
+        comp.update(&names, &[3.0, -1.0]);
+        comp.update(&names, &[4.0, f64::NAN]);
+        assert!(receive(&socket).ends_with(b"VALUE=NaN\nERROR_KIND=missing\n"));
+        fs::remove_file("journal_test1.sock").unwrap();
+    }
+
+    #[test]
+    fn test_output_for_sanity() {
+        let socket = listen("journal_test2.sock");
+        let names = vec![
+            "timestamp".to_string(),
+            "pv_power".to_string(),
+            "fritz_power".to_string(),
+        ];
+        let output = JournalOutput::new(Journal::new("journal_test2.sock".to_string()));
+        let mut dispatcher = output::Dispatcher::new(
+            &names,
+            None,
+            &[false, false, true],
+            vec![(Box::new(output), privacy::Visibility::Public)],
+        );
+
+        // the private column never reaches the journal.
+        dispatcher.dispatch(&[1.0, 100.0, f64::NAN]);
+        assert_eq!(
+            receive(&socket),
+            b"MESSAGE=measurement\nPRIORITY=6\nSYSLOG_IDENTIFIER=ogc\nTIMESTAMP=1\nPV_POWER=100\n"
+                .to_vec()
+        );
+        fs::remove_file("journal_test2.sock").unwrap();
+    }
+}
@@ -0,0 +1,343 @@
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time;
+
+use crate::common;
+use crate::common::{ConfigError, Reading, SensorError};
+use crate::shelly;
+
+/// The port Kasa plugs listen on.
+const PORT: u16 = 9999;
+
+/// The first key of the autokey cipher.
+const KEY: u8 = 171;
+
+/// The largest answer accepted; an emeter answer is a few hundred bytes.
+const MAX_FRAME: usize = 64 * 1024;
+
+/// The request for the current readings of the energy meter.
+const GET_REALTIME: &str = "{\"emeter\":{\"get_realtime\":{}}}";
+
+/// The metrics reported, their units, and their fields in unit and in milli-unit firmware.
+const METRICS: [(&str, &str, &str, &str); 4] = [
+    ("power", "W", "power", "power_mw"),
+    ("voltage", "V", "voltage", "voltage_mv"),
+    ("current", "A", "current", "current_ma"),
+    ("energy", "Wh", "total", "total_wh"),
+];
+
+/// Encrypts with the autokey XOR cipher: each byte is the key for the next.
+pub(crate) fn encrypt(plain: &[u8]) -> Vec<u8> {
+    let mut key = KEY;
+    plain
+        .iter()
+        .map(|b| {
+            key ^= b;
+            key
+        })
+        .collect()
+}
+
+/// Reverses encrypt: each cipher byte is the key for the next.
+pub(crate) fn decrypt(cipher: &[u8]) -> Vec<u8> {
+    let mut key = KEY;
+    cipher
+        .iter()
+        .map(|c| {
+            let b = key ^ c;
+            key = *c;
+            b
+        })
+        .collect()
+}
+
+/// The power, voltage, current and total energy given the answer to get_realtime; each if present.
+///
+/// Older firmware (e.g. the HS110 v1) reports power, voltage, current and total in W, V, A and kWh; newer firmware
+/// power_mw, voltage_mv, current_ma and total_wh.
+pub(crate) fn parse_realtime(body: &[u8]) -> Result<[Option<f64>; 4], SensorError> {
+    let body: serde_json::Value =
+        serde_json::from_slice(body).map_err(|e| SensorError::Parse(e.to_string()))?;
+    let realtime = body
+        .get("emeter")
+        .and_then(|e| e.get("get_realtime"))
+        .ok_or_else(|| {
+            SensorError::Protocol("no emeter in the answer; not an HS110 or KP115?".to_string())
+        })?;
+    match realtime["err_code"].as_i64() {
+        Some(0) | None => {}
+        Some(code) => {
+            return Err(SensorError::Protocol(format!(
+                "get_realtime failed with: {} ({}).",
+                code,
+                realtime["err_msg"].as_str().unwrap_or("no message")
+            )))
+        }
+    }
+    let mut res = [None; 4];
+    for (value, (_, _, unit, milli)) in res.iter_mut().zip(METRICS) {
+        *value = match (realtime[unit].as_f64(), realtime[milli].as_f64()) {
+            // kWh.
+            (Some(total), _) if unit == "total" => Some(total * 1000.0),
+            (Some(value), _) => Some(value),
+            (None, Some(value)) if milli == "total_wh" => Some(value),
+            (None, Some(value)) => Some(value / 1000.0),
+            (None, None) => None,
+        };
+    }
+    Ok(res)
+}
+
+/// Reads a TP-Link Kasa plug with energy meter, e.g. an HS110 or KP115, via its legacy protocol on TCP port 9999.
+pub struct KasaSensor {
+    name: String,
+    address: String,
+    timeout: time::Duration,
+}
+
+impl KasaSensor {
+    pub(crate) fn new(name: String, address: String, timeout: time::Duration) -> KasaSensor {
+        KasaSensor {
+            name,
+            address,
+            timeout,
+        }
+    }
+
+    fn io_error(&self, err: io::Error) -> SensorError {
+        match err.kind() {
+            io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => SensorError::Timeout(format!(
+                "no answer from {} within {:?}.",
+                self.address, self.timeout
+            )),
+            _ => SensorError::Io(format!("{}: {}", self.address, err)),
+        }
+    }
+
+    /// Sends a request as one length-prefixed frame and returns the decrypted answer.
+    fn send(&self, request: &str) -> Result<Vec<u8>, SensorError> {
+        let address = self
+            .address
+            .to_socket_addrs()
+            .map_err(|e| SensorError::Io(format!("could not resolve {}: {}", self.address, e)))?
+            .next()
+            .ok_or_else(|| SensorError::Io(format!("could not resolve {}.", self.address)))?;
+        let mut stream =
+            TcpStream::connect_timeout(&address, self.timeout).map_err(|e| self.io_error(e))?;
+        stream
+            .set_read_timeout(Some(self.timeout))
+            .and_then(|_| stream.set_write_timeout(Some(self.timeout)))
+            .map_err(|e| self.io_error(e))?;
+        let mut frame = (request.len() as u32).to_be_bytes().to_vec();
+        frame.extend(encrypt(request.as_bytes()));
+        stream.write_all(&frame).map_err(|e| self.io_error(e))?;
+        let mut length = [0; 4];
+        stream
+            .read_exact(&mut length)
+            .map_err(|e| self.io_error(e))?;
+        let length = u32::from_be_bytes(length) as usize;
+        if length > MAX_FRAME {
+            return Err(SensorError::Parse(format!(
+                "frame length must be at most {}; got: {}.",
+                MAX_FRAME, length
+            )));
+        }
+        let mut answer = vec![0; length];
+        stream
+            .read_exact(&mut answer)
+            .map_err(|e| self.io_error(e))?;
+        Ok(decrypt(&answer))
+    }
+}
+
+impl common::Sensor for KasaSensor {
+    fn get_names(&self) -> Vec<String> {
+        METRICS
+            .iter()
+            .map(|(metric, _, _, _)| format!("{}_{}", self.name, metric))
+            .collect()
+    }
+
+    fn get_units(&self) -> Vec<String> {
+        METRICS
+            .iter()
+            .map(|(_, unit, _, _)| unit.to_string())
+            .collect()
+    }
+
+    fn measure(&self) -> Result<Vec<Reading>, SensorError> {
+        let values = parse_realtime(&self.send(GET_REALTIME)?)?;
+        Ok(self
+            .get_names()
+            .into_iter()
+            .zip(values)
+            .filter_map(|(name, value)| value.map(|v| Reading::new(name, v)))
+            .collect())
+    }
+}
+
+/// TP-Link Kasa plugs with energy meter.
+pub(crate) fn sensor_type() -> common::SensorType {
+    common::SensorType {
+        name: "kasa",
+        required: &["host"],
+        optional: &["timeout_secs"],
+        create: |name, sensor_cfg, _| {
+            let host = sensor_cfg["host"]
+                .as_str()
+                .ok_or_else(|| ConfigError::Invalid("host must be a string.".to_string()))?;
+            let address = if host.contains(':') {
+                host.to_string()
+            } else {
+                format!("{}:{}", host, PORT)
+            };
+            Ok(Box::new(KasaSensor::new(
+                name.to_string(),
+                address,
+                shelly::get_timeout(sensor_cfg)?,
+            )))
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+    use std::thread;
+
+    use super::*;
+    use crate::common::values;
+    use crate::common::Sensor;
+
+    /// As answered by an HS110 v1.
+    const HS110: &str = "{\"emeter\":{\"get_realtime\":{\"current\":0.457,\"voltage\":231.2,\
+    \"power\":98.6,\"total\":12.25,\"err_code\":0}}}";
+
+    /// As answered by a KP115.
+    const KP115: &str = "{\"emeter\":{\"get_realtime\":{\"voltage_mv\":230845,\"current_ma\":61,\
+    \"power_mw\":6012,\"total_wh\":1423,\"err_code\":0}}}";
+
+    /// Serves a single connection, answering the request with the given frame and handing the request on.
+    fn plug(answer: Vec<u8>) -> (String, thread::JoinHandle<Vec<u8>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let handle = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut length = [0; 4];
+            stream.read_exact(&mut length).unwrap();
+            let mut request = vec![0; u32::from_be_bytes(length) as usize];
+            stream.read_exact(&mut request).unwrap();
+            stream.write_all(&answer).unwrap();
+            decrypt(&request)
+        });
+        (address, handle)
+    }
+
+    fn frame(body: &str) -> Vec<u8> {
+        let mut res = (body.len() as u32).to_be_bytes().to_vec();
+        res.extend(encrypt(body.as_bytes()));
+        res
+    }
+
+    // Tests for success.
+
+    #[test]
+    fn test_cipher_for_success() {
+        // the well-known start of every system info request.
+        assert_eq!(
+            encrypt(b"{\"system\""),
+            vec![0xd0, 0xf2, 0x81, 0xf8, 0x8b, 0xff, 0x9a, 0xf7, 0xd5]
+        );
+        assert_eq!(
+            decrypt(&encrypt(GET_REALTIME.as_bytes())),
+            GET_REALTIME.as_bytes()
+        );
+    }
+
+    #[test]
+    fn test_parse_realtime_for_success() {
+        assert_eq!(
+            parse_realtime(HS110.as_bytes()).unwrap(),
+            [Some(98.6), Some(231.2), Some(0.457), Some(12250.0)]
+        );
+        assert_eq!(
+            parse_realtime(KP115.as_bytes()).unwrap(),
+            [Some(6.012), Some(230.845), Some(0.061), Some(1423.0)]
+        );
+    }
+
+    #[test]
+    fn test_measure_for_success() {
+        let (address, handle) = plug(frame(KP115));
+        let sensor = KasaSensor::new("kettle".to_string(), address, time::Duration::from_secs(1));
+        assert_eq!(sensor.get_units(), vec!["W", "V", "A", "Wh"]);
+        assert_eq!(
+            values(sensor.measure()),
+            vec![
+                ("kettle_power".to_string(), 6.012),
+                ("kettle_voltage".to_string(), 230.845),
+                ("kettle_current".to_string(), 0.061),
+                ("kettle_energy".to_string(), 1423.0)
+            ]
+        );
+        assert_eq!(handle.join().unwrap(), GET_REALTIME.as_bytes());
+    }
+
+    // Tests for failure.
+
+    #[test]
+    fn test_parse_realtime_for_failure() {
+        // an HS100 has no energy meter.
+        let res =
+            parse_realtime(b"{\"emeter\":{\"err_code\":-1,\"err_msg\":\"module not support\"}}");
+        assert!(matches!(res, Err(SensorError::Protocol(_))));
+        let res = parse_realtime(
+            b"{\"emeter\":{\"get_realtime\":{\"err_code\":-3,\"err_msg\":\"invalid argument\"}}}",
+        );
+        match res {
+            Err(SensorError::Protocol(msg)) => {
+                assert_eq!(msg, "get_realtime failed with: -3 (invalid argument).")
+            }
+            _ => panic!("error code not reported."),
+        }
+        assert!(matches!(
+            parse_realtime(&encrypt(KP115.as_bytes())),
+            Err(SensorError::Parse(_))
+        ));
+    }
+
+    #[test]
+    fn test_measure_for_failure() {
+        // a frame claiming more than is sent.
+        let mut answer = frame(KP115);
+        answer.truncate(20);
+        let (address, handle) = plug(answer);
+        let sensor = KasaSensor::new("kettle".to_string(), address, time::Duration::from_secs(1));
+        assert!(sensor.measure().is_err());
+        handle.join().unwrap();
+        // a frame of garbage.
+        let (address, handle) = plug(vec![0xff; 8]);
+        let sensor = KasaSensor::new("kettle".to_string(), address, time::Duration::from_secs(1));
+        assert!(matches!(sensor.measure(), Err(SensorError::Parse(_))));
+        handle.join().unwrap();
+        // nothing listening.
+        let sensor = KasaSensor::new(
+            "kettle".to_string(),
+            "127.0.0.1:1".to_string(),
+            time::Duration::from_secs(1),
+        );
+        assert!(sensor.measure().is_err());
+    }
+
+    // Tests for sanity.
+
+    #[test]
+    fn test_sensor_type_for_sanity() {
+        let sensor_cfg: toml::value::Table = toml::from_str("host='192.168.178.42'").unwrap();
+        let sensor = (sensor_type().create)("kettle", &sensor_cfg, "state").unwrap();
+        assert_eq!(sensor.get_names()[0], "kettle_power");
+        // the fields of older firmware win; some report both.
+        let both = "{\"emeter\":{\"get_realtime\":{\"power\":98.6,\"power_mw\":98600}}}";
+        assert_eq!(parse_realtime(both.as_bytes()).unwrap()[0], Some(98.6));
+    }
+}
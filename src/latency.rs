@@ -0,0 +1,365 @@
+use std::fmt::Write;
+
+use crate::tz;
+
+/// Upper bounds (in seconds) of the latency buckets; a last bucket catches everything above.
+pub(crate) const BUCKETS: [f64; 10] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0];
+
+/// A latency histogram with fixed buckets; counts are floats so they can be decayed.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct Histogram {
+    counts: [f64; BUCKETS.len() + 1],
+    sum: f64,
+    count: f64,
+    failures: f64,
+}
+
+impl Histogram {
+    pub(crate) fn observe(&mut self, secs: f64, ok: bool) {
+        let i = BUCKETS
+            .iter()
+            .position(|b| secs <= *b)
+            .unwrap_or(BUCKETS.len());
+        self.counts[i] += 1.0;
+        self.sum += secs;
+        self.count += 1.0;
+        if !ok {
+            self.failures += 1.0;
+        }
+    }
+
+    pub(crate) fn merge(&mut self, other: &Histogram) {
+        for (a, b) in self.counts.iter_mut().zip(other.counts.iter()) {
+            *a += b;
+        }
+        self.sum += other.sum;
+        self.count += other.count;
+        self.failures += other.failures;
+    }
+
+    /// Scales all counts by the given factor (0..1).
+    pub(crate) fn decay(&mut self, factor: f64) {
+        for count in self.counts.iter_mut() {
+            *count *= factor;
+        }
+        self.sum *= factor;
+        self.count *= factor;
+        self.failures *= factor;
+    }
+
+    /// Estimates a quantile by linear interpolation within its bucket; capped at the last bound.
+    pub(crate) fn quantile(&self, q: f64) -> f64 {
+        if self.count <= 0.0 {
+            return f64::NAN;
+        }
+        let target = q * self.count;
+        let mut seen = 0.0;
+        for (i, count) in self.counts.iter().enumerate() {
+            if *count > 0.0 && seen + count >= target {
+                if i == BUCKETS.len() {
+                    break;
+                }
+                let lower = if i == 0 { 0.0 } else { BUCKETS[i - 1] };
+                return lower + (BUCKETS[i] - lower) * (target - seen) / count;
+            }
+            seen += count;
+        }
+        BUCKETS[BUCKETS.len() - 1]
+    }
+
+    pub(crate) fn error_rate(&self) -> f64 {
+        if self.count <= 0.0 {
+            return f64::NAN;
+        }
+        self.failures / self.count
+    }
+}
+
+/// Tracks latencies & failures of a sensor: in total, per day and over a rolling window.
+pub(crate) struct Tracker {
+    name: String,
+    zone: tz::Zone,
+    window: f64,
+    window_start: f64,
+    current: Histogram,
+    previous: Histogram,
+    total: Histogram,
+    day: i64,
+    daily: Histogram,
+    last_success: Option<f64>,
+}
+
+impl Tracker {
+    pub(crate) fn new(name: String, window: f64, zone: tz::Zone) -> Tracker {
+        Tracker {
+            name,
+            zone,
+            window,
+            window_start: f64::NAN,
+            current: Histogram::default(),
+            previous: Histogram::default(),
+            total: Histogram::default(),
+            day: -1,
+            daily: Histogram::default(),
+            last_success: None,
+        }
+    }
+
+    /// Records a measurement; returns a summary of the previous day when a new one starts.
+    pub(crate) fn record(&mut self, now: f64, secs: f64, ok: bool) -> Option<String> {
+        if self.window_start.is_nan() {
+            self.window_start = now;
+        }
+        let elapsed = ((now - self.window_start) / self.window).floor();
+        if elapsed >= 1.0 {
+            self.previous = if elapsed < 2.0 {
+                self.current.clone()
+            } else {
+                Histogram::default()
+            };
+            self.current = Histogram::default();
+            self.window_start += elapsed * self.window;
+        }
+
+        let mut res = None;
+        let day = self.zone.day(now);
+        if day != self.day {
+            if self.day >= 0 {
+                res = Some(self.summary(&self.daily));
+            }
+            self.day = day;
+            self.daily = Histogram::default();
+        }
+
+        if ok {
+            self.last_success = Some(now);
+        }
+        self.current.observe(secs, ok);
+        self.total.observe(secs, ok);
+        self.daily.observe(secs, ok);
+        res
+    }
+
+    /// Histogram over the rolling window; the previous window is weighted by its remaining overlap.
+    pub(crate) fn window(&self, now: f64) -> Histogram {
+        let mut res = self.previous.clone();
+        let overlap = 1.0 - ((now - self.window_start) / self.window).clamp(0.0, 1.0);
+        res.decay(overlap);
+        res.merge(&self.current);
+        res
+    }
+
+    fn summary(&self, hist: &Histogram) -> String {
+        format!(
+            "{}: p50={:.3}s p95={:.3}s p99={:.3}s error rate={:.1}% ({} calls)",
+            self.name,
+            hist.quantile(0.5),
+            hist.quantile(0.95),
+            hist.quantile(0.99),
+            hist.error_rate() * 100.0,
+            hist.count
+        )
+    }
+
+    pub(crate) fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Summary over the rolling window.
+    pub(crate) fn status(&self, now: f64) -> String {
+        self.summary(&self.window(now))
+    }
+}
+
+/// Renders the total histograms and failure counters in the Prometheus text format.
+pub(crate) fn render(trackers: &[Tracker]) -> String {
+    let mut res = String::new();
+    res.push_str("# HELP ogc_sensor_latency_seconds Time taken to measure a sensor.\n");
+    res.push_str("# TYPE ogc_sensor_latency_seconds histogram\n");
+    for tracker in trackers {
+        let hist = &tracker.total;
+        let mut cumulative = 0.0;
+        for (i, count) in hist.counts.iter().enumerate() {
+            cumulative += count;
+            let le = match BUCKETS.get(i) {
+                Some(bound) => bound.to_string(),
+                None => "+Inf".to_string(),
+            };
+            let _ = writeln!(
+                res,
+                "ogc_sensor_latency_seconds_bucket{{sensor=\"{}\",le=\"{}\"}} {}",
+                tracker.name, le, cumulative
+            );
+        }
+        let _ = writeln!(
+            res,
+            "ogc_sensor_latency_seconds_sum{{sensor=\"{}\"}} {}",
+            tracker.name, hist.sum
+        );
+        let _ = writeln!(
+            res,
+            "ogc_sensor_latency_seconds_count{{sensor=\"{}\"}} {}",
+            tracker.name, hist.count
+        );
+    }
+    res.push_str("# HELP ogc_sensor_failures_total Measurements of a sensor that failed.\n");
+    res.push_str("# TYPE ogc_sensor_failures_total counter\n");
+    for tracker in trackers {
+        let _ = writeln!(
+            res,
+            "ogc_sensor_failures_total{{sensor=\"{}\"}} {}",
+            tracker.name, tracker.total.failures
+        );
+    }
+    res.push_str("# HELP ogc_sensor_last_success_timestamp_seconds When a sensor was last measured successfully.\n");
+    res.push_str("# TYPE ogc_sensor_last_success_timestamp_seconds gauge\n");
+    for tracker in trackers {
+        if let Some(last_success) = tracker.last_success {
+            let _ = writeln!(
+                res,
+                "ogc_sensor_last_success_timestamp_seconds{{sensor=\"{}\"}} {}",
+                tracker.name, last_success
+            );
+        }
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn histogram(latencies: &[f64]) -> Histogram {
+        let mut hist = Histogram::default();
+        for secs in latencies {
+            hist.observe(*secs, true);
+        }
+        hist
+    }
+
+    // Tests for success.
+
+    #[test]
+    fn test_observe_for_success() {
+        let hist = histogram(&[0.001, 0.3, 10.0]);
+        assert_eq!(hist.count, 3.0);
+        assert_eq!(hist.counts[0], 1.0);
+        assert_eq!(hist.counts[6], 1.0);
+        assert_eq!(hist.counts[10], 1.0);
+    }
+
+    // Tests for failure.
+
+    #[test]
+    fn test_quantile_for_failure() {
+        let hist = Histogram::default();
+        assert!(hist.quantile(0.5).is_nan());
+        assert!(hist.error_rate().is_nan());
+        // everything beyond the last bucket.
+        assert_eq!(histogram(&[10.0, 20.0]).quantile(0.99), 5.0);
+    }
+
+    // Tests for sanity.
+
+    #[test]
+    fn test_quantile_for_sanity() {
+        // 0.1 < x <= 0.25 for all.
+        let hist = histogram(&[0.2, 0.2, 0.2, 0.2]);
+        assert!((hist.quantile(0.5) - 0.175).abs() < 1e-9);
+        assert_eq!(hist.quantile(1.0), 0.25);
+
+        let mut values = vec![0.004; 90];
+        values.extend(vec![0.7; 10]);
+        let hist = histogram(&values);
+        assert!(hist.quantile(0.5) <= 0.005);
+        assert!(hist.quantile(0.95) > 0.5 && hist.quantile(0.95) <= 1.0);
+    }
+
+    #[test]
+    fn test_merge_and_decay_for_sanity() {
+        let mut a = histogram(&[0.001, 0.3]);
+        let mut b = histogram(&[0.001]);
+        b.observe(3.0, false);
+        a.merge(&b);
+        assert_eq!(a.count, 4.0);
+        assert_eq!(a.counts[0], 2.0);
+        assert_eq!(a.error_rate(), 0.25);
+
+        a.decay(0.5);
+        assert_eq!(a.count, 2.0);
+        assert_eq!(a.counts[0], 1.0);
+        assert!((a.sum - (0.001 + 0.3 + 0.001 + 3.0) / 2.0).abs() < 1e-9);
+        // rates are not affected by decay.
+        assert_eq!(a.error_rate(), 0.25);
+    }
+
+    #[test]
+    fn test_window_for_sanity() {
+        let mut tracker = Tracker::new("foxess".to_string(), 3600.0, tz::Zone::Utc);
+        for i in 0..60 {
+            tracker.record(i as f64 * 60.0, 0.1, i % 2 == 0);
+        }
+        assert_eq!(tracker.window(3599.0).count, 60.0);
+        assert_eq!(tracker.window(3599.0).error_rate(), 0.5);
+
+        // half way into the next window, half of the previous one still counts.
+        for i in 0..30 {
+            tracker.record(3600.0 + i as f64 * 60.0, 2.0, true);
+        }
+        let window = tracker.window(5400.0);
+        assert_eq!(window.count, 30.0 + 30.0);
+        assert_eq!(window.error_rate(), 0.25);
+
+        // long gaps forget everything.
+        tracker.record(20000.0, 0.1, true);
+        assert_eq!(tracker.window(20000.0).count, 1.0);
+        assert_eq!(tracker.total.count, 91.0);
+    }
+
+    #[test]
+    fn test_summary_for_sanity() {
+        let mut tracker = Tracker::new("fritz".to_string(), 3600.0, tz::Zone::Utc);
+        assert_eq!(tracker.record(86000.0, 0.7, true), None);
+        assert_eq!(tracker.record(86100.0, 0.7, false), None);
+        assert_eq!(
+            tracker.record(86400.0, 0.7, true),
+            Some("fritz: p50=0.750s p95=0.975s p99=0.995s error rate=50.0% (2 calls)".to_string())
+        );
+        assert_eq!(
+            tracker.status(86400.0),
+            "fritz: p50=0.750s p95=0.975s p99=0.995s error rate=33.3% (3 calls)"
+        );
+    }
+
+    #[test]
+    fn test_render_for_sanity() {
+        let mut tracker = Tracker::new("fritz".to_string(), 3600.0, tz::Zone::Utc);
+        tracker.record(0.0, 0.00390625, true);
+        tracker.record(30.0, 0.25, true);
+        tracker.record(60.0, 7.5, false);
+        assert_eq!(
+            render(&[tracker]),
+            "# HELP ogc_sensor_latency_seconds Time taken to measure a sensor.\n\
+            # TYPE ogc_sensor_latency_seconds histogram\n\
+            ogc_sensor_latency_seconds_bucket{sensor=\"fritz\",le=\"0.005\"} 1\n\
+            ogc_sensor_latency_seconds_bucket{sensor=\"fritz\",le=\"0.01\"} 1\n\
+            ogc_sensor_latency_seconds_bucket{sensor=\"fritz\",le=\"0.025\"} 1\n\
+            ogc_sensor_latency_seconds_bucket{sensor=\"fritz\",le=\"0.05\"} 1\n\
+            ogc_sensor_latency_seconds_bucket{sensor=\"fritz\",le=\"0.1\"} 1\n\
+            ogc_sensor_latency_seconds_bucket{sensor=\"fritz\",le=\"0.25\"} 2\n\
+            ogc_sensor_latency_seconds_bucket{sensor=\"fritz\",le=\"0.5\"} 2\n\
+            ogc_sensor_latency_seconds_bucket{sensor=\"fritz\",le=\"1\"} 2\n\
+            ogc_sensor_latency_seconds_bucket{sensor=\"fritz\",le=\"2.5\"} 2\n\
+            ogc_sensor_latency_seconds_bucket{sensor=\"fritz\",le=\"5\"} 2\n\
+            ogc_sensor_latency_seconds_bucket{sensor=\"fritz\",le=\"+Inf\"} 3\n\
+            ogc_sensor_latency_seconds_sum{sensor=\"fritz\"} 7.75390625\n\
+            ogc_sensor_latency_seconds_count{sensor=\"fritz\"} 3\n\
+            # HELP ogc_sensor_failures_total Measurements of a sensor that failed.\n\
+            # TYPE ogc_sensor_failures_total counter\n\
+            ogc_sensor_failures_total{sensor=\"fritz\"} 1\n\
+            # HELP ogc_sensor_last_success_timestamp_seconds When a sensor was last measured successfully.\n\
+            # TYPE ogc_sensor_last_success_timestamp_seconds gauge\n\
+            ogc_sensor_last_success_timestamp_seconds{sensor=\"fritz\"} 30\n"
+        );
+    }
+}
@@ -0,0 +1,476 @@
+use std::cell::RefCell;
+use std::error::Error;
+use std::fs;
+use std::io;
+use std::path;
+use std::thread;
+use std::time;
+
+use serde::{Deserialize, Serialize};
+
+use crate::clock;
+use crate::common;
+use crate::common::{Reading, SensorError};
+use crate::state;
+
+/// How often to try to get hold of the lock of a file store.
+const LOCK_ATTEMPTS: u32 = 50;
+/// Locks older than this were left behind by a crashed instance.
+const STALE_LOCK: time::Duration = time::Duration::from_secs(10);
+
+/// Lease on an expensive sensor; the term is the fencing token and grows with every new leader.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub(crate) struct Lease {
+    pub(crate) holder: String,
+    pub(crate) term: u64,
+    /// Epoch secs; by the clock of the holder.
+    pub(crate) expires: f64,
+}
+
+/// Values measured by the leader, for the followers to consume.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub(crate) struct Published {
+    pub(crate) term: u64,
+    pub(crate) time: f64,
+    pub(crate) values: Vec<f64>,
+}
+
+/// State shared by all instances; implementations must update it atomically across instances.
+pub(crate) trait LeaseStore {
+    /// Replaces the lease with what f makes of the current one.
+    fn update(&self, f: &mut dyn FnMut(Option<Lease>) -> Lease) -> Result<Lease, Box<dyn Error>>;
+    /// Publishes values unless they were measured under an outdated term; false if fenced off.
+    fn publish(&self, values: &Published) -> Result<bool, Box<dyn Error>>;
+    fn consume(&self) -> Option<Published>;
+}
+
+/// Keeps lease and values as files in a directory on shared storage, guarded by a lock file.
+pub(crate) struct FileStore {
+    dir: String,
+    name: String,
+}
+
+/// Removes the lock file once dropped.
+struct Lock {
+    path: path::PathBuf,
+}
+
+impl Drop for Lock {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+impl FileStore {
+    pub(crate) fn new(dir: String, name: String) -> FileStore {
+        FileStore { dir, name }
+    }
+
+    fn lock(&self) -> Result<Lock, Box<dyn Error>> {
+        fs::create_dir_all(&self.dir)?;
+        let path = path::Path::new(&self.dir).join(format!("lease_{}.lock", self.name));
+        for _ in 0..LOCK_ATTEMPTS {
+            match fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&path)
+            {
+                Ok(_) => return Ok(Lock { path }),
+                Err(err) if err.kind() == io::ErrorKind::AlreadyExists => {
+                    let age = fs::metadata(&path)
+                        .and_then(|m| m.modified())
+                        .map(|t| t.elapsed().unwrap_or_default());
+                    if age.map(|age| age > STALE_LOCK).unwrap_or(false) {
+                        let _ = fs::remove_file(&path);
+                        continue;
+                    }
+                    thread::sleep(time::Duration::from_millis(10));
+                }
+                Err(err) => return Err(Box::new(err)),
+            }
+        }
+        Err(Box::from(format!("could not lock {}.", path.display())))
+    }
+}
+
+impl LeaseStore for FileStore {
+    fn update(&self, f: &mut dyn FnMut(Option<Lease>) -> Lease) -> Result<Lease, Box<dyn Error>> {
+        let _lock = self.lock()?;
+        let lease = f(state::load(&self.dir, &format!("lease_{}", self.name)));
+        state::save(&self.dir, &format!("lease_{}", self.name), &lease)?;
+        Ok(lease)
+    }
+
+    fn publish(&self, values: &Published) -> Result<bool, Box<dyn Error>> {
+        let _lock = self.lock()?;
+        let lease: Option<Lease> = state::load(&self.dir, &format!("lease_{}", self.name));
+        if lease.map(|l| l.term) != Some(values.term) {
+            return Ok(false);
+        }
+        state::save(&self.dir, &format!("values_{}", self.name), values)?;
+        Ok(true)
+    }
+
+    fn consume(&self) -> Option<Published> {
+        state::load(&self.dir, &format!("values_{}", self.name))
+    }
+}
+
+/// Elects a leader among the instances sharing a store.
+///
+/// Clocks of the instances may be off by up to max_skew: others only take over a lease max_skew
+/// after it expired, while the leader stops leading max_skew before it expires - even if its
+/// renewals got lost on the way.
+pub(crate) struct Elector {
+    name: String,
+    id: String,
+    ttl: f64,
+    max_skew: f64,
+    lease: Option<Lease>,
+    leading: bool,
+}
+
+impl Elector {
+    pub(crate) fn new(name: String, id: String, ttl: f64, max_skew: f64) -> Elector {
+        if ttl <= 2.0 * max_skew {
+            panic!("a lease must last longer than twice the maximum clock skew.");
+        }
+        Elector {
+            name,
+            id,
+            ttl,
+            max_skew,
+            lease: None,
+            leading: false,
+        }
+    }
+
+    /// Acquires, renews or just observes the lease; the term if this instance leads.
+    pub(crate) fn tick(&mut self, store: &dyn LeaseStore, now: f64) -> Option<u64> {
+        let (id, ttl, max_skew) = (&self.id, self.ttl, self.max_skew);
+        let res = store.update(&mut |lease| match lease {
+            Some(lease) if &lease.holder == id => Lease {
+                expires: now + ttl,
+                ..lease
+            },
+            Some(lease) if now <= lease.expires + max_skew => lease,
+            lease => Lease {
+                holder: id.clone(),
+                term: lease.map(|l| l.term).unwrap_or(0) + 1,
+                expires: now + ttl,
+            },
+        });
+        match res {
+            Ok(lease) => self.lease = Some(lease),
+            // keep leading on the last renewal while it lasts.
+            Err(err) => eprintln!("Could not renew the lease on {}: {}", self.name, err),
+        }
+        let term = self.leading(now);
+        if term.is_some() != self.leading {
+            self.leading = term.is_some();
+            match term {
+                Some(term) => eprintln!("Leading {} (term {}).", self.name, term),
+                None => eprintln!("Following on {}.", self.name),
+            }
+        }
+        term
+    }
+
+    /// The term if this instance leads at the given time; without asking the store.
+    pub(crate) fn leading(&self, now: f64) -> Option<u64> {
+        self.lease
+            .as_ref()
+            .filter(|l| l.holder == self.id && now < l.expires - self.max_skew)
+            .map(|l| l.term)
+    }
+}
+
+/// Only measures while leading; otherwise consumes what the leader published.
+pub struct CoordinatedSensor {
+    inner: Box<dyn common::Sensor>,
+    store: Box<dyn LeaseStore>,
+    elector: RefCell<Elector>,
+}
+
+impl CoordinatedSensor {
+    pub(crate) fn new(
+        inner: Box<dyn common::Sensor>,
+        store: Box<dyn LeaseStore>,
+        elector: Elector,
+    ) -> CoordinatedSensor {
+        CoordinatedSensor {
+            inner,
+            store,
+            elector: RefCell::new(elector),
+        }
+    }
+
+    /// As follower, values that are missing or too old are left out; only the leader's own measurements can fail.
+    fn measure_at(&self, now: f64) -> Result<Vec<Reading>, SensorError> {
+        let names = self.inner.get_names();
+        let mut elector = self.elector.borrow_mut();
+        match elector.tick(self.store.as_ref(), now) {
+            Some(term) => {
+                let readings = self.inner.measure()?;
+                // published in the order of the names; followers have the same ones.
+                let published = Published {
+                    term,
+                    time: now,
+                    values: common::align(&names, &readings).0,
+                };
+                match self.store.publish(&published) {
+                    Ok(true) => {}
+                    Ok(false) => eprintln!("Lease on {} moved on; not publishing.", elector.name),
+                    Err(err) => eprintln!("Could not publish {}: {}", elector.name, err),
+                }
+                Ok(readings)
+            }
+            None => match self.store.consume() {
+                Some(p) if p.values.len() == names.len() && now - p.time <= elector.ttl => {
+                    Ok(names
+                        .into_iter()
+                        .zip(p.values)
+                        .map(|(name, value)| Reading {
+                            name,
+                            value,
+                            time: Some(p.time),
+                            // measured by the leader.
+                            quality: common::CACHED,
+                        })
+                        .collect())
+                }
+                _ => Ok(Vec::new()),
+            },
+        }
+    }
+}
+
+impl common::Sensor for CoordinatedSensor {
+    fn get_names(&self) -> Vec<String> {
+        self.inner.get_names()
+    }
+
+    fn get_units(&self) -> Vec<String> {
+        self.inner.get_units()
+    }
+
+    fn init(&mut self) -> Result<(), SensorError> {
+        self.inner.init()
+    }
+
+    fn shutdown(&mut self) {
+        self.inner.shutdown()
+    }
+
+    fn measure(&self) -> Result<Vec<Reading>, SensorError> {
+        self.measure_at(clock::epoch_secs(time::SystemTime::now()))
+    }
+
+    fn validate(&self) -> Result<(), Box<dyn Error>> {
+        self.inner.validate()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::dummy;
+
+    /// A store in memory; connections to it can go down to drop renewals.
+    #[derive(Clone, Default)]
+    struct MemoryStore {
+        lease: Rc<RefCell<Option<Lease>>>,
+        published: Rc<RefCell<Option<Published>>>,
+        down: Rc<Cell<bool>>,
+    }
+
+    impl MemoryStore {
+        fn connect(&self) -> MemoryStore {
+            MemoryStore {
+                down: Rc::new(Cell::new(false)),
+                ..self.clone()
+            }
+        }
+    }
+
+    impl LeaseStore for MemoryStore {
+        fn update(
+            &self,
+            f: &mut dyn FnMut(Option<Lease>) -> Lease,
+        ) -> Result<Lease, Box<dyn Error>> {
+            if self.down.get() {
+                return Err(Box::from("store unreachable."));
+            }
+            let lease = f(self.lease.borrow().clone());
+            *self.lease.borrow_mut() = Some(lease.clone());
+            Ok(lease)
+        }
+
+        fn publish(&self, values: &Published) -> Result<bool, Box<dyn Error>> {
+            if self.lease.borrow().as_ref().map(|l| l.term) != Some(values.term) {
+                return Ok(false);
+            }
+            *self.published.borrow_mut() = Some(values.clone());
+            Ok(true)
+        }
+
+        fn consume(&self) -> Option<Published> {
+            self.published.borrow().clone()
+        }
+    }
+
+    fn elector(id: &str) -> Elector {
+        Elector::new("fox".to_string(), id.to_string(), 30.0, 5.0)
+    }
+
+    fn fox() -> Box<dyn common::Sensor> {
+        Box::new(dummy::DummySensor::new(
+            "fox".to_string(),
+            vec![("pvPower".to_string(), 1.5)],
+        ))
+    }
+
+    // Tests for success.
+
+    #[test]
+    fn test_tick_for_success() {
+        let store = MemoryStore::default();
+        let mut a = elector("a");
+        assert_eq!(a.tick(&store, 0.0), Some(1));
+        // renewals keep the term.
+        assert_eq!(a.tick(&store, 10.0), Some(1));
+        assert_eq!(store.lease.borrow().as_ref().unwrap().expires, 40.0);
+        // others follow.
+        let mut b = elector("b");
+        assert_eq!(b.tick(&store, 12.0), None);
+    }
+
+    // Tests for failure.
+
+    #[test]
+    #[should_panic]
+    fn test_new_for_failure() {
+        Elector::new("fox".to_string(), "a".to_string(), 10.0, 5.0);
+    }
+
+    #[test]
+    fn test_dropped_renewals_for_failure() {
+        let store = MemoryStore::default();
+        let mut a = elector("a");
+        assert_eq!(a.tick(&store, 0.0), Some(1));
+        // with the store unreachable the leader stops leading before the lease expires...
+        store.down.set(true);
+        assert_eq!(a.tick(&store, 20.0), Some(1));
+        assert_eq!(a.tick(&store, 25.0), None);
+        // ... and resumes once a renewal gets through, as nobody else took over.
+        store.down.set(false);
+        assert_eq!(a.tick(&store, 40.0), Some(1));
+    }
+
+    // Tests for sanity.
+
+    #[test]
+    fn test_failover_for_sanity() {
+        let store = MemoryStore::default();
+        let (a_store, b_store) = (store.connect(), store.connect());
+        let mut a = elector("a");
+        let mut b = elector("b");
+        assert_eq!(a.tick(&a_store, 0.0), Some(1));
+        a_store.down.set(true);
+
+        // b only takes over max_skew after the lease expired.
+        assert_eq!(b.tick(&b_store, 35.0), None);
+        assert_eq!(b.tick(&b_store, 36.0), Some(2));
+
+        // a learns about it with its next renewal; its values are fenced off meanwhile.
+        let stale = Published {
+            term: 1,
+            time: 36.0,
+            values: vec![1.0],
+        };
+        assert!(!a_store.publish(&stale).unwrap());
+        a_store.down.set(false);
+        assert_eq!(a.tick(&a_store, 40.0), None);
+        assert_eq!(b.tick(&b_store, 45.0), Some(2));
+    }
+
+    #[test]
+    fn test_clock_skew_for_sanity() {
+        // b's clock is 3 secs ahead; the leader's renewals stop getting through after 50 secs.
+        let store = MemoryStore::default();
+        let (a_store, b_store) = (store.connect(), store.connect());
+        let mut a = elector("a");
+        let mut b = elector("b");
+        let mut leaders = Vec::new();
+        for t in 0..200 {
+            let t = t as f64;
+            a_store.down.set(t > 50.0);
+            if t % 10.0 == 0.0 {
+                a.tick(&a_store, t);
+            }
+            if t % 10.0 == 5.0 {
+                b.tick(&b_store, t + 3.0);
+            }
+            let (a_leads, b_leads) = (a.leading(t), b.leading(t + 3.0));
+            // never two leaders at the same (real) time.
+            assert!(a_leads.is_none() || b_leads.is_none(), "at {}", t);
+            leaders.push((a_leads, b_leads));
+        }
+        assert_eq!(leaders[50], (Some(1), None));
+        assert_eq!(leaders[80], (None, None));
+        assert_eq!(leaders[85], (None, Some(2)));
+        assert_eq!(leaders[199], (None, Some(2)));
+    }
+
+    #[test]
+    fn test_coordinated_sensor_for_sanity() {
+        let store = MemoryStore::default();
+        let leader = CoordinatedSensor::new(fox(), Box::new(store.connect()), elector("a"));
+        let follower = CoordinatedSensor::new(fox(), Box::new(store.connect()), elector("b"));
+
+        // a leads, but did not publish anything yet.
+        *store.lease.borrow_mut() = Some(Lease {
+            holder: "a".to_string(),
+            term: 1,
+            expires: 30.0,
+        });
+        assert!(follower.measure_at(0.0).unwrap().is_empty());
+        // the leader measures, the follower consumes...
+        let measured = leader.measure_at(1.0).unwrap();
+        assert_eq!((measured[0].value, measured[0].time), (1.5, None));
+        let consumed = follower.measure_at(2.0).unwrap();
+        assert_eq!(consumed[0].name, measured[0].name);
+        assert_eq!((consumed[0].value, consumed[0].time), (1.5, Some(1.0)));
+        // ... as long as the values are fresh.
+        assert!(follower.measure_at(32.0).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_file_store_for_sanity() {
+        let a = FileStore::new("lease_test0".to_string(), "fox".to_string());
+        let b = FileStore::new("lease_test0".to_string(), "fox".to_string());
+        let (mut a_elector, mut b_elector) = (elector("a"), elector("b"));
+        assert_eq!(a_elector.tick(&a, 0.0), Some(1));
+        assert_eq!(b_elector.tick(&b, 1.0), None);
+
+        let values = Published {
+            term: 1,
+            time: 1.0,
+            values: vec![1.5],
+        };
+        assert!(a.publish(&values).unwrap());
+        assert_eq!(b.consume(), Some(values));
+        assert!(!b
+            .publish(&Published {
+                term: 0,
+                time: 2.0,
+                values: vec![0.0],
+            })
+            .unwrap());
+        assert!(!path::Path::new("lease_test0/lease_fox.lock").exists());
+        fs::remove_dir_all("lease_test0").unwrap();
+    }
+}
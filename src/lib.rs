@@ -0,0 +1,4164 @@
+// the README's examples are configs and shell commands; not Rust.
+#![cfg_attr(not(doctest), doc = include_str!("../README.md"))]
+#![warn(missing_docs)]
+
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::io::IsTerminal;
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::time;
+
+mod aggregate;
+mod alerts;
+mod amdgpu;
+mod battery_stats;
+mod ble;
+mod calibration;
+/// Sources of time; simulated ones allow running the loop without waiting.
+pub mod clock;
+mod co2;
+/// Sensors and their types, for embedders to add their own.
+pub mod common;
+mod compress;
+/// Loading of the configuration.
+pub mod config;
+mod cost;
+mod csv_out;
+mod debug;
+mod degraded;
+mod derived;
+/// Sensors grouped into devices that are switched on and off as a unit.
+pub mod device;
+mod dummy;
+mod esphome;
+mod evse;
+mod exec;
+mod expr;
+mod forecast;
+mod foxess;
+mod fritz;
+mod ha_import;
+mod health;
+mod hold;
+mod http;
+mod http_json;
+mod hue;
+mod hwmon;
+mod i2c_scan;
+mod influx;
+mod interlock;
+mod jobs;
+mod journal;
+mod kasa;
+mod keys;
+mod latency;
+mod lease;
+mod migrate;
+mod modbus;
+mod modbus_rtu;
+mod modbus_tcp;
+#[cfg(feature = "mqtt")]
+mod mqtt;
+mod mqtt_in;
+mod netdev;
+mod output;
+#[cfg(feature = "parquet")]
+mod parquet_out;
+#[cfg(feature = "postgres")]
+mod postgres_out;
+mod power;
+mod power_supply;
+mod privacy;
+mod prometheus;
+mod pv_stats;
+mod pvoutput;
+mod rapl;
+mod rate;
+mod redis;
+#[cfg(feature = "remote_write")]
+mod remote_write;
+mod replay;
+mod retention;
+mod rpi;
+mod rules;
+mod s3;
+#[cfg(feature = "scripting")]
+mod script;
+mod self_energy;
+mod shelly;
+mod smart;
+/// Lock-free access to the latest values of a running loop.
+pub mod snapshot;
+mod split_out;
+#[cfg(feature = "sqlite")]
+mod sqlite_out;
+mod state;
+mod statsd;
+mod sunspec;
+mod system;
+mod tail;
+mod tapo;
+mod tasmota;
+mod tz;
+mod units;
+mod weather;
+mod webhook;
+mod websocket;
+mod zigbee2mqtt;
+
+/// struct to hold the fast & slow loop and the components working on their results.
+struct Loops {
+    fast_loop: Vec<Box<dyn common::Sensor>>,
+    slow_loop: Vec<Box<dyn common::Sensor>>,
+    components: Vec<Box<dyn common::Component>>,
+    // names of the fast & slow loop sensors; in that order.
+    sensor_names: Vec<String>,
+}
+
+/// Instantiates the rist sensor type based on the config; measured with a timeout and retried if it sets timeout_secs
+/// or retries.
+fn create_sensor(
+    registry: &common::Registry,
+    name: &str,
+    sensor_cfg: &toml::value::Table,
+    state_dir: &str,
+) -> Option<Box<dyn common::Sensor>> {
+    let sensor = registry
+        .create(name, sensor_cfg, state_dir)
+        .unwrap_or_else(|err| panic!("{}", err))?;
+    // each attempt gets its own timeout; exec and http_json sensors enforce it themselves.
+    let timeout = get_timeout(sensor_cfg).filter(|_| {
+        !matches!(
+            sensor_cfg.get("type").and_then(|v| v.as_str()),
+            Some("exec") | Some("http_json")
+        )
+    });
+    let sensor: Box<dyn common::Sensor> = match timeout {
+        Some(timeout) => Box::new(common::TimeoutSensor::new(sensor, timeout)),
+        None => sensor,
+    };
+    match get_retries(sensor_cfg) {
+        Some((retries, delay)) => Some(Box::new(common::RetrySensor::new(sensor, retries, delay))),
+        None => Some(sensor),
+    }
+}
+
+/// How often a sensor is measured again after a transient failure, and how long to wait in between; None for never.
+fn get_retries(sensor_cfg: &toml::value::Table) -> Option<(u32, time::Duration)> {
+    let retries = sensor_cfg
+        .get("retries")
+        .map(|v| v.as_integer().expect("retries must be an integer."))
+        .unwrap_or(0);
+    if retries < 0 {
+        panic!("retries must not be negative; got: {}.", retries);
+    }
+    let delay = sensor_cfg
+        .get("retry_delay_ms")
+        .map(|v| v.as_integer().expect("retry_delay_ms must be an integer."))
+        .unwrap_or(500);
+    if delay < 0 {
+        panic!("retry_delay_ms must not be negative; got: {}.", delay);
+    }
+    if retries == 0 {
+        return None;
+    }
+    Some((retries as u32, time::Duration::from_millis(delay as u64)))
+}
+
+/// How long a sensor may take to measure; None for as long as it takes.
+fn get_timeout(sensor_cfg: &toml::value::Table) -> Option<time::Duration> {
+    let secs = sensor_cfg
+        .get("timeout_secs")?
+        .as_float()
+        .or_else(|| sensor_cfg["timeout_secs"].as_integer().map(|i| i as f64))
+        .expect("timeout_secs must be a number.");
+    if secs <= 0.0 {
+        panic!("timeout_secs must be positive; got: {}.", secs);
+    }
+    Some(time::Duration::from_secs_f64(secs))
+}
+
+/// Fields any sensor may set; handled around the sensor itself.
+const SENSOR_FIELDS: [&str; 12] = [
+    "type",
+    "timeout_secs",
+    "retries",
+    "retry_delay_ms",
+    "on_error",
+    "hold_max_secs",
+    "calibration",
+    "units",
+    "derive",
+    "transform",
+    "max_operations",
+    "device",
+];
+
+/// The types of sensors known to create_sensor.
+fn sensor_registry() -> common::Registry {
+    let mut registry = common::Registry::default();
+    registry.register(weather::sensor_type());
+    registry.register(power::sensor_type());
+    registry.register(fritz::sensor_type());
+    registry.register(foxess::sensor_type());
+    registry.register(ble::sensor_type());
+    registry.register(evse::sensor_type());
+    registry.register(evse::ocpp_sensor_type());
+    registry.register(dummy::sensor_type());
+    registry.register(exec::sensor_type());
+    registry.register(http_json::sensor_type());
+    registry.register(replay::sensor_type());
+    registry.register(rapl::sensor_type());
+    registry.register(system::sensor_type());
+    registry.register(hwmon::sensor_type());
+    registry.register(rpi::sensor_type());
+    registry.register(amdgpu::sensor_type());
+    registry.register(power_supply::sensor_type());
+    registry.register(smart::sensor_type());
+    registry.register(netdev::sensor_type());
+    registry.register(shelly::sensor_type());
+    registry.register(shelly::rpc_sensor_type());
+    registry.register(shelly::em_sensor_type());
+    registry.register(tasmota::sensor_type());
+    registry.register(tapo::sensor_type());
+    registry.register(kasa::sensor_type());
+    registry.register(hue::sensor_type());
+    registry.register(zigbee2mqtt::sensor_type());
+    registry.register(mqtt_in::sensor_type());
+    registry.register(esphome::sensor_type());
+    registry.register(modbus_rtu::sensor_type());
+    registry.register(modbus_tcp::sensor_type());
+    registry.register(sunspec::sensor_type());
+    registry
+}
+
+/// The journal a journald component writes to; the system's unless a socket is set.
+fn get_journal(component_cfg: &toml::value::Table) -> journal::Journal {
+    journal::Journal::new(
+        component_cfg
+            .get("socket")
+            .and_then(|v| v.as_str())
+            .unwrap_or(journal::SOCKET)
+            .to_string(),
+    )
+}
+
+/// Instantiates the right component type based on the config.
+fn create_component(
+    name: &str,
+    component_cfg: &toml::value::Table,
+    state_dir: &str,
+    sensor_names: &[String],
+    zone: tz::Zone,
+) -> Option<Box<dyn common::Component>> {
+    match component_cfg["type"]
+        .as_str()
+        .expect("missing type information for a component.")
+    {
+        "alerts" => {
+            if !component_cfg.contains_key("notifiers") || !component_cfg.contains_key("rules") {
+                panic!("an alerts component requires the following fields to be set: notifiers, and rules.");
+            }
+            let notifier_cfgs = component_cfg["notifiers"]
+                .as_table()
+                .expect("notifiers must be a table.");
+            let notifier_names: Vec<&String> = notifier_cfgs.keys().collect();
+            let notifiers: Vec<Box<dyn alerts::Notifier>> = notifier_cfgs
+                .values()
+                .map(alerts::create_notifier)
+                .collect();
+            let get_notifier = |name: &str| {
+                notifier_names
+                    .iter()
+                    .position(|n| *n == name)
+                    .unwrap_or_else(|| panic!("unknown notifier: {}.", name))
+            };
+            let rules: Vec<alerts::Rule> = component_cfg["rules"]
+                .as_array()
+                .expect("rules must be an array.")
+                .iter()
+                .map(|r| {
+                    let get = |key: &str| {
+                        r.get(key)
+                            .and_then(|v| v.as_str())
+                            .unwrap_or_else(|| panic!("an alert rule requires the field {}.", key))
+                    };
+                    let threshold = &r["threshold"];
+                    alerts::Rule::new(
+                        get("name").to_string(),
+                        get("column").to_string(),
+                        get("operator").to_string(),
+                        threshold
+                            .as_float()
+                            .or_else(|| threshold.as_integer().map(|i| i as f64))
+                            .expect("threshold must be a number."),
+                        r.get("message")
+                            .and_then(|v| v.as_str())
+                            .unwrap_or("{rule}: {column} is {value}")
+                            .to_string(),
+                        get_notifier(get("notifier")),
+                        r.get("min_interval_secs")
+                            .and_then(|v| v.as_integer())
+                            .unwrap_or(0) as f64,
+                    )
+                })
+                .collect();
+            let summary = component_cfg
+                .get("daily_summary")
+                .and_then(|v| v.as_str())
+                .map(get_notifier);
+            let active_hours = component_cfg
+                .get("active_hours")
+                .and_then(|v| v.as_str())
+                .map(tz::ActiveHours::parse);
+            let tmp = alerts::AlertsComponent::new(notifiers, rules, summary, zone, active_hours);
+            Some(Box::new(tmp))
+        }
+        "battery_stats" => {
+            if !component_cfg.contains_key("power_column")
+                || !component_cfg.contains_key("soc_column")
+                || !component_cfg.contains_key("capacity_wh")
+            {
+                panic!("a battery stats component requires the following fields to be set: power_column, soc_column, and capacity_wh.");
+            }
+            let tmp = battery_stats::BatteryStatsComponent::new(
+                name.to_string(),
+                component_cfg["power_column"]
+                    .as_str()
+                    .unwrap_or("battery_power")
+                    .to_string(),
+                component_cfg["soc_column"]
+                    .as_str()
+                    .unwrap_or("battery_soc")
+                    .to_string(),
+                get_capacity(component_cfg),
+                component_cfg
+                    .get("discharge_positive")
+                    .and_then(|v| v.as_bool())
+                    .unwrap_or(true),
+                component_cfg
+                    .get("max_gap_secs")
+                    .and_then(|v| v.as_integer())
+                    .unwrap_or(900) as f64,
+                state_dir.to_string(),
+                zone,
+            );
+            Some(Box::new(tmp))
+        }
+        "self_energy" => {
+            let tmp = self_energy::SelfEnergyComponent::new(
+                component_cfg
+                    .get("proc_dir")
+                    .and_then(|v| v.as_str())
+                    .unwrap_or("/proc")
+                    .to_string(),
+                component_cfg
+                    .get("joules_per_cpu_sec")
+                    .and_then(|v| v.as_float().or_else(|| v.as_integer().map(|i| i as f64)))
+                    .unwrap_or(10.0),
+                component_cfg
+                    .get("power_column")
+                    .and_then(|v| v.as_str())
+                    .map(|v| v.to_string()),
+                component_cfg
+                    .get("clock_ticks")
+                    .and_then(|v| v.as_integer())
+                    .unwrap_or(100) as f64,
+            );
+            Some(Box::new(tmp))
+        }
+        "goe_control" => {
+            if !component_cfg.contains_key("url") || !component_cfg.contains_key("surplus_column") {
+                panic!("a go-e controller requires the following fields to be set: url, and surplus_column.");
+            }
+            let get_float = |key: &str, default: f64| {
+                component_cfg
+                    .get(key)
+                    .and_then(|v| v.as_float().or_else(|| v.as_integer().map(|i| i as f64)))
+                    .unwrap_or(default)
+            };
+            // chargers take whole amps, and do not charge below 6 A at all (IEC 61851).
+            let amps = |key: &str, default: u8| match component_cfg.get(key) {
+                None => default,
+                Some(v) => match v.as_integer() {
+                    Some(amps) if (6..=80).contains(&amps) => amps as u8,
+                    _ => panic!(
+                        "{} must be a whole number of amps from 6 to 80; got: {}.",
+                        key, v
+                    ),
+                },
+            };
+            let (min_current, max_current) = (amps("min_current", 6), amps("max_current", 16));
+            if max_current < min_current {
+                panic!(
+                    "max_current must not be below min_current; got: {} < {}.",
+                    max_current, min_current
+                );
+            }
+            let limiter = evse::CurrentLimiter::new(
+                min_current,
+                max_current,
+                get_float("phases", 1.0),
+                get_float("voltage", 230.0),
+                component_cfg
+                    .get("hold")
+                    .and_then(|v| v.as_integer())
+                    .unwrap_or(10) as u32,
+            );
+            let tmp = evse::GoeController::new(
+                name.to_string(),
+                component_cfg["url"]
+                    .as_str()
+                    .unwrap_or("http://192.168.178.2")
+                    .to_string(),
+                component_cfg["surplus_column"]
+                    .as_str()
+                    .unwrap_or("surplus")
+                    .to_string(),
+                limiter,
+            );
+            Some(Box::new(tmp))
+        }
+        "derived" => {
+            if !component_cfg.contains_key("expression") {
+                panic!("a derived component requires the following fields to be set: expression.");
+            }
+            let tmp = derived::DerivedComponent::new(
+                name.to_string(),
+                component_cfg["expression"]
+                    .as_str()
+                    .expect("expression must be a string."),
+            );
+            Some(Box::new(tmp))
+        }
+        "co2" => {
+            if !component_cfg.contains_key("power_column")
+                || !(component_cfg.contains_key("intensity_column")
+                    || component_cfg.contains_key("intensity"))
+            {
+                panic!("a co2 component requires the following fields to be set: power_column, and intensity_column or intensity.");
+            }
+            let intensity = match component_cfg.get("intensity_column") {
+                Some(column) => co2::Intensity::Column(
+                    column
+                        .as_str()
+                        .expect("intensity_column must be a string.")
+                        .to_string(),
+                ),
+                None => {
+                    let value = &component_cfg["intensity"];
+                    co2::Intensity::Static(
+                        value
+                            .as_float()
+                            .or_else(|| value.as_integer().map(|i| i as f64))
+                            .expect("intensity must be a number."),
+                    )
+                }
+            };
+            let tmp = co2::Co2Component::new(
+                name.to_string(),
+                component_cfg["power_column"]
+                    .as_str()
+                    .expect("power_column must be a string.")
+                    .to_string(),
+                intensity,
+                component_cfg
+                    .get("max_gap_secs")
+                    .and_then(|v| v.as_integer())
+                    .unwrap_or(900) as f64,
+                state_dir.to_string(),
+            );
+            Some(Box::new(tmp))
+        }
+        "cost" => {
+            if !component_cfg.contains_key("power_column")
+                || !component_cfg.contains_key("price_column")
+            {
+                panic!("a cost component requires the following fields to be set: power_column, price_column.");
+            }
+            let direction = component_cfg
+                .get("direction")
+                .map(|v| v.as_str().expect("direction must be a string."))
+                .unwrap_or("import");
+            let tmp = cost::CostComponent::new(
+                name.to_string(),
+                component_cfg["power_column"]
+                    .as_str()
+                    .expect("power_column must be a string.")
+                    .to_string(),
+                component_cfg["price_column"]
+                    .as_str()
+                    .expect("price_column must be a string.")
+                    .to_string(),
+                cost::Direction::parse(direction),
+                component_cfg
+                    .get("max_gap_secs")
+                    .and_then(|v| v.as_integer())
+                    .unwrap_or(900) as f64,
+                state_dir.to_string(),
+            );
+            Some(Box::new(tmp))
+        }
+        "pv_stats" => {
+            if !component_cfg.contains_key("generation_column")
+                || !(component_cfg.contains_key("feedin_column")
+                    || component_cfg.contains_key("consumption_column"))
+            {
+                panic!("a pv stats component requires the following fields to be set: generation_column, and feedin_column or consumption_column.");
+            }
+            let column = |key: &str| {
+                component_cfg
+                    .get(key)
+                    .map(|v| v.as_str().expect("columns must be strings.").to_string())
+            };
+            let surplus = match column("feedin_column") {
+                Some(feedin) => pv_stats::Surplus::FeedIn(feedin),
+                None => pv_stats::Surplus::Consumption(column("consumption_column").unwrap()),
+            };
+            let tmp = pv_stats::PvStatsComponent::new(
+                name.to_string(),
+                column("generation_column").unwrap(),
+                surplus,
+            );
+            Some(Box::new(tmp))
+        }
+        "interlock" => {
+            if !component_cfg.contains_key("invariants") {
+                panic!(
+                    "an interlock component requires the following fields to be set: invariants."
+                );
+            }
+            let invariants: Vec<interlock::Invariant> = component_cfg["invariants"]
+                .as_table()
+                .expect("invariants must be a table.")
+                .iter()
+                .map(|(name, v)| {
+                    interlock::Invariant::new(
+                        name.to_string(),
+                        v.as_str().expect("an invariant must be an expression."),
+                    )
+                })
+                .collect();
+            let tmp = interlock::InterlockComponent::new(
+                invariants,
+                component_cfg
+                    .get("clear_secs")
+                    .and_then(|v| v.as_integer())
+                    .unwrap_or(300) as f64,
+            );
+            Some(Box::new(tmp))
+        }
+        "forecast" => {
+            if !component_cfg.contains_key("column") {
+                panic!("a forecast component requires the following fields to be set: column.");
+            }
+            let get_float = |key: &str, default: f64| {
+                component_cfg
+                    .get(key)
+                    .and_then(|v| v.as_float().or_else(|| v.as_integer().map(|i| i as f64)))
+                    .unwrap_or(default)
+            };
+            let tmp = forecast::ForecastComponent::new(
+                component_cfg["column"].as_str().unwrap_or("").to_string(),
+                get_float("alpha", 0.5),
+                get_float("beta", 0.1),
+                get_float("max_gap_secs", 300.0),
+                component_cfg
+                    .get("max_nan")
+                    .and_then(|v| v.as_integer())
+                    .unwrap_or(10) as u32,
+            );
+            Some(Box::new(tmp))
+        }
+        "journald" => {
+            let tmp =
+                journal::JournalComponent::new(get_journal(component_cfg), sensor_names.to_vec());
+            Some(Box::new(tmp))
+        }
+        #[cfg(feature = "scripting")]
+        "script" => {
+            if !component_cfg.contains_key("path") {
+                panic!("a script component requires the following fields to be set: path.");
+            }
+            let (engine, ast) = script::compile(
+                component_cfg["path"].as_str().unwrap_or(""),
+                get_max_operations(component_cfg),
+            )
+            .unwrap_or_else(|err| panic!("{}", err));
+            let tmp = script::ScriptComponent::new(engine, ast)
+                .unwrap_or_else(|err| panic!("could not get the columns of {}: {}", name, err));
+            Some(Box::new(tmp))
+        }
+        #[cfg(not(feature = "scripting"))]
+        "script" => panic!("script components require the scripting feature."),
+        &_ => None,
+    }
+}
+
+/// Budget of operations per call for scripts.
+#[cfg(feature = "scripting")]
+fn get_max_operations(cfg: &toml::value::Table) -> u64 {
+    cfg.get("max_operations")
+        .and_then(|v| v.as_integer())
+        .map(|v| v as u64)
+        .unwrap_or(script::MAX_OPERATIONS)
+}
+
+/// The device a sensor belongs to, if any.
+fn get_device(sensor_cfg: &toml::value::Table) -> Option<&str> {
+    sensor_cfg.get("device").and_then(|v| v.as_str())
+}
+
+/// Whether a device is enabled at startup; set as enabled in its table in the devices section.
+fn get_device_enabled(cfg: &config::Config, device: &str) -> bool {
+    cfg.data
+        .get("devices")
+        .and_then(|v| v.get(device))
+        .and_then(|v| v.get("enabled"))
+        .and_then(|v| v.as_bool())
+        .unwrap_or(true)
+}
+
+/// Wraps sensors that are part of a device, so they can be disabled together.
+fn with_device(
+    cfg: &config::Config,
+    sensor_cfg: &toml::value::Table,
+    sensor: Box<dyn common::Sensor>,
+) -> Box<dyn common::Sensor> {
+    match get_device(sensor_cfg) {
+        Some(device) => {
+            let enabled = device::switch(device, get_device_enabled(cfg, device));
+            Box::new(device::DeviceSensor::new(sensor, enabled))
+        }
+        None => sensor,
+    }
+}
+
+/// The device of each column; age, status & quality columns belong to the device of their sensor.
+fn get_device_labels(
+    cfg: &config::Config,
+    sensors: &Loops,
+    headers: &[String],
+) -> Vec<Option<String>> {
+    let devices: Vec<Option<String>> = sensors
+        .sensor_names
+        .iter()
+        .map(|name| {
+            cfg.data
+                .get(name)
+                .and_then(|v| v.as_table())
+                .and_then(get_device)
+                .map(String::from)
+        })
+        .collect();
+    let mut labels = vec![None];
+    let all = sensors.fast_loop.iter().chain(sensors.slow_loop.iter());
+    for (sensor, device) in all.zip(&devices) {
+        labels.extend(vec![device.clone(); sensor.get_names().len()]);
+    }
+    for column in &headers[labels.len()..] {
+        let sensor = column
+            .strip_suffix("_age_seconds")
+            .or_else(|| column.strip_suffix("_status"))
+            .or_else(|| column.strip_suffix("_quality"));
+        let device = sensor
+            .and_then(|sensor| sensors.sensor_names.iter().position(|n| n == sensor))
+            .and_then(|i| devices[i].clone());
+        labels.push(device);
+    }
+    labels
+}
+
+/// Wraps expensive sensors so only the elected leader among the instances measures them.
+fn with_coordination(
+    cfg: &config::Config,
+    name: &str,
+    sensor: Box<dyn common::Sensor>,
+) -> Box<dyn common::Sensor> {
+    let coordination = match cfg.data.get("coordination").and_then(|v| v.as_table()) {
+        Some(coordination) => coordination,
+        None => return sensor,
+    };
+    let coordinated = coordination
+        .get("sensors")
+        .and_then(|v| v.as_array())
+        .map(|v| v.iter().any(|s| s.as_str() == Some(name)))
+        .unwrap_or(false);
+    if !coordinated {
+        return sensor;
+    }
+    if !coordination.contains_key("id") || !coordination.contains_key("dir") {
+        panic!("coordination requires the following fields to be set: id, dir.");
+    }
+    let number = |field: &str, default: f64| {
+        coordination
+            .get(field)
+            .and_then(|v| v.as_float().or_else(|| v.as_integer().map(|i| i as f64)))
+            .unwrap_or(default)
+    };
+    let store = lease::FileStore::new(
+        coordination["dir"].as_str().unwrap_or("shared").to_string(),
+        name.to_string(),
+    );
+    let elector = lease::Elector::new(
+        name.to_string(),
+        coordination["id"].as_str().unwrap_or("ogc").to_string(),
+        number("lease_secs", 120.0),
+        number("max_skew_secs", 5.0),
+    );
+    Box::new(lease::CoordinatedSensor::new(
+        sensor,
+        Box::new(store),
+        elector,
+    ))
+}
+
+/// Parses the calibration of a sensor; metrics are the column names without the sensor name.
+fn get_calibration(
+    name: &str,
+    sensor_cfg: &toml::value::Table,
+    columns: &[String],
+) -> Option<calibration::Calibration> {
+    let table = sensor_cfg.get("calibration")?.as_table()?;
+    let number = |v: &toml::Value| {
+        v.as_float()
+            .or_else(|| v.as_integer().map(|i| i as f64))
+            .expect("calibration values must be numbers.")
+    };
+    let prefix = format!("{}_", name);
+    for metric in table.keys() {
+        if !columns.contains(&format!("{}{}", prefix, metric)) {
+            panic!(
+                "cannot calibrate {}: sensor {} has no such metric.",
+                metric, name
+            );
+        }
+    }
+    let corrections = columns
+        .iter()
+        .map(|column| {
+            let cfg = table.get(column.strip_prefix(&prefix)?)?;
+            Some(match cfg.get("points").and_then(|v| v.as_array()) {
+                Some(points) => calibration::Correction::points(
+                    points
+                        .iter()
+                        .map(|p| match p.as_array().map(|p| p.as_slice()) {
+                            Some([raw, corrected]) => (number(raw), number(corrected)),
+                            _ => panic!("calibration points must look like [raw, corrected]."),
+                        })
+                        .collect(),
+                ),
+                None => calibration::Correction::Linear {
+                    offset: cfg.get("offset").map(number).unwrap_or(0.0),
+                    gain: cfg.get("gain").map(number).unwrap_or(1.0),
+                },
+            })
+        })
+        .collect();
+    Some(calibration::Calibration::new(corrections))
+}
+
+/// Wraps a sensor with its calibration, if one is configured.
+fn with_calibration(
+    name: &str,
+    sensor_cfg: &toml::value::Table,
+    sensor: Box<dyn common::Sensor>,
+) -> Box<dyn common::Sensor> {
+    match get_calibration(name, sensor_cfg, &sensor.get_names()) {
+        Some(calibration) => Box::new(calibration::CalibratedSensor::new(sensor, calibration)),
+        None => sensor,
+    }
+}
+
+/// The conversion of each column of a sensor into the unit it is normalised to; None if nothing is normalised.
+///
+/// A metric is either given a unit conversion like { unit="kW->W" }, or a scale and offset (and optionally the unit the
+/// values are in then) like { scale=0.1, unit="°C" }.
+fn get_conversions(
+    name: &str,
+    sensor_cfg: &toml::value::Table,
+    columns: &[String],
+    units: &[String],
+) -> Option<Vec<Option<units::Conversion>>> {
+    let table = sensor_cfg.get("units")?.as_table()?;
+    let number = |v: &toml::Value| {
+        v.as_float()
+            .or_else(|| v.as_integer().map(|i| i as f64))
+            .expect("scale and offset must be numbers.")
+    };
+    let prefix = format!("{}_", name);
+    for metric in table.keys() {
+        if !columns.contains(&format!("{}{}", prefix, metric)) {
+            panic!(
+                "cannot normalise {}: sensor {} has no such metric.",
+                metric, name
+            );
+        }
+    }
+    let conversions = columns
+        .iter()
+        .zip(units)
+        .map(|(column, reported)| {
+            let metric = column.strip_prefix(&prefix)?;
+            let cfg = table.get(metric)?;
+            let unit = cfg
+                .get("unit")
+                .map(|v| v.as_str().expect("unit must be a string."));
+            if cfg.get("scale").is_some() || cfg.get("offset").is_some() {
+                return Some(units::Conversion {
+                    scale: cfg.get("scale").map(number).unwrap_or(1.0),
+                    offset: cfg.get("offset").map(number).unwrap_or(0.0),
+                    unit: unit.map(String::from),
+                });
+            }
+            let shorthand = unit.unwrap_or_else(|| {
+                panic!(
+                    "normalising {} of sensor {} requires a unit, or a scale and offset.",
+                    metric, name
+                )
+            });
+            let conversion =
+                units::Conversion::parse(shorthand).unwrap_or_else(|e| panic!("{}", e));
+            // a conversion from a unit the sensor does not report is off by a factor.
+            let from = shorthand.split("->").next().unwrap_or_default().trim();
+            if units::parse_unit(reported).is_some() && reported != from {
+                panic!(
+                    "cannot normalise {} of sensor {} from {}: it is in {}.",
+                    metric, name, from, reported
+                );
+            }
+            Some(conversion)
+        })
+        .collect();
+    Some(conversions)
+}
+
+/// Wraps a sensor so its values are normalised, if any conversions are configured.
+fn with_units(
+    name: &str,
+    sensor_cfg: &toml::value::Table,
+    sensor: Box<dyn common::Sensor>,
+) -> Box<dyn common::Sensor> {
+    match get_conversions(name, sensor_cfg, &sensor.get_names(), &sensor.get_units()) {
+        Some(conversions) => Box::new(units::NormalisedSensor::new(sensor, conversions)),
+        None => sensor,
+    }
+}
+
+/// Wraps a sensor with its transform script, if one is configured.
+fn with_transform(
+    sensor_cfg: &toml::value::Table,
+    sensor: Box<dyn common::Sensor>,
+) -> Box<dyn common::Sensor> {
+    match sensor_cfg.get("transform").and_then(|v| v.as_str()) {
+        None => sensor,
+        #[cfg(feature = "scripting")]
+        Some(path) => {
+            let (engine, ast) = script::compile(path, get_max_operations(sensor_cfg))
+                .unwrap_or_else(|err| panic!("{}", err));
+            Box::new(script::TransformSensor::new(sensor, engine, ast))
+        }
+        #[cfg(not(feature = "scripting"))]
+        Some(_) => panic!("transform scripts require the scripting feature."),
+    }
+}
+
+/// Parses which counters of a sensor are reported as rates or deltas; metrics are the column names w/o the sensor name.
+fn get_derives(
+    name: &str,
+    sensor_cfg: &toml::value::Table,
+    columns: &[String],
+) -> Option<Vec<Option<rate::Derive>>> {
+    let table = sensor_cfg.get("derive")?.as_table()?;
+    let prefix = format!("{}_", name);
+    for metric in table.keys() {
+        if !columns.contains(&format!("{}{}", prefix, metric)) {
+            panic!(
+                "cannot derive {}: sensor {} has no such metric.",
+                metric, name
+            );
+        }
+    }
+    Some(
+        columns
+            .iter()
+            .map(|column| {
+                let derive = table.get(column.strip_prefix(&prefix)?)?;
+                Some(rate::Derive::parse(
+                    derive.as_str().expect("derive must be a string."),
+                ))
+            })
+            .collect(),
+    )
+}
+
+/// Wraps a sensor so its counters are reported as rates or deltas, if any are configured.
+fn with_counters(
+    name: &str,
+    sensor_cfg: &toml::value::Table,
+    sensor: Box<dyn common::Sensor>,
+) -> Box<dyn common::Sensor> {
+    match get_derives(name, sensor_cfg, &sensor.get_names()) {
+        Some(derives) => Box::new(rate::CounterSensor::new(sensor, derives)),
+        None => sensor,
+    }
+}
+
+/// What a sensor reports instead of failing; None for missing values.
+fn get_fallback(sensor_cfg: &toml::value::Table) -> Option<hold::Fallback> {
+    let max_age = sensor_cfg.get("hold_max_secs").map(|v| {
+        let secs = v
+            .as_float()
+            .or_else(|| v.as_integer().map(|i| i as f64))
+            .expect("hold_max_secs must be a number.");
+        if secs < 0.0 {
+            panic!("hold_max_secs must not be negative; got: {}.", secs);
+        }
+        secs
+    });
+    match sensor_cfg.get("on_error").and_then(|v| v.as_str()) {
+        None | Some("missing") => None,
+        Some("zero") => Some(hold::Fallback::Zero),
+        Some("hold_last") => Some(hold::Fallback::HoldLast { max_age }),
+        Some(other) => panic!(
+            "on_error must be one of 'missing', 'zero' or 'hold_last'; got: {}.",
+            other
+        ),
+    }
+}
+
+/// Wraps a sensor with its fallback for failed measurements, if one is configured.
+fn with_fallback(
+    name: &str,
+    sensor_cfg: &toml::value::Table,
+    sensor: Box<dyn common::Sensor>,
+) -> Box<dyn common::Sensor> {
+    match get_fallback(sensor_cfg) {
+        Some(fallback) => Box::new(hold::HoldLastValueSensor::new(
+            name.to_string(),
+            sensor,
+            fallback,
+        )),
+        None => sensor,
+    }
+}
+
+/// Instantiates an actuator of the actuators section.
+fn create_actuator(name: &str, actuator_cfg: &toml::value::Table) -> Box<dyn common::Actuator> {
+    let get = |key: &str| {
+        actuator_cfg
+            .get(key)
+            .and_then(|v| v.as_str())
+            .unwrap_or_else(|| panic!("actuator {} requires the field {}.", name, key))
+            .to_string()
+    };
+    let command = |key: &str| {
+        actuator_cfg.get(key).map(|v| {
+            v.as_array()
+                .and_then(|a| {
+                    a.iter()
+                        .map(|s| s.as_str().map(String::from))
+                        .collect::<Option<Vec<String>>>()
+                })
+                .unwrap_or_else(|| {
+                    panic!("{} of actuator {} must be an array of strings.", key, name)
+                })
+        })
+    };
+    match actuator_cfg.get("type").and_then(|v| v.as_str()) {
+        Some("fritz") => Box::new(fritz::FritzSensor::new(
+            name.to_string(),
+            get("url"),
+            get("user"),
+            get("password"),
+            get("ain"),
+            false,
+        )),
+        Some("exec") => Box::new(exec::ExecActuator::new(
+            command("on").unwrap_or_else(|| panic!("actuator {} requires the field on.", name)),
+            command("off").unwrap_or_else(|| panic!("actuator {} requires the field off.", name)),
+            command("state"),
+            get_timeout(actuator_cfg).unwrap_or(time::Duration::from_secs(10)),
+        )),
+        other => panic!("unknown actuator type: {:?}.", other),
+    }
+}
+
+/// The rules of the rules section and the actuators they drive; None if there are no rules.
+fn get_rules(cfg: &config::Config) -> Option<rules::RulesComponent> {
+    let rule_cfgs = cfg.data.get("rules")?.as_table()?;
+    let actuator_cfgs = cfg
+        .data
+        .get("actuators")
+        .and_then(|v| v.as_table())
+        .expect("rules require an actuators section.");
+    let mut actuators: Vec<(String, Box<dyn common::Actuator>)> = Vec::new();
+    let mut rules = Vec::new();
+    for (name, rule_cfg) in rule_cfgs {
+        let get = |key: &str| {
+            rule_cfg
+                .get(key)
+                .and_then(|v| v.as_str())
+                .unwrap_or_else(|| panic!("rule {} requires the field {}.", name, key))
+        };
+        let number = |key: &str, default: Option<f64>| {
+            match rule_cfg.get(key) {
+                Some(v) => v.as_float().or_else(|| v.as_integer().map(|i| i as f64)),
+                None => default,
+            }
+            .unwrap_or_else(|| panic!("{} of rule {} must be a number.", key, name))
+        };
+        let actuator = get("actuator");
+        let index = match actuators.iter().position(|(n, _)| n == actuator) {
+            Some(index) => index,
+            None => {
+                let actuator_cfg = actuator_cfgs
+                    .get(actuator)
+                    .and_then(|v| v.as_table())
+                    .unwrap_or_else(|| panic!("unknown actuator: {}.", actuator));
+                actuators.push((
+                    actuator.to_string(),
+                    create_actuator(actuator, actuator_cfg),
+                ));
+                actuators.len() - 1
+            }
+        };
+        rules.push(rules::Rule::new(
+            name.to_string(),
+            get("column").to_string(),
+            get("operator").to_string(),
+            number("threshold", None),
+            number("hysteresis", Some(0.0)),
+            number("min_on_secs", Some(0.0)),
+            number("min_off_secs", Some(0.0)),
+            index,
+        ));
+    }
+    Some(rules::RulesComponent::new(rules, actuators))
+}
+
+/// Jobs started while their conditions hold; configured in a section of their own.
+fn get_jobs(cfg: &config::Config) -> Option<jobs::JobsComponent> {
+    let job_cfgs = cfg.data.get("jobs")?.as_table()?;
+    let mut res = Vec::new();
+    for (name, job_cfg) in job_cfgs {
+        let get = |key: &str| {
+            job_cfg
+                .get(key)
+                .and_then(|v| v.as_str())
+                .unwrap_or_else(|| panic!("job {} requires the field {}.", name, key))
+        };
+        let number = |key: &str, default: Option<f64>| {
+            match job_cfg.get(key) {
+                Some(v) => v.as_float().or_else(|| v.as_integer().map(|i| i as f64)),
+                None => default,
+            }
+            .unwrap_or_else(|| panic!("{} of job {} must be a number.", key, name))
+        };
+        let command = |key: &str| {
+            job_cfg.get(key).map(|v| {
+                v.as_array()
+                    .and_then(|a| {
+                        a.iter()
+                            .map(|c| c.as_str().map(|c| c.to_string()))
+                            .collect::<Option<Vec<String>>>()
+                    })
+                    .filter(|c| !c.is_empty())
+                    .unwrap_or_else(|| {
+                        panic!(
+                            "{} of job {} must be a non-empty array of strings.",
+                            key, name
+                        )
+                    })
+            })
+        };
+        let stop = match (command("stop_command"), job_cfg.get("stop_signal")) {
+            (Some(_), Some(_)) => panic!(
+                "job {} can have a stop_command or a stop_signal; not both.",
+                name
+            ),
+            (Some(command), None) => jobs::Stop::Command(command),
+            (None, Some(signal)) => match signal.as_str() {
+                Some("term") => jobs::Stop::Term,
+                Some("kill") => jobs::Stop::Kill,
+                _ => panic!(
+                    "stop_signal must be one of 'term' or 'kill'; got: {}.",
+                    signal
+                ),
+            },
+            (None, None) => jobs::Stop::Never,
+        };
+        res.push(jobs::Job::new(
+            name.to_string(),
+            get("column").to_string(),
+            job_cfg
+                .get("operator")
+                .map(|_| get("operator"))
+                .unwrap_or(">")
+                .to_string(),
+            number("threshold", None),
+            number("sustain_secs", Some(0.0)),
+            command("command")
+                .unwrap_or_else(|| panic!("job {} requires the field command.", name)),
+            stop,
+        ));
+    }
+    Some(jobs::JobsComponent::new(res))
+}
+
+/// Given the configuration determine slow and fast loop sensors.
+fn get_sensors(cfg: &config::Config) -> Loops {
+    get_sensors_with(cfg, &sensor_registry())
+}
+
+/// Given the configuration determine slow and fast loop sensors, of the types in the registry.
+fn get_sensors_with(cfg: &config::Config, registry: &common::Registry) -> Loops {
+    let mut slow_sensors: Vec<Box<dyn common::Sensor>> = Vec::new();
+    let mut fast_sensors: Vec<Box<dyn common::Sensor>> = Vec::new();
+    let mut fast_names: Vec<String> = Vec::new();
+    let mut slow_names: Vec<String> = Vec::new();
+    if let Some(tmp) = cfg.data["general"]["slow_loop"].as_array() {
+        for item in tmp {
+            let name = item.as_str().expect("no name provided.");
+            let sensor_cfg = cfg.data[name].as_table().expect("no config provided.");
+            if let Some(sensor) = create_sensor(registry, name, sensor_cfg, get_state_dir(cfg)) {
+                let sensor = with_coordination(cfg, name, sensor);
+                slow_sensors.push(with_device(
+                    cfg,
+                    sensor_cfg,
+                    with_fallback(
+                        name,
+                        sensor_cfg,
+                        with_counters(
+                            name,
+                            sensor_cfg,
+                            with_transform(
+                                sensor_cfg,
+                                with_units(
+                                    name,
+                                    sensor_cfg,
+                                    with_calibration(name, sensor_cfg, sensor),
+                                ),
+                            ),
+                        ),
+                    ),
+                ));
+                slow_names.push(name.to_string());
+            }
+        }
+    }
+    if let Some(tmp) = cfg.data["general"]["fast_loop"].as_array() {
+        for item in tmp {
+            let name = item.as_str().expect("no name provided.");
+            let sensor_cfg = cfg.data[name].as_table().expect("no config provided.");
+            if let Some(sensor) = create_sensor(registry, name, sensor_cfg, get_state_dir(cfg)) {
+                let sensor = with_coordination(cfg, name, sensor);
+                fast_sensors.push(with_device(
+                    cfg,
+                    sensor_cfg,
+                    with_fallback(
+                        name,
+                        sensor_cfg,
+                        with_counters(
+                            name,
+                            sensor_cfg,
+                            with_transform(
+                                sensor_cfg,
+                                with_units(
+                                    name,
+                                    sensor_cfg,
+                                    with_calibration(name, sensor_cfg, sensor),
+                                ),
+                            ),
+                        ),
+                    ),
+                ));
+                fast_names.push(name.to_string());
+            }
+        }
+    }
+    fast_names.extend(slow_names);
+    let mut components: Vec<Box<dyn common::Component>> = Vec::new();
+    if let Some(tmp) = cfg.data["general"]
+        .get("components")
+        .and_then(|v| v.as_array())
+    {
+        for item in tmp {
+            let name = item.as_str().expect("no name provided.");
+            let component_cfg = cfg.data[name].as_table().expect("no config provided.");
+            if let Some(component) = create_component(
+                name,
+                component_cfg,
+                get_state_dir(cfg),
+                &fast_names,
+                get_timezone(cfg),
+            ) {
+                components.push(component);
+            }
+        }
+    }
+    // after all other components, so rules see their columns too.
+    if let Some(rules) = get_rules(cfg) {
+        components.push(Box::new(rules));
+    }
+    if let Some(jobs) = get_jobs(cfg) {
+        components.push(Box::new(jobs));
+    }
+    Loops {
+        slow_loop: slow_sensors,
+        fast_loop: fast_sensors,
+        components,
+        sensor_names: fast_names,
+    }
+}
+
+/// Zone for everything calendar based; UTC unless configured otherwise.
+fn get_timezone(cfg: &config::Config) -> tz::Zone {
+    cfg.data["general"]
+        .get("timezone")
+        .and_then(|v| v.as_str())
+        .map(tz::Zone::parse)
+        .unwrap_or(tz::Zone::Utc)
+}
+
+/// Delimiter, decimal places and quoting of the CSV file; set as the csv table of the general section.
+///
+/// A datetime column is added when timestamp_format is 'utc' or 'local' (the configured timezone).
+fn get_csv_format(cfg: &config::Config) -> csv_out::Format {
+    let datetime = match cfg.data["general"]
+        .get("timestamp_format")
+        .and_then(|v| v.as_str())
+    {
+        None | Some("epoch") => None,
+        Some("utc") => Some(tz::Zone::Utc),
+        Some("local") => Some(get_timezone(cfg)),
+        Some(other) => panic!(
+            "timestamp_format must be one of 'epoch', 'utc' or 'local'; got: {}.",
+            other
+        ),
+    };
+    let csv = cfg.data["general"].get("csv");
+    csv_out::Format::new(
+        csv.and_then(|v| v.get("delimiter"))
+            .and_then(|v| v.as_str())
+            .unwrap_or(","),
+        csv.and_then(|v| v.get("decimals"))
+            .and_then(|v| v.as_integer())
+            .map(|v| v as usize),
+        csv.and_then(|v| v.get("quote"))
+            .and_then(|v| v.as_bool())
+            .unwrap_or(false),
+        datetime,
+        get_missing(cfg),
+    )
+}
+
+/// How missing (failed) values are written to files; an empty cell unless set in the csv table.
+fn get_missing(cfg: &config::Config) -> String {
+    cfg.data["general"]
+        .get("csv")
+        .and_then(|v| v.get("missing"))
+        .and_then(|v| v.as_str())
+        .unwrap_or("")
+        .to_string()
+}
+
+/// Directory in which components persist their state.
+fn get_state_dir(cfg: &config::Config) -> &str {
+    cfg.data["general"]
+        .get("state_dir")
+        .and_then(|v| v.as_str())
+        .unwrap_or("state")
+}
+
+/// Nominal battery capacity in Wh (integer or float in the config).
+fn get_capacity(component_cfg: &toml::value::Table) -> f64 {
+    let value = &component_cfg["capacity_wh"];
+    value
+        .as_float()
+        .or_else(|| value.as_integer().map(|i| i as f64))
+        .expect("capacity_wh must be a number.")
+}
+
+/// Prints a summary of the long term statistics persisted by the components.
+fn report(cfg: &config::Config) {
+    if let Some(tmp) = cfg.data["general"]
+        .get("components")
+        .and_then(|v| v.as_array())
+    {
+        for item in tmp {
+            let name = item.as_str().expect("no name provided.");
+            let component_cfg = cfg.data[name].as_table().expect("no config provided.");
+            if component_cfg.get("type").and_then(|v| v.as_str()) != Some("battery_stats") {
+                continue;
+            }
+            match state::load::<battery_stats::BatteryStats>(get_state_dir(cfg), name) {
+                Some(stats) => println!("{}: {}", name, stats.summary(get_capacity(component_cfg))),
+                None => println!("{}: no statistics recorded yet.", name),
+            }
+        }
+    }
+}
+
+/// Checks the configuration without touching any hardware: the fields of all sensors, and compiles all scripts.
+fn check(cfg: &config::Config) -> bool {
+    let mut ok = true;
+    let registry = sensor_registry();
+    for (name, value) in &cfg.data {
+        let table = match value.as_table() {
+            Some(table) => table,
+            None => continue,
+        };
+        for problem in registry.check(table, &SENSOR_FIELDS) {
+            println!("{}: {}", name, problem);
+            ok = false;
+        }
+        let path = match (
+            table.get("transform").and_then(|v| v.as_str()),
+            table.get("type").and_then(|v| v.as_str()),
+        ) {
+            (Some(path), _) => path,
+            (None, Some("script")) => table.get("path").and_then(|v| v.as_str()).unwrap_or(""),
+            _ => continue,
+        };
+        #[cfg(feature = "scripting")]
+        let res = script::compile(path, get_max_operations(table)).map(|_| ());
+        #[cfg(not(feature = "scripting"))]
+        let res: Result<(), String> = Err(format!("{} requires the scripting feature.", path));
+        if let Err(err) = res {
+            println!("{}: {}", name, err);
+            ok = false;
+        }
+    }
+    ok
+}
+
+/// Measures a single sensor once and prints its raw and calibrated values; e.g. `probe fritz`.
+fn probe(cfg: &config::Config, args: &[String]) {
+    match (args.first().map(|v| v.as_str()), args.get(1)) {
+        (Some("--device"), Some(device)) => {
+            let members = get_members(cfg, device);
+            if members.is_empty() {
+                println!("No sensors of device {} configured.", device);
+            }
+            for name in members {
+                println!("{}:", name);
+                probe_sensor(cfg, &name);
+            }
+        }
+        (Some(name), None) if name != "--device" => probe_sensor(cfg, name),
+        _ => println!("usage: open_green_compute probe <sensor> | --device <device>"),
+    }
+}
+
+/// Names of the sensors of a device; sorted.
+fn get_members(cfg: &config::Config, device: &str) -> Vec<String> {
+    let mut members: Vec<String> = cfg
+        .data
+        .iter()
+        .filter(|(_, v)| v.as_table().and_then(get_device) == Some(device))
+        .map(|(name, _)| name.clone())
+        .collect();
+    members.sort();
+    members
+}
+
+fn probe_sensor(cfg: &config::Config, name: &str) {
+    let sensor_cfg = match cfg.data.get(name).and_then(|v| v.as_table()) {
+        Some(sensor_cfg) => sensor_cfg,
+        None => {
+            println!("No sensor {} configured.", name);
+            return;
+        }
+    };
+    let sensor = match create_sensor(&sensor_registry(), name, sensor_cfg, get_state_dir(cfg)) {
+        Some(sensor) => sensor,
+        None => {
+            println!("Sensor {} is of an unknown type.", name);
+            return;
+        }
+    };
+    let columns = sensor.get_names();
+    let raw = match sensor.measure() {
+        Ok(readings) => common::align(&columns, &readings).0,
+        Err(err) => {
+            println!("Could not measure {}: {}", name, err);
+            return;
+        }
+    };
+    let corrected = match get_calibration(name, sensor_cfg, &columns) {
+        Some(calibration) => calibration.correct(&raw),
+        None => raw.clone(),
+    };
+    print!("{}", format_probe(&columns, &raw, &corrected));
+}
+
+/// One line per column with the raw and - where it differs - the corrected value.
+fn format_probe(columns: &[String], raw: &[f64], corrected: &[f64]) -> String {
+    let mut res = String::new();
+    for ((column, raw), corrected) in columns.iter().zip(raw).zip(corrected) {
+        if raw.to_bits() == corrected.to_bits() {
+            res.push_str(&format!("{}: {}\n", column, raw));
+        } else {
+            res.push_str(&format!("{}: {} (raw: {})\n", column, corrected, raw));
+        }
+    }
+    res
+}
+
+/// Follows the data file and prints its new rows aligned; e.g. `tail --columns pv_power,grid_power`.
+fn tail(cfg: &config::Config, args: &[String]) {
+    let value_of = |flag: &str| {
+        args.iter()
+            .position(|a| a == flag)
+            .and_then(|i| args.get(i + 1))
+            .cloned()
+    };
+    let file = value_of("--file").unwrap_or_else(|| {
+        cfg.data["general"]["filename"]
+            .as_str()
+            .unwrap_or("data.csv")
+            .to_string()
+    });
+    let columns: Vec<String> = value_of("--columns")
+        .map(|v| v.split(',').map(|c| c.trim().to_string()).collect())
+        .unwrap_or_default();
+    let color = std::io::stdout().is_terminal();
+    let missing = get_missing(cfg);
+
+    let zone = get_timezone(cfg);
+    let now = || clock::epoch_secs(time::SystemTime::now());
+    let mut path = output::render(&file, zone, now());
+    // JSON lines have no header, and files might have no line of units; the configuration tells.
+    let format = match cfg.data["general"].get("format").and_then(|v| v.as_str()) {
+        _ if file.ends_with(".jsonl") => None,
+        Some("jsonl") => None,
+        _ => Some(get_csv_format(cfg)),
+    };
+    let sensors = get_sensors(cfg);
+    let (age, status, quality) = (
+        get_age_columns(cfg),
+        get_degraded_mode(cfg),
+        get_quality_columns(cfg),
+    );
+    let schema: Vec<(String, String)> = get_headers(&sensors, age, status, quality)
+        .into_iter()
+        .zip(get_units(&sensors, age, status, quality))
+        .collect();
+    let mut follower = tail::Follower::new(&path, format.clone(), schema.clone());
+    let mut indices = Vec::new();
+    let mut resolved = false;
+    loop {
+        // files named by date are followed into the next day.
+        let current = output::render(&file, zone, now());
+        if current != path && fs::metadata(&current).is_ok() {
+            follower = tail::Follower::new(&current, format.clone(), schema.clone());
+            path = current;
+        }
+        let (reopened, rows) = follower.poll();
+        // a new file might come with other columns; it might also not have a header yet.
+        resolved &= !reopened;
+        if !resolved && !follower.names().is_empty() {
+            indices = match tail::resolve(follower.names(), &columns) {
+                Ok(indices) => indices,
+                Err(err) => {
+                    println!("{}", err);
+                    return;
+                }
+            };
+            println!("{}", tail::format_header(follower.names(), &indices));
+            resolved = true;
+        }
+        for row in rows {
+            println!(
+                "{}",
+                tail::format_row(
+                    follower.names(),
+                    follower.units(),
+                    &indices,
+                    &row,
+                    &missing,
+                    color
+                )
+            );
+        }
+        std::thread::sleep(time::Duration::from_secs(1));
+    }
+}
+
+/// Scans a bus for devices; e.g. `scan i2c --bus /dev/i2c-1`.
+fn scan(args: &[String]) {
+    if args.first().map(|v| v.as_str()) != Some("i2c") {
+        println!("usage: open_green_compute scan i2c [--bus /dev/i2c-1]");
+        return;
+    }
+    let bus = args
+        .iter()
+        .position(|a| a == "--bus")
+        .and_then(|i| args.get(i + 1))
+        .map(|v| v.as_str())
+        .unwrap_or("/dev/i2c-1");
+    match linux_embedded_hal::I2cdev::new(bus) {
+        Ok(mut device) => print!("{}", i2c_scan::scan(&mut device, bus)),
+        Err(err) => println!("Could not open {}: {}", bus, err),
+    }
+}
+
+/// Imports history from other systems into the CSV file; for now only Home Assistant.
+fn import(cfg: &config::Config, source: Option<String>, path: Option<String>) {
+    let path = match (source.as_deref(), path) {
+        (Some("ha"), Some(path)) => path,
+        _ => {
+            println!("usage: open_green_compute import ha <export.json|home-assistant_v2.db>");
+            return;
+        }
+    };
+    let mappings: Vec<ha_import::Mapping> = cfg
+        .data
+        .get("import_ha")
+        .and_then(|v| v.get("mappings"))
+        .and_then(|v| v.as_array())
+        .expect("an import requires mappings in the import_ha section.")
+        .iter()
+        .map(ha_import::create_mapping)
+        .collect();
+
+    // stick to the header of an existing file; else the one the loop would create.
+    let filename = cfg.data["general"]["filename"]
+        .as_str()
+        .unwrap_or("data.csv");
+    if filename.contains('%') {
+        println!("Cannot import into files named by date: {}.", filename);
+        return;
+    }
+    let format = get_csv_format(cfg);
+    let (headers, units) = match fs::read_to_string(filename) {
+        Ok(content) => (
+            format.columns(content.lines().next().unwrap_or("timestamp")),
+            None,
+        ),
+        Err(_) => {
+            let sensors = get_sensors(cfg);
+            let (age, status, quality) = (
+                get_age_columns(cfg),
+                get_degraded_mode(cfg),
+                get_quality_columns(cfg),
+            );
+            let units = get_units(&sensors, age, status, quality);
+            (
+                get_headers(&sensors, age, status, quality),
+                get_units_header(cfg).then_some(units),
+            )
+        }
+    };
+    match ha_import::import(
+        &path,
+        &mappings,
+        &headers,
+        units.as_deref(),
+        filename,
+        &format,
+    ) {
+        Ok(n) => println!("Imported {} rows.", n),
+        Err(err) => println!("Could not import: {}", err),
+    }
+}
+
+/// Determine the column names: timestamp, fast loop, slow loop, the optional age, status and
+/// quality columns and finally the components.
+fn get_headers(
+    sensors: &Loops,
+    age_columns: bool,
+    status_columns: bool,
+    quality_columns: bool,
+) -> Vec<String> {
+    let mut headers = vec!["timestamp".to_string()];
+    for sensor in &sensors.fast_loop {
+        headers.extend(sensor.get_names());
+    }
+    for sensor in &sensors.slow_loop {
+        headers.extend(sensor.get_names());
+    }
+    if age_columns {
+        for name in &sensors.sensor_names {
+            headers.push(format!("{}_age_seconds", name));
+        }
+    }
+    if status_columns {
+        for name in &sensors.sensor_names {
+            headers.push(format!("{}_status", name));
+        }
+    }
+    if quality_columns {
+        for name in &sensors.sensor_names {
+            headers.push(format!("{}_quality", name));
+        }
+    }
+    for component in &sensors.components {
+        headers.extend(component.get_names());
+    }
+    headers
+}
+
+/// The unit of each column, in the order of get_headers; columns the sensors do not describe have none.
+fn get_units(
+    sensors: &Loops,
+    age_columns: bool,
+    status_columns: bool,
+    quality_columns: bool,
+) -> Vec<String> {
+    let mut units = vec!["s".to_string()];
+    for sensor in &sensors.fast_loop {
+        units.extend(sensor.get_units());
+    }
+    for sensor in &sensors.slow_loop {
+        units.extend(sensor.get_units());
+    }
+    if age_columns {
+        units.extend(vec!["s".to_string(); sensors.sensor_names.len()]);
+    }
+    if status_columns {
+        units.extend(vec![String::new(); sensors.sensor_names.len()]);
+    }
+    if quality_columns {
+        units.extend(vec![String::new(); sensors.sensor_names.len()]);
+    }
+    for component in &sensors.components {
+        units.extend(vec![String::new(); component.get_names().len()]);
+    }
+    units
+}
+
+/// Whether CSV files get a second header line with the units of the columns.
+fn get_units_header(cfg: &config::Config) -> bool {
+    cfg.data["general"]
+        .get("units_header")
+        .and_then(|v| v.as_bool())
+        .unwrap_or(false)
+}
+
+/// State kept between the iterations of the loop.
+struct LoopState {
+    iteration: i64,
+    slow_loop_delay: i64,
+    age_columns: bool,
+    quality_columns: bool,
+    cache: Vec<f64>,
+    cache_times: Vec<f64>,
+    // the quality flags of each sensor of the slow loop when it was last measured.
+    cache_quality: Vec<u8>,
+    // when each cached value and each value of the last row was measured.
+    cache_value_times: Vec<f64>,
+    times: Vec<f64>,
+    latencies: Vec<latency::Tracker>,
+    latency_window: f64,
+    latency_summary: bool,
+    health: Vec<health::Health>,
+    zone: tz::Zone,
+    degraded_mode: bool,
+    retry_every: u32,
+    supervisors: Vec<degraded::Supervisor>,
+    // whether the last measurement of each sensor failed; and where to dump their exchanges then.
+    failing: Vec<bool>,
+    debug_dir: String,
+}
+
+impl LoopState {
+    fn new(cfg: &config::Config) -> LoopState {
+        LoopState {
+            iteration: 0,
+            slow_loop_delay: cfg.data["general"]
+                .get("slow_loop_delay")
+                .and_then(|v| v.as_integer())
+                .unwrap_or(20),
+            age_columns: get_age_columns(cfg),
+            quality_columns: get_quality_columns(cfg),
+            cache: Vec::new(),
+            cache_times: Vec::new(),
+            cache_quality: Vec::new(),
+            cache_value_times: Vec::new(),
+            times: Vec::new(),
+            latencies: Vec::new(),
+            latency_window: cfg.data["general"]
+                .get("latency_window_secs")
+                .and_then(|v| v.as_integer())
+                .unwrap_or(3600) as f64,
+            latency_summary: cfg.data["general"]
+                .get("latency_summary")
+                .and_then(|v| v.as_bool())
+                .unwrap_or(false),
+            health: Vec::new(),
+            zone: get_timezone(cfg),
+            degraded_mode: get_degraded_mode(cfg),
+            retry_every: cfg.data["general"]
+                .get("degraded_retry_every")
+                .and_then(|v| v.as_integer())
+                .unwrap_or(10) as u32,
+            supervisors: Vec::new(),
+            failing: Vec::new(),
+            debug_dir: format!("{}/debug", get_state_dir(cfg)),
+        }
+    }
+}
+
+/// Measures a sensor while keeping track of its latency, failures and health; the columns of a failed sensor are NaN.
+///
+/// Also returns the quality flags of the measurement as a whole; missing columns count as failed.
+fn measure(
+    sensor: &dyn common::Sensor,
+    tracker: &mut latency::Tracker,
+    health: &mut health::Health,
+    now: f64,
+    summary: bool,
+) -> (Vec<f64>, Vec<Option<f64>>, u8) {
+    let start = time::Instant::now();
+    let res = sensor.measure_at(now).map(|readings| {
+        let (values, times) = common::align(&sensor.get_names(), &readings);
+        let mut quality = readings.iter().fold(0, |q, r| q | r.quality);
+        if values.iter().any(|v| v.is_nan()) {
+            quality |= common::FAILED;
+        }
+        (values, times, quality)
+    });
+    let ok = matches!(&res, Ok((values, _, _)) if !values.iter().any(|v| v.is_nan()));
+    if let Some(line) = tracker.record(now, start.elapsed().as_secs_f64(), ok) {
+        if summary {
+            eprintln!("{}", line);
+        }
+    }
+    match res {
+        Ok(res) => {
+            health.success(now);
+            res
+        }
+        Err(err) => {
+            eprintln!("Could not measure {}: {}", tracker.name(), err);
+            health.failure(err.to_string());
+            let count = sensor.get_names().len();
+            (vec![f64::NAN; count], vec![None; count], common::FAILED)
+        }
+    }
+}
+
+/// Glob patterns of private columns, and of columns that are public regardless.
+fn get_privacy(cfg: &config::Config) -> privacy::Privacy {
+    let patterns = |key: &str| -> Vec<String> {
+        cfg.data
+            .get("privacy")
+            .and_then(|v| v.get(key))
+            .and_then(|v| v.as_array())
+            .map(|items| {
+                items
+                    .iter()
+                    .map(|p| p.as_str().expect("patterns must be strings.").to_string())
+                    .collect()
+            })
+            .unwrap_or_default()
+    };
+    privacy::Privacy::new(patterns("private"), patterns("public"))
+}
+
+/// The columns each component column is derived from; by default all columns the component sees.
+fn get_derived(sensors: &Loops, headers: &[String]) -> HashMap<String, Vec<String>> {
+    let mut res = HashMap::new();
+    let mut offset = headers.len()
+        - sensors
+            .components
+            .iter()
+            .map(|c| c.get_names().len())
+            .sum::<usize>();
+    for component in &sensors.components {
+        let inputs = component
+            .get_inputs()
+            .unwrap_or_else(|| headers[..offset].to_vec());
+        for name in component.get_names() {
+            res.insert(name, inputs.clone());
+        }
+        offset += component.get_names().len();
+    }
+    res
+}
+
+/// Whether sensors failing validation at startup are run degraded instead of being measured.
+fn get_degraded_mode(cfg: &config::Config) -> bool {
+    cfg.data["general"]
+        .get("degraded_mode")
+        .and_then(|v| v.as_bool())
+        .unwrap_or(false)
+}
+
+/// Measures the i-th sensor of the loop; the columns of degraded sensors are NaN.
+fn measure_active(
+    sensor: &dyn common::Sensor,
+    state: &mut LoopState,
+    i: usize,
+    now: f64,
+) -> (Vec<f64>, Vec<Option<f64>>, u8) {
+    if state.supervisors.get(i).map(|s| !s.is_active()) == Some(true) {
+        let count = sensor.get_names().len();
+        return (vec![f64::NAN; count], vec![None; count], common::FAILED);
+    }
+    let (values, times, quality) = measure(
+        sensor,
+        &mut state.latencies[i],
+        &mut state.health[i],
+        now,
+        state.latency_summary,
+    );
+    // the exchanges leading up to a failure are the interesting ones.
+    let failing = values.iter().any(|v| calibration::is_failure(*v));
+    if failing && !state.failing[i] {
+        debug::dump(&state.debug_dir, state.latencies[i].name(), now);
+    }
+    state.failing[i] = failing;
+    (values, times, quality)
+}
+
+/// Whether to add a column per sensor stating how old its values are; record_staleness is another name for it.
+fn get_age_columns(cfg: &config::Config) -> bool {
+    ["age_columns", "record_staleness"].iter().any(|key| {
+        cfg.data["general"]
+            .get(*key)
+            .and_then(|v| v.as_bool())
+            .unwrap_or(false)
+    })
+}
+
+/// Whether to add a column per sensor with the quality flags of its values.
+fn get_quality_columns(cfg: &config::Config) -> bool {
+    cfg.data["general"]
+        .get("quality_columns")
+        .and_then(|v| v.as_bool())
+        .unwrap_or(false)
+}
+
+/// Time the oldest of the given values was measured; values w/o a timestamp were measured now.
+fn oldest(now: f64, times: &[Option<f64>]) -> f64 {
+    times.iter().map(|t| t.unwrap_or(now)).fold(now, f64::min)
+}
+
+/// How often the health of the sensors is logged (in iterations; 0 for never) and where it is written to.
+struct HealthReporting {
+    summary_every: usize,
+    file: Option<String>,
+}
+
+fn get_health_reporting(cfg: &config::Config) -> HealthReporting {
+    let general = &cfg.data["general"];
+    HealthReporting {
+        summary_every: general
+            .get("health_summary_every")
+            .and_then(|v| v.as_integer())
+            .unwrap_or(0) as usize,
+        file: general
+            .get("health_file")
+            .and_then(|v| v.as_str())
+            .map(|v| v.to_string()),
+    }
+}
+
+/// Logs the health of the sensors every so many iterations and keeps the health file up to date.
+fn report_health(reporting: &HealthReporting, health: &[health::Health], i: usize, now: f64) {
+    if reporting.summary_every > 0 && (i + 1).is_multiple_of(reporting.summary_every) {
+        eprint!("{}", health::summary(health, now));
+    }
+    if let Some(path) = &reporting.file {
+        if let Err(err) = health::save(path, health) {
+            eprintln!("Could not write the health of the sensors: {}", err);
+        }
+    }
+}
+
+/// Sets up all sensors before the loop starts; a sensor that cannot be set up stops the startup, unless sensors may run
+/// degraded.
+fn init_sensors(sensors: &mut Loops, degraded_mode: bool) {
+    let all = sensors
+        .fast_loop
+        .iter_mut()
+        .chain(sensors.slow_loop.iter_mut());
+    for (sensor, name) in all.zip(&sensors.sensor_names) {
+        if let Err(err) = sensor.init() {
+            if !degraded_mode {
+                panic!("could not initialise sensor {}: {}", name, err);
+            }
+            eprintln!(
+                "Could not initialise sensor {}; it will retry when measured: {}",
+                name, err
+            );
+        }
+    }
+}
+
+/// Cleans up all sensors once the loop stopped.
+fn shutdown_sensors(sensors: &mut Loops) {
+    for sensor in sensors
+        .fast_loop
+        .iter_mut()
+        .chain(sensors.slow_loop.iter_mut())
+    {
+        sensor.shutdown();
+    }
+}
+
+/// Runs one iteration of the loop at the given time and returns the resulting row.
+fn iterate(sensors: &mut Loops, state: &mut LoopState, headers: &[String], now: f64) -> Vec<f64> {
+    let count = sensors.fast_loop.len() + sensors.slow_loop.len();
+    if state.latencies.len() != count {
+        state.latencies = (0..count)
+            .map(|i| {
+                let name = sensors.sensor_names.get(i).cloned();
+                latency::Tracker::new(name.unwrap_or_default(), state.latency_window, state.zone)
+            })
+            .collect();
+        state.failing = vec![false; count];
+        state.health = (0..count)
+            .map(|i| health::Health::new(sensors.sensor_names.get(i).cloned().unwrap_or_default()))
+            .collect();
+    }
+    let all = || sensors.fast_loop.iter().chain(sensors.slow_loop.iter());
+    if state.degraded_mode && state.supervisors.is_empty() {
+        state.supervisors = all()
+            .enumerate()
+            .map(|(i, sensor)| {
+                let name = sensors.sensor_names.get(i).cloned();
+                degraded::Supervisor::start(
+                    name.unwrap_or_default(),
+                    state.retry_every,
+                    sensor.as_ref(),
+                )
+            })
+            .collect();
+    } else if state.iteration == 0 {
+        for (supervisor, sensor) in state.supervisors.iter_mut().zip(all()) {
+            supervisor.refresh(sensor.as_ref());
+        }
+    }
+
+    let mut val: Vec<f64> = vec![now];
+    // when each value was measured.
+    let mut times: Vec<f64> = vec![now];
+    let mut ages: Vec<f64> = Vec::new();
+    let mut qualities: Vec<f64> = Vec::new();
+    for (i, sensor) in sensors.fast_loop.iter().enumerate() {
+        let (tmp, tmp_times, quality) = measure_active(sensor.as_ref(), state, i, now);
+        ages.push(now - oldest(now, &tmp_times));
+        qualities.push(quality as f64);
+        times.extend(tmp_times.iter().map(|t| t.unwrap_or(now)));
+        val.extend(tmp);
+    }
+    if state.iteration == 0 {
+        let mut new_cache: Vec<f64> = Vec::new();
+        state.cache_times.clear();
+        state.cache_value_times.clear();
+        state.cache_quality.clear();
+        let offset = sensors.fast_loop.len();
+        for (i, sensor) in sensors.slow_loop.iter().enumerate() {
+            let (tmp, tmp_times, quality) = measure_active(sensor.as_ref(), state, offset + i, now);
+            state.cache_times.push(oldest(now, &tmp_times));
+            state.cache_quality.push(quality);
+            state
+                .cache_value_times
+                .extend(tmp_times.iter().map(|t| t.unwrap_or(now)));
+            new_cache.extend(tmp);
+        }
+        state.cache = new_cache;
+        qualities.extend(state.cache_quality.iter().map(|q| *q as f64));
+    } else {
+        qualities.extend(
+            state
+                .cache_quality
+                .iter()
+                .map(|q| (q | common::CACHED) as f64),
+        );
+    }
+    val.extend(state.cache.iter());
+    times.extend(state.cache_value_times.iter());
+    if state.age_columns {
+        val.extend(ages);
+        val.extend(state.cache_times.iter().map(|t| now - t));
+    }
+    val.extend(state.supervisors.iter().map(|s| s.status()));
+    if state.quality_columns {
+        val.extend(qualities);
+    }
+    for component in &mut sensors.components {
+        let tmp = component.update(&headers[..val.len()], &val);
+        val.extend(tmp);
+    }
+    times.resize(val.len(), now);
+    state.times = times;
+    state.iteration += 1;
+    if state.iteration == state.slow_loop_delay {
+        state.iteration = 0;
+    }
+    val
+}
+
+/// The output rows are written to; a CSV file unless format is 'jsonl', stdout if filename is '-' or sink 'stdout'.
+///
+/// With split_files set, each sensor gets a CSV file of its own instead.
+fn create_output(cfg: &config::Config, path: &str, sensors: &Loops) -> Box<dyn output::Output> {
+    let jsonl = match cfg.data["general"].get("format").and_then(|v| v.as_str()) {
+        None | Some("csv") => false,
+        Some("jsonl") => true,
+        Some(other) => panic!("format must be either 'csv' or 'jsonl'; got: {}.", other),
+    };
+    let stdout = match cfg.data["general"].get("sink").and_then(|v| v.as_str()) {
+        None | Some("file") => path == "-",
+        Some("stdout") => true,
+        Some(other) => panic!("sink must be either 'file' or 'stdout'; got: {}.", other),
+    };
+    let split = cfg.data["general"]
+        .get("split_files")
+        .and_then(|v| v.as_bool())
+        .unwrap_or(false);
+    if split && (stdout || jsonl) {
+        panic!(
+            "split_files requires CSV files; it cannot be combined with format='jsonl' or stdout."
+        );
+    }
+    if split && cfg.data["general"].get("aggregate").is_some() {
+        panic!("split_files cannot be combined with aggregate.");
+    }
+    if split {
+        return Box::new(create_split_output(cfg, path, sensors));
+    }
+    if stdout {
+        let format = if jsonl {
+            None
+        } else {
+            Some(get_csv_format(cfg))
+        };
+        return Box::new(output::StdoutOutput::new(format));
+    }
+    if jsonl {
+        return Box::new(output::JsonlOutput::new(path.to_string()));
+    }
+    Box::new(create_csv_output(cfg, path))
+}
+
+/// A CSV file with the options of the general section.
+fn create_csv_output(cfg: &config::Config, path: &str) -> output::CsvOutput {
+    let on_mismatch = cfg.data["general"]
+        .get("on_header_mismatch")
+        .and_then(|v| v.as_str())
+        .map(output::OnMismatch::parse)
+        .unwrap_or(output::OnMismatch::Fail);
+    output::CsvOutput::new(
+        path.to_string(),
+        on_mismatch,
+        get_timezone(cfg),
+        cfg.data["general"]
+            .get("compress_rotated")
+            .and_then(|v| v.as_bool())
+            .unwrap_or(false),
+        get_csv_format(cfg),
+        cfg.data["general"]
+            .get("flush_every")
+            .and_then(|v| v.as_integer())
+            .unwrap_or(1) as usize,
+        cfg.data["general"]
+            .get("sync")
+            .and_then(|v| v.as_str())
+            .map(output::SyncMode::parse)
+            .unwrap_or(output::SyncMode::None),
+        get_retention(cfg),
+        cfg.data["general"]
+            .get("max_buffered_rows")
+            .and_then(|v| v.as_integer())
+            .unwrap_or(1000) as usize,
+    )
+}
+
+/// A CSV file per sensor, named after it and placed next to the configured filename.
+fn create_split_output(
+    cfg: &config::Config,
+    path: &str,
+    sensors: &Loops,
+) -> split_out::SplitOutput {
+    let fast = sensors.fast_loop.iter().map(|s| (s, false));
+    let slow = sensors.slow_loop.iter().map(|s| (s, true));
+    let files = fast
+        .chain(slow)
+        .zip(&sensors.sensor_names)
+        .map(|((sensor, slow), name)| split_out::SensorFile {
+            columns: sensor.get_names(),
+            slow,
+            output: Box::new(create_csv_output(cfg, &split_out::path(path, name))),
+        })
+        .collect();
+    let slow_loop_delay = cfg.data["general"]
+        .get("slow_loop_delay")
+        .and_then(|v| v.as_integer())
+        .unwrap_or(20) as usize;
+    split_out::SplitOutput::new(files, slow_loop_delay)
+}
+
+/// Limits on the size and age of the data files; set as max_file_size_mb and max_age_days in the general section.
+fn get_retention(cfg: &config::Config) -> Option<retention::Retention> {
+    let get = |key: &str| {
+        cfg.data["general"]
+            .get(key)
+            .and_then(|v| v.as_float().or_else(|| v.as_integer().map(|i| i as f64)))
+    };
+    let max_bytes = get("max_file_size_mb").map(|mb| (mb * 1024.0 * 1024.0) as u64);
+    let max_age = get("max_age_days").map(|days| days * 86400.0);
+    if max_bytes.is_none() && max_age.is_none() {
+        return None;
+    }
+    Some(retention::Retention::new(max_bytes, max_age))
+}
+
+/// Additional output to an InfluxDB v2 bucket; set up in the influxdb section.
+fn create_influx_output(
+    influx_cfg: &toml::value::Table,
+    sensors: &[String],
+) -> influx::InfluxOutput {
+    if !influx_cfg.contains_key("url")
+        || !influx_cfg.contains_key("org")
+        || !influx_cfg.contains_key("bucket")
+        || !influx_cfg.contains_key("token")
+    {
+        panic!(
+            "an InfluxDB output requires the following fields to be set: url, org, bucket, token."
+        );
+    }
+    let text = |key: &str| influx_cfg[key].as_str().unwrap_or_default().to_string();
+    let target = influx::Target {
+        url: text("url"),
+        org: text("org"),
+        bucket: text("bucket"),
+        token: text("token"),
+        measurement: influx_cfg
+            .get("measurement")
+            .and_then(|v| v.as_str())
+            .unwrap_or("open_green_compute")
+            .to_string(),
+    };
+    let max_lines = influx_cfg
+        .get("max_buffered_lines")
+        .and_then(|v| v.as_integer())
+        .unwrap_or(1000) as usize;
+    influx::InfluxOutput::new(target, sensors.to_vec(), max_lines)
+}
+
+/// Additional output to Parquet files; set up in the parquet section.
+#[cfg(feature = "parquet")]
+fn create_parquet_output(
+    cfg: &config::Config,
+    parquet_cfg: &toml::value::Table,
+) -> Box<dyn output::Output> {
+    let path = parquet_cfg
+        .get("path")
+        .and_then(|v| v.as_str())
+        .unwrap_or("data-%Y-%m-%d.parquet");
+    let rows_per_group = parquet_cfg
+        .get("rows_per_group")
+        .and_then(|v| v.as_integer())
+        .unwrap_or(1000) as usize;
+    Box::new(parquet_out::ParquetOutput::new(
+        path.to_string(),
+        get_timezone(cfg),
+        rows_per_group,
+    ))
+}
+
+#[cfg(not(feature = "parquet"))]
+fn create_parquet_output(_: &config::Config, _: &toml::value::Table) -> Box<dyn output::Output> {
+    panic!("writing Parquet files requires the parquet feature.");
+}
+
+/// Additional output to a PostgreSQL (or TimescaleDB) database; set up in the postgres section.
+#[cfg(feature = "postgres")]
+fn create_postgres_output(
+    postgres_cfg: &toml::value::Table,
+    sensors: &[String],
+) -> Box<dyn output::Output> {
+    let dsn = postgres_cfg
+        .get("dsn")
+        .and_then(|v| v.as_str())
+        .expect("a PostgreSQL output requires the following fields to be set: dsn.");
+    let max_rows = postgres_cfg
+        .get("max_buffered_rows")
+        .and_then(|v| v.as_integer())
+        .unwrap_or(1000) as usize;
+    match postgres_out::PostgresOutput::new(dsn, sensors.to_vec(), max_rows) {
+        Ok(output) => Box::new(output),
+        Err(err) => panic!("{}.", err),
+    }
+}
+
+#[cfg(not(feature = "postgres"))]
+fn create_postgres_output(_: &toml::value::Table, _: &[String]) -> Box<dyn output::Output> {
+    panic!("writing to PostgreSQL requires the postgres feature.");
+}
+
+/// Additional output to a statsd daemon; set up in the statsd section.
+fn create_statsd_output(
+    statsd_cfg: &toml::value::Table,
+    sensors: &[String],
+) -> Box<dyn output::Output> {
+    let address = statsd_cfg
+        .get("address")
+        .and_then(|v| v.as_str())
+        .expect("a statsd output requires the following fields to be set: address.");
+    let prefix = statsd_cfg
+        .get("prefix")
+        .and_then(|v| v.as_str())
+        .unwrap_or("ogc");
+    match statsd::StatsdOutput::new(address, prefix, sensors.to_vec()) {
+        Ok(output) => Box::new(output),
+        Err(err) => panic!("{}.", err),
+    }
+}
+
+/// Additional output to pvoutput.org; set up in the pvoutput section.
+///
+/// The fields table maps the status fields (v1, v2, v5 & v6) to columns.
+fn create_pvoutput_output(cfg: &config::Config, pv_cfg: &toml::value::Table) -> pvoutput::PvOutput {
+    let get = |key: &str| pv_cfg.get(key).and_then(|v| v.as_str());
+    let fields = pv_cfg.get("fields").and_then(|v| v.as_table());
+    let (api_key, system_id, fields) = match (get("api_key"), get("system_id"), fields) {
+        (Some(api_key), Some(system_id), Some(fields)) => (api_key, system_id, fields),
+        _ => panic!(
+            "a pvoutput output requires the following fields to be set: api_key, system_id & fields."
+        ),
+    };
+    let mut columns: [Option<String>; 4] = Default::default();
+    for (field, column) in fields {
+        match pvoutput::FIELDS.iter().position(|f| f == field) {
+            Some(i) => columns[i] = column.as_str().map(|c| c.to_string()),
+            None => panic!(
+                "fields of pvoutput must be one of {}; got: {}.",
+                pvoutput::FIELDS.join(", "),
+                field
+            ),
+        }
+    }
+    pvoutput::PvOutput::new(pvoutput::System {
+        url: get("url")
+            .unwrap_or("https://pvoutput.org/service/r2/addstatus.jsp")
+            .to_string(),
+        api_key: api_key.to_string(),
+        system_id: system_id.to_string(),
+        columns,
+        cumulative: pv_cfg
+            .get("cumulative")
+            .and_then(|v| v.as_bool())
+            .unwrap_or(false),
+        zone: get_timezone(cfg),
+    })
+}
+
+/// Additional output to Redis; set up in the redis section.
+///
+/// The password is read from the environment variable named as password_env, to keep it out of the configuration.
+fn create_redis_output(redis_cfg: &toml::value::Table, sensors: &[String]) -> redis::RedisOutput {
+    let address = redis_cfg
+        .get("address")
+        .and_then(|v| v.as_str())
+        .expect("a redis output requires the following fields to be set: address.");
+    let password =
+        redis_cfg
+            .get("password_env")
+            .and_then(|v| v.as_str())
+            .map(|name| match env::var(name) {
+                Ok(password) => password,
+                Err(_) => panic!(
+                    "the environment variable {} set as password_env is not set.",
+                    name
+                ),
+            });
+    let get_int = |key: &str| redis_cfg.get(key).and_then(|v| v.as_integer());
+    let target = redis::Target {
+        address: address.to_string(),
+        password,
+        db: get_int("db"),
+        mode: redis_cfg
+            .get("mode")
+            .and_then(|v| v.as_str())
+            .map(redis::Mode::parse)
+            .unwrap_or(redis::Mode::TimeSeries),
+        retention_ms: (get_int("retention_secs").unwrap_or(0) as u64) * 1000,
+        expire_secs: get_int("expire_secs").unwrap_or(300) as u64,
+    };
+    redis::RedisOutput::new(target, sensors.to_vec())
+}
+
+/// Additional output to an HTTP endpoint; set up in the webhook section.
+///
+/// The bearer token is read from the environment variable named as token_env, to keep it out of the configuration.
+fn create_webhook_output(webhook_cfg: &toml::value::Table) -> webhook::WebhookOutput {
+    let url = webhook_cfg
+        .get("url")
+        .and_then(|v| v.as_str())
+        .expect("a webhook output requires the following fields to be set: url.");
+    let method = match webhook_cfg.get("method").and_then(|v| v.as_str()) {
+        None | Some("POST") => reqwest::Method::POST,
+        Some("PUT") => reqwest::Method::PUT,
+        Some(other) => panic!("method must be either 'POST' or 'PUT'; got: {}.", other),
+    };
+    let headers = webhook_cfg
+        .get("headers")
+        .and_then(|v| v.as_table())
+        .map(|headers| {
+            headers
+                .iter()
+                .map(|(key, value)| (key.clone(), value.as_str().unwrap_or_default().to_string()))
+                .collect()
+        })
+        .unwrap_or_default();
+    let token = webhook_cfg
+        .get("token_env")
+        .and_then(|v| v.as_str())
+        .map(|name| match env::var(name) {
+            Ok(token) => token,
+            Err(_) => panic!(
+                "the environment variable {} set as token_env is not set.",
+                name
+            ),
+        });
+    let endpoint = webhook::Endpoint {
+        url: url.to_string(),
+        method,
+        headers,
+        token,
+        timeout: time::Duration::from_secs_f64(
+            webhook_cfg
+                .get("timeout")
+                .and_then(|v| v.as_float().or_else(|| v.as_integer().map(|i| i as f64)))
+                .unwrap_or(5.0),
+        ),
+    };
+    let max_rows = webhook_cfg
+        .get("max_buffered_rows")
+        .and_then(|v| v.as_integer())
+        .unwrap_or(100) as usize;
+    webhook::WebhookOutput::new(endpoint, max_rows)
+}
+
+/// Additional output to a Prometheus remote_write endpoint; set up in the remote_write section.
+#[cfg(feature = "remote_write")]
+fn create_remote_write_output(
+    remote_cfg: &toml::value::Table,
+    sensors: &[String],
+) -> Box<dyn output::Output> {
+    let url = remote_cfg
+        .get("url")
+        .and_then(|v| v.as_str())
+        .expect("a remote_write output requires the following fields to be set: url.");
+    let credentials = match (
+        remote_cfg.get("username").and_then(|v| v.as_str()),
+        remote_cfg.get("password").and_then(|v| v.as_str()),
+    ) {
+        (Some(user), password) => {
+            Some((user.to_string(), password.unwrap_or_default().to_string()))
+        }
+        _ => None,
+    };
+    let labels = remote_cfg
+        .get("labels")
+        .and_then(|v| v.as_table())
+        .map(|labels| {
+            labels
+                .iter()
+                .map(|(key, value)| (key.clone(), value.as_str().unwrap_or_default().to_string()))
+                .collect()
+        })
+        .unwrap_or_default();
+    let max_rows = remote_cfg
+        .get("max_buffered_rows")
+        .and_then(|v| v.as_integer())
+        .unwrap_or(1000) as usize;
+    let target = remote_write::Target {
+        url: url.to_string(),
+        credentials,
+        labels,
+    };
+    Box::new(remote_write::RemoteWriteOutput::new(
+        target,
+        sensors.to_vec(),
+        max_rows,
+    ))
+}
+
+#[cfg(not(feature = "remote_write"))]
+fn create_remote_write_output(_: &toml::value::Table, _: &[String]) -> Box<dyn output::Output> {
+    panic!("pushing to a remote_write endpoint requires the remote_write feature.");
+}
+
+/// Additional output to a SQLite database; set as sqlite_path in the general section.
+#[cfg(feature = "sqlite")]
+fn create_sqlite_output(path: &str) -> Box<dyn output::Output> {
+    Box::new(sqlite_out::SqliteOutput::new(path))
+}
+
+#[cfg(not(feature = "sqlite"))]
+fn create_sqlite_output(path: &str) -> Box<dyn output::Output> {
+    panic!("writing to {} requires the sqlite feature.", path);
+}
+
+/// Additional output to an MQTT broker; set up in the mqtt section.
+#[cfg(feature = "mqtt")]
+fn create_mqtt_output(
+    mqtt_cfg: &toml::value::Table,
+    sensors: &[String],
+) -> Box<dyn output::Output> {
+    let host = mqtt_cfg
+        .get("host")
+        .and_then(|v| v.as_str())
+        .expect("an MQTT output requires the following fields to be set: host.");
+    let text = |key: &str| mqtt_cfg.get(key).and_then(|v| v.as_str()).map(String::from);
+    let credentials = match (text("username"), text("password")) {
+        (Some(username), Some(password)) => Some((username, password)),
+        (None, None) => None,
+        _ => panic!("an MQTT output requires both username and password to be set, or neither."),
+    };
+    let json = match mqtt_cfg
+        .get("payload")
+        .and_then(|v| v.as_str())
+        .unwrap_or("value")
+    {
+        "value" => false,
+        "json" => true,
+        other => panic!("payload must be either 'value' or 'json'; got: {}.", other),
+    };
+    let broker = mqtt::Broker {
+        host: host.to_string(),
+        port: mqtt_cfg
+            .get("port")
+            .and_then(|v| v.as_integer())
+            .unwrap_or(1883) as u16,
+        client_id: text("client_id").unwrap_or_else(|| "open_green_compute".to_string()),
+        credentials,
+        topic_prefix: text("topic_prefix").unwrap_or_else(|| "open_green_compute".to_string()),
+        retain: mqtt_cfg
+            .get("retain")
+            .and_then(|v| v.as_bool())
+            .unwrap_or(false),
+        json,
+    };
+    Box::new(mqtt::MqttOutput::new(broker, sensors.to_vec()))
+}
+
+#[cfg(not(feature = "mqtt"))]
+fn create_mqtt_output(_: &toml::value::Table, _: &[String]) -> Box<dyn output::Output> {
+    panic!("publishing to an MQTT broker requires the mqtt feature.");
+}
+
+/// Aggregates the columns of the fast loop sensors into windows if aggregate is set in the general section.
+fn get_aggregator(
+    cfg: &config::Config,
+    sensors: &Loops,
+    headers: &[String],
+) -> Option<aggregate::Aggregator> {
+    let aggregate_cfg = cfg.data["general"]
+        .get("aggregate")?
+        .as_table()
+        .expect("aggregate must be a table.");
+    let window = aggregate_cfg
+        .get("window_secs")
+        .and_then(|v| v.as_float().or_else(|| v.as_integer().map(|i| i as f64)))
+        .expect("aggregate requires window_secs to be set.");
+    if window <= 0.0 {
+        panic!("window_secs must be positive; got: {}.", window);
+    }
+    let stats: Vec<aggregate::Stat> = match aggregate_cfg.get("stats") {
+        Some(v) => v
+            .as_array()
+            .expect("stats must be an array.")
+            .iter()
+            .map(|s| aggregate::Stat::parse(s.as_str().expect("stats must be strings.")))
+            .collect(),
+        None => vec![
+            aggregate::Stat::Min,
+            aggregate::Stat::Max,
+            aggregate::Stat::Avg,
+        ],
+    };
+    if stats.is_empty() {
+        panic!("stats must not be empty.");
+    }
+    let fast: usize = sensors.fast_loop.iter().map(|s| s.get_names().len()).sum();
+    let aggregated = (0..headers.len()).map(|i| i >= 1 && i <= fast).collect();
+    Some(aggregate::Aggregator::new(window, stats, aggregated))
+}
+
+/// Which columns an output receives, by the visibility set in its own section; all unless set to public.
+fn get_visibility(visibility: Option<&toml::Value>) -> privacy::Visibility {
+    visibility
+        .and_then(|v| v.as_str())
+        .map(privacy::Visibility::parse)
+        .unwrap_or(privacy::Visibility::All)
+}
+
+/// Instantiates all configured outputs; the file output is always there, the others when their section is set.
+fn get_outputs(
+    cfg: &config::Config,
+    sensors: &Loops,
+) -> Vec<(Box<dyn output::Output>, privacy::Visibility)> {
+    let path = cfg.data["general"]["filename"]
+        .as_str()
+        .unwrap_or("data.csv");
+    let visibility = get_visibility(cfg.data["general"].get("visibility"));
+    let mut outputs = vec![(create_output(cfg, path, sensors), visibility)];
+    if let Some(db) = cfg.data["general"]
+        .get("sqlite_path")
+        .and_then(|v| v.as_str())
+    {
+        outputs.push((create_sqlite_output(db), visibility));
+    }
+    if let Some(influx_cfg) = cfg.data.get("influxdb").and_then(|v| v.as_table()) {
+        let output = create_influx_output(influx_cfg, &sensors.sensor_names);
+        outputs.push((
+            Box::new(output),
+            get_visibility(influx_cfg.get("visibility")),
+        ));
+    }
+    if let Some(mqtt_cfg) = cfg.data.get("mqtt").and_then(|v| v.as_table()) {
+        let output = create_mqtt_output(mqtt_cfg, &sensors.sensor_names);
+        outputs.push((output, get_visibility(mqtt_cfg.get("visibility"))));
+    }
+    if let Some(parquet_cfg) = cfg.data.get("parquet").and_then(|v| v.as_table()) {
+        let output = create_parquet_output(cfg, parquet_cfg);
+        outputs.push((output, get_visibility(parquet_cfg.get("visibility"))));
+    }
+    if let Some(postgres_cfg) = cfg.data.get("postgres").and_then(|v| v.as_table()) {
+        let output = create_postgres_output(postgres_cfg, &sensors.sensor_names);
+        outputs.push((output, get_visibility(postgres_cfg.get("visibility"))));
+    }
+    if let Some(statsd_cfg) = cfg.data.get("statsd").and_then(|v| v.as_table()) {
+        let output = create_statsd_output(statsd_cfg, &sensors.sensor_names);
+        outputs.push((output, get_visibility(statsd_cfg.get("visibility"))));
+    }
+    if let Some(remote_cfg) = cfg.data.get("remote_write").and_then(|v| v.as_table()) {
+        let output = create_remote_write_output(remote_cfg, &sensors.sensor_names);
+        outputs.push((output, get_visibility(remote_cfg.get("visibility"))));
+    }
+    if let Some(pv_cfg) = cfg.data.get("pvoutput").and_then(|v| v.as_table()) {
+        let output = create_pvoutput_output(cfg, pv_cfg);
+        outputs.push((Box::new(output), get_visibility(pv_cfg.get("visibility"))));
+    }
+    if let Some(redis_cfg) = cfg.data.get("redis").and_then(|v| v.as_table()) {
+        let output = create_redis_output(redis_cfg, &sensors.sensor_names);
+        outputs.push((
+            Box::new(output),
+            get_visibility(redis_cfg.get("visibility")),
+        ));
+    }
+    if let Some(webhook_cfg) = cfg.data.get("webhook").and_then(|v| v.as_table()) {
+        let output = create_webhook_output(webhook_cfg);
+        outputs.push((
+            Box::new(output),
+            get_visibility(webhook_cfg.get("visibility")),
+        ));
+    }
+    // the rows of journald components go through here as well, so they respect the visibility.
+    let components = cfg.data["general"]
+        .get("components")
+        .and_then(|v| v.as_array())
+        .map(|v| v.as_slice())
+        .unwrap_or_default();
+    for name in components.iter().filter_map(|v| v.as_str()) {
+        let component_cfg = match cfg.data.get(name).and_then(|v| v.as_table()) {
+            Some(tmp) => tmp,
+            None => continue,
+        };
+        let rows = component_cfg
+            .get("rows")
+            .and_then(|v| v.as_bool())
+            .unwrap_or(false);
+        if component_cfg.get("type").and_then(|v| v.as_str()) == Some("journald") && rows {
+            let output = journal::JournalOutput::new(get_journal(component_cfg));
+            outputs.push((
+                Box::new(output),
+                get_visibility(component_cfg.get("visibility")),
+            ));
+        }
+    }
+    outputs
+}
+
+/// Serves the latest values on /metrics if listen is set in the prometheus section.
+fn get_exporter(cfg: &config::Config, sensors: &Loops) -> Option<prometheus::Exporter> {
+    let listen = cfg
+        .data
+        .get("prometheus")
+        .and_then(|v| v.get("listen"))
+        .and_then(|v| v.as_str())?;
+    let kinds = sensors
+        .sensor_names
+        .iter()
+        .map(|name| {
+            let kind = cfg.data[name]["type"].as_str().unwrap_or_default();
+            let device = cfg.data[name].as_table().and_then(get_device);
+            (name.clone(), kind.to_string(), device.map(String::from))
+        })
+        .collect();
+    match prometheus::Exporter::start(listen, kinds) {
+        Ok(exporter) => Some(exporter),
+        Err(err) => {
+            eprintln!(
+                "Could not listen on {}; not serving metrics: {}",
+                listen, err
+            );
+            None
+        }
+    }
+}
+
+/// Uploads finished data files to an S3 compatible object storage if the s3 section is set.
+///
+/// Covers the file output (or the files of each sensor with split_files) and the Parquet files.
+fn get_uploader(cfg: &config::Config, sensors: &Loops) -> Option<s3::Uploader> {
+    let s3_cfg = cfg.data.get("s3").and_then(|v| v.as_table())?;
+    let get = |key: &str| s3_cfg.get(key).and_then(|v| v.as_str());
+    let credential = |key: &str, var: &str| {
+        get(key)
+            .map(|v| v.to_string())
+            .or_else(|| env::var(var).ok())
+    };
+    let (endpoint, bucket, access_key, secret_key) = match (
+        get("endpoint"),
+        get("bucket"),
+        credential("access_key", "AWS_ACCESS_KEY_ID"),
+        credential("secret_key", "AWS_SECRET_ACCESS_KEY"),
+    ) {
+        (Some(endpoint), Some(bucket), Some(access_key), Some(secret_key)) => {
+            (endpoint, bucket, access_key, secret_key)
+        }
+        _ => panic!(
+            "The s3 section requires the following fields to be set: endpoint, bucket, access_key \
+            & secret_key (or AWS_ACCESS_KEY_ID & AWS_SECRET_ACCESS_KEY)."
+        ),
+    };
+    let delete = match get("after_upload") {
+        None | Some("keep") => false,
+        Some("delete") => true,
+        Some(other) => panic!(
+            "after_upload must be either 'keep' or 'delete'; got: {}.",
+            other
+        ),
+    };
+    let store = s3::Bucket {
+        endpoint: endpoint.to_string(),
+        bucket: bucket.to_string(),
+        region: get("region").unwrap_or("us-east-1").to_string(),
+        access_key,
+        secret_key,
+    };
+
+    let zone = get_timezone(cfg);
+    let path = cfg.data["general"]["filename"]
+        .as_str()
+        .unwrap_or("data.csv");
+    let mut patterns = vec![];
+    if cfg.data["general"]
+        .get("split_files")
+        .and_then(|v| v.as_bool())
+        .unwrap_or(false)
+    {
+        patterns.extend(
+            sensors
+                .sensor_names
+                .iter()
+                .map(|name| split_out::path(path, name)),
+        );
+    } else if path != "-" {
+        patterns.push(path.to_string());
+    }
+    if let Some(parquet_cfg) = cfg.data.get("parquet").and_then(|v| v.as_table()) {
+        let parquet_path = parquet_cfg
+            .get("path")
+            .and_then(|v| v.as_str())
+            .unwrap_or("data-%Y-%m-%d.parquet");
+        patterns.push(parquet_path.to_string());
+    }
+    let sources = patterns
+        .into_iter()
+        .map(|pattern| s3::Source { pattern, zone })
+        .collect();
+
+    let interval = s3_cfg
+        .get("interval_secs")
+        .and_then(|v| v.as_integer())
+        .unwrap_or(300) as u64;
+    Some(s3::Uploader::start(
+        Box::new(store),
+        sources,
+        get("prefix").unwrap_or_default().to_string(),
+        delete,
+        get_state_dir(cfg).to_string(),
+        time::Duration::from_secs(interval),
+    ))
+}
+
+/// Runs the instrumentation loop; forever, or for the given number of iterations.
+fn run(
+    cfg: &config::Config,
+    sensors: &mut Loops,
+    clock: &dyn clock::Clock,
+    iterations: Option<usize>,
+    shutdown: &mpsc::Receiver<()>,
+    collector: &snapshot::Collector,
+) {
+    init_sensors(sensors, get_degraded_mode(cfg));
+
+    // create CSV file if it does not exists...
+    let headers = get_headers(
+        sensors,
+        get_age_columns(cfg),
+        get_degraded_mode(cfg),
+        get_quality_columns(cfg),
+    );
+    let private = get_privacy(cfg).classify(&headers, &get_derived(sensors, &headers));
+    let visibility = get_visibility(cfg.data.get("prometheus").and_then(|v| v.get("visibility")));
+    let outputs = get_outputs(cfg, sensors);
+    let units = get_units(
+        sensors,
+        get_age_columns(cfg),
+        get_degraded_mode(cfg),
+        get_quality_columns(cfg),
+    );
+    // the outputs get a row per window when aggregating; everything else sees every iteration.
+    let mut aggregator = get_aggregator(cfg, sensors, &headers);
+    let (rows, units, row_private) = match &aggregator {
+        Some(aggregator) => (
+            aggregator.headers(&headers),
+            aggregator.expand(&units),
+            aggregator.expand(&private),
+        ),
+        None => (headers.clone(), units, private.clone()),
+    };
+    let units = get_units_header(cfg).then_some(units.as_slice());
+    let mut dispatcher = output::Dispatcher::new(&rows, units, &row_private, outputs);
+    let mut exporter = get_exporter(cfg, sensors);
+    let mut uploader = get_uploader(cfg, sensors);
+    let exported = output::visible(&private, visibility);
+    let exported_names: Vec<String> = exported.iter().map(|i| headers[*i].clone()).collect();
+
+    // the actual instrumentation loop...
+    let timeout = cfg.data["general"]
+        .get("timeout")
+        .and_then(|v| v.as_integer())
+        .unwrap_or(30);
+    let timeout = time::Duration::from_secs(timeout as u64);
+    let mut state = LoopState::new(cfg);
+    let health_cfg = get_health_reporting(cfg);
+    let names = Arc::new(headers.clone());
+    let devices = Arc::new(get_device_labels(cfg, sensors, &headers));
+    let dump = Arc::new(AtomicBool::new(false));
+    if let Err(err) = signal_hook::flag::register(signal_hook::consts::SIGUSR1, dump.clone()) {
+        eprintln!("Could not listen for SIGUSR1: {}", err);
+    }
+    let mut i = 0;
+    while iterations.map(|n| i < n).unwrap_or(true) {
+        // a dropped sender just means nobody is going to ask us to stop.
+        if shutdown.try_recv().is_ok() {
+            eprintln!("Shutting down.");
+            break;
+        }
+        let start = clock.now();
+        if dump.swap(false, Ordering::Relaxed) {
+            debug::dump_all(&state.debug_dir, clock::epoch_secs(start));
+        }
+        let val = iterate(sensors, &mut state, &headers, clock::epoch_secs(start));
+        match &mut aggregator {
+            Some(aggregator) => {
+                if let Some(row) = aggregator.add(&val) {
+                    dispatcher.dispatch(&row);
+                }
+            }
+            None => dispatcher.dispatch_at(&val, &state.times),
+        }
+        if let Some(exporter) = &exporter {
+            let row: Vec<f64> = exported.iter().map(|i| val[*i]).collect();
+            exporter.update(&exported_names, &row, &state.latencies, &state.health);
+        }
+        report_health(&health_cfg, &state.health, i, clock::epoch_secs(start));
+        collector.publish(snapshot::Snapshot::new(
+            i as u64 + 1,
+            names.clone(),
+            val,
+            state.times.clone(),
+            devices.clone(),
+        ));
+        clock.sleep_until(start + timeout);
+        i += 1;
+    }
+    shutdown_sensors(sensors);
+    // the window so far.
+    if let Some(row) = aggregator.as_mut().and_then(|a| a.flush()) {
+        dispatcher.dispatch(&row);
+    }
+    dispatcher.flush();
+    if let Some(exporter) = &mut exporter {
+        exporter.stop();
+    }
+    if let Some(uploader) = &mut uploader {
+        uploader.stop();
+    }
+}
+
+/// Runs the fast & slow loop as configured until something is sent on shutdown.
+///
+/// The values of every row are handed to the collector, to be read from other threads.
+pub fn run_loop(
+    cfg: &config::Config,
+    clock: &dyn clock::Clock,
+    shutdown: mpsc::Receiver<()>,
+    collector: &snapshot::Collector,
+) {
+    run_loop_with(cfg, Vec::new(), clock, shutdown, collector);
+}
+
+/// Runs the loop like run_loop; sensors can also be of the given types, next to the built-in ones.
+pub fn run_loop_with(
+    cfg: &config::Config,
+    sensor_types: Vec<common::SensorType>,
+    clock: &dyn clock::Clock,
+    shutdown: mpsc::Receiver<()>,
+    collector: &snapshot::Collector,
+) {
+    let mut registry = sensor_registry();
+    for sensor_type in sensor_types {
+        registry.register(sensor_type);
+    }
+    let mut sensors = get_sensors_with(cfg, &registry);
+    run(cfg, &mut sensors, clock, None, &shutdown, collector);
+}
+
+/// Entry point of the command line tool; handles the subcommands or runs the loop.
+pub fn cli() {
+    // scanning for devices happens before there is a configuration.
+    if env::args().nth(1).as_deref() == Some("scan") {
+        let args: Vec<String> = env::args().skip(2).collect();
+        return scan(&args);
+    }
+
+    // Load the configuration.
+    let cfg_file: String = env::var("OGC_CONFIG").unwrap_or_else(|_| String::from("defaults.toml"));
+    let cfg = config::load_config(&cfg_file);
+
+    match env::args().nth(1).as_deref() {
+        Some("report") => return report(&cfg),
+        Some("import") => return import(&cfg, env::args().nth(2), env::args().nth(3)),
+        Some("probe") => return probe(&cfg, &env::args().skip(2).collect::<Vec<String>>()),
+        Some("tail") => return tail(&cfg, &env::args().skip(2).collect::<Vec<String>>()),
+        Some("check") => {
+            if !check(&cfg) {
+                process::exit(1);
+            }
+            println!("Configuration looks good.");
+            return;
+        }
+        _ => {}
+    }
+
+    // run until the process gets terminated.
+    let (_shutdown, rx) = mpsc::channel();
+    run_loop(
+        &cfg,
+        &clock::SystemClock {},
+        rx,
+        &snapshot::Collector::new(),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEST_DATA: &str = "[general]\nfast_loop=[\"foo\",\"dummy\"]\nslow_loop=[\"bar\"]\nfilename=\"test.csv\"\n\n[foo]\ntype=\"power\"\nbus=\"\"\naddress=0x40\nexpected_amps=1.0\n\n[bar]\ntype=\"weather\"\nlat=0.0\nlong=0.0\napp_id=123\nurl=\"localhost\"\n\n[dummy]\ntype=\"na\"\n";
+    const FAULTY_DATA: &str = "[general]\nfast_loop=[\"foo\"]\nslow_loop=[\"bar\"]\n\n";
+    const SENSOR_DATA: &str = "[foo]\ntype=\"power\"\nbus=\"\"\naddress=0x40\nexpected_amps=1.0\n\n[bar]\ntype=\"weather\"\nlat=0.0\nlong=0.0\napp_id=123\nurl=\"localhost\"\n";
+    const COMPONENT_DATA: &str = "[general]\nfast_loop=[\"foo\"]\nslow_loop=[]\ncomponents=[\"charger\"]\n\n[foo]\ntype=\"goe\"\nurl=\"localhost\"\n\n[charger]\ntype=\"goe_control\"\nurl=\"localhost\"\nsurplus_column=\"foo_power\"\nphases=3\n";
+    const FAULTY_SENSOR: &str = "[foo]\ntype=\"power\"\n\n[bar]\ntype=\"weather\"\n";
+
+    /// An MQTT sensor whose payloads arrive 40 seconds after they were measured, by the timestamp they carry.
+    fn late() -> mqtt_in::MqttSensor {
+        let hub = Arc::new(mqtt_in::Hub::new());
+        let entry = mqtt_in::Entry {
+            name: "power".to_string(),
+            topic: "late/power".to_string(),
+            pointer: Some("/power".to_string()),
+            timestamp_pointer: Some("/ts".to_string()),
+            max_age: time::Duration::from_secs(300),
+            availability: None,
+        };
+        let sensor = mqtt_in::MqttSensor::new("late".to_string(), vec![entry], hub.clone());
+        hub.handle("late/power", b"{\"ts\":960,\"power\":42}");
+        sensor
+    }
+
+    /// A sensor not knowing when it measured; i.e. now.
+    struct NowSensor {}
+
+    impl common::Sensor for NowSensor {
+        fn get_names(&self) -> Vec<String> {
+            vec!["now_temperature".to_string()]
+        }
+
+        fn get_units(&self) -> Vec<String> {
+            vec!["°C".to_string()]
+        }
+
+        fn measure(&self) -> Result<Vec<common::Reading>, common::SensorError> {
+            Ok(common::readings(self.get_names(), vec![21.0]))
+        }
+    }
+
+    /// A sensor whose board only shows up after the given number of validations.
+    struct FlakySensor {
+        validations: std::cell::Cell<u32>,
+        plugged_after: u32,
+    }
+
+    impl common::Sensor for FlakySensor {
+        fn get_names(&self) -> Vec<String> {
+            vec!["flaky_voltage".to_string(), "flaky_power".to_string()]
+        }
+
+        fn get_units(&self) -> Vec<String> {
+            vec!["V".to_string(), "W".to_string()]
+        }
+
+        fn measure(&self) -> Result<Vec<common::Reading>, common::SensorError> {
+            Ok(common::readings(self.get_names(), vec![12.0, 100.0]))
+        }
+
+        fn validate(&self) -> Result<(), Box<dyn std::error::Error>> {
+            self.validations.set(self.validations.get() + 1);
+            if self.validations.get() > self.plugged_after {
+                Ok(())
+            } else {
+                Err(Box::from("unplugged."))
+            }
+        }
+    }
+
+    /// A HTTP sensor whose service breaks down after the first request.
+    struct BreakingSensor {
+        count: std::cell::Cell<u32>,
+    }
+
+    impl common::Sensor for BreakingSensor {
+        fn get_names(&self) -> Vec<String> {
+            vec!["debug_flaky_power".to_string()]
+        }
+
+        fn get_units(&self) -> Vec<String> {
+            vec!["W".to_string()]
+        }
+
+        fn measure(&self) -> Result<Vec<common::Reading>, common::SensorError> {
+            self.count.set(self.count.get() + 1);
+            let ok = self.count.get() == 1;
+            debug::ring("debug_flaky").record(
+                "http://localhost/power?token=abcdef",
+                Some(if ok { 200 } else { 500 }),
+                if ok { "10" } else { "Internal Server Error" },
+                time::Instant::now(),
+            );
+            if !ok {
+                return Err(common::SensorError::Http(
+                    "Status code was not 200; but: 500.".to_string(),
+                ));
+            }
+            Ok(common::readings(self.get_names(), vec![10.0]))
+        }
+    }
+
+    /// A HTTP sensor whose service is never there.
+    struct FailingSensor {}
+
+    impl common::Sensor for FailingSensor {
+        fn get_names(&self) -> Vec<String> {
+            vec!["down_power".to_string()]
+        }
+
+        fn get_units(&self) -> Vec<String> {
+            vec!["W".to_string()]
+        }
+
+        fn measure(&self) -> Result<Vec<common::Reading>, common::SensorError> {
+            Err(common::SensorError::Http("connection refused.".to_string()))
+        }
+    }
+
+    /// A sensor whose every other measurement times out.
+    struct FlappySensor {
+        calls: std::cell::Cell<u32>,
+    }
+
+    impl common::Sensor for FlappySensor {
+        fn get_names(&self) -> Vec<String> {
+            vec!["flappy_power".to_string()]
+        }
+
+        fn get_units(&self) -> Vec<String> {
+            vec!["W".to_string()]
+        }
+
+        fn measure(&self) -> Result<Vec<common::Reading>, common::SensorError> {
+            self.calls.set(self.calls.get() + 1);
+            if self.calls.get() % 2 == 1 {
+                return Err(common::SensorError::Timeout("no answer.".to_string()));
+            }
+            Ok(common::readings(self.get_names(), vec![5.0]))
+        }
+    }
+
+    /// Alternates between a state of charge of 20% and 80% while discharging at 100 W.
+    struct SocSensor {
+        count: std::cell::Cell<u32>,
+    }
+
+    impl common::Sensor for SocSensor {
+        fn get_names(&self) -> Vec<String> {
+            vec!["bat_power".to_string(), "bat_soc".to_string()]
+        }
+
+        fn get_units(&self) -> Vec<String> {
+            vec!["W".to_string(), "%".to_string()]
+        }
+
+        fn measure(&self) -> Result<Vec<common::Reading>, common::SensorError> {
+            self.count.set(self.count.get() + 1);
+            let soc = if self.count.get().is_multiple_of(2) {
+                80.0
+            } else {
+                20.0
+            };
+            Ok(common::readings(self.get_names(), vec![100.0, soc]))
+        }
+    }
+
+    /// An energy meter whose counter goes up by 50 Wh per measurement; not knowing when it measured.
+    struct MeterSensor {
+        count: std::cell::Cell<u32>,
+    }
+
+    impl common::Sensor for MeterSensor {
+        fn get_names(&self) -> Vec<String> {
+            vec!["meter_energy".to_string()]
+        }
+
+        fn get_units(&self) -> Vec<String> {
+            vec!["Wh".to_string()]
+        }
+
+        fn measure(&self) -> Result<Vec<common::Reading>, common::SensorError> {
+            self.count.set(self.count.get() + 1);
+            let energy = 50.0 * self.count.get() as f64;
+            Ok(common::readings(self.get_names(), vec![energy]))
+        }
+    }
+
+    /// A sensor that cannot log in at startup; counts its shutdowns.
+    struct LockedOutSensor {
+        shutdowns: std::rc::Rc<std::cell::Cell<u32>>,
+    }
+
+    impl common::Sensor for LockedOutSensor {
+        fn get_names(&self) -> Vec<String> {
+            vec!["locked_power".to_string()]
+        }
+
+        fn get_units(&self) -> Vec<String> {
+            vec!["W".to_string()]
+        }
+
+        fn measure(&self) -> Result<Vec<common::Reading>, common::SensorError> {
+            Ok(common::readings(self.get_names(), vec![1.0]))
+        }
+
+        fn init(&mut self) -> Result<(), common::SensorError> {
+            Err(common::SensorError::Protocol("login rejected.".to_string()))
+        }
+
+        fn shutdown(&mut self) {
+            self.shutdowns.set(self.shutdowns.get() + 1);
+        }
+    }
+
+    fn locked_out(shutdowns: std::rc::Rc<std::cell::Cell<u32>>) -> Loops {
+        Loops {
+            fast_loop: vec![Box::new(NowSensor {})],
+            slow_loop: vec![Box::new(LockedOutSensor { shutdowns })],
+            components: Vec::new(),
+            sensor_names: vec!["now".to_string(), "locked".to_string()],
+        }
+    }
+
+    fn setup(filename: &str, data: &str) {
+        fs::write(filename, data).expect("failed to write sample config file.");
+    }
+
+    fn tear_down(filename: &str) {
+        fs::remove_file(filename).expect("failed to delete config file for testing.");
+    }
+
+    // Tests for success.
+
+    #[test]
+    fn test_get_sensors_for_success() {
+        setup("for_testing0.toml", TEST_DATA);
+        let cfg = config::load_config("for_testing0.toml");
+        get_sensors(&cfg);
+        tear_down("for_testing0.toml");
+    }
+
+    #[test]
+    fn test_create_sensors_for_success() {
+        setup("for_testing_0.toml", SENSOR_DATA);
+        let cfg = config::load_config("for_testing_0.toml");
+        create_sensor(
+            &sensor_registry(),
+            "foo",
+            cfg.data["foo"].as_table().unwrap(),
+            "state",
+        );
+        tear_down("for_testing_0.toml");
+    }
+
+    // Tests for failure.
+
+    #[test]
+    #[should_panic]
+    fn test_get_sensors_for_failure() {
+        setup("for_testing1.toml", FAULTY_DATA);
+        let cfg = config::load_config("for_testing1.toml");
+        get_sensors(&cfg);
+        tear_down("for_testing1.toml");
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_create_sensors_foo_for_failure() {
+        setup("for_testing_1.toml", FAULTY_SENSOR);
+        let cfg = config::load_config("for_testing_1.toml");
+        create_sensor(
+            &sensor_registry(),
+            "foo",
+            cfg.data["foo"].as_table().unwrap(),
+            "state",
+        );
+        tear_down("for_testing_1.toml");
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_create_sensors_bar_for_failure() {
+        setup("for_testing_1.toml", FAULTY_SENSOR);
+        let cfg = config::load_config("for_testing_1.toml");
+        create_sensor(
+            &sensor_registry(),
+            "bar",
+            cfg.data["bar"].as_table().unwrap(),
+            "state",
+        );
+        tear_down("for_testing_1.toml");
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_get_calibration_for_failure() {
+        let sensor_cfg: toml::value::Table =
+            toml::from_str("calibration={ current={ offset=0.1 } }").unwrap();
+        get_calibration("plug", &sensor_cfg, &["plug_power".to_string()]);
+    }
+
+    #[test]
+    #[should_panic(expected = "unknown actuator: boiler.")]
+    fn test_get_rules_for_failure() {
+        setup("for_testing_rules0.toml", "[general]\nfast_loop=[]\nslow_loop=[]\n\n[actuators.heater]\ntype=\"exec\"\non=[\"/bin/true\"]\noff=[\"/bin/true\"]\n\n[rules.pv_boiler]\ncolumn=\"pv_power\"\noperator=\">\"\nthreshold=2000\nactuator=\"boiler\"\n");
+        let cfg = config::load_config("for_testing_rules0.toml");
+        tear_down("for_testing_rules0.toml");
+        get_rules(&cfg);
+    }
+
+    #[test]
+    #[should_panic(expected = "stop_signal must be one of 'term' or 'kill'; got: \"hup\".")]
+    fn test_get_jobs_for_failure() {
+        setup("for_testing_jobs0.toml", "[general]\nfast_loop=[]\nslow_loop=[]\n\n[jobs.backup]\ncolumn=\"grid_feedin\"\nthreshold=800\ncommand=[\"/usr/bin/restic\", \"backup\"]\nstop_signal=\"hup\"\n");
+        let cfg = config::load_config("for_testing_jobs0.toml");
+        tear_down("for_testing_jobs0.toml");
+        get_jobs(&cfg);
+    }
+
+    #[test]
+    #[should_panic(expected = "cannot normalise power of sensor flappy from kW: it is in W.")]
+    fn test_get_conversions_for_failure() {
+        let sensor_cfg: toml::value::Table =
+            toml::from_str("units={ power={ unit='kW->W' } }").unwrap();
+        get_conversions(
+            "flappy",
+            &sensor_cfg,
+            &["flappy_power".to_string()],
+            &["W".to_string()],
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "cannot derive bytes: sensor router has no such metric.")]
+    fn test_get_derives_for_failure() {
+        let sensor_cfg: toml::value::Table = toml::from_str("derive={ bytes='rate' }").unwrap();
+        get_derives("router", &sensor_cfg, &["router_rx_bytes".to_string()]);
+    }
+
+    #[test]
+    #[should_panic(
+        expected = "on_error must be one of 'missing', 'zero' or 'hold_last'; got: nan."
+    )]
+    fn test_get_fallback_for_failure() {
+        let sensor_cfg: toml::value::Table = toml::from_str("on_error='nan'").unwrap();
+        get_fallback(&sensor_cfg);
+    }
+
+    #[test]
+    #[should_panic(expected = "retries must not be negative; got: -1.")]
+    fn test_get_retries_for_failure() {
+        let sensor_cfg: toml::value::Table = toml::from_str("retries=-1").unwrap();
+        get_retries(&sensor_cfg);
+    }
+
+    #[test]
+    fn test_check_for_failure() {
+        // a typo; and with it a missing field.
+        setup("for_testing_check.toml", "[general]\nfast_loop=[\"foo\"]\nslow_loop=[]\n\n[foo]\ntype=\"goe\"\nulr=\"localhost\"\ntimeout_secs=5\n");
+        let cfg = config::load_config("for_testing_check.toml");
+        assert!(!check(&cfg));
+        tear_down("for_testing_check.toml");
+    }
+
+    #[test]
+    #[should_panic(expected = "min_current must be a whole number of amps from 6 to 80; got: 6.5.")]
+    fn test_create_component_for_failure() {
+        let component_cfg: toml::value::Table = toml::from_str(
+            "type='goe_control'\nurl='localhost'\nsurplus_column='surplus'\nmin_current=6.5",
+        )
+        .unwrap();
+        create_component("charger", &component_cfg, "state", &[], tz::Zone::Utc);
+    }
+
+    #[test]
+    #[should_panic(expected = "timeout_secs must be positive; got: 0.")]
+    fn test_get_timeout_for_failure() {
+        let sensor_cfg: toml::value::Table = toml::from_str("timeout_secs=0").unwrap();
+        get_timeout(&sensor_cfg);
+    }
+
+    #[test]
+    #[should_panic(
+        expected = "could not initialise sensor locked: protocol error: login rejected."
+    )]
+    fn test_init_sensors_for_failure() {
+        init_sensors(&mut locked_out(Default::default()), false);
+    }
+
+    // Tests for sanity.
+
+    #[test]
+    fn test_get_fallback_for_sanity() {
+        let sensor_cfg: toml::value::Table = toml::from_str("type='foxess'").unwrap();
+        assert_eq!(get_fallback(&sensor_cfg), None);
+        let sensor_cfg: toml::value::Table = toml::from_str("on_error='missing'").unwrap();
+        assert_eq!(get_fallback(&sensor_cfg), None);
+        let sensor_cfg: toml::value::Table = toml::from_str("on_error='zero'").unwrap();
+        assert_eq!(get_fallback(&sensor_cfg), Some(hold::Fallback::Zero));
+        let sensor_cfg: toml::value::Table =
+            toml::from_str("on_error='hold_last'\nhold_max_secs=300").unwrap();
+        assert_eq!(
+            get_fallback(&sensor_cfg),
+            Some(hold::Fallback::HoldLast {
+                max_age: Some(300.0)
+            })
+        );
+    }
+
+    #[test]
+    fn test_get_retries_for_sanity() {
+        let sensor_cfg: toml::value::Table = toml::from_str("type='weather'").unwrap();
+        assert_eq!(get_retries(&sensor_cfg), None);
+        let sensor_cfg: toml::value::Table = toml::from_str("retries=2").unwrap();
+        assert_eq!(
+            get_retries(&sensor_cfg),
+            Some((2, time::Duration::from_millis(500)))
+        );
+        let sensor_cfg: toml::value::Table =
+            toml::from_str("retries=1\nretry_delay_ms=100").unwrap();
+        assert_eq!(
+            get_retries(&sensor_cfg),
+            Some((1, time::Duration::from_millis(100)))
+        );
+    }
+
+    #[test]
+    fn test_get_timeout_for_sanity() {
+        let sensor_cfg: toml::value::Table = toml::from_str("type='dummy'").unwrap();
+        assert_eq!(get_timeout(&sensor_cfg), None);
+        let sensor_cfg: toml::value::Table = toml::from_str("timeout_secs=10").unwrap();
+        assert_eq!(
+            get_timeout(&sensor_cfg),
+            Some(time::Duration::from_secs(10))
+        );
+        let sensor_cfg: toml::value::Table = toml::from_str("timeout_secs=2.5").unwrap();
+        assert_eq!(
+            get_timeout(&sensor_cfg),
+            Some(time::Duration::from_millis(2500))
+        );
+    }
+
+    #[test]
+    fn test_init_sensors_for_sanity() {
+        // sensors running degraded retry when measured; and are shut down like any other.
+        let shutdowns: std::rc::Rc<std::cell::Cell<u32>> = Default::default();
+        let mut sensors = locked_out(shutdowns.clone());
+        init_sensors(&mut sensors, true);
+        shutdown_sensors(&mut sensors);
+        assert_eq!(shutdowns.get(), 1);
+    }
+
+    #[test]
+    fn test_get_sensors_for_sanity() {
+        setup("for_testing2.toml", TEST_DATA);
+        let cfg = config::load_config("for_testing2.toml");
+        let res = get_sensors(&cfg);
+        assert_eq!(res.slow_loop.len(), 1);
+        assert_eq!(res.fast_loop.len(), 1);
+        tear_down("for_testing2.toml");
+    }
+
+    #[test]
+    fn test_get_rules_for_sanity() {
+        setup("for_testing_rules1.toml", "[general]\nfast_loop=[]\nslow_loop=[]\n\n[actuators.heater]\ntype=\"exec\"\non=[\"/bin/true\"]\noff=[\"/bin/true\"]\n\n[rules.pv_heater]\ncolumn=\"pv_power\"\noperator=\">\"\nthreshold=2000\nhysteresis=500\nmin_on_secs=600\nactuator=\"heater\"\n");
+        let cfg = config::load_config("for_testing_rules1.toml");
+        tear_down("for_testing_rules1.toml");
+        let mut rules = get_rules(&cfg).unwrap();
+        let names = vec!["timestamp".to_string(), "pv_power".to_string()];
+        assert_eq!(common::Component::get_names(&rules), vec!["pv_heater_on"]);
+        assert_eq!(
+            common::Component::update(&mut rules, &names, &[1000.0, 2500.0]),
+            vec![1.0]
+        );
+        // held on for 10 minutes.
+        assert_eq!(
+            common::Component::update(&mut rules, &names, &[1030.0, 0.0]),
+            vec![1.0]
+        );
+    }
+
+    #[test]
+    fn test_get_jobs_for_sanity() {
+        setup("for_testing_jobs1.toml", "[general]\nfast_loop=[]\nslow_loop=[]\n\n[jobs.backup]\ncolumn=\"grid_feedin\"\nthreshold=800\nsustain_secs=300\ncommand=[\"/bin/true\"]\n");
+        let cfg = config::load_config("for_testing_jobs1.toml");
+        tear_down("for_testing_jobs1.toml");
+        let mut jobs = get_jobs(&cfg).unwrap();
+        let names = vec!["timestamp".to_string(), "grid_feedin".to_string()];
+        assert_eq!(common::Component::get_names(&jobs), vec!["backup_running"]);
+        assert_eq!(
+            common::Component::update(&mut jobs, &names, &[1000.0, 1000.0]),
+            vec![0.0]
+        );
+        assert_eq!(
+            common::Component::update(&mut jobs, &names, &[1030.0, 1000.0]),
+            vec![0.0]
+        );
+    }
+
+    #[test]
+    fn test_with_units_for_sanity() {
+        let sensor_cfg: toml::value::Table =
+            toml::from_str("on_error='hold_last'\nunits={ power={ unit='W->kW' } }").unwrap();
+        let flappy = FlappySensor {
+            calls: std::cell::Cell::new(0),
+        };
+        let sensor = with_fallback(
+            "flappy",
+            &sensor_cfg,
+            with_units("flappy", &sensor_cfg, Box::new(flappy)),
+        );
+        assert_eq!(sensor.get_units(), vec!["kW"]);
+        // nothing to hold yet.
+        assert!(sensor.measure().is_err());
+        assert_eq!(sensor.measure().unwrap()[0].value, 0.005);
+        // held values were normalised once; not again.
+        let held = sensor.measure().unwrap();
+        assert_eq!(held[0].value, 0.005);
+        assert_eq!(held[0].quality, common::HELD);
+    }
+
+    #[test]
+    fn test_get_derives_for_sanity() {
+        let columns = vec!["plug_energy".to_string(), "plug_power".to_string()];
+        let sensor_cfg: toml::value::Table = toml::from_str("type=\"fritz\"").unwrap();
+        assert!(get_derives("plug", &sensor_cfg, &columns).is_none());
+        let sensor_cfg: toml::value::Table = toml::from_str("derive={ energy='delta' }").unwrap();
+        assert_eq!(
+            get_derives("plug", &sensor_cfg, &columns),
+            Some(vec![Some(rate::Derive::Delta), None])
+        );
+    }
+
+    #[test]
+    fn test_get_calibration_for_sanity() {
+        let columns = vec!["plug_power".to_string(), "plug_voltage".to_string()];
+        let sensor_cfg: toml::value::Table = toml::from_str("type=\"dummy\"").unwrap();
+        assert!(get_calibration("plug", &sensor_cfg, &columns).is_none());
+
+        let sensor_cfg: toml::value::Table =
+            toml::from_str("calibration={ power={ offset=-1.5, gain=0.5 } }").unwrap();
+        let calibration = get_calibration("plug", &sensor_cfg, &columns).unwrap();
+        assert_eq!(calibration.correct(&[11.0, 230.0]), vec![4.0, 230.0]);
+        assert_eq!(calibration.correct(&[-1.0, 230.0]), vec![-2.0, 230.0]);
+        assert!(calibration.correct(&[f64::NAN, 230.0])[0].is_nan());
+
+        let sensor_cfg: toml::value::Table =
+            toml::from_str("calibration={ voltage={ points=[[0, 0], [200, 210.0]] } }").unwrap();
+        let calibration = get_calibration("plug", &sensor_cfg, &columns).unwrap();
+        assert_eq!(calibration.correct(&[11.8, 100.0]), vec![11.8, 105.0]);
+    }
+
+    #[test]
+    fn test_format_probe_for_sanity() {
+        let columns = vec!["plug_power".to_string(), "plug_voltage".to_string()];
+        assert_eq!(
+            format_probe(&columns, &[10.0, 230.0], &[8.2, 230.0]),
+            "plug_power: 8.2 (raw: 10)\nplug_voltage: 230\n"
+        );
+    }
+
+    #[test]
+    fn test_degraded_mode_for_sanity() {
+        let mut sensors = Loops {
+            fast_loop: vec![Box::new(FlakySensor {
+                validations: std::cell::Cell::new(0),
+                plugged_after: 2,
+            })],
+            slow_loop: vec![Box::new(NowSensor {})],
+            components: Vec::new(),
+            sensor_names: vec!["flaky".to_string(), "now".to_string()],
+        };
+        let mut state = LoopState {
+            iteration: 0,
+            slow_loop_delay: 2,
+            age_columns: false,
+            quality_columns: false,
+            cache: Vec::new(),
+            cache_times: Vec::new(),
+            cache_quality: Vec::new(),
+            cache_value_times: Vec::new(),
+            times: Vec::new(),
+            latencies: Vec::new(),
+            latency_window: 3600.0,
+            latency_summary: false,
+            health: Vec::new(),
+            zone: tz::Zone::Utc,
+            degraded_mode: true,
+            retry_every: 2,
+            supervisors: Vec::new(),
+            failing: Vec::new(),
+            debug_dir: "debug".to_string(),
+        };
+        let headers = get_headers(&sensors, false, true, false);
+        assert_eq!(
+            headers,
+            vec![
+                "timestamp",
+                "flaky_voltage",
+                "flaky_power",
+                "now_temperature",
+                "flaky_status",
+                "now_status"
+            ]
+        );
+
+        // unplugged at startup: degraded, while the other sensors are still measured.
+        let res = iterate(&mut sensors, &mut state, &headers, 0.0);
+        assert!(res[1].is_nan() && res[2].is_nan());
+        assert_eq!(res[3..], [21.0, 5.0, 0.0]);
+        // retried every 2nd slow loop refresh (every 4 iterations); the 1st retry fails.
+        for i in 1..8 {
+            let res = iterate(&mut sensors, &mut state, &headers, i as f64 * 30.0);
+            assert!(res[1].is_nan());
+            assert_eq!(res[4], 5.0);
+        }
+        // ... the 2nd promotes it.
+        let res = iterate(&mut sensors, &mut state, &headers, 240.0);
+        assert_eq!(res, vec![240.0, 12.0, 100.0, 21.0, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn test_debug_dump_for_sanity() {
+        let mut sensors = Loops {
+            fast_loop: vec![Box::new(BreakingSensor {
+                count: std::cell::Cell::new(0),
+            })],
+            slow_loop: Vec::new(),
+            components: Vec::new(),
+            sensor_names: vec!["debug_flaky".to_string()],
+        };
+        let mut state = LoopState {
+            iteration: 0,
+            slow_loop_delay: 20,
+            age_columns: false,
+            quality_columns: false,
+            cache: Vec::new(),
+            cache_times: Vec::new(),
+            cache_quality: Vec::new(),
+            cache_value_times: Vec::new(),
+            times: Vec::new(),
+            latencies: Vec::new(),
+            latency_window: 3600.0,
+            latency_summary: false,
+            health: Vec::new(),
+            zone: tz::Zone::Utc,
+            degraded_mode: false,
+            retry_every: 10,
+            supervisors: Vec::new(),
+            failing: Vec::new(),
+            debug_dir: "debug_test2".to_string(),
+        };
+        let headers = get_headers(&sensors, false, false, false);
+        for i in 0..4 {
+            iterate(
+                &mut sensors,
+                &mut state,
+                &headers,
+                1700000000.0 + i as f64 * 30.0,
+            );
+        }
+
+        // dumped once; when it started failing.
+        let files: Vec<_> = fs::read_dir("debug_test2").unwrap().collect();
+        assert_eq!(files.len(), 1);
+        let content = fs::read_to_string("debug_test2/debug_debug_flaky_1700000030.json").unwrap();
+        assert!(content.contains("\"status\":200"));
+        assert!(content.contains("\"status\":500"));
+        assert!(content.contains("token=abcd****"));
+        fs::remove_dir_all("debug_test2").unwrap();
+    }
+
+    #[test]
+    fn test_health_for_sanity() {
+        let mut sensors = Loops {
+            fast_loop: vec![Box::new(FailingSensor {})],
+            slow_loop: vec![Box::new(NowSensor {})],
+            components: Vec::new(),
+            sensor_names: vec!["down".to_string(), "now".to_string()],
+        };
+        let mut state = LoopState {
+            iteration: 0,
+            slow_loop_delay: 2,
+            age_columns: false,
+            quality_columns: false,
+            cache: Vec::new(),
+            cache_times: Vec::new(),
+            cache_quality: Vec::new(),
+            cache_value_times: Vec::new(),
+            times: Vec::new(),
+            latencies: Vec::new(),
+            latency_window: 3600.0,
+            latency_summary: false,
+            health: Vec::new(),
+            zone: tz::Zone::Utc,
+            degraded_mode: false,
+            retry_every: 10,
+            supervisors: Vec::new(),
+            failing: Vec::new(),
+            debug_dir: "debug".to_string(),
+        };
+        let headers = get_headers(&sensors, false, false, false);
+        for i in 1..4 {
+            let res = iterate(&mut sensors, &mut state, &headers, 1000.0 + i as f64 * 10.0);
+            assert!(res[1].is_nan());
+            assert_eq!(state.health[0].consecutive_failures, i);
+            assert_eq!(state.health[0].total_failures, i);
+        }
+        assert!(!state.health[0].is_up());
+        assert_eq!(state.health[0].last_success, None);
+        assert_eq!(
+            state.health[0].last_error.as_deref(),
+            Some("HTTP error: connection refused.")
+        );
+        // the other sensor is unaffected.
+        assert!(state.health[1].is_up());
+        assert_eq!(state.health[1].total_failures, 0);
+        assert!(state.health[1].last_success.is_some());
+    }
+
+    #[test]
+    fn test_iterate_for_sanity() {
+        let mut sensors = Loops {
+            fast_loop: vec![Box::new(late())],
+            slow_loop: vec![Box::new(NowSensor {})],
+            components: Vec::new(),
+            sensor_names: vec!["late".to_string(), "now".to_string()],
+        };
+        let mut state = LoopState {
+            iteration: 0,
+            slow_loop_delay: 2,
+            age_columns: true,
+            quality_columns: false,
+            cache: Vec::new(),
+            cache_times: Vec::new(),
+            cache_quality: Vec::new(),
+            cache_value_times: Vec::new(),
+            times: Vec::new(),
+            latencies: Vec::new(),
+            latency_window: 3600.0,
+            latency_summary: false,
+            health: Vec::new(),
+            zone: tz::Zone::Utc,
+            degraded_mode: false,
+            retry_every: 10,
+            supervisors: Vec::new(),
+            failing: Vec::new(),
+            debug_dir: "debug".to_string(),
+        };
+        let headers = get_headers(&sensors, true, false, false);
+        assert_eq!(
+            headers,
+            vec![
+                "timestamp",
+                "late_power",
+                "now_temperature",
+                "late_age_seconds",
+                "now_age_seconds"
+            ]
+        );
+        // row timestamp stays the loop time; the age reflects the late payload.
+        let res = iterate(&mut sensors, &mut state, &headers, 1000.0);
+        assert_eq!(res, vec![1000.0, 42.0, 21.0, 40.0, 0.0]);
+        // one latency tracker per sensor.
+        assert_eq!(state.latencies.len(), 2);
+        // cached slow loop values age.
+        let res = iterate(&mut sensors, &mut state, &headers, 1030.0);
+        assert_eq!(res, vec![1030.0, 42.0, 21.0, 70.0, 30.0]);
+        // ... until refreshed.
+        let res = iterate(&mut sensors, &mut state, &headers, 1060.0);
+        assert_eq!(res, vec![1060.0, 42.0, 21.0, 100.0, 0.0]);
+
+        state.age_columns = false;
+        let headers = get_headers(&sensors, false, false, false);
+        let res = iterate(&mut sensors, &mut state, &headers, 1090.0);
+        assert_eq!(res, vec![1090.0, 42.0, 21.0]);
+    }
+
+    #[test]
+    fn test_run_for_sanity() {
+        let _ = fs::remove_file("test_run.csv");
+        let _ = fs::remove_dir_all("test_run_state");
+        setup("for_testing4.toml", "[general]\nfast_loop=[]\nslow_loop=[]\nfilename=\"test_run.csv\"\ntimeout=30\nslow_loop_delay=20\n");
+        let cfg = config::load_config("for_testing4.toml");
+        let mut sensors = Loops {
+            fast_loop: vec![Box::new(SocSensor {
+                count: std::cell::Cell::new(0),
+            })],
+            slow_loop: vec![Box::new(NowSensor {})],
+            components: vec![Box::new(battery_stats::BatteryStatsComponent::new(
+                "bat".to_string(),
+                "bat_power".to_string(),
+                "bat_soc".to_string(),
+                1000.0,
+                true,
+                900.0,
+                "test_run_state".to_string(),
+                tz::Zone::Utc,
+            ))],
+            sensor_names: vec!["soc".to_string(), "now".to_string()],
+        };
+
+        // 48 hours starting 2023-11-14 00:00:00 UTC.
+        let clock = clock::SimClock::new(1699920000);
+        let (_shutdown, rx) = mpsc::channel();
+        let collector = snapshot::Collector::new();
+        run(
+            &cfg,
+            &mut sensors,
+            &clock,
+            Some(48 * 3600 / 30),
+            &rx,
+            &collector,
+        );
+        // the collector holds the last row.
+        let latest = collector.latest();
+        assert_eq!(latest.version, 48 * 3600 / 30);
+        assert_eq!(latest.values.len(), latest.names.len());
+        assert_eq!(latest.timestamps.len(), latest.names.len());
+        assert_eq!(latest.values[0], 1699920000.0 + 48.0 * 3600.0 - 30.0);
+
+        let content = fs::read_to_string("test_run.csv").unwrap();
+        assert_eq!(
+            content.lines().next().unwrap(),
+            "timestamp,bat_power,bat_soc,now_temperature,bat_cycles,bat_dod,bat_hours_above_90,bat_hours_below_10"
+        );
+        let rows: Vec<Vec<f64>> = content
+            .lines()
+            .skip(1)
+            .map(|l| l.split(',').map(|v| v.parse().unwrap()).collect())
+            .collect();
+        assert_eq!(rows.len(), 5760);
+        assert_eq!(rows[0][0], 1699920000.0);
+        assert_eq!(rows[5759][0], 1699920000.0 + 5759.0 * 30.0);
+
+        // daily reset of the depth of discharge at midnight.
+        assert_eq!(rows[2879][5], 60.0);
+        assert_eq!(rows[2880][5], 0.0);
+        assert_eq!(rows[2881][5], 60.0);
+        let expected = 5759.0 * 30.0 * 100.0 / 3600.0 / 1000.0;
+        assert!((rows[5759][4] - expected).abs() < 1e-9);
+
+        tear_down("for_testing4.toml");
+        fs::remove_file("test_run.csv").unwrap();
+        fs::remove_dir_all("test_run_state").unwrap();
+    }
+
+    #[test]
+    fn test_run_quality_for_sanity() {
+        let _ = fs::remove_file("test_run_quality.csv");
+        setup("for_testing_quality.toml", "[general]\nfast_loop=[]\nslow_loop=[]\nfilename=\"test_run_quality.csv\"\ntimeout=30\nslow_loop_delay=2\nquality_columns=true\n");
+        let cfg = config::load_config("for_testing_quality.toml");
+        let mut sensors = Loops {
+            fast_loop: vec![
+                Box::new(FailingSensor {}),
+                Box::new(common::RetrySensor::new(
+                    Box::new(FlappySensor {
+                        calls: std::cell::Cell::new(0),
+                    }),
+                    1,
+                    time::Duration::from_millis(1),
+                )),
+                Box::new(NowSensor {}),
+            ],
+            slow_loop: vec![Box::new(late())],
+            components: Vec::new(),
+            sensor_names: vec![
+                "down".to_string(),
+                "flappy".to_string(),
+                "now".to_string(),
+                "late".to_string(),
+            ],
+        };
+        let clock = clock::SimClock::new(1699920000);
+        let (_shutdown, rx) = mpsc::channel();
+        let collector = snapshot::Collector::new();
+        run(&cfg, &mut sensors, &clock, Some(4), &rx, &collector);
+
+        let content = fs::read_to_string("test_run_quality.csv").unwrap();
+        let mut lines = content.lines();
+        assert_eq!(
+            lines.next().unwrap(),
+            "timestamp,down_power,flappy_power,now_temperature,late_power,down_quality,flappy_quality,now_quality,late_quality"
+        );
+        let qualities: Vec<Vec<f64>> = lines
+            .map(|l| l.split(',').skip(5).map(|v| v.parse().unwrap()).collect())
+            .collect();
+        // failed, retried, fresh; the slow loop sensor alternates between fresh and cached.
+        assert_eq!(
+            qualities,
+            vec![
+                vec![8.0, 4.0, 0.0, 0.0],
+                vec![8.0, 4.0, 0.0, 1.0],
+                vec![8.0, 4.0, 0.0, 0.0],
+                vec![8.0, 4.0, 0.0, 1.0],
+            ]
+        );
+
+        tear_down("for_testing_quality.toml");
+        fs::remove_file("test_run_quality.csv").unwrap();
+    }
+
+    #[test]
+    fn test_run_counter_for_sanity() {
+        let _ = fs::remove_file("test_run_counter.csv");
+        setup(
+            "for_testing_counter.toml",
+            "[general]\nfast_loop=[]\nslow_loop=[]\nfilename=\"test_run_counter.csv\"\ntimeout=10\n",
+        );
+        let cfg = config::load_config("for_testing_counter.toml");
+        let mut sensors = Loops {
+            fast_loop: vec![Box::new(rate::CounterSensor::new(
+                Box::new(MeterSensor {
+                    count: std::cell::Cell::new(0),
+                }),
+                vec![Some(rate::Derive::Rate)],
+            ))],
+            slow_loop: Vec::new(),
+            components: Vec::new(),
+            sensor_names: vec!["meter".to_string()],
+        };
+        let clock = clock::SimClock::new(1699920000);
+        let (_shutdown, rx) = mpsc::channel();
+        run(
+            &cfg,
+            &mut sensors,
+            &clock,
+            Some(3),
+            &rx,
+            &snapshot::Collector::new(),
+        );
+
+        // the rate is taken over the 10 secs of the simulated clock; not the few µs it really took.
+        let content = fs::read_to_string("test_run_counter.csv").unwrap();
+        let rates: Vec<&str> = content
+            .lines()
+            .skip(2)
+            .map(|l| l.split(',').nth(1).unwrap())
+            .collect();
+        assert_eq!(rates, vec!["5", "5"]);
+
+        tear_down("for_testing_counter.toml");
+        fs::remove_file("test_run_counter.csv").unwrap();
+    }
+
+    #[test]
+    fn test_run_public_for_sanity() {
+        let _ = fs::remove_file("test_run_public.csv");
+        setup("for_testing6.toml", "[general]\nfast_loop=[]\nslow_loop=[]\nfilename=\"test_run_public.csv\"\nvisibility=\"public\"\n\n[privacy]\nprivate=[\"bat_p*\"]\n");
+        let cfg = config::load_config("for_testing6.toml");
+        let mut sensors = Loops {
+            fast_loop: vec![Box::new(SocSensor {
+                count: std::cell::Cell::new(0),
+            })],
+            slow_loop: vec![Box::new(NowSensor {})],
+            components: vec![
+                Box::new(forecast::ForecastComponent::new(
+                    "bat_soc".to_string(),
+                    0.5,
+                    0.1,
+                    300.0,
+                    10,
+                )),
+                // derived from the private power column.
+                Box::new(battery_stats::BatteryStatsComponent::new(
+                    "stats".to_string(),
+                    "bat_power".to_string(),
+                    "bat_soc".to_string(),
+                    1000.0,
+                    true,
+                    900.0,
+                    "test_run_public_state".to_string(),
+                    tz::Zone::Utc,
+                )),
+            ],
+            sensor_names: vec!["soc".to_string(), "now".to_string()],
+        };
+        let (_shutdown, rx) = mpsc::channel();
+        run(
+            &cfg,
+            &mut sensors,
+            &clock::SimClock::new(1699920000),
+            Some(3),
+            &rx,
+            &snapshot::Collector::new(),
+        );
+
+        let content = fs::read_to_string("test_run_public.csv").unwrap();
+        assert_eq!(
+            content.lines().next().unwrap(),
+            "timestamp,bat_soc,now_temperature,bat_soc_forecast_5m,bat_soc_forecast_15m"
+        );
+        assert_eq!(content.lines().count(), 4);
+        assert!(content.lines().all(|l| l.split(',').count() == 5));
+
+        tear_down("for_testing6.toml");
+        fs::remove_file("test_run_public.csv").unwrap();
+        let _ = fs::remove_dir_all("test_run_public_state");
+    }
+
+    #[test]
+    fn test_derived_for_sanity() {
+        setup("for_testing_derived.toml", "[general]\nfast_loop=[\"fox0\"]\nslow_loop=[\"grid\"]\ncomponents=[\"self_consumption\", \"house_net\"]\n\n[fox0]\ntype=\"dummy\"\nvalues={ generationPower=3.5, feedinPower=1.25 }\n\n[grid]\ntype=\"dummy\"\nvalues={ power=-0.5 }\n\n[self_consumption]\ntype=\"derived\"\nexpression=\"fox0_generationPower - fox0_feedinPower\"\n\n[house_net]\ntype=\"derived\"\nexpression=\"max(grid_power, 0) + self_consumption / fox0_missing\"\n");
+        let cfg = config::load_config("for_testing_derived.toml");
+        let mut sensors = get_sensors(&cfg);
+        let mut state = LoopState::new(&cfg);
+        let headers = get_headers(&sensors, false, false, false);
+        assert_eq!(headers[4..], ["self_consumption", "house_net"]);
+        // evaluated after all sensors; a missing column makes for a missing value.
+        let res = iterate(&mut sensors, &mut state, &headers, 1000.0);
+        assert_eq!(res[..4], [1000.0, 1.25, 3.5, -0.5]);
+        assert_eq!(res[4], 2.25);
+        assert!(res[5].is_nan());
+        tear_down("for_testing_derived.toml");
+    }
+
+    #[test]
+    fn test_run_aggregate_for_sanity() {
+        let _ = fs::remove_file("test_run_aggregate.csv");
+        setup("for_testing_aggregate.toml", "[general]\nfast_loop=[]\nslow_loop=[]\nfilename=\"test_run_aggregate.csv\"\ntimeout=20\naggregate={ window_secs=60, stats=[\"min\", \"max\", \"avg\"] }\n");
+        let cfg = config::load_config("for_testing_aggregate.toml");
+        let mut sensors = Loops {
+            fast_loop: vec![Box::new(SocSensor {
+                count: std::cell::Cell::new(0),
+            })],
+            slow_loop: vec![Box::new(NowSensor {})],
+            components: Vec::new(),
+            sensor_names: vec!["bat".to_string(), "now".to_string()],
+        };
+        let (_shutdown, rx) = mpsc::channel();
+        run(
+            &cfg,
+            &mut sensors,
+            &clock::SimClock::new(1699920000),
+            Some(6),
+            &rx,
+            &snapshot::Collector::new(),
+        );
+
+        // 3 samples per window; the last one is written on shutdown.
+        let content = fs::read_to_string("test_run_aggregate.csv").unwrap();
+        assert_eq!(
+            content.lines().collect::<Vec<_>>(),
+            vec![
+                "timestamp,bat_power_min,bat_power_max,bat_power_avg,bat_soc_min,bat_soc_max,bat_soc_avg,now_temperature",
+                "1699920000,100,100,100,20,80,40,21",
+                "1699920060,100,100,100,20,80,60,21",
+            ]
+        );
+
+        tear_down("for_testing_aggregate.toml");
+        fs::remove_file("test_run_aggregate.csv").unwrap();
+    }
+
+    #[test]
+    fn test_device_for_sanity() {
+        let _ = fs::remove_file("test_run_device.csv");
+        setup("for_testing7.toml", "[general]\nfast_loop=[\"bat_soc\", \"bat_temp\", \"pv\"]\nslow_loop=[]\nfilename=\"test_run_device.csv\"\ndegraded_mode=true\n\n[devices]\ntest_battery={ enabled=false }\n\n[bat_soc]\ntype=\"dummy\"\ndevice=\"test_battery\"\nvalues={ soc=80.0 }\n\n[bat_temp]\ntype=\"dummy\"\ndevice=\"test_battery\"\nvalues={ temperature=12.5 }\n\n[pv]\ntype=\"dummy\"\nvalues={ power=1500.0 }\n");
+        let cfg = config::load_config("for_testing7.toml");
+        assert_eq!(
+            get_members(&cfg, "test_battery"),
+            vec!["bat_soc", "bat_temp"]
+        );
+        let mut sensors = get_sensors(&cfg);
+        let (_shutdown, rx) = mpsc::channel();
+        let collector = snapshot::Collector::new();
+        run(
+            &cfg,
+            &mut sensors,
+            &clock::SimClock::new(1699920000),
+            Some(2),
+            &rx,
+            &collector,
+        );
+
+        // the columns of a disabled device stay, but are empty.
+        let content = fs::read_to_string("test_run_device.csv").unwrap();
+        assert_eq!(
+            content.lines().next().unwrap(),
+            "timestamp,bat_soc_soc,bat_temp_temperature,pv_power,bat_soc_status,bat_temp_status,pv_status"
+        );
+        assert_eq!(content.lines().nth(2).unwrap(), "1699920030,,,1500,0,0,0");
+
+        // ... and are labeled with their device.
+        let latest = collector.latest();
+        let members: Vec<&str> = latest
+            .device("test_battery")
+            .iter()
+            .map(|(n, _)| *n)
+            .collect();
+        assert_eq!(
+            members,
+            vec![
+                "bat_soc_soc",
+                "bat_temp_temperature",
+                "bat_soc_status",
+                "bat_temp_status"
+            ]
+        );
+        assert_eq!(latest.devices[3], None);
+
+        // enabled at runtime; all members measure again.
+        assert!(device::set_enabled("test_battery", true));
+        run(
+            &cfg,
+            &mut sensors,
+            &clock::SimClock::new(1699920060),
+            Some(1),
+            &rx,
+            &collector,
+        );
+        assert_eq!(collector.latest().get("bat_temp_temperature"), Some(12.5));
+
+        tear_down("for_testing7.toml");
+        fs::remove_file("test_run_device.csv").unwrap();
+    }
+
+    #[test]
+    fn test_jsonl_for_sanity() {
+        let _ = fs::remove_file("test_run.jsonl");
+        setup("for_testing8.toml", "[general]\nfast_loop=[\"pv\"]\nslow_loop=[]\nfilename=\"test_run.jsonl\"\nformat=\"jsonl\"\n\n[pv]\ntype=\"dummy\"\nvalues={ power=1500.0 }\n");
+        let cfg = config::load_config("for_testing8.toml");
+        let mut sensors = get_sensors(&cfg);
+        let (_shutdown, rx) = mpsc::channel();
+        run(
+            &cfg,
+            &mut sensors,
+            &clock::SimClock::new(1699920000),
+            Some(2),
+            &rx,
+            &snapshot::Collector::new(),
+        );
+
+        // no header; one object per row.
+        let content = fs::read_to_string("test_run.jsonl").unwrap();
+        assert_eq!(content.lines().count(), 2);
+        let row: serde_json::Value = serde_json::from_str(content.lines().nth(1).unwrap()).unwrap();
+        assert_eq!(
+            row,
+            serde_json::json!({"timestamp": 1699920030.0, "pv_power": 1500.0})
+        );
+
+        tear_down("for_testing8.toml");
+        fs::remove_file("test_run.jsonl").unwrap();
+    }
+
+    #[test]
+    fn test_get_outputs_for_sanity() {
+        setup("for_testing9.toml", "[general]\nfast_loop=[\"pv\"]\nslow_loop=[]\nfilename=\"test_outputs.csv\"\n\n[pv]\ntype=\"dummy\"\nvalues={ power=1500.0 }\n\n[influxdb]\nurl=\"http://localhost:1\"\norg=\"home\"\nbucket=\"solar\"\ntoken=\"abc\"\nvisibility=\"public\"\n");
+        let cfg = config::load_config("for_testing9.toml");
+        let mut sensors = get_sensors(&cfg);
+        // the file and the database, side by side; each with the visibility of its own section.
+        let visibilities: Vec<privacy::Visibility> = get_outputs(&cfg, &sensors)
+            .into_iter()
+            .map(|(_, v)| v)
+            .collect();
+        assert_eq!(
+            visibilities,
+            vec![privacy::Visibility::All, privacy::Visibility::Public]
+        );
+
+        // the database is unreachable; the file still gets every row.
+        let (_shutdown, rx) = mpsc::channel();
+        run(
+            &cfg,
+            &mut sensors,
+            &clock::SimClock::new(1699920000),
+            Some(2),
+            &rx,
+            &snapshot::Collector::new(),
+        );
+        let content = fs::read_to_string("test_outputs.csv").unwrap();
+        assert_eq!(content.lines().count(), 3);
+
+        tear_down("for_testing9.toml");
+        fs::remove_file("test_outputs.csv").unwrap();
+    }
+
+    #[test]
+    fn test_get_derived_for_sanity() {
+        let sensors = Loops {
+            fast_loop: Vec::new(),
+            slow_loop: vec![Box::new(NowSensor {})],
+            components: vec![
+                Box::new(forecast::ForecastComponent::new(
+                    "now_temperature".to_string(),
+                    0.5,
+                    0.1,
+                    300.0,
+                    10,
+                )),
+                // also looks at whether a row is suspect; so depends on everything before it.
+                Box::new(evse::GoeController::new(
+                    "charger".to_string(),
+                    "localhost".to_string(),
+                    "now_temperature".to_string(),
+                    evse::CurrentLimiter::new(6, 16, 3.0, 230.0, 3),
+                )),
+            ],
+            sensor_names: vec!["now".to_string()],
+        };
+        let headers = get_headers(&sensors, false, false, false);
+        let derived = get_derived(&sensors, &headers);
+        assert_eq!(derived.len(), 3);
+        assert_eq!(derived["charger_current_limit"], headers[..4].to_vec());
+        assert_eq!(
+            derived["now_temperature_forecast_5m"],
+            vec!["now_temperature"]
+        );
+        assert_eq!(
+            derived["now_temperature_forecast_15m"],
+            vec!["now_temperature"]
+        );
+    }
+
+    #[cfg(feature = "scripting")]
+    #[test]
+    fn test_script_headers_for_sanity() {
+        fs::write(
+            "script_test0.rhai",
+            "fn columns() { [\"surplus\"] }\nfn row(row) { [row.foo_power - 100.0] }",
+        )
+        .unwrap();
+        setup("for_testing5.toml", "[general]\nfast_loop=[\"foo\"]\nslow_loop=[]\ncomponents=[\"derived\"]\n\n[foo]\ntype=\"goe\"\nurl=\"localhost\"\ntransform=\"script_test0.rhai\"\n\n[derived]\ntype=\"script\"\npath=\"script_test0.rhai\"\n");
+        let cfg = config::load_config("for_testing5.toml");
+        assert!(check(&cfg));
+        let sensors = get_sensors(&cfg);
+        assert_eq!(
+            get_headers(&sensors, false, false, false),
+            vec![
+                "timestamp",
+                "foo_power",
+                "foo_session_energy",
+                "foo_connected",
+                "foo_charging",
+                "surplus"
+            ]
+        );
+        tear_down("for_testing5.toml");
+        fs::remove_file("script_test0.rhai").unwrap();
+    }
+
+    #[test]
+    fn test_get_headers_for_sanity() {
+        setup("for_testing3.toml", COMPONENT_DATA);
+        let cfg = config::load_config("for_testing3.toml");
+        let res = get_sensors(&cfg);
+        assert_eq!(res.components.len(), 1);
+        assert_eq!(
+            get_headers(&res, false, false, false),
+            vec![
+                "timestamp",
+                "foo_power",
+                "foo_session_energy",
+                "foo_connected",
+                "foo_charging",
+                "charger_current_limit"
+            ]
+        );
+        tear_down("for_testing3.toml");
+    }
+
+    #[test]
+    fn test_get_units_for_sanity() {
+        setup("for_testing_units.toml", COMPONENT_DATA);
+        let cfg = config::load_config("for_testing_units.toml");
+        let res = get_sensors(&cfg);
+        let units = get_units(&res, true, false, false);
+        assert_eq!(units.len(), get_headers(&res, true, false, false).len());
+        assert_eq!(units, vec!["s", "W", "Wh", "", "", "s", ""]);
+        assert!(!get_units_header(&cfg));
+        tear_down("for_testing_units.toml");
+    }
+
+    #[test]
+    fn test_get_age_columns_for_sanity() {
+        setup(
+            "for_testing_staleness.toml",
+            "[general]\nfast_loop=[]\nslow_loop=[]\nrecord_staleness=true\n",
+        );
+        let cfg = config::load_config("for_testing_staleness.toml");
+        assert!(get_age_columns(&cfg));
+        tear_down("for_testing_staleness.toml");
+
+        setup("for_testing_staleness.toml", TEST_DATA);
+        let cfg = config::load_config("for_testing_staleness.toml");
+        assert!(!get_age_columns(&cfg));
+        tear_down("for_testing_staleness.toml");
+    }
+}
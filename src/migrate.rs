@@ -0,0 +1,230 @@
+use std::fs;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+
+use crate::csv_out;
+
+/// Where each existing column goes among the configured ones.
+///
+/// Fails with a diff of the two headers if the existing columns are not all configured, in the same order.
+pub(crate) fn plan(existing: &[String], configured: &[String]) -> Result<Vec<usize>, String> {
+    let mut positions = Vec::new();
+    let mut next = 0;
+    for column in existing {
+        match configured[next..].iter().position(|c| c == column) {
+            Some(i) => {
+                positions.push(next + i);
+                next += i + 1;
+            }
+            None => return Err(diff(existing, configured)),
+        }
+    }
+    Ok(positions)
+}
+
+/// The changes from the existing to the configured columns; one per line, '-' for removed and '+' for added ones.
+fn diff(existing: &[String], configured: &[String]) -> String {
+    // longest common subsequence; columns are few.
+    let (n, m) = (existing.len(), configured.len());
+    let mut lcs = vec![vec![0; m + 1]; n + 1];
+    for i in (0..n).rev() {
+        for j in (0..m).rev() {
+            lcs[i][j] = if existing[i] == configured[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+    let mut lines = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < n || j < m {
+        if i < n && j < m && existing[i] == configured[j] {
+            lines.push(format!("  {}", existing[i]));
+            i += 1;
+            j += 1;
+        } else if j < m && (i == n || lcs[i][j + 1] >= lcs[i + 1][j]) {
+            lines.push(format!("+ {}", configured[j]));
+            j += 1;
+        } else {
+            lines.push(format!("- {}", existing[i]));
+            i += 1;
+        }
+    }
+    lines.join("\n")
+}
+
+/// Rewrites a CSV file with the given header, moving the fields of each row to their positions and padding the rows
+/// with missing values; returns the number of rows.
+///
+/// An existing line of units is replaced by the given one, or dropped if there is none.
+///
+/// The file is written under a temporary name and synced before it replaces the original, so a crash or power cut
+/// leaves either the original or the migrated file.
+pub(crate) fn rewrite(
+    path: &str,
+    format: &csv_out::Format,
+    header: &str,
+    units: Option<&str>,
+    positions: &[usize],
+) -> io::Result<usize> {
+    let width = format.split(header).len();
+    let tmp = format!("{}.migrating", path);
+    let res: io::Result<usize> = (|| {
+        let mut lines = BufReader::new(fs::File::open(path)?).lines();
+        let mut writer = BufWriter::new(fs::File::create(&tmp)?);
+        // the old header.
+        lines.next().transpose()?;
+        writeln!(writer, "{}", header)?;
+        if let Some(units) = units {
+            writeln!(writer, "{}", units)?;
+        }
+        let mut rows = 0;
+        for line in lines {
+            let line = line?;
+            if rows == 0 && !is_row(format, &line) {
+                // the old units; replaced by the new ones, or dropped.
+                continue;
+            }
+            let mut fields = vec![format.missing(); width];
+            for (field, position) in line.split(format.delimiter()).zip(positions) {
+                fields[*position] = field.to_string();
+            }
+            writeln!(writer, "{}", fields.join(&format.delimiter().to_string()))?;
+            rows += 1;
+        }
+        writer.into_inner()?.sync_all()?;
+        fs::rename(&tmp, path)?;
+        // make the rename itself durable.
+        let dir = match Path::new(path).parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        fs::File::open(dir)?.sync_all()?;
+        Ok(rows)
+    })();
+    if res.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    res
+}
+
+/// Whether a line holds values rather than units; rows start with their timestamp.
+fn is_row(format: &csv_out::Format, line: &str) -> bool {
+    format
+        .split(line)
+        .first()
+        .map(|field| field.trim().parse::<f64>().is_ok())
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn columns(names: &[&str]) -> Vec<String> {
+        names.iter().map(|n| n.to_string()).collect()
+    }
+
+    // Tests for success.
+
+    #[test]
+    fn test_plan_for_success() {
+        assert_eq!(
+            plan(
+                &columns(&["timestamp", "pv_power"]),
+                &columns(&["timestamp", "fritz_power", "pv_power", "bat_soc"])
+            ),
+            Ok(vec![0, 2])
+        );
+        assert_eq!(
+            plan(
+                &columns(&["timestamp", "pv_power"]),
+                &columns(&["timestamp", "pv_power"])
+            ),
+            Ok(vec![0, 1])
+        );
+    }
+
+    // Tests for failure.
+
+    #[test]
+    fn test_plan_for_failure() {
+        // removed.
+        assert_eq!(
+            plan(
+                &columns(&["timestamp", "pv_power", "weather_temp"]),
+                &columns(&["timestamp", "pv_power", "fritz_power"])
+            ),
+            Err("  timestamp\n  pv_power\n+ fritz_power\n- weather_temp".to_string())
+        );
+        // reordered.
+        assert_eq!(
+            plan(
+                &columns(&["timestamp", "pv_power", "fritz_power"]),
+                &columns(&["timestamp", "fritz_power", "pv_power"])
+            ),
+            Err("  timestamp\n+ fritz_power\n  pv_power\n- fritz_power".to_string())
+        );
+    }
+
+    #[test]
+    fn test_rewrite_for_failure() {
+        // nothing to migrate; nothing left behind.
+        let format = csv_out::Format::default();
+        assert!(rewrite("migrate_test0.csv", &format, "timestamp", None, &[0]).is_err());
+        assert!(fs::metadata("migrate_test0.csv.migrating").is_err());
+    }
+
+    // Tests for sanity.
+
+    #[test]
+    fn test_rewrite_for_sanity() {
+        fs::write(
+            "migrate_test1.csv",
+            "\"timestamp\";\"pv_power\"\n\"1\";\"100\"\n\"2\";\"\"\n",
+        )
+        .unwrap();
+        let format = csv_out::Format::new(";", None, true, None, "NaN".to_string());
+        let header = "\"timestamp\";\"fritz_power\";\"pv_power\"";
+        assert_eq!(
+            rewrite("migrate_test1.csv", &format, header, None, &[0, 2]).unwrap(),
+            2
+        );
+        assert_eq!(
+            fs::read_to_string("migrate_test1.csv").unwrap(),
+            "\"timestamp\";\"fritz_power\";\"pv_power\"\n\"1\";\"NaN\";\"100\"\n\"2\";\"NaN\";\"\"\n"
+        );
+        assert!(fs::metadata("migrate_test1.csv.migrating").is_err());
+        fs::remove_file("migrate_test1.csv").unwrap();
+    }
+
+    #[test]
+    fn test_rewrite_units_for_sanity() {
+        fs::write(
+            "migrate_test2.csv",
+            "timestamp,pv_power
+s,W
+1,100
+",
+        )
+        .unwrap();
+        let format = csv_out::Format::default();
+        assert_eq!(
+            rewrite(
+                "migrate_test2.csv",
+                &format,
+                "timestamp,fritz_power,pv_power",
+                Some("s,mW,W"),
+                &[0, 2]
+            )
+            .unwrap(),
+            1
+        );
+        assert_eq!(
+            fs::read_to_string("migrate_test2.csv").unwrap(),
+            "timestamp,fritz_power,pv_power\ns,mW,W\n1,,100\n"
+        );
+        fs::remove_file("migrate_test2.csv").unwrap();
+    }
+}
@@ -0,0 +1,474 @@
+use crate::common::{ConfigError, Reading, SensorError};
+
+/// The most registers a single read may ask for.
+pub(crate) const MAX_COUNT: u16 = 125;
+
+/// Function codes of the register tables.
+pub(crate) const READ_HOLDING_REGISTERS: u8 = 3;
+pub(crate) const READ_INPUT_REGISTERS: u8 = 4;
+
+/// The types of values, as stored in one or two registers.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Kind {
+    U16,
+    I16,
+    U32,
+    I32,
+    F32,
+}
+
+impl Kind {
+    fn parse(text: &str) -> Option<Kind> {
+        match text {
+            "u16" => Some(Kind::U16),
+            "i16" => Some(Kind::I16),
+            "u32" => Some(Kind::U32),
+            "i32" => Some(Kind::I32),
+            "f32" => Some(Kind::F32),
+            _ => None,
+        }
+    }
+
+    /// The number of registers a value takes.
+    pub(crate) fn count(self) -> u16 {
+        match self {
+            Kind::U16 | Kind::I16 => 1,
+            Kind::U32 | Kind::I32 | Kind::F32 => 2,
+        }
+    }
+}
+
+/// The order the bytes of a value are sent in, A being the most significant; 16 bit values use the first two.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum ByteOrder {
+    /// Big endian, as the standard has it.
+    Abcd,
+    /// Big endian bytes, but the low word first; common for 32 bit values.
+    Cdab,
+    /// Little endian bytes in big endian words.
+    Badc,
+    /// Little endian.
+    Dcba,
+}
+
+impl ByteOrder {
+    fn parse(text: &str) -> Option<ByteOrder> {
+        match text.to_uppercase().as_str() {
+            "ABCD" => Some(ByteOrder::Abcd),
+            "CDAB" => Some(ByteOrder::Cdab),
+            "BADC" => Some(ByteOrder::Badc),
+            "DCBA" => Some(ByteOrder::Dcba),
+            _ => None,
+        }
+    }
+}
+
+/// The value stored in the registers, given as received.
+pub(crate) fn decode(registers: &[u16], kind: Kind, order: ByteOrder) -> f64 {
+    let bytes: Vec<u8> = registers.iter().flat_map(|r| r.to_be_bytes()).collect();
+    if kind.count() == 1 {
+        let value = match order {
+            ByteOrder::Abcd | ByteOrder::Cdab => [bytes[0], bytes[1]],
+            ByteOrder::Badc | ByteOrder::Dcba => [bytes[1], bytes[0]],
+        };
+        return match kind {
+            Kind::I16 => i16::from_be_bytes(value) as f64,
+            _ => u16::from_be_bytes(value) as f64,
+        };
+    }
+    // each order is its own inverse.
+    let value = match order {
+        ByteOrder::Abcd => [bytes[0], bytes[1], bytes[2], bytes[3]],
+        ByteOrder::Cdab => [bytes[2], bytes[3], bytes[0], bytes[1]],
+        ByteOrder::Badc => [bytes[1], bytes[0], bytes[3], bytes[2]],
+        ByteOrder::Dcba => [bytes[3], bytes[2], bytes[1], bytes[0]],
+    };
+    match kind {
+        Kind::I32 => i32::from_be_bytes(value) as f64,
+        Kind::F32 => f32::from_be_bytes(value) as f64,
+        _ => u32::from_be_bytes(value) as f64,
+    }
+}
+
+/// A value read from registers.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Entry {
+    pub(crate) name: String,
+    pub(crate) unit_id: u8,
+    pub(crate) function: u8,
+    pub(crate) register: u16,
+    pub(crate) kind: Kind,
+    pub(crate) order: ByteOrder,
+    pub(crate) scale: f64,
+    pub(crate) unit: String,
+}
+
+/// A contiguous range of registers read at once, and the entries in it.
+#[derive(Debug, PartialEq)]
+pub(crate) struct Batch {
+    pub(crate) unit_id: u8,
+    pub(crate) function: u8,
+    pub(crate) start: u16,
+    pub(crate) count: u16,
+    pub(crate) entries: Vec<usize>,
+}
+
+/// The reads needed for the entries; overlapping and adjacent registers of the same unit and table are read together.
+pub(crate) fn batches(entries: &[Entry]) -> Vec<Batch> {
+    let mut order: Vec<usize> = (0..entries.len()).collect();
+    order.sort_by_key(|i| {
+        let entry = &entries[*i];
+        (entry.unit_id, entry.function, entry.register)
+    });
+    let mut res: Vec<Batch> = Vec::new();
+    for i in order {
+        let entry = &entries[i];
+        let end = entry.register as u32 + entry.kind.count() as u32;
+        if let Some(batch) = res.last_mut() {
+            let start = batch.start as u32;
+            if (batch.unit_id, batch.function) == (entry.unit_id, entry.function)
+                && entry.register as u32 <= start + batch.count as u32
+                && end - start <= MAX_COUNT as u32
+            {
+                batch.count = batch.count.max((end - start) as u16);
+                batch.entries.push(i);
+                continue;
+            }
+        }
+        res.push(Batch {
+            unit_id: entry.unit_id,
+            function: entry.function,
+            start: entry.register,
+            count: entry.kind.count(),
+            entries: vec![i],
+        });
+    }
+    res
+}
+
+/// The meaning of an exception code.
+pub(crate) fn exception(code: u8) -> &'static str {
+    match code {
+        1 => "illegal function",
+        2 => "illegal data address",
+        3 => "illegal data value",
+        4 => "server device failure",
+        6 => "server device busy",
+        10 => "gateway path unavailable",
+        11 => "gateway target device failed to respond",
+        _ => "unknown exception",
+    }
+}
+
+/// Sets the values of the entries in a batch given its registers.
+pub(crate) fn fill(
+    entries: &[Entry],
+    batch: &Batch,
+    registers: &[u16],
+    values: &mut [Option<f64>],
+) {
+    for i in &batch.entries {
+        let entry = &entries[*i];
+        let offset = (entry.register - batch.start) as usize;
+        let raw = decode(
+            &registers[offset..offset + entry.kind.count() as usize],
+            entry.kind,
+            entry.order,
+        );
+        values[*i] = Some(raw * entry.scale);
+    }
+}
+
+/// The readings of the values read; the failure of a read if none were.
+pub(crate) fn readings(
+    names: Vec<String>,
+    values: Vec<Option<f64>>,
+    failure: Option<SensorError>,
+) -> Result<Vec<Reading>, SensorError> {
+    let res: Vec<Reading> = names
+        .into_iter()
+        .zip(values)
+        .filter_map(|(name, value)| value.map(|v| Reading::new(name, v)))
+        .collect();
+    match failure {
+        Some(err) if res.is_empty() => Err(err),
+        _ => Ok(res),
+    }
+}
+
+fn unit_id(value: Option<&toml::Value>, default: u8) -> Result<u8, ConfigError> {
+    match value.map(|v| v.as_integer()) {
+        None => Ok(default),
+        Some(Some(id)) if (0..=255).contains(&id) => Ok(id as u8),
+        Some(_) => Err(ConfigError::Invalid(
+            "unit_id must be between 0 and 255.".to_string(),
+        )),
+    }
+}
+
+/// The unit id in the configuration; 1 by default.
+pub(crate) fn get_unit_id(sensor_cfg: &toml::value::Table) -> Result<u8, ConfigError> {
+    unit_id(sensor_cfg.get("unit_id"), 1)
+}
+
+/// The entries of the register map in the configuration; each read from the sensor's unit id unless set.
+pub(crate) fn get_entries(sensor_cfg: &toml::value::Table) -> Result<Vec<Entry>, ConfigError> {
+    let invalid = ConfigError::Invalid;
+    let default_id = get_unit_id(sensor_cfg)?;
+    sensor_cfg["entries"]
+        .as_array()
+        .filter(|a| !a.is_empty())
+        .ok_or_else(|| invalid("entries must be a non-empty array.".to_string()))?
+        .iter()
+        .map(|entry| {
+            let text = |key: &str, default: &'static str| match entry.get(key) {
+                Some(v) => v
+                    .as_str()
+                    .ok_or_else(|| invalid(format!("{} must be a string.", key))),
+                None => Ok(default),
+            };
+            let name = entry
+                .get("name")
+                .and_then(|v| v.as_str())
+                .ok_or_else(|| invalid("each entry must have a name.".to_string()))?;
+            let register = entry
+                .get("register")
+                .and_then(|v| v.as_integer())
+                .filter(|r| (0..=65535).contains(r))
+                .ok_or_else(|| {
+                    invalid(format!("register of {} must be between 0 and 65535.", name))
+                })?;
+            let kind = Kind::parse(text("type", "u16")?).ok_or_else(|| {
+                invalid(format!(
+                    "type must be one of 'u16', 'i16', 'u32', 'i32' and 'f32'; got: {}.",
+                    text("type", "u16").unwrap_or_default()
+                ))
+            })?;
+            match entry.get("count").map(|v| v.as_integer()) {
+                None => {}
+                Some(Some(count)) if count == kind.count() as i64 => {}
+                Some(_) => {
+                    return Err(invalid(format!(
+                        "count of {} must be {} for its type.",
+                        name,
+                        kind.count()
+                    )))
+                }
+            }
+            if register + kind.count() as i64 > 65536 {
+                return Err(invalid(format!(
+                    "register of {} must leave room for its type.",
+                    name
+                )));
+            }
+            let order = ByteOrder::parse(text("byte_order", "ABCD")?).ok_or_else(|| {
+                invalid(format!(
+                    "byte_order must be one of 'ABCD', 'CDAB', 'BADC' and 'DCBA'; got: {}.",
+                    text("byte_order", "ABCD").unwrap_or_default()
+                ))
+            })?;
+            let function = match text("function", "holding")? {
+                "holding" => READ_HOLDING_REGISTERS,
+                "input" => READ_INPUT_REGISTERS,
+                other => {
+                    return Err(invalid(format!(
+                        "function must be 'holding' or 'input'; got: {}.",
+                        other
+                    )))
+                }
+            };
+            let scale = match entry.get("scale") {
+                Some(v) => v
+                    .as_float()
+                    .or_else(|| v.as_integer().map(|i| i as f64))
+                    .ok_or_else(|| invalid("scale must be a number.".to_string()))?,
+                None => 1.0,
+            };
+            Ok(Entry {
+                name: name.to_string(),
+                unit_id: unit_id(entry.get("unit_id"), default_id)?,
+                function,
+                register: register as u16,
+                kind,
+                order,
+                scale,
+                unit: text("unit", "unknown")?.to_string(),
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A kind of value, the registers it is sent as in each byte order, and the value they decode to.
+    type Case = (Kind, [(ByteOrder, &'static [u16]); 4], f64);
+
+    fn entry(name: &str, register: u16, kind: Kind, order: ByteOrder, scale: f64) -> Entry {
+        Entry {
+            name: name.to_string(),
+            unit_id: 1,
+            function: READ_HOLDING_REGISTERS,
+            register,
+            kind,
+            order,
+            scale,
+            unit: "unknown".to_string(),
+        }
+    }
+
+    // Tests for success.
+
+    #[test]
+    fn test_decode_for_success() {
+        use ByteOrder::*;
+        use Kind::*;
+        // 0x1234, -2, 0x12345678, -100000 and 230.5 as sent in each byte order.
+        let cases: [Case; 5] = [
+            (
+                U16,
+                [
+                    (Abcd, &[0x1234]),
+                    (Cdab, &[0x1234]),
+                    (Badc, &[0x3412]),
+                    (Dcba, &[0x3412]),
+                ],
+                4660.0,
+            ),
+            (
+                I16,
+                [
+                    (Abcd, &[0xfffe]),
+                    (Cdab, &[0xfffe]),
+                    (Badc, &[0xfeff]),
+                    (Dcba, &[0xfeff]),
+                ],
+                -2.0,
+            ),
+            (
+                U32,
+                [
+                    (Abcd, &[0x1234, 0x5678]),
+                    (Cdab, &[0x5678, 0x1234]),
+                    (Badc, &[0x3412, 0x7856]),
+                    (Dcba, &[0x7856, 0x3412]),
+                ],
+                305419896.0,
+            ),
+            (
+                I32,
+                [
+                    (Abcd, &[0xfffe, 0x7960]),
+                    (Cdab, &[0x7960, 0xfffe]),
+                    (Badc, &[0xfeff, 0x6079]),
+                    (Dcba, &[0x6079, 0xfeff]),
+                ],
+                -100000.0,
+            ),
+            (
+                F32,
+                [
+                    (Abcd, &[0x4366, 0x8000]),
+                    (Cdab, &[0x8000, 0x4366]),
+                    (Badc, &[0x6643, 0x0080]),
+                    (Dcba, &[0x0080, 0x6643]),
+                ],
+                230.5,
+            ),
+        ];
+        for (kind, orders, expected) in cases {
+            for (order, registers) in orders {
+                assert_eq!(
+                    decode(registers, kind, order),
+                    expected,
+                    "{:?} in {:?}",
+                    kind,
+                    order
+                );
+            }
+        }
+        // unsigned, not negative.
+        assert_eq!(decode(&[0xfffe], U16, Abcd), 65534.0);
+        assert_eq!(decode(&[0xfffe, 0x7960], U32, Abcd), 4294867296.0);
+    }
+
+    #[test]
+    fn test_get_entries_for_success() {
+        let sensor_cfg: toml::value::Table = toml::from_str(
+            "unit_id=3\nentries=[{ name='power', register=5, type='f32', byte_order='cdab', unit='W' },\
+            { name='energy', unit_id=4, register=7, scale=0.01, function='input' }]",
+        )
+        .unwrap();
+        let entries = get_entries(&sensor_cfg).unwrap();
+        assert_eq!(
+            (entries[0].unit_id, entries[0].kind, entries[0].order),
+            (3, Kind::F32, ByteOrder::Cdab)
+        );
+        assert_eq!(
+            (entries[1].unit_id, entries[1].function, entries[1].scale),
+            (4, READ_INPUT_REGISTERS, 0.01)
+        );
+    }
+
+    // Tests for failure.
+
+    #[test]
+    fn test_get_entries_for_failure() {
+        let sensor_cfg: toml::value::Table =
+            toml::from_str("entries=[{ name='power', register=5, type='f64' }]").unwrap();
+        assert_eq!(
+            get_entries(&sensor_cfg).err(),
+            Some(ConfigError::Invalid(
+                "type must be one of 'u16', 'i16', 'u32', 'i32' and 'f32'; got: f64.".to_string()
+            ))
+        );
+        let sensor_cfg: toml::value::Table =
+            toml::from_str("entries=[{ name='power', register=5, type='u32', count=1 }]").unwrap();
+        assert_eq!(
+            get_entries(&sensor_cfg).err(),
+            Some(ConfigError::Invalid(
+                "count of power must be 2 for its type.".to_string()
+            ))
+        );
+        let sensor_cfg: toml::value::Table =
+            toml::from_str("entries=[{ name='power', register=65535, type='u32' }]").unwrap();
+        assert!(get_entries(&sensor_cfg).is_err());
+    }
+
+    // Tests for sanity.
+
+    #[test]
+    fn test_batches_for_sanity() {
+        let mut entries = vec![
+            entry("b", 102, Kind::U32, ByteOrder::Abcd, 1.0),
+            entry("a", 100, Kind::U32, ByteOrder::Abcd, 1.0),
+            entry("c", 110, Kind::U16, ByteOrder::Abcd, 1.0),
+            entry("d", 110, Kind::U16, ByteOrder::Abcd, 1.0),
+            entry("e", 1000, Kind::U16, ByteOrder::Abcd, 1.0),
+            entry("f", 100, Kind::U16, ByteOrder::Abcd, 1.0),
+        ];
+        entries[4].function = READ_INPUT_REGISTERS;
+        // same registers, other unit.
+        entries[5].unit_id = 2;
+        let found = batches(&entries);
+        assert_eq!(
+            found
+                .iter()
+                .map(|b| (b.unit_id, b.start, b.count, b.entries.clone()))
+                .collect::<Vec<_>>(),
+            vec![
+                (1, 100, 4, vec![1, 0]),
+                (1, 110, 1, vec![2, 3]),
+                (1, 1000, 1, vec![4]),
+                (2, 100, 1, vec![5])
+            ]
+        );
+        // no more than 125 registers at once.
+        let entries: Vec<Entry> = (0..130)
+            .map(|r| entry("x", r, Kind::U16, ByteOrder::Abcd, 1.0))
+            .collect();
+        let counts: Vec<u16> = batches(&entries).iter().map(|b| b.count).collect();
+        assert_eq!(counts, vec![125, 5]);
+    }
+}
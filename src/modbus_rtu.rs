@@ -0,0 +1,558 @@
+use std::io;
+use std::sync::Mutex;
+use std::thread;
+use std::time;
+
+use crate::common;
+use crate::common::{ConfigError, Reading, SensorError};
+use crate::modbus;
+use crate::modbus::{Batch, Entry};
+use crate::shelly;
+
+/// How long the bus is left to settle before a retry.
+const QUIET: time::Duration = time::Duration::from_millis(50);
+
+/// The CRC of a frame, as the standard has it; sent low byte first.
+pub(crate) fn crc(data: &[u8]) -> u16 {
+    let mut crc = 0xffff;
+    for byte in data {
+        crc ^= *byte as u16;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xa001
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    crc
+}
+
+/// Where frames are sent and received: the serial port, or a fixture in tests.
+pub(crate) trait Port: Send {
+    fn write_all(&mut self, frame: &[u8]) -> io::Result<()>;
+
+    fn read_exact(&mut self, buf: &mut [u8]) -> io::Result<()>;
+
+    /// Discards what was received but not read, e.g. the rest of a garbled answer.
+    fn clear(&mut self) -> io::Result<()>;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Parity {
+    None,
+    Even,
+    Odd,
+}
+
+/// How to open the serial port.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Settings {
+    pub(crate) path: String,
+    pub(crate) baud_rate: u32,
+    pub(crate) parity: Parity,
+    pub(crate) stop_bits: u8,
+    pub(crate) timeout: time::Duration,
+}
+
+#[cfg(feature = "serial")]
+mod serial {
+    use std::io::{self, Read, Write};
+
+    use serialport::{ClearBuffer, DataBits, StopBits};
+
+    use super::{Parity, Port, Settings};
+
+    struct SerialPort(Box<dyn serialport::SerialPort>);
+
+    impl Port for SerialPort {
+        fn write_all(&mut self, frame: &[u8]) -> io::Result<()> {
+            self.0.write_all(frame)
+        }
+
+        fn read_exact(&mut self, buf: &mut [u8]) -> io::Result<()> {
+            self.0.read_exact(buf)
+        }
+
+        fn clear(&mut self) -> io::Result<()> {
+            Ok(self.0.clear(ClearBuffer::Input)?)
+        }
+    }
+
+    pub(super) fn open(settings: &Settings) -> io::Result<Box<dyn Port>> {
+        let port = serialport::new(&settings.path, settings.baud_rate)
+            .data_bits(DataBits::Eight)
+            .parity(match settings.parity {
+                Parity::None => serialport::Parity::None,
+                Parity::Even => serialport::Parity::Even,
+                Parity::Odd => serialport::Parity::Odd,
+            })
+            .stop_bits(if settings.stop_bits == 2 {
+                StopBits::Two
+            } else {
+                StopBits::One
+            })
+            .timeout(settings.timeout)
+            .open()?;
+        Ok(Box::new(SerialPort(port)))
+    }
+}
+
+#[cfg(feature = "serial")]
+use serial::open;
+
+#[cfg(not(feature = "serial"))]
+fn open(settings: &Settings) -> io::Result<Box<dyn Port>> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!(
+            "compiled without the serial feature; cannot open {}.",
+            settings.path
+        ),
+    ))
+}
+
+/// Why a read failed; bus contention and garbled answers are worth another try.
+enum Failure {
+    Retry(SensorError),
+    Fail(SensorError),
+}
+
+/// Reads registers from devices on an RS485 bus, e.g. energy meters, via Modbus RTU.
+///
+/// All units on the bus are read through the one serial port, opened on first use and again after it failed. Reads
+/// that time out or get a garbled answer are retried; if a read still fails, only the entries it covers are missing.
+pub struct ModbusRtuSensor {
+    name: String,
+    settings: Settings,
+    entries: Vec<Entry>,
+    batches: Vec<Batch>,
+    retries: u32,
+    port: Mutex<Option<Box<dyn Port>>>,
+}
+
+impl ModbusRtuSensor {
+    pub(crate) fn new(
+        name: String,
+        settings: Settings,
+        entries: Vec<Entry>,
+        retries: u32,
+    ) -> ModbusRtuSensor {
+        let batches = modbus::batches(&entries);
+        ModbusRtuSensor {
+            name,
+            settings,
+            entries,
+            batches,
+            retries,
+            port: Mutex::new(None),
+        }
+    }
+
+    fn io_error(&self, err: io::Error) -> Failure {
+        match err.kind() {
+            io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => {
+                Failure::Retry(SensorError::Timeout(format!(
+                    "no answer on {} within {:?}.",
+                    self.settings.path, self.settings.timeout
+                )))
+            }
+            _ => Failure::Fail(SensorError::Io(format!("{}: {}", self.settings.path, err))),
+        }
+    }
+
+    /// Reads the registers of a batch once.
+    fn request(&self, port: &mut dyn Port, batch: &Batch) -> Result<Vec<u16>, Failure> {
+        let mut request = vec![batch.unit_id, batch.function];
+        request.extend(batch.start.to_be_bytes());
+        request.extend(batch.count.to_be_bytes());
+        request.extend(crc(&request).to_le_bytes());
+        port.write_all(&request).map_err(|e| self.io_error(e))?;
+
+        let mut answer = vec![0; 3];
+        port.read_exact(&mut answer).map_err(|e| self.io_error(e))?;
+        let rest = if answer[1] == batch.function | 0x80 {
+            2
+        } else if answer[1] == batch.function && answer[2] as usize == 2 * batch.count as usize {
+            answer[2] as usize + 2
+        } else {
+            return Err(Failure::Retry(SensorError::Parse(format!(
+                "expected {} registers at {}; got a malformed answer.",
+                batch.count, batch.start
+            ))));
+        };
+        answer.resize(3 + rest, 0);
+        port.read_exact(&mut answer[3..])
+            .map_err(|e| self.io_error(e))?;
+        let (frame, checksum) = answer.split_at(answer.len() - 2);
+        if crc(frame).to_le_bytes() != checksum {
+            return Err(Failure::Retry(SensorError::Parse(
+                "CRC mismatch in the answer.".to_string(),
+            )));
+        }
+        // another master's conversation.
+        if frame[0] != batch.unit_id {
+            return Err(Failure::Retry(SensorError::Protocol(format!(
+                "answer from unit {} instead of {}.",
+                frame[0], batch.unit_id
+            ))));
+        }
+        if frame[1] == batch.function | 0x80 {
+            let err = SensorError::Protocol(format!(
+                "reading {} registers at {} of unit {} failed with: {} ({}).",
+                batch.count,
+                batch.start,
+                batch.unit_id,
+                frame[2],
+                modbus::exception(frame[2])
+            ));
+            // busy.
+            return Err(if frame[2] == 6 {
+                Failure::Retry(err)
+            } else {
+                Failure::Fail(err)
+            });
+        }
+        Ok(frame[3..]
+            .chunks(2)
+            .map(|w| u16::from_be_bytes([w[0], w[1]]))
+            .collect())
+    }
+
+    /// Reads the registers of a batch; retries as configured.
+    fn read(&self, port: &mut dyn Port, batch: &Batch) -> Result<Vec<u16>, Failure> {
+        let mut attempt = 0;
+        loop {
+            match self.request(port, batch) {
+                Err(Failure::Retry(err)) if attempt < self.retries => {
+                    attempt += 1;
+                    eprintln!(
+                        "Retrying unit {} of {} ({}/{}): {}",
+                        batch.unit_id,
+                        self.name,
+                        attempt,
+                        self.retries,
+                        err.message()
+                    );
+                    thread::sleep(QUIET);
+                    if let Err(err) = port.clear() {
+                        return Err(self.io_error(err));
+                    }
+                }
+                res => return res,
+            }
+        }
+    }
+}
+
+impl common::Sensor for ModbusRtuSensor {
+    fn get_names(&self) -> Vec<String> {
+        self.entries
+            .iter()
+            .map(|entry| format!("{}_{}", self.name, entry.name))
+            .collect()
+    }
+
+    fn get_units(&self) -> Vec<String> {
+        self.entries.iter().map(|e| e.unit.clone()).collect()
+    }
+
+    fn measure(&self) -> Result<Vec<Reading>, SensorError> {
+        let mut guard = self.port.lock().unwrap_or_else(|e| e.into_inner());
+        if guard.is_none() {
+            *guard = Some(open(&self.settings).map_err(|e| {
+                SensorError::Io(format!("could not open {}: {}", self.settings.path, e))
+            })?);
+        }
+        let port = guard.as_mut().unwrap();
+        let names = self.get_names();
+        let mut values = vec![None; self.entries.len()];
+        let mut failure = None;
+        let mut broken = false;
+        for batch in &self.batches {
+            match self.read(port.as_mut(), batch) {
+                Ok(registers) => modbus::fill(&self.entries, batch, &registers, &mut values),
+                Err(Failure::Retry(err)) => {
+                    eprintln!("Could not read {}: {}", self.name, err.message());
+                    failure = Some(err);
+                }
+                Err(Failure::Fail(err)) => {
+                    eprintln!("Could not read {}: {}", self.name, err.message());
+                    broken = matches!(err, SensorError::Io(_));
+                    failure = Some(err);
+                    if broken {
+                        break;
+                    }
+                }
+            }
+        }
+        // reopened next time.
+        if broken {
+            *guard = None;
+        }
+        modbus::readings(names, values, failure)
+    }
+}
+
+/// Registers of Modbus RTU devices on a serial bus.
+pub(crate) fn sensor_type() -> common::SensorType {
+    common::SensorType {
+        name: "modbus_rtu",
+        required: &["device", "entries"],
+        optional: &[
+            "baud_rate",
+            "parity",
+            "stop_bits",
+            "unit_id",
+            "retries",
+            "timeout_secs",
+        ],
+        create: |name, sensor_cfg, _| {
+            let invalid = ConfigError::Invalid;
+            let path = sensor_cfg["device"]
+                .as_str()
+                .ok_or_else(|| invalid("device must be a string.".to_string()))?;
+            let baud_rate = sensor_cfg
+                .get("baud_rate")
+                .map(|v| v.as_integer())
+                .unwrap_or(Some(9600))
+                .filter(|rate| (1..=4_000_000).contains(rate))
+                .ok_or_else(|| invalid("baud_rate must be a positive integer.".to_string()))?;
+            let parity = match sensor_cfg.get("parity").map(|v| v.as_str()) {
+                None | Some(Some("none")) => Parity::None,
+                Some(Some("even")) => Parity::Even,
+                Some(Some("odd")) => Parity::Odd,
+                Some(_) => {
+                    return Err(invalid(
+                        "parity must be one of 'none', 'even' and 'odd'.".to_string(),
+                    ))
+                }
+            };
+            let stop_bits = match sensor_cfg.get("stop_bits").map(|v| v.as_integer()) {
+                None | Some(Some(1)) => 1,
+                Some(Some(2)) => 2,
+                Some(_) => return Err(invalid("stop_bits must be 1 or 2.".to_string())),
+            };
+            let retries = sensor_cfg
+                .get("retries")
+                .map(|v| v.as_integer())
+                .unwrap_or(Some(2))
+                .filter(|retries| (0..=10).contains(retries))
+                .ok_or_else(|| invalid("retries must be between 0 and 10.".to_string()))?;
+            let timeout = shelly::get_timeout(sensor_cfg)?;
+            let entries = modbus::get_entries(sensor_cfg)?;
+            if cfg!(not(feature = "serial")) {
+                return Err(invalid(format!(
+                    "modbus_rtu sensor {} requires the serial feature.",
+                    name
+                )));
+            }
+            Ok(Box::new(ModbusRtuSensor::new(
+                name.to_string(),
+                Settings {
+                    path: path.to_string(),
+                    baud_rate: baud_rate as u32,
+                    parity,
+                    stop_bits,
+                    timeout,
+                },
+                entries,
+                retries as u32,
+            )))
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::common::values;
+    use crate::common::Sensor;
+    use crate::modbus::{ByteOrder, Kind, READ_HOLDING_REGISTERS};
+
+    /// Plays the bus: answers each request with the next of the answers, an empty one being silence; hands on the
+    /// requests.
+    struct Fixture {
+        answers: VecDeque<Vec<u8>>,
+        pending: VecDeque<u8>,
+        requests: Arc<Mutex<Vec<Vec<u8>>>>,
+    }
+
+    impl Port for Fixture {
+        fn write_all(&mut self, frame: &[u8]) -> io::Result<()> {
+            self.requests.lock().unwrap().push(frame.to_vec());
+            self.pending
+                .extend(self.answers.pop_front().unwrap_or_default());
+            Ok(())
+        }
+
+        fn read_exact(&mut self, buf: &mut [u8]) -> io::Result<()> {
+            if self.pending.len() < buf.len() {
+                self.pending.clear();
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "timed out"));
+            }
+            for byte in buf.iter_mut() {
+                *byte = self.pending.pop_front().unwrap();
+            }
+            Ok(())
+        }
+
+        fn clear(&mut self) -> io::Result<()> {
+            self.pending.clear();
+            Ok(())
+        }
+    }
+
+    fn frame(mut data: Vec<u8>) -> Vec<u8> {
+        data.extend(crc(&data).to_le_bytes());
+        data
+    }
+
+    /// The answer of a unit with the registers.
+    fn answer(unit_id: u8, registers: &[u16]) -> Vec<u8> {
+        let mut data = vec![unit_id, READ_HOLDING_REGISTERS, 2 * registers.len() as u8];
+        data.extend(registers.iter().flat_map(|r| r.to_be_bytes()));
+        frame(data)
+    }
+
+    fn entry(name: &str, unit_id: u8, register: u16, kind: Kind, scale: f64) -> Entry {
+        Entry {
+            name: name.to_string(),
+            unit_id,
+            function: READ_HOLDING_REGISTERS,
+            register,
+            kind,
+            order: ByteOrder::Abcd,
+            scale,
+            unit: "unknown".to_string(),
+        }
+    }
+
+    /// Two meters on the bus; unit 1 with voltage and power, unit 2 with power.
+    fn meters(answers: Vec<Vec<u8>>, retries: u32) -> (ModbusRtuSensor, Arc<Mutex<Vec<Vec<u8>>>>) {
+        let sensor = ModbusRtuSensor::new(
+            "meters".to_string(),
+            Settings {
+                path: "/dev/ttyUSB0".to_string(),
+                baud_rate: 9600,
+                parity: Parity::None,
+                stop_bits: 1,
+                timeout: time::Duration::from_secs(1),
+            },
+            vec![
+                entry("grid_voltage", 1, 0, Kind::U16, 0.1),
+                entry("grid_power", 1, 1, Kind::F32, 1.0),
+                entry("heatpump_power", 2, 0, Kind::I32, 1.0),
+            ],
+            retries,
+        );
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        *sensor.port.lock().unwrap() = Some(Box::new(Fixture {
+            answers: answers.into(),
+            pending: VecDeque::new(),
+            requests: requests.clone(),
+        }));
+        (sensor, requests)
+    }
+
+    // Tests for success.
+
+    #[test]
+    fn test_measure_for_success() {
+        let (sensor, requests) = meters(
+            vec![
+                answer(1, &[2315, 0x4366, 0x8000]),
+                answer(2, &[0xffff, 0xfc18]),
+            ],
+            0,
+        );
+        assert_eq!(
+            values(sensor.measure()),
+            vec![
+                ("meters_grid_voltage".to_string(), 231.5),
+                ("meters_grid_power".to_string(), 230.5),
+                ("meters_heatpump_power".to_string(), -1000.0)
+            ]
+        );
+        // one read per unit, through the one port.
+        assert_eq!(
+            *requests.lock().unwrap(),
+            vec![frame(vec![1, 3, 0, 0, 0, 3]), frame(vec![2, 3, 0, 0, 0, 2])]
+        );
+    }
+
+    #[test]
+    fn test_retries_for_success() {
+        let mut garbled = answer(1, &[2315, 0x4366, 0x8000]);
+        garbled[4] ^= 0xff;
+        let (sensor, requests) = meters(
+            vec![
+                garbled,
+                // silence.
+                vec![],
+                answer(1, &[2315, 0x4366, 0x8000]),
+                // another unit answering.
+                answer(3, &[0, 0]),
+                answer(2, &[0xffff, 0xfc18]),
+            ],
+            2,
+        );
+        assert_eq!(values(sensor.measure()).len(), 3);
+        assert_eq!(requests.lock().unwrap().len(), 5);
+    }
+
+    // Tests for failure.
+
+    #[test]
+    fn test_measure_for_failure() {
+        // unit 2 does not answer.
+        let (sensor, requests) = meters(vec![answer(1, &[2315, 0x4366, 0x8000])], 1);
+        assert_eq!(
+            values(sensor.measure())
+                .into_iter()
+                .map(|(name, _)| name)
+                .collect::<Vec<_>>(),
+            vec!["meters_grid_voltage", "meters_grid_power"]
+        );
+        assert_eq!(requests.lock().unwrap().len(), 3);
+
+        // exceptions are not retried.
+        let (sensor, requests) = meters(vec![frame(vec![1, 0x83, 2]), frame(vec![2, 0x83, 2])], 2);
+        match sensor.measure() {
+            Err(SensorError::Protocol(msg)) => assert_eq!(
+                msg,
+                "reading 2 registers at 0 of unit 2 failed with: 2 (illegal data address)."
+            ),
+            _ => panic!("exception not reported."),
+        }
+        assert_eq!(requests.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_sensor_type_for_failure() {
+        let sensor_cfg: toml::value::Table = toml::from_str(
+            "device='/dev/ttyUSB0'\nparity='mark'\nentries=[{ name='power', register=5 }]",
+        )
+        .unwrap();
+        assert_eq!(
+            (sensor_type().create)("meters", &sensor_cfg, "state").err(),
+            Some(ConfigError::Invalid(
+                "parity must be one of 'none', 'even' and 'odd'.".to_string()
+            ))
+        );
+    }
+
+    // Tests for sanity.
+
+    #[test]
+    fn test_crc_for_sanity() {
+        // the well-known read of 10 holding registers from unit 1.
+        assert_eq!(
+            frame(vec![0x01, 0x03, 0x00, 0x00, 0x00, 0x0a]),
+            vec![0x01, 0x03, 0x00, 0x00, 0x00, 0x0a, 0xc5, 0xcd]
+        );
+    }
+}
@@ -0,0 +1,374 @@
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time;
+
+use crate::common;
+use crate::common::{ConfigError, Reading, SensorError};
+use crate::modbus;
+use crate::modbus::{Batch, Entry};
+use crate::shelly;
+
+/// The port Modbus TCP servers listen on.
+const PORT: u16 = 502;
+
+/// A Modbus TCP server, e.g. a device or a gateway.
+pub(crate) struct Server {
+    pub(crate) address: String,
+    pub(crate) timeout: time::Duration,
+}
+
+impl Server {
+    fn io_error(&self, err: io::Error) -> SensorError {
+        match err.kind() {
+            io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => SensorError::Timeout(format!(
+                "no answer from {} within {:?}.",
+                self.address, self.timeout
+            )),
+            _ => SensorError::Io(format!("{}: {}", self.address, err)),
+        }
+    }
+
+    pub(crate) fn connect(&self) -> Result<TcpStream, SensorError> {
+        let address = self
+            .address
+            .to_socket_addrs()
+            .map_err(|e| SensorError::Io(format!("could not resolve {}: {}", self.address, e)))?
+            .next()
+            .ok_or_else(|| SensorError::Io(format!("could not resolve {}.", self.address)))?;
+        let stream =
+            TcpStream::connect_timeout(&address, self.timeout).map_err(|e| self.io_error(e))?;
+        stream
+            .set_read_timeout(Some(self.timeout))
+            .and_then(|_| stream.set_write_timeout(Some(self.timeout)))
+            .map_err(|e| self.io_error(e))?;
+        Ok(stream)
+    }
+
+    /// Reads the registers of a batch.
+    pub(crate) fn read(
+        &self,
+        stream: &mut TcpStream,
+        transaction: u16,
+        batch: &Batch,
+    ) -> Result<Vec<u16>, SensorError> {
+        let mut request = transaction.to_be_bytes().to_vec();
+        // the protocol id, and the length of what follows.
+        request.extend([0, 0, 0, 6, batch.unit_id, batch.function]);
+        request.extend(batch.start.to_be_bytes());
+        request.extend(batch.count.to_be_bytes());
+        stream.write_all(&request).map_err(|e| self.io_error(e))?;
+
+        let mut header = [0; 7];
+        stream
+            .read_exact(&mut header)
+            .map_err(|e| self.io_error(e))?;
+        let length = u16::from_be_bytes([header[4], header[5]]) as usize;
+        if !(3..=254).contains(&length) {
+            return Err(SensorError::Parse(format!(
+                "length must be between 3 and 254; got: {}.",
+                length
+            )));
+        }
+        let mut pdu = vec![0; length - 1];
+        stream.read_exact(&mut pdu).map_err(|e| self.io_error(e))?;
+        if header[..2] != transaction.to_be_bytes() {
+            return Err(SensorError::Protocol(format!(
+                "answer to transaction {} instead of {}.",
+                u16::from_be_bytes([header[0], header[1]]),
+                transaction
+            )));
+        }
+        if pdu[0] == batch.function | 0x80 {
+            return Err(SensorError::Protocol(format!(
+                "reading {} registers at {} failed with: {} ({}).",
+                batch.count,
+                batch.start,
+                pdu[1],
+                modbus::exception(pdu[1])
+            )));
+        }
+        if pdu[0] != batch.function
+            || pdu[1] as usize != 2 * batch.count as usize
+            || pdu.len() != 2 + pdu[1] as usize
+        {
+            return Err(SensorError::Parse(format!(
+                "expected {} registers at {}; got an answer of {} bytes.",
+                batch.count,
+                batch.start,
+                pdu.len()
+            )));
+        }
+        Ok(pdu[2..]
+            .chunks(2)
+            .map(|w| u16::from_be_bytes([w[0], w[1]]))
+            .collect())
+    }
+}
+
+/// Reads registers from a device, e.g. a heat pump, an inverter or a wallbox, via Modbus TCP.
+///
+/// Each measurement reads contiguous ranges of registers at once; if a read fails, only the entries in that range are
+/// missing.
+pub struct ModbusTcpSensor {
+    name: String,
+    server: Server,
+    entries: Vec<Entry>,
+    batches: Vec<Batch>,
+}
+
+impl ModbusTcpSensor {
+    pub(crate) fn new(name: String, server: Server, entries: Vec<Entry>) -> ModbusTcpSensor {
+        let batches = modbus::batches(&entries);
+        ModbusTcpSensor {
+            name,
+            server,
+            entries,
+            batches,
+        }
+    }
+}
+
+impl common::Sensor for ModbusTcpSensor {
+    fn get_names(&self) -> Vec<String> {
+        self.entries
+            .iter()
+            .map(|entry| format!("{}_{}", self.name, entry.name))
+            .collect()
+    }
+
+    fn get_units(&self) -> Vec<String> {
+        self.entries.iter().map(|e| e.unit.clone()).collect()
+    }
+
+    fn measure(&self) -> Result<Vec<Reading>, SensorError> {
+        let mut stream = self.server.connect()?;
+        let names = self.get_names();
+        let mut values = vec![None; self.entries.len()];
+        let mut failure = None;
+        for (transaction, batch) in self.batches.iter().enumerate() {
+            let registers = match self.server.read(&mut stream, transaction as u16, batch) {
+                Ok(registers) => registers,
+                Err(err) => {
+                    eprintln!("Could not read {}: {}", self.name, err.message());
+                    // only an exception leaves the connection usable.
+                    let broken = !matches!(err, SensorError::Protocol(_));
+                    failure = Some(err);
+                    if broken {
+                        break;
+                    }
+                    continue;
+                }
+            };
+            modbus::fill(&self.entries, batch, &registers, &mut values);
+        }
+        modbus::readings(names, values, failure)
+    }
+}
+
+/// The server in the configuration; at host and port.
+pub(crate) fn get_server(sensor_cfg: &toml::value::Table) -> Result<Server, ConfigError> {
+    let invalid = ConfigError::Invalid;
+    let host = sensor_cfg["host"]
+        .as_str()
+        .ok_or_else(|| invalid("host must be a string.".to_string()))?;
+    let port = match sensor_cfg.get("port").map(|v| v.as_integer()) {
+        None => PORT,
+        Some(Some(port)) if (1..=65535).contains(&port) => port as u16,
+        Some(_) => return Err(invalid("port must be between 1 and 65535.".to_string())),
+    };
+    let address = if host.contains(':') {
+        host.to_string()
+    } else {
+        format!("{}:{}", host, port)
+    };
+    Ok(Server {
+        address,
+        timeout: shelly::get_timeout(sensor_cfg)?,
+    })
+}
+
+/// Registers of Modbus TCP devices.
+pub(crate) fn sensor_type() -> common::SensorType {
+    common::SensorType {
+        name: "modbus_tcp",
+        required: &["host", "entries"],
+        optional: &["port", "unit_id", "timeout_secs"],
+        create: |name, sensor_cfg, _| {
+            Ok(Box::new(ModbusTcpSensor::new(
+                name.to_string(),
+                get_server(sensor_cfg)?,
+                modbus::get_entries(sensor_cfg)?,
+            )))
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::net::TcpListener;
+    use std::thread;
+
+    use super::*;
+    use crate::common::values;
+    use crate::common::Sensor;
+    use crate::modbus::{ByteOrder, Kind, READ_HOLDING_REGISTERS, READ_INPUT_REGISTERS};
+
+    /// The function, start and count of each request served.
+    type Requests = thread::JoinHandle<Vec<(u8, u16, u16)>>;
+
+    /// Serves a single connection from the holding and input registers, answering unknown ones with an exception;
+    /// hands on the requests once the client hangs up.
+    fn server(registers: HashMap<(u8, u16), u16>) -> (String, Requests) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let handle = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut requests = Vec::new();
+            let mut request = [0; 12];
+            while stream.read_exact(&mut request).is_ok() {
+                let function = request[7];
+                let start = u16::from_be_bytes([request[8], request[9]]);
+                let count = u16::from_be_bytes([request[10], request[11]]);
+                requests.push((function, start, count));
+                let values: Option<Vec<u16>> = (start..start + count)
+                    .map(|r| registers.get(&(function, r)).copied())
+                    .collect();
+                let pdu = match values {
+                    Some(values) => {
+                        let mut pdu = vec![function, 2 * count as u8];
+                        pdu.extend(values.iter().flat_map(|v| v.to_be_bytes()));
+                        pdu
+                    }
+                    None => vec![function | 0x80, 2],
+                };
+                let mut answer = request[..4].to_vec();
+                answer.extend((pdu.len() as u16 + 1).to_be_bytes());
+                answer.push(request[6]);
+                answer.extend(pdu);
+                stream.write_all(&answer).unwrap();
+            }
+            requests
+        });
+        (address, handle)
+    }
+
+    fn entry(name: &str, register: u16, kind: Kind, order: ByteOrder, scale: f64) -> Entry {
+        Entry {
+            name: name.to_string(),
+            unit_id: 1,
+            function: READ_HOLDING_REGISTERS,
+            register,
+            kind,
+            order,
+            scale,
+            unit: "unknown".to_string(),
+        }
+    }
+
+    // Tests for success.
+
+    #[test]
+    fn test_measure_for_success() {
+        let registers = HashMap::from([
+            ((READ_HOLDING_REGISTERS, 100), 2315),
+            ((READ_HOLDING_REGISTERS, 101), 0x8000),
+            ((READ_HOLDING_REGISTERS, 102), 0x4366),
+            ((READ_HOLDING_REGISTERS, 103), 0xfffe),
+            ((READ_INPUT_REGISTERS, 100), 42),
+        ]);
+        let (address, handle) = server(registers);
+        let mut flow = entry("flow", 100, Kind::U16, ByteOrder::Abcd, 1.0);
+        flow.function = READ_INPUT_REGISTERS;
+        let sensor = ModbusTcpSensor::new(
+            "heatpump".to_string(),
+            Server {
+                address,
+                timeout: time::Duration::from_secs(1),
+            },
+            vec![
+                entry("voltage", 100, Kind::U16, ByteOrder::Abcd, 0.1),
+                entry("power", 101, Kind::F32, ByteOrder::Cdab, 1.0),
+                entry("offset", 103, Kind::I16, ByteOrder::Abcd, 1.0),
+                flow,
+            ],
+        );
+        assert_eq!(
+            values(sensor.measure()),
+            vec![
+                ("heatpump_voltage".to_string(), 231.5),
+                ("heatpump_power".to_string(), 230.5),
+                ("heatpump_offset".to_string(), -2.0),
+                ("heatpump_flow".to_string(), 42.0)
+            ]
+        );
+        // one read per table.
+        assert_eq!(
+            handle.join().unwrap(),
+            vec![
+                (READ_HOLDING_REGISTERS, 100, 4),
+                (READ_INPUT_REGISTERS, 100, 1)
+            ]
+        );
+    }
+
+    // Tests for failure.
+
+    #[test]
+    fn test_measure_for_failure() {
+        // register 200 does not exist.
+        let registers = HashMap::from([
+            ((READ_HOLDING_REGISTERS, 100), 2315),
+            ((READ_HOLDING_REGISTERS, 201), 7),
+        ]);
+        let (address, handle) = server(registers);
+        let sensor = ModbusTcpSensor::new(
+            "heatpump".to_string(),
+            Server {
+                address,
+                timeout: time::Duration::from_secs(1),
+            },
+            vec![
+                entry("voltage", 100, Kind::U16, ByteOrder::Abcd, 0.1),
+                entry("energy", 200, Kind::U32, ByteOrder::Abcd, 1.0),
+            ],
+        );
+        // only the affected entry is missing.
+        assert_eq!(
+            values(sensor.measure()),
+            vec![("heatpump_voltage".to_string(), 231.5)]
+        );
+        handle.join().unwrap();
+
+        let (address, handle) = server(HashMap::new());
+        let sensor = ModbusTcpSensor::new(
+            "heatpump".to_string(),
+            Server {
+                address,
+                timeout: time::Duration::from_secs(1),
+            },
+            vec![entry("energy", 200, Kind::U32, ByteOrder::Abcd, 1.0)],
+        );
+        match sensor.measure() {
+            Err(SensorError::Protocol(msg)) => assert_eq!(
+                msg,
+                "reading 2 registers at 200 failed with: 2 (illegal data address)."
+            ),
+            _ => panic!("exception not reported."),
+        }
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_sensor_type_for_failure() {
+        let sensor_cfg: toml::value::Table =
+            toml::from_str("host='wallbox'\nport=0\nentries=[{ name='power', register=5 }]")
+                .unwrap();
+        assert_eq!(
+            (sensor_type().create)("wallbox", &sensor_cfg, "state").err(),
+            Some(ConfigError::Invalid(
+                "port must be between 1 and 65535.".to_string()
+            ))
+        );
+    }
+}
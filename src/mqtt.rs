@@ -0,0 +1,257 @@
+use std::thread;
+use std::time;
+
+use rumqttc::{Client, MqttOptions, QoS};
+
+use crate::output;
+
+/// Where and how to publish to an MQTT broker.
+pub(crate) struct Broker {
+    pub(crate) host: String,
+    pub(crate) port: u16,
+    pub(crate) client_id: String,
+    /// Username and password; None to connect anonymously.
+    pub(crate) credentials: Option<(String, String)>,
+    pub(crate) topic_prefix: String,
+    pub(crate) retain: bool,
+    /// One JSON object per sensor instead of one topic per metric.
+    pub(crate) json: bool,
+}
+
+/// Publishes each row to an MQTT broker; either <prefix>/<sensor>/<metric> or <prefix>/<sensor> as JSON.
+///
+/// Publishing never blocks the loop: messages are queued for a background thread that (re)connects to the broker;
+/// messages that do not fit into the queue while the broker is unreachable are dropped.
+pub(crate) struct MqttOutput {
+    client: Client,
+    topic_prefix: String,
+    retain: bool,
+    json: bool,
+    sensors: Vec<String>,
+    // (sensor, metric) of each column.
+    keys: Vec<(String, String)>,
+}
+
+impl MqttOutput {
+    pub(crate) fn new(broker: Broker, sensors: Vec<String>) -> MqttOutput {
+        let mut options = MqttOptions::new(broker.client_id, broker.host, broker.port);
+        options.set_keep_alive(time::Duration::from_secs(30));
+        if let Some((username, password)) = broker.credentials {
+            options.set_credentials(username, password);
+        }
+        let (client, mut connection) = Client::new(options, 100);
+        thread::spawn(move || {
+            // ends once the client is gone.
+            for notification in connection.iter() {
+                if let Err(err) = notification {
+                    eprintln!("Lost connection to the MQTT broker; reconnecting: {}", err);
+                    thread::sleep(time::Duration::from_secs(5));
+                }
+            }
+        });
+        MqttOutput {
+            client,
+            topic_prefix: broker.topic_prefix,
+            retain: broker.retain,
+            json: broker.json,
+            sensors,
+            keys: Vec::new(),
+        }
+    }
+
+    /// The (topic, payload) pairs of a row, given the time each value was measured; failed (NaN) values are left
+    /// out, or null in JSON.
+    fn messages(&self, values: &[f64], times: &[f64]) -> Vec<(String, String)> {
+        let mut res = Vec::new();
+        if self.json {
+            // (sensor, time) of each object.
+            let mut sensors: Vec<(&str, f64)> = Vec::new();
+            let mut fields: Vec<(Vec<String>, Vec<f64>)> = Vec::new();
+            for (((sensor, metric), value), time) in self.keys.iter().zip(values).zip(times).skip(1)
+            {
+                let point = (sensor.as_str(), *time);
+                let i = match sensors.iter().position(|s| *s == point) {
+                    Some(i) => i,
+                    None => {
+                        sensors.push(point);
+                        // every object carries the time its values were measured.
+                        fields.push((vec!["timestamp".to_string()], vec![*time]));
+                        sensors.len() - 1
+                    }
+                };
+                fields[i].0.push(metric.clone());
+                fields[i].1.push(*value);
+            }
+            for ((sensor, _), (names, values)) in sensors.iter().zip(fields) {
+                res.push((
+                    format!("{}/{}", self.topic_prefix, sensor),
+                    output::to_json(&names, &values),
+                ));
+            }
+        } else {
+            for ((sensor, metric), value) in self.keys.iter().zip(values).skip(1) {
+                if value.is_finite() {
+                    res.push((
+                        format!("{}/{}/{}", self.topic_prefix, sensor, metric),
+                        value.to_string(),
+                    ));
+                }
+            }
+        }
+        res
+    }
+}
+
+impl output::Output for MqttOutput {
+    fn write_header(&mut self, names: &[String]) {
+        self.keys = names
+            .iter()
+            .map(|n| output::split(&self.sensors, n))
+            .collect();
+    }
+
+    fn write(&mut self, values: &[f64]) -> Result<(), String> {
+        let times = vec![values.first().copied().unwrap_or_default(); values.len()];
+        self.write_at(values, &times)
+    }
+
+    fn write_at(&mut self, values: &[f64], times: &[f64]) -> Result<(), String> {
+        let messages = self.messages(values, times);
+        let mut dropped = 0;
+        for (topic, payload) in messages {
+            if self
+                .client
+                .try_publish(topic, QoS::AtLeastOnce, self.retain, payload)
+                .is_err()
+            {
+                dropped += 1;
+            }
+        }
+        if dropped > 0 {
+            return Err(format!(
+                "could not queue {} messages for the MQTT broker; dropping them",
+                dropped
+            ));
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) {
+        let _ = self.client.try_disconnect();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output::Output;
+
+    fn mqtt(json: bool) -> MqttOutput {
+        let broker = Broker {
+            // nobody listens here.
+            host: "localhost".to_string(),
+            port: 1,
+            client_id: "ogc_test".to_string(),
+            credentials: None,
+            topic_prefix: "home/ogc".to_string(),
+            retain: false,
+            json,
+        };
+        let mut output = MqttOutput::new(broker, vec!["fox".to_string(), "fox_2".to_string()]);
+        output.write_header(&[
+            "timestamp".to_string(),
+            "fox_pvPower".to_string(),
+            "fox_loadsPower".to_string(),
+            "fox_2_pvPower".to_string(),
+            "bat_cycles".to_string(),
+        ]);
+        output
+    }
+
+    // Tests for success.
+
+    #[test]
+    fn test_messages_for_success() {
+        let output = mqtt(false);
+        assert_eq!(
+            output.messages(
+                &[1699920000.0, 1.5, 0.5, f64::NAN, 12.0],
+                &[1699920000.0; 5]
+            ),
+            vec![
+                ("home/ogc/fox/pvPower".to_string(), "1.5".to_string()),
+                ("home/ogc/fox/loadsPower".to_string(), "0.5".to_string()),
+                ("home/ogc/bat/cycles".to_string(), "12".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_json_for_success() {
+        let output = mqtt(true);
+        assert_eq!(
+            output.messages(
+                &[1699920000.0, 1.5, 0.5, f64::NAN, 12.0],
+                &[1699920000.0; 5]
+            ),
+            vec![
+                (
+                    "home/ogc/fox".to_string(),
+                    "{\"timestamp\":1699920000.0,\"pvPower\":1.5,\"loadsPower\":0.5}".to_string()
+                ),
+                (
+                    "home/ogc/fox_2".to_string(),
+                    "{\"timestamp\":1699920000.0,\"pvPower\":null}".to_string()
+                ),
+                (
+                    "home/ogc/bat".to_string(),
+                    "{\"timestamp\":1699920000.0,\"cycles\":12.0}".to_string()
+                ),
+            ]
+        );
+    }
+
+    // Tests for failure.
+
+    #[test]
+    fn test_write_for_failure() {
+        // the broker is unreachable; the loop must not wait for it.
+        let mut output = mqtt(false);
+        let start = time::Instant::now();
+        for i in 0..200 {
+            let _ = output.write(&[1699920000.0 + i as f64, 1.5, 0.5, 2.0, 12.0]);
+        }
+        output.flush();
+        assert!(start.elapsed() < time::Duration::from_secs(1));
+    }
+
+    // Tests for sanity.
+
+    #[test]
+    fn test_times_for_sanity() {
+        let output = mqtt(true);
+        // the late value of fox gets an object of its own, with the time it was measured.
+        assert_eq!(
+            output.messages(
+                &[1699920000.0, 1.5, 0.5, 2.0, f64::NAN],
+                &[
+                    1699920000.0,
+                    1699919960.0,
+                    1699920000.0,
+                    1699920000.0,
+                    1699920000.0
+                ],
+            )[..2],
+            [
+                (
+                    "home/ogc/fox".to_string(),
+                    "{\"timestamp\":1699919960.0,\"pvPower\":1.5}".to_string()
+                ),
+                (
+                    "home/ogc/fox".to_string(),
+                    "{\"timestamp\":1699920000.0,\"loadsPower\":0.5}".to_string()
+                ),
+            ]
+        );
+    }
+}
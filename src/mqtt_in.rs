@@ -0,0 +1,537 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time;
+
+use crate::clock;
+use crate::common;
+use crate::common::{ConfigError, Reading, SensorError};
+use crate::http_json;
+
+/// How long an entry's messages count by default.
+const MAX_AGE_SECS: u64 = 300;
+
+/// Subscribes the connection of a hub to a topic.
+type Subscribe = Box<dyn Fn(&str) + Send>;
+
+/// The topics subscribed on one broker and the latest message on each; shared by all sensors using that broker.
+pub(crate) struct Hub {
+    topics: Mutex<Vec<String>>,
+    messages: Mutex<HashMap<String, (time::SystemTime, Vec<u8>)>>,
+    // subscribes the connection to a topic; None w/o a connection.
+    subscribe: Mutex<Option<Subscribe>>,
+}
+
+impl Hub {
+    pub(crate) fn new() -> Hub {
+        Hub {
+            topics: Mutex::new(Vec::new()),
+            messages: Mutex::new(HashMap::new()),
+            subscribe: Mutex::new(None),
+        }
+    }
+
+    /// Adds a topic; it is subscribed right away and again whenever the connection is re-established.
+    pub(crate) fn add(&self, topic: &str) {
+        let mut topics = self.topics.lock().unwrap_or_else(|e| e.into_inner());
+        if topics.iter().any(|t| t == topic) {
+            return;
+        }
+        topics.push(topic.to_string());
+        if let Some(subscribe) = &*self.subscribe.lock().unwrap_or_else(|e| e.into_inner()) {
+            subscribe(topic);
+        }
+    }
+
+    #[cfg_attr(not(feature = "mqtt"), allow(dead_code))]
+    pub(crate) fn topics(&self) -> Vec<String> {
+        self.topics
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    /// Keeps a message if it is on one of the topics.
+    #[cfg_attr(not(feature = "mqtt"), allow(dead_code))]
+    pub(crate) fn handle(&self, topic: &str, payload: &[u8]) {
+        if !self.topics().iter().any(|t| t == topic) {
+            return;
+        }
+        self.messages
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(
+                topic.to_string(),
+                (time::SystemTime::now(), payload.to_vec()),
+            );
+    }
+
+    /// The latest message on a topic and when it was received.
+    pub(crate) fn latest(&self, topic: &str) -> Option<(time::SystemTime, Vec<u8>)> {
+        self.messages
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(topic)
+            .cloned()
+    }
+}
+
+#[cfg_attr(not(feature = "mqtt"), allow(dead_code))]
+fn hubs() -> &'static Mutex<HashMap<String, Arc<Hub>>> {
+    static HUBS: OnceLock<Mutex<HashMap<String, Arc<Hub>>>> = OnceLock::new();
+    HUBS.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/// The hub of a broker; connected on first use.
+#[cfg_attr(not(feature = "mqtt"), allow(dead_code))]
+fn hub(broker: &str, connect: impl FnOnce(&Arc<Hub>)) -> Arc<Hub> {
+    let mut hubs = hubs().lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(hub) = hubs.get(broker) {
+        return hub.clone();
+    }
+    let hub = Arc::new(Hub::new());
+    connect(&hub);
+    hubs.insert(broker.to_string(), hub.clone());
+    hub
+}
+
+/// Whether a device is available given the message on its availability topic, e.g. its last will: offline if the
+/// payload is offline, or a JSON object with that state as Zigbee2MQTT sends.
+pub(crate) fn available(payload: &[u8]) -> bool {
+    let text = String::from_utf8_lossy(payload);
+    let state = match serde_json::from_str::<serde_json::Value>(&text) {
+        Ok(serde_json::Value::Object(map)) => map
+            .get("state")
+            .and_then(|s| s.as_str())
+            .unwrap_or_default()
+            .to_string(),
+        _ => text.trim().to_string(),
+    };
+    !state.eq_ignore_ascii_case("offline")
+}
+
+/// The value in a payload: a bare number, or the number the pointer points to in JSON.
+pub(crate) fn parse(payload: &[u8], pointer: Option<&str>) -> Result<f64, String> {
+    let text = std::str::from_utf8(payload).map_err(|e| e.to_string())?;
+    match pointer {
+        Some(pointer) => {
+            let body: serde_json::Value = serde_json::from_str(text).map_err(|e| e.to_string())?;
+            http_json::extract(&body, pointer)
+        }
+        None => common::parse_number(text, false).map_err(|e| e.to_string()),
+    }
+}
+
+/// When a payload was measured: the epoch secs the pointer points to in JSON, if the device sends them.
+pub(crate) fn parse_time(payload: &[u8], pointer: &str) -> Option<f64> {
+    let body: serde_json::Value = serde_json::from_slice(payload).ok()?;
+    http_json::extract(&body, pointer).ok()
+}
+
+/// A value recorded from a topic.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Entry {
+    pub(crate) name: String,
+    pub(crate) topic: String,
+    pub(crate) pointer: Option<String>,
+    /// Where the payload says when it was measured; it counts as measured when it was received otherwise.
+    pub(crate) timestamp_pointer: Option<String>,
+    pub(crate) max_age: time::Duration,
+    /// Where the device announces whether it is online, e.g. its last will.
+    pub(crate) availability: Option<String>,
+}
+
+/// Reports the latest value published on MQTT topics, e.g. by ESP devices; as bare numbers or in JSON.
+///
+/// All sensors on the same broker share one connection. An entry is missing while its latest message is older than
+/// its maximum age or its device announced it is offline.
+pub struct MqttSensor {
+    name: String,
+    entries: Vec<Entry>,
+    hub: Arc<Hub>,
+}
+
+impl MqttSensor {
+    pub(crate) fn new(name: String, entries: Vec<Entry>, hub: Arc<Hub>) -> MqttSensor {
+        for entry in &entries {
+            hub.add(&entry.topic);
+            if let Some(availability) = &entry.availability {
+                hub.add(availability);
+            }
+        }
+        MqttSensor { name, entries, hub }
+    }
+
+    /// The latest value of an entry and when it was measured.
+    fn value(&self, entry: &Entry) -> Result<Option<(f64, f64)>, String> {
+        if let Some(availability) = &entry.availability {
+            if let Some((_, payload)) = self.hub.latest(availability) {
+                if !available(&payload) {
+                    return Ok(None);
+                }
+            }
+        }
+        match self.hub.latest(&entry.topic) {
+            Some((received, payload))
+                if received.elapsed().unwrap_or_default() <= entry.max_age =>
+            {
+                let value = parse(&payload, entry.pointer.as_deref())?;
+                let time = entry
+                    .timestamp_pointer
+                    .as_deref()
+                    .and_then(|pointer| parse_time(&payload, pointer))
+                    .unwrap_or_else(|| clock::epoch_secs(received));
+                Ok(Some((value, time)))
+            }
+            _ => Ok(None),
+        }
+    }
+}
+
+impl common::Sensor for MqttSensor {
+    fn get_names(&self) -> Vec<String> {
+        self.entries
+            .iter()
+            .map(|entry| format!("{}_{}", self.name, entry.name))
+            .collect()
+    }
+
+    fn get_units(&self) -> Vec<String> {
+        vec!["unknown".to_string(); self.entries.len()]
+    }
+
+    fn measure(&self) -> Result<Vec<Reading>, SensorError> {
+        let mut res = Vec::new();
+        for (name, entry) in self.get_names().into_iter().zip(&self.entries) {
+            match self.value(entry) {
+                Ok(Some((value, time))) => res.push(Reading {
+                    time: Some(time),
+                    ..Reading::new(name, value)
+                }),
+                Ok(None) => {}
+                Err(err) => eprintln!("Could not read {} from {}: {}", name, entry.topic, err),
+            }
+        }
+        if res.is_empty() {
+            return Err(SensorError::Protocol(
+                "no recent message on any of the topics.".to_string(),
+            ));
+        }
+        Ok(res)
+    }
+}
+
+#[cfg(feature = "mqtt")]
+mod broker {
+    use std::sync::Arc;
+    use std::thread;
+    use std::time;
+
+    use rumqttc::{Client, Event, MqttOptions, Packet, QoS};
+
+    use super::Hub;
+
+    const MAX_BACKOFF: time::Duration = time::Duration::from_secs(60);
+
+    /// Connects the hub to a broker; a background thread feeds it and reconnects with backoff.
+    pub(super) fn connect(hub: &Arc<Hub>, options: MqttOptions) {
+        let (client, mut connection) = Client::new(options, 100);
+        let subscriber = client.clone();
+        *hub.subscribe.lock().unwrap_or_else(|e| e.into_inner()) = Some(Box::new(move |topic| {
+            let _ = subscriber.clone().try_subscribe(topic, QoS::AtMostOnce);
+        }));
+        let hub = hub.clone();
+        thread::spawn(move || {
+            let mut backoff = time::Duration::from_secs(1);
+            for notification in connection.iter() {
+                match notification {
+                    // a clean session; subscribed anew.
+                    Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                        backoff = time::Duration::from_secs(1);
+                        for topic in hub.topics() {
+                            let _ = client.clone().try_subscribe(topic, QoS::AtMostOnce);
+                        }
+                    }
+                    Ok(Event::Incoming(Packet::Publish(publish))) => {
+                        hub.handle(&publish.topic, &publish.payload)
+                    }
+                    Ok(_) => {}
+                    Err(err) => {
+                        eprintln!(
+                            "Lost connection to the MQTT broker; reconnecting in {:?}: {}",
+                            backoff, err
+                        );
+                        thread::sleep(backoff);
+                        backoff = (backoff * 2).min(MAX_BACKOFF);
+                    }
+                }
+            }
+        });
+    }
+}
+
+/// The hub of the broker in the configuration.
+#[cfg(feature = "mqtt")]
+fn get_hub(name: &str, sensor_cfg: &toml::value::Table) -> Result<Arc<Hub>, ConfigError> {
+    let text = |key: &str| sensor_cfg.get(key).and_then(|v| v.as_str());
+    let host = text("host").unwrap_or("localhost");
+    let port = match sensor_cfg.get("port").map(|v| v.as_integer()) {
+        None => 1883,
+        Some(Some(port)) if (1..=65535).contains(&port) => port as u16,
+        Some(_) => {
+            return Err(ConfigError::Invalid(
+                "port must be between 1 and 65535.".to_string(),
+            ))
+        }
+    };
+    let credentials = match (text("username"), text("password")) {
+        (Some(username), Some(password)) => Some((username, password)),
+        (None, None) => None,
+        _ => {
+            return Err(ConfigError::Invalid(
+                "set both username and password, or neither.".to_string(),
+            ))
+        }
+    };
+    let broker = format!(
+        "{}@{}:{}",
+        credentials.map(|(u, _)| u).unwrap_or_default(),
+        host,
+        port
+    );
+    Ok(hub(&broker, |hub| {
+        let mut options =
+            rumqttc::MqttOptions::new(format!("open_green_compute_{}", name), host, port);
+        options.set_keep_alive(time::Duration::from_secs(30));
+        if let Some((username, password)) = credentials {
+            options.set_credentials(username, password);
+        }
+        broker::connect(hub, options);
+    }))
+}
+
+#[cfg(not(feature = "mqtt"))]
+fn get_hub(name: &str, _: &toml::value::Table) -> Result<Arc<Hub>, ConfigError> {
+    Err(ConfigError::Invalid(format!(
+        "mqtt sensor {} requires the mqtt feature.",
+        name
+    )))
+}
+
+/// Any values published to an MQTT broker.
+pub(crate) fn sensor_type() -> common::SensorType {
+    common::SensorType {
+        name: "mqtt",
+        required: &["entries"],
+        optional: &["host", "port", "username", "password"],
+        create: |name, sensor_cfg, _| {
+            let invalid = |msg: &str| ConfigError::Invalid(msg.to_string());
+            let entries = sensor_cfg["entries"]
+                .as_array()
+                .filter(|a| !a.is_empty())
+                .ok_or_else(|| invalid("entries must be a non-empty array."))?
+                .iter()
+                .map(|entry| {
+                    let field = |key: &str| entry.get(key).and_then(|v| v.as_str());
+                    let (name, topic) = match (field("name"), field("topic")) {
+                        (Some(name), Some(topic)) => (name.to_string(), topic.to_string()),
+                        _ => return Err(invalid("each entry must have a name and a topic.")),
+                    };
+                    let pointer = match entry.get("json_pointer") {
+                        Some(v) => Some(
+                            v.as_str()
+                                .filter(|p| p.is_empty() || p.starts_with('/'))
+                                .ok_or_else(|| invalid("json_pointer must start with '/'."))?
+                                .to_string(),
+                        ),
+                        None => None,
+                    };
+                    let timestamp_pointer = match entry.get("timestamp_pointer") {
+                        Some(v) => Some(
+                            v.as_str()
+                                .filter(|p| p.starts_with('/'))
+                                .ok_or_else(|| invalid("timestamp_pointer must start with '/'."))?
+                                .to_string(),
+                        ),
+                        None => None,
+                    };
+                    let max_age = entry
+                        .get("max_age_secs")
+                        .map(|v| v.as_integer())
+                        .unwrap_or(Some(MAX_AGE_SECS as i64))
+                        .filter(|secs| *secs > 0)
+                        .ok_or_else(|| invalid("max_age_secs must be a positive integer."))?;
+                    Ok(Entry {
+                        name,
+                        topic,
+                        pointer,
+                        timestamp_pointer,
+                        max_age: time::Duration::from_secs(max_age as u64),
+                        availability: field("availability_topic").map(|t| t.to_string()),
+                    })
+                })
+                .collect::<Result<_, _>>()?;
+            Ok(Box::new(MqttSensor::new(
+                name.to_string(),
+                entries,
+                get_hub(name, sensor_cfg)?,
+            )))
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::values;
+    use crate::common::Sensor;
+
+    fn entry(name: &str, topic: &str, pointer: Option<&str>) -> Entry {
+        Entry {
+            name: name.to_string(),
+            topic: topic.to_string(),
+            pointer: pointer.map(|p| p.to_string()),
+            timestamp_pointer: None,
+            max_age: time::Duration::from_secs(MAX_AGE_SECS),
+            availability: None,
+        }
+    }
+
+    // Tests for success.
+
+    #[test]
+    fn test_measure_for_success() {
+        let hub = Arc::new(Hub::new());
+        let sensor = MqttSensor::new(
+            "esp".to_string(),
+            vec![
+                entry("temperature", "garage/temperature", None),
+                entry("power", "tele/heater/SENSOR", Some("/ENERGY/Power")),
+            ],
+            hub.clone(),
+        );
+        hub.handle("garage/temperature", b"12.5");
+        hub.handle(
+            "tele/heater/SENSOR",
+            b"{\"Time\":\"2024-05-01T12:00:00\",\"ENERGY\":{\"Power\":1450}}",
+        );
+        // not subscribed.
+        hub.handle("garage/humidity", b"71");
+        assert_eq!(
+            values(sensor.measure()),
+            vec![
+                ("esp_temperature".to_string(), 12.5),
+                ("esp_power".to_string(), 1450.0)
+            ]
+        );
+        assert!(hub.latest("garage/humidity").is_none());
+    }
+
+    #[test]
+    fn test_hub_for_success() {
+        let mut connected = 0;
+        let first = hub("test@localhost:1883", |_| connected += 1);
+        let second = hub("test@localhost:1883", |_| connected += 1);
+        let other = hub("test@localhost:1884", |_| connected += 1);
+        // one connection per broker.
+        assert!(Arc::ptr_eq(&first, &second));
+        assert!(!Arc::ptr_eq(&first, &other));
+        assert_eq!(connected, 2);
+    }
+
+    // Tests for failure.
+
+    #[test]
+    fn test_measure_for_failure() {
+        let hub = Arc::new(Hub::new());
+        let sensor = MqttSensor::new(
+            "esp".to_string(),
+            vec![entry("power", "tele/heater/SENSOR", Some("/ENERGY/Power"))],
+            hub.clone(),
+        );
+        // nothing yet.
+        assert!(matches!(sensor.measure(), Err(SensorError::Protocol(_))));
+        hub.handle("tele/heater/SENSOR", b"{\"ENERGY\":{}}");
+        assert!(sensor.measure().is_err());
+        assert!(parse(b"on", None).is_err());
+    }
+
+    #[test]
+    fn test_sensor_type_for_failure() {
+        let sensor_cfg: toml::value::Table =
+            toml::from_str("entries=[{ name='power', topic='a/b', json_pointer='ENERGY' }]")
+                .unwrap();
+        assert_eq!(
+            (sensor_type().create)("esp", &sensor_cfg, "state").err(),
+            Some(ConfigError::Invalid(
+                "json_pointer must start with '/'.".to_string()
+            ))
+        );
+        let sensor_cfg: toml::value::Table =
+            toml::from_str("entries=[{ name='power', topic='a/b', max_age_secs=0 }]").unwrap();
+        assert!((sensor_type().create)("esp", &sensor_cfg, "state").is_err());
+    }
+
+    // Tests for sanity.
+
+    #[test]
+    fn test_time_for_sanity() {
+        let hub = Arc::new(Hub::new());
+        let mut late = entry("power", "tele/heater/SENSOR", Some("/ENERGY/Power"));
+        late.timestamp_pointer = Some("/ts".to_string());
+        let sensor = MqttSensor::new(
+            "esp".to_string(),
+            vec![entry("temperature", "garage/temperature", None), late],
+            hub.clone(),
+        );
+        let now = clock::epoch_secs(time::SystemTime::now());
+        hub.handle("garage/temperature", b"12.5");
+        // measured 40 seconds before it arrived.
+        let measured = (now - 40.0).floor();
+        let payload = format!("{{\"ts\":{},\"ENERGY\":{{\"Power\":1450}}}}", measured);
+        hub.handle("tele/heater/SENSOR", payload.as_bytes());
+        let readings = sensor.measure().unwrap();
+        // w/o a timestamp in the payload, when it was received.
+        assert!((readings[0].time.unwrap() - now).abs() < 5.0);
+        assert_eq!(readings[1].time, Some(measured));
+        assert_eq!(parse_time(b"{\"ts\":\"soon\"}", "/ts"), None);
+    }
+
+    #[test]
+    fn test_staleness_for_sanity() {
+        let hub = Arc::new(Hub::new());
+        let mut stale = entry("humidity", "garage/humidity", None);
+        stale.max_age = time::Duration::from_secs(60);
+        let mut offline = entry("power", "shellies/pump/power", None);
+        offline.availability = Some("shellies/pump/online".to_string());
+        let sensor = MqttSensor::new(
+            "esp".to_string(),
+            vec![
+                entry("temperature", "garage/temperature", None),
+                stale,
+                offline,
+            ],
+            hub.clone(),
+        );
+        hub.handle("garage/temperature", b"12.5");
+        hub.handle("garage/humidity", b"71");
+        hub.handle("shellies/pump/power", b"310.2");
+        // an hour ago.
+        hub.messages
+            .lock()
+            .unwrap()
+            .get_mut("garage/humidity")
+            .unwrap()
+            .0 = time::SystemTime::now() - time::Duration::from_secs(3600);
+        assert_eq!(values(sensor.measure()).len(), 2);
+        // its last will.
+        hub.handle("shellies/pump/online", b"false");
+        assert_eq!(values(sensor.measure()).len(), 2);
+        hub.handle("shellies/pump/online", b"Offline");
+        assert_eq!(
+            values(sensor.measure()),
+            vec![("esp_temperature".to_string(), 12.5)]
+        );
+        assert!(!available(b"{\"state\":\"offline\"}"));
+        assert!(available(b"online"));
+    }
+}
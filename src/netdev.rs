@@ -0,0 +1,324 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs;
+use std::path;
+use std::time;
+
+use crate::clock;
+use crate::common;
+use crate::common::{ConfigError, Reading, SensorError};
+use crate::privacy;
+
+/// The rates reported per interface, in the order of the counters.
+const METRICS: [(&str, &str); 4] = [
+    ("rx_bytes_per_s", "B/s"),
+    ("tx_bytes_per_s", "B/s"),
+    ("rx_packets_per_s", "packets/s"),
+    ("tx_packets_per_s", "packets/s"),
+];
+
+/// The received and transmitted bytes and packets of each interface given the contents of /proc/net/dev.
+pub(crate) fn parse_netdev(netdev: &str) -> Vec<(String, [u64; 4])> {
+    netdev
+        .lines()
+        .filter_map(|line| {
+            // the headers have no ':'; large counters may follow it w/o a space.
+            let (iface, counters) = line.split_once(':')?;
+            let counters: Vec<u64> = counters
+                .split_whitespace()
+                .map(|c| c.parse().ok())
+                .collect::<Option<_>>()?;
+            if counters.len() < 10 {
+                return None;
+            }
+            Some((
+                iface.trim().to_string(),
+                [counters[0], counters[8], counters[1], counters[9]],
+            ))
+        })
+        .collect()
+}
+
+/// The increase of a counter; None for a reset.
+///
+/// 32-bit counters (e.g. on a Pi) wrap around. A counter going back is only taken as such if it was close enough to the
+/// top for the wrap to be plausible - by at most half the range; anything else is a reset, e.g. of a counter that was
+/// small when the interface went down and up again.
+fn increase(previous: u64, current: u64) -> Option<u64> {
+    if current >= previous {
+        return Some(current - previous);
+    }
+    if previous > u32::MAX as u64 {
+        return None;
+    }
+    let wrapped = u32::MAX as u64 - previous + current + 1;
+    (wrapped <= u32::MAX as u64 / 2).then_some(wrapped)
+}
+
+/// Reports the traffic of network interfaces in bytes and packets per second.
+///
+/// The rates are computed from the counters since the previous measurement, so the first one - and the first after an
+/// interface (re)appeared - reports nothing for it.
+pub struct NetdevSensor {
+    name: String,
+    proc_dir: String,
+    interfaces: Vec<String>,
+    // the counters of each interface and when they were read.
+    last: RefCell<HashMap<String, (f64, [u64; 4])>>,
+}
+
+impl NetdevSensor {
+    pub(crate) fn new(name: String, proc_dir: String, interfaces: Vec<String>) -> NetdevSensor {
+        NetdevSensor {
+            name,
+            proc_dir,
+            interfaces,
+            last: RefCell::new(HashMap::new()),
+        }
+    }
+
+    fn measure_at(&self, now: f64) -> Result<Vec<Reading>, SensorError> {
+        let names = common::Sensor::get_names(self);
+        let file = path::Path::new(&self.proc_dir).join("net").join("dev");
+        let netdev = fs::read_to_string(&file)
+            .map_err(|e| SensorError::Io(format!("cannot read {}: {}", file.display(), e)))?;
+        let counters: HashMap<String, [u64; 4]> = parse_netdev(&netdev).into_iter().collect();
+        if counters.is_empty() {
+            return Err(SensorError::Parse(format!(
+                "no interfaces in {}.",
+                file.display()
+            )));
+        }
+        let mut last = self.last.borrow_mut();
+        // gone; starts over once it is back.
+        last.retain(|iface, _| counters.contains_key(iface));
+        let mut res = Vec::new();
+        for (i, iface) in self.interfaces.iter().enumerate() {
+            let current = match counters.get(iface) {
+                Some(current) => *current,
+                None => continue,
+            };
+            if let Some((t, previous)) = last.insert(iface.clone(), (now, current)) {
+                if now <= t {
+                    continue;
+                }
+                for (j, (p, c)) in previous.iter().zip(current).enumerate() {
+                    if let Some(delta) = increase(*p, c) {
+                        res.push(Reading::new(
+                            names[4 * i + j].clone(),
+                            delta as f64 / (now - t),
+                        ));
+                    }
+                }
+            }
+        }
+        Ok(res)
+    }
+}
+
+impl common::Sensor for NetdevSensor {
+    fn get_names(&self) -> Vec<String> {
+        self.interfaces
+            .iter()
+            .flat_map(|iface| {
+                METRICS
+                    .iter()
+                    .map(move |(metric, _)| format!("{}_{}_{}", self.name, iface, metric))
+            })
+            .collect()
+    }
+
+    fn get_units(&self) -> Vec<String> {
+        self.interfaces
+            .iter()
+            .flat_map(|_| METRICS.iter().map(|(_, unit)| unit.to_string()))
+            .collect()
+    }
+
+    fn measure(&self) -> Result<Vec<Reading>, SensorError> {
+        self.measure_at(clock::epoch_secs(time::SystemTime::now()))
+    }
+}
+
+/// Traffic of the host's network interfaces.
+pub(crate) fn sensor_type() -> common::SensorType {
+    common::SensorType {
+        name: "netdev",
+        required: &["interfaces"],
+        optional: &["path"],
+        create: |name, sensor_cfg, _| {
+            let proc_dir = match sensor_cfg.get("path") {
+                Some(v) => v
+                    .as_str()
+                    .ok_or_else(|| ConfigError::Invalid("path must be a string.".to_string()))?,
+                None => "/proc",
+            };
+            let patterns = sensor_cfg["interfaces"]
+                .as_array()
+                .and_then(|a| a.iter().map(|i| i.as_str()).collect::<Option<Vec<_>>>())
+                .ok_or_else(|| {
+                    ConfigError::Invalid("interfaces must be an array of strings.".to_string())
+                })?;
+            // globs are expanded once; the columns are fixed from then on.
+            let present: Vec<String> =
+                fs::read_to_string(path::Path::new(proc_dir).join("net").join("dev"))
+                    .map(|netdev| parse_netdev(&netdev).into_iter().map(|(i, _)| i).collect())
+                    .unwrap_or_default();
+            let mut interfaces: Vec<String> = Vec::new();
+            for pattern in &patterns {
+                let matching: Vec<String> = if pattern.contains(['*', '?']) {
+                    present
+                        .iter()
+                        .filter(|i| privacy::matches(pattern, i))
+                        .cloned()
+                        .collect()
+                } else {
+                    vec![pattern.to_string()]
+                };
+                for iface in matching {
+                    if !interfaces.contains(&iface) {
+                        interfaces.push(iface);
+                    }
+                }
+            }
+            if interfaces.is_empty() {
+                return Err(ConfigError::Invalid(format!(
+                    "no interfaces match {}.",
+                    patterns.join(", ")
+                )));
+            }
+            Ok(Box::new(NetdevSensor::new(
+                name.to_string(),
+                proc_dir.to_string(),
+                interfaces,
+            )))
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::values;
+    use crate::common::Sensor;
+
+    const NETDEV0: &str = "Inter-|   Receive                                                |  Transmit
+ face |bytes    packets errs drop fifo frame compressed multicast|bytes    packets errs drop fifo colls carrier compressed
+    lo:  120000    1000    0    0    0     0          0         0   120000    1000    0    0    0     0       0          0
+  eth0: 5000000    4000    0    0    0     0          0         0  2000000    3000    0    0    0     0       0          0
+ wlan0:   10000     100    0    0    0     0          0         0     5000      50    0    0    0     0       0          0
+";
+
+    // 10s later; eth0 received 1 MB/s while the backup uploaded 5 MB/s.
+    const NETDEV1: &str = "Inter-|   Receive                                                |  Transmit
+ face |bytes    packets errs drop fifo frame compressed multicast|bytes    packets errs drop fifo colls carrier compressed
+    lo:  120000    1000    0    0    0     0          0         0   120000    1000    0    0    0     0       0          0
+  eth0:15000000    9000    0    0    0     0          0         0 52000000   43000    0    0    0     0       0          0
+";
+
+    fn proc_dir(dir: &str, netdev: &str) {
+        fs::create_dir_all(path::Path::new(dir).join("net")).unwrap();
+        fs::write(path::Path::new(dir).join("net").join("dev"), netdev).unwrap();
+    }
+
+    // Tests for success.
+
+    #[test]
+    fn test_parse_for_success() {
+        assert_eq!(
+            parse_netdev(NETDEV1),
+            vec![
+                ("lo".to_string(), [120000, 120000, 1000, 1000]),
+                ("eth0".to_string(), [15000000, 52000000, 9000, 43000])
+            ]
+        );
+    }
+
+    #[test]
+    fn test_measure_for_success() {
+        let dir = "netdev_test0";
+        proc_dir(dir, NETDEV0);
+        let sensor =
+            NetdevSensor::new("net".to_string(), dir.to_string(), vec!["eth0".to_string()]);
+        assert_eq!(
+            sensor.get_names(),
+            vec![
+                "net_eth0_rx_bytes_per_s",
+                "net_eth0_tx_bytes_per_s",
+                "net_eth0_rx_packets_per_s",
+                "net_eth0_tx_packets_per_s"
+            ]
+        );
+        // nothing to compare to yet.
+        assert!(values(sensor.measure_at(1000.0)).is_empty());
+        proc_dir(dir, NETDEV1);
+        assert_eq!(
+            values(sensor.measure_at(1010.0)),
+            vec![
+                ("net_eth0_rx_bytes_per_s".to_string(), 1000000.0),
+                ("net_eth0_tx_bytes_per_s".to_string(), 5000000.0),
+                ("net_eth0_rx_packets_per_s".to_string(), 500.0),
+                ("net_eth0_tx_packets_per_s".to_string(), 4000.0)
+            ]
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_increase_for_success() {
+        assert_eq!(increase(100, 300), Some(200));
+        // a 32-bit counter wrapped.
+        assert_eq!(increase(u32::MAX as u64 - 99, 100), Some(200));
+    }
+
+    // Tests for failure.
+
+    #[test]
+    fn test_increase_for_failure() {
+        // a 64-bit counter went back: reset.
+        assert_eq!(increase(5000000000, 100), None);
+        // a small counter went back: reset, not a wrap of almost 4 GB.
+        assert_eq!(increase(5000, 100), None);
+        assert_eq!(increase(u32::MAX as u64 / 2, 0), None);
+    }
+
+    #[test]
+    fn test_sensor_type_for_failure() {
+        let dir = "netdev_test1";
+        proc_dir(dir, NETDEV0);
+        let sensor_cfg: toml::value::Table =
+            toml::from_str("path='netdev_test1'\ninterfaces=['wg*']").unwrap();
+        assert_eq!(
+            (sensor_type().create)("net", &sensor_cfg, "state").err(),
+            Some(ConfigError::Invalid("no interfaces match wg*.".to_string()))
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    // Tests for sanity.
+
+    #[test]
+    fn test_appear_for_sanity() {
+        let dir = "netdev_test2";
+        proc_dir(dir, NETDEV0);
+        let sensor_cfg: toml::value::Table =
+            toml::from_str("path='netdev_test2'\ninterfaces=['eth*', 'wlan0']").unwrap();
+        let sensor = (sensor_type().create)("net", &sensor_cfg, "state").unwrap();
+        assert_eq!(sensor.get_names().len(), 8);
+        let sensor = NetdevSensor::new(
+            "net".to_string(),
+            dir.to_string(),
+            vec!["eth0".to_string(), "wlan0".to_string()],
+        );
+        sensor.measure_at(1000.0).unwrap();
+        // wlan0 is gone.
+        proc_dir(dir, NETDEV1);
+        assert_eq!(values(sensor.measure_at(1010.0)).len(), 4);
+        // and back, with its counters reset; nothing to compare to.
+        let wlan0 = " wlan0:     200       2    0    0    0     0          0         0      100       1    0    0    0     0       0          0\n";
+        proc_dir(dir, &format!("{}{}", NETDEV1, wlan0));
+        let res = values(sensor.measure_at(1020.0));
+        assert!(res.iter().all(|(name, _)| name.starts_with("net_eth0")));
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
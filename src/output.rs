@@ -0,0 +1,1261 @@
+use std::collections::VecDeque;
+use std::fs;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::os::unix::fs::MetadataExt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time;
+
+use chrono::format::{Item, StrftimeItems};
+
+use crate::clock;
+use crate::compress;
+use crate::csv_out;
+use crate::migrate;
+use crate::privacy;
+use crate::retention;
+use crate::tz;
+
+// rows dropped by all CSV outputs since the start; for the metrics.
+static DROPPED_ROWS: AtomicU64 = AtomicU64::new(0);
+
+/// The number of rows that could not be written to CSV files and were dropped as too many piled up.
+pub(crate) fn dropped_rows() -> u64 {
+    DROPPED_ROWS.load(Ordering::Relaxed)
+}
+
+/// Defines a destination for the rows of the loop.
+pub(crate) trait Output {
+    /// Called before write_header with the unit of each column, if units are to be written; most outputs have no
+    /// place for them.
+    fn write_units(&mut self, _units: &[String]) {}
+    fn write_header(&mut self, names: &[String]);
+    /// Errors only lose the row for this output; the dispatcher logs them and carries on with the others.
+    fn write(&mut self, values: &[f64]) -> Result<(), String>;
+    /// Writes a row along with when each of its values was measured (epoch secs); for outputs that can place values
+    /// at their own time instead of that of the row.
+    fn write_at(&mut self, values: &[f64], _times: &[f64]) -> Result<(), String> {
+        self.write(values)
+    }
+    /// Called once when the loop stops; outputs holding on to rows must hand them on now.
+    fn flush(&mut self) {}
+}
+
+/// What to do when an existing CSV file has another header than the one the loop would write.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum OnMismatch {
+    /// Refuse to start.
+    Fail,
+    /// Move the existing file aside and start a fresh one.
+    Rotate,
+    /// Add the new columns to the existing file, if its columns are all still there in the same order.
+    Migrate,
+}
+
+impl OnMismatch {
+    pub(crate) fn parse(value: &str) -> OnMismatch {
+        match value {
+            "fail" => OnMismatch::Fail,
+            "rotate" => OnMismatch::Rotate,
+            "migrate" => OnMismatch::Migrate,
+            _ => panic!(
+                "on_header_mismatch must be one of 'fail', 'rotate' or 'migrate'; got: {}.",
+                value
+            ),
+        }
+    }
+}
+
+/// When appended rows are forced from the page cache to the storage device.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum SyncMode {
+    /// Left to the operating system.
+    None,
+    /// After every row.
+    Line,
+    /// After a row, once the given time has passed since the last sync.
+    Interval(time::Duration),
+}
+
+impl SyncMode {
+    pub(crate) fn parse(value: &str) -> SyncMode {
+        let interval = value
+            .strip_prefix("interval:")
+            .and_then(|secs| secs.parse::<f64>().ok())
+            .filter(|secs| *secs > 0.0);
+        match (value, interval) {
+            ("none", _) => SyncMode::None,
+            ("line", _) => SyncMode::Line,
+            (_, Some(secs)) => SyncMode::Interval(time::Duration::from_secs_f64(secs)),
+            _ => panic!(
+                "sync must be one of 'none', 'line' or 'interval:<secs>'; got: {}.",
+                value
+            ),
+        }
+    }
+}
+
+/// Appends rows to a CSV file; the header is only written when the file is created.
+///
+/// The file is kept open and buffered; it is reopened when it was deleted or moved underneath.
+/// Rows that could not be written are kept (up to max_pending, dropping the oldest) and written before the next one.
+/// The filename can contain strftime placeholders like 'data-%Y-%m-%d.csv'; a new file is started
+/// whenever the rendered name of a row (by its timestamp, the first column) changes.
+pub(crate) struct CsvOutput {
+    pattern: String,
+    on_mismatch: OnMismatch,
+    zone: tz::Zone,
+    header: String,
+    // a second header line with the unit of each column.
+    units: Option<String>,
+    // the file rows currently go to.
+    path: Option<String>,
+    compress_rotated: bool,
+    compressions: Vec<thread::JoinHandle<()>>,
+    format: csv_out::Format,
+    // the open file and its inode; to notice it was replaced.
+    writer: Option<(BufWriter<fs::File>, u64)>,
+    flush_every: usize,
+    // rows handed to the writer since it was last flushed; written again if flushing fails.
+    unflushed: Vec<Vec<f64>>,
+    sync: SyncMode,
+    last_sync: time::Instant,
+    retention: Option<retention::Retention>,
+    // rows that could not be written yet; oldest first.
+    pending: VecDeque<Vec<f64>>,
+    max_pending: usize,
+    dropped: u64,
+}
+
+impl CsvOutput {
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn new(
+        pattern: String,
+        on_mismatch: OnMismatch,
+        zone: tz::Zone,
+        compress_rotated: bool,
+        format: csv_out::Format,
+        flush_every: usize,
+        sync: SyncMode,
+        retention: Option<retention::Retention>,
+        max_pending: usize,
+    ) -> CsvOutput {
+        if StrftimeItems::new(&pattern).any(|item| item == Item::Error) {
+            panic!("invalid placeholder in filename: {}.", pattern);
+        }
+        CsvOutput {
+            pattern,
+            on_mismatch,
+            zone,
+            header: String::new(),
+            units: None,
+            path: None,
+            compress_rotated,
+            compressions: Vec::new(),
+            format,
+            writer: None,
+            flush_every: flush_every.max(1),
+            unflushed: Vec::new(),
+            sync,
+            last_sync: time::Instant::now(),
+            retention,
+            pending: VecDeque::new(),
+            max_pending: max_pending.max(1),
+            dropped: 0,
+        }
+    }
+
+    /// Done with a file; compresses it in the background if configured.
+    fn rotated(&mut self, path: String) {
+        if self.compress_rotated {
+            self.compressions.retain(|c| !c.is_finished());
+            self.compressions.push(compress::spawn(path));
+        }
+    }
+
+    fn is_pattern(&self) -> bool {
+        self.pattern.contains('%')
+    }
+
+    /// Switches to the given file; writes the header if the file is new.
+    fn open(&mut self, path: String) -> Result<(), String> {
+        match existing_header(&path) {
+            Some(existing) if existing != self.header => match self.on_mismatch {
+                OnMismatch::Fail => panic!(
+                    "the header of {} does not match the configured sensors; expected: {}; found: {}. \
+                    Move the file aside or set on_header_mismatch to 'rotate' or 'migrate' in the general section.",
+                    path, self.header, existing
+                ),
+                OnMismatch::Rotate => {
+                    let now = clock::epoch_secs(time::SystemTime::now()) as u64;
+                    let aside = format!("{}.{}", path, now);
+                    fs::rename(&path, &aside)
+                        .map_err(|e| format!("could not move {} aside: {}", path, e))?;
+                    eprintln!("The header of {} did not match; moved it to {}.", path, aside);
+                    create(&path, &self.header, self.units.as_deref())?;
+                    self.rotated(aside);
+                }
+                OnMismatch::Migrate => {
+                    let positions = match migrate::plan(
+                        &self.format.split(&existing),
+                        &self.format.split(&self.header),
+                    ) {
+                        Ok(positions) => positions,
+                        Err(diff) => panic!(
+                            "the header of {} cannot be migrated to the configured sensors; columns can only be \
+                            added, not removed or reordered:\n{}",
+                            path, diff
+                        ),
+                    };
+                    let rows = migrate::rewrite(
+                        &path,
+                        &self.format,
+                        &self.header,
+                        self.units.as_deref(),
+                        &positions,
+                    )
+                    .map_err(|e| format!("could not migrate {}: {}", path, e))?;
+                    eprintln!("Added the new columns to {}; padded {} rows.", path, rows);
+                }
+            },
+            Some(_) => {}
+            // no file or an empty one; (re)create it.
+            None => create(&path, &self.header, self.units.as_deref())?,
+        }
+        let file = fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .map_err(|e| format!("could not open {} for appending data: {}", path, e))?;
+        let inode = file.metadata().map(|m| m.ino()).unwrap_or_default();
+        self.writer = Some((BufWriter::new(file), inode));
+        self.path = Some(path);
+        Ok(())
+    }
+
+    /// Writes out buffered rows and lets go of the file; the buffered rows are kept if that fails.
+    fn close(&mut self) -> Result<(), String> {
+        let (mut writer, _) = match self.writer.take() {
+            Some(writer) => writer,
+            None => return Ok(()),
+        };
+        if let Err(e) = writer.flush() {
+            // the buffer may end in a partial line; it is dropped, not written on drop.
+            let _ = writer.into_parts();
+            let path = self.path.as_deref().unwrap_or(&self.pattern);
+            return Err(format!("could not write to {}: {}", path, e));
+        }
+        self.unflushed.clear();
+        if self.sync != SyncMode::None {
+            if let Err(e) = writer.get_ref().sync_data() {
+                eprintln!("Couldn't sync file: {}", e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes a row to the file it belongs to; (re)opens and rotates files as needed.
+    fn append(&mut self, values: &[f64]) -> Result<(), String> {
+        let now = values.first().copied().unwrap_or(0.0);
+        let path = render(&self.pattern, self.zone, now);
+        if self.path.as_ref() != Some(&path) {
+            self.close()?;
+            if let Some(old) = self.path.take() {
+                eprintln!("Rotating to {}.", path);
+                self.rotated(old);
+            }
+            self.open(path.clone())?;
+        } else if self.writer.is_none() {
+            // writing failed before.
+            self.open(path.clone())?;
+        } else if self.replaced(&path) {
+            eprintln!("{} was deleted or moved; reopening it.", path);
+            self.close()?;
+            self.open(path.clone())?;
+        }
+        if self.retention.as_mut().map(|r| r.due(now)).unwrap_or(false) {
+            // files named by time are deleted as a whole; a single file loses its oldest rows.
+            self.close()?;
+            if let Some(retention) = &self.retention {
+                retention.prune(&self.pattern, &path, now, !self.is_pattern());
+            }
+            self.open(path.clone())?;
+        }
+
+        let line = self.format.row(values);
+        let sync = match self.sync {
+            SyncMode::None => false,
+            SyncMode::Line => true,
+            SyncMode::Interval(interval) => self.last_sync.elapsed() >= interval,
+        };
+        let (writer, _) = match &mut self.writer {
+            Some(writer) => writer,
+            None => return Ok(()),
+        };
+        let mut res = writeln!(writer, "{}", line);
+        // only what was handed to the OS can be synced.
+        let flush = self.unflushed.len() + 1 >= self.flush_every || sync;
+        if res.is_ok() && flush {
+            res = writer.flush();
+        }
+        if let Err(err) = res {
+            // the buffer may end in a partial line; it is dropped so the rows in it can be written again once the
+            // file is back.
+            if let Some((writer, _)) = self.writer.take() {
+                let _ = writer.into_parts();
+            }
+            return Err(format!("could not write to {}: {}", path, err));
+        }
+        if flush {
+            self.unflushed.clear();
+        } else {
+            self.unflushed.push(values.to_vec());
+        }
+        if sync {
+            self.last_sync = time::Instant::now();
+            // the row is with the OS already; writing it again would duplicate it.
+            if let Err(err) = writer.get_ref().sync_data() {
+                eprintln!("Could not sync {}: {}.", path, err);
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether the open file is no longer the one at its path.
+    fn replaced(&self, path: &str) -> bool {
+        match (fs::metadata(path), &self.writer) {
+            (Ok(metadata), Some((_, inode))) => metadata.ino() != *inode,
+            _ => true,
+        }
+    }
+}
+
+/// The name of the file for a row at the given time (epoch secs); filenames w/o placeholders stay as they are.
+pub(crate) fn render(pattern: &str, zone: tz::Zone, epoch: f64) -> String {
+    if !pattern.contains('%') {
+        return pattern.to_string();
+    }
+    match zone.local(epoch) {
+        Some(local) => local.format(pattern).to_string(),
+        None => pattern.to_string(),
+    }
+}
+
+/// The first line of an existing file; None if there is no file or it is empty.
+fn existing_header(path: &str) -> Option<String> {
+    let file = fs::File::open(path).ok()?;
+    BufReader::new(file).lines().next()?.ok()
+}
+
+fn create(path: &str, header: &str, units: Option<&str>) -> Result<(), String> {
+    fs::File::create(path)
+        .and_then(|mut output| match units {
+            Some(units) => writeln!(output, "{}\n{}", header, units),
+            None => writeln!(output, "{}", header),
+        })
+        .map_err(|e| format!("could not create {}: {}", path, e))
+}
+
+impl Output for CsvOutput {
+    fn write_units(&mut self, units: &[String]) {
+        self.units = Some(self.format.units(units));
+    }
+
+    fn write_header(&mut self, names: &[String]) {
+        self.header = self.format.header(names);
+        // files named by time are opened with their first row.
+        if !self.is_pattern() {
+            if let Err(err) = self.open(self.pattern.clone()) {
+                panic!("{}.", err);
+            }
+        }
+    }
+
+    fn write(&mut self, values: &[f64]) -> Result<(), String> {
+        self.pending.push_back(values.to_vec());
+        // oldest first, so the file stays in order.
+        while let Some(row) = self.pending.pop_front() {
+            if let Err(err) = self.append(&row) {
+                self.pending.push_front(row);
+                // rows lost with the buffer go first again.
+                for row in self.unflushed.drain(..).rev() {
+                    self.pending.push_front(row);
+                }
+                while self.pending.len() > self.max_pending {
+                    self.pending.pop_front();
+                    self.dropped += 1;
+                    DROPPED_ROWS.fetch_add(1, Ordering::Relaxed);
+                    eprintln!(
+                        "Too many rows could not be written; dropped the oldest ({} so far).",
+                        self.dropped
+                    );
+                }
+                return Err(format!(
+                    "{}; keeping {} rows to retry",
+                    err,
+                    self.pending.len()
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) {
+        if let Err(err) = self.close() {
+            eprintln!("{}; lost {} rows.", err, self.unflushed.len());
+        }
+        // compressions are safe to interrupt, but let them finish when there is the time.
+        for compression in self.compressions.drain(..) {
+            let _ = compression.join();
+        }
+    }
+}
+
+/// Appends one JSON object per row to a file; keys are the column names.
+pub(crate) struct JsonlOutput {
+    path: String,
+    names: Vec<String>,
+    writer: Option<BufWriter<fs::File>>,
+}
+
+impl JsonlOutput {
+    pub(crate) fn new(path: String) -> JsonlOutput {
+        JsonlOutput {
+            path,
+            names: Vec::new(),
+            writer: None,
+        }
+    }
+}
+
+/// A row as a JSON object; failure values like NaN become null, so every line has the same keys.
+pub(crate) fn to_json(names: &[String], values: &[f64]) -> String {
+    let fields: Vec<String> = names
+        .iter()
+        .zip(values)
+        .map(|(name, value)| {
+            format!(
+                "{}:{}",
+                serde_json::Value::from(name.as_str()),
+                serde_json::Value::from(*value)
+            )
+        })
+        .collect();
+    format!("{{{}}}", fields.join(","))
+}
+
+impl Output for JsonlOutput {
+    fn write_header(&mut self, names: &[String]) {
+        // every line names its keys; there is no header.
+        self.names = names.to_vec();
+    }
+
+    fn write(&mut self, values: &[f64]) -> Result<(), String> {
+        if self.writer.is_none() || fs::metadata(&self.path).is_err() {
+            let file = fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.path)
+                .map_err(|e| format!("could not open {} for appending data: {}", self.path, e))?;
+            self.writer = Some(BufWriter::new(file));
+        }
+        if let Some(writer) = &mut self.writer {
+            let line = to_json(&self.names, values);
+            writeln!(writer, "{}", line)
+                .and_then(|_| writer.flush())
+                .map_err(|e| format!("could not write to {}: {}", self.path, e))?;
+        }
+        Ok(())
+    }
+}
+
+/// Prints each row to stdout, as CSV or as a JSON object (format None); flushed after every line for piping.
+pub(crate) struct StdoutOutput {
+    format: Option<csv_out::Format>,
+    names: Vec<String>,
+}
+
+impl StdoutOutput {
+    pub(crate) fn new(format: Option<csv_out::Format>) -> StdoutOutput {
+        StdoutOutput {
+            format,
+            names: Vec::new(),
+        }
+    }
+
+    fn print(&self, line: &str) -> Result<(), String> {
+        let mut stdout = io::stdout().lock();
+        writeln!(stdout, "{}", line)
+            .and_then(|_| stdout.flush())
+            .map_err(|e| format!("could not write to stdout: {}", e))
+    }
+}
+
+impl Output for StdoutOutput {
+    fn write_header(&mut self, names: &[String]) {
+        self.names = names.to_vec();
+        if let Some(format) = &self.format {
+            if let Err(err) = self.print(&format.header(names)) {
+                panic!("{}.", err);
+            }
+        }
+    }
+
+    fn write(&mut self, values: &[f64]) -> Result<(), String> {
+        match &self.format {
+            Some(format) => self.print(&format.row(values)),
+            None => self.print(&to_json(&self.names, values)),
+        }
+    }
+}
+
+/// Splits a column into the sensor it belongs to and its metric.
+pub(crate) fn split(sensors: &[String], column: &str) -> (String, String) {
+    let sensor = sensors
+        .iter()
+        .filter(|s| column.starts_with(&format!("{}_", s)))
+        .max_by_key(|s| s.len());
+    match sensor {
+        Some(sensor) => (sensor.clone(), column[sensor.len() + 1..].to_string()),
+        // e.g. the columns of components.
+        None => match column.split_once('_') {
+            Some((sensor, metric)) => (sensor.to_string(), metric.to_string()),
+            None => (column.to_string(), "value".to_string()),
+        },
+    }
+}
+
+/// The columns an output with the given visibility receives; private flags each column.
+pub(crate) fn visible(private: &[bool], visibility: privacy::Visibility) -> Vec<usize> {
+    (0..private.len())
+        .filter(|i| visibility == privacy::Visibility::All || !private[*i])
+        .collect()
+}
+
+/// Hands the rows to all outputs; the only place where rows leave the loop.
+///
+/// Outputs with public visibility never see private columns, whatever the output does.
+pub(crate) struct Dispatcher {
+    routes: Vec<(Box<dyn Output>, Vec<usize>)>,
+}
+
+impl Dispatcher {
+    /// Sets up the outputs for the given columns, and their units if those are to be written; private flags each column.
+    pub(crate) fn new(
+        names: &[String],
+        units: Option<&[String]>,
+        private: &[bool],
+        outputs: Vec<(Box<dyn Output>, privacy::Visibility)>,
+    ) -> Dispatcher {
+        let mut routes = Vec::new();
+        for (mut output, visibility) in outputs {
+            let indices = visible(private, visibility);
+            let columns: Vec<String> = indices.iter().map(|i| names[*i].clone()).collect();
+            if let Some(units) = units {
+                let units: Vec<String> = indices.iter().map(|i| units[*i].clone()).collect();
+                output.write_units(&units);
+            }
+            output.write_header(&columns);
+            routes.push((output, indices));
+        }
+        Dispatcher { routes }
+    }
+
+    /// Hands a row to each output, all its values measured at the time of the row; e.g. an aggregated window.
+    pub(crate) fn dispatch(&mut self, values: &[f64]) {
+        let times = vec![values.first().copied().unwrap_or_default(); values.len()];
+        self.dispatch_at(values, &times);
+    }
+
+    /// Hands a row and the time each value was measured to each output; one that fails is skipped for this row, the
+    /// others still get it.
+    pub(crate) fn dispatch_at(&mut self, values: &[f64], times: &[f64]) {
+        for (output, indices) in &mut self.routes {
+            let row: Vec<f64> = indices.iter().map(|i| values[*i]).collect();
+            let row_times: Vec<f64> = indices.iter().map(|i| times[*i]).collect();
+            if let Err(err) = output.write_at(&row, &row_times) {
+                eprintln!(
+                    "Could not write a row; skipping it for this output: {}.",
+                    err
+                );
+            }
+        }
+    }
+
+    /// Flushes all outputs; called on shutdown.
+    pub(crate) fn flush(&mut self) {
+        for (output, _) in &mut self.routes {
+            output.flush();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+
+    /// The header and rows received, as text.
+    type Seen = Arc<Mutex<Vec<Vec<String>>>>;
+
+    /// Records what it receives, like a (public) MQTT publisher would send it.
+    struct RecordingOutput {
+        seen: Seen,
+        header: Vec<String>,
+    }
+
+    impl Output for RecordingOutput {
+        fn write_header(&mut self, names: &[String]) {
+            self.header = names.to_vec();
+        }
+
+        fn write(&mut self, values: &[f64]) -> Result<(), String> {
+            let row = self
+                .header
+                .iter()
+                .zip(values)
+                .map(|(n, v)| format!("{}={}", n, v))
+                .collect();
+            self.seen.lock().unwrap().push(row);
+            Ok(())
+        }
+    }
+
+    /// Fails on every row, like a remote endpoint that is down.
+    struct FailingOutput;
+
+    impl Output for FailingOutput {
+        fn write_header(&mut self, _: &[String]) {}
+
+        fn write(&mut self, _: &[f64]) -> Result<(), String> {
+            Err("endpoint is down".to_string())
+        }
+    }
+
+    fn recorder() -> (Box<dyn Output>, Seen) {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let output = RecordingOutput {
+            seen: seen.clone(),
+            header: Vec::new(),
+        };
+        (Box::new(output), seen)
+    }
+
+    fn names() -> Vec<String> {
+        vec![
+            "timestamp".to_string(),
+            "pv_power".to_string(),
+            "fritz_power".to_string(),
+        ]
+    }
+
+    // Tests for success.
+
+    #[test]
+    fn test_csv_for_success() {
+        let mut dispatcher = Dispatcher::new(
+            &names(),
+            None,
+            &[false, false, true],
+            vec![(
+                Box::new(CsvOutput::new(
+                    "output_test0.csv".to_string(),
+                    OnMismatch::Fail,
+                    tz::Zone::Utc,
+                    false,
+                    csv_out::Format::default(),
+                    1,
+                    SyncMode::None,
+                    None,
+                    1000,
+                )),
+                privacy::Visibility::All,
+            )],
+        );
+        dispatcher.dispatch(&[1.0, 100.0, 50.5]);
+        assert_eq!(
+            fs::read_to_string("output_test0.csv").unwrap(),
+            "timestamp,pv_power,fritz_power\n1,100,50.5\n"
+        );
+        fs::remove_file("output_test0.csv").unwrap();
+    }
+
+    #[test]
+    fn test_csv_units_for_success() {
+        let units = vec!["s".to_string(), "W".to_string(), "mW".to_string()];
+        let mut dispatcher = Dispatcher::new(
+            &names(),
+            Some(&units),
+            &[false, false, true],
+            vec![(
+                Box::new(CsvOutput::new(
+                    "output_test12.csv".to_string(),
+                    OnMismatch::Fail,
+                    tz::Zone::Utc,
+                    false,
+                    csv_out::Format::default(),
+                    1,
+                    SyncMode::None,
+                    None,
+                    1000,
+                )),
+                privacy::Visibility::Public,
+            )],
+        );
+        dispatcher.dispatch(&[1.0, 100.0, 50.5]);
+        // private columns lose their unit along with their values.
+        assert_eq!(
+            fs::read_to_string("output_test12.csv").unwrap(),
+            "timestamp,pv_power\ns,W\n1,100\n"
+        );
+        fs::remove_file("output_test12.csv").unwrap();
+    }
+
+    // Tests for failure.
+
+    #[test]
+    #[should_panic(expected = "does not match")]
+    fn test_header_mismatch_for_failure() {
+        fs::write("output_test1.csv", "timestamp,pv_power\n1,100\n").unwrap();
+        let mut output = CsvOutput::new(
+            "output_test1.csv".to_string(),
+            OnMismatch::Fail,
+            tz::Zone::Utc,
+            false,
+            csv_out::Format::default(),
+            1,
+            SyncMode::None,
+            None,
+            1000,
+        );
+        let res = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            output.write_header(&names());
+        }));
+        // the existing data is left alone.
+        assert_eq!(
+            fs::read_to_string("output_test1.csv").unwrap(),
+            "timestamp,pv_power\n1,100\n"
+        );
+        fs::remove_file("output_test1.csv").unwrap();
+        std::panic::resume_unwind(res.unwrap_err());
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_on_mismatch_for_failure() {
+        OnMismatch::parse("append");
+    }
+
+    #[test]
+    #[should_panic(expected = "sync must be one of")]
+    fn test_sync_mode_for_failure() {
+        SyncMode::parse("interval:soon");
+    }
+
+    #[test]
+    #[should_panic(expected = "invalid placeholder")]
+    fn test_pattern_for_failure() {
+        CsvOutput::new(
+            "output_test4-%J.csv".to_string(),
+            OnMismatch::Fail,
+            tz::Zone::Utc,
+            false,
+            csv_out::Format::default(),
+            1,
+            SyncMode::None,
+            None,
+            1000,
+        );
+    }
+
+    #[test]
+    fn test_dispatch_for_failure() {
+        // no outputs, nothing to do.
+        let mut dispatcher = Dispatcher::new(&names(), None, &[false, false, true], Vec::new());
+        dispatcher.dispatch(&[1.0, 100.0, 50.0]);
+
+        // a failing output does not keep the row from the others.
+        let (all, all_seen) = recorder();
+        let mut dispatcher = Dispatcher::new(
+            &names(),
+            None,
+            &[false, false, true],
+            vec![
+                (Box::new(FailingOutput), privacy::Visibility::All),
+                (all, privacy::Visibility::All),
+            ],
+        );
+        dispatcher.dispatch(&[1.0, 100.0, 50.0]);
+        dispatcher.dispatch(&[2.0, 110.0, 55.0]);
+        assert_eq!(all_seen.lock().unwrap().len(), 2);
+    }
+
+    // Tests for sanity.
+
+    #[test]
+    fn test_header_rotate_for_sanity() {
+        fs::write("output_test2.csv", "timestamp,pv_power\n1,100\n").unwrap();
+        let mut output = CsvOutput::new(
+            "output_test2.csv".to_string(),
+            OnMismatch::Rotate,
+            tz::Zone::Utc,
+            false,
+            csv_out::Format::default(),
+            1,
+            SyncMode::None,
+            None,
+            1000,
+        );
+        output.write_header(&names());
+        output.write(&[2.0, 110.0, 55.0]).unwrap();
+        assert_eq!(
+            fs::read_to_string("output_test2.csv").unwrap(),
+            "timestamp,pv_power,fritz_power\n2,110,55\n"
+        );
+
+        // the old file is kept next to the new one.
+        let aside: Vec<_> = fs::read_dir(".")
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .filter(|n| n.starts_with("output_test2.csv."))
+            .collect();
+        assert_eq!(aside.len(), 1);
+        assert_eq!(
+            fs::read_to_string(&aside[0]).unwrap(),
+            "timestamp,pv_power\n1,100\n"
+        );
+
+        // a matching header just appends.
+        let mut output = CsvOutput::new(
+            "output_test2.csv".to_string(),
+            OnMismatch::Fail,
+            tz::Zone::Utc,
+            false,
+            csv_out::Format::default(),
+            1,
+            SyncMode::None,
+            None,
+            1000,
+        );
+        output.write_header(&names());
+        output.write(&[3.0, 120.0, 60.0]).unwrap();
+        assert_eq!(
+            fs::read_to_string("output_test2.csv")
+                .unwrap()
+                .lines()
+                .count(),
+            3
+        );
+        fs::remove_file("output_test2.csv").unwrap();
+        fs::remove_file(&aside[0]).unwrap();
+    }
+
+    #[test]
+    fn test_header_migrate_for_sanity() {
+        fs::write("output_test10.csv", "timestamp,fritz_power\n1,50\n").unwrap();
+        let mut output = CsvOutput::new(
+            "output_test10.csv".to_string(),
+            OnMismatch::Migrate,
+            tz::Zone::Utc,
+            false,
+            csv_out::Format::default(),
+            1,
+            SyncMode::None,
+            None,
+            1000,
+        );
+        output.write_header(&names());
+        output.write(&[2.0, 110.0, 55.0]).unwrap();
+        assert_eq!(
+            fs::read_to_string("output_test10.csv").unwrap(),
+            "timestamp,pv_power,fritz_power\n1,,50\n2,110,55\n"
+        );
+
+        // a removed column is refused; the file is left alone.
+        let mut output = CsvOutput::new(
+            "output_test10.csv".to_string(),
+            OnMismatch::Migrate,
+            tz::Zone::Utc,
+            false,
+            csv_out::Format::default(),
+            1,
+            SyncMode::None,
+            None,
+            1000,
+        );
+        let res = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            output.write_header(&["timestamp".to_string(), "fritz_power".to_string()]);
+        }));
+        assert!(res.is_err());
+        assert_eq!(
+            fs::read_to_string("output_test10.csv")
+                .unwrap()
+                .lines()
+                .count(),
+            3
+        );
+        fs::remove_file("output_test10.csv").unwrap();
+    }
+
+    #[test]
+    fn test_retry_for_sanity() {
+        fs::create_dir("output_test11").unwrap();
+        let mut output = CsvOutput::new(
+            "output_test11/data.csv".to_string(),
+            OnMismatch::Fail,
+            tz::Zone::Utc,
+            false,
+            csv_out::Format::default(),
+            1,
+            SyncMode::None,
+            None,
+            2,
+        );
+        output.write_header(&names());
+        output.write(&[1.0, 100.0, 50.0]).unwrap();
+
+        // the stick is gone; rows are kept, but only so many.
+        fs::rename("output_test11", "output_test11.gone").unwrap();
+        for t in [2.0, 3.0, 4.0] {
+            assert!(output.write(&[t, 100.0, 50.0]).is_err());
+        }
+        assert_eq!(output.pending.len(), 2);
+        assert_eq!(output.dropped, 1);
+        assert!(dropped_rows() >= 1);
+
+        // and back; the kept rows go first.
+        fs::rename("output_test11.gone", "output_test11").unwrap();
+        output.write(&[5.0, 100.0, 50.0]).unwrap();
+        assert!(output.pending.is_empty());
+        assert_eq!(
+            fs::read_to_string("output_test11/data.csv").unwrap(),
+            "timestamp,pv_power,fritz_power\n1,100,50\n3,100,50\n4,100,50\n5,100,50\n"
+        );
+        fs::remove_dir_all("output_test11").unwrap();
+    }
+
+    #[test]
+    fn test_retry_buffered_for_sanity() {
+        let mut output = CsvOutput::new(
+            "output_test13.csv".to_string(),
+            OnMismatch::Fail,
+            tz::Zone::Utc,
+            false,
+            csv_out::Format::default(),
+            3,
+            SyncMode::None,
+            None,
+            10,
+        );
+        output.write_header(&names());
+
+        // the same file, but it cannot be written; the rows sit in the buffer until it is flushed.
+        let file = fs::File::open("output_test13.csv").unwrap();
+        let inode = file.metadata().unwrap().ino();
+        output.writer = Some((BufWriter::new(file), inode));
+        output.write(&[1.0, 100.0, 50.0]).unwrap();
+        output.write(&[2.0, 100.0, 50.0]).unwrap();
+        assert!(output.write(&[3.0, 100.0, 50.0]).is_err());
+        assert_eq!(output.pending.len(), 3);
+
+        // reopened; none of the buffered rows went missing.
+        output.write(&[4.0, 100.0, 50.0]).unwrap();
+        output.flush();
+        assert!(output.pending.is_empty());
+        assert_eq!(
+            fs::read_to_string("output_test13.csv").unwrap(),
+            "timestamp,pv_power,fritz_power\n1,100,50\n2,100,50\n3,100,50\n4,100,50\n"
+        );
+        fs::remove_file("output_test13.csv").unwrap();
+    }
+
+    #[test]
+    fn test_daily_rotation_for_sanity() {
+        let mut output = CsvOutput::new(
+            "output_test3-%Y-%m-%d.csv".to_string(),
+            OnMismatch::Fail,
+            tz::Zone::Utc,
+            false,
+            csv_out::Format::default(),
+            1,
+            SyncMode::None,
+            None,
+            1000,
+        );
+        output.write_header(&names());
+        // the file of a day is created with its first row.
+        assert!(fs::metadata("output_test3-2023-11-13.csv").is_err());
+
+        // around midnight of 2023-11-14 UTC.
+        for t in [1699919940.0, 1699919970.0, 1699920000.0, 1699920030.0] {
+            output.write(&[t, 100.0, 50.0]).unwrap();
+        }
+        assert_eq!(
+            fs::read_to_string("output_test3-2023-11-13.csv").unwrap(),
+            "timestamp,pv_power,fritz_power\n1699919940,100,50\n1699919970,100,50\n"
+        );
+        assert_eq!(
+            fs::read_to_string("output_test3-2023-11-14.csv").unwrap(),
+            "timestamp,pv_power,fritz_power\n1699920000,100,50\n1699920030,100,50\n"
+        );
+
+        // a restart during the day appends to the file of the day.
+        let mut output = CsvOutput::new(
+            "output_test3-%Y-%m-%d.csv".to_string(),
+            OnMismatch::Fail,
+            tz::Zone::Utc,
+            false,
+            csv_out::Format::default(),
+            1,
+            SyncMode::None,
+            None,
+            1000,
+        );
+        output.write_header(&names());
+        output.write(&[1699920060.0, 100.0, 50.0]).unwrap();
+        assert_eq!(
+            fs::read_to_string("output_test3-2023-11-14.csv")
+                .unwrap()
+                .lines()
+                .count(),
+            4
+        );
+        fs::remove_file("output_test3-2023-11-13.csv").unwrap();
+        fs::remove_file("output_test3-2023-11-14.csv").unwrap();
+    }
+
+    #[test]
+    fn test_compress_rotated_for_sanity() {
+        let mut output = CsvOutput::new(
+            "output_test5-%Y-%m-%d.csv".to_string(),
+            OnMismatch::Fail,
+            tz::Zone::Utc,
+            true,
+            csv_out::Format::default(),
+            1,
+            SyncMode::None,
+            None,
+            1000,
+        );
+        output.write_header(&names());
+        for t in [1699919970.0, 1699920000.0] {
+            output.write(&[t, 100.0, 50.0]).unwrap();
+        }
+        output.flush();
+        // only the completed file of the day before is compressed.
+        assert!(fs::metadata("output_test5-2023-11-13.csv").is_err());
+        assert!(fs::metadata("output_test5-2023-11-13.csv.gz").is_ok());
+        assert!(fs::metadata("output_test5-2023-11-14.csv").is_ok());
+        fs::remove_file("output_test5-2023-11-13.csv.gz").unwrap();
+        fs::remove_file("output_test5-2023-11-14.csv").unwrap();
+    }
+
+    #[test]
+    fn test_reopen_for_sanity() {
+        let mut output = CsvOutput::new(
+            "output_test6.csv".to_string(),
+            OnMismatch::Fail,
+            tz::Zone::Utc,
+            false,
+            csv_out::Format::default(),
+            2,
+            SyncMode::None,
+            None,
+            1000,
+        );
+        output.write_header(&names());
+        output.write(&[1.0, 100.0, 50.0]).unwrap();
+        // buffered until every second row.
+        assert_eq!(
+            fs::read_to_string("output_test6.csv").unwrap(),
+            "timestamp,pv_power,fritz_power\n"
+        );
+        output.write(&[2.0, 100.0, 50.0]).unwrap();
+        assert_eq!(
+            fs::read_to_string("output_test6.csv")
+                .unwrap()
+                .lines()
+                .count(),
+            3
+        );
+
+        // deleted underneath; logging resumes in a fresh file.
+        fs::remove_file("output_test6.csv").unwrap();
+        output.write(&[3.0, 110.0, 55.0]).unwrap();
+        output.write(&[4.0, 120.0, 60.0]).unwrap();
+        assert_eq!(
+            fs::read_to_string("output_test6.csv").unwrap(),
+            "timestamp,pv_power,fritz_power\n3,110,55\n4,120,60\n"
+        );
+
+        // moved away; the moved file is left alone.
+        fs::rename("output_test6.csv", "output_test6.csv.old").unwrap();
+        output.write(&[5.0, 130.0, 65.0]).unwrap();
+        output.flush();
+        assert_eq!(
+            fs::read_to_string("output_test6.csv").unwrap(),
+            "timestamp,pv_power,fritz_power\n5,130,65\n"
+        );
+        assert_eq!(
+            fs::read_to_string("output_test6.csv.old")
+                .unwrap()
+                .lines()
+                .count(),
+            3
+        );
+        fs::remove_file("output_test6.csv").unwrap();
+        fs::remove_file("output_test6.csv.old").unwrap();
+    }
+
+    #[test]
+    fn test_sync_for_sanity() {
+        assert_eq!(SyncMode::parse("none"), SyncMode::None);
+        assert_eq!(SyncMode::parse("line"), SyncMode::Line);
+        let mut output = CsvOutput::new(
+            "output_test8.csv".to_string(),
+            OnMismatch::Fail,
+            tz::Zone::Utc,
+            false,
+            csv_out::Format::default(),
+            100,
+            SyncMode::parse("interval:0.05"),
+            None,
+            1000,
+        );
+        output.write_header(&names());
+        output.write(&[1.0, 100.0, 50.0]).unwrap();
+        // too early to sync; still buffered.
+        assert_eq!(
+            fs::read_to_string("output_test8.csv").unwrap(),
+            "timestamp,pv_power,fritz_power\n"
+        );
+        let synced = output.last_sync;
+        thread::sleep(time::Duration::from_millis(60));
+        // synced with the first row after the interval, with everything written so far.
+        output.write(&[2.0, 110.0, 55.0]).unwrap();
+        assert!(output.last_sync > synced);
+        assert_eq!(
+            fs::read_to_string("output_test8.csv").unwrap(),
+            "timestamp,pv_power,fritz_power\n1,100,50\n2,110,55\n"
+        );
+        output.write(&[3.0, 120.0, 60.0]).unwrap();
+        assert_eq!(
+            fs::read_to_string("output_test8.csv")
+                .unwrap()
+                .lines()
+                .count(),
+            3
+        );
+        fs::remove_file("output_test8.csv").unwrap();
+    }
+
+    #[test]
+    fn test_retention_for_sanity() {
+        let mut output = CsvOutput::new(
+            "output_test9.csv".to_string(),
+            OnMismatch::Fail,
+            tz::Zone::Utc,
+            false,
+            csv_out::Format::default(),
+            1,
+            SyncMode::None,
+            Some(retention::Retention::new(Some(200), None)),
+            1000,
+        );
+        output.write_header(&names());
+        for i in 0..20 {
+            output
+                .write(&[1699920000.0 + i as f64 * 30.0, 100.0, 50.0])
+                .unwrap();
+        }
+        // not checked again within the hour.
+        assert!(fs::metadata("output_test9.csv").unwrap().len() > 200);
+
+        // the oldest rows are dropped to fit 200 bytes (a header and 9 rows), then the row is written.
+        output.write(&[1699923600.0, 100.0, 50.0]).unwrap();
+        let content = fs::read_to_string("output_test9.csv").unwrap();
+        assert_eq!(content.lines().count(), 11);
+        assert!(content.contains("\n1699920330,100,50\n"));
+        assert!(content.starts_with("timestamp,pv_power,fritz_power\n"));
+        assert!(content.ends_with("1699923600,100,50\n"));
+
+        // and rows keep going to the rewritten file.
+        output.write(&[1699923630.0, 100.0, 50.0]).unwrap();
+        let content = fs::read_to_string("output_test9.csv").unwrap();
+        assert!(content.ends_with("1699923600,100,50\n1699923630,100,50\n"));
+        fs::remove_file("output_test9.csv").unwrap();
+    }
+
+    #[test]
+    fn test_jsonl_for_sanity() {
+        let mut dispatcher = Dispatcher::new(
+            &names(),
+            None,
+            &[false, false, false],
+            vec![(
+                Box::new(JsonlOutput::new("output_test7.jsonl".to_string())),
+                privacy::Visibility::All,
+            )],
+        );
+        dispatcher.dispatch(&[1.0, 100.5, 50.0]);
+        dispatcher.dispatch(&[2.0, 110.0, f64::NAN]);
+
+        let content = fs::read_to_string("output_test7.jsonl").unwrap();
+        let lines: Vec<serde_json::Value> = content
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        assert_eq!(
+            lines[0],
+            serde_json::json!({"timestamp": 1.0, "pv_power": 100.5, "fritz_power": 50.0})
+        );
+        // failed values keep their key.
+        assert_eq!(lines[1]["fritz_power"], serde_json::Value::Null);
+        assert_eq!(lines[1].as_object().unwrap().len(), 3);
+        fs::remove_file("output_test7.jsonl").unwrap();
+    }
+
+    #[test]
+    fn test_split_for_sanity() {
+        let sensors = vec!["bat".to_string(), "bat_2".to_string()];
+        let check = |column, sensor: &str, metric: &str| {
+            assert_eq!(
+                split(&sensors, column),
+                (sensor.to_string(), metric.to_string())
+            );
+        };
+        check("bat_soc", "bat", "soc");
+        check("bat_2_soc", "bat_2", "soc");
+        // columns of no sensor.
+        check("battery_charge", "battery", "charge");
+        check("timestamp", "timestamp", "value");
+    }
+
+    #[test]
+    fn test_dispatch_for_sanity() {
+        let (public, public_seen) = recorder();
+        let (all, all_seen) = recorder();
+        let mut dispatcher = Dispatcher::new(
+            &names(),
+            None,
+            &[false, false, true],
+            vec![
+                (public, privacy::Visibility::Public),
+                (all, privacy::Visibility::All),
+            ],
+        );
+        dispatcher.dispatch(&[1.0, 100.0, 50.0]);
+        dispatcher.dispatch(&[2.0, 110.0, 55.0]);
+
+        // a public output never sees a private column...
+        let seen = public_seen.lock().unwrap();
+        assert_eq!(seen.len(), 2);
+        assert!(seen.iter().flatten().all(|c| !c.starts_with("fritz_")));
+        assert_eq!(seen[1], vec!["timestamp=2", "pv_power=110"]);
+        // ... while the others see everything.
+        assert_eq!(
+            all_seen.lock().unwrap()[1],
+            vec!["timestamp=2", "pv_power=110", "fritz_power=55"]
+        );
+    }
+}
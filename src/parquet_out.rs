@@ -0,0 +1,284 @@
+use std::fs;
+use std::sync::Arc;
+
+use arrow::array::{ArrayRef, Float64Array, TimestampMillisecondArray};
+use arrow::datatypes::{DataType, Field, Schema, SchemaRef, TimeUnit};
+use arrow::record_batch::RecordBatch;
+use parquet::arrow::ArrowWriter;
+use parquet::basic::Compression;
+use parquet::file::properties::WriterProperties;
+
+use crate::output;
+use crate::tz;
+
+/// Writes rows to Parquet files; a timestamp column (UTC, millis) and a nullable Float64 column per value.
+///
+/// Rows are buffered and written as one row group once there are rows_per_group of them. The filename can contain
+/// strftime placeholders to start a new file every day; as Parquet files cannot be appended to, an existing file is
+/// never touched - a numbered one is started next to it instead.
+pub(crate) struct ParquetOutput {
+    pattern: String,
+    zone: tz::Zone,
+    rows_per_group: usize,
+    schema: SchemaRef,
+    rows: Vec<Vec<f64>>,
+    // the file the buffered rows belong to.
+    path: Option<String>,
+    writer: Option<ArrowWriter<fs::File>>,
+}
+
+impl ParquetOutput {
+    pub(crate) fn new(pattern: String, zone: tz::Zone, rows_per_group: usize) -> ParquetOutput {
+        ParquetOutput {
+            pattern,
+            zone,
+            rows_per_group: rows_per_group.max(1),
+            schema: Arc::new(Schema::empty()),
+            rows: Vec::new(),
+            path: None,
+            writer: None,
+        }
+    }
+
+    fn batch(&self) -> Result<RecordBatch, String> {
+        let mut columns: Vec<ArrayRef> = Vec::new();
+        let timestamps: Vec<i64> = self
+            .rows
+            .iter()
+            .map(|row| (row[0] * 1000.0).round() as i64)
+            .collect();
+        columns.push(Arc::new(
+            TimestampMillisecondArray::from(timestamps).with_timezone("UTC"),
+        ));
+        for i in 1..self.schema.fields().len() {
+            // failure values become nulls.
+            let values: Vec<Option<f64>> = self
+                .rows
+                .iter()
+                .map(|row| Some(row[i]).filter(|v| !v.is_nan()))
+                .collect();
+            columns.push(Arc::new(Float64Array::from(values)));
+        }
+        RecordBatch::try_new(self.schema.clone(), columns).map_err(|e| e.to_string())
+    }
+
+    /// Writes the buffered rows as a row group.
+    fn write_group(&mut self) -> Result<(), String> {
+        if self.rows.is_empty() {
+            return Ok(());
+        }
+        let path = self.path.clone().unwrap_or_default();
+        // written or not, the rows are done with.
+        let batch = self.batch();
+        self.rows.clear();
+        let batch = batch?;
+        if self.writer.is_none() {
+            let target = unused(&path);
+            let file = fs::File::create(&target)
+                .map_err(|e| format!("could not create {}: {}", target, e))?;
+            let props = WriterProperties::builder()
+                .set_compression(Compression::SNAPPY)
+                .build();
+            let writer = ArrowWriter::try_new(file, self.schema.clone(), Some(props))
+                .map_err(|e| format!("could not write to {}: {}", target, e))?;
+            self.writer = Some(writer);
+        }
+        if let Some(writer) = &mut self.writer {
+            writer
+                .write(&batch)
+                .and_then(|_| writer.flush())
+                .map_err(|e| format!("could not write to {}: {}", path, e))?;
+        }
+        Ok(())
+    }
+
+    /// Writes the rest and the footer; only then the file is readable.
+    fn close(&mut self) -> Result<(), String> {
+        let res = self.write_group();
+        if let Some(writer) = self.writer.take() {
+            writer.close().map_err(|e| {
+                format!(
+                    "could not finish {}: {}",
+                    self.path.clone().unwrap_or_default(),
+                    e
+                )
+            })?;
+        }
+        res
+    }
+}
+
+/// The path itself if there is no such file yet; otherwise the first free <stem>.<n>.<extension>.
+fn unused(path: &str) -> String {
+    if fs::metadata(path).is_err() {
+        return path.to_string();
+    }
+    let (stem, extension) = match path.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() => (stem, format!(".{}", extension)),
+        _ => (path, String::new()),
+    };
+    (1..)
+        .map(|n| format!("{}.{}{}", stem, n, extension))
+        .find(|candidate| fs::metadata(candidate).is_err())
+        .unwrap()
+}
+
+impl output::Output for ParquetOutput {
+    fn write_header(&mut self, names: &[String]) {
+        let mut fields = vec![Field::new(
+            "timestamp",
+            DataType::Timestamp(TimeUnit::Millisecond, Some("UTC".into())),
+            false,
+        )];
+        for name in names.iter().skip(1) {
+            fields.push(Field::new(name, DataType::Float64, true));
+        }
+        self.schema = Arc::new(Schema::new(fields));
+    }
+
+    fn write(&mut self, values: &[f64]) -> Result<(), String> {
+        let path = output::render(
+            &self.pattern,
+            self.zone,
+            values.first().copied().unwrap_or(0.0),
+        );
+        if self.path.as_ref() != Some(&path) {
+            // the rows so far belong to the previous file.
+            let res = self.close();
+            self.path = Some(path);
+            res?;
+        }
+        self.rows.push(values.to_vec());
+        if self.rows.len() >= self.rows_per_group {
+            return self.write_group();
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) {
+        if let Err(err) = self.close() {
+            eprintln!("Could not write the last rows: {}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use arrow::array::Array;
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+
+    use super::*;
+    use crate::output::Output;
+
+    fn names() -> Vec<String> {
+        vec!["timestamp".to_string(), "pv_power".to_string()]
+    }
+
+    /// All batches of a file, together with the number of row groups.
+    fn read(path: &str) -> (Vec<RecordBatch>, usize) {
+        let builder =
+            ParquetRecordBatchReaderBuilder::try_new(fs::File::open(path).unwrap()).unwrap();
+        let groups = builder.metadata().num_row_groups();
+        let batches = builder.build().unwrap().map(|b| b.unwrap()).collect();
+        (batches, groups)
+    }
+
+    // Tests for success.
+
+    #[test]
+    fn test_write_for_success() {
+        let mut output = ParquetOutput::new("parquet_test0.parquet".to_string(), tz::Zone::Utc, 2);
+        output.write_header(&names());
+        for (t, v) in [
+            (1699920000.0, 1.5),
+            (1699920030.0, f64::NAN),
+            (1699920060.5, 2.5),
+        ] {
+            output.write(&[t, v]).unwrap();
+        }
+        // the last row is only in memory until shutdown.
+        output.flush();
+
+        let (batches, groups) = read("parquet_test0.parquet");
+        assert_eq!(groups, 2);
+        let rows: usize = batches.iter().map(|b| b.num_rows()).sum();
+        assert_eq!(rows, 3);
+        let batch = arrow::compute::concat_batches(&batches[0].schema(), &batches).unwrap();
+        let timestamps = batch
+            .column(0)
+            .as_any()
+            .downcast_ref::<TimestampMillisecondArray>()
+            .unwrap();
+        assert_eq!(timestamps.value(2), 1699920060500);
+        let values = batch
+            .column(1)
+            .as_any()
+            .downcast_ref::<Float64Array>()
+            .unwrap();
+        assert_eq!(values.value(0), 1.5);
+        assert!(values.is_null(1));
+        assert_eq!(batch.schema().field(1).name(), "pv_power");
+        fs::remove_file("parquet_test0.parquet").unwrap();
+    }
+
+    // Tests for failure.
+
+    #[test]
+    fn test_write_for_failure() {
+        // no such directory; the row is reported lost.
+        let mut output = ParquetOutput::new(
+            "parquet_test_nowhere/data.parquet".to_string(),
+            tz::Zone::Utc,
+            1,
+        );
+        output.write_header(&names());
+        assert!(output.write(&[1699920000.0, 1.5]).is_err());
+    }
+
+    // Tests for sanity.
+
+    #[test]
+    fn test_daily_rotation_for_sanity() {
+        let mut output = ParquetOutput::new(
+            "parquet_test1-%Y-%m-%d.parquet".to_string(),
+            tz::Zone::Utc,
+            100,
+        );
+        output.write_header(&names());
+        // 2023-11-14T23:59:30Z and a minute later.
+        output.write(&[1700006370.0, 1.0]).unwrap();
+        output.write(&[1700006430.0, 2.0]).unwrap();
+        output.flush();
+
+        for (path, value) in [
+            ("parquet_test1-2023-11-14.parquet", 1.0),
+            ("parquet_test1-2023-11-15.parquet", 2.0),
+        ] {
+            let (batches, _) = read(path);
+            assert_eq!(batches.len(), 1);
+            let values = batches[0]
+                .column(1)
+                .as_any()
+                .downcast_ref::<Float64Array>()
+                .unwrap();
+            assert_eq!(values.values().to_vec(), vec![value]);
+            fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_restart_for_sanity() {
+        // a second run on the same day must not overwrite the first one's file.
+        for v in [1.0, 2.0] {
+            let mut output =
+                ParquetOutput::new("parquet_test2.parquet".to_string(), tz::Zone::Utc, 10);
+            output.write_header(&names());
+            output.write(&[1699920000.0, v]).unwrap();
+            output.flush();
+        }
+        assert_eq!(read("parquet_test2.parquet").0[0].num_rows(), 1);
+        assert_eq!(read("parquet_test2.1.parquet").0[0].num_rows(), 1);
+        fs::remove_file("parquet_test2.parquet").unwrap();
+        fs::remove_file("parquet_test2.1.parquet").unwrap();
+    }
+}
@@ -0,0 +1,246 @@
+use std::collections::VecDeque;
+use std::time;
+
+use postgres::{Client, NoTls};
+
+use crate::output;
+
+const CREATE: &str = "CREATE TABLE IF NOT EXISTS measurements (\
+    ts timestamptz NOT NULL, \
+    sensor text NOT NULL, \
+    metric text NOT NULL, \
+    value double precision NOT NULL)";
+
+// all readings of the given rows in one statement.
+const INSERT: &str = "INSERT INTO measurements (ts, sensor, metric, value) \
+    SELECT to_timestamp(t), s, m, v \
+    FROM unnest($1::float8[], $2::text[], $3::text[], $4::float8[]) AS r(t, s, m, v)";
+
+const MAX_BACKOFF: time::Duration = time::Duration::from_secs(300);
+
+/// Inserts the readings of each row into a measurements table, one line per sensor and metric (TimescaleDB style).
+///
+/// While the database is unreachable rows are kept - up to max_rows, dropping the oldest - and sent once a reconnect
+/// succeeds; reconnects are attempted with an exponential backoff.
+pub(crate) struct PostgresOutput {
+    dsn: String,
+    client: Option<Client>,
+    sensors: Vec<String>,
+    // (sensor, metric) of each column.
+    keys: Vec<(String, String)>,
+    max_rows: usize,
+    pending: VecDeque<Vec<f64>>,
+    backoff: time::Duration,
+    retry_at: Option<time::Instant>,
+}
+
+impl PostgresOutput {
+    /// Connects right away; wrong credentials or a missing database are reported here, not in the loop.
+    pub(crate) fn new(
+        dsn: &str,
+        sensors: Vec<String>,
+        max_rows: usize,
+    ) -> Result<PostgresOutput, String> {
+        let client = connect(dsn)?;
+        Ok(PostgresOutput {
+            dsn: dsn.to_string(),
+            client: Some(client),
+            sensors,
+            keys: Vec::new(),
+            max_rows: max_rows.max(1),
+            pending: VecDeque::new(),
+            backoff: time::Duration::from_secs(1),
+            retry_at: None,
+        })
+    }
+
+    /// The columns of the insert statement for the given rows; failed (NaN) values are left out.
+    fn readings(
+        &self,
+        rows: &VecDeque<Vec<f64>>,
+    ) -> (Vec<f64>, Vec<String>, Vec<String>, Vec<f64>) {
+        let (mut ts, mut sensors, mut metrics, mut values) = (vec![], vec![], vec![], vec![]);
+        for row in rows {
+            for ((sensor, metric), value) in self.keys.iter().zip(row).skip(1) {
+                if value.is_finite() {
+                    ts.push(row[0]);
+                    sensors.push(sensor.clone());
+                    metrics.push(metric.clone());
+                    values.push(*value);
+                }
+            }
+        }
+        (ts, sensors, metrics, values)
+    }
+
+    fn send(&mut self) -> Result<(), String> {
+        if self.client.as_ref().map(|c| c.is_closed()).unwrap_or(true) {
+            self.client = Some(connect(&self.dsn)?);
+        }
+        let (ts, sensors, metrics, values) = self.readings(&self.pending);
+        let client = self.client.as_mut().unwrap();
+        if let Err(err) = client.execute(INSERT, &[&ts, &sensors, &metrics, &values]) {
+            // reconnect next time, in case the connection is what broke.
+            self.client = None;
+            return Err(format!("could not insert into the database: {}", err));
+        }
+        Ok(())
+    }
+}
+
+fn connect(dsn: &str) -> Result<Client, String> {
+    let mut client = Client::connect(dsn, NoTls)
+        .map_err(|e| format!("could not connect to PostgreSQL: {}", e))?;
+    client
+        .batch_execute(CREATE)
+        .map_err(|e| format!("could not create the measurements table: {}", e))?;
+    Ok(client)
+}
+
+impl output::Output for PostgresOutput {
+    fn write_header(&mut self, names: &[String]) {
+        self.keys = names
+            .iter()
+            .map(|n| output::split(&self.sensors, n))
+            .collect();
+    }
+
+    fn write(&mut self, values: &[f64]) -> Result<(), String> {
+        self.pending.push_back(values.to_vec());
+        while self.pending.len() > self.max_rows {
+            self.pending.pop_front();
+        }
+        if let Some(retry_at) = self.retry_at {
+            if time::Instant::now() < retry_at {
+                return Ok(());
+            }
+        }
+        match self.send() {
+            Ok(()) => {
+                self.pending.clear();
+                self.backoff = time::Duration::from_secs(1);
+                self.retry_at = None;
+                Ok(())
+            }
+            Err(err) => {
+                self.retry_at = Some(time::Instant::now() + self.backoff);
+                let res = Err(format!(
+                    "{}; retrying {} rows in {}s",
+                    err,
+                    self.pending.len(),
+                    self.backoff.as_secs()
+                ));
+                self.backoff = (self.backoff * 2).min(MAX_BACKOFF);
+                res
+            }
+        }
+    }
+
+    fn flush(&mut self) {
+        if !self.pending.is_empty() {
+            if let Err(err) = self.send() {
+                eprintln!(
+                    "Could not write the last {} rows: {}",
+                    self.pending.len(),
+                    err
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output::Output;
+
+    // nobody listens here.
+    const DSN: &str = "host=localhost port=1 user=ogc connect_timeout=1";
+
+    fn postgres(max_rows: usize) -> PostgresOutput {
+        let mut output = PostgresOutput {
+            dsn: DSN.to_string(),
+            client: None,
+            sensors: vec!["fox".to_string(), "fox_2".to_string()],
+            keys: Vec::new(),
+            max_rows,
+            pending: VecDeque::new(),
+            backoff: time::Duration::from_secs(1),
+            retry_at: None,
+        };
+        output.write_header(&[
+            "timestamp".to_string(),
+            "fox_pvPower".to_string(),
+            "fox_2_pvPower".to_string(),
+            "bat_cycles".to_string(),
+        ]);
+        output
+    }
+
+    // Tests for success.
+
+    #[test]
+    fn test_readings_for_success() {
+        let output = postgres(10);
+        let rows = VecDeque::from(vec![
+            vec![1699920000.0, 1.5, f64::NAN, 12.0],
+            vec![1699920030.0, 1.6, 2.0, 12.0],
+        ]);
+        let (ts, sensors, metrics, values) = output.readings(&rows);
+        assert_eq!(
+            ts,
+            vec![
+                1699920000.0,
+                1699920000.0,
+                1699920030.0,
+                1699920030.0,
+                1699920030.0
+            ]
+        );
+        assert_eq!(sensors, vec!["fox", "bat", "fox", "fox_2", "bat"]);
+        assert_eq!(
+            metrics,
+            vec!["pvPower", "cycles", "pvPower", "pvPower", "cycles"]
+        );
+        assert_eq!(values, vec![1.5, 12.0, 1.6, 2.0, 12.0]);
+    }
+
+    // Tests for failure.
+
+    #[test]
+    fn test_new_for_failure() {
+        // fails at startup, with a message saying why.
+        let err = PostgresOutput::new(DSN, Vec::new(), 10).err().unwrap();
+        assert!(
+            err.starts_with("could not connect to PostgreSQL: "),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_write_for_failure() {
+        let mut output = postgres(2);
+        assert!(output.write(&[1699920000.0, 1.5, 2.0, 12.0]).is_err());
+        assert_eq!(output.backoff, time::Duration::from_secs(2));
+        // backing off; not even trying.
+        assert!(output.write(&[1699920030.0, 1.5, 2.0, 12.0]).is_ok());
+        assert!(output.write(&[1699920060.0, 1.5, 2.0, 12.0]).is_ok());
+        assert_eq!(output.backoff, time::Duration::from_secs(2));
+        // only so many rows are kept.
+        assert_eq!(output.pending.len(), 2);
+        assert_eq!(output.pending[0][0], 1699920030.0);
+    }
+
+    // Tests for sanity.
+
+    #[test]
+    fn test_backoff_for_sanity() {
+        let mut output = postgres(10);
+        for _ in 0..12 {
+            output.retry_at = None;
+            let _ = output.write(&[1699920000.0, 1.5, 2.0, 12.0]);
+        }
+        assert_eq!(output.backoff, MAX_BACKOFF);
+    }
+}
@@ -2,6 +2,8 @@ extern crate byteorder;
 extern crate embedded_hal as hal;
 extern crate linux_embedded_hal;
 
+use std::cell::RefCell;
+use std::error::Error;
 use std::{thread, time};
 
 use byteorder::{BigEndian, ByteOrder};
@@ -9,8 +11,10 @@ use hal::blocking::i2c;
 use linux_embedded_hal::I2cdev;
 
 use crate::common;
+use crate::common::{Reading, SensorError};
 
 const NAMES: [&str; 3] = ["voltage", "current", "power"];
+const UNITS: [&str; 3] = ["V", "A", "W"];
 
 struct Ina219<I2C> {
     i2c: I2C,
@@ -71,6 +75,8 @@ pub struct PowerSensor {
     dev_bus: String,
     address: u8,
     current_lsb: f64,
+    // the open and calibrated chip; opened again after an error.
+    ina: RefCell<Option<Ina219<I2cdev>>>,
 }
 
 impl PowerSensor {
@@ -81,8 +87,45 @@ impl PowerSensor {
             dev_bus,
             address,
             current_lsb,
+            ina: RefCell::new(None),
         }
     }
+
+    /// Opens the bus and calibrates the chip.
+    fn open(&self) -> Result<Ina219<I2cdev>, Box<dyn Error>> {
+        let device = I2cdev::new(self.dev_bus.clone())?;
+        let mut ina = Ina219::new(device, self.address);
+        let calibration = (0.04096_f64 / (self.current_lsb * 0.1)).trunc(); // 0.1 = shunt amps
+        ina.calibrate(calibration as u16)?;
+        Ok(ina)
+    }
+
+    /// Reads from the chip opened by init; or opens it now, e.g. when it was not there at startup.
+    fn read(&self) -> Result<Vec<f64>, Box<dyn Error>> {
+        let mut slot = self.ina.borrow_mut();
+        let mut ina = match slot.take() {
+            Some(ina) => ina,
+            None => self.open()?,
+        };
+        let res = self.sample(&mut ina);
+        if res.is_ok() {
+            *slot = Some(ina);
+        }
+        res
+    }
+
+    fn sample(&self, ina: &mut Ina219<I2cdev>) -> Result<Vec<f64>, Box<dyn Error>> {
+        ina.wake()?;
+        let voltage: f64 = (ina.read(0x02)? >> 3) as f64 * 4.0 / 1000.0;
+        let current: f64 = ina.read(0x04)? as f64 * 1000.0 * self.current_lsb;
+        let power: f64 = ina.read(0x03)? as f64 * 20.0 * self.current_lsb * 1000.0;
+        ina.sleep()?;
+        if power <= 0.0 {
+            return Ok(vec![0.0; 3]);
+        }
+
+        Ok(vec![voltage, current, power])
+    }
 }
 
 impl common::Sensor for PowerSensor {
@@ -93,22 +136,57 @@ impl common::Sensor for PowerSensor {
         }
         names
     }
-    fn measure(&self) -> Vec<f64> {
-        let device = I2cdev::new(self.dev_bus.clone()).unwrap();
-        let mut ina = Ina219::new(device, self.address);
-        let calibration = (0.04096_f64 / (self.current_lsb * 0.1)).trunc(); // 0.1 = shunt amps
-        ina.calibrate(calibration as u16).unwrap();
 
-        ina.wake().unwrap();
-        let voltage: f64 = (ina.read(0x02).unwrap() >> 3) as f64 * 4.0 / 1000.0;
-        let current: f64 = ina.read(0x04).unwrap() as f64 * 1000.0 * self.current_lsb;
-        let power: f64 = ina.read(0x03).unwrap() as f64 * 20.0 * self.current_lsb * 1000.0;
-        ina.sleep().unwrap();
-        if power <= 0.0 {
-            return vec![0.0; 3];
-        }
+    fn get_units(&self) -> Vec<String> {
+        UNITS.iter().map(|u| u.to_string()).collect()
+    }
 
-        vec![voltage, current, power]
+    fn measure(&self) -> Result<Vec<Reading>, SensorError> {
+        let values = self
+            .read()
+            .map_err(|e| SensorError::Io(format!("could not read from the INA219: {}", e)))?;
+        Ok(common::readings(self.get_names(), values))
+    }
+
+    fn init(&mut self) -> Result<(), SensorError> {
+        let ina = self
+            .open()
+            .map_err(|e| SensorError::Io(format!("could not calibrate the INA219: {}", e)))?;
+        *self.ina.get_mut() = Some(ina);
+        Ok(())
+    }
+
+    fn shutdown(&mut self) {
+        // closes the bus.
+        *self.ina.get_mut() = None;
+    }
+
+    fn validate(&self) -> Result<(), Box<dyn Error>> {
+        // the configuration register is readable on any INA219.
+        let device = I2cdev::new(self.dev_bus.clone())?;
+        let mut ina = Ina219::new(device, self.address);
+        ina.read(0x00)?;
+        Ok(())
+    }
+}
+
+/// INA219 boards on an I2C bus.
+pub(crate) fn sensor_type() -> common::SensorType {
+    common::SensorType {
+        name: "power",
+        required: &["bus", "address", "expected_amps"],
+        optional: &[],
+        create: |name, sensor_cfg, _| {
+            Ok(Box::new(PowerSensor::new(
+                name.to_string(),
+                sensor_cfg["bus"]
+                    .as_str()
+                    .unwrap_or("/dev/i2c-0")
+                    .to_string(),
+                sensor_cfg["address"].as_integer().unwrap_or(64) as u8,
+                sensor_cfg["expected_amps"].as_float().unwrap_or(1.0),
+            )))
+        },
     }
 }
 
@@ -127,6 +205,22 @@ mod tests {
 
     // Tests for failure.
 
+    #[test]
+    fn test_measure_for_failure() {
+        // no such bus; must not panic.
+        let sensor = PowerSensor::new("foo".to_string(), "/dev/i2c-missing".to_string(), 64, 1.0);
+        assert!(matches!(sensor.measure(), Err(SensorError::Io(_))));
+        assert!(sensor.validate().is_err());
+    }
+
+    #[test]
+    fn test_init_for_failure() {
+        let mut sensor =
+            PowerSensor::new("foo".to_string(), "/dev/i2c-missing".to_string(), 64, 1.0);
+        assert!(matches!(sensor.init(), Err(SensorError::Io(_))));
+        assert!(sensor.ina.borrow().is_none());
+    }
+
     // Tests for sanity.
 
     #[test]
@@ -135,4 +229,10 @@ mod tests {
         let res: Vec<String> = sensor.get_names();
         assert_eq!(res, vec!["foo_voltage", "foo_current", "foo_power"]);
     }
+
+    #[test]
+    fn test_get_units_for_sanity() {
+        let sensor: PowerSensor = PowerSensor::new("foo".to_string(), "".to_string(), 0, 0.0);
+        assert_eq!(sensor.get_units(), vec!["V", "A", "W"]);
+    }
 }
@@ -0,0 +1,357 @@
+use std::fs;
+use std::path;
+
+use crate::common;
+use crate::common::{ConfigError, Reading, SensorError};
+
+/// Where the kernel exposes batteries and chargers.
+const POWER_SUPPLY_DIR: &str = "/sys/class/power_supply";
+
+/// The metrics reported per battery.
+const METRICS: [(&str, &str); 5] = [
+    ("charge_percent", "%"),
+    ("status", ""),
+    ("power", "W"),
+    ("energy_full", "Wh"),
+    ("energy_now", "Wh"),
+];
+
+/// A battery like BAT0, reporting either energy_* (µWh) or charge_* (µAh) attributes.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Battery {
+    /// Its name, e.g. BAT0.
+    pub(crate) name: String,
+    dir: path::PathBuf,
+}
+
+impl Battery {
+    fn text(&self, file: &str) -> Result<String, SensorError> {
+        let path = self.dir.join(file);
+        fs::read_to_string(&path)
+            .map(|c| c.trim().to_string())
+            .map_err(|e| SensorError::Io(format!("cannot read {}: {}", path.display(), e)))
+    }
+
+    fn value(&self, file: &str) -> Result<f64, SensorError> {
+        let content = self.text(file)?;
+        content.parse().map_err(|e| {
+            SensorError::Parse(format!(
+                "{} of {}: {}",
+                content,
+                self.dir.join(file).display(),
+                e
+            ))
+        })
+    }
+
+    /// The first of the attributes there is; the error is the one of the first.
+    fn either<T>(
+        &self,
+        first: impl Fn() -> Result<T, SensorError>,
+        second: impl Fn() -> Result<T, SensorError>,
+    ) -> Result<T, SensorError> {
+        first().or_else(|err| second().map_err(|_| err))
+    }
+
+    fn charge_percent(&self) -> Result<f64, SensorError> {
+        self.either(
+            || self.value("capacity"),
+            || Ok(self.energy("now")? / self.energy("full")? * 100.0),
+        )
+    }
+
+    /// 1 while charging, -1 while discharging and 0 otherwise, e.g. when full.
+    fn status(&self) -> Result<f64, SensorError> {
+        Ok(match self.text("status")?.as_str() {
+            "Charging" => 1.0,
+            "Discharging" => -1.0,
+            _ => 0.0,
+        })
+    }
+
+    /// The power flowing in or out in W; the status tells which.
+    fn power(&self) -> Result<f64, SensorError> {
+        self.either(
+            || Ok(self.value("power_now")?.abs() / 1e6),
+            || Ok((self.value("current_now")? * self.value("voltage_now")?).abs() / 1e12),
+        )
+    }
+
+    /// The energy when full or now in Wh; charges are multiplied by the design voltage.
+    fn energy(&self, which: &str) -> Result<f64, SensorError> {
+        self.either(
+            || Ok(self.value(&format!("energy_{}", which))? / 1e6),
+            || {
+                let voltage = self.either(
+                    || self.value("voltage_min_design"),
+                    || self.value("voltage_now"),
+                )?;
+                Ok(self.value(&format!("charge_{}", which))? * voltage / 1e12)
+            },
+        )
+    }
+
+    fn values(&self) -> [Result<f64, SensorError>; 5] {
+        [
+            self.charge_percent(),
+            self.status(),
+            self.power(),
+            self.energy("full"),
+            self.energy("now"),
+        ]
+    }
+}
+
+/// Finds the system's batteries in the given directory, e.g. BAT0 and BAT1; not the ones of mice and the like.
+pub(crate) fn discover(root: &str) -> Vec<Battery> {
+    let entries = match fs::read_dir(root) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+    let mut res: Vec<Battery> = entries
+        .filter_map(|e| e.ok())
+        .filter_map(|e| {
+            let dir = e.path();
+            let read = |file| fs::read_to_string(dir.join(file)).map(|c| c.trim().to_string());
+            if read("type").ok()? != "Battery" || read("scope").ok().as_deref() == Some("Device") {
+                return None;
+            }
+            let name = e.file_name().to_string_lossy().to_string();
+            Some(Battery { name, dir })
+        })
+        .collect();
+    res.sort_by(|a, b| a.name.cmp(&b.name));
+    res
+}
+
+/// Reports the charge, status, power and energy of a laptop's batteries, each under its own prefix like bat0.
+pub struct BatterySensor {
+    name: String,
+    batteries: Vec<Battery>,
+}
+
+impl BatterySensor {
+    pub(crate) fn new(name: String, batteries: Vec<Battery>) -> BatterySensor {
+        BatterySensor { name, batteries }
+    }
+}
+
+impl common::Sensor for BatterySensor {
+    fn get_names(&self) -> Vec<String> {
+        self.batteries
+            .iter()
+            .flat_map(|battery| {
+                METRICS.iter().map(move |(metric, _)| {
+                    format!("{}_{}_{}", self.name, battery.name.to_lowercase(), metric)
+                })
+            })
+            .collect()
+    }
+
+    fn get_units(&self) -> Vec<String> {
+        self.batteries
+            .iter()
+            .flat_map(|_| METRICS.iter().map(|(_, unit)| unit.to_string()))
+            .collect()
+    }
+
+    fn measure(&self) -> Result<Vec<Reading>, SensorError> {
+        let names = self.get_names();
+        let mut res = Vec::new();
+        let mut error = None;
+        for (i, battery) in self.batteries.iter().enumerate() {
+            for (j, value) in battery.values().into_iter().enumerate() {
+                match value {
+                    Ok(value) => res.push(Reading::new(names[5 * i + j].clone(), value)),
+                    Err(err) => error = Some(err),
+                }
+            }
+        }
+        match error {
+            Some(err) if res.is_empty() => Err(err),
+            Some(err) => {
+                eprintln!("Could not read all of {}: {}", self.name, err);
+                Ok(res)
+            }
+            None => Ok(res),
+        }
+    }
+
+    fn init(&mut self) -> Result<(), SensorError> {
+        // e.g. on a desktop.
+        for battery in &self.batteries {
+            for value in battery.values() {
+                value.map_err(|err| {
+                    SensorError::Io(format!(
+                        "{}; expected a battery at {}.",
+                        err.message(),
+                        battery.dir.display()
+                    ))
+                })?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Charge and power of the host's batteries.
+pub(crate) fn sensor_type() -> common::SensorType {
+    common::SensorType {
+        name: "battery",
+        required: &[],
+        optional: &["path", "batteries"],
+        create: |name, sensor_cfg, _| {
+            let root = match sensor_cfg.get("path") {
+                Some(v) => v
+                    .as_str()
+                    .ok_or_else(|| ConfigError::Invalid("path must be a string.".to_string()))?,
+                None => POWER_SUPPLY_DIR,
+            };
+            let names = match sensor_cfg.get("batteries") {
+                Some(v) => v
+                    .as_array()
+                    .and_then(|a| {
+                        a.iter()
+                            .map(|b| b.as_str().map(|b| b.to_string()))
+                            .collect::<Option<Vec<_>>>()
+                    })
+                    .ok_or_else(|| {
+                        ConfigError::Invalid("batteries must be an array of strings.".to_string())
+                    })?,
+                None => {
+                    let found: Vec<String> = discover(root).into_iter().map(|b| b.name).collect();
+                    // none at all fails at startup, naming where it was expected.
+                    if found.is_empty() {
+                        vec!["BAT0".to_string()]
+                    } else {
+                        found
+                    }
+                }
+            };
+            let batteries = names
+                .into_iter()
+                .map(|name| Battery {
+                    dir: path::Path::new(root).join(&name),
+                    name,
+                })
+                .collect();
+            Ok(Box::new(BatterySensor::new(name.to_string(), batteries)))
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::values;
+
+    /// A power_supply directory with a battery reporting energy, one reporting charge, AC and a mouse.
+    fn power_supply(root: &str) {
+        let _ = fs::remove_dir_all(root);
+        for (supply, files) in [
+            (
+                "BAT0",
+                vec![
+                    ("type", "Battery"),
+                    ("status", "Discharging"),
+                    ("capacity", "80"),
+                    ("power_now", "12500000"),
+                    ("energy_full", "50000000"),
+                    ("energy_now", "40000000"),
+                ],
+            ),
+            (
+                "BAT1",
+                vec![
+                    ("type", "Battery"),
+                    ("status", "Charging"),
+                    ("current_now", "2000000"),
+                    ("voltage_now", "12000000"),
+                    ("voltage_min_design", "10000000"),
+                    ("charge_full", "4000000"),
+                    ("charge_now", "1000000"),
+                ],
+            ),
+            ("AC", vec![("type", "Mains"), ("online", "1")]),
+            (
+                "hidpp_battery_0",
+                vec![("type", "Battery"), ("scope", "Device"), ("capacity", "50")],
+            ),
+        ] {
+            let dir = path::Path::new(root).join(supply);
+            fs::create_dir_all(&dir).unwrap();
+            for (file, content) in files {
+                fs::write(dir.join(file), format!("{}\n", content)).unwrap();
+            }
+        }
+    }
+
+    // Tests for success.
+
+    #[test]
+    fn test_measure_for_success() {
+        let root = "power_supply_test0";
+        power_supply(root);
+        let sensor_cfg: toml::value::Table = toml::from_str("path='power_supply_test0'").unwrap();
+        let mut sensor = (sensor_type().create)("laptop", &sensor_cfg, "state").unwrap();
+        sensor.init().unwrap();
+        assert_eq!(sensor.get_names().len(), 10);
+        assert_eq!(sensor.get_units()[..5], ["%", "", "W", "Wh", "Wh"]);
+        assert_eq!(
+            values(sensor.measure()),
+            vec![
+                ("laptop_bat0_charge_percent".to_string(), 80.0),
+                ("laptop_bat0_status".to_string(), -1.0),
+                ("laptop_bat0_power".to_string(), 12.5),
+                ("laptop_bat0_energy_full".to_string(), 50.0),
+                ("laptop_bat0_energy_now".to_string(), 40.0),
+                // from the charge; at the design voltage.
+                ("laptop_bat1_charge_percent".to_string(), 25.0),
+                ("laptop_bat1_status".to_string(), 1.0),
+                ("laptop_bat1_power".to_string(), 24.0),
+                ("laptop_bat1_energy_full".to_string(), 40.0),
+                ("laptop_bat1_energy_now".to_string(), 10.0)
+            ]
+        );
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    // Tests for failure.
+
+    #[test]
+    fn test_init_for_failure() {
+        // a desktop.
+        let sensor_cfg: toml::value::Table = toml::from_str("path='power_supply_missing'").unwrap();
+        let mut sensor = (sensor_type().create)("laptop", &sensor_cfg, "state").unwrap();
+        match sensor.init() {
+            Err(SensorError::Io(msg)) => {
+                assert!(msg.starts_with("cannot read power_supply_missing/BAT0/capacity: "));
+                assert!(msg.ends_with("; expected a battery at power_supply_missing/BAT0."));
+            }
+            _ => panic!("missing battery not reported."),
+        }
+    }
+
+    // Tests for sanity.
+
+    #[test]
+    fn test_batteries_for_sanity() {
+        let root = "power_supply_test1";
+        power_supply(root);
+        assert_eq!(
+            discover(root)
+                .into_iter()
+                .map(|b| b.name)
+                .collect::<Vec<_>>(),
+            vec!["BAT0", "BAT1"]
+        );
+        let sensor_cfg: toml::value::Table =
+            toml::from_str("path='power_supply_test1'\nbatteries=['BAT1']").unwrap();
+        let sensor = (sensor_type().create)("laptop", &sensor_cfg, "state").unwrap();
+        assert_eq!(sensor.get_names()[0], "laptop_bat1_charge_percent");
+        // pulled; nothing left to report.
+        fs::remove_dir_all(path::Path::new(root).join("BAT1")).unwrap();
+        assert!(sensor.measure().is_err());
+        fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,165 @@
+use std::collections::HashMap;
+
+/// Which columns an output receives.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Visibility {
+    /// Only columns that are not private.
+    Public,
+    All,
+}
+
+impl Visibility {
+    pub(crate) fn parse(value: &str) -> Visibility {
+        match value {
+            "public" => Visibility::Public,
+            "all" => Visibility::All,
+            _ => panic!(
+                "visibility must be either 'public' or 'all'; got: {}.",
+                value
+            ),
+        }
+    }
+}
+
+/// Matches a name against a glob pattern supporting '*' and '?'.
+pub(crate) fn matches(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    // positions to backtrack to for the last '*' seen.
+    let (mut p, mut n) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+    while n < name.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == name[n]) {
+            p += 1;
+            n += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, n));
+            p += 1;
+        } else if let Some((sp, sn)) = star {
+            p = sp + 1;
+            n = sn + 1;
+            star = Some((sp, sn + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+/// Tags columns as private based on glob patterns.
+pub(crate) struct Privacy {
+    private: Vec<String>,
+    public: Vec<String>,
+}
+
+impl Privacy {
+    /// Columns matching private are private; public overrides this, e.g. for derived columns.
+    pub(crate) fn new(private: Vec<String>, public: Vec<String>) -> Privacy {
+        Privacy { private, public }
+    }
+
+    /// Flags the private columns. Derived columns (given with the columns they are computed from)
+    /// are private if any of their inputs is, unless they match a private or public pattern.
+    pub(crate) fn classify(
+        &self,
+        names: &[String],
+        derived: &HashMap<String, Vec<String>>,
+    ) -> Vec<bool> {
+        let mut res: Vec<bool> = Vec::with_capacity(names.len());
+        for name in names {
+            let private = if self.public.iter().any(|p| matches(p, name)) {
+                false
+            } else if self.private.iter().any(|p| matches(p, name)) {
+                true
+            } else {
+                match derived.get(name) {
+                    Some(inputs) => inputs.iter().any(|input| {
+                        names
+                            .iter()
+                            .position(|n| n == input)
+                            .map(|i| i < res.len() && res[i])
+                            .unwrap_or(false)
+                    }),
+                    None => false,
+                }
+            };
+            res.push(private);
+        }
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|n| n.to_string()).collect()
+    }
+
+    // Tests for success.
+
+    #[test]
+    fn test_parse_for_success() {
+        assert_eq!(Visibility::parse("public"), Visibility::Public);
+        assert_eq!(Visibility::parse("all"), Visibility::All);
+    }
+
+    // Tests for failure.
+
+    #[test]
+    #[should_panic]
+    fn test_parse_for_failure() {
+        Visibility::parse("friends");
+    }
+
+    #[test]
+    fn test_matches_for_failure() {
+        assert!(!matches("fritz_*", "fritz"));
+        assert!(!matches("fritz_?", "fritz_ab"));
+        assert!(!matches("host", "host_cpu"));
+        assert!(!matches("", "a"));
+    }
+
+    // Tests for sanity.
+
+    #[test]
+    fn test_matches_for_sanity() {
+        assert!(matches("fritz_*", "fritz_power"));
+        assert!(matches("fritz_*", "fritz_"));
+        assert!(matches("*_power", "fritz_power"));
+        assert!(matches("*power*", "pv_power_forecast_5m"));
+        assert!(matches("host_?", "host_a"));
+        assert!(matches("a*b*c", "aXbYbZc"));
+        assert!(matches("*", ""));
+        assert!(matches("timestamp", "timestamp"));
+    }
+
+    #[test]
+    fn test_classify_for_sanity() {
+        let privacy = Privacy::new(
+            vec!["fritz_*".to_string(), "host_*".to_string()],
+            vec!["self_*".to_string()],
+        );
+        let columns = names(&[
+            "timestamp",
+            "pv_power",
+            "fritz_power",
+            "host_present",
+            "pv_power_forecast_5m",
+            "surplus",
+            "self_energy_wh",
+        ]);
+        let mut derived = HashMap::new();
+        derived.insert(
+            "pv_power_forecast_5m".to_string(),
+            names(&["timestamp", "pv_power"]),
+        );
+        derived.insert("surplus".to_string(), names(&["pv_power", "fritz_power"]));
+        derived.insert("self_energy_wh".to_string(), names(&["host_present"]));
+        assert_eq!(
+            privacy.classify(&columns, &derived),
+            vec![false, false, true, true, false, true, false]
+        );
+    }
+}
@@ -0,0 +1,378 @@
+use std::fmt::Write as _;
+use std::io::{self, Read, Write};
+use std::net;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time;
+
+use crate::device;
+use crate::health;
+use crate::latency;
+use crate::output;
+
+/// The pages served; rendered on each update.
+#[derive(Default)]
+struct Pages {
+    metrics: String,
+    /// p50/p95/p99 and error rate of each sensor over the rolling window.
+    status: String,
+}
+
+/// Serves the latest values of the loop as Prometheus gauges on /metrics and a summary of the
+/// sensors on /statusz; devices are switched on and off with a POST to /devices/<name>/enable or
+/// /devices/<name>/disable.
+pub(crate) struct Exporter {
+    addr: net::SocketAddr,
+    // (name, type, device) of each sensor.
+    sensors: Vec<(String, String, Option<String>)>,
+    pages: Arc<Mutex<Pages>>,
+    stop: Arc<AtomicBool>,
+    handle: Option<thread::JoinHandle<()>>,
+}
+
+impl Exporter {
+    /// Starts listening; the page stays empty until the first update.
+    pub(crate) fn start(
+        listen: &str,
+        sensors: Vec<(String, String, Option<String>)>,
+    ) -> io::Result<Exporter> {
+        let listener = net::TcpListener::bind(listen)?;
+        // polled, so the thread notices when to stop.
+        listener.set_nonblocking(true)?;
+        let addr = listener.local_addr()?;
+        let pages = Arc::new(Mutex::new(Pages::default()));
+        let stop = Arc::new(AtomicBool::new(false));
+        let handle = {
+            let (pages, stop) = (pages.clone(), stop.clone());
+            thread::spawn(move || serve(listener, pages, stop))
+        };
+        eprintln!("Serving metrics on http://{}/metrics.", addr);
+        Ok(Exporter {
+            addr,
+            sensors,
+            pages,
+            stop,
+            handle: Some(handle),
+        })
+    }
+
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub(crate) fn addr(&self) -> net::SocketAddr {
+        self.addr
+    }
+
+    /// Renders the pages for the latest row.
+    pub(crate) fn update(
+        &self,
+        names: &[String],
+        values: &[f64],
+        trackers: &[latency::Tracker],
+        health: &[health::Health],
+    ) {
+        let mut page = render(&self.sensors, names, values);
+        page.push_str(&latency::render(trackers));
+        page.push_str(&health::render(health));
+        page.push_str(
+            "# HELP ogc_dropped_rows_total Rows that could not be written to the data file.\n",
+        );
+        page.push_str("# TYPE ogc_dropped_rows_total counter\n");
+        let _ = writeln!(page, "ogc_dropped_rows_total {}", output::dropped_rows());
+        let now = values.first().copied().unwrap_or(f64::NAN);
+        let mut status = String::new();
+        for tracker in trackers {
+            let _ = writeln!(status, "{}", tracker.status(now));
+        }
+        let mut pages = self.pages.lock().expect("metrics lock poisoned.");
+        pages.metrics = page;
+        pages.status = status;
+    }
+
+    /// Stops listening and waits for the thread.
+    pub(crate) fn stop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+impl Drop for Exporter {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+fn serve(listener: net::TcpListener, pages: Arc<Mutex<Pages>>, stop: Arc<AtomicBool>) {
+    while !stop.load(Ordering::Relaxed) {
+        match listener.accept() {
+            Ok((stream, _)) => {
+                if let Err(err) = respond(stream, &pages) {
+                    eprintln!("Could not serve metrics: {}", err);
+                }
+            }
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                thread::sleep(time::Duration::from_millis(50));
+            }
+            Err(err) => eprintln!("Could not accept a connection: {}", err),
+        }
+    }
+}
+
+fn respond(mut stream: net::TcpStream, pages: &Mutex<Pages>) -> io::Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(time::Duration::from_secs(5)))?;
+    let mut buf = [0; 1024];
+    let n = stream.read(&mut buf)?;
+    let request = String::from_utf8_lossy(&buf[..n]);
+    let pages = pages.lock().expect("metrics lock poisoned.");
+    let (status, body) = if request.starts_with("GET /metrics ") {
+        ("200 OK", pages.metrics.clone())
+    } else if request.starts_with("GET /statusz ") {
+        ("200 OK", pages.status.clone())
+    } else if let Some(path) = request.strip_prefix("POST /devices/") {
+        toggle(path.split(' ').next().unwrap_or_default())
+    } else {
+        ("404 Not Found", String::new())
+    };
+    drop(pages);
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )
+}
+
+/// Enables or disables a device given as <name>/enable or <name>/disable.
+fn toggle(path: &str) -> (&'static str, String) {
+    let enabled = match path.rsplit_once('/') {
+        Some((name, "enable")) => Some((name, true)),
+        Some((name, "disable")) => Some((name, false)),
+        _ => None,
+    };
+    match enabled {
+        Some((name, enabled)) if device::set_enabled(name, enabled) => {
+            let state = if enabled { "enabled" } else { "disabled" };
+            ("200 OK", format!("{} {}\n", name, state))
+        }
+        _ => ("404 Not Found", String::new()),
+    }
+}
+
+/// A valid metric name; anything but letters, digits, '_' and ':' becomes '_'.
+pub(crate) fn sanitize(name: &str) -> String {
+    let mut res: String = name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '_' || c == ':' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    if res.starts_with(|c: char| c.is_ascii_digit()) {
+        res.insert(0, '_');
+    }
+    res
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// The values of a metric, each labeled with its sensor - and the sensor's device - if it has one.
+type Samples<'a> = Vec<(Option<(&'a str, Option<&'a str>)>, f64)>;
+
+/// Gauges for all columns but the timestamp, named ogc_<type>_<metric> and labeled with the sensor
+/// and its device.
+///
+/// Columns not belonging to a sensor (e.g. of components) become ogc_<column> without a label.
+fn render(
+    sensors: &[(String, String, Option<String>)],
+    names: &[String],
+    values: &[f64],
+) -> String {
+    // columns of the same metric are kept together.
+    let mut metrics: Vec<(String, Samples)> = Vec::new();
+    for (name, value) in names.iter().zip(values).skip(1) {
+        let sensor = sensors
+            .iter()
+            .filter(|(s, _, _)| name.starts_with(&format!("{}_", s)))
+            .max_by_key(|(s, _, _)| s.len());
+        let (metric, label) = match sensor {
+            Some((sensor, kind, device)) => (
+                format!("{}_{}", kind, &name[sensor.len() + 1..]),
+                Some((sensor.as_str(), device.as_deref())),
+            ),
+            None => (name.clone(), None),
+        };
+        let metric = format!("ogc_{}", sanitize(&metric));
+        match metrics.iter_mut().find(|(m, _)| *m == metric) {
+            Some((_, samples)) => samples.push((label, *value)),
+            None => metrics.push((metric, vec![(label, *value)])),
+        }
+    }
+    let mut res = String::new();
+    for (metric, samples) in metrics {
+        let _ = writeln!(res, "# TYPE {} gauge", metric);
+        for (label, value) in samples {
+            match label {
+                Some((sensor, Some(device))) => {
+                    let _ = writeln!(
+                        res,
+                        "{}{{sensor=\"{}\",device=\"{}\"}} {}",
+                        metric,
+                        escape(sensor),
+                        escape(device),
+                        value
+                    );
+                }
+                Some((sensor, None)) => {
+                    let _ = writeln!(res, "{}{{sensor=\"{}\"}} {}", metric, escape(sensor), value);
+                }
+                None => {
+                    let _ = writeln!(res, "{} {}", metric, value);
+                }
+            }
+        }
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|n| n.to_string()).collect()
+    }
+
+    fn sensors() -> Vec<(String, String, Option<String>)> {
+        vec![
+            ("fritz0".to_string(), "fritz".to_string(), None),
+            ("fritz-1".to_string(), "fritz".to_string(), None),
+            ("owa".to_string(), "weather".to_string(), None),
+            (
+                "bms".to_string(),
+                "modbus".to_string(),
+                Some("battery".to_string()),
+            ),
+        ]
+    }
+
+    // Tests for success.
+
+    #[test]
+    fn test_render_for_success() {
+        let page = render(
+            &sensors(),
+            &names(&[
+                "timestamp",
+                "fritz0_power",
+                "fritz-1_power",
+                "owa_temperature",
+                "bms_soc",
+                "bat_cycles",
+            ]),
+            &[1699920000.0, 10.5, f64::NAN, 12.0, 80.0, 3.0],
+        );
+        assert_eq!(
+            page,
+            "# TYPE ogc_fritz_power gauge\n\
+            ogc_fritz_power{sensor=\"fritz0\"} 10.5\n\
+            ogc_fritz_power{sensor=\"fritz-1\"} NaN\n\
+            # TYPE ogc_weather_temperature gauge\n\
+            ogc_weather_temperature{sensor=\"owa\"} 12\n\
+            # TYPE ogc_modbus_soc gauge\n\
+            ogc_modbus_soc{sensor=\"bms\",device=\"battery\"} 80\n\
+            # TYPE ogc_bat_cycles gauge\n\
+            ogc_bat_cycles 3\n"
+        );
+    }
+
+    // Tests for failure.
+
+    #[test]
+    fn test_start_for_failure() {
+        let exporter = Exporter::start("127.0.0.1:0", sensors()).unwrap();
+        // the port is taken.
+        assert!(Exporter::start(&exporter.addr().to_string(), sensors()).is_err());
+    }
+
+    // Tests for sanity.
+
+    #[test]
+    fn test_sanitize_for_sanity() {
+        assert_eq!(sanitize("pv-power in W"), "pv_power_in_W");
+        assert_eq!(sanitize("2nd_power"), "_2nd_power");
+        assert_eq!(escape("say \"hi\""), "say \\\"hi\\\"");
+    }
+
+    #[test]
+    fn test_serve_for_sanity() {
+        let mut exporter = Exporter::start("127.0.0.1:0", sensors()).unwrap();
+        let url = format!("http://{}", exporter.addr());
+        let mut tracker = latency::Tracker::new("fritz0".to_string(), 3600.0, crate::tz::Zone::Utc);
+        tracker.record(1699920000.0, 0.1, true);
+        exporter.update(
+            &names(&["timestamp", "fritz0_power"]),
+            &[1699920000.0, 10.5],
+            &[tracker],
+            &[crate::health::Health::new("fritz0".to_string())],
+        );
+
+        let res = reqwest::blocking::get(format!("{}/metrics", url)).unwrap();
+        assert_eq!(res.status(), 200);
+        let body = res.text().unwrap();
+        assert!(body.contains("ogc_fritz_power{sensor=\"fritz0\"} 10.5\n"));
+        assert!(body.contains("ogc_sensor_failures_total{sensor=\"fritz0\"} 0\n"));
+        assert!(body
+            .contains("ogc_sensor_last_success_timestamp_seconds{sensor=\"fritz0\"} 1699920000\n"));
+        assert!(body.contains("ogc_dropped_rows_total "));
+        assert!(body.contains("ogc_sensor_up{sensor=\"fritz0\"} 1\n"));
+        let res = reqwest::blocking::get(format!("{}/statusz", url)).unwrap();
+        assert_eq!(res.status(), 200);
+        assert_eq!(
+            res.text().unwrap(),
+            "fritz0: p50=0.075s p95=0.098s p99=0.100s error rate=0.0% (1 calls)\n"
+        );
+        assert_eq!(
+            reqwest::blocking::get(format!("{}/foo", url))
+                .unwrap()
+                .status(),
+            404
+        );
+
+        // devices are switched over HTTP.
+        let enabled = device::switch("prometheus_test0", true);
+        let client = reqwest::blocking::Client::new();
+        let res = client
+            .post(format!("{}/devices/prometheus_test0/disable", url))
+            .send()
+            .unwrap();
+        assert_eq!(res.status(), 200);
+        assert!(!enabled.load(Ordering::Relaxed));
+        client
+            .post(format!("{}/devices/prometheus_test0/enable", url))
+            .send()
+            .unwrap();
+        assert!(enabled.load(Ordering::Relaxed));
+        for path in ["prometheus_test1/enable", "prometheus_test0/foo"] {
+            let res = client
+                .post(format!("{}/devices/{}", url, path))
+                .send()
+                .unwrap();
+            assert_eq!(res.status(), 404);
+        }
+
+        // stopped cleanly; nobody listens anymore.
+        exporter.stop();
+        assert!(reqwest::blocking::get(format!("{}/metrics", url)).is_err());
+    }
+}
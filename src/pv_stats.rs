@@ -0,0 +1,173 @@
+use crate::common;
+
+const METRICS: [&str; 3] = ["surplus_w", "self_consumption_w", "self_consumption_ratio"];
+
+/// Where the surplus of the PV comes from.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Surplus {
+    /// A column of the power fed into the grid; negative while importing.
+    FeedIn(String),
+    /// A column of the power consumed by the house; the surplus is what the PV generates beyond that.
+    Consumption(String),
+}
+
+/// The surplus of the PV, the part of its power consumed locally, and its ratio to the generation.
+///
+/// Missing inputs make for missing outputs, as does the ratio while the PV generates nothing.
+pub struct PvStatsComponent {
+    name: String,
+    generation_column: String,
+    surplus: Surplus,
+}
+
+impl PvStatsComponent {
+    pub(crate) fn new(
+        name: String,
+        generation_column: String,
+        surplus: Surplus,
+    ) -> PvStatsComponent {
+        PvStatsComponent {
+            name,
+            generation_column,
+            surplus,
+        }
+    }
+}
+
+/// Surplus, self-consumption and self-consumption ratio for the given generation; anything negative is taken as 0.
+fn compute(generation: f64, surplus: f64) -> [f64; 3] {
+    if generation.is_nan() || surplus.is_nan() {
+        return [f64::NAN; 3];
+    }
+    let generation = generation.max(0.0);
+    // e.g. a battery exporting at night.
+    let surplus = surplus.clamp(0.0, generation);
+    let self_consumption = generation - surplus;
+    let ratio = if generation > 0.0 {
+        self_consumption / generation
+    } else {
+        f64::NAN
+    };
+    [surplus, self_consumption, ratio]
+}
+
+impl common::Component for PvStatsComponent {
+    fn get_names(&self) -> Vec<String> {
+        METRICS
+            .iter()
+            .map(|metric| format!("{}_{}", self.name, metric))
+            .collect()
+    }
+
+    fn update(&mut self, names: &[String], values: &[f64]) -> Vec<f64> {
+        let lookup = |column: &str| match names.iter().position(|n| n == column) {
+            Some(i) => values[i],
+            None => f64::NAN,
+        };
+        let generation = lookup(&self.generation_column);
+        let surplus = match &self.surplus {
+            Surplus::FeedIn(column) => lookup(column),
+            Surplus::Consumption(column) => generation - lookup(column),
+        };
+        compute(generation, surplus).to_vec()
+    }
+
+    fn get_inputs(&self) -> Option<Vec<String>> {
+        let other = match &self.surplus {
+            Surplus::FeedIn(column) | Surplus::Consumption(column) => column.clone(),
+        };
+        Some(vec![self.generation_column.clone(), other])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::Component;
+
+    fn names() -> Vec<String> {
+        vec![
+            "timestamp".to_string(),
+            "pv_power".to_string(),
+            "grid_feedin".to_string(),
+            "house_power".to_string(),
+        ]
+    }
+
+    fn feedin() -> PvStatsComponent {
+        PvStatsComponent::new(
+            "pv".to_string(),
+            "pv_power".to_string(),
+            Surplus::FeedIn("grid_feedin".to_string()),
+        )
+    }
+
+    // Tests for success.
+
+    #[test]
+    fn test_update_for_success() {
+        let mut component = feedin();
+        assert_eq!(
+            component.get_names(),
+            vec![
+                "pv_surplus_w",
+                "pv_self_consumption_w",
+                "pv_self_consumption_ratio"
+            ]
+        );
+        // 4 kW generated, 1 kW of it exported.
+        assert_eq!(
+            component.update(&names(), &[1000.0, 4000.0, 1000.0, 3000.0]),
+            vec![1000.0, 3000.0, 0.75]
+        );
+    }
+
+    // Tests for failure.
+
+    #[test]
+    fn test_update_for_failure() {
+        let mut component = feedin();
+        let res = component.update(&names(), &[1000.0, 4000.0, f64::NAN, 3000.0]);
+        assert!(res.iter().all(|v| v.is_nan()));
+        let res = component.update(&names()[..2], &[1000.0, 4000.0]);
+        assert!(res.iter().all(|v| v.is_nan()));
+        // night.
+        let res = component.update(&names(), &[1000.0, 0.0, -500.0, 500.0]);
+        assert_eq!(res[..2], [0.0, 0.0]);
+        assert!(res[2].is_nan());
+    }
+
+    // Tests for sanity.
+
+    #[test]
+    fn test_compute_for_sanity() {
+        // importing 500 W: all of the 2 kW is consumed.
+        assert_eq!(compute(2000.0, -500.0), [0.0, 2000.0, 1.0]);
+        // all exported.
+        assert_eq!(compute(2000.0, 2000.0), [2000.0, 0.0, 0.0]);
+        // more exported than generated, e.g. by a battery.
+        assert_eq!(compute(2000.0, 2500.0), [2000.0, 0.0, 0.0]);
+        assert_eq!(compute(1600.0, 400.0), [400.0, 1200.0, 0.75]);
+    }
+
+    #[test]
+    fn test_consumption_for_sanity() {
+        let mut component = PvStatsComponent::new(
+            "pv".to_string(),
+            "pv_power".to_string(),
+            Surplus::Consumption("house_power".to_string()),
+        );
+        assert_eq!(
+            component.update(&names(), &[1000.0, 5000.0, f64::NAN, 1250.0]),
+            vec![3750.0, 1250.0, 0.25]
+        );
+        assert_eq!(
+            component.update(&names(), &[1000.0, 1000.0, f64::NAN, 1250.0]),
+            vec![0.0, 1000.0, 1.0]
+        );
+        assert_eq!(
+            component.get_inputs(),
+            Some(vec!["pv_power".to_string(), "house_power".to_string()])
+        );
+    }
+}
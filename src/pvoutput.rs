@@ -0,0 +1,290 @@
+use std::time;
+
+use crate::output;
+use crate::tz;
+
+// PVOutput's status interval.
+const SLOT_SECS: f64 = 300.0;
+
+/// The status fields values can be mapped to.
+pub(crate) const FIELDS: [&str; 4] = ["v1", "v2", "v5", "v6"];
+
+/// Where and as which system to publish.
+pub(crate) struct System {
+    pub(crate) url: String,
+    pub(crate) api_key: String,
+    pub(crate) system_id: String,
+    // the column of each status field; in the order of FIELDS.
+    pub(crate) columns: [Option<String>; 4],
+    // v1 is the lifetime energy instead of today's.
+    pub(crate) cumulative: bool,
+    pub(crate) zone: tz::Zone,
+}
+
+/// Publishes a status per 5 minutes to pvoutput.org, regardless of the loop's interval: v1 (energy generation, Wh) as
+/// its last value of the interval, v2 (power, W), v5 (temperature, °C) and v6 (voltage, V) as their means.
+///
+/// Intervals w/o any mapped value are skipped. Once the hourly request limit is used up, intervals are skipped until
+/// PVOutput says it resets; failed uploads are not retried.
+pub(crate) struct PvOutput {
+    system: System,
+    // the position of each field's column in the rows.
+    indices: [Option<usize>; 4],
+    slot: Option<i64>,
+    sums: [f64; 4],
+    counts: [usize; 4],
+    last: [f64; 4],
+    // epoch secs until which the request limit is used up.
+    limited_until: Option<f64>,
+    client: reqwest::blocking::Client,
+}
+
+impl PvOutput {
+    pub(crate) fn new(system: System) -> PvOutput {
+        let client = reqwest::blocking::ClientBuilder::new()
+            .timeout(time::Duration::from_secs(10))
+            .build()
+            .unwrap();
+        PvOutput {
+            system,
+            indices: [None; 4],
+            slot: None,
+            sums: [0.0; 4],
+            counts: [0; 4],
+            last: [f64::NAN; 4],
+            limited_until: None,
+            client,
+        }
+    }
+
+    /// The form of the status of the current interval, stamped with its end; None if no field has a value.
+    fn form(&self) -> Option<Vec<(String, String)>> {
+        let slot = self.slot?;
+        if self.counts.iter().all(|c| *c == 0) {
+            return None;
+        }
+        let end = self.system.zone.local((slot + 1) as f64 * SLOT_SECS)?;
+        let mut form = vec![
+            ("d".to_string(), end.format("%Y%m%d").to_string()),
+            ("t".to_string(), end.format("%H:%M").to_string()),
+        ];
+        for (i, field) in FIELDS.iter().enumerate() {
+            if self.counts[i] == 0 {
+                continue;
+            }
+            let value = if i == 0 {
+                self.last[i]
+            } else {
+                self.sums[i] / self.counts[i] as f64
+            };
+            form.push((field.to_string(), format!("{:.1}", value)));
+        }
+        if self.system.cumulative && self.counts[0] > 0 {
+            form.push(("c1".to_string(), "1".to_string()));
+        }
+        Some(form)
+    }
+
+    /// Publishes the status of the current interval, if there is one and the request limit allows.
+    fn publish(&mut self, now: f64) -> Result<(), String> {
+        let form = match self.form() {
+            Some(form) => form,
+            None => {
+                if self.slot.is_some() {
+                    eprintln!("No values for PVOutput in the last interval; skipping it.");
+                }
+                return Ok(());
+            }
+        };
+        if let Some(until) = self.limited_until {
+            if now < until {
+                return Err(format!(
+                    "PVOutput's request limit is used up for another {}s; skipping the interval",
+                    (until - now).ceil()
+                ));
+            }
+            self.limited_until = None;
+        }
+        let res = self
+            .client
+            .post(&self.system.url)
+            .header("X-Pvoutput-Apikey", &self.system.api_key)
+            .header("X-Pvoutput-SystemId", &self.system.system_id)
+            .header("X-Rate-Limit", "1")
+            .form(&form)
+            .send()
+            .map_err(|e| format!("could not publish to PVOutput: {}", e))?;
+        let header = |name: &str| {
+            res.headers()
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.parse::<f64>().ok())
+        };
+        let reset = header("X-Rate-Limit-Reset").unwrap_or(now + 3600.0);
+        let status = res.status().as_u16();
+        if header("X-Rate-Limit-Remaining") == Some(0.0) || status == 403 {
+            self.limited_until = Some(reset);
+        }
+        if !(200..300).contains(&status) {
+            let body = res.text().unwrap_or_default();
+            return Err(format!(
+                "PVOutput rejected the status; status code: {}: {}",
+                status,
+                body.trim()
+            ));
+        }
+        Ok(())
+    }
+
+    fn reset(&mut self, slot: i64) {
+        self.slot = Some(slot);
+        self.sums = [0.0; 4];
+        self.counts = [0; 4];
+        self.last = [f64::NAN; 4];
+    }
+}
+
+impl output::Output for PvOutput {
+    fn write_header(&mut self, names: &[String]) {
+        for (index, column) in self.indices.iter_mut().zip(&self.system.columns) {
+            *index = None;
+            if let Some(column) = column {
+                *index = names.iter().position(|n| n == column);
+                if index.is_none() {
+                    eprintln!(
+                        "The column {} mapped for PVOutput is not written; leaving its field out.",
+                        column
+                    );
+                }
+            }
+        }
+    }
+
+    fn write(&mut self, values: &[f64]) -> Result<(), String> {
+        let slot = (values[0] / SLOT_SECS).floor() as i64;
+        let mut res = Ok(());
+        if self.slot != Some(slot) {
+            res = self.publish(values[0]);
+            self.reset(slot);
+        }
+        for (i, index) in self.indices.iter().enumerate() {
+            let value = index.map(|j| values[j]).unwrap_or(f64::NAN);
+            if value.is_finite() {
+                self.sums[i] += value;
+                self.counts[i] += 1;
+                self.last[i] = value;
+            }
+        }
+        res
+    }
+
+    fn flush(&mut self) {
+        let now = self.slot.map(|s| (s + 1) as f64 * SLOT_SECS).unwrap_or(0.0);
+        if let Err(err) = self.publish(now) {
+            eprintln!("Could not publish the last interval: {}", err);
+        }
+        self.slot = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output::Output;
+
+    fn pvoutput(url: String) -> PvOutput {
+        let mut output = PvOutput::new(System {
+            url,
+            api_key: "key".to_string(),
+            system_id: "1234".to_string(),
+            columns: [
+                Some("fox_generation".to_string()),
+                Some("fox_pvPower".to_string()),
+                Some("weather_temp".to_string()),
+                None,
+            ],
+            cumulative: false,
+            zone: tz::Zone::Utc,
+        });
+        output.write_header(&[
+            "timestamp".to_string(),
+            "fox_pvPower".to_string(),
+            "fox_generation".to_string(),
+            "weather_temp".to_string(),
+        ]);
+        output
+    }
+
+    // Tests for success.
+
+    #[test]
+    fn test_write_for_success() {
+        let mut server = mockito::Server::new();
+        let mock = server
+            .mock("POST", "/service/r2/addstatus.jsp")
+            .match_header("X-Pvoutput-Apikey", "key")
+            .match_header("X-Pvoutput-SystemId", "1234")
+            .match_header("Content-Type", "application/x-www-form-urlencoded")
+            .match_body("d=20231114&t=00%3A05&v1=1250.0&v2=1500.0&v5=7.5")
+            .with_status(200)
+            .expect(1)
+            .create();
+        let mut output = pvoutput(format!("{}/service/r2/addstatus.jsp", server.url()));
+        // 2023-11-14 00:00:00 UTC; a row every 30 seconds.
+        for (t, power, energy, temp) in [
+            (1699920000.0, 1000.0, 1200.0, 7.0),
+            (1699920030.0, 2000.0, 1250.0, f64::NAN),
+            (1699920270.0, f64::NAN, f64::NAN, 8.0),
+        ] {
+            output.write(&[t, power, energy, temp]).unwrap();
+        }
+        // not before the interval is over.
+        assert!(!mock.matched());
+        output.write(&[1699920300.0, 1.0, 1.0, 1.0]).unwrap();
+        mock.assert();
+    }
+
+    // Tests for failure.
+
+    #[test]
+    fn test_rate_limit_for_failure() {
+        let mut server = mockito::Server::new();
+        let limited = server
+            .mock("POST", "/service/r2/addstatus.jsp")
+            .with_status(403)
+            .with_header("X-Rate-Limit-Remaining", "0")
+            .with_header("X-Rate-Limit-Reset", "1699923600")
+            .with_body("Forbidden 403: Exceeded 60 requests per hour")
+            .expect(1)
+            .create();
+        let mut output = pvoutput(format!("{}/service/r2/addstatus.jsp", server.url()));
+        output.write(&[1699920000.0, 1000.0, 1200.0, 7.0]).unwrap();
+        let err = output
+            .write(&[1699920300.0, 1000.0, 1200.0, 7.0])
+            .unwrap_err();
+        assert!(err.contains("Exceeded 60 requests per hour"));
+        // not even trying until the limit resets.
+        let err = output
+            .write(&[1699920600.0, 1000.0, 1200.0, 7.0])
+            .unwrap_err();
+        assert!(err.contains("used up"));
+        limited.assert();
+        assert_eq!(output.limited_until, Some(1699923600.0));
+    }
+
+    // Tests for sanity.
+
+    #[test]
+    fn test_missing_for_sanity() {
+        // nothing to publish; nothing is sent (the URL would not even connect).
+        let mut output = pvoutput("http://localhost:1/service/r2/addstatus.jsp".to_string());
+        output
+            .write(&[1699920000.0, f64::NAN, f64::NAN, f64::NAN])
+            .unwrap();
+        output
+            .write(&[1699920300.0, f64::NAN, f64::NAN, f64::NAN])
+            .unwrap();
+        output.flush();
+        assert_eq!(output.form(), None);
+    }
+}
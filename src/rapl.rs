@@ -0,0 +1,354 @@
+use std::cell::RefCell;
+use std::fs;
+use std::io;
+use std::path;
+use std::time;
+
+use crate::clock;
+use crate::common;
+use crate::common::{ConfigError, Reading, SensorError};
+
+/// Where the kernel exposes the RAPL zones.
+const POWERCAP_DIR: &str = "/sys/class/powercap";
+
+/// A RAPL domain, e.g. a package or its DRAM.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Domain {
+    /// The name of its column, e.g. package_0 or package_0_dram.
+    pub(crate) name: String,
+    dir: path::PathBuf,
+    /// The energy counter wraps around to 0 at this value.
+    max_energy: u64,
+}
+
+impl Domain {
+    fn energy_path(&self) -> path::PathBuf {
+        self.dir.join("energy_uj")
+    }
+
+    /// The energy counter in µJ.
+    fn energy(&self) -> Result<u64, SensorError> {
+        let path = self.energy_path();
+        let content = fs::read_to_string(&path).map_err(|e| match e.kind() {
+            io::ErrorKind::PermissionDenied => SensorError::Io(format!(
+                "cannot read {}: permission denied; it is only readable by root on most systems - run as root or \
+                 grant read access, e.g. with a udev rule.",
+                path.display()
+            )),
+            _ => SensorError::Io(format!("cannot read {}: {}", path.display(), e)),
+        })?;
+        content.trim().parse().map_err(|e| {
+            SensorError::Parse(format!("{} of {}: {}", content.trim(), path.display(), e))
+        })
+    }
+}
+
+/// Finds the RAPL domains in the given powercap directory, ordered by zone.
+///
+/// Subzones are named after their package, e.g. the DRAM of intel-rapl:0 becomes package_0_dram.
+pub(crate) fn discover(root: &str) -> Vec<Domain> {
+    let entries = match fs::read_dir(root) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+    let mut zones: Vec<(Vec<u32>, path::PathBuf)> = entries
+        .filter_map(|e| e.ok())
+        .filter_map(|e| {
+            let file_name = e.file_name().to_string_lossy().to_string();
+            let ids = file_name
+                .strip_prefix("intel-rapl:")?
+                .split(':')
+                .map(|id| id.parse().ok())
+                .collect::<Option<Vec<u32>>>()?;
+            Some((ids, e.path()))
+        })
+        .collect();
+    zones.sort();
+    let mut res: Vec<Domain> = Vec::new();
+    for (ids, dir) in &zones {
+        let name = match common::read_sysfs(dir, "name") {
+            Some(name) => name.replace('-', "_"),
+            None => continue,
+        };
+        let name = match zones.iter().find(|(parent, _)| parent[..] == ids[..1]) {
+            Some((_, parent)) if ids.len() > 1 => match common::read_sysfs(parent, "name") {
+                Some(parent) => format!("{}_{}", parent.replace('-', "_"), name),
+                None => name,
+            },
+            _ => name,
+        };
+        let max_energy = common::read_sysfs(dir, "max_energy_range_uj")
+            .and_then(|m| m.parse().ok())
+            .unwrap_or(u64::MAX);
+        res.push(Domain {
+            name,
+            dir: dir.clone(),
+            max_energy,
+        });
+    }
+    res
+}
+
+/// Reports the power drawn by the RAPL domains of the host, e.g. its CPU packages and DRAM, in W.
+///
+/// The power is computed from the energy counters since the previous measurement, so the first one reports nothing.
+pub struct RaplSensor {
+    name: String,
+    domains: Vec<Domain>,
+    // the counter of each domain and when it was read.
+    last: RefCell<Vec<Option<(f64, u64)>>>,
+}
+
+impl RaplSensor {
+    pub(crate) fn new(name: String, domains: Vec<Domain>) -> RaplSensor {
+        RaplSensor {
+            name,
+            last: RefCell::new(vec![None; domains.len()]),
+            domains,
+        }
+    }
+
+    fn measure_at(&self, now: f64) -> Result<Vec<Reading>, SensorError> {
+        let names = common::Sensor::get_names(self);
+        let mut last = self.last.borrow_mut();
+        let mut res = Vec::new();
+        let mut error = None;
+        let mut read = 0;
+        for (i, domain) in self.domains.iter().enumerate() {
+            let energy = match domain.energy() {
+                Ok(energy) => energy,
+                Err(err) => {
+                    error = Some(err);
+                    continue;
+                }
+            };
+            read += 1;
+            if let Some((t, previous)) = last[i].replace((now, energy)) {
+                if now <= t {
+                    continue;
+                }
+                let delta = if energy >= previous {
+                    energy - previous
+                } else {
+                    // wrapped around.
+                    domain.max_energy - previous + energy
+                };
+                res.push(Reading::new(
+                    names[i].clone(),
+                    delta as f64 / 1e6 / (now - t),
+                ));
+            }
+        }
+        match error {
+            Some(err) if read == 0 => Err(err),
+            Some(err) => {
+                eprintln!("Could not read all RAPL domains of {}: {}", self.name, err);
+                Ok(res)
+            }
+            None => Ok(res),
+        }
+    }
+}
+
+impl common::Sensor for RaplSensor {
+    fn get_names(&self) -> Vec<String> {
+        self.domains
+            .iter()
+            .map(|d| format!("{}_{}_power", self.name, d.name))
+            .collect()
+    }
+
+    fn get_units(&self) -> Vec<String> {
+        vec!["W".to_string(); self.domains.len()]
+    }
+
+    fn measure(&self) -> Result<Vec<Reading>, SensorError> {
+        self.measure_at(clock::epoch_secs(time::SystemTime::now()))
+    }
+
+    fn init(&mut self) -> Result<(), SensorError> {
+        // energy_uj is often root-only; better to know right away.
+        for domain in &self.domains {
+            domain.energy()?;
+        }
+        Ok(())
+    }
+}
+
+/// The power of the host's RAPL domains.
+pub(crate) fn sensor_type() -> common::SensorType {
+    common::SensorType {
+        name: "rapl",
+        required: &[],
+        optional: &["path", "domains"],
+        create: |name, sensor_cfg, _| {
+            let root = match sensor_cfg.get("path") {
+                Some(v) => v
+                    .as_str()
+                    .ok_or_else(|| ConfigError::Invalid("path must be a string.".to_string()))?,
+                None => POWERCAP_DIR,
+            };
+            let mut domains = discover(root);
+            if domains.is_empty() {
+                return Err(ConfigError::Invalid(format!(
+                    "no RAPL domains found in {}.",
+                    root
+                )));
+            }
+            if let Some(v) = sensor_cfg.get("domains") {
+                let wanted = v
+                    .as_array()
+                    .and_then(|a| a.iter().map(|d| d.as_str()).collect::<Option<Vec<_>>>())
+                    .ok_or_else(|| {
+                        ConfigError::Invalid("domains must be an array of strings.".to_string())
+                    })?;
+                let available: Vec<String> = domains.iter().map(|d| d.name.clone()).collect();
+                if let Some(unknown) = wanted.iter().find(|w| !available.iter().any(|a| a == *w)) {
+                    return Err(ConfigError::Invalid(format!(
+                        "no RAPL domain {}; available are: {}.",
+                        unknown,
+                        available.join(", ")
+                    )));
+                }
+                domains.retain(|d| wanted.contains(&d.name.as_str()));
+            }
+            Ok(Box::new(RaplSensor::new(name.to_string(), domains)))
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::values;
+    use crate::common::Sensor;
+
+    /// A powercap directory with a package (and its DRAM) and psys.
+    fn powercap(root: &str) {
+        let _ = fs::remove_dir_all(root);
+        for (zone, name, energy) in [
+            ("intel-rapl:0", "package-0", 1000000),
+            ("intel-rapl:0:1", "dram", 500000),
+            ("intel-rapl:1", "psys", 2000000),
+        ] {
+            let dir = path::Path::new(root).join(zone);
+            fs::create_dir_all(&dir).unwrap();
+            fs::write(dir.join("name"), format!("{}\n", name)).unwrap();
+            fs::write(dir.join("max_energy_range_uj"), "262143328850\n").unwrap();
+            fs::write(dir.join("energy_uj"), format!("{}\n", energy)).unwrap();
+        }
+        // the control type; not a zone.
+        fs::create_dir_all(path::Path::new(root).join("intel-rapl")).unwrap();
+    }
+
+    fn set_energy(root: &str, zone: &str, energy: u64) {
+        let file = path::Path::new(root).join(zone).join("energy_uj");
+        fs::write(file, format!("{}\n", energy)).unwrap();
+    }
+
+    // Tests for success.
+
+    #[test]
+    fn test_measure_for_success() {
+        let root = "rapl_test0";
+        powercap(root);
+        let mut sensor = RaplSensor::new("host".to_string(), discover(root));
+        assert_eq!(
+            sensor.get_names(),
+            vec![
+                "host_package_0_power",
+                "host_package_0_dram_power",
+                "host_psys_power"
+            ]
+        );
+        sensor.init().unwrap();
+        // nothing to compare to yet.
+        assert!(values(sensor.measure_at(1000.0)).is_empty());
+        set_energy(root, "intel-rapl:0", 21000000);
+        set_energy(root, "intel-rapl:0:1", 2500000);
+        set_energy(root, "intel-rapl:1", 32000000);
+        assert_eq!(
+            values(sensor.measure_at(1002.0)),
+            vec![
+                ("host_package_0_power".to_string(), 10.0),
+                ("host_package_0_dram_power".to_string(), 1.0),
+                ("host_psys_power".to_string(), 15.0)
+            ]
+        );
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_wrap_for_success() {
+        let root = "rapl_test1";
+        powercap(root);
+        set_energy(root, "intel-rapl:0", 262143328850 - 4000000);
+        let sensor = RaplSensor::new("host".to_string(), discover(root));
+        sensor.measure_at(1000.0).unwrap();
+        set_energy(root, "intel-rapl:0", 6000000);
+        assert_eq!(values(sensor.measure_at(1001.0))[0].1, 10.0);
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    // Tests for failure.
+
+    #[test]
+    fn test_init_for_failure() {
+        let root = "rapl_test2";
+        powercap(root);
+        // unreadable.
+        let file = path::Path::new(root).join("intel-rapl:1").join("energy_uj");
+        fs::remove_file(&file).unwrap();
+        fs::create_dir(&file).unwrap();
+        let mut sensor = RaplSensor::new("host".to_string(), discover(root));
+        match sensor.init() {
+            Err(SensorError::Io(msg)) => {
+                assert!(msg.starts_with("cannot read rapl_test2/intel-rapl:1/energy_uj"))
+            }
+            _ => panic!("unreadable counter not reported."),
+        }
+        // the other domains are still reported.
+        sensor.measure_at(1000.0).unwrap();
+        assert_eq!(values(sensor.measure_at(1001.0)).len(), 2);
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_sensor_type_for_failure() {
+        let sensor_cfg: toml::value::Table = toml::from_str("path='rapl_missing'").unwrap();
+        assert_eq!(
+            (sensor_type().create)("host", &sensor_cfg, "state").err(),
+            Some(ConfigError::Invalid(
+                "no RAPL domains found in rapl_missing.".to_string()
+            ))
+        );
+        let root = "rapl_test3";
+        powercap(root);
+        let sensor_cfg: toml::value::Table =
+            toml::from_str("path='rapl_test3'\ndomains=['core']").unwrap();
+        assert_eq!(
+            (sensor_type().create)("host", &sensor_cfg, "state").err(),
+            Some(ConfigError::Invalid(
+                "no RAPL domain core; available are: package_0, package_0_dram, psys.".to_string()
+            ))
+        );
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    // Tests for sanity.
+
+    #[test]
+    fn test_sensor_type_for_sanity() {
+        let root = "rapl_test4";
+        powercap(root);
+        let sensor_cfg: toml::value::Table =
+            toml::from_str("path='rapl_test4'\ndomains=['psys', 'package_0']").unwrap();
+        let sensor = (sensor_type().create)("host", &sensor_cfg, "state").unwrap();
+        assert_eq!(
+            sensor.get_names(),
+            vec!["host_package_0_power", "host_psys_power"]
+        );
+        assert_eq!(sensor.get_units(), vec!["W", "W"]);
+        fs::remove_dir_all(root).unwrap();
+    }
+}
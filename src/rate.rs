@@ -0,0 +1,274 @@
+use std::cell::RefCell;
+use std::error::Error;
+use std::time;
+
+use crate::clock;
+use crate::common;
+use crate::common::{Reading, SensorError};
+
+/// What is reported instead of the value of a counter.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Derive {
+    /// The change per second since the previous sample.
+    Rate,
+    /// The change since the previous sample.
+    Delta,
+}
+
+impl Derive {
+    pub(crate) fn parse(name: &str) -> Derive {
+        match name {
+            "rate" => Derive::Rate,
+            "delta" => Derive::Delta,
+            other => panic!("derive must be one of 'rate' or 'delta'; got: {}.", other),
+        }
+    }
+
+    fn suffix(&self) -> &'static str {
+        match self {
+            Derive::Rate => "rate",
+            Derive::Delta => "delta",
+        }
+    }
+}
+
+/// Turns the monotonically increasing counters of the sensor it wraps into rates or deltas, e.g. Wh into Wh/s.
+///
+/// A derived column is renamed to <column>_rate or <column>_delta. It is missing for the first sample, when the counter
+/// was reset (it went down), and when the sample is not newer than the previous one (e.g. a held value).
+pub struct CounterSensor {
+    inner: Box<dyn common::Sensor>,
+    derives: Vec<Option<Derive>>,
+    // the previous sample of each counter and when it was measured.
+    last: RefCell<Vec<Option<(f64, f64)>>>,
+}
+
+impl CounterSensor {
+    pub(crate) fn new(
+        inner: Box<dyn common::Sensor>,
+        derives: Vec<Option<Derive>>,
+    ) -> CounterSensor {
+        CounterSensor {
+            last: RefCell::new(vec![None; derives.len()]),
+            inner,
+            derives,
+        }
+    }
+}
+
+impl common::Sensor for CounterSensor {
+    fn get_names(&self) -> Vec<String> {
+        self.inner
+            .get_names()
+            .into_iter()
+            .zip(&self.derives)
+            .map(|(name, derive)| match derive {
+                Some(derive) => format!("{}_{}", name, derive.suffix()),
+                None => name,
+            })
+            .collect()
+    }
+
+    fn get_units(&self) -> Vec<String> {
+        self.inner
+            .get_units()
+            .into_iter()
+            .zip(&self.derives)
+            .map(|(unit, derive)| match derive {
+                Some(Derive::Rate) => format!("{}/s", unit),
+                _ => unit,
+            })
+            .collect()
+    }
+
+    fn measure(&self) -> Result<Vec<Reading>, SensorError> {
+        self.measure_at(clock::epoch_secs(time::SystemTime::now()))
+    }
+
+    fn measure_at(&self, now: f64) -> Result<Vec<Reading>, SensorError> {
+        let names = self.inner.get_names();
+        let derived = self.get_names();
+        let mut last = self.last.borrow_mut();
+        let mut res = Vec::new();
+        for reading in self.inner.measure_at(now)? {
+            let i = match names.iter().position(|n| n == &reading.name) {
+                Some(i) => i,
+                None => {
+                    res.push(reading);
+                    continue;
+                }
+            };
+            let derive = match self.derives[i] {
+                Some(derive) => derive,
+                None => {
+                    res.push(reading);
+                    continue;
+                }
+            };
+            if reading.value.is_nan() {
+                continue;
+            }
+            let time = reading.time.unwrap_or(now);
+            // a held sample is not a new one.
+            if matches!(last[i], Some((t, _)) if time <= t) {
+                continue;
+            }
+            let value = match last[i].replace((time, reading.value)) {
+                Some((t, v)) if reading.value >= v => match derive {
+                    Derive::Rate => (reading.value - v) / (time - t),
+                    Derive::Delta => reading.value - v,
+                },
+                // the first sample, or the counter was reset.
+                _ => continue,
+            };
+            res.push(Reading {
+                name: derived[i].clone(),
+                value,
+                time: reading.time,
+                quality: reading.quality,
+            });
+        }
+        Ok(res)
+    }
+
+    fn init(&mut self) -> Result<(), SensorError> {
+        self.inner.init()
+    }
+
+    fn shutdown(&mut self) {
+        self.inner.shutdown()
+    }
+
+    fn validate(&self) -> Result<(), Box<dyn Error>> {
+        self.inner.validate()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::common::values;
+    use crate::common::Sensor;
+
+    /// A Fritz!Box plug whose energy counter and power are set by the test.
+    struct PlugSensor {
+        sample: Rc<Cell<(f64, f64)>>,
+    }
+
+    impl common::Sensor for PlugSensor {
+        fn get_names(&self) -> Vec<String> {
+            vec!["plug_energy".to_string(), "plug_power".to_string()]
+        }
+
+        fn get_units(&self) -> Vec<String> {
+            vec!["Wh".to_string(), "W".to_string()]
+        }
+
+        fn measure(&self) -> Result<Vec<Reading>, SensorError> {
+            let (energy, power) = self.sample.get();
+            Ok(common::readings(self.get_names(), vec![energy, power]))
+        }
+    }
+
+    fn plug(derive: Derive) -> (CounterSensor, Rc<Cell<(f64, f64)>>) {
+        let sample = Rc::new(Cell::new((0.0, 0.0)));
+        let sensor = PlugSensor {
+            sample: sample.clone(),
+        };
+        (
+            CounterSensor::new(Box::new(sensor), vec![Some(derive), None]),
+            sample,
+        )
+    }
+
+    // Tests for success.
+
+    #[test]
+    fn test_rate_for_success() {
+        let (sensor, sample) = plug(Derive::Rate);
+        sample.set((1200.0, 40.0));
+        // nothing to compare the first sample to.
+        assert_eq!(
+            values(sensor.measure_at(1000.0)),
+            vec![("plug_power".to_string(), 40.0)]
+        );
+        sample.set((1203.0, 45.0));
+        assert_eq!(
+            values(sensor.measure_at(1030.0)),
+            vec![
+                ("plug_energy_rate".to_string(), 0.1),
+                ("plug_power".to_string(), 45.0)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_delta_for_success() {
+        let (sensor, sample) = plug(Derive::Delta);
+        sample.set((1200.0, 40.0));
+        sensor.measure_at(1000.0).unwrap();
+        sample.set((1203.0, 45.0));
+        assert_eq!(
+            values(sensor.measure_at(1030.0))[0],
+            ("plug_energy_delta".to_string(), 3.0)
+        );
+        sample.set((1203.0, 0.0));
+        assert_eq!(
+            values(sensor.measure_at(1060.0))[0],
+            ("plug_energy_delta".to_string(), 0.0)
+        );
+    }
+
+    // Tests for failure.
+
+    #[test]
+    #[should_panic(expected = "derive must be one of 'rate' or 'delta'; got: sum.")]
+    fn test_parse_for_failure() {
+        Derive::parse("sum");
+    }
+
+    #[test]
+    fn test_reset_for_failure() {
+        let (sensor, sample) = plug(Derive::Rate);
+        sample.set((1200.0, 40.0));
+        sensor.measure_at(1000.0).unwrap();
+        // the plug was reset.
+        sample.set((3.0, 40.0));
+        assert_eq!(
+            values(sensor.measure_at(1030.0)),
+            vec![("plug_power".to_string(), 40.0)]
+        );
+        // counting on from there.
+        sample.set((6.0, 40.0));
+        assert_eq!(
+            values(sensor.measure_at(1060.0))[0],
+            ("plug_energy_rate".to_string(), 0.1)
+        );
+    }
+
+    #[test]
+    fn test_held_for_failure() {
+        let (sensor, sample) = plug(Derive::Rate);
+        sample.set((1200.0, 40.0));
+        sensor.measure_at(1000.0).unwrap();
+        // the same instant again.
+        assert_eq!(values(sensor.measure_at(1000.0)).len(), 1);
+        sample.set((1203.0, 40.0));
+        assert_eq!(values(sensor.measure_at(1030.0))[0].1, 0.1);
+    }
+
+    // Tests for sanity.
+
+    #[test]
+    fn test_names_for_sanity() {
+        let (sensor, _) = plug(Derive::Rate);
+        assert_eq!(sensor.get_names(), vec!["plug_energy_rate", "plug_power"]);
+        assert_eq!(sensor.get_units(), vec!["Wh/s", "W"]);
+        let (sensor, _) = plug(Derive::Delta);
+        assert_eq!(sensor.get_names(), vec!["plug_energy_delta", "plug_power"]);
+        assert_eq!(sensor.get_units(), vec!["Wh", "W"]);
+    }
+}
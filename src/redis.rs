@@ -0,0 +1,458 @@
+use std::collections::HashSet;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time;
+
+use crate::output;
+
+const MAX_BACKOFF: time::Duration = time::Duration::from_secs(60);
+
+/// How values are stored.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Mode {
+    /// A RedisTimeSeries series per column, getting a sample per row.
+    TimeSeries,
+    /// A plain key per column holding the latest value; for servers w/o the RedisTimeSeries module.
+    Latest,
+}
+
+impl Mode {
+    pub(crate) fn parse(mode: &str) -> Mode {
+        match mode {
+            "timeseries" => Mode::TimeSeries,
+            "latest" => Mode::Latest,
+            other => panic!(
+                "mode must be either 'timeseries' or 'latest'; got: {}.",
+                other
+            ),
+        }
+    }
+}
+
+/// Where and how to store the values.
+pub(crate) struct Target {
+    // host:port.
+    pub(crate) address: String,
+    pub(crate) password: Option<String>,
+    pub(crate) db: Option<i64>,
+    pub(crate) mode: Mode,
+    // passed to TS.CREATE; 0 keeps samples forever.
+    pub(crate) retention_ms: u64,
+    // how long the latest values live w/o being updated.
+    pub(crate) expire_secs: u64,
+}
+
+/// Stores each value under the key ogc:<sensor>:<metric> in Redis; as a sample of a RedisTimeSeries series or as the
+/// latest value with an expiry.
+///
+/// Series are created with the configured retention the first time they are written to. A lost connection is
+/// re-established with the next rows, at most once per backoff (up to a minute), so a Redis that is down never blocks
+/// the loop for long; rows in between are skipped - only live values matter here.
+pub(crate) struct RedisOutput {
+    target: Target,
+    sensors: Vec<String>,
+    // the key of each column.
+    keys: Vec<String>,
+    // the labels of each column's series.
+    labels: Vec<(String, String)>,
+    // the series known to exist on the current connection.
+    created: HashSet<String>,
+    connection: Option<BufReader<TcpStream>>,
+    backoff: time::Duration,
+    retry_at: Option<time::Instant>,
+}
+
+impl RedisOutput {
+    pub(crate) fn new(target: Target, sensors: Vec<String>) -> RedisOutput {
+        RedisOutput {
+            target,
+            sensors,
+            keys: Vec::new(),
+            labels: Vec::new(),
+            created: HashSet::new(),
+            connection: None,
+            backoff: time::Duration::from_secs(1),
+            retry_at: None,
+        }
+    }
+
+    fn connect(&self) -> Result<BufReader<TcpStream>, String> {
+        let address = self
+            .target
+            .address
+            .to_socket_addrs()
+            .map_err(|e| format!("could not resolve {}: {}", self.target.address, e))?
+            .next()
+            .ok_or_else(|| format!("could not resolve {}", self.target.address))?;
+        let stream = TcpStream::connect_timeout(&address, time::Duration::from_secs(1))
+            .map_err(|e| format!("could not connect to {}: {}", self.target.address, e))?;
+        let timeout = Some(time::Duration::from_secs(2));
+        stream
+            .set_read_timeout(timeout)
+            .and_then(|_| stream.set_write_timeout(timeout))
+            .map_err(|e| e.to_string())?;
+        let mut connection = BufReader::new(stream);
+        let mut setup = Vec::new();
+        if let Some(password) = &self.target.password {
+            setup.push(vec!["AUTH".to_string(), password.clone()]);
+        }
+        if let Some(db) = self.target.db {
+            setup.push(vec!["SELECT".to_string(), db.to_string()]);
+        }
+        for reply in execute(&mut connection, &setup).map_err(|e| e.to_string())? {
+            reply.map_err(|e| format!("could not set up the connection: {}", e))?;
+        }
+        Ok(connection)
+    }
+
+    /// The commands storing a row; failed (NaN) values are left out.
+    fn commands(&self, values: &[f64]) -> Vec<Vec<String>> {
+        let mut res = Vec::new();
+        let ts = ((values[0] * 1000.0).round() as i64).to_string();
+        for (i, (key, value)) in self.keys.iter().zip(values).enumerate().skip(1) {
+            if !value.is_finite() {
+                continue;
+            }
+            match self.target.mode {
+                Mode::TimeSeries => {
+                    if !self.created.contains(key) {
+                        let (sensor, metric) = &self.labels[i];
+                        res.push(
+                            [
+                                "TS.CREATE",
+                                key,
+                                "RETENTION",
+                                &self.target.retention_ms.to_string(),
+                                "LABELS",
+                                "sensor",
+                                sensor,
+                                "metric",
+                                metric,
+                            ]
+                            .iter()
+                            .map(|s| s.to_string())
+                            .collect(),
+                        );
+                    }
+                    res.push(vec![
+                        "TS.ADD".to_string(),
+                        key.clone(),
+                        ts.clone(),
+                        value.to_string(),
+                    ]);
+                }
+                Mode::Latest => res.push(vec![
+                    "SET".to_string(),
+                    key.clone(),
+                    value.to_string(),
+                    "EX".to_string(),
+                    self.target.expire_secs.max(1).to_string(),
+                ]),
+            }
+        }
+        res
+    }
+}
+
+/// Sends the commands in one go and reads their replies; a reply is an error if Redis answered with one.
+fn execute(
+    connection: &mut BufReader<TcpStream>,
+    commands: &[Vec<String>],
+) -> io::Result<Vec<Result<String, String>>> {
+    let mut buf = Vec::new();
+    for command in commands {
+        buf.extend_from_slice(format!("*{}\r\n", command.len()).as_bytes());
+        for arg in command {
+            buf.extend_from_slice(format!("${}\r\n{}\r\n", arg.len(), arg).as_bytes());
+        }
+    }
+    connection.get_mut().write_all(&buf)?;
+    commands.iter().map(|_| reply(connection)).collect()
+}
+
+/// Reads a reply; arrays are flattened into a space separated string.
+fn reply(connection: &mut BufReader<TcpStream>) -> io::Result<Result<String, String>> {
+    let mut line = String::new();
+    if connection.read_line(&mut line)? == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "connection closed",
+        ));
+    }
+    let line = line.trim_end();
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, format!("bad reply: {}", line));
+    let (kind, rest) = (
+        line.get(..1).unwrap_or_default(),
+        line.get(1..).unwrap_or_default(),
+    );
+    match kind {
+        "+" | ":" => Ok(Ok(rest.to_string())),
+        "-" => Ok(Err(rest.to_string())),
+        "$" => {
+            let len: i64 = rest.parse().map_err(|_| invalid())?;
+            if len < 0 {
+                return Ok(Ok(String::new()));
+            }
+            let mut data = vec![0; len as usize + 2];
+            io::Read::read_exact(connection, &mut data)?;
+            data.truncate(len as usize);
+            Ok(Ok(String::from_utf8_lossy(&data).to_string()))
+        }
+        "*" => {
+            let len: i64 = rest.parse().map_err(|_| invalid())?;
+            // all items are read, even after an error, to stay in step with the replies.
+            let items = (0..len.max(0))
+                .map(|_| reply(connection))
+                .collect::<io::Result<Vec<_>>>()?;
+            Ok(items
+                .into_iter()
+                .collect::<Result<Vec<String>, String>>()
+                .map(|items| items.join(" ")))
+        }
+        _ => Err(invalid()),
+    }
+}
+
+impl output::Output for RedisOutput {
+    fn write_header(&mut self, names: &[String]) {
+        self.labels = names
+            .iter()
+            .map(|n| output::split(&self.sensors, n))
+            .collect();
+        self.keys = self
+            .labels
+            .iter()
+            .map(|(sensor, metric)| format!("ogc:{}:{}", sensor, metric))
+            .collect();
+        self.created.clear();
+    }
+
+    fn write(&mut self, values: &[f64]) -> Result<(), String> {
+        if self.connection.is_none() {
+            if let Some(retry_at) = self.retry_at {
+                if time::Instant::now() < retry_at {
+                    return Ok(());
+                }
+            }
+            match self.connect() {
+                Ok(connection) => {
+                    self.connection = Some(connection);
+                    self.created.clear();
+                    self.backoff = time::Duration::from_secs(1);
+                    self.retry_at = None;
+                }
+                Err(err) => {
+                    self.retry_at = Some(time::Instant::now() + self.backoff);
+                    let res = Err(format!("{}; retrying in {}s", err, self.backoff.as_secs()));
+                    self.backoff = (self.backoff * 2).min(MAX_BACKOFF);
+                    return res;
+                }
+            }
+        }
+        let commands = self.commands(values);
+        let replies = match execute(self.connection.as_mut().unwrap(), &commands) {
+            Ok(replies) => replies,
+            Err(err) => {
+                // the replies are out of step now; start over.
+                self.connection = None;
+                return Err(format!(
+                    "lost the connection to {}: {}",
+                    self.target.address, err
+                ));
+            }
+        };
+        let mut errors = Vec::new();
+        for (command, reply) in commands.iter().zip(replies) {
+            match reply {
+                Ok(_) if command[0] == "TS.CREATE" => {
+                    self.created.insert(command[1].clone());
+                }
+                Err(err) if command[0] == "TS.CREATE" && err.contains("already exists") => {
+                    self.created.insert(command[1].clone());
+                }
+                Err(err) if err.starts_with("ERR unknown command") => {
+                    errors.push(format!(
+                        "{} (is the RedisTimeSeries module loaded? if not, use mode='latest')",
+                        err
+                    ));
+                }
+                Err(err) => errors.push(format!("{} {}: {}", command[0], command[1], err)),
+                Ok(_) => {}
+            }
+        }
+        if errors.is_empty() {
+            return Ok(());
+        }
+        errors.dedup();
+        Err(errors.join("; "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+    use std::thread;
+
+    use super::*;
+    use crate::output::Output;
+
+    /// Serves a single connection, replying to each command as given and handing the commands on.
+    fn server(
+        respond: fn(&[String]) -> String,
+    ) -> (String, mpsc::Receiver<Vec<String>>, thread::JoinHandle<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let (tx, rx) = mpsc::channel();
+        let handle = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut writer = stream;
+            loop {
+                let mut line = String::new();
+                if reader.read_line(&mut line).unwrap_or(0) == 0 {
+                    return;
+                }
+                let argc: usize = line.trim()[1..].parse().unwrap();
+                let mut command = Vec::new();
+                for _ in 0..argc {
+                    let mut len = String::new();
+                    reader.read_line(&mut len).unwrap();
+                    let mut arg = String::new();
+                    reader.read_line(&mut arg).unwrap();
+                    command.push(arg.trim_end().to_string());
+                }
+                let response = respond(&command);
+                // handed on before replying; the output has sent it once it gets the reply.
+                if tx.send(command).is_err() {
+                    return;
+                }
+                writer.write_all(response.as_bytes()).unwrap();
+            }
+        });
+        (address, rx, handle)
+    }
+
+    fn redis(address: String, mode: Mode) -> RedisOutput {
+        let target = Target {
+            address,
+            password: Some("secret".to_string()),
+            db: None,
+            mode,
+            retention_ms: 86400000,
+            expire_secs: 300,
+        };
+        let mut output = RedisOutput::new(target, vec!["fox".to_string(), "fritz0".to_string()]);
+        output.write_header(&[
+            "timestamp".to_string(),
+            "fox_pvPower".to_string(),
+            "fritz0_power".to_string(),
+        ]);
+        output
+    }
+
+    fn command(args: &[&str]) -> Vec<String> {
+        args.iter().map(|a| a.to_string()).collect()
+    }
+
+    // Tests for success.
+
+    #[test]
+    fn test_timeseries_for_success() {
+        let (address, commands, _) = server(|command| match command[0].as_str() {
+            "TS.ADD" => ":1699920000500\r\n".to_string(),
+            _ => "+OK\r\n".to_string(),
+        });
+        let mut output = redis(address, Mode::TimeSeries);
+        output.write(&[1699920000.5, 1.5, f64::NAN]).unwrap();
+        output.write(&[1699920030.0, 2.0, f64::NAN]).unwrap();
+        let sent: Vec<Vec<String>> = commands.try_iter().collect();
+        assert_eq!(
+            sent,
+            vec![
+                command(&["AUTH", "secret"]),
+                command(&[
+                    "TS.CREATE",
+                    "ogc:fox:pvPower",
+                    "RETENTION",
+                    "86400000",
+                    "LABELS",
+                    "sensor",
+                    "fox",
+                    "metric",
+                    "pvPower"
+                ]),
+                command(&["TS.ADD", "ogc:fox:pvPower", "1699920000500", "1.5"]),
+                // created only once.
+                command(&["TS.ADD", "ogc:fox:pvPower", "1699920030000", "2"]),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_latest_for_success() {
+        let (address, commands, _) = server(|_| "+OK\r\n".to_string());
+        let mut output = redis(address, Mode::Latest);
+        output.write(&[1699920000.0, 1.5, -50.0]).unwrap();
+        let sent: Vec<Vec<String>> = commands.try_iter().collect();
+        assert_eq!(
+            sent[1..],
+            vec![
+                command(&["SET", "ogc:fox:pvPower", "1.5", "EX", "300"]),
+                command(&["SET", "ogc:fritz0:power", "-50", "EX", "300"]),
+            ]
+        );
+    }
+
+    // Tests for failure.
+
+    #[test]
+    fn test_connect_for_failure() {
+        let mut output = redis("127.0.0.1:1".to_string(), Mode::Latest);
+        assert!(output.write(&[1699920000.0, 1.5, 50.0]).is_err());
+        assert_eq!(output.backoff, time::Duration::from_secs(2));
+        // backing off; not even trying.
+        assert!(output.write(&[1699920030.0, 1.5, 50.0]).is_ok());
+        assert_eq!(output.backoff, time::Duration::from_secs(2));
+    }
+
+    #[test]
+    fn test_unknown_command_for_failure() {
+        let (address, _commands, _) = server(|command| match command[0].as_str() {
+            "AUTH" => "+OK\r\n".to_string(),
+            other => format!("-ERR unknown command '{}'\r\n", other),
+        });
+        let mut output = redis(address, Mode::TimeSeries);
+        let err = output.write(&[1699920000.0, 1.5, f64::NAN]).unwrap_err();
+        assert!(err.contains("mode='latest'"));
+        // still connected; only the module is missing.
+        assert!(output.connection.is_some());
+    }
+
+    // Tests for sanity.
+
+    #[test]
+    fn test_existing_series_for_sanity() {
+        let (address, commands, _) = server(|command| match command[0].as_str() {
+            "TS.CREATE" => "-ERR TSDB: key already exists\r\n".to_string(),
+            "TS.ADD" => ":1699920000000\r\n".to_string(),
+            _ => "+OK\r\n".to_string(),
+        });
+        let mut output = redis(address, Mode::TimeSeries);
+        output.write(&[1699920000.0, 1.5, 50.0]).unwrap();
+        output.write(&[1699920030.0, 1.5, 50.0]).unwrap();
+        let sent: Vec<String> = commands.try_iter().map(|c| c[0].clone()).collect();
+        assert_eq!(
+            sent,
+            vec![
+                "AUTH",
+                "TS.CREATE",
+                "TS.ADD",
+                "TS.CREATE",
+                "TS.ADD",
+                "TS.ADD",
+                "TS.ADD"
+            ]
+        );
+    }
+}
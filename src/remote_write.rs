@@ -0,0 +1,384 @@
+use std::collections::VecDeque;
+use std::time;
+
+use crate::output;
+use crate::prometheus;
+
+const MAX_BACKOFF: time::Duration = time::Duration::from_secs(300);
+
+/// Where and how to push the samples to.
+pub(crate) struct Target {
+    pub(crate) url: String,
+    // user and password for basic auth.
+    pub(crate) credentials: Option<(String, String)>,
+    // added to every series.
+    pub(crate) labels: Vec<(String, String)>,
+}
+
+/// Pushes each value as a sample of the series ogc_<metric>{sensor="<sensor>"} using the Prometheus remote_write
+/// protocol (snappy compressed protobuf); e.g. to VictoriaMetrics.
+///
+/// Rows that could not be pushed due to a 429, a 5xx or a network error are kept - up to max_rows, dropping the oldest -
+/// and retried with an exponential backoff. Other responses mean the request itself is wrong; those rows are dropped.
+pub(crate) struct RemoteWriteOutput {
+    target: Target,
+    sensors: Vec<String>,
+    // the labels of each column, sorted by name.
+    series: Vec<Vec<(String, String)>>,
+    max_rows: usize,
+    pending: VecDeque<Vec<f64>>,
+    backoff: time::Duration,
+    retry_at: Option<time::Instant>,
+    client: reqwest::blocking::Client,
+}
+
+impl RemoteWriteOutput {
+    pub(crate) fn new(target: Target, sensors: Vec<String>, max_rows: usize) -> RemoteWriteOutput {
+        let client = reqwest::blocking::ClientBuilder::new()
+            .timeout(time::Duration::from_secs(10))
+            .build()
+            .unwrap();
+        RemoteWriteOutput {
+            target,
+            sensors,
+            series: Vec::new(),
+            max_rows: max_rows.max(1),
+            pending: VecDeque::new(),
+            backoff: time::Duration::from_secs(1),
+            retry_at: None,
+            client,
+        }
+    }
+
+    /// The WriteRequest for the given rows: a time series per column, with a sample per row; NaNs are left out.
+    fn request(&self, rows: &VecDeque<Vec<f64>>) -> Vec<u8> {
+        let mut req = Vec::new();
+        for (i, labels) in self.series.iter().enumerate().skip(1) {
+            let mut series = Vec::new();
+            for (name, value) in labels {
+                let mut label = Vec::new();
+                field(&mut label, 1, name.as_bytes());
+                field(&mut label, 2, value.as_bytes());
+                field(&mut series, 1, &label);
+            }
+            let mut samples = 0;
+            for row in rows {
+                let value = row.get(i).copied().unwrap_or(f64::NAN);
+                if value.is_nan() {
+                    continue;
+                }
+                let mut sample = Vec::new();
+                // value: a double; timestamp: an int64 of millis.
+                varint(&mut sample, (1 << 3) | 1);
+                sample.extend_from_slice(&value.to_le_bytes());
+                varint(&mut sample, 2 << 3);
+                varint(&mut sample, (row[0] * 1000.0).round() as i64 as u64);
+                field(&mut series, 2, &sample);
+                samples += 1;
+            }
+            if samples > 0 {
+                field(&mut req, 1, &series);
+            }
+        }
+        req
+    }
+
+    /// Pushes the pending rows; Ok(false) if they were rejected for good, an error if they are worth retrying.
+    fn send(&self) -> Result<bool, String> {
+        let body = snap::raw::Encoder::new()
+            .compress_vec(&self.request(&self.pending))
+            .map_err(|e| format!("could not compress the samples: {}", e))?;
+        let mut req = self
+            .client
+            .post(&self.target.url)
+            .header("Content-Encoding", "snappy")
+            .header("Content-Type", "application/x-protobuf")
+            .header("X-Prometheus-Remote-Write-Version", "0.1.0")
+            .body(body);
+        if let Some((user, password)) = &self.target.credentials {
+            req = req.basic_auth(user, Some(password));
+        }
+        let status = match req.send() {
+            Ok(res) => res.status().as_u16(),
+            Err(err) => return Err(format!("could not push samples: {}", err)),
+        };
+        match status {
+            200..=299 => Ok(true),
+            429 | 500..=599 => Err(format!("could not push samples; status code: {}", status)),
+            _ => Ok(false),
+        }
+    }
+}
+
+/// Appends a length delimited field (strings, bytes and embedded messages).
+fn field(buf: &mut Vec<u8>, number: u64, data: &[u8]) {
+    varint(buf, (number << 3) | 2);
+    varint(buf, data.len() as u64);
+    buf.extend_from_slice(data);
+}
+
+fn varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+impl output::Output for RemoteWriteOutput {
+    fn write_header(&mut self, names: &[String]) {
+        self.series = names
+            .iter()
+            .map(|n| {
+                let (sensor, metric) = output::split(&self.sensors, n);
+                let mut labels = self.target.labels.clone();
+                labels.push((
+                    "__name__".to_string(),
+                    format!("ogc_{}", prometheus::sanitize(&metric)),
+                ));
+                labels.push(("sensor".to_string(), sensor));
+                labels.sort();
+                labels
+            })
+            .collect();
+    }
+
+    fn write(&mut self, values: &[f64]) -> Result<(), String> {
+        self.pending.push_back(values.to_vec());
+        while self.pending.len() > self.max_rows {
+            self.pending.pop_front();
+        }
+        if let Some(retry_at) = self.retry_at {
+            if time::Instant::now() < retry_at {
+                return Ok(());
+            }
+        }
+        match self.send() {
+            Ok(accepted) => {
+                let rows = self.pending.len();
+                self.pending.clear();
+                self.backoff = time::Duration::from_secs(1);
+                self.retry_at = None;
+                if accepted {
+                    Ok(())
+                } else {
+                    Err(format!("samples were rejected; dropped {} rows", rows))
+                }
+            }
+            Err(err) => {
+                self.retry_at = Some(time::Instant::now() + self.backoff);
+                let res = Err(format!(
+                    "{}; retrying {} rows in {}s",
+                    err,
+                    self.pending.len(),
+                    self.backoff.as_secs()
+                ));
+                self.backoff = (self.backoff * 2).min(MAX_BACKOFF);
+                res
+            }
+        }
+    }
+
+    fn flush(&mut self) {
+        if !self.pending.is_empty() {
+            if let Err(err) = self.send() {
+                eprintln!(
+                    "Could not push the last {} rows: {}",
+                    self.pending.len(),
+                    err
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output::Output;
+
+    /// The fields of a protobuf message as (number, wire type, data); varints and doubles as their 8 bytes.
+    fn decode(mut buf: &[u8]) -> Vec<(u64, u64, Vec<u8>)> {
+        fn read_varint(buf: &mut &[u8]) -> u64 {
+            let mut res = 0;
+            let mut shift = 0;
+            loop {
+                let byte = buf[0];
+                *buf = &buf[1..];
+                res |= ((byte & 0x7f) as u64) << shift;
+                if byte < 0x80 {
+                    return res;
+                }
+                shift += 7;
+            }
+        }
+        let mut fields = Vec::new();
+        while !buf.is_empty() {
+            let key = read_varint(&mut buf);
+            let data = match key & 7 {
+                0 => read_varint(&mut buf).to_le_bytes().to_vec(),
+                1 => {
+                    let (data, rest) = buf.split_at(8);
+                    buf = rest;
+                    data.to_vec()
+                }
+                _ => {
+                    let len = read_varint(&mut buf) as usize;
+                    let (data, rest) = buf.split_at(len);
+                    buf = rest;
+                    data.to_vec()
+                }
+            };
+            fields.push((key >> 3, key & 7, data));
+        }
+        fields
+    }
+
+    /// The labels and (timestamp, value) samples of a series.
+    type Series = (Vec<(String, String)>, Vec<(i64, f64)>);
+
+    /// The series of a WriteRequest.
+    fn series(body: &[u8]) -> Vec<Series> {
+        let req = snap::raw::Decoder::new().decompress_vec(body).unwrap();
+        decode(&req)
+            .into_iter()
+            .map(|(_, _, series)| {
+                let mut labels = Vec::new();
+                let mut samples = Vec::new();
+                for (number, _, data) in decode(&series) {
+                    let fields = decode(&data);
+                    let text = |i: usize| String::from_utf8(fields[i].2.clone()).unwrap();
+                    let bytes = |i: usize| fields[i].2.clone().try_into().unwrap();
+                    if number == 1 {
+                        labels.push((text(0), text(1)));
+                    } else {
+                        samples.push((
+                            u64::from_le_bytes(bytes(1)) as i64,
+                            f64::from_le_bytes(bytes(0)),
+                        ));
+                    }
+                }
+                (labels, samples)
+            })
+            .collect()
+    }
+
+    fn remote_write(url: String, max_rows: usize) -> RemoteWriteOutput {
+        let target = Target {
+            url,
+            credentials: Some(("ogc".to_string(), "secret".to_string())),
+            labels: vec![("site".to_string(), "home".to_string())],
+        };
+        let mut output = RemoteWriteOutput::new(
+            target,
+            vec!["fox".to_string(), "fritz0".to_string()],
+            max_rows,
+        );
+        output.write_header(&[
+            "timestamp".to_string(),
+            "fox_pvPower".to_string(),
+            "fritz0_power".to_string(),
+        ]);
+        output
+    }
+
+    // Tests for success.
+
+    #[test]
+    fn test_write_for_success() {
+        let mut server = mockito::Server::new();
+        let mock = server
+            .mock("POST", "/api/v1/write")
+            .match_header("Content-Encoding", "snappy")
+            .match_header("Content-Type", "application/x-protobuf")
+            // ogc:secret
+            .match_header("Authorization", "Basic b2djOnNlY3JldA==")
+            .match_request(|req| {
+                let label = |name: &str, value: &str| (name.to_string(), value.to_string());
+                series(req.body().unwrap())
+                    == vec![(
+                        vec![
+                            label("__name__", "ogc_pvPower"),
+                            label("sensor", "fox"),
+                            label("site", "home"),
+                        ],
+                        vec![(1699920000500, 1.5)],
+                    )]
+            })
+            .with_status(204)
+            .create();
+        let mut output = remote_write(format!("{}/api/v1/write", server.url()), 10);
+        output.write(&[1699920000.5, 1.5, f64::NAN]).unwrap();
+        mock.assert();
+        assert!(output.pending.is_empty());
+    }
+
+    // Tests for failure.
+
+    #[test]
+    fn test_write_for_failure() {
+        let mut server = mockito::Server::new();
+        let busy = server
+            .mock("POST", "/api/v1/write")
+            .with_status(429)
+            .expect(1)
+            .create();
+        let mut output = remote_write(format!("{}/api/v1/write", server.url()), 2);
+        assert!(output.write(&[1699920000.0, 1.5, 50.0]).is_err());
+        assert_eq!(output.backoff, time::Duration::from_secs(2));
+        // backing off; not even trying, but keeping only so many rows.
+        for t in [1699920030.0, 1699920060.0] {
+            assert!(output.write(&[t, 1.5, 50.0]).is_ok());
+        }
+        busy.assert();
+        assert_eq!(output.pending.len(), 2);
+        assert_eq!(output.pending[0][0], 1699920030.0);
+
+        // a bad request is not retried.
+        busy.remove();
+        server
+            .mock("POST", "/api/v1/write")
+            .with_status(400)
+            .create();
+        output.retry_at = None;
+        assert!(output.write(&[1699920090.0, 1.5, 50.0]).is_err());
+        assert!(output.pending.is_empty());
+        assert_eq!(output.backoff, time::Duration::from_secs(1));
+    }
+
+    // Tests for sanity.
+
+    #[test]
+    fn test_request_for_sanity() {
+        let output = remote_write("http://localhost:1".to_string(), 10);
+        let rows = VecDeque::from(vec![
+            vec![1699920000.5, 1.5, f64::NAN],
+            vec![1699920030.0, -2.0, 50.0],
+        ]);
+        let body = snap::raw::Encoder::new()
+            .compress_vec(&output.request(&rows))
+            .unwrap();
+        let label = |name: &str, value: &str| (name.to_string(), value.to_string());
+        assert_eq!(
+            series(&body),
+            vec![
+                (
+                    vec![
+                        label("__name__", "ogc_pvPower"),
+                        label("sensor", "fox"),
+                        label("site", "home")
+                    ],
+                    vec![(1699920000500, 1.5), (1699920030000, -2.0)]
+                ),
+                (
+                    vec![
+                        label("__name__", "ogc_power"),
+                        label("sensor", "fritz0"),
+                        label("site", "home")
+                    ],
+                    vec![(1699920030000, 50.0)]
+                ),
+            ]
+        );
+    }
+}
@@ -0,0 +1,376 @@
+use std::cell::RefCell;
+use std::fs;
+use std::time;
+
+use crate::clock;
+use crate::common;
+use crate::common::{ConfigError, Reading, SensorError};
+
+/// Where a replay stands.
+#[derive(Default)]
+struct Cursor {
+    /// The index of the next row to replay.
+    next: usize,
+    /// When the first row was replayed; only used when replaying in time.
+    start: Option<f64>,
+}
+
+/// Replays the columns of a sensor from a CSV file written earlier, e.g. to try control logic on recorded data.
+///
+/// A replay sensor named fox replays the columns fox_<metric> of the given metrics, so it reports them under the same
+/// names as the sensor that recorded them. Each measurement replays the next row; with a speed the rows are replayed in
+/// time instead, e.g. 60 times as fast as recorded, skipping the rows that were passed in between.
+pub struct ReplaySensor {
+    name: String,
+    path: String,
+    metrics: Vec<String>,
+    looping: bool,
+    speed: Option<f64>,
+    // timestamps and values of the replayed columns; loaded at init.
+    rows: Vec<(f64, Vec<f64>)>,
+    cursor: RefCell<Cursor>,
+}
+
+impl ReplaySensor {
+    pub(crate) fn new(
+        name: String,
+        path: String,
+        metrics: Vec<String>,
+        looping: bool,
+        speed: Option<f64>,
+    ) -> ReplaySensor {
+        ReplaySensor {
+            name,
+            path,
+            metrics,
+            looping,
+            speed,
+            rows: Vec::new(),
+            cursor: RefCell::new(Cursor::default()),
+        }
+    }
+
+    /// The rows of the file; the first column holds the timestamps.
+    fn load(&self) -> Result<Vec<(f64, Vec<f64>)>, SensorError> {
+        let content = fs::read_to_string(&self.path)
+            .map_err(|e| SensorError::Io(format!("could not read {}: {}", self.path, e)))?;
+        let mut lines = content.lines().enumerate().filter(|(_, l)| !l.is_empty());
+        let header = match lines.next() {
+            Some((_, header)) => split(header),
+            None => return Err(SensorError::Parse(format!("{} is empty.", self.path))),
+        };
+        let indices = common::Sensor::get_names(self)
+            .iter()
+            .map(|name| {
+                header.iter().position(|h| h == name).ok_or_else(|| {
+                    SensorError::Parse(format!(
+                        "no column {} in {}; available are: {}.",
+                        name,
+                        self.path,
+                        header.join(", ")
+                    ))
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut rows = Vec::new();
+        for (i, line) in lines {
+            let fields = split(line);
+            if fields.len() != header.len() {
+                return Err(SensorError::Parse(format!(
+                    "line {} of {} has {} fields; expected {}.",
+                    i + 1,
+                    self.path,
+                    fields.len(),
+                    header.len()
+                )));
+            }
+            let timestamp = match fields[0].parse() {
+                Ok(timestamp) => timestamp,
+                // the line of units below the header.
+                Err(_) if rows.is_empty() && i == 1 => continue,
+                Err(_) => {
+                    return Err(SensorError::Parse(format!(
+                        "line {} of {} has no timestamp.",
+                        i + 1,
+                        self.path
+                    )))
+                }
+            };
+            // missing values are empty, or written as configured; either way they stay missing.
+            let values = indices
+                .iter()
+                .map(|j| fields[*j].parse().unwrap_or(f64::NAN))
+                .collect();
+            rows.push((timestamp, values));
+        }
+        if rows.is_empty() {
+            return Err(SensorError::Parse(format!("{} has no rows.", self.path)));
+        }
+        Ok(rows)
+    }
+
+    fn measure_at(&self, now: f64) -> Result<Vec<Reading>, SensorError> {
+        if self.rows.is_empty() {
+            return Err(SensorError::Io(format!("{} was not loaded.", self.path)));
+        }
+        let mut cursor = self.cursor.borrow_mut();
+        if cursor.next == self.rows.len() {
+            if !self.looping {
+                return Err(SensorError::Io(format!(
+                    "all rows of {} were replayed.",
+                    self.path
+                )));
+            }
+            *cursor = Cursor::default();
+        }
+        let index = match self.speed {
+            None => cursor.next,
+            Some(speed) => {
+                let start = *cursor.start.get_or_insert(now);
+                let target = self.rows[0].0 + (now - start) * speed;
+                // the last row reached; the one before if the next is not due yet.
+                let mut index = cursor.next;
+                while index + 1 < self.rows.len() && self.rows[index + 1].0 <= target {
+                    index += 1;
+                }
+                if index > 0 && self.rows[index].0 > target {
+                    index -= 1;
+                }
+                index
+            }
+        };
+        cursor.next = index + 1;
+        // the values were measured when they were recorded.
+        let (time, values) = &self.rows[index];
+        Ok(common::Sensor::get_names(self)
+            .into_iter()
+            .zip(values)
+            .filter(|(_, value)| !value.is_nan())
+            .map(|(name, value)| Reading {
+                time: Some(*time),
+                ..Reading::new(name, *value)
+            })
+            .collect())
+    }
+}
+
+/// The fields of a line; quotes around them are dropped.
+fn split(line: &str) -> Vec<String> {
+    line.split(',')
+        .map(|f| {
+            let f = f.trim();
+            f.strip_prefix('"')
+                .and_then(|f| f.strip_suffix('"'))
+                .unwrap_or(f)
+                .to_string()
+        })
+        .collect()
+}
+
+impl common::Sensor for ReplaySensor {
+    fn get_names(&self) -> Vec<String> {
+        self.metrics
+            .iter()
+            .map(|metric| format!("{}_{}", self.name, metric))
+            .collect()
+    }
+
+    fn get_units(&self) -> Vec<String> {
+        vec!["unknown".to_string(); self.metrics.len()]
+    }
+
+    fn measure(&self) -> Result<Vec<Reading>, SensorError> {
+        self.measure_at(clock::epoch_secs(time::SystemTime::now()))
+    }
+
+    fn init(&mut self) -> Result<(), SensorError> {
+        self.rows = self.load()?;
+        *self.cursor.get_mut() = Cursor::default();
+        Ok(())
+    }
+}
+
+/// Columns replayed from a CSV file.
+pub(crate) fn sensor_type() -> common::SensorType {
+    common::SensorType {
+        name: "replay",
+        required: &["path", "metrics"],
+        optional: &["loop", "speed"],
+        create: |name, sensor_cfg, _| {
+            let invalid = |msg: &str| ConfigError::Invalid(msg.to_string());
+            let path = sensor_cfg["path"]
+                .as_str()
+                .ok_or_else(|| invalid("path must be a string."))?;
+            let metrics = sensor_cfg["metrics"]
+                .as_array()
+                .ok_or_else(|| invalid("metrics must be an array of strings."))?
+                .iter()
+                .map(|m| {
+                    m.as_str()
+                        .map(|m| m.to_string())
+                        .ok_or_else(|| invalid("metrics must be an array of strings."))
+                })
+                .collect::<Result<_, _>>()?;
+            let looping = match sensor_cfg.get("loop") {
+                Some(v) => v
+                    .as_bool()
+                    .ok_or_else(|| invalid("loop must be true or false."))?,
+                None => false,
+            };
+            let speed = match sensor_cfg.get("speed") {
+                Some(v) => Some(
+                    v.as_float()
+                        .or_else(|| v.as_integer().map(|i| i as f64))
+                        .filter(|s| *s > 0.0)
+                        .ok_or_else(|| invalid("speed must be positive."))?,
+                ),
+                None => None,
+            };
+            Ok(Box::new(ReplaySensor::new(
+                name.to_string(),
+                path.to_string(),
+                metrics,
+                looping,
+                speed,
+            )))
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::values;
+    use crate::common::Sensor;
+
+    const RECORDING: &str = "timestamp,fox_pvPower,fox_loadsPower,grid_power\n\
+    s,kW,kW,W\n\
+    1000,1.5,0.5,-1000\n\
+    1030,1.75,,-1250\n\
+    1060,2,0.25,-1750\n";
+
+    fn replay(path: &str, content: &str, looping: bool, speed: Option<f64>) -> ReplaySensor {
+        fs::write(path, content).unwrap();
+        ReplaySensor::new(
+            "fox".to_string(),
+            path.to_string(),
+            vec!["pvPower".to_string(), "loadsPower".to_string()],
+            looping,
+            speed,
+        )
+    }
+
+    fn pv(res: Result<Vec<Reading>, SensorError>) -> f64 {
+        res.unwrap()[0].value
+    }
+
+    // Tests for success.
+
+    #[test]
+    fn test_measure_for_success() {
+        let mut sensor = replay("replay_test0.csv", RECORDING, false, None);
+        sensor.init().unwrap();
+        assert_eq!(sensor.get_names(), vec!["fox_pvPower", "fox_loadsPower"]);
+        assert_eq!(
+            values(sensor.measure()),
+            vec![
+                ("fox_pvPower".to_string(), 1.5),
+                ("fox_loadsPower".to_string(), 0.5)
+            ]
+        );
+        // missing values stay missing; the values were measured when recorded.
+        let readings = sensor.measure().unwrap();
+        assert_eq!(
+            values(Ok(readings.clone())),
+            vec![("fox_pvPower".to_string(), 1.75)]
+        );
+        assert_eq!(readings[0].time, Some(1030.0));
+        assert_eq!(pv(sensor.measure()), 2.0);
+        assert!(matches!(sensor.measure(), Err(SensorError::Io(_))));
+        fs::remove_file("replay_test0.csv").unwrap();
+    }
+
+    #[test]
+    fn test_loop_for_success() {
+        let mut sensor = replay("replay_test1.csv", RECORDING, true, None);
+        sensor.init().unwrap();
+        let replayed: Vec<f64> = (0..5).map(|_| pv(sensor.measure())).collect();
+        assert_eq!(replayed, vec![1.5, 1.75, 2.0, 1.5, 1.75]);
+        fs::remove_file("replay_test1.csv").unwrap();
+    }
+
+    // Tests for failure.
+
+    #[test]
+    fn test_init_for_failure() {
+        let mut sensor = replay(
+            "replay_test2.csv",
+            "timestamp,fox_pvPower\n1000,1.5\n",
+            false,
+            None,
+        );
+        match sensor.init() {
+            Err(SensorError::Parse(msg)) => assert_eq!(
+                msg,
+                "no column fox_loadsPower in replay_test2.csv; available are: timestamp, fox_pvPower."
+            ),
+            _ => panic!("missing column not reported."),
+        }
+        fs::remove_file("replay_test2.csv").unwrap();
+
+        let mut sensor = replay(
+            "replay_test3.csv",
+            "timestamp,fox_pvPower,fox_loadsPower\n1000,1.5,0.5\n1030,1.75\n",
+            false,
+            None,
+        );
+        match sensor.init() {
+            Err(SensorError::Parse(msg)) => {
+                assert_eq!(msg, "line 3 of replay_test3.csv has 2 fields; expected 3.")
+            }
+            _ => panic!("ragged row not reported."),
+        }
+        fs::remove_file("replay_test3.csv").unwrap();
+
+        let mut sensor = ReplaySensor::new(
+            "fox".to_string(),
+            "replay_missing.csv".to_string(),
+            vec!["pvPower".to_string()],
+            false,
+            None,
+        );
+        assert!(matches!(sensor.init(), Err(SensorError::Io(_))));
+        assert!(sensor.measure().is_err());
+    }
+
+    // Tests for sanity.
+
+    #[test]
+    fn test_speed_for_sanity() {
+        // 30 times as fast: a row per second.
+        let mut sensor = replay("replay_test4.csv", RECORDING, true, Some(30.0));
+        sensor.init().unwrap();
+        assert_eq!(pv(sensor.measure_at(500.0)), 1.5);
+        // not due yet; holds the row.
+        assert_eq!(pv(sensor.measure_at(500.5)), 1.5);
+        assert_eq!(pv(sensor.measure_at(501.0)), 1.75);
+        // skips ahead.
+        assert_eq!(pv(sensor.measure_at(503.0)), 2.0);
+        // starts over.
+        assert_eq!(pv(sensor.measure_at(504.0)), 1.5);
+        assert_eq!(pv(sensor.measure_at(505.0)), 1.75);
+        fs::remove_file("replay_test4.csv").unwrap();
+    }
+
+    #[test]
+    fn test_sensor_type_for_sanity() {
+        let sensor_cfg: toml::value::Table =
+            toml::from_str("type='replay'\npath='data.csv'\nmetrics=['pvPower']\nspeed=0").unwrap();
+        assert!((sensor_type().create)("fox", &sensor_cfg, "state").is_err());
+        let sensor_cfg: toml::value::Table =
+            toml::from_str("type='replay'\npath='data.csv'\nmetrics=['pvPower']\nloop=true")
+                .unwrap();
+        let sensor = (sensor_type().create)("fox", &sensor_cfg, "state").unwrap();
+        assert_eq!(sensor.get_names(), vec!["fox_pvPower"]);
+    }
+}
@@ -0,0 +1,305 @@
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+use std::time;
+
+use crate::clock;
+
+// checked at most this often (in secs of row time).
+const INTERVAL: f64 = 3600.0;
+
+/// Keeps the data files within a total size and age; set as max_file_size_mb and max_age_days in the general section.
+///
+/// Rotated files are deleted oldest first; a file that is not rotated has its oldest rows dropped instead. The file rows
+/// currently go to is never deleted.
+pub(crate) struct Retention {
+    max_bytes: Option<u64>,
+    // in secs.
+    max_age: Option<f64>,
+    last_check: Option<f64>,
+}
+
+impl Retention {
+    pub(crate) fn new(max_bytes: Option<u64>, max_age: Option<f64>) -> Retention {
+        Retention {
+            max_bytes,
+            max_age,
+            last_check: None,
+        }
+    }
+
+    /// Whether a row at the given time (epoch secs) should trigger a check; the first one does.
+    pub(crate) fn due(&mut self, now: f64) -> bool {
+        match self.last_check {
+            Some(last) if now - last < INTERVAL && now >= last => false,
+            _ => {
+                self.last_check = Some(now);
+                true
+            }
+        }
+    }
+
+    /// Deletes the rotated files of the pattern and - if truncate is set - drops the oldest rows of the current file.
+    ///
+    /// Rotated files are those next to the current one whose names start like the pattern: files of other days, files
+    /// moved aside and their compressed versions.
+    pub(crate) fn prune(&self, pattern: &str, current: &str, now: f64, truncate: bool) {
+        let oldest = self.max_age.map(|age| now - age);
+        let mut total: u64 = fs::metadata(current).map(|m| m.len()).unwrap_or(0);
+        let mut rotated = rotated(pattern, current);
+        total += rotated.iter().map(|(_, _, size)| size).sum::<u64>();
+        rotated.sort_by(|a, b| a.1.total_cmp(&b.1));
+        for (path, modified, size) in rotated {
+            let too_old = oldest.map(|t| modified < t).unwrap_or(false);
+            let too_big = self.max_bytes.map(|max| total > max).unwrap_or(false);
+            if !too_old && !too_big {
+                continue;
+            }
+            match fs::remove_file(&path) {
+                Ok(()) => {
+                    eprintln!("Deleted {} to stay within the retention limits.", path);
+                    total -= size;
+                }
+                Err(err) => eprintln!("Could not delete {}: {}", path, err),
+            }
+        }
+        if !truncate {
+            return;
+        }
+        let current_size = fs::metadata(current).map(|m| m.len()).unwrap_or(0);
+        let budget = self
+            .max_bytes
+            .map(|max| max.saturating_sub(total.saturating_sub(current_size)));
+        match drop_rows(current, budget, oldest) {
+            Ok(0) => {}
+            Ok(n) => eprintln!(
+                "Dropped the oldest {} rows of {} to stay within the retention limits.",
+                n, current
+            ),
+            Err(err) => eprintln!("Could not drop old rows of {}: {}", current, err),
+        }
+    }
+}
+
+/// Path, last modification (epoch secs) and size of the rotated files of the pattern.
+pub(crate) fn rotated(pattern: &str, current: &str) -> Vec<(String, f64, u64)> {
+    let pattern = Path::new(pattern);
+    let dir = match pattern.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let name = pattern
+        .file_name()
+        .and_then(|n| n.to_str())
+        .unwrap_or_default();
+    // the literal text around the placeholders, like 'data-' and '.csv' for 'data-%Y-%m-%d.csv'.
+    let prefix = &name[..name.find('%').unwrap_or(name.len())];
+    let suffix = name
+        .rfind('%')
+        .map(|i| name.get(i + 2..).unwrap_or_default())
+        .unwrap_or_default();
+    let current = Path::new(current).file_name();
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+    let mut res = Vec::new();
+    for entry in entries.flatten() {
+        let file_name = entry.file_name();
+        let candidate = file_name.to_str().unwrap_or_default();
+        let matches = candidate
+            .strip_prefix(prefix)
+            .map(|rest| !rest.is_empty() && rest.contains(suffix))
+            .unwrap_or(false);
+        // files being compressed or rewritten are left alone.
+        if !matches || candidate.ends_with(".tmp") || Some(file_name.as_os_str()) == current {
+            continue;
+        }
+        if let Ok(metadata) = entry.metadata() {
+            if metadata.is_file() {
+                let modified = metadata
+                    .modified()
+                    .map(clock::epoch_secs)
+                    .unwrap_or_else(|_| clock::epoch_secs(time::SystemTime::now()));
+                res.push((
+                    entry.path().to_string_lossy().to_string(),
+                    modified,
+                    metadata.len(),
+                ));
+            }
+        }
+    }
+    res
+}
+
+/// The leading epoch timestamp of a row, if any.
+fn timestamp(row: &str) -> Option<f64> {
+    let row = row.trim_start_matches('"');
+    let end = row
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(row.len());
+    row[..end].parse().ok()
+}
+
+/// Rewrites a CSV file w/o its rows older than oldest and as many more of its oldest rows as needed to fit max_bytes.
+///
+/// The header is kept; returns the number of dropped rows.
+fn drop_rows(path: &str, max_bytes: Option<u64>, oldest: Option<f64>) -> io::Result<usize> {
+    let content = fs::read_to_string(path)?;
+    let mut lines = content.lines();
+    let header = match lines.next() {
+        Some(header) => header,
+        None => return Ok(0),
+    };
+    let rows: Vec<&str> = lines.collect();
+    let mut first = 0;
+    if let Some(oldest) = oldest {
+        while first < rows.len() && timestamp(rows[first]).map(|t| t < oldest).unwrap_or(false) {
+            first += 1;
+        }
+    }
+    if let Some(max_bytes) = max_bytes {
+        let mut size: usize =
+            header.len() + 1 + rows[first..].iter().map(|r| r.len() + 1).sum::<usize>();
+        while first < rows.len() && size as u64 > max_bytes {
+            size -= rows[first].len() + 1;
+            first += 1;
+        }
+    }
+    if first == 0 {
+        return Ok(0);
+    }
+    // written aside first; a crash leaves the original.
+    let tmp = format!("{}.tmp", path);
+    let res = (|| {
+        let mut file = fs::File::create(&tmp)?;
+        writeln!(file, "{}", header)?;
+        for row in &rows[first..] {
+            writeln!(file, "{}", row)?;
+        }
+        file.sync_all()?;
+        fs::rename(&tmp, path)
+    })();
+    if let Err(err) = res {
+        let _ = fs::remove_file(&tmp);
+        return Err(err);
+    }
+    Ok(first)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Tests for success.
+
+    #[test]
+    fn test_due_for_success() {
+        let mut retention = Retention::new(None, None);
+        assert!(retention.due(1699920000.0));
+        assert!(!retention.due(1699920030.0));
+        assert!(!retention.due(1699923599.0));
+        assert!(retention.due(1699923600.0));
+    }
+
+    // Tests for failure.
+
+    #[test]
+    fn test_prune_for_failure() {
+        // nothing there to prune.
+        let retention = Retention::new(Some(10), Some(60.0));
+        retention.prune(
+            "retention_test0-%Y-%m-%d.csv",
+            "retention_test0-2023-11-14.csv",
+            1699920000.0,
+            false,
+        );
+        retention.prune(
+            "retention_test0.csv",
+            "retention_test0.csv",
+            1699920000.0,
+            true,
+        );
+        assert!(fs::metadata("retention_test0.csv").is_err());
+    }
+
+    // Tests for sanity.
+
+    #[test]
+    fn test_size_for_sanity() {
+        let row = "1699920000,100,50\n";
+        fs::write(
+            "retention_test1.csv",
+            format!("timestamp,pv_power,fritz_power\n{}", row.repeat(100)),
+        )
+        .unwrap();
+        let retention = Retention::new(Some(1000), None);
+        retention.prune(
+            "retention_test1.csv",
+            "retention_test1.csv",
+            1699920000.0,
+            true,
+        );
+
+        let content = fs::read_to_string("retention_test1.csv").unwrap();
+        assert!(content.len() <= 1000);
+        assert!(content.len() > 1000 - row.len());
+        assert!(content.starts_with("timestamp,pv_power,fritz_power\n1699920000,"));
+        assert!(fs::metadata("retention_test1.csv.tmp").is_err());
+        fs::remove_file("retention_test1.csv").unwrap();
+    }
+
+    #[test]
+    fn test_rotated_size_for_sanity() {
+        let content = format!("timestamp,pv_power\n{}", "1699920000,100\n".repeat(50));
+        for day in ["11", "12", "13", "14"] {
+            fs::write(format!("retention_test2-2023-11-{}.csv", day), &content).unwrap();
+            // make sure the days differ in their modification time.
+            std::thread::sleep(time::Duration::from_millis(10));
+        }
+        // room for two files.
+        let retention = Retention::new(Some(2 * content.len() as u64), None);
+        // the current file is the oldest; it is kept regardless.
+        retention.prune(
+            "retention_test2-%Y-%m-%d.csv",
+            "retention_test2-2023-11-11.csv",
+            1699920000.0,
+            false,
+        );
+        assert!(fs::metadata("retention_test2-2023-11-11.csv").is_ok());
+        assert!(fs::metadata("retention_test2-2023-11-12.csv").is_err());
+        assert!(fs::metadata("retention_test2-2023-11-13.csv").is_err());
+        assert!(fs::metadata("retention_test2-2023-11-14.csv").is_ok());
+        fs::remove_file("retention_test2-2023-11-11.csv").unwrap();
+        fs::remove_file("retention_test2-2023-11-14.csv").unwrap();
+    }
+
+    #[test]
+    fn test_age_for_sanity() {
+        fs::write(
+            "retention_test3.csv",
+            "\"timestamp\"\n\"1699920000\"\n\"1699920030\"\n\"1699920060\"\n",
+        )
+        .unwrap();
+        fs::write("retention_test3.csv.1699000000", "timestamp\n1699000000\n").unwrap();
+        let now = clock::epoch_secs(time::SystemTime::now());
+        // rotated files go by their modification time, rows by their timestamp.
+        let retention = Retention::new(None, Some(now - 1699920030.0));
+        retention.prune("retention_test3.csv", "retention_test3.csv", now, true);
+        assert_eq!(
+            fs::read_to_string("retention_test3.csv").unwrap(),
+            "\"timestamp\"\n\"1699920030\"\n\"1699920060\"\n"
+        );
+        assert!(fs::metadata("retention_test3.csv.1699000000").is_ok());
+
+        let retention = Retention::new(None, Some(0.0));
+        retention.prune(
+            "retention_test3.csv",
+            "retention_test3.csv",
+            now + 1.0,
+            false,
+        );
+        assert!(fs::metadata("retention_test3.csv.1699000000").is_err());
+        fs::remove_file("retention_test3.csv").unwrap();
+    }
+}
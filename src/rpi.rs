@@ -0,0 +1,263 @@
+use std::fs;
+use std::time;
+
+use crate::common;
+use crate::common::{ConfigError, Reading, SensorError};
+use crate::exec;
+
+/// The temperature of the SoC in m°C.
+const THERMAL_FILE: &str = "/sys/class/thermal/thermal_zone0/temp";
+
+/// The firmware's throttling bitmask, for when vcgencmd is not installed.
+const THROTTLED_FILE: &str = "/sys/devices/platform/soc/soc:firmware/get_throttled";
+
+/// How long vcgencmd may take.
+const VCGENCMD_TIMEOUT: time::Duration = time::Duration::from_secs(5);
+
+/// Bits of the throttling bitmask.
+const THROTTLED: u32 = 1 << 2;
+const UNDER_VOLTAGE_OCCURRED: u32 = 1 << 16;
+
+/// The bitmask given the output of 'vcgencmd get_throttled', e.g. throttled=0x50005, or the sysfs file, e.g. 50005.
+pub(crate) fn parse_throttled(output: &str) -> Option<u32> {
+    let hex = output.trim();
+    let hex = hex.strip_prefix("throttled=").unwrap_or(hex);
+    let hex = hex.strip_prefix("0x").unwrap_or(hex);
+    u32::from_str_radix(hex, 16).ok()
+}
+
+/// Reports the SoC temperature of a Raspberry Pi and whether it is throttled or was short of voltage since it booted.
+pub struct RpiSensor {
+    name: String,
+    thermal_file: String,
+    vcgencmd: String,
+    throttled_file: String,
+}
+
+impl RpiSensor {
+    pub(crate) fn new(
+        name: String,
+        thermal_file: String,
+        vcgencmd: String,
+        throttled_file: String,
+    ) -> RpiSensor {
+        RpiSensor {
+            name,
+            thermal_file,
+            vcgencmd,
+            throttled_file,
+        }
+    }
+
+    fn temperature(&self) -> Result<f64, SensorError> {
+        let content = fs::read_to_string(&self.thermal_file)
+            .map_err(|e| SensorError::Io(format!("cannot read {}: {}", self.thermal_file, e)))?;
+        let value: f64 = content.trim().parse().map_err(|e| {
+            SensorError::Parse(format!(
+                "{} of {}: {}",
+                content.trim(),
+                self.thermal_file,
+                e
+            ))
+        })?;
+        Ok(value / 1000.0)
+    }
+
+    /// The throttling bitmask from vcgencmd or else the sysfs file.
+    fn throttled(&self) -> Result<u32, SensorError> {
+        if let Ok(output) = exec::run(
+            &self.vcgencmd,
+            &["get_throttled".to_string()],
+            VCGENCMD_TIMEOUT,
+        ) {
+            if let Some(mask) = parse_throttled(&output) {
+                return Ok(mask);
+            }
+        }
+        let content = fs::read_to_string(&self.throttled_file).map_err(|e| {
+            SensorError::Io(format!(
+                "cannot run {} nor read {}: {}",
+                self.vcgencmd, self.throttled_file, e
+            ))
+        })?;
+        parse_throttled(&content).ok_or_else(|| {
+            SensorError::Parse(format!("{} of {}.", content.trim(), self.throttled_file))
+        })
+    }
+}
+
+impl common::Sensor for RpiSensor {
+    fn get_names(&self) -> Vec<String> {
+        ["temperature", "throttled", "under_voltage_occurred"]
+            .iter()
+            .map(|metric| format!("{}_{}", self.name, metric))
+            .collect()
+    }
+
+    fn get_units(&self) -> Vec<String> {
+        vec!["°C".to_string(), String::new(), String::new()]
+    }
+
+    fn measure(&self) -> Result<Vec<Reading>, SensorError> {
+        let names = self.get_names();
+        let mut res = Vec::new();
+        let mut error = None;
+        match self.temperature() {
+            Ok(temperature) => res.push(Reading::new(names[0].clone(), temperature)),
+            Err(err) => error = Some(err),
+        }
+        match self.throttled() {
+            Ok(mask) => {
+                let bit = |flag| if mask & flag != 0 { 1.0 } else { 0.0 };
+                res.push(Reading::new(names[1].clone(), bit(THROTTLED)));
+                res.push(Reading::new(names[2].clone(), bit(UNDER_VOLTAGE_OCCURRED)));
+            }
+            Err(err) => error = Some(err),
+        }
+        match error {
+            Some(err) if res.is_empty() => Err(err),
+            Some(err) => {
+                eprintln!("Could not read all of {}: {}", self.name, err);
+                Ok(res)
+            }
+            None => Ok(res),
+        }
+    }
+
+    fn init(&mut self) -> Result<(), SensorError> {
+        // on other hardware, better to know right away.
+        let not_a_pi = |err: SensorError| {
+            SensorError::Io(format!("{}; is this a Raspberry Pi?", err.message()))
+        };
+        self.temperature().map_err(not_a_pi)?;
+        self.throttled().map_err(not_a_pi)?;
+        Ok(())
+    }
+}
+
+/// SoC temperature and throttling of a Raspberry Pi.
+pub(crate) fn sensor_type() -> common::SensorType {
+    common::SensorType {
+        name: "rpi",
+        required: &[],
+        optional: &["thermal_file", "vcgencmd", "throttled_file"],
+        create: |name, sensor_cfg, _| {
+            let string = |field: &str, default: &str| -> Result<String, ConfigError> {
+                match sensor_cfg.get(field) {
+                    Some(v) => v.as_str().map(|s| s.to_string()).ok_or_else(|| {
+                        ConfigError::Invalid(format!("{} must be a string.", field))
+                    }),
+                    None => Ok(default.to_string()),
+                }
+            };
+            Ok(Box::new(RpiSensor::new(
+                name.to_string(),
+                string("thermal_file", THERMAL_FILE)?,
+                string("vcgencmd", "vcgencmd")?,
+                string("throttled_file", THROTTLED_FILE)?,
+            )))
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+    use std::path;
+
+    use super::*;
+    use crate::common::values;
+    use crate::common::Sensor;
+
+    /// A directory with the temperature, the sysfs bitmask and a fake vcgencmd.
+    fn pi(dir: &str, temperature: &str, throttled: &str) -> RpiSensor {
+        fs::create_dir_all(dir).unwrap();
+        let file = |name: &str| path::Path::new(dir).join(name).display().to_string();
+        fs::write(file("temp"), format!("{}\n", temperature)).unwrap();
+        fs::write(file("get_throttled"), format!("{}\n", throttled)).unwrap();
+        fs::write(file("vcgencmd"), "#!/bin/sh\necho throttled=0x50005\n").unwrap();
+        fs::set_permissions(file("vcgencmd"), fs::Permissions::from_mode(0o755)).unwrap();
+        RpiSensor::new(
+            "pi".to_string(),
+            file("temp"),
+            file("vcgencmd"),
+            file("get_throttled"),
+        )
+    }
+
+    // Tests for success.
+
+    #[test]
+    fn test_parse_throttled_for_success() {
+        assert_eq!(parse_throttled("throttled=0x0\n"), Some(0));
+        assert_eq!(parse_throttled("throttled=0x50005\n"), Some(0x50005));
+        assert_eq!(parse_throttled("80000\n"), Some(0x80000));
+    }
+
+    #[test]
+    fn test_measure_for_success() {
+        let dir = "rpi_test0";
+        let mut sensor = pi(dir, "61835", "0");
+        sensor.init().unwrap();
+        // from vcgencmd: under-voltage and throttled now, under-voltage since boot.
+        assert_eq!(
+            values(sensor.measure()),
+            vec![
+                ("pi_temperature".to_string(), 61.835),
+                ("pi_throttled".to_string(), 1.0),
+                ("pi_under_voltage_occurred".to_string(), 1.0)
+            ]
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    // Tests for failure.
+
+    #[test]
+    fn test_parse_throttled_for_failure() {
+        assert_eq!(parse_throttled(""), None);
+        assert_eq!(parse_throttled("throttled=0xzz"), None);
+        assert_eq!(
+            parse_throttled("error=1 error_msg=\"Command not registered\""),
+            None
+        );
+    }
+
+    #[test]
+    fn test_init_for_failure() {
+        let mut sensor = RpiSensor::new(
+            "pi".to_string(),
+            "rpi_missing/temp".to_string(),
+            "rpi_missing/vcgencmd".to_string(),
+            "rpi_missing/get_throttled".to_string(),
+        );
+        match sensor.init() {
+            Err(SensorError::Io(msg)) => assert!(msg.ends_with("is this a Raspberry Pi?")),
+            _ => panic!("missing hardware not reported."),
+        }
+        assert!(matches!(sensor.measure(), Err(SensorError::Io(_))));
+    }
+
+    // Tests for sanity.
+
+    #[test]
+    fn test_fallback_for_sanity() {
+        let dir = "rpi_test1";
+        let mut sensor = pi(dir, "48000", "20002");
+        // w/o vcgencmd, e.g. on a minimal image.
+        sensor.vcgencmd = "rpi_missing/vcgencmd".to_string();
+        sensor.init().unwrap();
+        assert_eq!(
+            values(sensor.measure()),
+            vec![
+                ("pi_temperature".to_string(), 48.0),
+                ("pi_throttled".to_string(), 0.0),
+                ("pi_under_voltage_occurred".to_string(), 0.0)
+            ]
+        );
+        // the temperature alone is still reported.
+        fs::remove_file(path::Path::new(dir).join("get_throttled")).unwrap();
+        assert_eq!(values(sensor.measure()).len(), 1);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
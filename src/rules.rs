@@ -0,0 +1,325 @@
+use crate::common;
+use crate::interlock;
+
+/// The operators conditions can use.
+pub(crate) const OPERATORS: [&str; 4] = ["<", "<=", ">", ">="];
+
+/// Whether the value compares to the threshold as the operator says; never for unknown values.
+pub(crate) fn compare(operator: &str, value: f64, threshold: f64) -> bool {
+    match operator {
+        "<" => value < threshold,
+        "<=" => value <= threshold,
+        ">" => value > threshold,
+        _ => value >= threshold,
+    }
+}
+
+/// Switches an actuator on while a condition on a column holds, e.g. a heater while the PV produces more than 2 kW.
+pub(crate) struct Rule {
+    name: String,
+    column: String,
+    operator: String,
+    threshold: f64,
+    hysteresis: f64,
+    min_on: f64,
+    min_off: f64,
+    actuator: usize,
+    // the state of the actuator as far as known, and when the rule last switched it.
+    on: Option<bool>,
+    since: Option<f64>,
+}
+
+impl Rule {
+    /// Once on, the condition has to miss the threshold by more than the hysteresis to switch off again.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn new(
+        name: String,
+        column: String,
+        operator: String,
+        threshold: f64,
+        hysteresis: f64,
+        min_on: f64,
+        min_off: f64,
+        actuator: usize,
+    ) -> Rule {
+        if !OPERATORS.contains(&operator.as_str()) {
+            panic!("unknown operator in rule {}: {}.", name, operator);
+        }
+        if hysteresis < 0.0 || min_on < 0.0 || min_off < 0.0 {
+            panic!(
+                "hysteresis and minimum durations of rule {} must not be negative.",
+                name
+            );
+        }
+        Rule {
+            name,
+            column,
+            operator,
+            threshold,
+            hysteresis,
+            min_on,
+            min_off,
+            actuator,
+            on: None,
+            since: None,
+        }
+    }
+
+    fn holds(&self, value: f64, on: bool) -> bool {
+        let band = if on { self.hysteresis } else { 0.0 };
+        let threshold = if self.operator.starts_with('<') {
+            self.threshold + band
+        } else {
+            self.threshold - band
+        };
+        compare(&self.operator, value, threshold)
+    }
+
+    /// The state to switch to, if any; suspect data switches off right away, unknown values change nothing.
+    fn decide(&self, value: f64, suspect: bool, now: f64) -> Option<bool> {
+        let on = self.on.unwrap_or(false);
+        let wanted = if suspect {
+            false
+        } else if value.is_nan() {
+            return None;
+        } else {
+            self.holds(value, on)
+        };
+        if self.on == Some(wanted) {
+            return None;
+        }
+        let min = if on { self.min_on } else { self.min_off };
+        match self.since {
+            Some(since) if !suspect && now - since < min => None,
+            _ => Some(wanted),
+        }
+    }
+}
+
+/// Evaluates rules on the columns of each iteration and drives their actuators.
+///
+/// Each rule adds a column <rule>_on that is 1 while its actuator is on. Rows flagged as suspect by an interlock switch
+/// all actuators off.
+pub struct RulesComponent {
+    rules: Vec<Rule>,
+    actuators: Vec<(String, Box<dyn common::Actuator>)>,
+    // whether the states of the actuators were read yet.
+    synced: bool,
+}
+
+impl RulesComponent {
+    pub(crate) fn new(
+        rules: Vec<Rule>,
+        actuators: Vec<(String, Box<dyn common::Actuator>)>,
+    ) -> RulesComponent {
+        RulesComponent {
+            rules,
+            actuators,
+            synced: false,
+        }
+    }
+}
+
+impl common::Component for RulesComponent {
+    fn get_names(&self) -> Vec<String> {
+        self.rules
+            .iter()
+            .map(|r| format!("{}_on", r.name))
+            .collect()
+    }
+
+    fn update(&mut self, names: &[String], values: &[f64]) -> Vec<f64> {
+        let now = values[0];
+        let value_of = |column: &str| {
+            names
+                .iter()
+                .position(|n| n == column)
+                .map(|i| values[i])
+                .unwrap_or(f64::NAN)
+        };
+        let suspect = value_of(interlock::SUSPECT_COLUMN) == 1.0;
+        if !self.synced {
+            for rule in self.rules.iter_mut() {
+                let (name, actuator) = &self.actuators[rule.actuator];
+                match actuator.get_state() {
+                    Ok(on) => rule.on = Some(on),
+                    Err(err) => eprintln!("Could not get the state of {}: {}", name, err),
+                }
+            }
+            self.synced = true;
+        }
+        for rule in self.rules.iter_mut() {
+            let value = value_of(&rule.column);
+            let on = match rule.decide(value, suspect, now) {
+                Some(on) => on,
+                None => continue,
+            };
+            let (name, actuator) = &mut self.actuators[rule.actuator];
+            let state = if on { "on" } else { "off" };
+            match actuator.set_state(on) {
+                Ok(()) => {
+                    eprintln!(
+                        "Rule {}: {} is {}{}; switched {} {}.",
+                        rule.name,
+                        rule.column,
+                        value,
+                        if suspect { " (suspect)" } else { "" },
+                        name,
+                        state
+                    );
+                    rule.on = Some(on);
+                    rule.since = Some(now);
+                }
+                Err(err) => eprintln!(
+                    "Rule {}: could not switch {} {}: {}",
+                    rule.name, name, state, err
+                ),
+            }
+        }
+        self.rules
+            .iter()
+            .map(|r| if r.on == Some(true) { 1.0 } else { 0.0 })
+            .collect()
+    }
+
+    fn get_inputs(&self) -> Option<Vec<String>> {
+        let mut res: Vec<String> = self.rules.iter().map(|r| r.column.clone()).collect();
+        res.push(interlock::SUSPECT_COLUMN.to_string());
+        Some(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::common::{Component, SensorError};
+
+    /// Records the states it was switched to; fails while broken.
+    struct DummyActuator {
+        switched: Rc<RefCell<Vec<bool>>>,
+        broken: bool,
+    }
+
+    impl common::Actuator for DummyActuator {
+        fn set_state(&mut self, on: bool) -> Result<(), SensorError> {
+            if self.broken {
+                return Err(SensorError::Http(
+                    "Status code was not 200; but: 503.".to_string(),
+                ));
+            }
+            self.switched.borrow_mut().push(on);
+            Ok(())
+        }
+
+        fn get_state(&self) -> Result<bool, SensorError> {
+            Ok(self.switched.borrow().last().copied().unwrap_or(false))
+        }
+    }
+
+    fn names() -> Vec<String> {
+        vec![
+            "timestamp".to_string(),
+            "pv_power".to_string(),
+            interlock::SUSPECT_COLUMN.to_string(),
+        ]
+    }
+
+    fn heater(min_on: f64, min_off: f64, broken: bool) -> (RulesComponent, Rc<RefCell<Vec<bool>>>) {
+        let switched = Rc::new(RefCell::new(Vec::new()));
+        let rule = Rule::new(
+            "pv_heater".to_string(),
+            "pv_power".to_string(),
+            ">".to_string(),
+            2000.0,
+            500.0,
+            min_on,
+            min_off,
+            0,
+        );
+        let actuator: Box<dyn common::Actuator> = Box::new(DummyActuator {
+            switched: switched.clone(),
+            broken,
+        });
+        (
+            RulesComponent::new(vec![rule], vec![("heater".to_string(), actuator)]),
+            switched,
+        )
+    }
+
+    // Tests for success.
+
+    #[test]
+    fn test_update_for_success() {
+        let (mut rules, switched) = heater(0.0, 0.0, false);
+        assert_eq!(rules.get_names(), vec!["pv_heater_on"]);
+        // known to be off already.
+        assert_eq!(rules.update(&names(), &[1000.0, 1500.0, 0.0]), vec![0.0]);
+        assert_eq!(rules.update(&names(), &[1030.0, 2500.0, 0.0]), vec![1.0]);
+        assert_eq!(rules.update(&names(), &[1060.0, 1000.0, 0.0]), vec![0.0]);
+        assert_eq!(*switched.borrow(), vec![true, false]);
+    }
+
+    // Tests for failure.
+
+    #[test]
+    #[should_panic(expected = "unknown operator in rule pv_heater: ~.")]
+    fn test_new_for_failure() {
+        Rule::new(
+            "pv_heater".to_string(),
+            "pv_power".to_string(),
+            "~".to_string(),
+            2000.0,
+            0.0,
+            0.0,
+            0.0,
+            0,
+        );
+    }
+
+    #[test]
+    fn test_update_for_failure() {
+        // the switch failed; tried again next time.
+        let (mut rules, switched) = heater(0.0, 0.0, true);
+        assert_eq!(rules.update(&names(), &[1000.0, 2500.0, 0.0]), vec![0.0]);
+        assert!(switched.borrow().is_empty());
+        // unknown values change nothing.
+        let (mut rules, switched) = heater(0.0, 0.0, false);
+        rules.update(&names(), &[1000.0, 2500.0, 0.0]);
+        assert_eq!(rules.update(&names(), &[1030.0, f64::NAN, 0.0]), vec![1.0]);
+        assert_eq!(*switched.borrow(), vec![true]);
+    }
+
+    // Tests for sanity.
+
+    #[test]
+    fn test_hysteresis_for_sanity() {
+        let (mut rules, switched) = heater(0.0, 0.0, false);
+        // within the band: stays off, ...
+        assert_eq!(rules.update(&names(), &[1000.0, 1800.0, 0.0]), vec![0.0]);
+        assert_eq!(rules.update(&names(), &[1030.0, 2100.0, 0.0]), vec![1.0]);
+        // ... and on.
+        assert_eq!(rules.update(&names(), &[1060.0, 1800.0, 0.0]), vec![1.0]);
+        assert_eq!(rules.update(&names(), &[1090.0, 1600.0, 0.0]), vec![1.0]);
+        assert_eq!(rules.update(&names(), &[1120.0, 1500.0, 0.0]), vec![0.0]);
+        assert_eq!(rules.update(&names(), &[1150.0, 1800.0, 0.0]), vec![0.0]);
+        assert_eq!(*switched.borrow(), vec![true, false]);
+    }
+
+    #[test]
+    fn test_min_hold_for_sanity() {
+        let (mut rules, switched) = heater(300.0, 120.0, false);
+        assert_eq!(rules.update(&names(), &[1000.0, 2500.0, 0.0]), vec![1.0]);
+        // a passing cloud.
+        assert_eq!(rules.update(&names(), &[1030.0, 500.0, 0.0]), vec![1.0]);
+        assert_eq!(rules.update(&names(), &[1299.0, 500.0, 0.0]), vec![1.0]);
+        assert_eq!(rules.update(&names(), &[1300.0, 500.0, 0.0]), vec![0.0]);
+        assert_eq!(rules.update(&names(), &[1330.0, 2500.0, 0.0]), vec![0.0]);
+        assert_eq!(rules.update(&names(), &[1420.0, 2500.0, 0.0]), vec![1.0]);
+        // suspect data switches off regardless.
+        assert_eq!(rules.update(&names(), &[1450.0, 2500.0, 1.0]), vec![0.0]);
+        assert_eq!(*switched.borrow(), vec![true, false, true, false]);
+    }
+}
@@ -0,0 +1,498 @@
+use std::fs;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time;
+
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::sign::Signer;
+
+use crate::clock;
+use crate::output;
+use crate::retention;
+use crate::state;
+use crate::tz;
+
+/// Somewhere to put files; an S3 bucket, or anything else in tests.
+pub(crate) trait Store: Send {
+    fn put(&self, key: &str, body: Vec<u8>) -> Result<(), String>;
+}
+
+/// A bucket of an S3 compatible object storage (AWS, MinIO, ...); addressed path style and signed with SigV4.
+pub(crate) struct Bucket {
+    pub(crate) endpoint: String,
+    pub(crate) bucket: String,
+    pub(crate) region: String,
+    pub(crate) access_key: String,
+    pub(crate) secret_key: String,
+}
+
+impl Bucket {
+    /// The headers of a signed PUT of a body with the given hash at the given time.
+    fn sign(
+        &self,
+        key: &str,
+        hash: &str,
+        now: chrono::DateTime<chrono::Utc>,
+    ) -> Vec<(String, String)> {
+        let host = self
+            .endpoint
+            .split("://")
+            .last()
+            .unwrap_or_default()
+            .split('/')
+            .next()
+            .unwrap_or_default();
+        let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let date = now.format("%Y%m%d").to_string();
+        let scope = format!("{}/{}/s3/aws4_request", date, self.region);
+        let canonical = format!(
+            "PUT\n/{}/{}\n\nhost:{}\nx-amz-content-sha256:{}\nx-amz-date:{}\n\n\
+            host;x-amz-content-sha256;x-amz-date\n{}",
+            encode(&self.bucket),
+            encode(key),
+            host,
+            hash,
+            amz_date,
+            hash
+        );
+        let to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            amz_date,
+            scope,
+            hex(&openssl::sha::sha256(canonical.as_bytes()))
+        );
+        let mut signing_key = format!("AWS4{}", self.secret_key).into_bytes();
+        for part in [date.as_str(), self.region.as_str(), "s3", "aws4_request"] {
+            signing_key = hmac(&signing_key, part.as_bytes());
+        }
+        let signature = hex(&hmac(&signing_key, to_sign.as_bytes()));
+        vec![
+            ("x-amz-content-sha256".to_string(), hash.to_string()),
+            ("x-amz-date".to_string(), amz_date),
+            (
+                "Authorization".to_string(),
+                format!(
+                    "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders=host;x-amz-content-sha256;x-amz-date, \
+                    Signature={}",
+                    self.access_key, scope, signature
+                ),
+            ),
+        ]
+    }
+}
+
+impl Store for Bucket {
+    fn put(&self, key: &str, body: Vec<u8>) -> Result<(), String> {
+        let hash = hex(&openssl::sha::sha256(&body));
+        let client = reqwest::blocking::ClientBuilder::new()
+            // a day's file over a slow uplink.
+            .timeout(time::Duration::from_secs(600))
+            .build()
+            .map_err(|e| e.to_string())?;
+        let mut req = client.put(format!(
+            "{}/{}/{}",
+            self.endpoint.trim_end_matches('/'),
+            encode(&self.bucket),
+            encode(key)
+        ));
+        for (name, value) in self.sign(key, &hash, chrono::Utc::now()) {
+            req = req.header(name, value);
+        }
+        let res = req.body(body).send().map_err(|e| e.to_string())?;
+        let status = res.status().as_u16();
+        if !(200..300).contains(&status) {
+            return Err(format!("Status code was not 2xx; but: {}.", status));
+        }
+        Ok(())
+    }
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let key = PKey::hmac(key).expect("could not create an HMAC key.");
+    let mut signer =
+        Signer::new(MessageDigest::sha256(), &key).expect("could not create a signer.");
+    signer.sign_oneshot_to_vec(data).expect("could not sign.")
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// URI encodes a key as SigV4 expects it; slashes are kept.
+fn encode(key: &str) -> String {
+    key.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+/// Files named by a pattern like the filename of the CSV or Parquet output.
+pub(crate) struct Source {
+    pub(crate) pattern: String,
+    pub(crate) zone: tz::Zone,
+}
+
+impl Source {
+    /// The files rows no longer go to: of earlier days, or moved aside; compressed or not.
+    fn finished(&self, now: f64) -> Vec<String> {
+        let current = output::render(&self.pattern, self.zone, now);
+        // numbered files of the current day (as Parquet starts them) are still being written too.
+        let name = Path::new(&current)
+            .file_name()
+            .and_then(|n| n.to_str())
+            .unwrap_or_default()
+            .to_string();
+        let stem = match name.rfind('.') {
+            Some(i) if self.pattern.contains('%') => Some(&name[..i + 1]),
+            _ => None,
+        };
+        retention::rotated(&self.pattern, &current)
+            .into_iter()
+            .map(|(path, _, _)| path)
+            .filter(|path| {
+                let file = Path::new(path).file_name().and_then(|n| n.to_str());
+                stem.map(|s| !file.unwrap_or_default().starts_with(s))
+                    .unwrap_or(true)
+            })
+            .collect()
+    }
+}
+
+/// Uploads the finished files of the sources, which were not uploaded before, as <prefix><file name>.
+///
+/// Uploaded files are deleted, or - if delete is not set - remembered as uploaded. Files that could not be uploaded are
+/// left as they are, to be retried with the next pass; a PUT either stores a complete object or none.
+pub(crate) fn upload(
+    store: &dyn Store,
+    sources: &[Source],
+    prefix: &str,
+    delete: bool,
+    uploaded: &mut Vec<String>,
+    now: f64,
+) -> usize {
+    let mut count = 0;
+    for path in sources.iter().flat_map(|s| s.finished(now)) {
+        if uploaded.contains(&path) {
+            continue;
+        }
+        let file = Path::new(&path)
+            .file_name()
+            .and_then(|n| n.to_str())
+            .unwrap_or_default();
+        let key = format!("{}{}", prefix, file);
+        let res = fs::read(&path)
+            .map_err(|e| e.to_string())
+            .and_then(|body| store.put(&key, body));
+        if let Err(err) = res {
+            eprintln!("Could not upload {}; retrying later: {}", path, err);
+            continue;
+        }
+        count += 1;
+        if !delete {
+            eprintln!("Uploaded {} as {}.", path, key);
+            uploaded.push(path);
+        } else if let Err(err) = fs::remove_file(&path) {
+            eprintln!(
+                "Uploaded {} as {}, but could not delete it: {}",
+                path, key, err
+            );
+            uploaded.push(path);
+        } else {
+            eprintln!("Uploaded {} as {} and deleted it.", path, key);
+        }
+    }
+    // forget files that are gone, e.g. due to the retention limits.
+    uploaded.retain(|path| Path::new(path).exists());
+    count
+}
+
+/// Uploads finished files in the background, every interval.
+pub(crate) struct Uploader {
+    stop: Arc<AtomicBool>,
+    handle: Option<thread::JoinHandle<()>>,
+}
+
+impl Uploader {
+    /// Starts right away; which files were uploaded is kept in the state directory.
+    pub(crate) fn start(
+        store: Box<dyn Store>,
+        sources: Vec<Source>,
+        prefix: String,
+        delete: bool,
+        state_dir: String,
+        interval: time::Duration,
+    ) -> Uploader {
+        let stop = Arc::new(AtomicBool::new(false));
+        let handle = {
+            let stop = stop.clone();
+            thread::spawn(move || {
+                let mut uploaded: Vec<String> = state::load(&state_dir, "s3").unwrap_or_default();
+                while !stop.load(Ordering::Relaxed) {
+                    let now = clock::epoch_secs(time::SystemTime::now());
+                    let known = uploaded.len();
+                    let count = upload(
+                        store.as_ref(),
+                        &sources,
+                        &prefix,
+                        delete,
+                        &mut uploaded,
+                        now,
+                    );
+                    if count > 0 || uploaded.len() != known {
+                        if let Err(err) = state::save(&state_dir, "s3", &uploaded) {
+                            eprintln!("Could not save which files were uploaded: {}", err);
+                        }
+                    }
+                    let started = time::Instant::now();
+                    while started.elapsed() < interval && !stop.load(Ordering::Relaxed) {
+                        thread::sleep(time::Duration::from_millis(100));
+                    }
+                }
+            })
+        };
+        Uploader {
+            stop,
+            handle: Some(handle),
+        }
+    }
+
+    /// Stops after the current upload, if any.
+    pub(crate) fn stop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+impl Drop for Uploader {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+
+    /// The key and body of each object put.
+    type Objects = Arc<Mutex<Vec<(String, Vec<u8>)>>>;
+
+    struct RecordingStore {
+        objects: Objects,
+        fail: bool,
+    }
+
+    impl Store for RecordingStore {
+        fn put(&self, key: &str, body: Vec<u8>) -> Result<(), String> {
+            if self.fail {
+                return Err("connection reset".to_string());
+            }
+            self.objects.lock().unwrap().push((key.to_string(), body));
+            Ok(())
+        }
+    }
+
+    fn store(fail: bool) -> (RecordingStore, Objects) {
+        let objects = Arc::new(Mutex::new(Vec::new()));
+        (
+            RecordingStore {
+                objects: objects.clone(),
+                fail,
+            },
+            objects,
+        )
+    }
+
+    fn sources(pattern: &str) -> Vec<Source> {
+        vec![Source {
+            pattern: pattern.to_string(),
+            zone: tz::Zone::Utc,
+        }]
+    }
+
+    // Tests for success.
+
+    #[test]
+    fn test_put_for_success() {
+        let mut server = mockito::Server::new();
+        let mock = server
+            .mock("PUT", "/solar/site%201/data.csv")
+            .match_header(
+                "x-amz-content-sha256",
+                hex(&openssl::sha::sha256(b"timestamp\n")).as_str(),
+            )
+            .match_header(
+                "x-amz-date",
+                mockito::Matcher::Regex(r"^\d{8}T\d{6}Z$".to_string()),
+            )
+            .match_header(
+                "Authorization",
+                mockito::Matcher::Regex(
+                    "^AWS4-HMAC-SHA256 Credential=ogc/\\d{8}/eu-central-1/s3/aws4_request, \
+                    SignedHeaders=host;x-amz-content-sha256;x-amz-date, Signature=[0-9a-f]{64}$"
+                        .to_string(),
+                ),
+            )
+            .match_body("timestamp\n")
+            .with_status(200)
+            .create();
+        let bucket = Bucket {
+            endpoint: server.url(),
+            bucket: "solar".to_string(),
+            region: "eu-central-1".to_string(),
+            access_key: "ogc".to_string(),
+            secret_key: "secret".to_string(),
+        };
+        bucket
+            .put("site 1/data.csv", b"timestamp\n".to_vec())
+            .unwrap();
+        mock.assert();
+    }
+
+    // Tests for failure.
+
+    #[test]
+    fn test_upload_for_failure() {
+        fs::write("s3_test0-2023-11-13.csv", "timestamp\n1\n").unwrap();
+        let (failing, _) = store(true);
+        let mut uploaded = Vec::new();
+        // around midnight of 2023-11-14 UTC.
+        let now = 1699920030.0;
+        assert_eq!(
+            upload(
+                &failing,
+                &sources("s3_test0-%Y-%m-%d.csv"),
+                "",
+                true,
+                &mut uploaded,
+                now
+            ),
+            0
+        );
+        // kept for the next pass.
+        assert!(fs::metadata("s3_test0-2023-11-13.csv").is_ok());
+
+        let (working, objects) = store(false);
+        assert_eq!(
+            upload(
+                &working,
+                &sources("s3_test0-%Y-%m-%d.csv"),
+                "",
+                true,
+                &mut uploaded,
+                now
+            ),
+            1
+        );
+        assert!(fs::metadata("s3_test0-2023-11-13.csv").is_err());
+        assert_eq!(objects.lock().unwrap()[0].0, "s3_test0-2023-11-13.csv");
+    }
+
+    // Tests for sanity.
+
+    #[test]
+    fn test_upload_for_sanity() {
+        fs::write("s3_test1-2023-11-12.csv.gz", "gzipped").unwrap();
+        fs::write("s3_test1-2023-11-13.csv", "timestamp\n1\n").unwrap();
+        fs::write("s3_test1-2023-11-14.csv", "timestamp\n2\n").unwrap();
+        fs::write("s3_test1-2023-11-14.csv.gz.tmp", "").unwrap();
+        let (store, objects) = store(false);
+        let mut uploaded = Vec::new();
+        let now = 1699920030.0;
+        assert_eq!(
+            upload(
+                &store,
+                &sources("s3_test1-%Y-%m-%d.csv"),
+                "site1/",
+                false,
+                &mut uploaded,
+                now
+            ),
+            2
+        );
+        let mut keys: Vec<String> = objects
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|o| o.0.clone())
+            .collect();
+        keys.sort();
+        // the file of the day is still being written to.
+        assert_eq!(
+            keys,
+            vec![
+                "site1/s3_test1-2023-11-12.csv.gz",
+                "site1/s3_test1-2023-11-13.csv"
+            ]
+        );
+        // kept, but not uploaded again.
+        assert!(fs::metadata("s3_test1-2023-11-13.csv").is_ok());
+        assert_eq!(
+            upload(
+                &store,
+                &sources("s3_test1-%Y-%m-%d.csv"),
+                "site1/",
+                false,
+                &mut uploaded,
+                now
+            ),
+            0
+        );
+        assert_eq!(uploaded.len(), 2);
+
+        // forgotten once deleted.
+        fs::remove_file("s3_test1-2023-11-12.csv.gz").unwrap();
+        upload(
+            &store,
+            &sources("s3_test1-%Y-%m-%d.csv"),
+            "site1/",
+            false,
+            &mut uploaded,
+            now,
+        );
+        assert_eq!(uploaded.len(), 1);
+        for file in [
+            "s3_test1-2023-11-13.csv",
+            "s3_test1-2023-11-14.csv",
+            "s3_test1-2023-11-14.csv.gz.tmp",
+        ] {
+            fs::remove_file(file).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_finished_for_sanity() {
+        // a single file is never finished; the files moved aside are.
+        fs::write("s3_test2.csv", "timestamp\n").unwrap();
+        fs::write("s3_test2.csv.1699920000", "timestamp\n").unwrap();
+        assert_eq!(
+            sources("s3_test2.csv")[0].finished(1699920030.0),
+            vec!["./s3_test2.csv.1699920000"]
+        );
+
+        // numbered Parquet files of the day are not.
+        fs::write("s3_test3-2023-11-14.parquet", "").unwrap();
+        fs::write("s3_test3-2023-11-14.1.parquet", "").unwrap();
+        assert!(sources("s3_test3-%Y-%m-%d.parquet")[0]
+            .finished(1699920030.0)
+            .is_empty());
+        for file in [
+            "s3_test2.csv",
+            "s3_test2.csv.1699920000",
+            "s3_test3-2023-11-14.parquet",
+            "s3_test3-2023-11-14.1.parquet",
+        ] {
+            fs::remove_file(file).unwrap();
+        }
+    }
+}
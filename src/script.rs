@@ -0,0 +1,303 @@
+use std::error::Error;
+use std::fs;
+
+use rhai::module_resolvers::DummyModuleResolver;
+use rhai::{Array, Dynamic, Engine, Map, Scope, AST};
+
+use crate::common;
+use crate::common::{Reading, SensorError};
+
+/// Default budget of operations per script call.
+pub(crate) const MAX_OPERATIONS: u64 = 100_000;
+
+/// Creates a sandboxed engine: Rhai has no network access, modules cannot be imported from files, and each call is
+/// bounded.
+fn engine(max_operations: u64) -> Engine {
+    let mut engine = Engine::new();
+    engine.set_module_resolver(DummyModuleResolver::new());
+    engine.set_max_operations(max_operations);
+    engine.set_max_call_levels(32);
+    engine.disable_symbol("eval");
+    engine
+}
+
+/// Compiles a script given its source.
+pub(crate) fn compile_str(source: &str, max_operations: u64) -> Result<(Engine, AST), String> {
+    let engine = engine(max_operations);
+    let ast = engine.compile(source).map_err(|err| err.to_string())?;
+    Ok((engine, ast))
+}
+
+/// Compiles the script at the given path.
+pub(crate) fn compile(path: &str, max_operations: u64) -> Result<(Engine, AST), String> {
+    let source =
+        fs::read_to_string(path).map_err(|err| format!("could not read {}: {}", path, err))?;
+    compile_str(&source, max_operations)
+        .map_err(|err| format!("could not compile {}: {}", path, err))
+}
+
+fn to_floats(values: Array) -> Result<Vec<f64>, String> {
+    values
+        .into_iter()
+        .map(|v| {
+            v.as_float()
+                .or_else(|_| v.as_int().map(|i| i as f64))
+                .map_err(|t| format!("expected a number, got: {}.", t))
+        })
+        .collect()
+}
+
+/// Runs the transform function of a script on the values of the wrapped sensor.
+///
+/// The script must define `fn transform(values)` returning as many values as it was given.
+pub struct TransformSensor {
+    inner: Box<dyn common::Sensor>,
+    engine: Engine,
+    ast: AST,
+}
+
+impl TransformSensor {
+    pub(crate) fn new(inner: Box<dyn common::Sensor>, engine: Engine, ast: AST) -> TransformSensor {
+        TransformSensor { inner, engine, ast }
+    }
+
+    fn transform(&self, values: Vec<f64>) -> Result<Vec<f64>, SensorError> {
+        let len = values.len();
+        let args: Array = values.into_iter().map(Dynamic::from_float).collect();
+        let res = self
+            .engine
+            .call_fn::<Array>(&mut Scope::new(), &self.ast, "transform", (args,))
+            .map_err(|err| err.to_string())
+            .and_then(to_floats);
+        match res {
+            Ok(res) if res.len() == len => Ok(res),
+            Ok(res) => Err(SensorError::Protocol(format!(
+                "transform returned {} values instead of {}.",
+                res.len(),
+                len
+            ))),
+            Err(err) => Err(SensorError::Protocol(format!("transform failed: {}", err))),
+        }
+    }
+}
+
+impl common::Sensor for TransformSensor {
+    fn get_names(&self) -> Vec<String> {
+        self.inner.get_names()
+    }
+
+    fn get_units(&self) -> Vec<String> {
+        self.inner.get_units()
+    }
+
+    fn init(&mut self) -> Result<(), SensorError> {
+        self.inner.init()
+    }
+
+    fn shutdown(&mut self) {
+        self.inner.shutdown()
+    }
+
+    /// The script sees the values in the order of the names; missing ones as NaN.
+    fn measure(&self) -> Result<Vec<Reading>, SensorError> {
+        let names = self.get_names();
+        let readings = self.inner.measure()?;
+        let quality = readings.iter().fold(0, |q, r| q | r.quality);
+        let (values, times) = common::align(&names, &readings);
+        let values = self.transform(values)?;
+        Ok(names
+            .into_iter()
+            .zip(values)
+            .zip(times)
+            .map(|((name, value), time)| Reading {
+                name,
+                value,
+                time,
+                quality,
+            })
+            .collect())
+    }
+
+    fn validate(&self) -> Result<(), Box<dyn Error>> {
+        self.inner.validate()
+    }
+}
+
+/// Adds columns computed by a script from the row so far.
+///
+/// The script must define `fn columns()` returning the names of the columns it adds, and
+/// `fn row(row)` which is given a map of the values by column name and returns the new values.
+pub struct ScriptComponent {
+    names: Vec<String>,
+    engine: Engine,
+    ast: AST,
+}
+
+impl ScriptComponent {
+    pub(crate) fn new(engine: Engine, ast: AST) -> Result<ScriptComponent, String> {
+        let names = engine
+            .call_fn::<Array>(&mut Scope::new(), &ast, "columns", ())
+            .map_err(|err| err.to_string())?
+            .into_iter()
+            .map(|v| {
+                v.into_string()
+                    .map_err(|t| format!("expected a column name, got: {}.", t))
+            })
+            .collect::<Result<Vec<String>, String>>()?;
+        Ok(ScriptComponent { names, engine, ast })
+    }
+}
+
+impl common::Component for ScriptComponent {
+    fn get_names(&self) -> Vec<String> {
+        self.names.clone()
+    }
+
+    fn update(&mut self, names: &[String], values: &[f64]) -> Vec<f64> {
+        let row: Map = names
+            .iter()
+            .zip(values)
+            .map(|(n, v)| (n.as_str().into(), Dynamic::from_float(*v)))
+            .collect();
+        let res = self
+            .engine
+            .call_fn::<Array>(&mut Scope::new(), &self.ast, "row", (row,))
+            .map_err(|err| err.to_string())
+            .and_then(to_floats);
+        match res {
+            Ok(res) if res.len() == self.names.len() => res,
+            Ok(res) => {
+                eprintln!(
+                    "Row script returned {} values instead of {}.",
+                    res.len(),
+                    self.names.len()
+                );
+                vec![f64::NAN; self.names.len()]
+            }
+            Err(err) => {
+                eprintln!("Row script failed: {}", err);
+                vec![f64::NAN; self.names.len()]
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::{Component, Sensor};
+
+    struct DummySensor {}
+
+    impl common::Sensor for DummySensor {
+        fn get_names(&self) -> Vec<String> {
+            vec!["dummy_power".to_string(), "dummy_flags".to_string()]
+        }
+
+        fn get_units(&self) -> Vec<String> {
+            vec!["W".to_string(), "".to_string()]
+        }
+
+        fn measure(&self) -> Result<Vec<Reading>, SensorError> {
+            Ok(vec![Reading {
+                name: "dummy_power".to_string(),
+                value: 1.5,
+                time: Some(960.0),
+                quality: 0,
+            }])
+        }
+    }
+
+    const TRANSFORM: &str = "fn transform(values) { [values[0] * 1000.0, values[1]] }";
+    const ROW: &str = "fn columns() { [\"heater_allowed\", \"surplus\"] }\n\
+    fn row(row) {\n\
+        let surplus = row.pv_power - row.load;\n\
+        [if surplus > 2000.0 { 1 } else { 0 }, surplus]\n\
+    }";
+
+    fn transform(source: &str) -> TransformSensor {
+        let (engine, ast) = compile_str(source, MAX_OPERATIONS).unwrap();
+        TransformSensor::new(Box::new(DummySensor {}), engine, ast)
+    }
+
+    // Tests for success.
+
+    #[test]
+    fn test_compile_for_success() {
+        compile_str(TRANSFORM, MAX_OPERATIONS).unwrap();
+        compile_str(ROW, MAX_OPERATIONS).unwrap();
+    }
+
+    // Tests for failure.
+
+    #[test]
+    fn test_compile_for_failure() {
+        assert!(compile_str("fn transform(values) {", MAX_OPERATIONS).is_err());
+        assert!(compile_str("eval(\"1\")", MAX_OPERATIONS).is_err());
+        assert!(compile("script_missing.rhai", MAX_OPERATIONS).is_err());
+    }
+
+    #[test]
+    fn test_transform_for_failure() {
+        // exceeds its budget.
+        let sensor = transform("fn transform(values) { loop {} }");
+        assert!(matches!(sensor.measure(), Err(SensorError::Protocol(_))));
+        // wrong number of values.
+        let sensor = transform("fn transform(values) { [1.0] }");
+        assert!(matches!(sensor.measure(), Err(SensorError::Protocol(_))));
+        // wrong types.
+        let sensor = transform("fn transform(values) { [\"a\", 1.0] }");
+        assert!(matches!(sensor.measure(), Err(SensorError::Protocol(_))));
+        // no modules from files; not even existing ones.
+        fs::write("script_test_module.rhai", "fn scale() { 1000.0 }").unwrap();
+        let sensor = transform(
+            "fn transform(values) { import \"script_test_module\" as m; [values[0] * m::scale(), values[1]] }",
+        );
+        let res = sensor.measure();
+        fs::remove_file("script_test_module.rhai").unwrap();
+        assert!(matches!(res, Err(SensorError::Protocol(_))));
+    }
+
+    #[test]
+    fn test_script_component_for_failure() {
+        let (engine, ast) = compile_str("fn row(row) { [] }", MAX_OPERATIONS).unwrap();
+        assert!(ScriptComponent::new(engine, ast).is_err());
+
+        let (engine, ast) = compile_str(ROW, MAX_OPERATIONS).unwrap();
+        let mut comp = ScriptComponent::new(engine, ast).unwrap();
+        // missing columns.
+        let res = comp.update(&["timestamp".to_string()], &[0.0]);
+        assert!(res.iter().all(|v| v.is_nan()));
+    }
+
+    // Tests for sanity.
+
+    #[test]
+    fn test_transform_for_sanity() {
+        let sensor = transform(TRANSFORM);
+        assert_eq!(sensor.get_names(), vec!["dummy_power", "dummy_flags"]);
+        let readings = sensor.measure().unwrap();
+        // the missing flags are NaN to the script; their time is kept.
+        assert_eq!(readings[0].name, "dummy_power");
+        assert_eq!(readings[0].value, 1500.0);
+        assert_eq!(readings[0].time, Some(960.0));
+        assert!(readings[1].value.is_nan());
+        assert_eq!(readings[1].time, None);
+    }
+
+    #[test]
+    fn test_script_component_for_sanity() {
+        let (engine, ast) = compile_str(ROW, MAX_OPERATIONS).unwrap();
+        let mut comp = ScriptComponent::new(engine, ast).unwrap();
+        assert_eq!(comp.get_names(), vec!["heater_allowed", "surplus"]);
+        let names: Vec<String> = vec!["timestamp", "pv_power", "load"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+        assert_eq!(
+            comp.update(&names, &[0.0, 3000.0, 500.0]),
+            vec![1.0, 2500.0]
+        );
+        assert_eq!(comp.update(&names, &[0.0, 1000.0, 500.0]), vec![0.0, 500.0]);
+    }
+}
@@ -0,0 +1,259 @@
+use std::fs;
+use std::path;
+
+use crate::common;
+
+const NAMES: [&str; 3] = [
+    "ogc_self_power_w",
+    "ogc_self_energy_wh",
+    "ogc_self_estimate",
+];
+
+/// CPU time (in seconds) spent by a process given the contents of its /proc/<pid>/stat file.
+pub(crate) fn parse_process_cpu(stat: &str, ticks: f64) -> Option<f64> {
+    // the command name can contain spaces & brackets; fields after the last ')' start at state.
+    let fields: Vec<&str> = stat[stat.rfind(')')? + 1..].split_whitespace().collect();
+    // utime and stime are fields 14 and 15 of the whole line.
+    let utime: f64 = fields.get(11)?.parse().ok()?;
+    let stime: f64 = fields.get(12)?.parse().ok()?;
+    Some((utime + stime) / ticks)
+}
+
+/// Busy CPU time (in seconds) of the whole host given the contents of /proc/stat.
+pub(crate) fn parse_busy_cpu(stat: &str, ticks: f64) -> Option<f64> {
+    let line = stat.lines().find(|l| l.starts_with("cpu "))?;
+    let values: Vec<f64> = line
+        .split_whitespace()
+        .skip(1)
+        .map(|v| v.parse())
+        .collect::<Result<_, _>>()
+        .ok()?;
+    if values.len() < 5 {
+        return None;
+    }
+    // idle & iowait are not busy.
+    let total: f64 = values.iter().sum();
+    Some((total - values[3] - values[4]) / ticks)
+}
+
+/// Share of the host's energy (in J) attributable to the process given both CPU time deltas.
+pub(crate) fn apportion(host_energy: f64, process_cpu: f64, busy_cpu: f64) -> f64 {
+    if busy_cpu <= 0.0 {
+        return 0.0;
+    }
+    host_energy * (process_cpu / busy_cpu).min(1.0)
+}
+
+/// Estimates the energy used by the collector itself.
+///
+/// With a host power column (e.g. from RAPL) configured, its energy is apportioned by the
+/// process' share of the busy CPU time; otherwise a fixed factor per CPU second is used, which is
+/// flagged in the estimate column.
+pub struct SelfEnergyComponent {
+    proc_dir: String,
+    joules_per_cpu_sec: f64,
+    power_column: Option<String>,
+    ticks: f64,
+    last: Option<(f64, f64, f64)>,
+    energy_wh: f64,
+}
+
+impl SelfEnergyComponent {
+    pub fn new(
+        proc_dir: String,
+        joules_per_cpu_sec: f64,
+        power_column: Option<String>,
+        ticks: f64,
+    ) -> SelfEnergyComponent {
+        if power_column.is_none() {
+            eprintln!(
+                "Self energy is estimated using {} J per CPU second.",
+                joules_per_cpu_sec
+            );
+        }
+        SelfEnergyComponent {
+            proc_dir,
+            joules_per_cpu_sec,
+            power_column,
+            ticks,
+            last: None,
+            energy_wh: 0.0,
+        }
+    }
+
+    fn read_cpu(&self) -> Option<(f64, f64)> {
+        let dir = path::Path::new(&self.proc_dir);
+        let process = fs::read_to_string(dir.join("self").join("stat")).ok()?;
+        let host = fs::read_to_string(dir.join("stat")).ok()?;
+        Some((
+            parse_process_cpu(&process, self.ticks)?,
+            parse_busy_cpu(&host, self.ticks)?,
+        ))
+    }
+}
+
+impl common::Component for SelfEnergyComponent {
+    fn get_names(&self) -> Vec<String> {
+        NAMES.iter().map(|n| n.to_string()).collect()
+    }
+
+    fn update(&mut self, names: &[String], values: &[f64]) -> Vec<f64> {
+        let now = values[0];
+        let host_power = match &self.power_column {
+            Some(column) => match names.iter().position(|n| n == column) {
+                Some(i) => values[i],
+                None => f64::NAN,
+            },
+            None => f64::NAN,
+        };
+        let (process, busy) = match self.read_cpu() {
+            Some(cpu) => cpu,
+            None => {
+                eprintln!("Could not read CPU times for self energy.");
+                return vec![f64::NAN, self.energy_wh, 1.0];
+            }
+        };
+
+        let mut res = vec![f64::NAN, self.energy_wh, 1.0];
+        if let Some((t0, process0, busy0)) = self.last {
+            let dt = now - t0;
+            if dt > 0.0 {
+                let (energy, estimate) = if host_power.is_nan() || host_power < 0.0 {
+                    ((process - process0) * self.joules_per_cpu_sec, 1.0)
+                } else {
+                    (
+                        apportion(host_power * dt, process - process0, busy - busy0),
+                        0.0,
+                    )
+                };
+                self.energy_wh += energy / 3600.0;
+                res = vec![energy / dt, self.energy_wh, estimate];
+            }
+        }
+        self.last = Some((now, process, busy));
+        res
+    }
+
+    fn get_inputs(&self) -> Option<Vec<String>> {
+        Some(self.power_column.iter().cloned().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::Component;
+
+    const SELF_STAT: &str = "4242 (open green (compute)) S 1 4242 4242 0 -1 4194560 1234 0 0 0 \
+    150 50 0 0 20 0 3 0 12345 123456789 1234 18446744073709551615 1 1 0 0 0 0 0 4096 0 0 0 0 17 \
+    2 0 0 0 0 0";
+    const HOST_STAT: &str = "cpu  1000 0 500 8000 500 0 0 0 0 0\n\
+    cpu0 500 0 250 4000 250 0 0 0 0 0\n\
+    intr 12345\n";
+
+    fn setup(dir: &str, process: (u64, u64), host: (u64, u64)) {
+        fs::create_dir_all(format!("{}/self", dir)).unwrap();
+        fs::write(
+            format!("{}/self/stat", dir),
+            format!(
+                "1 (ogc) S 1 1 1 0 -1 0 0 0 0 0 {} {} 0 0 20 0 1 0 0 0 0",
+                process.0, process.1
+            ),
+        )
+        .unwrap();
+        fs::write(
+            format!("{}/stat", dir),
+            format!("cpu  {} 0 {} 5000 100 0 0 0 0 0\n", host.0, host.1),
+        )
+        .unwrap();
+    }
+
+    // Tests for success.
+
+    #[test]
+    fn test_parse_for_success() {
+        parse_process_cpu(SELF_STAT, 100.0).unwrap();
+        parse_busy_cpu(HOST_STAT, 100.0).unwrap();
+    }
+
+    // Tests for failure.
+
+    #[test]
+    fn test_parse_for_failure() {
+        assert_eq!(parse_process_cpu("4242 (ogc S 1", 100.0), None);
+        assert_eq!(parse_process_cpu("4242 (ogc) S 1 2 3", 100.0), None);
+        assert_eq!(parse_busy_cpu("intr 1234\n", 100.0), None);
+        assert_eq!(parse_busy_cpu("cpu  1 2 x 4 5\n", 100.0), None);
+    }
+
+    #[test]
+    fn test_update_for_failure() {
+        let mut comp = SelfEnergyComponent::new("self_energy_test0".to_string(), 10.0, None, 100.0);
+        let res = comp.update(&["timestamp".to_string()], &[0.0]);
+        assert!(res[0].is_nan());
+        assert_eq!(res[1..], [0.0, 1.0]);
+    }
+
+    // Tests for sanity.
+
+    #[test]
+    fn test_get_names_for_sanity() {
+        let comp = SelfEnergyComponent::new("/proc".to_string(), 10.0, None, 100.0);
+        assert_eq!(
+            comp.get_names(),
+            vec![
+                "ogc_self_power_w",
+                "ogc_self_energy_wh",
+                "ogc_self_estimate"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_for_sanity() {
+        assert_eq!(parse_process_cpu(SELF_STAT, 100.0), Some(2.0));
+        assert_eq!(parse_busy_cpu(HOST_STAT, 100.0), Some(15.0));
+    }
+
+    #[test]
+    fn test_apportion_for_sanity() {
+        // 10 W for 60 s; we used 1.5 s of the 6 s the CPUs were busy.
+        assert_eq!(apportion(600.0, 1.5, 6.0), 150.0);
+        assert_eq!(apportion(600.0, 1.5, 0.0), 0.0);
+        // can't use more than the host did.
+        assert_eq!(apportion(600.0, 2.0, 1.0), 600.0);
+    }
+
+    #[test]
+    fn test_update_for_sanity() {
+        let names = vec!["timestamp".to_string(), "rapl_package".to_string()];
+
+        // configured factor.
+        setup("self_energy_test1", (100, 100), (1000, 1000));
+        let mut comp = SelfEnergyComponent::new("self_energy_test1".to_string(), 10.0, None, 100.0);
+        comp.update(&names, &[0.0, 20.0]);
+        // 0.6 s CPU in 60 s at 10 J per CPU second -> 6 J -> 0.1 W.
+        setup("self_energy_test1", (130, 130), (1300, 1300));
+        let res = comp.update(&names, &[60.0, 20.0]);
+        assert!((res[0] - 0.1).abs() < 1e-9);
+        assert!((res[1] - 6.0 / 3600.0).abs() < 1e-9);
+        assert_eq!(res[2], 1.0);
+        fs::remove_dir_all("self_energy_test1").unwrap();
+
+        // apportioned host energy: 20 W for 60 s, we got 0.6 s of the 6 s busy CPU time.
+        setup("self_energy_test2", (100, 100), (1000, 1000));
+        let mut comp = SelfEnergyComponent::new(
+            "self_energy_test2".to_string(),
+            10.0,
+            Some("rapl_package".to_string()),
+            100.0,
+        );
+        comp.update(&names, &[0.0, 20.0]);
+        setup("self_energy_test2", (130, 130), (1300, 1300));
+        let res = comp.update(&names, &[60.0, 20.0]);
+        assert!((res[0] - 2.0).abs() < 1e-9);
+        assert!((res[1] - 120.0 / 3600.0).abs() < 1e-9);
+        assert_eq!(res[2], 0.0);
+        fs::remove_dir_all("self_energy_test2").unwrap();
+    }
+}
@@ -0,0 +1,987 @@
+use std::io::Read;
+use std::sync::{Arc, Mutex};
+use std::time;
+
+use crate::common;
+use crate::common::{ConfigError, Reading, SensorError};
+use crate::debug;
+
+const METRICS: [&str; 4] = ["power", "energy", "on", "temperature"];
+const UNITS: [&str; 4] = ["W", "Wh", "", "°C"];
+
+/// The URL of a device given its host, e.g. 192.168.178.30, or its URL.
+pub(crate) fn base_url(host: &str) -> String {
+    let host = host.trim_end_matches('/');
+    if host.starts_with("http://") || host.starts_with("https://") {
+        host.to_string()
+    } else {
+        format!("http://{}", host)
+    }
+}
+
+/// The power, energy, relay state and temperature of a channel given the body of a Gen1 /status; each if present.
+///
+/// The energy counter of Gen1 devices is in Wmin.
+pub(crate) fn parse_status(
+    body: &serde_json::Value,
+    channel: usize,
+) -> Result<[Option<f64>; 4], String> {
+    let object = body.as_object().ok_or("not an object.")?;
+    if !object.contains_key("meters") && !object.contains_key("relays") {
+        if object.contains_key("sys") || object.keys().any(|k| k.starts_with("switch:")) {
+            return Err("this is a Gen2 device; use the shelly_rpc type.".to_string());
+        }
+        return Err("no meters or relays.".to_string());
+    }
+    let meter = &body["meters"][channel];
+    let on = body["relays"][channel]["ison"]
+        .as_bool()
+        .map(|on| if on { 1.0 } else { 0.0 });
+    let temperature = body["temperature"]
+        .as_f64()
+        .or_else(|| body["tmp"]["tC"].as_f64());
+    Ok([
+        meter["power"].as_f64(),
+        meter["total"].as_f64().map(|wmin| wmin / 60.0),
+        on,
+        temperature,
+    ])
+}
+
+/// Reads a Gen1 Shelly plug or relay, e.g. a Plug S or 1PM, via its /status endpoint.
+pub struct ShellySensor {
+    name: String,
+    url: String,
+    channel: usize,
+    auth: Option<(String, Option<String>)>,
+    client: reqwest::blocking::Client,
+    ring: Arc<debug::Ring>,
+}
+
+impl ShellySensor {
+    pub(crate) fn new(
+        name: String,
+        url: String,
+        channel: usize,
+        auth: Option<(String, Option<String>)>,
+        timeout: time::Duration,
+    ) -> ShellySensor {
+        ShellySensor {
+            ring: debug::ring(&name),
+            name,
+            url,
+            channel,
+            auth,
+            client: reqwest::blocking::Client::builder()
+                .timeout(timeout)
+                .build()
+                .unwrap(),
+        }
+    }
+
+    fn get_status(&self) -> Result<serde_json::Value, SensorError> {
+        let query = format!("{}/status", self.url);
+        let mut request = self.client.get(&query);
+        if let Some((username, password)) = &self.auth {
+            request = request.basic_auth(username, password.as_ref());
+        }
+        let started = time::Instant::now();
+        let mut res = match request.send() {
+            Ok(res) => res,
+            Err(err) => {
+                self.ring.record(&query, None, &err.to_string(), started);
+                return Err(SensorError::from(err));
+            }
+        };
+        let mut body: String = String::new();
+        res.read_to_string(&mut body)?;
+        self.ring
+            .record(&query, Some(res.status().as_u16()), &body, started);
+        match res.status().as_u16() {
+            200 => serde_json::from_str(&body).map_err(|e| SensorError::Parse(e.to_string())),
+            404 => Err(SensorError::Http(
+                "Status code was not 200; but: 404 Not Found - a Gen2 device? Use the shelly_rpc type."
+                    .to_string(),
+            )),
+            _ => Err(SensorError::Http(format!(
+                "Status code was not 200; but: {}.",
+                res.status()
+            ))),
+        }
+    }
+}
+
+impl common::Sensor for ShellySensor {
+    fn get_names(&self) -> Vec<String> {
+        METRICS
+            .iter()
+            .map(|metric| format!("{}_{}", self.name, metric))
+            .collect()
+    }
+
+    fn get_units(&self) -> Vec<String> {
+        UNITS.iter().map(|u| u.to_string()).collect()
+    }
+
+    fn measure(&self) -> Result<Vec<Reading>, SensorError> {
+        let body = self.get_status()?;
+        let values = parse_status(&body, self.channel).map_err(SensorError::Protocol)?;
+        Ok(self
+            .get_names()
+            .into_iter()
+            .zip(values)
+            .filter_map(|(name, value)| value.map(|v| Reading::new(name, v)))
+            .collect())
+    }
+}
+
+const RPC_METRICS: [&str; 5] = ["power", "voltage", "current", "energy", "temperature"];
+const RPC_UNITS: [&str; 5] = ["W", "V", "A", "Wh", "°C"];
+
+/// The user Gen2 devices authenticate; it is fixed.
+const RPC_USER: &str = "admin";
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn sha256(text: &str) -> String {
+    hex(&openssl::sha::sha256(text.as_bytes()))
+}
+
+/// A digest challenge as sent by Gen2 devices along with a 401.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Challenge {
+    realm: String,
+    nonce: String,
+    // the requests made with the nonce so far.
+    count: u32,
+}
+
+impl Challenge {
+    /// Parses a header like 'Digest qop="auth", realm="shellyplus1pm-a8032ab12345", nonce="60dc59c6",
+    /// algorithm=SHA-256'; only SHA-256 is supported.
+    pub(crate) fn parse(header: &str) -> Result<Challenge, String> {
+        let params = header
+            .strip_prefix("Digest ")
+            .ok_or_else(|| format!("not a digest challenge: {}.", header))?;
+        let param = |key: &str| {
+            params.split(',').find_map(|p| {
+                let (k, v) = p.trim().split_once('=')?;
+                (k == key).then(|| v.trim_matches('"').to_string())
+            })
+        };
+        match param("algorithm") {
+            Some(algorithm) if algorithm == "SHA-256" => {}
+            other => {
+                return Err(format!(
+                    "algorithm must be SHA-256; got: {}.",
+                    other.unwrap_or_else(|| "none".to_string())
+                ))
+            }
+        }
+        match (param("realm"), param("nonce")) {
+            (Some(realm), Some(nonce)) => Ok(Challenge {
+                realm,
+                nonce,
+                count: 0,
+            }),
+            _ => Err(format!("realm or nonce missing in: {}.", header)),
+        }
+    }
+
+    /// The authorization header for a request; counts it.
+    pub(crate) fn authorize(&mut self, password: &str, method: &str, uri: &str) -> String {
+        self.count += 1;
+        let mut cnonce = [0u8; 8];
+        openssl::rand::rand_bytes(&mut cnonce).expect("no randomness for the cnonce.");
+        self.authorization(RPC_USER, password, method, uri, &hex(&cnonce))
+    }
+
+    fn authorization(
+        &self,
+        user: &str,
+        password: &str,
+        method: &str,
+        uri: &str,
+        cnonce: &str,
+    ) -> String {
+        let ha1 = sha256(&format!("{}:{}:{}", user, self.realm, password));
+        let ha2 = sha256(&format!("{}:{}", method, uri));
+        let nc = format!("{:08x}", self.count);
+        let response = sha256(&format!(
+            "{}:{}:{}:{}:auth:{}",
+            ha1, self.nonce, nc, cnonce, ha2
+        ));
+        format!(
+            "Digest username=\"{}\", realm=\"{}\", nonce=\"{}\", uri=\"{}\", algorithm=SHA-256, \
+             response=\"{}\", qop=auth, nc={}, cnonce=\"{}\"",
+            user, self.realm, self.nonce, uri, response, nc, cnonce
+        )
+    }
+}
+
+/// The power, voltage, current, energy and temperature given the body of Switch.GetStatus; each if present.
+pub(crate) fn parse_switch_status(body: &serde_json::Value) -> [Option<f64>; 5] {
+    [
+        body["apower"].as_f64(),
+        body["voltage"].as_f64(),
+        body["current"].as_f64(),
+        body["aenergy"]["total"].as_f64(),
+        body["temperature"]["tC"].as_f64(),
+    ]
+}
+
+/// Reads the switches of a Gen2 Shelly device, e.g. a Plus Plug S or Pro 2PM, via its RPC API.
+///
+/// With a password set, the device asks for digest authentication; its challenge is reused until it sends a new one.
+pub struct ShellyRpcSensor {
+    name: String,
+    url: String,
+    password: Option<String>,
+    ids: Vec<u32>,
+    challenge: Mutex<Option<Challenge>>,
+    client: reqwest::blocking::Client,
+    ring: Arc<debug::Ring>,
+}
+
+impl ShellyRpcSensor {
+    pub(crate) fn new(
+        name: String,
+        url: String,
+        password: Option<String>,
+        ids: Vec<u32>,
+        timeout: time::Duration,
+    ) -> ShellyRpcSensor {
+        ShellyRpcSensor {
+            ring: debug::ring(&name),
+            name,
+            url,
+            password,
+            ids,
+            challenge: Mutex::new(None),
+            client: reqwest::blocking::Client::builder()
+                .timeout(timeout)
+                .build()
+                .unwrap(),
+        }
+    }
+
+    fn send(&self, uri: &str) -> Result<reqwest::blocking::Response, SensorError> {
+        let query = format!("{}{}", self.url, uri);
+        let mut request = self.client.get(&query);
+        if let (Some(password), Some(challenge)) =
+            (&self.password, self.challenge.lock().unwrap().as_mut())
+        {
+            request = request.header(
+                reqwest::header::AUTHORIZATION,
+                challenge.authorize(password, "GET", uri),
+            );
+        }
+        let started = time::Instant::now();
+        request.send().map_err(|err| {
+            self.ring.record(&query, None, &err.to_string(), started);
+            SensorError::from(err)
+        })
+    }
+
+    /// GETs an RPC method; answers a challenge once.
+    fn get(&self, uri: &str) -> Result<serde_json::Value, SensorError> {
+        let mut res = self.send(uri)?;
+        if res.status() == 401 && self.password.is_some() {
+            let header = res
+                .headers()
+                .get(reqwest::header::WWW_AUTHENTICATE)
+                .and_then(|h| h.to_str().ok())
+                .ok_or_else(|| SensorError::Protocol("401 without a challenge.".to_string()))?;
+            let challenge = Challenge::parse(header).map_err(SensorError::Protocol)?;
+            *self.challenge.lock().unwrap() = Some(challenge);
+            res = self.send(uri)?;
+        }
+        let started = time::Instant::now();
+        let mut body: String = String::new();
+        res.read_to_string(&mut body)?;
+        self.ring.record(
+            &format!("{}{}", self.url, uri),
+            Some(res.status().as_u16()),
+            &body,
+            started,
+        );
+        match res.status().as_u16() {
+            200 => serde_json::from_str(&body).map_err(|e| SensorError::Parse(e.to_string())),
+            401 if self.password.is_none() => Err(SensorError::Http(
+                "Status code was not 200; but: 401 Unauthorized - is the password set?".to_string(),
+            )),
+            _ => Err(SensorError::Http(format!(
+                "Status code was not 200; but: {}.",
+                res.status()
+            ))),
+        }
+    }
+}
+
+impl common::Sensor for ShellyRpcSensor {
+    fn get_names(&self) -> Vec<String> {
+        self.ids
+            .iter()
+            .flat_map(|id| {
+                RPC_METRICS.iter().map(move |metric| {
+                    // a switch of its own only on devices with several.
+                    if self.ids.len() > 1 {
+                        format!("{}_switch{}_{}", self.name, id, metric)
+                    } else {
+                        format!("{}_{}", self.name, metric)
+                    }
+                })
+            })
+            .collect()
+    }
+
+    fn get_units(&self) -> Vec<String> {
+        self.ids
+            .iter()
+            .flat_map(|_| RPC_UNITS.iter().map(|u| u.to_string()))
+            .collect()
+    }
+
+    fn measure(&self) -> Result<Vec<Reading>, SensorError> {
+        let names = self.get_names();
+        let mut res = Vec::new();
+        for (i, id) in self.ids.iter().enumerate() {
+            let body = self.get(&format!("/rpc/Switch.GetStatus?id={}", id))?;
+            for (j, value) in parse_switch_status(&body).into_iter().enumerate() {
+                if let Some(value) = value {
+                    res.push(Reading::new(names[5 * i + j].clone(), value));
+                }
+            }
+        }
+        Ok(res)
+    }
+}
+
+/// The phases of an energy meter.
+const PHASES: [&str; 3] = ["a", "b", "c"];
+
+/// The metrics reported per phase.
+const EM_METRICS: [(&str, &str); 4] = [
+    ("power", "W"),
+    ("voltage", "V"),
+    ("current", "A"),
+    ("pf", ""),
+];
+
+/// The power, voltage, current and power factor of the given phases and the total power given the body of a Gen1
+/// /status of an EM or 3EM; each if present - the 3EM reports null while it reconnects.
+pub(crate) fn parse_em_status(body: &serde_json::Value, phases: &[usize]) -> Vec<Option<f64>> {
+    let mut res = Vec::new();
+    for phase in phases {
+        let meter = &body["emeters"][phase];
+        for key in ["power", "voltage", "current", "pf"] {
+            res.push(meter[key].as_f64());
+        }
+    }
+    res.push(body["total_power"].as_f64());
+    res
+}
+
+/// Like parse_em_status, given the body of EM.GetStatus of a Gen2 Pro 3EM.
+pub(crate) fn parse_em_rpc_status(body: &serde_json::Value, phases: &[usize]) -> Vec<Option<f64>> {
+    let mut res = Vec::new();
+    for phase in phases {
+        for key in ["act_power", "voltage", "current", "pf"] {
+            res.push(body[format!("{}_{}", PHASES[*phase], key)].as_f64());
+        }
+    }
+    res.push(body["total_act_power"].as_f64());
+    res
+}
+
+/// Where an energy meter is read from.
+pub(crate) enum Meter {
+    Gen1(ShellySensor),
+    Rpc(ShellyRpcSensor),
+}
+
+/// Reads a Shelly EM or 3EM, e.g. at the grid connection, per phase and in total.
+///
+/// The power keeps the sign of the meter: positive while importing, negative while exporting.
+pub struct ShellyEmSensor {
+    name: String,
+    phases: Vec<usize>,
+    meter: Meter,
+}
+
+impl ShellyEmSensor {
+    pub(crate) fn new(name: String, phases: Vec<usize>, meter: Meter) -> ShellyEmSensor {
+        ShellyEmSensor {
+            name,
+            phases,
+            meter,
+        }
+    }
+}
+
+impl common::Sensor for ShellyEmSensor {
+    fn get_names(&self) -> Vec<String> {
+        let mut res: Vec<String> = self
+            .phases
+            .iter()
+            .flat_map(|phase| {
+                EM_METRICS
+                    .iter()
+                    .map(move |(metric, _)| format!("{}_{}_{}", self.name, PHASES[*phase], metric))
+            })
+            .collect();
+        res.push(format!("{}_total_power", self.name));
+        res
+    }
+
+    fn get_units(&self) -> Vec<String> {
+        let mut res: Vec<String> = self
+            .phases
+            .iter()
+            .flat_map(|_| EM_METRICS.iter().map(|(_, unit)| unit.to_string()))
+            .collect();
+        res.push("W".to_string());
+        res
+    }
+
+    fn measure(&self) -> Result<Vec<Reading>, SensorError> {
+        let values = match &self.meter {
+            Meter::Gen1(meter) => {
+                let body = meter.get_status()?;
+                if body.get("emeters").is_none() {
+                    return Err(SensorError::Protocol(
+                        "no emeters; not an EM or 3EM?".to_string(),
+                    ));
+                }
+                parse_em_status(&body, &self.phases)
+            }
+            Meter::Rpc(meter) => {
+                parse_em_rpc_status(&meter.get("/rpc/EM.GetStatus?id=0")?, &self.phases)
+            }
+        };
+        Ok(self
+            .get_names()
+            .into_iter()
+            .zip(values)
+            .filter_map(|(name, value)| value.map(|v| Reading::new(name, v)))
+            .collect())
+    }
+}
+
+/// Reads the optional username and password of a device.
+pub(crate) fn get_auth(
+    sensor_cfg: &toml::value::Table,
+) -> Result<Option<(String, Option<String>)>, ConfigError> {
+    match sensor_cfg.get("username") {
+        Some(username) => Ok(Some((
+            username
+                .as_str()
+                .ok_or_else(|| ConfigError::Invalid("username must be a string.".to_string()))?
+                .to_string(),
+            sensor_cfg
+                .get("password")
+                .and_then(|p| p.as_str())
+                .map(|p| p.to_string()),
+        ))),
+        None => Ok(None),
+    }
+}
+
+/// Reads the optional password of a Gen2 device.
+pub(crate) fn get_password(sensor_cfg: &toml::value::Table) -> Result<Option<String>, ConfigError> {
+    match sensor_cfg.get("password") {
+        Some(v) => Ok(Some(
+            v.as_str()
+                .ok_or_else(|| ConfigError::Invalid("password must be a string.".to_string()))?
+                .to_string(),
+        )),
+        None => Ok(None),
+    }
+}
+
+/// Reads the optional timeout; 5 secs by default so an unreachable device does not hold up the loop for long.
+pub(crate) fn get_timeout(sensor_cfg: &toml::value::Table) -> Result<time::Duration, ConfigError> {
+    sensor_cfg
+        .get("timeout_secs")
+        .map(|v| v.as_float().or_else(|| v.as_integer().map(|i| i as f64)))
+        .unwrap_or(Some(5.0))
+        .filter(|secs| *secs > 0.0)
+        .map(time::Duration::from_secs_f64)
+        .ok_or_else(|| ConfigError::Invalid("timeout_secs must be positive.".to_string()))
+}
+
+/// Gen1 Shelly plugs and relays.
+pub(crate) fn sensor_type() -> common::SensorType {
+    common::SensorType {
+        name: "shelly",
+        required: &["host"],
+        optional: &["channel", "username", "password", "timeout_secs"],
+        create: |name, sensor_cfg, _| {
+            let host = sensor_cfg["host"]
+                .as_str()
+                .ok_or_else(|| ConfigError::Invalid("host must be a string.".to_string()))?;
+            let channel = match sensor_cfg.get("channel") {
+                Some(v) => v.as_integer().filter(|c| *c >= 0).ok_or_else(|| {
+                    ConfigError::Invalid("channel must be a non-negative integer.".to_string())
+                })? as usize,
+                None => 0,
+            };
+            Ok(Box::new(ShellySensor::new(
+                name.to_string(),
+                base_url(host),
+                channel,
+                get_auth(sensor_cfg)?,
+                get_timeout(sensor_cfg)?,
+            )))
+        },
+    }
+}
+
+/// Gen2 Shelly devices, e.g. the Plus and Pro series.
+pub(crate) fn rpc_sensor_type() -> common::SensorType {
+    common::SensorType {
+        name: "shelly_rpc",
+        required: &["host"],
+        optional: &["ids", "password", "timeout_secs"],
+        create: |name, sensor_cfg, _| {
+            let host = sensor_cfg["host"]
+                .as_str()
+                .ok_or_else(|| ConfigError::Invalid("host must be a string.".to_string()))?;
+            let ids = match sensor_cfg.get("ids") {
+                Some(v) => v
+                    .as_array()
+                    .and_then(|a| {
+                        a.iter()
+                            .map(|id| id.as_integer().and_then(|id| u32::try_from(id).ok()))
+                            .collect::<Option<Vec<_>>>()
+                    })
+                    .filter(|ids| !ids.is_empty())
+                    .ok_or_else(|| {
+                        ConfigError::Invalid(
+                            "ids must be a non-empty array of switch ids.".to_string(),
+                        )
+                    })?,
+                None => vec![0],
+            };
+            Ok(Box::new(ShellyRpcSensor::new(
+                name.to_string(),
+                base_url(host),
+                get_password(sensor_cfg)?,
+                ids,
+                get_timeout(sensor_cfg)?,
+            )))
+        },
+    }
+}
+
+/// Shelly EM and 3EM energy meters of either generation.
+pub(crate) fn em_sensor_type() -> common::SensorType {
+    common::SensorType {
+        name: "shelly_em",
+        required: &["host"],
+        optional: &["api", "phases", "username", "password", "timeout_secs"],
+        create: |name, sensor_cfg, _| {
+            let host = sensor_cfg["host"]
+                .as_str()
+                .ok_or_else(|| ConfigError::Invalid("host must be a string.".to_string()))?;
+            let phases = match sensor_cfg.get("phases") {
+                Some(v) => {
+                    let phases = v.as_array().ok_or_else(|| {
+                        ConfigError::Invalid("phases must be an array.".to_string())
+                    })?;
+                    phases
+                        .iter()
+                        .map(|p| {
+                            p.as_str()
+                                .and_then(|p| PHASES.iter().position(|phase| *phase == p))
+                                .ok_or_else(|| {
+                                    ConfigError::Invalid(format!(
+                                        "phases must be some of 'a', 'b' and 'c'; got: {}.",
+                                        p
+                                    ))
+                                })
+                        })
+                        .collect::<Result<Vec<_>, _>>()?
+                }
+                None => vec![0, 1, 2],
+            };
+            let timeout = get_timeout(sensor_cfg)?;
+            let meter = match sensor_cfg.get("api").map(|a| a.as_str()) {
+                None | Some(Some("gen1")) => Meter::Gen1(ShellySensor::new(
+                    name.to_string(),
+                    base_url(host),
+                    0,
+                    get_auth(sensor_cfg)?,
+                    timeout,
+                )),
+                Some(Some("rpc")) => Meter::Rpc(ShellyRpcSensor::new(
+                    name.to_string(),
+                    base_url(host),
+                    get_password(sensor_cfg)?,
+                    vec![0],
+                    timeout,
+                )),
+                Some(other) => {
+                    return Err(ConfigError::Invalid(format!(
+                        "api must be one of 'gen1' or 'rpc'; got: {}.",
+                        other.unwrap_or("a non-string")
+                    )))
+                }
+            };
+            Ok(Box::new(ShellyEmSensor::new(
+                name.to_string(),
+                phases,
+                meter,
+            )))
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::values;
+    use crate::common::Sensor;
+
+    /// As served by a Plug S (firmware 1.14).
+    const PLUG_S: &str = r#"{"wifi_sta":{"connected":true,"ssid":"home","ip":"192.168.178.30","rssi":-61},
+    "cloud":{"enabled":false,"connected":false},"mqtt":{"connected":false},"time":"12:01","unixtime":1714564860,
+    "serial":1,"has_update":false,"mac":"C45BBE6B1A2F","cfg_changed_cnt":0,"actions_stats":{"skipped":0},
+    "relays":[{"ison":true,"has_timer":false,"timer_started":0,"timer_duration":0,"timer_remaining":0,
+    "overpower":false,"source":"http"}],
+    "meters":[{"power":84.21,"overpower":0.00,"is_valid":true,"timestamp":1714572060,
+    "counters":[84.006,83.950,84.103],"total":1234567}],
+    "temperature":37.47,"overtemperature":false,"tmp":{"tC":37.47,"tF":99.45,"is_valid":true},
+    "update":{"status":"idle","has_update":false,"new_version":"","old_version":""},
+    "ram_total":52064,"ram_free":39088,"fs_size":233681,"fs_free":164907,"uptime":86001}"#;
+
+    /// As served by a 1PM (firmware 1.14); its relay is off.
+    const ONE_PM: &str = r#"{"wifi_sta":{"connected":true,"ssid":"home","ip":"192.168.178.31","rssi":-70},
+    "relays":[{"ison":false,"has_timer":false,"timer_started":0,"timer_duration":0,"timer_remaining":0,
+    "overpower":false,"is_valid":true,"source":"input"}],
+    "meters":[{"power":0.00,"overpower":0.00,"is_valid":true,"timestamp":1714572060,
+    "counters":[0.000,0.000,0.000],"total":600}],
+    "inputs":[{"input":0,"event":"","event_cnt":0}],
+    "tmp":{"tC":51.2,"tF":124.17,"is_valid":true},"temperature_status":"Normal","overtemperature":false,
+    "uptime":3021}"#;
+
+    /// As served by a Plus Plug S.
+    const GEN2: &str = r#"{"ble":{},"cloud":{"connected":false},"mqtt":{"connected":false},
+    "switch:0":{"id":0,"source":"init","output":true,"apower":84.2,"voltage":231.4,"current":0.41,
+    "aenergy":{"total":20576.123},"temperature":{"tC":41.5,"tF":106.7}},
+    "sys":{"mac":"80646FE3B1C4","restart_required":false,"uptime":1234}}"#;
+
+    /// As served by a Pro 2PM for Switch.GetStatus.
+    const SWITCH: &str = r#"{"id":0,"source":"init","output":true,"apower":84.2,"voltage":231.4,"freq":50.0,
+    "current":0.41,"pf":0.89,"aenergy":{"total":20576.123,"by_minute":[1402.1,1398.7,1401.3],"minute_ts":1714572060},
+    "temperature":{"tC":41.5,"tF":106.7}}"#;
+
+    /// As served by a 3EM (firmware 1.14); importing on a and b, exporting on c.
+    const THREE_EM: &str = r#"{"wifi_sta":{"connected":true,"ssid":"home","ip":"192.168.178.33","rssi":-58},
+    "relays":[{"ison":false,"has_timer":false,"timer_started":0,"timer_duration":0,"timer_remaining":0,
+    "overpower":false,"is_valid":true,"source":"input"}],
+    "emeters":[{"power":412.50,"pf":0.93,"current":1.91,"voltage":231.20,"is_valid":true,"total":1520345.1,
+    "total_returned":803012.4},{"power":65.70,"pf":0.41,"current":0.69,"voltage":230.60,"is_valid":true,
+    "total":980120.3,"total_returned":15.2},{"power":-1180.30,"pf":-0.99,"current":5.12,"voltage":233.00,
+    "is_valid":true,"total":1203400.8,"total_returned":2904310.6}],
+    "total_power":-702.10,"fs_mounted":true,"uptime":412003}"#;
+
+    /// As served by a Pro 3EM for EM.GetStatus.
+    const PRO_3EM: &str = r#"{"id":0,"a_current":1.02,"a_voltage":230.1,"a_act_power":-720.3,"a_aprt_power":234.1,
+    "a_pf":-0.98,"a_freq":50.0,"b_current":0.65,"b_voltage":229.8,"b_act_power":130.7,"b_aprt_power":149.4,
+    "b_pf":0.87,"b_freq":50.0,"c_current":null,"c_voltage":231.0,"c_act_power":-26.3,"c_aprt_power":30.2,
+    "c_pf":-0.87,"c_freq":50.0,"n_current":null,"total_current":1.98,"total_act_power":-615.9,
+    "total_aprt_power":413.7,"user_calibrated_phase":[]}"#;
+
+    // Tests for success.
+
+    #[test]
+    fn test_measure_for_success() {
+        let mut server = mockito::Server::new();
+        server.mock("GET", "/status").with_body(PLUG_S).create();
+        let sensor = ShellySensor::new(
+            "fridge".to_string(),
+            server.url(),
+            0,
+            None,
+            time::Duration::from_secs(5),
+        );
+        assert_eq!(
+            values(sensor.measure()),
+            vec![
+                ("fridge_power".to_string(), 84.21),
+                // in Wmin.
+                ("fridge_energy".to_string(), 1234567.0 / 60.0),
+                ("fridge_on".to_string(), 1.0),
+                ("fridge_temperature".to_string(), 37.47)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_auth_for_success() {
+        let mut server = mockito::Server::new();
+        let mock = server
+            .mock("GET", "/status")
+            // admin:secret
+            .match_header("authorization", "Basic YWRtaW46c2VjcmV0")
+            .with_body(ONE_PM)
+            .create();
+        let sensor_cfg: toml::value::Table = toml::from_str(&format!(
+            "type='shelly'\nhost='{}/'\nusername='admin'\npassword='secret'",
+            server.url()
+        ))
+        .unwrap();
+        let sensor = (sensor_type().create)("boiler", &sensor_cfg, "state").unwrap();
+        assert_eq!(
+            values(sensor.measure()),
+            vec![
+                ("boiler_power".to_string(), 0.0),
+                ("boiler_energy".to_string(), 10.0),
+                ("boiler_on".to_string(), 0.0),
+                ("boiler_temperature".to_string(), 51.2)
+            ]
+        );
+        mock.assert();
+    }
+
+    #[test]
+    fn test_rpc_measure_for_success() {
+        let mut server = mockito::Server::new();
+        let challenge = server
+            .mock("GET", "/rpc/Switch.GetStatus")
+            .match_query(mockito::Matcher::Any)
+            .match_header("authorization", mockito::Matcher::Missing)
+            .with_status(401)
+            .with_header(
+                "www-authenticate",
+                "Digest qop=\"auth\", realm=\"shellypro2pm-a8032ab12345\", nonce=\"60dc59c6\", \
+                 algorithm=SHA-256",
+            )
+            .expect(1)
+            .create();
+        let authorized = server
+            .mock("GET", "/rpc/Switch.GetStatus")
+            .match_query(mockito::Matcher::Any)
+            .match_header(
+                "authorization",
+                mockito::Matcher::Regex(
+                    "^Digest username=\"admin\", realm=\"shellypro2pm-a8032ab12345\", \
+                     nonce=\"60dc59c6\", uri=\"/rpc/Switch.GetStatus\\?id=[01]\", algorithm=SHA-256, \
+                     response=\"[0-9a-f]{64}\", qop=auth, nc=0000000[12], cnonce=\"[0-9a-f]{16}\"$"
+                        .to_string(),
+                ),
+            )
+            .with_body(SWITCH)
+            .expect(2)
+            .create();
+        let sensor = ShellyRpcSensor::new(
+            "heatpump".to_string(),
+            server.url(),
+            Some("secret".to_string()),
+            vec![0, 1],
+            time::Duration::from_secs(5),
+        );
+        let res = values(sensor.measure());
+        assert_eq!(res.len(), 10);
+        assert_eq!(
+            res[..5],
+            [
+                ("heatpump_switch0_power".to_string(), 84.2),
+                ("heatpump_switch0_voltage".to_string(), 231.4),
+                ("heatpump_switch0_current".to_string(), 0.41),
+                ("heatpump_switch0_energy".to_string(), 20576.123),
+                ("heatpump_switch0_temperature".to_string(), 41.5)
+            ]
+        );
+        assert_eq!(res[5].0, "heatpump_switch1_power");
+        challenge.assert();
+        authorized.assert();
+    }
+
+    #[test]
+    fn test_authorization_for_success() {
+        // the SHA-256 example of RFC 7616.
+        let challenge = Challenge {
+            realm: "http-auth@example.org".to_string(),
+            nonce: "7ypf/xlj9XXwfDPEoM4URrv/xwf94BcCAzFZH4GiTo0v".to_string(),
+            count: 1,
+        };
+        let header = challenge.authorization(
+            "Mufasa",
+            "Circle of Life",
+            "GET",
+            "/dir/index.html",
+            "f2/wE4q74E6zIJEtWaHKaf5wv/H5QzzpXusqGemxURZJ",
+        );
+        assert!(header.contains(
+            "response=\"753927fa0e85d155564e2e272a28d1802ca10daf4496794697cf8db5856cb6c1\""
+        ));
+    }
+
+    #[test]
+    fn test_em_measure_for_success() {
+        let mut server = mockito::Server::new();
+        server.mock("GET", "/status").with_body(THREE_EM).create();
+        let sensor_cfg: toml::value::Table =
+            toml::from_str(&format!("host='{}'\nphases=['a', 'c']", server.url())).unwrap();
+        let sensor = (em_sensor_type().create)("grid", &sensor_cfg, "state").unwrap();
+        assert_eq!(
+            sensor.get_units(),
+            vec!["W", "V", "A", "", "W", "V", "A", "", "W"]
+        );
+        // exporting on c; the sign is kept.
+        assert_eq!(
+            values(sensor.measure()),
+            vec![
+                ("grid_a_power".to_string(), 412.5),
+                ("grid_a_voltage".to_string(), 231.2),
+                ("grid_a_current".to_string(), 1.91),
+                ("grid_a_pf".to_string(), 0.93),
+                ("grid_c_power".to_string(), -1180.3),
+                ("grid_c_voltage".to_string(), 233.0),
+                ("grid_c_current".to_string(), 5.12),
+                ("grid_c_pf".to_string(), -0.99),
+                ("grid_total_power".to_string(), -702.1)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_em_rpc_measure_for_success() {
+        let mut server = mockito::Server::new();
+        server
+            .mock("GET", "/rpc/EM.GetStatus")
+            .match_query(mockito::Matcher::UrlEncoded("id".into(), "0".into()))
+            .with_body(PRO_3EM)
+            .create();
+        let sensor_cfg: toml::value::Table =
+            toml::from_str(&format!("host='{}'\napi='rpc'\nphases=['b']", server.url())).unwrap();
+        let sensor = (em_sensor_type().create)("grid", &sensor_cfg, "state").unwrap();
+        assert_eq!(
+            values(sensor.measure()),
+            vec![
+                ("grid_b_power".to_string(), 130.7),
+                ("grid_b_voltage".to_string(), 229.8),
+                ("grid_b_current".to_string(), 0.65),
+                ("grid_b_pf".to_string(), 0.87),
+                ("grid_total_power".to_string(), -615.9)
+            ]
+        );
+    }
+
+    // Tests for failure.
+
+    #[test]
+    fn test_measure_for_failure() {
+        let mut server = mockito::Server::new();
+        server.mock("GET", "/status").with_body(GEN2).create();
+        let sensor = ShellySensor::new(
+            "plug".to_string(),
+            server.url(),
+            0,
+            None,
+            time::Duration::from_secs(5),
+        );
+        match sensor.measure() {
+            Err(SensorError::Protocol(msg)) => {
+                assert_eq!(msg, "this is a Gen2 device; use the shelly_rpc type.")
+            }
+            _ => panic!("Gen2 body not reported."),
+        }
+
+        let mut server = mockito::Server::new();
+        server.mock("GET", "/status").with_status(401).create();
+        let sensor = ShellySensor::new(
+            "plug".to_string(),
+            server.url(),
+            0,
+            None,
+            time::Duration::from_secs(5),
+        );
+        assert!(matches!(sensor.measure(), Err(SensorError::Http(_))));
+    }
+
+    #[test]
+    fn test_timeout_for_failure() {
+        // nothing listens there; fails fast rather than blocking the loop.
+        let sensor = ShellySensor::new(
+            "plug".to_string(),
+            "http://127.0.0.1:9".to_string(),
+            0,
+            None,
+            time::Duration::from_millis(200),
+        );
+        let started = time::Instant::now();
+        assert!(sensor.measure().is_err());
+        assert!(started.elapsed() < time::Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_challenge_for_failure() {
+        assert_eq!(
+            Challenge::parse("Digest realm=\"x\", nonce=\"1\", algorithm=MD5"),
+            Err("algorithm must be SHA-256; got: MD5.".to_string())
+        );
+        assert!(Challenge::parse("Basic realm=\"x\"").is_err());
+
+        // w/o a password there is nothing to answer with.
+        let mut server = mockito::Server::new();
+        server
+            .mock("GET", "/rpc/Switch.GetStatus")
+            .match_query(mockito::Matcher::Any)
+            .with_status(401)
+            .create();
+        let sensor = ShellyRpcSensor::new(
+            "plug".to_string(),
+            server.url(),
+            None,
+            vec![0],
+            time::Duration::from_secs(5),
+        );
+        assert!(matches!(sensor.measure(), Err(SensorError::Http(_))));
+    }
+
+    #[test]
+    fn test_em_measure_for_failure() {
+        // reconnecting: null values are left out.
+        let body: serde_json::Value = serde_json::from_str(
+            "{\"emeters\": [{\"power\": null, \"voltage\": 231.2, \"current\": null, \"pf\": null, \
+             \"is_valid\": false}], \"total_power\": null}",
+        )
+        .unwrap();
+        assert_eq!(
+            parse_em_status(&body, &[0, 1]),
+            vec![None, Some(231.2), None, None, None, None, None, None, None]
+        );
+
+        let mut server = mockito::Server::new();
+        server.mock("GET", "/status").with_body(PLUG_S).create();
+        let sensor_cfg: toml::value::Table =
+            toml::from_str(&format!("host='{}'", server.url())).unwrap();
+        let sensor = (em_sensor_type().create)("grid", &sensor_cfg, "state").unwrap();
+        assert!(matches!(sensor.measure(), Err(SensorError::Protocol(_))));
+
+        let sensor_cfg: toml::value::Table =
+            toml::from_str("host='192.168.178.33'\nphases=['l1']").unwrap();
+        assert_eq!(
+            (em_sensor_type().create)("grid", &sensor_cfg, "state").err(),
+            Some(ConfigError::Invalid(
+                "phases must be some of 'a', 'b' and 'c'; got: \"l1\".".to_string()
+            ))
+        );
+    }
+
+    // Tests for sanity.
+
+    #[test]
+    fn test_parse_status_for_sanity() {
+        let body: serde_json::Value = serde_json::from_str(PLUG_S).unwrap();
+        // no second channel on a plug.
+        assert_eq!(parse_status(&body, 1), Ok([None, None, None, Some(37.47)]));
+        assert_eq!(base_url("192.168.178.30"), "http://192.168.178.30");
+        assert_eq!(base_url("https://plug.local/"), "https://plug.local");
+    }
+}
@@ -0,0 +1,396 @@
+use std::fs;
+use std::path;
+use std::time;
+
+use crate::common;
+use crate::common::{ConfigError, Reading, SensorError};
+use crate::exec;
+
+/// Where the kernel exposes the block devices; drivetemp adds a hwmon device to disks.
+const BLOCK_DIR: &str = "/sys/block";
+
+/// The id of the SMART attribute counting reallocated sectors.
+const REALLOCATED_SECTORS: u64 = 5;
+
+/// Where the values of the drives come from.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Source {
+    /// 'smartctl -j'; its path.
+    Smartctl(String),
+    /// The hwmon device of the drive in the given block directory; only knows the temperature.
+    Hwmon(String),
+}
+
+/// The temperature (°C), power-on hours and reallocated sectors of a drive; each if it reports it.
+pub(crate) type Health = [Option<f64>; 3];
+
+/// The health of a drive given the JSON output of smartctl; none if it was left asleep.
+pub(crate) fn parse_smartctl(device: &str, output: &str) -> Result<Option<Health>, SensorError> {
+    let json: serde_json::Value = serde_json::from_str(output)
+        .map_err(|e| SensorError::Parse(format!("smartctl output for {}: {}", device, e)))?;
+    let messages: Vec<&str> = json
+        .pointer("/smartctl/messages")
+        .and_then(|m| m.as_array())
+        .map(|m| m.iter().filter_map(|m| m["string"].as_str()).collect())
+        .unwrap_or_default();
+    if messages
+        .iter()
+        .any(|m| m.contains("STANDBY") || m.contains("SLEEP"))
+    {
+        return Ok(None);
+    }
+    let exit_status = json
+        .pointer("/smartctl/exit_status")
+        .and_then(|s| s.as_u64())
+        .ok_or_else(|| {
+            SensorError::Parse(format!(
+                "smartctl output for {} has no exit_status.",
+                device
+            ))
+        })?;
+    // bits 0 and 1: the command line was wrong or the device could not be opened.
+    if exit_status & 0b11 != 0 {
+        return Err(SensorError::Protocol(format!(
+            "smartctl failed for {}: {}",
+            device,
+            messages.join("; ")
+        )));
+    }
+    let reallocated = json
+        .pointer("/ata_smart_attributes/table")
+        .and_then(|t| t.as_array())
+        .and_then(|t| {
+            t.iter()
+                .find(|a| a["id"].as_u64() == Some(REALLOCATED_SECTORS))
+        })
+        .and_then(|a| a.pointer("/raw/value"))
+        .and_then(|v| v.as_f64());
+    Ok(Some([
+        json.pointer("/temperature/current")
+            .and_then(|v| v.as_f64()),
+        json.pointer("/power_on_time/hours")
+            .and_then(|v| v.as_f64()),
+        reallocated,
+    ]))
+}
+
+/// Reports the temperature and health of drives, e.g. SD cards and SSDs in an outdoor enclosure.
+///
+/// Drives that are spun down are not woken up; they are missing from that measurement.
+pub struct SmartSensor {
+    name: String,
+    devices: Vec<String>,
+    source: Source,
+    timeout: time::Duration,
+}
+
+impl SmartSensor {
+    pub(crate) fn new(
+        name: String,
+        devices: Vec<String>,
+        source: Source,
+        timeout: time::Duration,
+    ) -> SmartSensor {
+        SmartSensor {
+            name,
+            devices,
+            source,
+            timeout,
+        }
+    }
+
+    /// The name of the drive in its columns, e.g. sda for /dev/sda.
+    fn drive(device: &str) -> String {
+        path::Path::new(device)
+            .file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_else(|| device.to_string())
+    }
+
+    fn metrics(&self) -> &'static [&'static str] {
+        match self.source {
+            Source::Smartctl(_) => &["temperature", "power_on_hours", "reallocated_sectors"],
+            Source::Hwmon(_) => &["temperature"],
+        }
+    }
+
+    fn health(&self, device: &str) -> Result<Option<Health>, SensorError> {
+        match &self.source {
+            Source::Smartctl(smartctl) => {
+                let args: Vec<String> = ["-j", "-n", "standby", "-A", device]
+                    .iter()
+                    .map(|a| a.to_string())
+                    .collect();
+                // its exit status is a bitmask that is set for mere warnings as well.
+                let (_, stdout, stderr) = exec::output(smartctl, &args, self.timeout)?;
+                if stdout.trim().is_empty() {
+                    return Err(SensorError::Protocol(format!(
+                        "smartctl failed for {}: {}",
+                        device,
+                        stderr.trim()
+                    )));
+                }
+                parse_smartctl(device, &stdout)
+            }
+            Source::Hwmon(root) => {
+                let dir = path::Path::new(root)
+                    .join(SmartSensor::drive(device))
+                    .join("device")
+                    .join("hwmon");
+                let hwmon = fs::read_dir(&dir)
+                    .ok()
+                    .and_then(|mut entries| entries.find_map(|e| e.ok()))
+                    .ok_or_else(|| {
+                        SensorError::Io(format!("no hwmon device in {}.", dir.display()))
+                    })?;
+                let file = hwmon.path().join("temp1_input");
+                let content = fs::read_to_string(&file).map_err(|e| {
+                    SensorError::Io(format!("cannot read {}: {}", file.display(), e))
+                })?;
+                let value: f64 = content
+                    .trim()
+                    .parse()
+                    .map_err(|e| SensorError::Parse(format!("temperature of {}: {}", device, e)))?;
+                Ok(Some([Some(value / 1000.0), None, None]))
+            }
+        }
+    }
+}
+
+impl common::Sensor for SmartSensor {
+    fn get_names(&self) -> Vec<String> {
+        self.devices
+            .iter()
+            .flat_map(|device| {
+                let drive = SmartSensor::drive(device);
+                self.metrics()
+                    .iter()
+                    .map(move |metric| format!("{}_{}_{}", self.name, drive, metric))
+            })
+            .collect()
+    }
+
+    fn get_units(&self) -> Vec<String> {
+        let units = ["°C", "h", ""];
+        self.devices
+            .iter()
+            .flat_map(|_| units[..self.metrics().len()].iter().map(|u| u.to_string()))
+            .collect()
+    }
+
+    fn measure(&self) -> Result<Vec<Reading>, SensorError> {
+        let names = self.get_names();
+        let per_device = self.metrics().len();
+        let mut res = Vec::new();
+        let mut error = None;
+        let mut asleep = 0;
+        for (i, device) in self.devices.iter().enumerate() {
+            match self.health(device) {
+                Ok(Some(health)) => {
+                    for (j, value) in health.iter().take(per_device).enumerate() {
+                        if let Some(value) = value {
+                            res.push(Reading::new(names[per_device * i + j].clone(), *value));
+                        }
+                    }
+                }
+                Ok(None) => asleep += 1,
+                Err(err) => error = Some(err),
+            }
+        }
+        match error {
+            Some(err) if res.is_empty() && asleep == 0 => Err(err),
+            Some(err) => {
+                eprintln!("Could not read all drives of {}: {}", self.name, err);
+                Ok(res)
+            }
+            None => Ok(res),
+        }
+    }
+}
+
+/// Temperature and health of drives.
+pub(crate) fn sensor_type() -> common::SensorType {
+    common::SensorType {
+        name: "smart",
+        required: &["devices"],
+        optional: &["source", "smartctl", "path", "timeout_secs"],
+        create: |name, sensor_cfg, _| {
+            let string = |field: &str, default: &str| -> Result<String, ConfigError> {
+                match sensor_cfg.get(field) {
+                    Some(v) => v.as_str().map(|s| s.to_string()).ok_or_else(|| {
+                        ConfigError::Invalid(format!("{} must be a string.", field))
+                    }),
+                    None => Ok(default.to_string()),
+                }
+            };
+            let devices = sensor_cfg["devices"]
+                .as_array()
+                .and_then(|a| {
+                    a.iter()
+                        .map(|d| d.as_str().map(|d| d.to_string()))
+                        .collect::<Option<Vec<_>>>()
+                })
+                .filter(|d| !d.is_empty())
+                .ok_or_else(|| {
+                    ConfigError::Invalid(
+                        "devices must be a non-empty array of strings.".to_string(),
+                    )
+                })?;
+            let source = match string("source", "smartctl")?.as_str() {
+                "smartctl" => Source::Smartctl(string("smartctl", "smartctl")?),
+                "hwmon" => Source::Hwmon(string("path", BLOCK_DIR)?),
+                other => {
+                    return Err(ConfigError::Invalid(format!(
+                        "source must be one of 'smartctl' or 'hwmon'; got: {}.",
+                        other
+                    )))
+                }
+            };
+            let timeout = match sensor_cfg.get("timeout_secs") {
+                Some(v) => v.as_integer().filter(|t| *t > 0).ok_or_else(|| {
+                    ConfigError::Invalid("timeout_secs must be a positive integer.".to_string())
+                })? as u64,
+                None => 10,
+            };
+            Ok(Box::new(SmartSensor::new(
+                name.to_string(),
+                devices,
+                source,
+                time::Duration::from_secs(timeout),
+            )))
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::Sensor;
+
+    const SATA: &str = r#"{
+      "json_format_version": [1, 0],
+      "smartctl": {"version": [7, 3], "exit_status": 4},
+      "device": {"name": "/dev/sda", "type": "sat"},
+      "temperature": {"current": 41},
+      "power_on_time": {"hours": 12873},
+      "ata_smart_attributes": {"table": [
+        {"id": 1, "name": "Raw_Read_Error_Rate", "raw": {"value": 0, "string": "0"}},
+        {"id": 5, "name": "Reallocated_Sector_Ct", "raw": {"value": 3, "string": "3"}},
+        {"id": 194, "name": "Temperature_Celsius", "raw": {"value": 41, "string": "41"}}
+      ]}
+    }"#;
+
+    const NVME: &str = r#"{
+      "smartctl": {"exit_status": 0},
+      "device": {"name": "/dev/nvme0", "type": "nvme"},
+      "temperature": {"current": 38},
+      "power_on_time": {"hours": 2210}
+    }"#;
+
+    const STANDBY: &str = r#"{
+      "smartctl": {"exit_status": 2, "messages": [
+        {"string": "Device is in STANDBY mode, exit(2)", "severity": "information"}
+      ]}
+    }"#;
+
+    const MISSING: &str = r#"{
+      "smartctl": {"exit_status": 2, "messages": [
+        {"string": "/dev/sdz: Unable to detect device type", "severity": "error"}
+      ]}
+    }"#;
+
+    // Tests for success.
+
+    #[test]
+    fn test_parse_smartctl_for_success() {
+        assert_eq!(
+            parse_smartctl("/dev/sda", SATA).unwrap(),
+            Some([Some(41.0), Some(12873.0), Some(3.0)])
+        );
+        // no ATA attributes.
+        assert_eq!(
+            parse_smartctl("/dev/nvme0", NVME).unwrap(),
+            Some([Some(38.0), Some(2210.0), None])
+        );
+        // spun down; skipped.
+        assert_eq!(parse_smartctl("/dev/sdb", STANDBY).unwrap(), None);
+    }
+
+    #[test]
+    fn test_hwmon_for_success() {
+        let root = "smart_test0";
+        let dir = path::Path::new(root).join("sda/device/hwmon/hwmon3");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("temp1_input"), "43000\n").unwrap();
+        let sensor = SmartSensor::new(
+            "disk".to_string(),
+            vec!["/dev/sda".to_string()],
+            Source::Hwmon(root.to_string()),
+            time::Duration::from_secs(10),
+        );
+        assert_eq!(sensor.get_names(), vec!["disk_sda_temperature"]);
+        assert_eq!(sensor.get_units(), vec!["°C"]);
+        assert_eq!(sensor.measure().unwrap()[0].value, 43.0);
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    // Tests for failure.
+
+    #[test]
+    fn test_parse_smartctl_for_failure() {
+        match parse_smartctl("/dev/sdz", MISSING) {
+            Err(SensorError::Protocol(msg)) => assert_eq!(
+                msg,
+                "smartctl failed for /dev/sdz: /dev/sdz: Unable to detect device type"
+            ),
+            _ => panic!("failure not reported."),
+        }
+        match parse_smartctl("/dev/sda", "smartctl 7.3 2022-02-28") {
+            Err(SensorError::Parse(msg)) => {
+                assert!(msg.starts_with("smartctl output for /dev/sda: "))
+            }
+            _ => panic!("garbage not reported."),
+        }
+    }
+
+    #[test]
+    fn test_sensor_type_for_failure() {
+        let sensor_cfg: toml::value::Table =
+            toml::from_str("devices=['/dev/sda']\nsource='ioctl'").unwrap();
+        assert_eq!(
+            (sensor_type().create)("disk", &sensor_cfg, "state").err(),
+            Some(ConfigError::Invalid(
+                "source must be one of 'smartctl' or 'hwmon'; got: ioctl.".to_string()
+            ))
+        );
+        let sensor_cfg: toml::value::Table = toml::from_str("devices=[]").unwrap();
+        assert_eq!(
+            (sensor_type().create)("disk", &sensor_cfg, "state").err(),
+            Some(ConfigError::Invalid(
+                "devices must be a non-empty array of strings.".to_string()
+            ))
+        );
+    }
+
+    // Tests for sanity.
+
+    #[test]
+    fn test_sensor_type_for_sanity() {
+        let sensor_cfg: toml::value::Table =
+            toml::from_str("devices=['/dev/sda', '/dev/nvme0']\nsmartctl='/usr/sbin/smartctl'")
+                .unwrap();
+        let sensor = (sensor_type().create)("disk", &sensor_cfg, "state").unwrap();
+        assert_eq!(
+            sensor.get_names(),
+            vec![
+                "disk_sda_temperature",
+                "disk_sda_power_on_hours",
+                "disk_sda_reallocated_sectors",
+                "disk_nvme0_temperature",
+                "disk_nvme0_power_on_hours",
+                "disk_nvme0_reallocated_sectors"
+            ]
+        );
+        assert_eq!(sensor.get_units()[..3], ["°C", "h", ""]);
+    }
+}
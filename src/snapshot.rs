@@ -0,0 +1,289 @@
+use std::sync::{Arc, Condvar, Mutex};
+use std::time;
+
+use arc_swap::ArcSwap;
+
+use crate::calibration;
+
+/// State of a single value.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Status {
+    /// Measured or computed as usual.
+    Ok,
+    /// The sensor or component failed; the value is NaN.
+    Failed,
+}
+
+/// The latest row of the loop.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Snapshot {
+    /// Number of rows written so far; grows with every snapshot.
+    pub version: u64,
+    /// Column names; the same as in the header of the CSV file.
+    pub names: Arc<Vec<String>>,
+    /// Values by column.
+    pub values: Vec<f64>,
+    /// When each value was measured (epoch secs); slow loop values might be older than the row.
+    pub timestamps: Vec<f64>,
+    /// Status by column.
+    pub status: Vec<Status>,
+    /// The device each column belongs to, if any.
+    pub devices: Arc<Vec<Option<String>>>,
+}
+
+impl Snapshot {
+    pub(crate) fn new(
+        version: u64,
+        names: Arc<Vec<String>>,
+        values: Vec<f64>,
+        timestamps: Vec<f64>,
+        devices: Arc<Vec<Option<String>>>,
+    ) -> Snapshot {
+        let status = values
+            .iter()
+            .map(|v| {
+                if calibration::is_failure(*v) {
+                    Status::Failed
+                } else {
+                    Status::Ok
+                }
+            })
+            .collect();
+        Snapshot {
+            version,
+            names,
+            values,
+            timestamps,
+            status,
+            devices,
+        }
+    }
+
+    /// The value of a column; None if there is no such column.
+    pub fn get(&self, name: &str) -> Option<f64> {
+        let i = self.names.iter().position(|n| n == name)?;
+        self.values.get(i).copied()
+    }
+
+    /// Names and values of the columns of a device.
+    pub fn device(&self, device: &str) -> Vec<(&str, f64)> {
+        self.names
+            .iter()
+            .zip(&self.values)
+            .zip(self.devices.iter())
+            .filter(|(_, d)| d.as_deref() == Some(device))
+            .map(|((name, value), _)| (name.as_str(), *value))
+            .collect()
+    }
+}
+
+struct Shared {
+    latest: ArcSwap<Snapshot>,
+    version: Mutex<u64>,
+    changed: Condvar,
+}
+
+/// Handle on the values of a running loop; cheap to clone and to share between threads.
+///
+/// Reading the latest snapshot is lock-free and never blocks the loop.
+#[derive(Clone)]
+pub struct Collector {
+    shared: Arc<Shared>,
+}
+
+impl Default for Collector {
+    fn default() -> Self {
+        Collector::new()
+    }
+}
+
+impl Collector {
+    /// A collector without any values yet; pass it to [crate::run_loop].
+    pub fn new() -> Collector {
+        Collector {
+            shared: Arc::new(Shared {
+                latest: ArcSwap::from_pointee(Snapshot::default()),
+                version: Mutex::new(0),
+                changed: Condvar::new(),
+            }),
+        }
+    }
+
+    /// The latest snapshot; version 0 until the loop wrote its first row.
+    pub fn latest(&self) -> Arc<Snapshot> {
+        self.shared.latest.load_full()
+    }
+
+    /// Notifies about snapshots published from now on.
+    pub fn subscribe(&self) -> Subscription {
+        Subscription {
+            shared: self.shared.clone(),
+            seen: self.latest().version,
+        }
+    }
+
+    pub(crate) fn publish(&self, snapshot: Snapshot) {
+        let version = snapshot.version;
+        self.shared.latest.store(Arc::new(snapshot));
+        *self.shared.version.lock().expect("snapshot lock poisoned.") = version;
+        self.shared.changed.notify_all();
+    }
+}
+
+/// Waits for new snapshots; snapshots published while not waiting are skipped, not queued.
+pub struct Subscription {
+    shared: Arc<Shared>,
+    seen: u64,
+}
+
+impl Subscription {
+    /// Blocks until a snapshot newer than the last one seen was published.
+    pub fn changed(&mut self) -> Arc<Snapshot> {
+        let mut version = self.shared.version.lock().expect("snapshot lock poisoned.");
+        while *version <= self.seen {
+            version = self
+                .shared
+                .changed
+                .wait(version)
+                .expect("snapshot lock poisoned.");
+        }
+        drop(version);
+        self.take()
+    }
+
+    /// Like changed, but gives up after the timeout.
+    pub fn changed_timeout(&mut self, timeout: time::Duration) -> Option<Arc<Snapshot>> {
+        let version = self.shared.version.lock().expect("snapshot lock poisoned.");
+        let (version, _) = self
+            .shared
+            .changed
+            .wait_timeout_while(version, timeout, |v| *v <= self.seen)
+            .expect("snapshot lock poisoned.");
+        if *version <= self.seen {
+            return None;
+        }
+        drop(version);
+        Some(self.take())
+    }
+
+    fn take(&mut self) -> Arc<Snapshot> {
+        let snapshot = self.shared.latest.load_full();
+        self.seen = snapshot.version;
+        snapshot
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    fn snapshot(version: u64) -> Snapshot {
+        let v = version as f64;
+        Snapshot::new(
+            version,
+            Arc::new(vec!["timestamp".to_string(), "pv_power".to_string()]),
+            vec![v, v],
+            vec![v, v],
+            Arc::new(vec![None, Some("pv".to_string())]),
+        )
+    }
+
+    // Tests for success.
+
+    #[test]
+    fn test_latest_for_success() {
+        let collector = Collector::new();
+        assert_eq!(collector.latest().version, 0);
+        collector.publish(snapshot(1));
+        assert_eq!(collector.latest().get("pv_power"), Some(1.0));
+        assert_eq!(collector.latest().get("foo"), None);
+        assert_eq!(collector.latest().status, vec![Status::Ok, Status::Ok]);
+        assert_eq!(collector.latest().device("pv"), vec![("pv_power", 1.0)]);
+    }
+
+    // Tests for failure.
+
+    #[test]
+    fn test_status_for_failure() {
+        let names = Arc::new(vec!["a".to_string(), "b".to_string(), "c".to_string()]);
+        let snapshot = Snapshot::new(
+            1,
+            names,
+            vec![-1.0, f64::NAN, 0.0],
+            vec![0.0; 3],
+            Arc::default(),
+        );
+        assert_eq!(
+            snapshot.status,
+            vec![Status::Ok, Status::Failed, Status::Ok]
+        );
+    }
+
+    #[test]
+    fn test_changed_timeout_for_failure() {
+        let collector = Collector::new();
+        collector.publish(snapshot(1));
+        // nothing new since subscribing.
+        let mut subscription = collector.subscribe();
+        assert_eq!(
+            subscription.changed_timeout(time::Duration::from_millis(20)),
+            None
+        );
+    }
+
+    // Tests for sanity.
+
+    #[test]
+    fn test_subscribe_for_sanity() {
+        let collector = Collector::new();
+        let mut subscription = collector.subscribe();
+        let publisher = collector.clone();
+        let handle = thread::spawn(move || {
+            for version in 1..=3 {
+                thread::sleep(time::Duration::from_millis(10));
+                publisher.publish(snapshot(version));
+            }
+        });
+        let mut seen = Vec::new();
+        while seen.last() != Some(&3) {
+            seen.push(subscription.changed().version);
+        }
+        handle.join().unwrap();
+        // never the same snapshot twice; skipped ones are fine.
+        assert!(seen.windows(2).all(|w| w[0] < w[1]));
+    }
+
+    #[test]
+    fn test_concurrency_for_sanity() {
+        let collector = Collector::new();
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let collector = collector.clone();
+                thread::spawn(move || {
+                    let mut last = 0;
+                    while last < 10000 {
+                        let snapshot = collector.latest();
+                        // snapshots are never torn, and never go back in time.
+                        assert!(snapshot.version >= last);
+                        assert!(snapshot
+                            .values
+                            .iter()
+                            .all(|v| *v == snapshot.version as f64));
+                        if snapshot.version > 0 {
+                            assert_eq!(snapshot.names.len(), snapshot.values.len());
+                        }
+                        last = snapshot.version;
+                    }
+                })
+            })
+            .collect();
+        for version in 1..=10000 {
+            collector.publish(snapshot(version));
+        }
+        for reader in readers {
+            reader.join().unwrap();
+        }
+    }
+}
@@ -0,0 +1,195 @@
+use std::path::Path;
+
+use crate::output;
+
+/// The columns of one sensor and where they go.
+pub(crate) struct SensorFile {
+    pub(crate) columns: Vec<String>,
+    // slow loop sensors are only written when they were measured, not with each cached copy.
+    pub(crate) slow: bool,
+    pub(crate) output: Box<dyn output::Output>,
+}
+
+/// Writes the columns of each sensor to an output of its own, with its own timestamp column.
+///
+/// Fast loop sensors get every row, slow loop sensors every slow_loop_delay-th row starting with the first - when the
+/// loop actually measured them. Columns belonging to no sensor (ages, status, components) are not written.
+pub(crate) struct SplitOutput {
+    files: Vec<SensorFile>,
+    // the positions of the columns of each file in the rows.
+    indices: Vec<Vec<usize>>,
+    slow_loop_delay: usize,
+    rows: usize,
+}
+
+impl SplitOutput {
+    pub(crate) fn new(files: Vec<SensorFile>, slow_loop_delay: usize) -> SplitOutput {
+        SplitOutput {
+            files,
+            indices: Vec::new(),
+            slow_loop_delay: slow_loop_delay.max(1),
+            rows: 0,
+        }
+    }
+}
+
+/// The file of a sensor: the filename up to its first '-' or '.' replaced by the sensor's name.
+///
+/// E.g. 'fritz0.csv' for 'data.csv' and 'fritz0-%Y-%m-%d.csv' for 'data-%Y-%m-%d.csv'.
+pub(crate) fn path(filename: &str, sensor: &str) -> String {
+    let path = Path::new(filename);
+    let name = path
+        .file_name()
+        .and_then(|n| n.to_str())
+        .unwrap_or_default();
+    let rest = &name[name.find(['-', '.']).unwrap_or(name.len())..];
+    path.with_file_name(format!("{}{}", sensor, rest))
+        .to_string_lossy()
+        .to_string()
+}
+
+impl output::Output for SplitOutput {
+    fn write_header(&mut self, names: &[String]) {
+        self.indices.clear();
+        for file in &mut self.files {
+            // private columns may have been left out.
+            let indices: Vec<usize> = file
+                .columns
+                .iter()
+                .filter_map(|c| names.iter().position(|n| n == c))
+                .collect();
+            let mut header = vec![names[0].clone()];
+            header.extend(indices.iter().map(|i| names[*i].clone()));
+            file.output.write_header(&header);
+            self.indices.push(indices);
+        }
+    }
+
+    fn write(&mut self, values: &[f64]) -> Result<(), String> {
+        let measured = self.rows.is_multiple_of(self.slow_loop_delay);
+        self.rows += 1;
+        let mut errors: Vec<String> = Vec::new();
+        for (file, indices) in self.files.iter_mut().zip(&self.indices) {
+            if file.slow && !measured {
+                continue;
+            }
+            let mut row = vec![values[0]];
+            row.extend(indices.iter().map(|i| values[*i]));
+            if let Err(err) = file.output.write(&row) {
+                errors.push(err);
+            }
+        }
+        if errors.is_empty() {
+            return Ok(());
+        }
+        Err(errors.join("; "))
+    }
+
+    fn flush(&mut self) {
+        for file in &mut self.files {
+            file.output.flush();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::csv_out;
+    use crate::output::Output;
+    use crate::tz;
+
+    fn csv(path: &str) -> Box<dyn output::Output> {
+        Box::new(output::CsvOutput::new(
+            path.to_string(),
+            output::OnMismatch::Fail,
+            tz::Zone::Utc,
+            false,
+            csv_out::Format::default(),
+            1,
+            output::SyncMode::None,
+            None,
+            1000,
+        ))
+    }
+
+    // Tests for success.
+
+    #[test]
+    fn test_path_for_success() {
+        assert_eq!(path("data.csv", "fritz0"), "fritz0.csv");
+        assert_eq!(path("/var/ogc/data.csv", "weather"), "/var/ogc/weather.csv");
+        assert_eq!(
+            path("archive/data-%Y-%m-%d.csv", "fritz0"),
+            "archive/fritz0-%Y-%m-%d.csv"
+        );
+        assert_eq!(path("data", "fritz0"), "fritz0");
+    }
+
+    // Tests for failure.
+
+    #[test]
+    fn test_private_columns_for_failure() {
+        // columns the output does not get are left out of the file.
+        let mut output = SplitOutput::new(
+            vec![SensorFile {
+                columns: vec!["fritz_power".to_string(), "fritz_energy".to_string()],
+                slow: false,
+                output: csv("split_test0-fritz.csv"),
+            }],
+            2,
+        );
+        output.write_header(&["timestamp".to_string(), "fritz_power".to_string()]);
+        output.write(&[1.0, 50.0]).unwrap();
+        assert_eq!(
+            fs::read_to_string("split_test0-fritz.csv").unwrap(),
+            "timestamp,fritz_power\n1,50\n"
+        );
+        fs::remove_file("split_test0-fritz.csv").unwrap();
+    }
+
+    // Tests for sanity.
+
+    #[test]
+    fn test_split_for_sanity() {
+        let mut output = SplitOutput::new(
+            vec![
+                SensorFile {
+                    columns: vec!["fritz_power".to_string(), "fritz_energy".to_string()],
+                    slow: false,
+                    output: csv("split_test1-fritz.csv"),
+                },
+                SensorFile {
+                    columns: vec!["weather_temp".to_string()],
+                    slow: true,
+                    output: csv("split_test1-weather.csv"),
+                },
+            ],
+            2,
+        );
+        output.write_header(&[
+            "timestamp".to_string(),
+            "fritz_power".to_string(),
+            "fritz_energy".to_string(),
+            "weather_temp".to_string(),
+            "battery_cycles".to_string(),
+        ]);
+        for (t, power) in [(1.0, 50.0), (2.0, 55.0), (3.0, 60.0)] {
+            output.write(&[t, power, 1200.0, 21.5, 12.0]).unwrap();
+        }
+        output.flush();
+        assert_eq!(
+            fs::read_to_string("split_test1-fritz.csv").unwrap(),
+            "timestamp,fritz_power,fritz_energy\n1,50,1200\n2,55,1200\n3,60,1200\n"
+        );
+        // only when measured; not the cached copies in between.
+        assert_eq!(
+            fs::read_to_string("split_test1-weather.csv").unwrap(),
+            "timestamp,weather_temp\n1,21.5\n3,21.5\n"
+        );
+        fs::remove_file("split_test1-fritz.csv").unwrap();
+        fs::remove_file("split_test1-weather.csv").unwrap();
+    }
+}
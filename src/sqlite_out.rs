@@ -0,0 +1,180 @@
+use rusqlite::{params_from_iter, Connection};
+
+use crate::output;
+
+const TABLE: &str = "measurements";
+
+/// Inserts each row into the measurements table of a SQLite database; one REAL column per column.
+pub(crate) struct SqliteOutput {
+    conn: Connection,
+    insert: String,
+}
+
+impl SqliteOutput {
+    pub(crate) fn new(path: &str) -> SqliteOutput {
+        let conn = Connection::open(path)
+            .unwrap_or_else(|err| panic!("could not open database {}: {}.", path, err));
+        SqliteOutput {
+            conn,
+            insert: String::new(),
+        }
+    }
+
+    /// Columns of the measurements table; empty if there is no such table yet.
+    fn existing_columns(&self) -> Vec<String> {
+        let mut query = self
+            .conn
+            .prepare(&format!("PRAGMA table_info({})", TABLE))
+            .expect("could not read the schema of the database.");
+        let columns = query
+            .query_map([], |row| row.get::<_, String>(1))
+            .expect("could not read the schema of the database.");
+        columns.filter_map(Result::ok).collect()
+    }
+}
+
+fn quote(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+/// What differs between the columns of the database and the configured ones; None if nothing.
+fn diff(existing: &[String], names: &[String]) -> Option<String> {
+    if existing == names {
+        return None;
+    }
+    let missing: Vec<&str> = names
+        .iter()
+        .filter(|n| !existing.contains(n))
+        .map(|n| n.as_str())
+        .collect();
+    let unknown: Vec<&str> = existing
+        .iter()
+        .filter(|n| !names.contains(n))
+        .map(|n| n.as_str())
+        .collect();
+    if missing.is_empty() && unknown.is_empty() {
+        return Some("the columns are in another order".to_string());
+    }
+    Some(format!(
+        "not in the database: {}; not configured: {}",
+        missing.join(", "),
+        unknown.join(", ")
+    ))
+}
+
+impl output::Output for SqliteOutput {
+    fn write_header(&mut self, names: &[String]) {
+        let existing = self.existing_columns();
+        let columns: Vec<String> = names.iter().map(|n| quote(n)).collect();
+        if existing.is_empty() {
+            let definitions: Vec<String> = columns.iter().map(|c| format!("{} REAL", c)).collect();
+            self.conn
+                .execute(
+                    &format!("CREATE TABLE {} ({})", TABLE, definitions.join(", ")),
+                    [],
+                )
+                .expect("could not create the measurements table.");
+        } else if let Some(diff) = diff(&existing, names) {
+            panic!(
+                "the measurements table does not match the configured sensors; {}.",
+                diff
+            );
+        }
+        let placeholders = vec!["?"; names.len()].join(", ");
+        self.insert = format!(
+            "INSERT INTO {} ({}) VALUES ({})",
+            TABLE,
+            columns.join(", "),
+            placeholders
+        );
+    }
+
+    fn write(&mut self, values: &[f64]) -> Result<(), String> {
+        self.conn
+            .transaction()
+            .and_then(|tx| {
+                tx.execute(&self.insert, params_from_iter(values.iter()))?;
+                tx.commit()
+            })
+            .map_err(|e| format!("could not write to the database: {}", e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::output::Output;
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|n| n.to_string()).collect()
+    }
+
+    // Tests for success.
+
+    #[test]
+    fn test_write_for_success() {
+        let _ = fs::remove_file("sqlite_out_test0.db");
+        let mut output = SqliteOutput::new("sqlite_out_test0.db");
+        output.write_header(&names(&["timestamp", "pv_power"]));
+        output.write(&[1699920000.0, 1500.0]).unwrap();
+        output.write(&[1699920030.0, f64::NAN]).unwrap();
+
+        let conn = Connection::open("sqlite_out_test0.db").unwrap();
+        let (count, avg): (i64, f64) = conn
+            .query_row(
+                "SELECT COUNT(*), AVG(pv_power) FROM measurements",
+                [],
+                |r| Ok((r.get(0)?, r.get(1)?)),
+            )
+            .unwrap();
+        // failed values are stored as NULL.
+        assert_eq!((count, avg), (2, 1500.0));
+        fs::remove_file("sqlite_out_test0.db").unwrap();
+    }
+
+    // Tests for failure.
+
+    #[test]
+    fn test_diff_for_failure() {
+        let existing = names(&["timestamp", "pv_power", "fox_soc"]);
+        assert_eq!(diff(&existing, &existing), None);
+        assert_eq!(
+            diff(&existing, &names(&["timestamp", "pv_power", "grid_power"])),
+            Some("not in the database: grid_power; not configured: fox_soc".to_string())
+        );
+        assert_eq!(
+            diff(&existing, &names(&["timestamp", "fox_soc", "pv_power"])),
+            Some("the columns are in another order".to_string())
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "not in the database: grid_power")]
+    fn test_schema_for_failure() {
+        let _ = fs::remove_file("sqlite_out_test1.db");
+        SqliteOutput::new("sqlite_out_test1.db").write_header(&names(&["timestamp", "pv_power"]));
+        let mut output = SqliteOutput::new("sqlite_out_test1.db");
+        fs::remove_file("sqlite_out_test1.db").unwrap();
+        output.write_header(&names(&["timestamp", "pv_power", "grid_power"]));
+    }
+
+    // Tests for sanity.
+
+    #[test]
+    fn test_reopen_for_sanity() {
+        let _ = fs::remove_file("sqlite_out_test2.db");
+        for t in [1.0, 2.0] {
+            let mut output = SqliteOutput::new("sqlite_out_test2.db");
+            output.write_header(&names(&["timestamp", "pv_power"]));
+            output.write(&[t, 100.0]).unwrap();
+        }
+        let conn = Connection::open("sqlite_out_test2.db").unwrap();
+        let count: i64 = conn
+            .query_row("SELECT COUNT(*) FROM measurements", [], |r| r.get(0))
+            .unwrap();
+        assert_eq!(count, 2);
+        fs::remove_file("sqlite_out_test2.db").unwrap();
+    }
+}
@@ -0,0 +1,62 @@
+use std::error::Error;
+use std::fs;
+use std::path;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+/// Loads the persisted state of a component; None if there is none (yet) or it is unreadable.
+pub(crate) fn load<T: DeserializeOwned>(dir: &str, name: &str) -> Option<T> {
+    let file = path::Path::new(dir).join(format!("{}.json", name));
+    let contents = fs::read_to_string(file).ok()?;
+    match serde_json::from_str(&contents) {
+        Ok(state) => Some(state),
+        Err(err) => {
+            eprintln!("Ignoring unreadable state for {}: {}.", name, err);
+            None
+        }
+    }
+}
+
+/// Persists the state of a component; written to a temp file first so a power cut can't corrupt it.
+pub(crate) fn save<T: Serialize>(dir: &str, name: &str, state: &T) -> Result<(), Box<dyn Error>> {
+    fs::create_dir_all(dir)?;
+    let file = path::Path::new(dir).join(format!("{}.json", name));
+    let tmp = path::Path::new(dir).join(format!("{}.json.tmp", name));
+    fs::write(&tmp, serde_json::to_string(state)?)?;
+    fs::rename(tmp, file)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Tests for success.
+
+    #[test]
+    fn test_save_for_success() {
+        save("state_test0", "foo", &vec![1.0, 2.0]).unwrap();
+        fs::remove_dir_all("state_test0").unwrap();
+    }
+
+    // Tests for failure.
+
+    #[test]
+    fn test_load_for_failure() {
+        assert_eq!(load::<Vec<f64>>("state_test1", "foo"), None);
+        fs::create_dir_all("state_test1").unwrap();
+        fs::write("state_test1/foo.json", "ohno").unwrap();
+        assert_eq!(load::<Vec<f64>>("state_test1", "foo"), None);
+        fs::remove_dir_all("state_test1").unwrap();
+    }
+
+    // Tests for sanity.
+
+    #[test]
+    fn test_load_for_sanity() {
+        save("state_test2", "foo", &vec![1.0, 2.0]).unwrap();
+        assert_eq!(load::<Vec<f64>>("state_test2", "foo"), Some(vec![1.0, 2.0]));
+        fs::remove_dir_all("state_test2").unwrap();
+    }
+}
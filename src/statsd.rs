@@ -0,0 +1,213 @@
+use std::net::{ToSocketAddrs, UdpSocket};
+
+use crate::output;
+
+// what fits into a single Ethernet frame w/o fragmentation, as recommended by statsd.
+const MAX_DATAGRAM: usize = 1432;
+
+/// Sends each value of a row as a statsd gauge '<prefix>.<sensor>.<metric>:<value>|g' over UDP.
+///
+/// Gauges are batched into as few datagrams as fit; nothing is retried - statsd is fire-and-forget.
+pub(crate) struct StatsdOutput {
+    socket: UdpSocket,
+    prefix: String,
+    sensors: Vec<String>,
+    // the metric name of each column.
+    names: Vec<String>,
+}
+
+impl StatsdOutput {
+    /// Resolves the address (host:port) right away; an unknown host is reported here, not in the loop.
+    pub(crate) fn new(
+        address: &str,
+        prefix: &str,
+        sensors: Vec<String>,
+    ) -> Result<StatsdOutput, String> {
+        let target = address
+            .to_socket_addrs()
+            .map_err(|e| format!("could not resolve statsd address {}: {}", address, e))?
+            .next()
+            .ok_or_else(|| format!("could not resolve statsd address {}", address))?;
+        let local = if target.is_ipv4() {
+            "0.0.0.0:0"
+        } else {
+            "[::]:0"
+        };
+        let socket = UdpSocket::bind(local)
+            .and_then(|socket| socket.connect(target).map(|_| socket))
+            .map_err(|e| format!("could not open a UDP socket to {}: {}", address, e))?;
+        Ok(StatsdOutput {
+            socket,
+            prefix: prefix.to_string(),
+            sensors,
+            names: Vec::new(),
+        })
+    }
+
+    /// The datagrams of a row; failed (NaN) values are left out.
+    fn datagrams(&self, values: &[f64]) -> Vec<String> {
+        let mut res: Vec<String> = Vec::new();
+        let mut current = String::new();
+        for (name, value) in self.names.iter().zip(values).skip(1) {
+            if !value.is_finite() {
+                continue;
+            }
+            // a sign means a change to a gauge; negative values have to be set from zero.
+            let gauge = if *value < 0.0 {
+                format!("{}:0|g\n{}:{}|g", name, name, value)
+            } else {
+                format!("{}:{}|g", name, value)
+            };
+            if !current.is_empty() && current.len() + 1 + gauge.len() > MAX_DATAGRAM {
+                res.push(current);
+                current = String::new();
+            }
+            if !current.is_empty() {
+                current.push('\n');
+            }
+            current.push_str(&gauge);
+        }
+        if !current.is_empty() {
+            res.push(current);
+        }
+        res
+    }
+}
+
+/// Replaces the characters statsd uses as separators.
+fn sanitize(text: &str) -> String {
+    text.chars()
+        .map(|c| match c {
+            ':' | '|' | '@' | '#' | ' ' | '\n' => '_',
+            c => c,
+        })
+        .collect()
+}
+
+impl output::Output for StatsdOutput {
+    fn write_header(&mut self, names: &[String]) {
+        self.names = names
+            .iter()
+            .map(|n| {
+                let (sensor, metric) = output::split(&self.sensors, n);
+                let mut parts = vec![sanitize(&sensor), sanitize(&metric)];
+                if !self.prefix.is_empty() {
+                    parts.insert(0, sanitize(&self.prefix));
+                }
+                parts.join(".")
+            })
+            .collect();
+    }
+
+    fn write(&mut self, values: &[f64]) -> Result<(), String> {
+        let datagrams = self.datagrams(values);
+        let total = datagrams.len();
+        let mut errors: Vec<String> = Vec::new();
+        for datagram in datagrams {
+            if let Err(err) = self.socket.send(datagram.as_bytes()) {
+                errors.push(err.to_string());
+            }
+        }
+        if errors.is_empty() {
+            return Ok(());
+        }
+        Err(format!(
+            "could not send {} of {} datagrams to statsd: {}",
+            errors.len(),
+            total,
+            errors.join("; ")
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time;
+
+    use super::*;
+    use crate::output::Output;
+
+    fn names() -> Vec<String> {
+        vec![
+            "timestamp".to_string(),
+            "fox_pvPower".to_string(),
+            "fox_2_pvPower".to_string(),
+            "bat_cycles".to_string(),
+        ]
+    }
+
+    fn statsd(address: &str) -> StatsdOutput {
+        let mut output =
+            StatsdOutput::new(address, "ogc", vec!["fox".to_string(), "fox_2".to_string()])
+                .unwrap();
+        output.write_header(&names());
+        output
+    }
+
+    // Tests for success.
+
+    #[test]
+    fn test_write_for_success() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        server
+            .set_read_timeout(Some(time::Duration::from_secs(5)))
+            .unwrap();
+        let mut output = statsd(&server.local_addr().unwrap().to_string());
+        output.write(&[1699920000.0, 1.5, f64::NAN, 12.25]).unwrap();
+
+        let mut buf = [0; MAX_DATAGRAM];
+        let n = server.recv(&mut buf).unwrap();
+        assert_eq!(
+            std::str::from_utf8(&buf[..n]).unwrap(),
+            "ogc.fox.pvPower:1.5|g\nogc.bat.cycles:12.25|g"
+        );
+    }
+
+    // Tests for failure.
+
+    #[test]
+    fn test_new_for_failure() {
+        assert!(StatsdOutput::new("no-such-host.invalid:8125", "ogc", Vec::new()).is_err());
+        assert!(StatsdOutput::new("localhost", "ogc", Vec::new()).is_err());
+    }
+
+    #[test]
+    fn test_write_for_failure() {
+        // nobody listens; sending still works or is merely reported.
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let address = server.local_addr().unwrap().to_string();
+        drop(server);
+        let mut output = statsd(&address);
+        for _ in 0..3 {
+            let _ = output.write(&[1699920000.0, 1.5, 2.0, 12.25]);
+        }
+    }
+
+    // Tests for sanity.
+
+    #[test]
+    fn test_datagrams_for_sanity() {
+        let output = statsd("127.0.0.1:8125");
+        // negative gauges are reset to zero first.
+        assert_eq!(
+            output.datagrams(&[1699920000.0, -1.5, 2.0, f64::NAN]),
+            vec!["ogc.fox.pvPower:0|g\nogc.fox.pvPower:-1.5|g\nogc.fox_2.pvPower:2|g"]
+        );
+
+        // many columns are split into datagrams that fit.
+        let mut output = StatsdOutput::new("127.0.0.1:8125", "", Vec::new()).unwrap();
+        let mut names = vec!["timestamp".to_string()];
+        names.extend((0..200).map(|i| format!("sensor{}_power:w", i)));
+        output.write_header(&names);
+        let mut values = vec![1699920000.0];
+        values.extend((0..200).map(|i| i as f64 * 100.5));
+        let datagrams = output.datagrams(&values);
+        assert!(datagrams.len() > 1);
+        assert!(datagrams.iter().all(|d| d.len() <= MAX_DATAGRAM));
+        assert!(datagrams[0].starts_with("sensor0.power_w:0|g\n"));
+        assert_eq!(
+            datagrams.iter().map(|d| d.lines().count()).sum::<usize>(),
+            200
+        );
+    }
+}
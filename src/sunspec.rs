@@ -0,0 +1,735 @@
+use std::collections::HashMap;
+use std::net::TcpStream;
+use std::sync::Mutex;
+
+use crate::common;
+use crate::common::{ConfigError, Reading, SensorError};
+use crate::modbus;
+use crate::modbus::Batch;
+use crate::modbus_tcp;
+
+/// Where the SunS marker is looked for, in this order.
+const BASES: [u16; 3] = [40000, 50000, 0];
+
+/// "SunS" in two registers.
+const MARKER: [u16; 2] = [0x5375, 0x6e53];
+
+/// The id ending the model chain.
+const END: u16 = 0xffff;
+
+/// The most models walked; guards against a chain that does not end.
+const MAX_MODELS: usize = 64;
+
+/// The types of points; each with its own value for not implemented.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Type {
+    Uint16,
+    Int16,
+    Acc32,
+    Enum16,
+}
+
+/// A point of a model: its metric, unit, offset from the start of the model (its id), type, and the offset of its
+/// scale factor if it has one.
+pub(crate) struct Point {
+    metric: &'static str,
+    unit: &'static str,
+    offset: u16,
+    kind: Type,
+    scale: Option<u16>,
+}
+
+const fn point(
+    metric: &'static str,
+    unit: &'static str,
+    offset: u16,
+    kind: Type,
+    scale: Option<u16>,
+) -> Point {
+    Point {
+        metric,
+        unit,
+        offset,
+        kind,
+        scale,
+    }
+}
+
+/// Models sharing their points, e.g. the single, split and three phase inverter.
+pub(crate) struct Family {
+    name: &'static str,
+    models: &'static [u16],
+    points: &'static [Point],
+}
+
+impl Family {
+    /// The number of registers from the start of the model up to the last one used.
+    fn span(&self) -> u16 {
+        self.points
+            .iter()
+            .flat_map(|p| {
+                let last = if p.kind == Type::Acc32 { 1 } else { 0 };
+                [p.offset + last, p.scale.unwrap_or(0)]
+            })
+            .max()
+            .unwrap_or(0)
+            + 1
+    }
+}
+
+/// Inverters, integer and scale factor models 101 to 103.
+const INVERTER: [Point; 10] = [
+    point("ac_power", "W", 14, Type::Int16, Some(15)),
+    point("ac_current", "A", 2, Type::Uint16, Some(6)),
+    point("ac_voltage", "V", 10, Type::Uint16, Some(13)),
+    point("frequency", "Hz", 16, Type::Uint16, Some(17)),
+    point("energy", "Wh", 24, Type::Acc32, Some(26)),
+    point("dc_power", "W", 31, Type::Int16, Some(32)),
+    point("dc_voltage", "V", 29, Type::Uint16, Some(30)),
+    point("dc_current", "A", 27, Type::Uint16, Some(28)),
+    point("temperature", "°C", 33, Type::Int16, Some(37)),
+    point("state", "", 38, Type::Enum16, None),
+];
+
+/// Wye-connect three phase meters, integer and scale factor model 203.
+const METER: [Point; 6] = [
+    point("power", "W", 18, Type::Int16, Some(22)),
+    point("current", "A", 2, Type::Int16, Some(6)),
+    point("voltage", "V", 7, Type::Int16, Some(15)),
+    point("frequency", "Hz", 16, Type::Int16, Some(17)),
+    point("energy_exported", "Wh", 38, Type::Acc32, Some(54)),
+    point("energy_imported", "Wh", 46, Type::Acc32, Some(54)),
+];
+
+/// Lithium-ion batteries, base model 802.
+const BATTERY: [Point; 6] = [
+    point("soc", "%", 11, Type::Uint16, Some(56)),
+    point("soh", "%", 13, Type::Uint16, Some(58)),
+    point("voltage", "V", 34, Type::Uint16, Some(59)),
+    point("current", "A", 44, Type::Int16, Some(61)),
+    point("power", "W", 47, Type::Int16, Some(63)),
+    point("charge_state", "", 16, Type::Enum16, None),
+];
+
+pub(crate) const FAMILIES: [Family; 3] = [
+    Family {
+        name: "inverter",
+        models: &[101, 102, 103],
+        points: &INVERTER,
+    },
+    Family {
+        name: "meter",
+        models: &[203],
+        points: &METER,
+    },
+    Family {
+        name: "battery",
+        models: &[802],
+        points: &BATTERY,
+    },
+];
+
+/// The scale factor at the offset of a model; None if not implemented or out of range.
+fn scale(block: &[u16], offset: u16) -> Option<i16> {
+    block
+        .get(offset as usize)
+        .map(|sf| *sf as i16)
+        .filter(|sf| (-10..=10).contains(sf))
+}
+
+/// The value of a point given the registers of its model; None if not implemented.
+pub(crate) fn value(block: &[u16], point: &Point) -> Option<f64> {
+    let register = |i: u16| block.get((point.offset + i) as usize).copied();
+    let raw = match point.kind {
+        Type::Uint16 | Type::Enum16 => Some(register(0)?).filter(|r| *r != 0xffff)? as f64,
+        Type::Int16 => Some(register(0)?).filter(|r| *r != 0x8000)? as i16 as f64,
+        Type::Acc32 => {
+            Some(((register(0)? as u32) << 16) | register(1)? as u32).filter(|r| *r != 0)? as f64
+        }
+    };
+    match point.scale {
+        None => Some(raw),
+        // divided, so e.g. 1234 at -2 is exactly 12.34.
+        Some(offset) => match scale(block, offset)? {
+            sf if sf < 0 => Some(raw / 10f64.powi(-sf as i32)),
+            sf => Some(raw * 10f64.powi(sf as i32)),
+        },
+    }
+}
+
+/// Reads inverters, meters and batteries that describe themselves as SunSpec models over Modbus TCP, e.g. from
+/// Fronius, SMA or SolarEdge.
+///
+/// The model chain is discovered on first use, and again if it changed. A value whose scale factor changed since the
+/// last read is missing once, as the device may not have updated both at the same moment.
+pub struct SunSpecSensor {
+    name: String,
+    server: modbus_tcp::Server,
+    unit_id: u8,
+    families: Vec<&'static Family>,
+    /// Where the model of each family starts, once discovered; None for a family the device lacks.
+    layout: Mutex<Option<Vec<Option<u16>>>>,
+    /// The scale factors of the last read, by family and offset.
+    scales: Mutex<HashMap<(usize, u16), i16>>,
+}
+
+impl SunSpecSensor {
+    pub(crate) fn new(
+        name: String,
+        server: modbus_tcp::Server,
+        unit_id: u8,
+        families: Vec<&'static Family>,
+    ) -> SunSpecSensor {
+        SunSpecSensor {
+            name,
+            server,
+            unit_id,
+            families,
+            layout: Mutex::new(None),
+            scales: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn read(
+        &self,
+        stream: &mut TcpStream,
+        transaction: &mut u16,
+        start: u16,
+        count: u16,
+    ) -> Result<Vec<u16>, SensorError> {
+        *transaction = transaction.wrapping_add(1);
+        let batch = Batch {
+            unit_id: self.unit_id,
+            function: modbus::READ_HOLDING_REGISTERS,
+            start,
+            count,
+            entries: Vec::new(),
+        };
+        self.server.read(stream, *transaction, &batch)
+    }
+
+    /// Finds the SunS marker and walks the model chain from there.
+    fn discover(
+        &self,
+        stream: &mut TcpStream,
+        transaction: &mut u16,
+    ) -> Result<Vec<Option<u16>>, SensorError> {
+        let mut base = None;
+        for candidate in BASES {
+            match self.read(stream, transaction, candidate, 2) {
+                Ok(marker) if marker == MARKER => {
+                    base = Some(candidate);
+                    break;
+                }
+                // nothing there.
+                Ok(_) | Err(SensorError::Protocol(_)) => {}
+                Err(err) => return Err(err),
+            }
+        }
+        let base = base.ok_or_else(|| {
+            SensorError::Protocol(
+                "no SunS marker at 40000, 50000 or 0; not a SunSpec device?".to_string(),
+            )
+        })?;
+        let mut layout = vec![None; self.families.len()];
+        let mut address = base + 2;
+        for _ in 0..MAX_MODELS {
+            let header = match self.read(stream, transaction, address, 2) {
+                Ok(header) => header,
+                // some devices end the chain with an exception.
+                Err(SensorError::Protocol(_)) => break,
+                Err(err) => return Err(err),
+            };
+            if header[0] == END {
+                break;
+            }
+            if let Some(i) = self
+                .families
+                .iter()
+                .position(|f| f.models.contains(&header[0]))
+            {
+                match layout[i] {
+                    None => layout[i] = Some(address),
+                    Some(_) => eprintln!(
+                        "Ignoring another {} model {} of {}.",
+                        self.families[i].name, header[0], self.name
+                    ),
+                }
+            }
+            match header[1]
+                .checked_add(2)
+                .and_then(|len| address.checked_add(len))
+            {
+                Some(next) => address = next,
+                None => break,
+            }
+        }
+        for (family, start) in self.families.iter().zip(&layout) {
+            if start.is_none() {
+                eprintln!("{} has no {} model.", self.name, family.name);
+            }
+        }
+        Ok(layout)
+    }
+}
+
+impl common::Sensor for SunSpecSensor {
+    fn get_names(&self) -> Vec<String> {
+        self.families
+            .iter()
+            .flat_map(|family| {
+                family
+                    .points
+                    .iter()
+                    .map(move |p| format!("{}_{}_{}", self.name, family.name, p.metric))
+            })
+            .collect()
+    }
+
+    fn get_units(&self) -> Vec<String> {
+        self.families
+            .iter()
+            .flat_map(|family| family.points.iter().map(|p| p.unit.to_string()))
+            .collect()
+    }
+
+    fn init(&mut self) -> Result<(), SensorError> {
+        // early, to report what the device has; measure tries again if this fails.
+        let mut transaction = 0;
+        match self
+            .server
+            .connect()
+            .and_then(|mut stream| self.discover(&mut stream, &mut transaction))
+        {
+            Ok(layout) => *self.layout.get_mut().unwrap_or_else(|e| e.into_inner()) = Some(layout),
+            Err(err) => eprintln!(
+                "Could not discover the models of {} yet: {}",
+                self.name,
+                err.message()
+            ),
+        }
+        Ok(())
+    }
+
+    fn measure(&self) -> Result<Vec<Reading>, SensorError> {
+        let mut stream = self.server.connect()?;
+        let mut transaction = 0;
+        let mut layout = self.layout.lock().unwrap_or_else(|e| e.into_inner());
+        if layout.is_none() {
+            *layout = Some(self.discover(&mut stream, &mut transaction)?);
+        }
+        let starts = layout.clone().unwrap_or_default();
+        let mut scales = self.scales.lock().unwrap_or_else(|e| e.into_inner());
+        let names = self.get_names();
+        let mut values = vec![None; names.len()];
+        let mut failure = None;
+        let mut index = 0;
+        for (i, (family, start)) in self.families.iter().zip(starts).enumerate() {
+            let first = index;
+            index += family.points.len();
+            let start = match start {
+                Some(start) => start,
+                None => continue,
+            };
+            let block = match self.read(&mut stream, &mut transaction, start, family.span()) {
+                Ok(block) if family.models.contains(&block[0]) => block,
+                Ok(_) => {
+                    *layout = None;
+                    failure = Some(SensorError::Protocol(
+                        "the model chain changed; discovering it again.".to_string(),
+                    ));
+                    break;
+                }
+                Err(err) => {
+                    eprintln!("Could not read {}: {}", self.name, err.message());
+                    // only an exception leaves the connection usable.
+                    let broken = !matches!(err, SensorError::Protocol(_));
+                    failure = Some(err);
+                    if broken {
+                        break;
+                    }
+                    continue;
+                }
+            };
+            let changed: Vec<u16> = family
+                .points
+                .iter()
+                .filter_map(|p| p.scale)
+                .filter(|offset| {
+                    let sf = block.get(*offset as usize).map(|sf| *sf as i16);
+                    match (sf, scales.insert((i, *offset), sf.unwrap_or_default())) {
+                        (Some(sf), Some(previous)) => sf != previous,
+                        _ => false,
+                    }
+                })
+                .collect();
+            for (j, point) in family.points.iter().enumerate() {
+                if !matches!(point.scale, Some(offset) if changed.contains(&offset)) {
+                    values[first + j] = value(&block, point);
+                }
+            }
+        }
+        modbus::readings(names, values, failure)
+    }
+}
+
+/// SunSpec inverters, meters and batteries.
+pub(crate) fn sensor_type() -> common::SensorType {
+    common::SensorType {
+        name: "sunspec",
+        required: &["host"],
+        optional: &["port", "unit_id", "models", "timeout_secs"],
+        create: |name, sensor_cfg, _| {
+            let families = match sensor_cfg.get("models") {
+                None => FAMILIES.iter().collect(),
+                Some(v) => {
+                    let models = v
+                        .as_array()
+                        .filter(|a| !a.is_empty())
+                        .and_then(|a| {
+                            a.iter()
+                                .map(|m| {
+                                    m.as_integer().filter(|m| {
+                                        FAMILIES.iter().any(|f| f.models.contains(&(*m as u16)))
+                                    })
+                                })
+                                .collect::<Option<Vec<_>>>()
+                        })
+                        .ok_or_else(|| {
+                            ConfigError::Invalid(format!(
+                                "models must be some of 101, 102, 103, 203 and 802; got: {}.",
+                                v
+                            ))
+                        })?;
+                    FAMILIES
+                        .iter()
+                        .filter(|f| models.iter().any(|m| f.models.contains(&(*m as u16))))
+                        .collect()
+                }
+            };
+            Ok(Box::new(SunSpecSensor::new(
+                name.to_string(),
+                modbus_tcp::get_server(sensor_cfg)?,
+                modbus::get_unit_id(sensor_cfg)?,
+                families,
+            )))
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time;
+
+    use super::*;
+    use crate::common::Sensor;
+
+    type Registers = Arc<Mutex<HashMap<u16, u16>>>;
+    /// The id and length of a model and the points set in it, by offset.
+    type Model<'a> = (u16, u16, &'a [(u16, i32)]);
+
+    /// The registers of a device: the marker at the base, then each model as id, length and the points set, all
+    /// others 0.
+    fn device(base: u16, models: &[Model]) -> Registers {
+        let mut registers = HashMap::from([(base, MARKER[0]), (base + 1, MARKER[1])]);
+        let mut address = base + 2;
+        for (id, length, points) in models {
+            for offset in 0..length + 2 {
+                registers.insert(address + offset, 0);
+            }
+            registers.insert(address, *id);
+            registers.insert(address + 1, *length);
+            for (offset, value) in points.iter() {
+                registers.insert(address + offset, *value as u16);
+            }
+            address += length + 2;
+        }
+        registers.insert(address, END);
+        registers.insert(address + 1, 0);
+        Arc::new(Mutex::new(registers))
+    }
+
+    /// As read from a Fronius Symo with a Smart Meter: models 1, 103, 120 and 203.
+    fn fronius() -> Registers {
+        device(
+            40000,
+            &[
+                (1, 65, &[]),
+                (
+                    103,
+                    50,
+                    &[
+                        (2, 1234),
+                        (6, -2),
+                        (10, 2301),
+                        (13, -1),
+                        (14, 2845),
+                        (15, 0),
+                        (16, 5002),
+                        (17, -2),
+                        // 12345678 Wh.
+                        (24, 0x00bc),
+                        (25, 0x614e),
+                        (26, 0),
+                        // no DC current.
+                        (27, 0xffff),
+                        (28, -32768),
+                        (29, 6123),
+                        (30, -1),
+                        (31, 2950),
+                        (32, 0),
+                        // no temperatures.
+                        (33, -32768),
+                        (37, -32768),
+                        (38, 4),
+                    ],
+                ),
+                (120, 26, &[]),
+                (
+                    203,
+                    105,
+                    &[
+                        (2, -654),
+                        (6, -2),
+                        (7, 2312),
+                        (15, -1),
+                        (16, 4999),
+                        (17, -2),
+                        (18, -1520),
+                        (22, 0),
+                        // 2500000 and 1234567 Wh.
+                        (38, 0x0026),
+                        (39, 0x25a0),
+                        (46, 0x0012),
+                        (47, 0xd687),
+                        (54, 0),
+                    ],
+                ),
+            ],
+        )
+    }
+
+    /// As read from a single phase hybrid inverter with a battery: models 1, 101 and 802 at 50000.
+    fn hybrid() -> Registers {
+        device(
+            50000,
+            &[
+                (1, 65, &[]),
+                (
+                    101,
+                    50,
+                    &[
+                        (2, 1502),
+                        (6, -2),
+                        (10, 2298),
+                        (13, -1),
+                        (14, 3410),
+                        (15, 0),
+                        (16, 5000),
+                        (17, -2),
+                        (38, 4),
+                    ],
+                ),
+                (
+                    802,
+                    62,
+                    &[
+                        (11, 873),
+                        (56, -1),
+                        // no state of health.
+                        (13, 0xffff),
+                        (16, 3),
+                        (34, 512),
+                        (59, -1),
+                        (44, -125),
+                        (61, -1),
+                        (47, -640),
+                    ],
+                ),
+            ],
+        )
+    }
+
+    /// Serves the registers to a number of connections; unknown registers get an exception.
+    fn server(
+        registers: Registers,
+        connections: usize,
+    ) -> (modbus_tcp::Server, thread::JoinHandle<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let handle = thread::spawn(move || {
+            for stream in listener.incoming().take(connections) {
+                let mut stream = stream.unwrap();
+                let mut request = [0; 12];
+                while stream.read_exact(&mut request).is_ok() {
+                    let start = u16::from_be_bytes([request[8], request[9]]);
+                    let count = u16::from_be_bytes([request[10], request[11]]);
+                    let registers = registers.lock().unwrap();
+                    let values: Option<Vec<u16>> = (start..start.saturating_add(count))
+                        .map(|r| registers.get(&r).copied())
+                        .collect();
+                    let pdu = match values {
+                        Some(values) => {
+                            let mut pdu = vec![request[7], 2 * count as u8];
+                            pdu.extend(values.iter().flat_map(|v| v.to_be_bytes()));
+                            pdu
+                        }
+                        None => vec![request[7] | 0x80, 2],
+                    };
+                    let mut answer = request[..4].to_vec();
+                    answer.extend((pdu.len() as u16 + 1).to_be_bytes());
+                    answer.push(request[6]);
+                    answer.extend(pdu);
+                    stream.write_all(&answer).unwrap();
+                }
+            }
+        });
+        let server = modbus_tcp::Server {
+            address,
+            timeout: time::Duration::from_secs(1),
+        };
+        (server, handle)
+    }
+
+    fn values(res: Result<Vec<Reading>, SensorError>) -> HashMap<String, f64> {
+        res.unwrap()
+            .into_iter()
+            .map(|r| (r.name, r.value))
+            .collect()
+    }
+
+    // Tests for success.
+
+    #[test]
+    fn test_measure_for_success() {
+        let (server, handle) = server(fronius(), 1);
+        let sensor = SunSpecSensor::new("symo".to_string(), server, 1, FAMILIES.iter().collect());
+        assert_eq!(sensor.get_names().len(), 22);
+        assert_eq!(sensor.get_units()[..3], ["W", "A", "V"]);
+        let values = values(sensor.measure());
+        for (name, expected) in [
+            ("symo_inverter_ac_power", 2845.0),
+            ("symo_inverter_ac_current", 12.34),
+            ("symo_inverter_ac_voltage", 230.1),
+            ("symo_inverter_frequency", 50.02),
+            ("symo_inverter_energy", 12345678.0),
+            ("symo_inverter_dc_power", 2950.0),
+            ("symo_inverter_dc_voltage", 612.3),
+            ("symo_inverter_state", 4.0),
+            ("symo_meter_power", -1520.0),
+            ("symo_meter_current", -6.54),
+            ("symo_meter_voltage", 231.2),
+            ("symo_meter_frequency", 49.99),
+            ("symo_meter_energy_exported", 2500000.0),
+            ("symo_meter_energy_imported", 1234567.0),
+        ] {
+            assert_eq!(values.get(name), Some(&expected), "{}", name);
+        }
+        // not implemented, and no battery.
+        assert_eq!(values.len(), 14);
+        drop(sensor);
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_models_for_success() {
+        let (server, handle) = server(hybrid(), 2);
+        let sensor_cfg: toml::value::Table =
+            toml::from_str(&format!("host='{}'\nmodels=[101, 802]", server.address)).unwrap();
+        let mut sensor = (sensor_type().create)("hybrid", &sensor_cfg, "state").unwrap();
+        assert_eq!(sensor.get_names().len(), 16);
+        sensor.init().unwrap();
+        let values = values(sensor.measure());
+        for (name, expected) in [
+            ("hybrid_inverter_ac_power", 3410.0),
+            ("hybrid_inverter_ac_current", 15.02),
+            ("hybrid_battery_soc", 87.3),
+            ("hybrid_battery_voltage", 51.2),
+            ("hybrid_battery_current", -12.5),
+            ("hybrid_battery_power", -640.0),
+            ("hybrid_battery_charge_state", 3.0),
+        ] {
+            assert_eq!(values.get(name), Some(&expected), "{}", name);
+        }
+        // no energy counter, no state of health.
+        assert!(!values.contains_key("hybrid_inverter_energy"));
+        assert!(!values.contains_key("hybrid_battery_soh"));
+        drop(sensor);
+        handle.join().unwrap();
+    }
+
+    // Tests for failure.
+
+    #[test]
+    fn test_measure_for_failure() {
+        let (server, handle) = server(Arc::new(Mutex::new(HashMap::new())), 1);
+        let sensor = SunSpecSensor::new("symo".to_string(), server, 1, FAMILIES.iter().collect());
+        match sensor.measure() {
+            Err(SensorError::Protocol(msg)) => assert_eq!(
+                msg,
+                "no SunS marker at 40000, 50000 or 0; not a SunSpec device?"
+            ),
+            _ => panic!("missing marker not reported."),
+        }
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_sensor_type_for_failure() {
+        let sensor_cfg: toml::value::Table =
+            toml::from_str("host='symo'\nmodels=[103, 160]").unwrap();
+        assert_eq!(
+            (sensor_type().create)("symo", &sensor_cfg, "state").err(),
+            Some(ConfigError::Invalid(
+                "models must be some of 101, 102, 103, 203 and 802; got: [103, 160].".to_string()
+            ))
+        );
+    }
+
+    // Tests for sanity.
+
+    #[test]
+    fn test_scale_change_for_sanity() {
+        let registers = fronius();
+        let (server, handle) = server(registers.clone(), 3);
+        let sensor = SunSpecSensor::new("symo".to_string(), server, 1, vec![&FAMILIES[0]]);
+        assert_eq!(values(sensor.measure())["symo_inverter_ac_power"], 2845.0);
+        // the model starts at 40002 + 67.
+        {
+            let mut registers = registers.lock().unwrap();
+            registers.insert(40069 + 14, 285);
+            registers.insert(40069 + 15, 1);
+        }
+        let values_now = values(sensor.measure());
+        assert!(!values_now.contains_key("symo_inverter_ac_power"));
+        assert_eq!(values_now["symo_inverter_ac_current"], 12.34);
+        assert_eq!(values(sensor.measure())["symo_inverter_ac_power"], 2850.0);
+        drop(sensor);
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_value_for_sanity() {
+        let mut block = vec![0; 64];
+        block[14] = 0x8000;
+        block[15] = 0;
+        assert_eq!(value(&block, &INVERTER[0]), None);
+        block[14] = (-1520i16) as u16;
+        assert_eq!(value(&block, &INVERTER[0]), Some(-1520.0));
+        // a scale factor not implemented.
+        block[15] = 0x8000;
+        assert_eq!(value(&block, &INVERTER[0]), None);
+        // an accumulator not implemented.
+        block[26] = 0;
+        assert_eq!(value(&block, &INVERTER[4]), None);
+        block[25] = 1;
+        assert_eq!(value(&block, &INVERTER[4]), Some(1.0));
+        // a model shorter than expected.
+        assert_eq!(value(&block[..20], &INVERTER[8]), None);
+        assert_eq!(FAMILIES[1].span(), 55);
+    }
+}
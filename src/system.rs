@@ -0,0 +1,283 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs;
+use std::path;
+
+use crate::common;
+use crate::common::{ConfigError, Reading, SensorError};
+
+/// Busy and total jiffies of each line of /proc/stat starting with cpu; the first one is the whole host.
+pub(crate) fn parse_cpus(stat: &str) -> Vec<(String, f64, f64)> {
+    stat.lines()
+        .filter(|l| l.starts_with("cpu"))
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let label = fields.next()?.to_string();
+            let values: Vec<f64> = fields.map(|v| v.parse().ok()).collect::<Option<_>>()?;
+            if values.len() < 5 {
+                return None;
+            }
+            // idle & iowait are not busy; guest time is already part of user time.
+            let total: f64 = values.iter().take(8).sum();
+            Some((label, total - values[3] - values[4], total))
+        })
+        .collect()
+}
+
+/// The load averages over 1, 5 and 15 minutes given the contents of /proc/loadavg.
+pub(crate) fn parse_loadavg(loadavg: &str) -> Option<[f64; 3]> {
+    let mut fields = loadavg.split_whitespace().map(|v| v.parse().ok());
+    Some([fields.next()??, fields.next()??, fields.next()??])
+}
+
+/// Reports how busy the host is: its CPU utilisation in percent, optionally per core, and its load averages.
+///
+/// The utilisation is computed from the jiffies since the previous measurement, so the first one only reports the load.
+pub struct SystemSensor {
+    name: String,
+    proc_dir: String,
+    // the labels of the cores, e.g. cpu0; empty unless reported per core.
+    cores: Vec<String>,
+    // busy and total jiffies per label of the previous measurement.
+    last: RefCell<HashMap<String, (f64, f64)>>,
+}
+
+impl SystemSensor {
+    pub(crate) fn new(name: String, proc_dir: String, cores: Vec<String>) -> SystemSensor {
+        SystemSensor {
+            name,
+            proc_dir,
+            cores,
+            last: RefCell::new(HashMap::new()),
+        }
+    }
+
+    fn read(&self, file: &str) -> Result<String, SensorError> {
+        let path = path::Path::new(&self.proc_dir).join(file);
+        fs::read_to_string(&path)
+            .map_err(|e| SensorError::Io(format!("cannot read {}: {}", path.display(), e)))
+    }
+}
+
+impl common::Sensor for SystemSensor {
+    fn get_names(&self) -> Vec<String> {
+        let mut res: Vec<String> = ["cpu_percent", "load1", "load5", "load15"]
+            .iter()
+            .map(|metric| format!("{}_{}", self.name, metric))
+            .collect();
+        res.extend(
+            self.cores
+                .iter()
+                .map(|core| format!("{}_{}_percent", self.name, core)),
+        );
+        res
+    }
+
+    fn get_units(&self) -> Vec<String> {
+        let mut res = vec!["%".to_string(), String::new(), String::new(), String::new()];
+        res.extend(vec!["%".to_string(); self.cores.len()]);
+        res
+    }
+
+    fn measure(&self) -> Result<Vec<Reading>, SensorError> {
+        let names = self.get_names();
+        let cpus = parse_cpus(&self.read("stat")?);
+        if cpus.is_empty() {
+            return Err(SensorError::Parse(format!(
+                "no cpu lines in {}/stat.",
+                self.proc_dir
+            )));
+        }
+        let loads = self.read("loadavg")?;
+        let loads = parse_loadavg(&loads).ok_or_else(|| {
+            SensorError::Parse(format!("{} of {}/loadavg.", loads.trim(), self.proc_dir))
+        })?;
+
+        let mut res: Vec<Reading> = names[1..4]
+            .iter()
+            .zip(loads)
+            .map(|(name, load)| Reading::new(name.clone(), load))
+            .collect();
+        let mut last = self.last.borrow_mut();
+        for (label, busy, total) in cpus {
+            let name = if label == "cpu" {
+                &names[0]
+            } else {
+                match self.cores.iter().position(|c| c == &label) {
+                    Some(i) => &names[4 + i],
+                    None => continue,
+                }
+            };
+            // nothing to compare the first one to; nor after the counters were reset.
+            if let Some((busy0, total0)) = last.insert(label, (busy, total)) {
+                if total > total0 && busy >= busy0 {
+                    let percent = 100.0 * (busy - busy0) / (total - total0);
+                    res.push(Reading::new(name.clone(), percent));
+                }
+            }
+        }
+        Ok(res)
+    }
+}
+
+/// CPU utilisation and load of the host.
+pub(crate) fn sensor_type() -> common::SensorType {
+    common::SensorType {
+        name: "system",
+        required: &[],
+        optional: &["path", "per_core"],
+        create: |name, sensor_cfg, _| {
+            let proc_dir = match sensor_cfg.get("path") {
+                Some(v) => v
+                    .as_str()
+                    .ok_or_else(|| ConfigError::Invalid("path must be a string.".to_string()))?,
+                None => "/proc",
+            };
+            let per_core = match sensor_cfg.get("per_core") {
+                Some(v) => v.as_bool().ok_or_else(|| {
+                    ConfigError::Invalid("per_core must be true or false.".to_string())
+                })?,
+                None => false,
+            };
+            let mut cores = Vec::new();
+            if per_core {
+                let stat =
+                    fs::read_to_string(path::Path::new(proc_dir).join("stat")).map_err(|e| {
+                        ConfigError::Invalid(format!("cannot read {}/stat: {}", proc_dir, e))
+                    })?;
+                cores = parse_cpus(&stat)
+                    .into_iter()
+                    .map(|(label, _, _)| label)
+                    .filter(|label| label != "cpu")
+                    .collect();
+            }
+            Ok(Box::new(SystemSensor::new(
+                name.to_string(),
+                proc_dir.to_string(),
+                cores,
+            )))
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::values;
+    use crate::common::Sensor;
+
+    const STAT0: &str = "cpu  1000 0 500 8000 500 0 0 0 0 0\n\
+    cpu0 500 0 250 4000 250 0 0 0 0 0\n\
+    cpu1 500 0 250 4000 250 0 0 0 0 0\n\
+    intr 12345 0 0\n\
+    ctxt 67890\n";
+
+    // 100 jiffies later: cpu0 was busy for 80 of them, cpu1 for 20.
+    const STAT1: &str = "cpu  1080 0 520 8090 510 0 0 0 0 0\n\
+    cpu0 570 0 260 4015 255 0 0 0 0 0\n\
+    cpu1 510 0 260 4075 255 0 0 0 0 0\n\
+    intr 12400 0 0\n\
+    ctxt 67990\n";
+
+    const LOADAVG: &str = "0.52 0.58 0.59 2/1234 56789\n";
+
+    fn proc_dir(dir: &str, stat: &str, loadavg: &str) {
+        fs::create_dir_all(dir).unwrap();
+        fs::write(path::Path::new(dir).join("stat"), stat).unwrap();
+        fs::write(path::Path::new(dir).join("loadavg"), loadavg).unwrap();
+    }
+
+    // Tests for success.
+
+    #[test]
+    fn test_parse_for_success() {
+        let cpus = parse_cpus(STAT0);
+        assert_eq!(
+            cpus,
+            vec![
+                ("cpu".to_string(), 1500.0, 10000.0),
+                ("cpu0".to_string(), 750.0, 5000.0),
+                ("cpu1".to_string(), 750.0, 5000.0)
+            ]
+        );
+        assert_eq!(parse_loadavg(LOADAVG), Some([0.52, 0.58, 0.59]));
+    }
+
+    #[test]
+    fn test_measure_for_success() {
+        let dir = "system_test0";
+        proc_dir(dir, STAT0, LOADAVG);
+        let sensor_cfg: toml::value::Table =
+            toml::from_str("path='system_test0'\nper_core=true").unwrap();
+        let sensor = (sensor_type().create)("host", &sensor_cfg, "state").unwrap();
+        assert_eq!(
+            sensor.get_names(),
+            vec![
+                "host_cpu_percent",
+                "host_load1",
+                "host_load5",
+                "host_load15",
+                "host_cpu0_percent",
+                "host_cpu1_percent"
+            ]
+        );
+        // no utilisation yet; neither 0 nor 100.
+        assert_eq!(
+            values(sensor.measure()),
+            vec![
+                ("host_load1".to_string(), 0.52),
+                ("host_load5".to_string(), 0.58),
+                ("host_load15".to_string(), 0.59)
+            ]
+        );
+        proc_dir(dir, STAT1, LOADAVG);
+        assert_eq!(
+            values(sensor.measure())[3..],
+            [
+                ("host_cpu_percent".to_string(), 50.0),
+                ("host_cpu0_percent".to_string(), 80.0),
+                ("host_cpu1_percent".to_string(), 20.0)
+            ]
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    // Tests for failure.
+
+    #[test]
+    fn test_parse_for_failure() {
+        assert!(parse_cpus("intr 12345 0 0\ncpu 1 2\n").is_empty());
+        assert_eq!(parse_loadavg("0.52 0.58\n"), None);
+        assert_eq!(parse_loadavg(""), None);
+    }
+
+    #[test]
+    fn test_measure_for_failure() {
+        let sensor =
+            SystemSensor::new("host".to_string(), "system_missing".to_string(), Vec::new());
+        assert!(matches!(sensor.measure(), Err(SensorError::Io(_))));
+
+        let dir = "system_test1";
+        proc_dir(dir, STAT0, "nonsense\n");
+        let sensor = SystemSensor::new("host".to_string(), dir.to_string(), Vec::new());
+        assert!(matches!(sensor.measure(), Err(SensorError::Parse(_))));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    // Tests for sanity.
+
+    #[test]
+    fn test_reset_for_sanity() {
+        let dir = "system_test2";
+        proc_dir(dir, STAT1, LOADAVG);
+        let sensor = SystemSensor::new("host".to_string(), dir.to_string(), Vec::new());
+        assert_eq!(sensor.get_units(), vec!["%", "", "", ""]);
+        sensor.measure().unwrap();
+        // e.g. a suspended VM restored from an older snapshot.
+        proc_dir(dir, STAT0, LOADAVG);
+        assert_eq!(values(sensor.measure()).len(), 3);
+        proc_dir(dir, STAT1, LOADAVG);
+        assert_eq!(values(sensor.measure())[3].1, 50.0);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
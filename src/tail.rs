@@ -0,0 +1,405 @@
+use std::fs;
+use std::io::{Read, Seek, SeekFrom};
+use std::os::unix::fs::MetadataExt;
+
+use crate::calibration;
+use crate::csv_out;
+
+/// Width of a column when printed; wider names widen their column.
+const WIDTH: usize = 10;
+
+/// Follows a data file like `tail -f`, reopening it when it was rotated or truncated; the file is
+/// CSV in the given format, or JSON lines (format None).
+pub(crate) struct Follower {
+    path: String,
+    format: Option<csv_out::Format>,
+    /// Names and units of the configured columns; used where the file does not tell.
+    schema: Vec<(String, String)>,
+    inode: Option<u64>,
+    offset: u64,
+    /// The end of a line that was not completely written yet.
+    partial: String,
+    names: Vec<String>,
+    units: Vec<String>,
+    /// Whether the next line might be the line of units below the header.
+    header_read: bool,
+}
+
+impl Follower {
+    pub(crate) fn new(
+        path: &str,
+        format: Option<csv_out::Format>,
+        schema: Vec<(String, String)>,
+    ) -> Follower {
+        let mut follower = Follower {
+            path: path.to_string(),
+            format,
+            schema,
+            inode: None,
+            offset: 0,
+            partial: String::new(),
+            names: Vec::new(),
+            units: Vec::new(),
+            header_read: false,
+        };
+        follower.reset();
+        follower
+    }
+
+    /// Starts over with a new file; JSON lines have no header, so their columns are the configured ones.
+    fn reset(&mut self) {
+        self.offset = 0;
+        self.partial.clear();
+        self.header_read = false;
+        self.names.clear();
+        if self.format.is_none() {
+            self.names = self.schema.iter().map(|(name, _)| name.clone()).collect();
+        }
+        self.units = self.lookup();
+    }
+
+    /// The configured units of the current columns.
+    fn lookup(&self) -> Vec<String> {
+        self.names
+            .iter()
+            .map(|name| {
+                self.schema
+                    .iter()
+                    .find(|(n, _)| n == name)
+                    .map(|(_, unit)| unit.clone())
+                    .unwrap_or_default()
+            })
+            .collect()
+    }
+
+    /// The column names from the header of the current file.
+    pub(crate) fn names(&self) -> &[String] {
+        &self.names
+    }
+
+    /// The units of the columns; from the line of units in the file if it has one.
+    pub(crate) fn units(&self) -> &[String] {
+        &self.units
+    }
+
+    /// Rows appended since the last poll; the flag tells whether the file was (re)opened, so the header might differ.
+    pub(crate) fn poll(&mut self) -> (bool, Vec<Vec<String>>) {
+        let metadata = match fs::metadata(&self.path) {
+            Ok(metadata) => metadata,
+            // in the middle of a rotation; try again later.
+            Err(_) => return (false, Vec::new()),
+        };
+        let reopened = self.inode != Some(metadata.ino()) || metadata.len() < self.offset;
+        if reopened {
+            self.inode = Some(metadata.ino());
+            self.reset();
+        }
+
+        let mut file = match fs::File::open(&self.path) {
+            Ok(file) => file,
+            Err(_) => return (false, Vec::new()),
+        };
+        let mut appended = String::new();
+        if file.seek(SeekFrom::Start(self.offset)).is_err()
+            || file.read_to_string(&mut appended).is_err()
+        {
+            return (false, Vec::new());
+        }
+        self.offset += appended.len() as u64;
+        self.partial.push_str(&appended);
+
+        let mut rows = Vec::new();
+        while let Some(end) = self.partial.find('\n') {
+            let line: String = self.partial.drain(..=end).collect();
+            let line = line.trim_end_matches(['\r', '\n']);
+            let format = match &self.format {
+                Some(format) => format,
+                None => {
+                    rows.extend(self.parse(line));
+                    continue;
+                }
+            };
+            let fields = format.split(line);
+            if self.names.is_empty() {
+                self.names = fields;
+                self.units = self.lookup();
+                self.header_read = true;
+            } else if std::mem::take(&mut self.header_read)
+                && fields.first().map(|f| f.parse::<f64>().is_err()) == Some(true)
+            {
+                // rows start with the timestamp; this is the line of units.
+                self.units = fields;
+            } else {
+                rows.push(fields);
+            }
+        }
+        (reopened, rows)
+    }
+
+    /// The fields of a JSON line in the order of the columns; null (a failure) becomes NaN.
+    fn parse(&self, line: &str) -> Option<Vec<String>> {
+        let object: serde_json::Value = serde_json::from_str(line).ok()?;
+        let fields = self
+            .names
+            .iter()
+            .map(|name| match object.get(name) {
+                None | Some(serde_json::Value::Null) => "NaN".to_string(),
+                Some(serde_json::Value::String(value)) => value.clone(),
+                Some(value) => value.to_string(),
+            })
+            .collect();
+        Some(fields)
+    }
+}
+
+/// Indices of the given columns; all columns if none are given.
+pub(crate) fn resolve(names: &[String], columns: &[String]) -> Result<Vec<usize>, String> {
+    if columns.is_empty() {
+        return Ok((0..names.len()).collect());
+    }
+    columns
+        .iter()
+        .map(|column| {
+            names.iter().position(|n| n == column).ok_or_else(|| {
+                format!(
+                    "No column {} in the data file; available are: {}.",
+                    column,
+                    names.join(", ")
+                )
+            })
+        })
+        .collect()
+}
+
+fn width(name: &str) -> usize {
+    name.chars().count().max(WIDTH)
+}
+
+pub(crate) fn format_header(names: &[String], indices: &[usize]) -> String {
+    let cells: Vec<String> = indices
+        .iter()
+        .map(|i| format!("{:>w$}", names[*i], w = width(&names[*i])))
+        .collect();
+    cells.join("  ")
+}
+
+/// A row aligned to the header; missing values (written as missing, or NaN) are shown as '-' and highlighted in red
+/// when colored.
+pub(crate) fn format_row(
+    names: &[String],
+    units: &[String],
+    indices: &[usize],
+    fields: &[String],
+    missing: &str,
+    color: bool,
+) -> String {
+    let cells: Vec<String> = indices
+        .iter()
+        .map(|i| {
+            let field = fields.get(*i).map(|f| f.as_str()).unwrap_or("");
+            let failed =
+                field == missing || field.parse().map(calibration::is_failure).unwrap_or(false);
+            let text = match units.get(*i).map(|u| u.as_str()).unwrap_or("") {
+                _ if failed => "-".to_string(),
+                "" => field.to_string(),
+                unit => format!("{} {}", field, unit),
+            };
+            let cell = format!("{:>w$}", text, w = width(&names[*i]));
+            if color && failed {
+                format!("\x1b[31m{}\x1b[0m", cell)
+            } else {
+                cell
+            }
+        })
+        .collect();
+    cells.join("  ")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+
+    fn append(path: &str, content: &str) {
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .unwrap();
+        write!(file, "{}", content).unwrap();
+    }
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|n| n.to_string()).collect()
+    }
+
+    // Tests for success.
+
+    #[test]
+    fn test_resolve_for_success() {
+        let header = names(&["timestamp", "pv_power", "grid_power"]);
+        assert_eq!(resolve(&header, &[]), Ok(vec![0, 1, 2]));
+        assert_eq!(
+            resolve(&header, &names(&["grid_power", "timestamp"])),
+            Ok(vec![2, 0])
+        );
+    }
+
+    #[test]
+    fn test_format_row_for_success() {
+        let header = names(&["timestamp", "pv_power", "owa_description"]);
+        let units = names(&["s", "W", ""]);
+        let row = names(&["1700000000", "-1", "800"]);
+        // a legitimate reading, even if negative.
+        assert_eq!(
+            format_row(&header, &units, &[1, 2], &row, "", true),
+            "      -1 W              800"
+        );
+        let row = names(&["1700000000", "", "NaN"]);
+        assert_eq!(
+            format_row(&header, &units, &[1, 2], &row, "", false),
+            "         -                -"
+        );
+        assert_eq!(
+            format_row(&header, &units, &[1], &row, "", true),
+            "\x1b[31m         -\x1b[0m"
+        );
+        assert_eq!(
+            format_header(&header, &[1, 2]),
+            "  pv_power  owa_description"
+        );
+    }
+
+    // Tests for failure.
+
+    #[test]
+    fn test_resolve_for_failure() {
+        let header = names(&["timestamp", "pv_power"]);
+        let err = resolve(&header, &names(&["grid_power"])).unwrap_err();
+        assert!(err.contains("grid_power"));
+        assert!(err.contains("timestamp, pv_power"));
+    }
+
+    #[test]
+    fn test_poll_for_failure() {
+        // no file yet, nothing to show.
+        let mut follower = Follower::new(
+            "tail_test0.csv",
+            Some(csv_out::Format::default()),
+            Vec::new(),
+        );
+        assert_eq!(follower.poll(), (false, Vec::<Vec<String>>::new()));
+        assert!(follower.names().is_empty());
+    }
+
+    // Tests for sanity.
+
+    #[test]
+    fn test_follow_for_sanity() {
+        let path = "tail_test1.csv";
+        append(path, "timestamp,pv_power\n1,100\n");
+        let mut follower = Follower::new(path, Some(csv_out::Format::default()), Vec::new());
+        assert_eq!(follower.poll(), (true, vec![names(&["1", "100"])]));
+        assert_eq!(follower.names(), names(&["timestamp", "pv_power"]));
+
+        // half written lines wait for their end.
+        append(path, "2,20");
+        assert_eq!(follower.poll(), (false, Vec::<Vec<String>>::new()));
+        append(path, "0\n3,300\n");
+        assert_eq!(
+            follower.poll(),
+            (false, vec![names(&["2", "200"]), names(&["3", "300"])])
+        );
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_format_for_sanity() {
+        let path = "tail_test3.csv";
+        append(
+            path,
+            "\"timestamp\";\"pv_power\"\n\"1\";\"100\"\n\"2\";\"\"\n",
+        );
+        let format = csv_out::Format::new(";", None, true, None, String::new());
+        let mut follower = Follower::new(path, Some(format), Vec::new());
+        assert_eq!(
+            follower.poll(),
+            (true, vec![names(&["1", "100"]), names(&["2", ""])])
+        );
+        assert_eq!(follower.names(), names(&["timestamp", "pv_power"]));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_units_for_sanity() {
+        let path = "tail_test4.csv";
+        let schema = vec![
+            ("timestamp".to_string(), "s".to_string()),
+            ("pv_power".to_string(), "kW".to_string()),
+        ];
+        // without a line of units, the configured ones are used.
+        append(path, "timestamp,pv_power,foo\n1,100,2\n");
+        let mut follower = Follower::new(path, Some(csv_out::Format::default()), schema);
+        assert_eq!(follower.poll(), (true, vec![names(&["1", "100", "2"])]));
+        assert_eq!(follower.units(), names(&["s", "kW", ""]));
+
+        // the line of units below the header is no row.
+        fs::rename(path, "tail_test4.csv.1").unwrap();
+        append(path, "timestamp,pv_power,foo\ns,W,V\n1,100,2\n");
+        assert_eq!(follower.poll(), (true, vec![names(&["1", "100", "2"])]));
+        assert_eq!(follower.units(), names(&["s", "W", "V"]));
+        fs::remove_file(path).unwrap();
+        fs::remove_file("tail_test4.csv.1").unwrap();
+    }
+
+    #[test]
+    fn test_jsonl_for_sanity() {
+        let path = "tail_test5.jsonl";
+        let schema = vec![
+            ("timestamp".to_string(), "s".to_string()),
+            ("pv_power".to_string(), "W".to_string()),
+        ];
+        append(
+            path,
+            "{\"timestamp\":1,\"pv_power\":100.5}\n{\"timestamp\":2,\"pv_power\":null}\n",
+        );
+        let mut follower = Follower::new(path, None, schema);
+        assert_eq!(follower.names(), names(&["timestamp", "pv_power"]));
+        assert_eq!(
+            follower.poll(),
+            (true, vec![names(&["1", "100.5"]), names(&["2", "NaN"])])
+        );
+        assert_eq!(follower.units(), names(&["s", "W"]));
+
+        // truncated; the columns stay the configured ones.
+        fs::write(path, "{\"timestamp\":3,\"pv_power\":300}\n").unwrap();
+        assert_eq!(follower.poll(), (true, vec![names(&["3", "300"])]));
+        assert_eq!(follower.names(), names(&["timestamp", "pv_power"]));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_rotation_for_sanity() {
+        let (path, rotated) = ("tail_test2.csv", "tail_test2.csv.1");
+        append(path, "timestamp,pv_power\n1,100\n");
+        let mut follower = Follower::new(path, Some(csv_out::Format::default()), Vec::new());
+        follower.poll();
+
+        // rotated away and recreated with another header; read from its start.
+        fs::rename(path, rotated).unwrap();
+        append(rotated, "2,200\n");
+        append(path, "timestamp,pv_power,grid_power\n3,300,-1\n");
+        assert_eq!(follower.poll(), (true, vec![names(&["3", "300", "-1"])]));
+        assert_eq!(
+            follower.names(),
+            names(&["timestamp", "pv_power", "grid_power"])
+        );
+
+        // truncated in place.
+        fs::write(path, "timestamp,pv_power\n4,400\n").unwrap();
+        assert_eq!(follower.poll(), (true, vec![names(&["4", "400"])]));
+        fs::remove_file(path).unwrap();
+        fs::remove_file(rotated).unwrap();
+    }
+}
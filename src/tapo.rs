@@ -0,0 +1,600 @@
+use std::sync::{Arc, Mutex};
+use std::time;
+
+use openssl::sha::{sha1, Sha256};
+use openssl::symm::{decrypt, encrypt, Cipher};
+use reqwest::header::{COOKIE, SET_COOKIE};
+
+use crate::clock;
+use crate::common;
+use crate::common::{ConfigError, Reading, SensorError};
+use crate::debug;
+use crate::shelly;
+
+/// The metrics reported and their units.
+const METRICS: [(&str, &str); 3] = [
+    ("power", "W"),
+    ("today_energy", "Wh"),
+    ("month_energy", "Wh"),
+];
+
+/// The methods called and which of their results go into which of the metrics.
+const CALLS: [(&str, &[(&str, usize)]); 2] = [
+    ("get_current_power", &[("current_power", 0)]),
+    (
+        "get_energy_usage",
+        &[("today_energy", 1), ("month_energy", 2)],
+    ),
+];
+
+/// How long a session lasts if the plug does not say.
+const SESSION_SECS: u64 = 86400;
+
+fn hash(parts: &[&[u8]]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    for part in parts {
+        hasher.update(part);
+    }
+    hasher.finish()
+}
+
+/// The hash of the TP-Link account credentials both sides prove they know.
+pub(crate) fn auth_hash(username: &str, password: &str) -> [u8; 32] {
+    hash(&[&sha1(username.as_bytes()), &sha1(password.as_bytes())])
+}
+
+/// The remote seed given the answer to handshake1, after checking the plug knows the credentials.
+pub(crate) fn check_handshake1(
+    local_seed: &[u8; 16],
+    answer: &[u8],
+    auth_hash: &[u8; 32],
+) -> Result<[u8; 16], SensorError> {
+    if answer.len() != 48 {
+        return Err(SensorError::Protocol(format!(
+            "handshake1 answer must be 48 bytes; got: {}.",
+            answer.len()
+        )));
+    }
+    let remote_seed: [u8; 16] = answer[..16].try_into().unwrap();
+    if hash(&[local_seed, &remote_seed, auth_hash])[..] != answer[16..] {
+        return Err(SensorError::Protocol(
+            "the plug does not know these credentials; check username and password.".to_string(),
+        ));
+    }
+    Ok(remote_seed)
+}
+
+/// The body of handshake2, proving we know the credentials too.
+pub(crate) fn handshake2(
+    local_seed: &[u8; 16],
+    remote_seed: &[u8; 16],
+    auth_hash: &[u8; 32],
+) -> [u8; 32] {
+    hash(&[remote_seed, local_seed, auth_hash])
+}
+
+/// The session id and its lifetime in secs given the cookie set in handshake1, e.g. TP_SESSIONID=AB12;TIMEOUT=86400.
+pub(crate) fn parse_cookie(cookie: &str) -> Option<(String, u64)> {
+    let mut id = None;
+    let mut timeout = SESSION_SECS;
+    for part in cookie.split(';').map(|p| p.trim()) {
+        if part.starts_with("TP_SESSIONID=") {
+            id = Some(part.to_string());
+        } else if let Some(secs) = part.strip_prefix("TIMEOUT=") {
+            timeout = secs.parse().ok()?;
+        }
+    }
+    Some((id?, timeout))
+}
+
+/// A KLAP session: the keys derived from both seeds and the sequence number of the last request.
+pub(crate) struct Session {
+    cookie: String,
+    expires: time::Instant,
+    key: [u8; 16],
+    iv: [u8; 12],
+    sig: [u8; 28],
+    seq: i32,
+}
+
+impl Session {
+    pub(crate) fn new(
+        cookie: String,
+        timeout: time::Duration,
+        local_seed: &[u8; 16],
+        remote_seed: &[u8; 16],
+        auth_hash: &[u8; 32],
+    ) -> Session {
+        let derive = |label: &[u8]| hash(&[label, local_seed, remote_seed, auth_hash]);
+        let iv = derive(b"iv");
+        Session {
+            cookie,
+            expires: time::Instant::now() + timeout,
+            key: derive(b"lsk")[..16].try_into().unwrap(),
+            iv: iv[..12].try_into().unwrap(),
+            sig: derive(b"ldk")[..28].try_into().unwrap(),
+            seq: i32::from_be_bytes(iv[28..].try_into().unwrap()),
+        }
+    }
+
+    fn iv_of(&self, seq: i32) -> [u8; 16] {
+        let mut res = [0; 16];
+        res[..12].copy_from_slice(&self.iv);
+        res[12..].copy_from_slice(&seq.to_be_bytes());
+        res
+    }
+
+    /// The sequence number and payload of the next request: signature and AES-128-CBC cipher text.
+    pub(crate) fn encrypt(&mut self, msg: &[u8]) -> Result<(i32, Vec<u8>), SensorError> {
+        self.seq = self.seq.wrapping_add(1);
+        let cipher_text = encrypt(
+            Cipher::aes_128_cbc(),
+            &self.key,
+            Some(&self.iv_of(self.seq)),
+            msg,
+        )
+        .map_err(|e| SensorError::Protocol(format!("cannot encrypt the request: {}", e)))?;
+        let signature = hash(&[&self.sig, &self.seq.to_be_bytes(), &cipher_text]);
+        Ok((self.seq, [&signature[..], &cipher_text[..]].concat()))
+    }
+
+    /// The answer to the request with the given sequence number.
+    pub(crate) fn decrypt(&self, seq: i32, payload: &[u8]) -> Result<Vec<u8>, SensorError> {
+        if payload.len() < 32 {
+            return Err(SensorError::Parse(format!(
+                "answer must be at least 32 bytes; got: {}.",
+                payload.len()
+            )));
+        }
+        decrypt(
+            Cipher::aes_128_cbc(),
+            &self.key,
+            Some(&self.iv_of(seq)),
+            &payload[32..],
+        )
+        .map_err(|e| SensorError::Parse(format!("cannot decrypt the answer: {}", e)))
+    }
+}
+
+/// What the plug answered to a request.
+#[derive(Debug, PartialEq)]
+pub(crate) enum Answer {
+    Result(serde_json::Value),
+    /// The session is no longer valid; a new handshake is needed.
+    Expired,
+    Failed(i64),
+}
+
+/// The answer given its decrypted body; the error codes 1003 and 9999 mean the session is gone.
+pub(crate) fn parse_answer(body: &[u8]) -> Result<Answer, SensorError> {
+    let body: serde_json::Value =
+        serde_json::from_slice(body).map_err(|e| SensorError::Parse(e.to_string()))?;
+    match body["error_code"].as_i64() {
+        Some(0) => Ok(Answer::Result(body["result"].clone())),
+        Some(1003) | Some(9999) => Ok(Answer::Expired),
+        Some(code) => Ok(Answer::Failed(code)),
+        None => Err(SensorError::Parse(format!("no error_code in {}.", body))),
+    }
+}
+
+fn random_seed() -> [u8; 16] {
+    let mut seed = [0; 16];
+    openssl::rand::rand_bytes(&mut seed).expect("no randomness for the seed.");
+    seed
+}
+
+/// Reads a TP-Link Tapo P110 (or P115) via its local KLAP protocol: the current power and the energy of today and
+/// this month.
+///
+/// The session is kept between measurements and renewed once it times out or the plug no longer accepts it.
+pub struct TapoSensor {
+    name: String,
+    url: String,
+    auth_hash: [u8; 32],
+    session: Mutex<Option<Session>>,
+    local_seed: fn() -> [u8; 16],
+    client: reqwest::blocking::Client,
+    ring: Arc<debug::Ring>,
+}
+
+impl TapoSensor {
+    pub(crate) fn new(
+        name: String,
+        url: String,
+        username: &str,
+        password: &str,
+        timeout: time::Duration,
+    ) -> TapoSensor {
+        TapoSensor {
+            ring: debug::ring(&name),
+            name,
+            url,
+            auth_hash: auth_hash(username, password),
+            session: Mutex::new(None),
+            local_seed: random_seed,
+            client: reqwest::blocking::Client::builder()
+                .timeout(timeout)
+                .build()
+                .unwrap(),
+        }
+    }
+
+    /// The status code, cookie and body of the answer.
+    fn post(
+        &self,
+        path: &str,
+        body: Vec<u8>,
+        cookie: Option<&str>,
+    ) -> Result<(u16, Option<String>, Vec<u8>), SensorError> {
+        let query = format!("{}{}", self.url, path);
+        let mut request = self.client.post(&query).body(body);
+        if let Some(cookie) = cookie {
+            request = request.header(COOKIE, cookie);
+        }
+        let started = time::Instant::now();
+        let res = match request.send() {
+            Ok(res) => res,
+            Err(err) => {
+                self.ring.record(&query, None, &err.to_string(), started);
+                return Err(SensorError::from(err));
+            }
+        };
+        let status = res.status().as_u16();
+        let cookie = res
+            .headers()
+            .get(SET_COOKIE)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.to_string());
+        let body = res.bytes()?.to_vec();
+        self.ring.record(
+            &query,
+            Some(status),
+            &format!("{} bytes", body.len()),
+            started,
+        );
+        Ok((status, cookie, body))
+    }
+
+    fn handshake(&self) -> Result<Session, SensorError> {
+        let local_seed = (self.local_seed)();
+        let (status, cookie, body) = self.post("/app/handshake1", local_seed.to_vec(), None)?;
+        if status != 200 {
+            return Err(SensorError::Http(format!(
+                "Status code was not 200; but: {} - not a KLAP device?",
+                status
+            )));
+        }
+        let remote_seed = check_handshake1(&local_seed, &body, &self.auth_hash)?;
+        let (cookie, timeout) = cookie
+            .as_deref()
+            .and_then(parse_cookie)
+            .ok_or_else(|| SensorError::Protocol("no session cookie in handshake1.".to_string()))?;
+        let proof = handshake2(&local_seed, &remote_seed, &self.auth_hash);
+        let (status, _, _) = self.post("/app/handshake2", proof.to_vec(), Some(&cookie))?;
+        if status != 200 {
+            return Err(SensorError::Protocol(format!(
+                "handshake2 was refused with: {}.",
+                status
+            )));
+        }
+        Ok(Session::new(
+            cookie,
+            time::Duration::from_secs(timeout),
+            &local_seed,
+            &remote_seed,
+            &self.auth_hash,
+        ))
+    }
+
+    /// The result of a method; a session the plug refuses is renewed once.
+    fn call(&self, method: &str) -> Result<serde_json::Value, SensorError> {
+        let mut session = self.session.lock().unwrap_or_else(|e| e.into_inner());
+        let mut renewed = false;
+        loop {
+            if session
+                .as_ref()
+                .filter(|s| s.expires > time::Instant::now())
+                .is_none()
+            {
+                *session = Some(self.handshake()?);
+                renewed = true;
+            }
+            let current = session.as_mut().unwrap();
+            let request = serde_json::json!({
+                "method": method,
+                "requestTimeMils": (clock::epoch_secs(time::SystemTime::now()) * 1000.0) as u64,
+            });
+            let (seq, payload) = current.encrypt(request.to_string().as_bytes())?;
+            let (status, _, body) = self.post(
+                &format!("/app/request?seq={}", seq),
+                payload,
+                Some(&current.cookie),
+            )?;
+            let answer = match status {
+                200 => parse_answer(&current.decrypt(seq, &body)?)?,
+                // e.g. after the plug restarted.
+                401 | 403 => Answer::Expired,
+                _ => {
+                    return Err(SensorError::Http(format!(
+                        "Status code was not 200; but: {}.",
+                        status
+                    )))
+                }
+            };
+            match answer {
+                Answer::Result(result) => return Ok(result),
+                Answer::Expired => {
+                    *session = None;
+                    if renewed {
+                        return Err(SensorError::Protocol(format!(
+                            "{} was refused with a new session.",
+                            method
+                        )));
+                    }
+                }
+                Answer::Failed(code) => {
+                    return Err(SensorError::Protocol(format!(
+                        "{} failed with error code {}.",
+                        method, code
+                    )))
+                }
+            }
+        }
+    }
+}
+
+impl common::Sensor for TapoSensor {
+    fn get_names(&self) -> Vec<String> {
+        METRICS
+            .iter()
+            .map(|(metric, _)| format!("{}_{}", self.name, metric))
+            .collect()
+    }
+
+    fn get_units(&self) -> Vec<String> {
+        METRICS.iter().map(|(_, unit)| unit.to_string()).collect()
+    }
+
+    fn measure(&self) -> Result<Vec<Reading>, SensorError> {
+        let names = self.get_names();
+        let mut res = Vec::new();
+        let mut error = None;
+        for (method, keys) in CALLS {
+            let result = match self.call(method) {
+                Ok(result) => result,
+                Err(err) => {
+                    error = Some(err);
+                    continue;
+                }
+            };
+            for (key, i) in keys {
+                match result[key].as_f64() {
+                    Some(value) => res.push(Reading::new(names[*i].clone(), value)),
+                    None => {
+                        error = Some(SensorError::Parse(format!(
+                            "no {} in the answer to {}.",
+                            key, method
+                        )))
+                    }
+                }
+            }
+        }
+        match error {
+            Some(err) if res.is_empty() => Err(err),
+            Some(err) => {
+                eprintln!("Could not read all values of {}: {}", self.name, err);
+                Ok(res)
+            }
+            None => Ok(res),
+        }
+    }
+}
+
+/// TP-Link Tapo energy plugs.
+pub(crate) fn sensor_type() -> common::SensorType {
+    common::SensorType {
+        name: "tapo",
+        required: &["host", "username", "password"],
+        optional: &["timeout_secs"],
+        create: |name, sensor_cfg, _| {
+            let field = |key: &str| {
+                sensor_cfg[key]
+                    .as_str()
+                    .ok_or_else(|| ConfigError::Invalid(format!("{} must be a string.", key)))
+            };
+            Ok(Box::new(TapoSensor::new(
+                name.to_string(),
+                shelly::base_url(field("host")?),
+                field("username")?,
+                field("password")?,
+                shelly::get_timeout(sensor_cfg)?,
+            )))
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::values;
+    use crate::common::Sensor;
+
+    // An exchange with user@example.com and secret, the local seed 00..0f and the remote seed 10..1f.
+    const LOCAL_SEED: [u8; 16] = [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15];
+    const REMOTE_SEED: [u8; 16] = [
+        16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31,
+    ];
+    const AUTH_HASH: &str = "b039216532fc844e9ae0cc8fe3ea911c9ba09c641bb96a3e1f776d93f7b5ae9b";
+    const SERVER_HASH: &str = "8ed3b905e654fbee23900d0244156a33029c025b71b03bfcc601eff33aaebb70";
+    const HANDSHAKE2: &str = "1e590e7a4dc128da48ed17e06fe23ddb6ec646bb6da2eb00708d590f194201d7";
+    const SEQ: i32 = -903964344;
+    // {"method":"get_current_power"} as the first request.
+    const REQUEST: &str =
+        "1407390aba2ad08e9a033d490dd0edb49623653ecbd6a2917e44f8181e1eed1182b121e6631d6dd1ff7495\
+    47f4ca34fea88e42d099aa12773514133876851b81";
+    // the answers to the first, second and third request.
+    const POWER: &str = "5d4596c447a010b999e5d995462aa32adfcfd629e0367a20225315c78bb0d7db88e3bbd2371bedf22012e6b4533\
+    a4f2975d34c4ec902f56500a2b18bd0dc4901a3c904e1637358d1a3d4f6ebcbbd94ba";
+    const USAGE: &str = "75e57ac8d178f6f4157a26877a98fc1c496cc54f9f76fd81279365846db6325e5a5e47e7f77da102f158c3a84e7\
+    b044fb2586f2cbd3be2e2bec7c82bd3c02fe54e8a446bf8abc6d68c2210a1da17da7c648e1d460700e4a8f4cc0b57d998d83db1400cfc0a1\
+    14f675f580b13486af34c44fc1d2eb51907009fbc6a1019000a7ce15c340c08a8a44352dfdc7c8eeca44b46b5a0e75362ebbe8bc72432c59\
+    06c02514400ea1fa306a59512c7c48f46134124cfddf453fe3b6b47916d81cb3eed2ddb3529290f9dd7b2c698463773c5e31d";
+    const EXPIRED: &str =
+        "775020e4295b5bb09cef02b8c8d76daa7c368130bd82fd0872c756e82d1654c8c65526642493ffbc1f0d4196\
+    a6883e92e651b6f9cfc3f6c68c57490ed08853cf";
+
+    fn hex(data: &str) -> Vec<u8> {
+        (0..data.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&data[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn session() -> Session {
+        Session::new(
+            "TP_SESSIONID=AB12".to_string(),
+            time::Duration::from_secs(SESSION_SECS),
+            &LOCAL_SEED,
+            &REMOTE_SEED,
+            &auth_hash("user@example.com", "secret"),
+        )
+    }
+
+    /// A plug answering the handshake of the exchange above.
+    fn plug(server: &mut mockito::Server, handshakes: usize) -> (mockito::Mock, TapoSensor) {
+        let handshake1 = server
+            .mock("POST", "/app/handshake1")
+            .with_header("set-cookie", "TP_SESSIONID=AB12;TIMEOUT=86400")
+            .with_body([&REMOTE_SEED[..], &hex(SERVER_HASH)[..]].concat())
+            .expect(handshakes)
+            .create();
+        server
+            .mock("POST", "/app/handshake2")
+            .match_header("cookie", "TP_SESSIONID=AB12")
+            .create();
+        let mut sensor = TapoSensor::new(
+            "plug".to_string(),
+            server.url(),
+            "user@example.com",
+            "secret",
+            time::Duration::from_secs(1),
+        );
+        sensor.local_seed = || LOCAL_SEED;
+        (handshake1, sensor)
+    }
+
+    fn answer(server: &mut mockito::Server, seq: i32, body: &str) -> mockito::Mock {
+        server
+            .mock("POST", "/app/request")
+            .match_query(mockito::Matcher::UrlEncoded("seq".into(), seq.to_string()))
+            .with_body(hex(body))
+            .create()
+    }
+
+    // Tests for success.
+
+    #[test]
+    fn test_handshake_for_success() {
+        let auth_hash = auth_hash("user@example.com", "secret");
+        assert_eq!(auth_hash.to_vec(), hex(AUTH_HASH));
+        let answer = [&REMOTE_SEED[..], &hex(SERVER_HASH)[..]].concat();
+        assert_eq!(
+            check_handshake1(&LOCAL_SEED, &answer, &auth_hash).unwrap(),
+            REMOTE_SEED
+        );
+        assert_eq!(
+            handshake2(&LOCAL_SEED, &REMOTE_SEED, &auth_hash).to_vec(),
+            hex(HANDSHAKE2)
+        );
+        assert_eq!(
+            parse_cookie("TP_SESSIONID=AB12;TIMEOUT=1440"),
+            Some(("TP_SESSIONID=AB12".to_string(), 1440))
+        );
+    }
+
+    #[test]
+    fn test_session_for_success() {
+        let mut session = session();
+        assert_eq!(session.seq, SEQ);
+        assert_eq!(
+            session
+                .encrypt(b"{\"method\":\"get_current_power\"}")
+                .unwrap(),
+            (SEQ + 1, hex(REQUEST))
+        );
+        assert_eq!(
+            parse_answer(&session.decrypt(SEQ + 1, &hex(POWER)).unwrap()).unwrap(),
+            Answer::Result(serde_json::json!({"current_power": 12}))
+        );
+    }
+
+    #[test]
+    fn test_measure_for_success() {
+        let mut server = mockito::Server::new();
+        let (handshake1, sensor) = plug(&mut server, 1);
+        answer(&mut server, SEQ + 1, POWER);
+        answer(&mut server, SEQ + 2, USAGE);
+        assert_eq!(sensor.get_units(), vec!["W", "Wh", "Wh"]);
+        assert_eq!(
+            values(sensor.measure()),
+            vec![
+                ("plug_power".to_string(), 12.0),
+                ("plug_today_energy".to_string(), 310.0),
+                ("plug_month_energy".to_string(), 8842.0)
+            ]
+        );
+        // one session for both.
+        handshake1.assert();
+    }
+
+    // Tests for failure.
+
+    #[test]
+    fn test_handshake_for_failure() {
+        let auth_hash = auth_hash("user@example.com", "wrong");
+        let answer = [&REMOTE_SEED[..], &hex(SERVER_HASH)[..]].concat();
+        match check_handshake1(&LOCAL_SEED, &answer, &auth_hash) {
+            Err(SensorError::Protocol(msg)) => assert_eq!(
+                msg,
+                "the plug does not know these credentials; check username and password."
+            ),
+            _ => panic!("wrong credentials not reported."),
+        }
+        assert!(check_handshake1(&LOCAL_SEED, &REMOTE_SEED, &auth_hash).is_err());
+        assert_eq!(parse_cookie("TIMEOUT=1440"), None);
+    }
+
+    #[test]
+    fn test_session_for_failure() {
+        let session = session();
+        // decrypted as the answer to another request, only its first block is garbled; the power is not in it.
+        match parse_answer(&session.decrypt(SEQ + 2, &hex(POWER)).unwrap()) {
+            Ok(Answer::Result(result)) => assert!(result["current_power"].is_null()),
+            other => panic!("unexpected answer: {:?}", other),
+        }
+        assert!(session.decrypt(SEQ + 1, &[0; 16]).is_err());
+        assert_eq!(
+            parse_answer(b"{\"error_code\":-1008}").unwrap(),
+            Answer::Failed(-1008)
+        );
+    }
+
+    // Tests for sanity.
+
+    #[test]
+    fn test_renew_for_sanity() {
+        let mut server = mockito::Server::new();
+        let (handshake1, sensor) = plug(&mut server, 2);
+        answer(&mut server, SEQ + 1, POWER);
+        answer(&mut server, SEQ + 2, USAGE);
+        values(sensor.measure());
+        // the plug forgot the session; a new one starts over.
+        answer(&mut server, SEQ + 3, EXPIRED);
+        assert_eq!(values(sensor.measure()).len(), 3);
+        handshake1.assert();
+        assert_eq!(
+            parse_answer(&session().decrypt(SEQ + 3, &hex(EXPIRED)).unwrap()).unwrap(),
+            Answer::Expired
+        );
+    }
+}
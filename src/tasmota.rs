@@ -0,0 +1,435 @@
+use std::io::Read;
+use std::sync::Arc;
+use std::time;
+
+use crate::common;
+use crate::common::{ConfigError, Reading, SensorError};
+use crate::debug;
+use crate::prometheus;
+use crate::shelly;
+
+/// The units of the values Tasmota commonly reports, by the last part of their path.
+const UNITS: [(&str, &str); 11] = [
+    ("Power", "W"),
+    ("ApparentPower", "VA"),
+    ("ReactivePower", "var"),
+    ("Factor", ""),
+    ("Voltage", "V"),
+    ("Current", "A"),
+    ("Total", "kWh"),
+    ("Today", "kWh"),
+    ("Yesterday", "kWh"),
+    ("Temperature", "°C"),
+    ("Humidity", "%"),
+];
+
+/// The value at a dotted path like ENERGY.Power in the StatusSNS tree; numbers index into arrays, e.g. ENERGY.Power.1.
+pub(crate) fn extract(body: &serde_json::Value, path: &str) -> Option<f64> {
+    let mut value = &body["StatusSNS"];
+    for part in path.split('.') {
+        value = match part.parse::<usize>() {
+            Ok(index) if value.is_array() => &value[index],
+            _ => &value[part],
+        };
+    }
+    match value {
+        serde_json::Value::String(text) => text.trim().parse().ok(),
+        value => value.as_f64(),
+    }
+}
+
+/// The paths that are not in the StatusSNS tree, e.g. of a sensor that is not attached.
+pub(crate) fn unknown<'a>(body: &serde_json::Value, paths: &'a [String]) -> Vec<&'a str> {
+    paths
+        .iter()
+        .filter(|path| extract(body, path).is_none())
+        .map(|path| path.as_str())
+        .collect()
+}
+
+/// Reads a Tasmota device, e.g. a Sonoff POW R2 or a plug, via its status command.
+///
+/// Each value is found by its dotted path in the StatusSNS tree; a device that cannot be reached, asleep or refusing
+/// the credentials fails the measurement, so its columns are missing until it answers again.
+pub struct TasmotaSensor {
+    name: String,
+    url: String,
+    auth: Option<(String, Option<String>)>,
+    paths: Vec<String>,
+    client: reqwest::blocking::Client,
+    ring: Arc<debug::Ring>,
+}
+
+impl TasmotaSensor {
+    pub(crate) fn new(
+        name: String,
+        url: String,
+        status: u8,
+        auth: Option<(String, Option<String>)>,
+        paths: Vec<String>,
+        timeout: time::Duration,
+    ) -> TasmotaSensor {
+        TasmotaSensor {
+            ring: debug::ring(&name),
+            name,
+            url: format!("{}/cm?cmnd=Status%20{}", url, status),
+            auth,
+            paths,
+            client: reqwest::blocking::Client::builder()
+                .timeout(timeout)
+                .build()
+                .unwrap(),
+        }
+    }
+
+    fn get_status(&self) -> Result<serde_json::Value, SensorError> {
+        let mut request = self.client.get(&self.url);
+        if let Some((username, password)) = &self.auth {
+            request = request.query(&[
+                ("user", username.as_str()),
+                ("password", password.as_deref().unwrap_or("")),
+            ]);
+        }
+        let started = time::Instant::now();
+        let mut res = match request.send() {
+            Ok(res) => res,
+            Err(err) => {
+                self.ring.record(&self.url, None, &err.to_string(), started);
+                if err.is_timeout() {
+                    return Err(SensorError::Timeout(format!(
+                        "no answer from {}; asleep?",
+                        self.url
+                    )));
+                }
+                return Err(SensorError::from(err));
+            }
+        };
+        let mut body: String = String::new();
+        res.read_to_string(&mut body)?;
+        self.ring
+            .record(&self.url, Some(res.status().as_u16()), &body, started);
+        // older firmware answers 200 and a warning.
+        if res.status() == 401 || body.contains("\"WARNING\"") {
+            return Err(SensorError::Http(
+                "authentication failed; check username and password.".to_string(),
+            ));
+        }
+        if res.status() != 200 {
+            return Err(SensorError::Http(format!(
+                "Status code was not 200; but: {}.",
+                res.status()
+            )));
+        }
+        let body: serde_json::Value =
+            serde_json::from_str(&body).map_err(|e| SensorError::Parse(e.to_string()))?;
+        if body.get("StatusSNS").is_none() {
+            return Err(SensorError::Protocol(
+                "no StatusSNS in the status; is this a Tasmota device?".to_string(),
+            ));
+        }
+        Ok(body)
+    }
+}
+
+impl common::Sensor for TasmotaSensor {
+    fn get_names(&self) -> Vec<String> {
+        self.paths
+            .iter()
+            .map(|path| {
+                format!(
+                    "{}_{}",
+                    self.name,
+                    prometheus::sanitize(path).to_lowercase()
+                )
+            })
+            .collect()
+    }
+
+    fn get_units(&self) -> Vec<String> {
+        self.paths
+            .iter()
+            .map(|path| {
+                // the last part that is not an index.
+                let key = path
+                    .split('.')
+                    .rev()
+                    .find(|p| p.parse::<usize>().is_err())
+                    .unwrap_or("");
+                UNITS
+                    .iter()
+                    .find(|(k, _)| *k == key)
+                    .map(|(_, unit)| unit.to_string())
+                    .unwrap_or_else(|| "unknown".to_string())
+            })
+            .collect()
+    }
+
+    fn measure(&self) -> Result<Vec<Reading>, SensorError> {
+        let body = self.get_status()?;
+        Ok(self
+            .get_names()
+            .into_iter()
+            .zip(&self.paths)
+            .filter_map(|(name, path)| extract(&body, path).map(|v| Reading::new(name, v)))
+            .collect())
+    }
+
+    fn init(&mut self) -> Result<(), SensorError> {
+        // probed once; a device that does not answer yet is not held against the paths.
+        let body = match self.get_status() {
+            Ok(body) => body,
+            Err(err) => {
+                eprintln!("Could not probe {}: {}", self.name, err);
+                return Ok(());
+            }
+        };
+        let unknown = unknown(&body, &self.paths);
+        if !unknown.is_empty() {
+            let mut available = Vec::new();
+            paths(&body["StatusSNS"], "", &mut available);
+            return Err(SensorError::Protocol(format!(
+                "unknown paths: {}; available are: {}.",
+                unknown.join(", "),
+                available.join(", ")
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// The dotted paths of all numbers in the tree, e.g. to tell which paths a device has.
+fn paths(value: &serde_json::Value, prefix: &str, res: &mut Vec<String>) {
+    let join = |key: &str| {
+        if prefix.is_empty() {
+            key.to_string()
+        } else {
+            format!("{}.{}", prefix, key)
+        }
+    };
+    match value {
+        serde_json::Value::Object(map) => {
+            for (key, value) in map {
+                paths(value, &join(key), res);
+            }
+        }
+        serde_json::Value::Array(values) => {
+            for (i, value) in values.iter().enumerate() {
+                paths(value, &join(&i.to_string()), res);
+            }
+        }
+        serde_json::Value::Number(_) => res.push(prefix.to_string()),
+        _ => {}
+    }
+}
+
+/// Tasmota plugs, relays and sensors.
+pub(crate) fn sensor_type() -> common::SensorType {
+    common::SensorType {
+        name: "tasmota",
+        required: &["host", "paths"],
+        optional: &["status", "username", "password", "timeout_secs"],
+        create: |name, sensor_cfg, _| {
+            let host = sensor_cfg["host"]
+                .as_str()
+                .ok_or_else(|| ConfigError::Invalid("host must be a string.".to_string()))?;
+            let paths = sensor_cfg["paths"]
+                .as_array()
+                .and_then(|a| {
+                    a.iter()
+                        .map(|p| p.as_str().map(|p| p.to_string()))
+                        .collect::<Option<Vec<_>>>()
+                })
+                .filter(|paths| !paths.is_empty())
+                .ok_or_else(|| {
+                    ConfigError::Invalid("paths must be a non-empty array of strings.".to_string())
+                })?;
+            let status = match sensor_cfg.get("status") {
+                None => 8,
+                Some(toml::Value::Integer(8)) => 8,
+                Some(toml::Value::Integer(10)) => 10,
+                Some(other) => {
+                    return Err(ConfigError::Invalid(format!(
+                        "status must be 8 or 10; got: {}.",
+                        other
+                    )))
+                }
+            };
+            Ok(Box::new(TasmotaSensor::new(
+                name.to_string(),
+                shelly::base_url(host),
+                status,
+                shelly::get_auth(sensor_cfg)?,
+                paths,
+                shelly::get_timeout(sensor_cfg)?,
+            )))
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::values;
+    use crate::common::Sensor;
+
+    /// As served by a Sonoff POW R2 for Status 8.
+    const POW_R2: &str = r#"{"StatusSNS":{"Time":"2024-05-01T12:00:00","ENERGY":{"TotalStartTime":
+    "2023-11-02T17:40:12","Total":412.187,"Yesterday":1.532,"Today":0.874,"Power":1840,"ApparentPower":1852,
+    "ReactivePower":212,"Factor":0.99,"Voltage":229,"Current":8.088}}}"#;
+
+    /// As served by a plug with a DS18B20 attached.
+    const PLUG_DS18B20: &str = r#"{"StatusSNS":{"Time":"2024-05-01T12:00:00","DS18B20":{"Id":"3C01D607A1B3",
+    "Temperature":21.4},"ENERGY":{"TotalStartTime":"2024-01-12T09:03:51","Total":38.102,"Yesterday":0.211,
+    "Today":0.095,"Period":0,"Power":[12,0],"ApparentPower":[24,0],"ReactivePower":[21,0],"Factor":[0.49,0.00],
+    "Voltage":231,"Current":[0.104,0.000]},"TempUnit":"C"}}"#;
+
+    fn tasmota(
+        url: String,
+        auth: Option<(String, Option<String>)>,
+        paths: &[&str],
+    ) -> TasmotaSensor {
+        TasmotaSensor::new(
+            "plug".to_string(),
+            url,
+            8,
+            auth,
+            paths.iter().map(|p| p.to_string()).collect(),
+            time::Duration::from_secs(1),
+        )
+    }
+
+    // Tests for success.
+
+    #[test]
+    fn test_measure_for_success() {
+        let mut server = mockito::Server::new();
+        server
+            .mock("GET", "/cm")
+            .match_query(mockito::Matcher::UrlEncoded(
+                "cmnd".into(),
+                "Status 8".into(),
+            ))
+            .with_body(POW_R2)
+            .create();
+        let mut sensor = tasmota(server.url(), None, &["ENERGY.Power", "ENERGY.Total"]);
+        sensor.init().unwrap();
+        assert_eq!(
+            sensor.get_names(),
+            vec!["plug_energy_power", "plug_energy_total"]
+        );
+        assert_eq!(sensor.get_units(), vec!["W", "kWh"]);
+        assert_eq!(
+            values(sensor.measure()),
+            vec![
+                ("plug_energy_power".to_string(), 1840.0),
+                ("plug_energy_total".to_string(), 412.187)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_ds18b20_for_success() {
+        let mut server = mockito::Server::new();
+        server
+            .mock("GET", "/cm")
+            .match_query(mockito::Matcher::AllOf(vec![
+                mockito::Matcher::UrlEncoded("cmnd".into(), "Status 8".into()),
+                mockito::Matcher::UrlEncoded("user".into(), "admin".into()),
+                mockito::Matcher::UrlEncoded("password".into(), "s&cret".into()),
+            ]))
+            .with_body(PLUG_DS18B20)
+            .create();
+        let mut sensor = tasmota(
+            server.url(),
+            Some(("admin".to_string(), Some("s&cret".to_string()))),
+            &["DS18B20.Temperature", "ENERGY.Power.0"],
+        );
+        sensor.init().unwrap();
+        assert_eq!(sensor.get_units(), vec!["°C", "W"]);
+        assert_eq!(
+            values(sensor.measure()),
+            vec![
+                ("plug_ds18b20_temperature".to_string(), 21.4),
+                ("plug_energy_power_0".to_string(), 12.0)
+            ]
+        );
+    }
+
+    // Tests for failure.
+
+    #[test]
+    fn test_init_for_failure() {
+        let mut server = mockito::Server::new();
+        server
+            .mock("GET", "/cm")
+            .match_query(mockito::Matcher::Any)
+            .with_body(POW_R2)
+            .create();
+        let mut sensor = tasmota(server.url(), None, &["ENERGY.Power", "DS18B20.Temperature"]);
+        match sensor.init() {
+            Err(SensorError::Protocol(msg)) => {
+                assert!(msg.starts_with("unknown paths: DS18B20.Temperature; available are: "));
+                assert!(msg.contains("ENERGY.Power, "));
+            }
+            _ => panic!("unknown path not reported."),
+        }
+    }
+
+    #[test]
+    fn test_measure_for_failure() {
+        let mut server = mockito::Server::new();
+        server
+            .mock("GET", "/cm")
+            .match_query(mockito::Matcher::Any)
+            .with_body(r#"{"WARNING":"Need user=<username>&password=<password>"}"#)
+            .create();
+        let mut sensor = tasmota(server.url(), None, &["ENERGY.Power"]);
+        // not held against it at startup.
+        sensor.init().unwrap();
+        match sensor.measure() {
+            Err(SensorError::Http(msg)) => {
+                assert_eq!(msg, "authentication failed; check username and password.")
+            }
+            _ => panic!("refused credentials not reported."),
+        }
+        // asleep.
+        let sensor = tasmota("http://127.0.0.1:1".to_string(), None, &["ENERGY.Power"]);
+        assert!(sensor.measure().is_err());
+    }
+
+    #[test]
+    fn test_sensor_type_for_failure() {
+        let sensor_cfg: toml::value::Table =
+            toml::from_str("host='192.168.178.40'\npaths=['ENERGY.Power']\nstatus=9").unwrap();
+        assert_eq!(
+            (sensor_type().create)("plug", &sensor_cfg, "state").err(),
+            Some(ConfigError::Invalid(
+                "status must be 8 or 10; got: 9.".to_string()
+            ))
+        );
+        let sensor_cfg: toml::value::Table =
+            toml::from_str("host='192.168.178.40'\npaths=[]").unwrap();
+        assert!((sensor_type().create)("plug", &sensor_cfg, "state").is_err());
+    }
+
+    // Tests for sanity.
+
+    #[test]
+    fn test_extract_for_sanity() {
+        let body: serde_json::Value = serde_json::from_str(PLUG_DS18B20).unwrap();
+        assert_eq!(extract(&body, "ENERGY.Current.0"), Some(0.104));
+        // an array as a whole is not a value.
+        assert_eq!(extract(&body, "ENERGY.Current"), None);
+        assert_eq!(extract(&body, "DS18B20.Id"), None);
+        assert_eq!(
+            unknown(
+                &body,
+                &[
+                    "ENERGY.Voltage".to_string(),
+                    "SHT3X.Temperature".to_string()
+                ]
+            ),
+            vec!["SHT3X.Temperature"]
+        );
+    }
+}
@@ -0,0 +1,236 @@
+use chrono::{
+    Datelike, Duration, LocalResult, NaiveDateTime, NaiveTime, SecondsFormat, TimeZone, Timelike,
+    Utc,
+};
+
+/// The time zone all calendar based features (daily resets, active hours, ...) resolve through.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Zone {
+    Utc,
+    /// The zone the system is set to.
+    Local,
+    Named(chrono_tz::Tz),
+}
+
+impl Zone {
+    /// Parses 'UTC', 'local' or an IANA name like 'Europe/Berlin'.
+    pub(crate) fn parse(name: &str) -> Zone {
+        match name {
+            "UTC" | "utc" => Zone::Utc,
+            "local" => Zone::Local,
+            _ => Zone::Named(
+                name.parse()
+                    .unwrap_or_else(|_| panic!("unknown timezone: {}.", name)),
+            ),
+        }
+    }
+
+    /// Local date and time of an instant (epoch secs); this is never ambiguous.
+    pub(crate) fn local(&self, epoch: f64) -> Option<NaiveDateTime> {
+        let utc = Utc.timestamp_opt(epoch.floor() as i64, 0).single()?;
+        Some(match self {
+            Zone::Utc => utc.naive_utc(),
+            Zone::Local => utc.with_timezone(&chrono::Local).naive_local(),
+            Zone::Named(tz) => utc.with_timezone(tz).naive_local(),
+        })
+    }
+
+    /// RFC 3339 representation of an instant (epoch secs) with the offset of the zone; to the millisecond.
+    pub(crate) fn rfc3339(&self, epoch: f64) -> String {
+        let utc = match Utc
+            .timestamp_millis_opt((epoch * 1000.0).round() as i64)
+            .single()
+        {
+            Some(utc) => utc,
+            None => return String::new(),
+        };
+        let format = SecondsFormat::AutoSi;
+        match self {
+            Zone::Utc => utc.to_rfc3339_opts(format, true),
+            Zone::Local => utc
+                .with_timezone(&chrono::Local)
+                .to_rfc3339_opts(format, true),
+            Zone::Named(tz) => utc.with_timezone(tz).to_rfc3339_opts(format, true),
+        }
+    }
+
+    /// Number of the local day of an instant; changes at local midnight.
+    pub(crate) fn day(&self, epoch: f64) -> i64 {
+        self.local(epoch)
+            .map(|t| t.date().num_days_from_ce() as i64)
+            .unwrap_or(0)
+    }
+
+    /// Instant (epoch secs) of a local time. Times in a DST gap resolve to the instant the clocks
+    /// jump; times that exist twice in a DST overlap resolve to their first occurrence.
+    pub(crate) fn instant(&self, local: NaiveDateTime) -> i64 {
+        match self {
+            Zone::Utc => instant_in(&Utc, local),
+            Zone::Local => instant_in(&chrono::Local, local),
+            Zone::Named(tz) => instant_in(tz, local),
+        }
+    }
+}
+
+fn instant_in<T: TimeZone>(tz: &T, local: NaiveDateTime) -> i64 {
+    let mut t = local;
+    // gaps are whole minutes long and never longer than a day.
+    for _ in 0..24 * 60 {
+        match tz.from_local_datetime(&t) {
+            LocalResult::Single(res) => return res.timestamp(),
+            LocalResult::Ambiguous(first, second) => {
+                return first.timestamp().min(second.timestamp())
+            }
+            LocalResult::None => {
+                t = t.with_second(0).unwrap_or(t) + Duration::minutes(1);
+            }
+        }
+    }
+    Utc.from_utc_datetime(&local).timestamp()
+}
+
+/// A daily window of local time, e.g. '07:00-22:00'; windows may span midnight.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct ActiveHours {
+    start: NaiveTime,
+    end: NaiveTime,
+}
+
+impl ActiveHours {
+    pub(crate) fn parse(window: &str) -> ActiveHours {
+        let parse = |t: &str| {
+            NaiveTime::parse_from_str(t.trim(), "%H:%M")
+                .unwrap_or_else(|_| panic!("invalid time in active hours: {}.", window))
+        };
+        match window.split_once('-') {
+            Some((start, end)) => ActiveHours {
+                start: parse(start),
+                end: parse(end),
+            },
+            None => panic!("active hours must look like 07:00-22:00; got: {}.", window),
+        }
+    }
+
+    /// Whether the instant lies within the window on its local day.
+    pub(crate) fn contains(&self, zone: &Zone, epoch: f64) -> bool {
+        let date = match zone.local(epoch) {
+            Some(t) => t.date(),
+            None => return false,
+        };
+        let start = zone.instant(date.and_time(self.start)) as f64;
+        let end = zone.instant(date.and_time(self.end)) as f64;
+        if self.start <= self.end {
+            start <= epoch && epoch < end
+        } else {
+            epoch >= start || epoch < end
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDate;
+
+    use super::*;
+
+    fn berlin() -> Zone {
+        Zone::parse("Europe/Berlin")
+    }
+
+    fn at(y: i32, m: u32, d: u32, h: u32, min: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(y, m, d)
+            .unwrap()
+            .and_hms_opt(h, min, 0)
+            .unwrap()
+    }
+
+    // Tests for success.
+
+    #[test]
+    fn test_parse_for_success() {
+        assert_eq!(Zone::parse("UTC"), Zone::Utc);
+        assert_eq!(Zone::parse("local"), Zone::Local);
+        assert_eq!(berlin(), Zone::Named(chrono_tz::Europe::Berlin));
+        assert_eq!(
+            ActiveHours::parse("07:00-22:30"),
+            ActiveHours {
+                start: NaiveTime::from_hms_opt(7, 0, 0).unwrap(),
+                end: NaiveTime::from_hms_opt(22, 30, 0).unwrap()
+            }
+        );
+    }
+
+    // Tests for failure.
+
+    #[test]
+    #[should_panic]
+    fn test_parse_for_failure() {
+        Zone::parse("Europe/Atlantis");
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_active_hours_for_failure() {
+        ActiveHours::parse("7-22");
+    }
+
+    // Tests for sanity.
+
+    #[test]
+    fn test_day_for_sanity() {
+        // 2023-11-14 23:30 UTC is already the 15th in Berlin.
+        let t = 1700004600.0;
+        assert_eq!(Zone::Utc.day(t) + 1, berlin().day(t));
+        assert_eq!(Zone::Utc.day(t + 1800.0), berlin().day(t));
+    }
+
+    #[test]
+    fn test_rfc3339_for_sanity() {
+        assert_eq!(Zone::Utc.rfc3339(1699920000.0), "2023-11-14T00:00:00Z");
+        assert_eq!(
+            berlin().rfc3339(1699920000.25),
+            "2023-11-14T01:00:00.250+01:00"
+        );
+        // summer time.
+        assert_eq!(berlin().rfc3339(1690000000.0), "2023-07-22T06:26:40+02:00");
+    }
+
+    #[test]
+    fn test_instant_for_sanity() {
+        // regular times.
+        assert_eq!(Zone::Utc.instant(at(2023, 11, 14, 0, 0)), 1699920000);
+        assert_eq!(berlin().instant(at(2023, 11, 14, 1, 0)), 1699920000);
+        // 2024-03-31 02:30 does not exist in Berlin; clocks jump at 01:00 UTC.
+        assert_eq!(berlin().instant(at(2024, 3, 31, 2, 30)), 1711846800);
+        assert_eq!(berlin().instant(at(2024, 3, 31, 3, 0)), 1711846800);
+        // 2024-10-27 02:30 exists twice (00:30 & 01:30 UTC); the first one wins.
+        assert_eq!(berlin().instant(at(2024, 10, 27, 2, 30)), 1729989000);
+    }
+
+    #[test]
+    fn test_contains_for_sanity() {
+        let hours = ActiveHours::parse("07:00-22:00");
+        // 2023-11-14 06:30 UTC is 07:30 in Berlin.
+        assert!(!hours.contains(&Zone::Utc, 1699943400.0));
+        assert!(hours.contains(&berlin(), 1699943400.0));
+
+        // windows across midnight.
+        let night = ActiveHours::parse("22:00-06:00");
+        assert!(night.contains(&Zone::Utc, 1699920000.0 + 23.0 * 3600.0));
+        assert!(night.contains(&Zone::Utc, 1699920000.0 + 5.0 * 3600.0));
+        assert!(!night.contains(&Zone::Utc, 1699920000.0 + 12.0 * 3600.0));
+    }
+
+    #[test]
+    fn test_dst_for_sanity() {
+        // a window starting in the gap starts when the clocks jump...
+        let hours = ActiveHours::parse("02:30-04:00");
+        assert!(!hours.contains(&berlin(), 1711846800.0 - 1.0));
+        assert!(hours.contains(&berlin(), 1711846800.0));
+        // ... and one ending in the overlap ends at the first 02:30.
+        let hours = ActiveHours::parse("01:00-02:30");
+        assert!(hours.contains(&berlin(), 1729989000.0 - 1.0));
+        assert!(!hours.contains(&berlin(), 1729989000.0));
+        assert!(!hours.contains(&berlin(), 1729989000.0 + 3600.0 - 1.0));
+    }
+}
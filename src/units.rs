@@ -0,0 +1,218 @@
+use std::error::Error;
+
+use crate::calibration;
+use crate::common;
+use crate::common::{Reading, SensorError};
+
+/// Prefixes of units and their factors.
+const PREFIXES: [(&str, f64); 4] = [("m", 1e-3), ("k", 1e3), ("M", 1e6), ("G", 1e9)];
+
+/// Units a prefix may go with.
+const BASES: [&str; 7] = ["Wh", "W", "VA", "V", "A", "Hz", "°C"];
+
+/// Splits a unit like 'kW' or '0.1°C' into its factor and base unit.
+pub(crate) fn parse_unit(unit: &str) -> Option<(f64, &str)> {
+    // a leading number like in '0.1°C'.
+    let split = unit
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(unit.len());
+    let (number, rest) = unit.split_at(split);
+    let factor = match number {
+        "" => 1.0,
+        number => number.parse().ok()?,
+    };
+    if BASES.contains(&rest) {
+        return Some((factor, rest));
+    }
+    PREFIXES.iter().find_map(|(prefix, prefix_factor)| {
+        let base = rest.strip_prefix(prefix)?;
+        BASES
+            .contains(&base)
+            .then_some((factor * prefix_factor, base))
+    })
+}
+
+/// How the values of a metric are normalised, and the unit they are in then.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Conversion {
+    pub(crate) scale: f64,
+    pub(crate) offset: f64,
+    pub(crate) unit: Option<String>,
+}
+
+impl Conversion {
+    /// The conversion given as shorthand like 'kW->W'.
+    pub(crate) fn parse(shorthand: &str) -> Result<Conversion, String> {
+        let (from, to) = shorthand
+            .split_once("->")
+            .ok_or_else(|| format!("unit must look like 'kW->W'; got: {}.", shorthand))?;
+        let (from, to) = (from.trim(), to.trim());
+        let parse = |unit| parse_unit(unit).ok_or_else(|| format!("unknown unit: {}.", unit));
+        let ((from_factor, from_base), (to_factor, to_base)) = (parse(from)?, parse(to)?);
+        if from_base != to_base {
+            return Err(format!("cannot convert {} to {}.", from, to));
+        }
+        Ok(Conversion {
+            scale: from_factor / to_factor,
+            offset: 0.0,
+            unit: Some(to.to_string()),
+        })
+    }
+
+    fn apply(&self, value: f64) -> f64 {
+        if calibration::is_failure(value) {
+            return value;
+        }
+        value * self.scale + self.offset
+    }
+}
+
+/// Normalises the values of the sensor it wraps, e.g. from kW to W, and reports the units they are in then.
+pub struct NormalisedSensor {
+    inner: Box<dyn common::Sensor>,
+    conversions: Vec<Option<Conversion>>,
+}
+
+impl NormalisedSensor {
+    pub(crate) fn new(
+        inner: Box<dyn common::Sensor>,
+        conversions: Vec<Option<Conversion>>,
+    ) -> NormalisedSensor {
+        NormalisedSensor { inner, conversions }
+    }
+}
+
+impl common::Sensor for NormalisedSensor {
+    fn get_names(&self) -> Vec<String> {
+        self.inner.get_names()
+    }
+
+    fn get_units(&self) -> Vec<String> {
+        self.inner
+            .get_units()
+            .into_iter()
+            .zip(&self.conversions)
+            .map(|(unit, conversion)| match conversion {
+                Some(Conversion {
+                    unit: Some(normalised),
+                    ..
+                }) => normalised.clone(),
+                _ => unit,
+            })
+            .collect()
+    }
+
+    fn measure(&self) -> Result<Vec<Reading>, SensorError> {
+        let names = self.inner.get_names();
+        let mut readings = self.inner.measure()?;
+        for reading in readings.iter_mut() {
+            let i = names.iter().position(|n| n == &reading.name);
+            if let Some(Some(conversion)) = i.map(|i| &self.conversions[i]) {
+                reading.value = conversion.apply(reading.value);
+            }
+        }
+        Ok(readings)
+    }
+
+    fn init(&mut self) -> Result<(), SensorError> {
+        self.inner.init()
+    }
+
+    fn shutdown(&mut self) {
+        self.inner.shutdown()
+    }
+
+    fn validate(&self) -> Result<(), Box<dyn Error>> {
+        self.inner.validate()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::Sensor;
+    use crate::dummy;
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-9
+    }
+
+    // Tests for success.
+
+    #[test]
+    fn test_parse_for_success() {
+        let conversion = Conversion::parse("kW->W").unwrap();
+        assert_eq!(conversion.scale, 1000.0);
+        assert_eq!(conversion.unit, Some("W".to_string()));
+        assert!(close(Conversion::parse("mW -> W").unwrap().scale, 0.001));
+        assert!(close(Conversion::parse("Wh->kWh").unwrap().scale, 0.001));
+        assert!(close(Conversion::parse("0.1°C->°C").unwrap().scale, 0.1));
+    }
+
+    #[test]
+    fn test_measure_for_success() {
+        let inner = dummy::DummySensor::new(
+            "fox".to_string(),
+            vec![("pvPower".to_string(), 1.5), ("soc".to_string(), 80.0)],
+        );
+        let sensor = NormalisedSensor::new(
+            Box::new(inner),
+            vec![Some(Conversion::parse("kW->W").unwrap()), None],
+        );
+        let readings = sensor.measure().unwrap();
+        assert_eq!(readings[0].value, 1500.0);
+        assert_eq!(readings[1].value, 80.0);
+    }
+
+    // Tests for failure.
+
+    #[test]
+    fn test_parse_for_failure() {
+        assert_eq!(
+            Conversion::parse("kW"),
+            Err("unit must look like 'kW->W'; got: kW.".to_string())
+        );
+        assert_eq!(
+            Conversion::parse("kW->Wh"),
+            Err("cannot convert kW to Wh.".to_string())
+        );
+        assert_eq!(
+            Conversion::parse("kW->PS"),
+            Err("unknown unit: PS.".to_string())
+        );
+    }
+
+    // Tests for sanity.
+
+    #[test]
+    fn test_parse_unit_for_sanity() {
+        assert_eq!(parse_unit("W"), Some((1.0, "W")));
+        assert_eq!(parse_unit("mA"), Some((0.001, "A")));
+        assert_eq!(parse_unit("kWh"), Some((1000.0, "Wh")));
+        assert_eq!(parse_unit("0.1°C"), Some((0.1, "°C")));
+        assert_eq!(parse_unit("unknown"), None);
+        assert_eq!(parse_unit("%"), None);
+    }
+
+    #[test]
+    fn test_units_for_sanity() {
+        let inner = dummy::DummySensor::new(
+            "fox".to_string(),
+            vec![("pvPower".to_string(), 1.5), ("soc".to_string(), 80.0)],
+        );
+        let sensor = NormalisedSensor::new(
+            Box::new(inner),
+            vec![
+                Some(Conversion::parse("kW->W").unwrap()),
+                Some(Conversion {
+                    scale: 0.01,
+                    offset: 0.0,
+                    unit: None,
+                }),
+            ],
+        );
+        // w/o a unit, the one of the sensor stays.
+        assert_eq!(sensor.get_units(), vec!["W", "unknown"]);
+        assert!(close(sensor.measure().unwrap()[1].value, 0.8));
+    }
+}
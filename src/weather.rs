@@ -1,8 +1,14 @@
-use std::io::Read;
-
 use serde::{Deserialize, Serialize};
 
+use std::error::Error;
+use std::sync::Arc;
+use std::time;
+
 use crate::common;
+use crate::common::{Reading, SensorError};
+use crate::debug;
+use crate::http;
+use crate::keys;
 
 const NAMES: [&str; 8] = [
     "temperature",
@@ -14,6 +20,8 @@ const NAMES: [&str; 8] = [
     "cloud_coverage",
     "description",
 ];
+// metric units; the description is OpenWeatherMap's condition code.
+const UNITS: [&str; 8] = ["°C", "%", "hPa", "m", "m/s", "°", "%", ""];
 
 #[derive(Serialize, Deserialize)]
 struct WeatherData {
@@ -52,17 +60,38 @@ pub struct WeatherSensor {
     url: String,
     lat: f64,
     long: f64,
-    app_id: String,
+    app_ids: keys::KeyPool,
+    coalesce_secs: f64,
+    ring: Arc<debug::Ring>,
+}
+
+/// OpenWeatherMap answers with 401 for blocked keys and 429 once the calls per minute are used up.
+fn classify(err: Box<dyn Error>) -> Box<dyn Error> {
+    match err.downcast_ref::<http::StatusError>() {
+        Some(res) if res.status == 401 || res.status == 429 => {
+            Box::new(keys::QuotaError(format!("{} {}", res.status, res.body)))
+        }
+        _ => err,
+    }
 }
 
 impl WeatherSensor {
-    pub fn new(name: String, url: String, lat: f64, long: f64, app_id: String) -> WeatherSensor {
+    pub fn new(
+        name: String,
+        url: String,
+        lat: f64,
+        long: f64,
+        app_ids: keys::KeyPool,
+        coalesce_secs: f64,
+    ) -> WeatherSensor {
         WeatherSensor {
+            ring: debug::ring(&name),
             name,
             url,
             lat,
             long,
-            app_id,
+            app_ids,
+            coalesce_secs,
         }
     }
 }
@@ -76,50 +105,81 @@ impl common::Sensor for WeatherSensor {
         names
     }
 
-    fn measure(&self) -> Vec<f64> {
+    fn get_units(&self) -> Vec<String> {
+        UNITS.iter().map(|u| u.to_string()).collect()
+    }
+
+    fn measure(&self) -> Result<Vec<Reading>, SensorError> {
         // blocking requests are ok, weather doesn't change that often. async prog hence might be overkill.
-        let uri: String = format!(
-            "{0}?lat={1}&lon={2}&appid={3}&units=metric",
-            self.url, self.lat, self.long, self.app_id
-        );
-        let mut body: String = String::new();
-        let mut res = match reqwest::blocking::get(uri) {
-            Ok(res) => res,
-            Err(_) => return vec![-1.0; NAMES.len()],
-        };
-        if res.status() != 200 {
-            return vec![-1.0; NAMES.len()];
-        }
-        if res.read_to_string(&mut body).is_err() {
-            return vec![-1.0; NAMES.len()];
-        }
+        // sensors sharing a location can share a response.
+        let res = self.app_ids.with_key(|app_id| {
+            let uri: String = format!(
+                "{0}?lat={1}&lon={2}&appid={3}&units=metric",
+                self.url, self.lat, self.long, app_id
+            );
+            let started = time::Instant::now();
+            let res = http::get(&uri, self.coalesce_secs);
+            match &res {
+                Ok(body) => self.ring.record(&uri, Some(200), body, started),
+                Err(err) => match err.downcast_ref::<http::StatusError>() {
+                    Some(res) => self.ring.record(&uri, Some(res.status), &res.body, started),
+                    None => self.ring.record(&uri, None, &err.to_string(), started),
+                },
+            }
+            res.map_err(classify)
+        });
+        let body: String = res.map_err(SensorError::from)?;
 
         // parse the data.
-        let weather: WeatherInfo = match serde_json::from_str(&body) {
-            Ok(body) => body,
-            Err(_error) => return vec![-1.0; NAMES.len()],
-        };
-        let main: MainData = weather.main.unwrap_or_else(|| MainData {
-            temp: -1.0,
-            pressure: -1.0,
-            humidity: -1.0,
+        let weather: WeatherInfo =
+            serde_json::from_str(&body).map_err(|e| SensorError::Parse(e.to_string()))?;
+        let main: MainData = weather.main.unwrap_or(MainData {
+            temp: f64::NAN,
+            pressure: f64::NAN,
+            humidity: f64::NAN,
         });
-        let wind: WindData = weather.wind.unwrap_or_else(|| WindData {
-            speed: -1.0,
-            deg: -1.0,
+        let wind: WindData = weather.wind.unwrap_or(WindData {
+            speed: f64::NAN,
+            deg: f64::NAN,
         });
-        let clouds: CloudData = weather.clouds.unwrap_or_else(|| CloudData { all: -1.0 });
+        let clouds: CloudData = weather.clouds.unwrap_or(CloudData { all: f64::NAN });
 
-        vec![
+        let values = vec![
             main.temp,
             main.humidity,
             main.pressure,
-            weather.visibility.unwrap_or_else(|| -1.0),
+            weather.visibility.unwrap_or(f64::NAN),
             wind.speed,
             wind.deg,
             clouds.all,
-            weather.weather[0].id,
-        ]
+            weather.weather.first().map(|w| w.id).unwrap_or(f64::NAN),
+        ];
+        Ok(common::readings(self.get_names(), values))
+    }
+}
+
+/// Weather sensors; the app_id may be an array of keys to rotate through.
+pub(crate) fn sensor_type() -> common::SensorType {
+    common::SensorType {
+        name: "weather",
+        required: &["lat", "long", "app_id", "url"],
+        optional: &["coalesce_secs", "key_cooldown_secs"],
+        create: |name, sensor_cfg, state_dir| {
+            Ok(Box::new(WeatherSensor::new(
+                name.to_string(),
+                sensor_cfg["url"]
+                    .as_str()
+                    .unwrap_or("https://api.openweathermap.org/data/2.5/weather")
+                    .to_string(),
+                sensor_cfg["lat"].as_float().unwrap_or(0.0),
+                sensor_cfg["long"].as_float().unwrap_or(0.0),
+                keys::from_config(name, sensor_cfg, "app_id", "", state_dir)?,
+                sensor_cfg
+                    .get("coalesce_secs")
+                    .and_then(|v| v.as_float().or_else(|| v.as_integer().map(|i| i as f64)))
+                    .unwrap_or(0.0),
+            )))
+        },
     }
 }
 
@@ -128,6 +188,9 @@ mod tests {
     // Using mockito is not perfect as is spins up a server & hence is more of an integration tests;
     // but works for now w/o to many add on dependencies, so should be easy to replace.
 
+    use std::thread;
+    use std::time;
+
     use crate::common::Sensor;
 
     use super::*;
@@ -143,6 +206,15 @@ mod tests {
     \"clouds\": {}, \
     \"wind\": {}}";
 
+    fn pool(app_ids: &[&str]) -> keys::KeyPool {
+        keys::KeyPool::new(
+            "test".to_string(),
+            app_ids.iter().map(|k| k.to_string()).collect(),
+            60.0,
+            None,
+        )
+    }
+
     // Tests for success.
 
     #[test]
@@ -152,7 +224,8 @@ mod tests {
             "localhost".to_string(),
             0.0,
             0.0,
-            "foo".to_string(),
+            pool(&["foo"]),
+            0.0,
         );
         sensor.get_names();
     }
@@ -177,10 +250,12 @@ mod tests {
             url.to_owned() + "/data/2.5/weather",
             0.0,
             0.0,
-            "foo".to_string(),
+            pool(&["foo"]),
+            0.0,
         );
-        let data: Vec<f64> = sensor.measure();
+        let data: Vec<Reading> = sensor.measure().unwrap();
         assert_eq!(data.len(), NAMES.len());
+        assert_eq!(data[0].name, "test_temperature");
     }
 
     // Tests for failure.
@@ -205,10 +280,10 @@ mod tests {
             url.to_owned() + "/data/2.5/weather",
             0.0,
             0.0,
-            "foo".to_string(),
+            pool(&["foo"]),
+            0.0,
         );
-        let data: Vec<f64> = sensor.measure();
-        assert_eq!(data, vec![-1.0; NAMES.len()]);
+        assert!(matches!(sensor.measure(), Err(SensorError::Parse(_))));
 
         // partly faulty data.
         server
@@ -220,8 +295,7 @@ mod tests {
             .with_header("content-type", "application/json")
             .with_body(FAULTY_DATA)
             .create();
-        let data: Vec<f64> = sensor.measure();
-        assert_eq!(data, vec![-1.0; NAMES.len()]);
+        assert!(matches!(sensor.measure(), Err(SensorError::Parse(_))));
 
         // server error
         server
@@ -233,8 +307,40 @@ mod tests {
             .with_header("content-type", "application/json")
             .with_body("Whoops")
             .create();
-        let data: Vec<f64> = sensor.measure();
-        assert_eq!(data, vec![-1.0; NAMES.len()]);
+        assert!(matches!(sensor.measure(), Err(SensorError::Http(_))));
+    }
+
+    #[test]
+    fn test_coalesce_for_failure() {
+        let mut server = mockito::Server::new();
+        let mock_a = server
+            .mock(
+                "GET",
+                "/data/2.5/weather?lat=0&lon=0&appid=foo&units=metric",
+            )
+            .with_status(200)
+            .with_body(TEST_DATA)
+            .expect(1)
+            .create();
+        let mock_b = server
+            .mock(
+                "GET",
+                "/data/2.5/weather?lat=1&lon=0&appid=foo&units=metric",
+            )
+            .with_status(200)
+            .with_body(TEST_DATA)
+            .expect(1)
+            .create();
+
+        // different coordinates are not coalesced.
+        let url: String = server.url() + "/data/2.5/weather";
+        let sensor_a =
+            WeatherSensor::new("a".to_string(), url.clone(), 0.0, 0.0, pool(&["foo"]), 60.0);
+        let sensor_b = WeatherSensor::new("b".to_string(), url, 1.0, 0.0, pool(&["foo"]), 60.0);
+        sensor_a.measure().unwrap();
+        sensor_b.measure().unwrap();
+        mock_a.assert();
+        mock_b.assert();
     }
 
     // Tests for sanity.
@@ -246,7 +352,8 @@ mod tests {
             "localhost:8080/data/2.5/weather".to_string(),
             0.0,
             0.0,
-            "foo".to_string(),
+            pool(&["foo"]),
+            0.0,
         );
         let res: Vec<String> = sensor.get_names();
         assert_eq!(
@@ -264,6 +371,20 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_get_units_for_sanity() {
+        let sensor = WeatherSensor::new(
+            "test".to_string(),
+            "localhost:8080/data/2.5/weather".to_string(),
+            0.0,
+            0.0,
+            pool(&["foo"]),
+            0.0,
+        );
+        assert_eq!(sensor.get_units().len(), sensor.get_names().len());
+        assert_eq!(sensor.get_units()[..3], ["°C", "%", "hPa"]);
+    }
+
     #[test]
     fn test_measure_for_sanity() {
         let mut server = mockito::Server::new();
@@ -284,12 +405,135 @@ mod tests {
             url.to_owned() + "/data/2.5/weather",
             0.0,
             0.0,
-            "foo".to_string(),
+            pool(&["foo"]),
+            0.0,
         );
-        let data: Vec<f64> = sensor.measure();
+        let data: Vec<f64> = sensor.measure().unwrap().iter().map(|r| r.value).collect();
         assert_eq!(
             data,
             vec![23.0, 65.0, 900.0, 100000.0, 2.4, 270.0, 75.0, 201.0]
         );
     }
+
+    #[test]
+    fn test_coalesce_for_sanity() {
+        let mut server = mockito::Server::new();
+        let mock = server
+            .mock(
+                "GET",
+                "/data/2.5/weather?lat=0&lon=0&appid=foo&units=metric",
+            )
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(TEST_DATA)
+            .expect(1)
+            .create();
+
+        // two sensors at the same location only trigger one request.
+        let url: String = server.url() + "/data/2.5/weather";
+        let sensor_a =
+            WeatherSensor::new("a".to_string(), url.clone(), 0.0, 0.0, pool(&["foo"]), 60.0);
+        let sensor_b = WeatherSensor::new("b".to_string(), url, 0.0, 0.0, pool(&["foo"]), 60.0);
+        let values = |sensor: &WeatherSensor| -> Vec<f64> {
+            sensor.measure().unwrap().iter().map(|r| r.value).collect()
+        };
+        assert_eq!(values(&sensor_a), values(&sensor_b));
+        mock.assert();
+    }
+
+    #[test]
+    fn test_key_rotation_for_sanity() {
+        let mut server = mockito::Server::new();
+        let exhausted = server
+            .mock(
+                "GET",
+                "/data/2.5/weather?lat=0&lon=0&appid=foo&units=metric",
+            )
+            .with_status(429)
+            .with_body("{\"cod\": 429}")
+            .expect(1)
+            .create();
+        let fallback = server
+            .mock(
+                "GET",
+                "/data/2.5/weather?lat=0&lon=0&appid=bar&units=metric",
+            )
+            .with_status(200)
+            .with_body(TEST_DATA)
+            .expect(2)
+            .create();
+
+        let sensor = WeatherSensor::new(
+            "test".to_string(),
+            server.url() + "/data/2.5/weather",
+            0.0,
+            0.0,
+            keys::KeyPool::new(
+                "test".to_string(),
+                vec!["foo".to_string(), "bar".to_string()],
+                0.5,
+                None,
+            ),
+            0.0,
+        );
+        // the exhausted key is skipped...
+        assert_eq!(sensor.measure().unwrap()[0].value, 23.0);
+        assert_eq!(sensor.measure().unwrap()[0].value, 23.0);
+        exhausted.assert();
+        fallback.assert();
+
+        // ... until its cooldown has passed; the second key is kept while it works though.
+        thread::sleep(time::Duration::from_millis(600));
+        exhausted.remove();
+        fallback.remove();
+        let fallback = server
+            .mock(
+                "GET",
+                "/data/2.5/weather?lat=0&lon=0&appid=bar&units=metric",
+            )
+            .with_status(401)
+            .expect(1)
+            .create();
+        let recovered = server
+            .mock(
+                "GET",
+                "/data/2.5/weather?lat=0&lon=0&appid=foo&units=metric",
+            )
+            .with_status(200)
+            .with_body(TEST_DATA)
+            .expect(1)
+            .create();
+        assert_eq!(sensor.measure().unwrap()[0].value, 23.0);
+        fallback.assert();
+        recovered.assert();
+    }
+
+    #[test]
+    fn test_debug_ring_for_sanity() {
+        let mut server = mockito::Server::new();
+        server
+            .mock(
+                "GET",
+                "/data/2.5/weather?lat=0&lon=0&appid=secretkey&units=metric",
+            )
+            .with_status(500)
+            .with_body("Whoops")
+            .create();
+        let sensor = WeatherSensor::new(
+            "weather_debug".to_string(),
+            server.url() + "/data/2.5/weather",
+            0.0,
+            0.0,
+            pool(&["secretkey"]),
+            0.0,
+        );
+        assert!(matches!(sensor.measure(), Err(SensorError::Http(_))));
+
+        let entries = debug::ring("weather_debug").entries();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].status, Some(500));
+        assert_eq!(entries[0].body, "Whoops");
+        assert!(entries[0].url.contains("appid=secr****"));
+        assert!(!entries[0].url.contains("secretkey"));
+    }
 }
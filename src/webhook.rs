@@ -0,0 +1,184 @@
+use std::collections::VecDeque;
+use std::error::Error;
+use std::time;
+
+use crate::output;
+
+/// Where and how to send the rows to.
+pub(crate) struct Endpoint {
+    pub(crate) url: String,
+    pub(crate) method: reqwest::Method,
+    pub(crate) headers: Vec<(String, String)>,
+    // sent as bearer token.
+    pub(crate) token: Option<String>,
+    pub(crate) timeout: time::Duration,
+}
+
+/// Sends each row as a JSON object - keyed by the column names, failed values as null - to an HTTP endpoint.
+///
+/// Rows that could not be sent are kept (up to max_rows, dropping the oldest) and sent in order with the next row; a
+/// failing endpoint costs at most one timeout per row.
+pub(crate) struct WebhookOutput {
+    endpoint: Endpoint,
+    names: Vec<String>,
+    max_rows: usize,
+    pending: VecDeque<String>,
+    client: reqwest::blocking::Client,
+}
+
+impl WebhookOutput {
+    pub(crate) fn new(endpoint: Endpoint, max_rows: usize) -> WebhookOutput {
+        let client = reqwest::blocking::ClientBuilder::new()
+            .timeout(endpoint.timeout)
+            .build()
+            .unwrap();
+        WebhookOutput {
+            endpoint,
+            names: Vec::new(),
+            max_rows: max_rows.max(1),
+            pending: VecDeque::new(),
+            client,
+        }
+    }
+
+    fn send(&self, body: &str) -> Result<(), Box<dyn Error>> {
+        let mut req = self
+            .client
+            .request(self.endpoint.method.clone(), &self.endpoint.url)
+            .header("Content-Type", "application/json");
+        for (key, value) in &self.endpoint.headers {
+            req = req.header(key.as_str(), value.as_str());
+        }
+        if let Some(token) = &self.endpoint.token {
+            req = req.bearer_auth(token);
+        }
+        let status = req.body(body.to_string()).send()?.status().as_u16();
+        if !(200..300).contains(&status) {
+            return Err(Box::from(format!(
+                "Status code was not 2xx; but: {}.",
+                status
+            )));
+        }
+        Ok(())
+    }
+}
+
+impl output::Output for WebhookOutput {
+    fn write_header(&mut self, names: &[String]) {
+        self.names = names.to_vec();
+    }
+
+    fn write(&mut self, values: &[f64]) -> Result<(), String> {
+        self.pending.push_back(output::to_json(&self.names, values));
+        while self.pending.len() > self.max_rows {
+            self.pending.pop_front();
+        }
+        while let Some(body) = self.pending.front() {
+            if let Err(err) = self.send(body) {
+                return Err(format!(
+                    "could not send to {}; retrying {} rows later: {}",
+                    self.endpoint.url,
+                    self.pending.len(),
+                    err
+                ));
+            }
+            self.pending.pop_front();
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output::Output;
+
+    fn webhook(url: String, max_rows: usize) -> WebhookOutput {
+        let endpoint = Endpoint {
+            url,
+            method: reqwest::Method::PUT,
+            headers: vec![("X-Site".to_string(), "home".to_string())],
+            token: Some("abc".to_string()),
+            timeout: time::Duration::from_secs(2),
+        };
+        let mut output = WebhookOutput::new(endpoint, max_rows);
+        output.write_header(&[
+            "timestamp".to_string(),
+            "fox_pvPower".to_string(),
+            "fritz_power".to_string(),
+        ]);
+        output
+    }
+
+    // Tests for success.
+
+    #[test]
+    fn test_write_for_success() {
+        let mut server = mockito::Server::new();
+        let mock = server
+            .mock("PUT", "/ingest")
+            .match_header("Authorization", "Bearer abc")
+            .match_header("X-Site", "home")
+            .match_header("Content-Type", "application/json")
+            .match_body(mockito::Matcher::JsonString(
+                r#"{"timestamp":1699920000.0,"fox_pvPower":1.5,"fritz_power":null}"#.to_string(),
+            ))
+            .with_status(200)
+            .create();
+        let mut output = webhook(format!("{}/ingest", server.url()), 10);
+        output.write(&[1699920000.0, 1.5, f64::NAN]).unwrap();
+        mock.assert();
+        assert!(output.pending.is_empty());
+    }
+
+    // Tests for failure.
+
+    #[test]
+    fn test_write_for_failure() {
+        // nowhere to send to; rows are kept, but only so many.
+        let mut output = webhook("http://localhost:1/ingest".to_string(), 2);
+        for t in [1699920000.0, 1699920030.0, 1699920060.0] {
+            assert!(output.write(&[t, 1.5, 50.0]).is_err());
+        }
+        assert_eq!(output.pending.len(), 2);
+        assert!(output.pending[0].starts_with(r#"{"timestamp":1699920030"#));
+    }
+
+    // Tests for sanity.
+
+    #[test]
+    fn test_retry_for_sanity() {
+        let mut server = mockito::Server::new();
+        let down = server
+            .mock("PUT", "/ingest")
+            .with_status(503)
+            .expect(1)
+            .create();
+        let mut output = webhook(format!("{}/ingest", server.url()), 10);
+        assert!(output.write(&[1699920000.0, 1.5, 50.0]).is_err());
+        down.assert();
+        down.remove();
+
+        // the kept row goes first.
+        let first = server
+            .mock("PUT", "/ingest")
+            .match_body(mockito::Matcher::PartialJsonString(
+                r#"{"timestamp":1699920000.0}"#.to_string(),
+            ))
+            .with_status(204)
+            .expect(1)
+            .create();
+        let second = server
+            .mock("PUT", "/ingest")
+            .match_body(mockito::Matcher::PartialJsonString(
+                r#"{"timestamp":1699920030.0}"#.to_string(),
+            ))
+            .with_status(204)
+            .expect(1)
+            .create();
+        output.write(&[1699920030.0, 1.6, 55.0]).unwrap();
+        first.assert();
+        second.assert();
+        assert!(output.pending.is_empty());
+    }
+}
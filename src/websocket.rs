@@ -0,0 +1,287 @@
+use std::io::{self, Read, Write};
+
+/// Appended to the key of the client to prove the handshake was understood (RFC 6455).
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+pub(crate) const TEXT: u8 = 0x1;
+pub(crate) const CLOSE: u8 = 0x8;
+pub(crate) const PING: u8 = 0x9;
+pub(crate) const PONG: u8 = 0xA;
+const CONTINUATION: u8 = 0x0;
+
+/// Upgrade requests larger than this are refused.
+const MAX_REQUEST: usize = 8192;
+
+/// Frames larger than this are refused; OCPP messages are a few KB at most.
+const MAX_PAYLOAD: u64 = 1 << 20;
+
+/// The value of the Sec-WebSocket-Accept header for the given key.
+pub(crate) fn accept_key(key: &str) -> String {
+    let digest = openssl::sha::sha1(format!("{}{}", key.trim(), GUID).as_bytes());
+    openssl::base64::encode_block(&digest)
+}
+
+/// Reads the upgrade request of a client and switches protocols; returns the path it asked for.
+///
+/// A subprotocol is agreed on if the client offers it; e.g. ocpp1.6.
+pub(crate) fn handshake<S: Read + Write>(stream: &mut S, protocol: &str) -> io::Result<String> {
+    // byte by byte; whatever follows the request already belongs to the frames.
+    let mut request = Vec::new();
+    let mut byte = [0; 1];
+    while !request.ends_with(b"\r\n\r\n") {
+        if request.len() > MAX_REQUEST {
+            return Err(invalid("upgrade request too large."));
+        }
+        if stream.read(&mut byte)? == 0 {
+            break;
+        }
+        request.push(byte[0]);
+    }
+    let request = String::from_utf8_lossy(&request);
+    let path = request
+        .strip_prefix("GET ")
+        .and_then(|r| r.split(' ').next())
+        .ok_or_else(|| invalid("not a websocket upgrade request."))?
+        .to_string();
+    let header = |name: &str| {
+        request.lines().find_map(|l| {
+            let (key, value) = l.split_once(':')?;
+            key.trim()
+                .eq_ignore_ascii_case(name)
+                .then(|| value.trim().to_string())
+        })
+    };
+    let key = header("Sec-WebSocket-Key").ok_or_else(|| invalid("no Sec-WebSocket-Key."))?;
+    let offered = header("Sec-WebSocket-Protocol")
+        .map(|p| p.split(',').any(|p| p.trim() == protocol))
+        .unwrap_or(false);
+    let mut response = format!(
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n",
+        accept_key(&key)
+    );
+    if offered {
+        response.push_str(&format!("Sec-WebSocket-Protocol: {}\r\n", protocol));
+    }
+    response.push_str("\r\n");
+    stream.write_all(response.as_bytes())?;
+    Ok(path)
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Reads a single frame; returns its opcode, whether it is the final one of its message and the unmasked payload.
+pub(crate) fn read_frame<R: Read>(stream: &mut R) -> io::Result<(u8, bool, Vec<u8>)> {
+    let mut head = [0; 2];
+    stream.read_exact(&mut head)?;
+    let fin = head[0] & 0x80 != 0;
+    let opcode = head[0] & 0x0F;
+    let masked = head[1] & 0x80 != 0;
+    let len = match head[1] & 0x7F {
+        126 => {
+            let mut buf = [0; 2];
+            stream.read_exact(&mut buf)?;
+            u16::from_be_bytes(buf) as u64
+        }
+        127 => {
+            let mut buf = [0; 8];
+            stream.read_exact(&mut buf)?;
+            u64::from_be_bytes(buf)
+        }
+        len => len as u64,
+    };
+    if len > MAX_PAYLOAD {
+        return Err(invalid("frame too large."));
+    }
+    let mut mask = [0; 4];
+    if masked {
+        stream.read_exact(&mut mask)?;
+    }
+    let mut payload = vec![0; len as usize];
+    stream.read_exact(&mut payload)?;
+    if masked {
+        for (i, byte) in payload.iter_mut().enumerate() {
+            *byte ^= mask[i % 4];
+        }
+    }
+    Ok((opcode, fin, payload))
+}
+
+/// Reads the next message, answering pings on the way; None once the peer closed the connection.
+pub(crate) fn read_message<S: Read + Write>(stream: &mut S) -> io::Result<Option<String>> {
+    let mut message = Vec::new();
+    loop {
+        let (opcode, fin, payload) = read_frame(stream)?;
+        match opcode {
+            TEXT | CONTINUATION => {
+                message.extend(payload);
+                if fin {
+                    return String::from_utf8(message)
+                        .map(Some)
+                        .map_err(|_| invalid("text is not UTF-8."));
+                }
+            }
+            PING => write_frame(stream, PONG, &payload, None)?,
+            CLOSE => {
+                write_frame(stream, CLOSE, &payload, None)?;
+                return Ok(None);
+            }
+            // pongs and binary messages mean nothing to us.
+            _ => {}
+        }
+    }
+}
+
+/// Writes a single, final frame; clients have to mask theirs, servers must not.
+pub(crate) fn write_frame<W: Write>(
+    stream: &mut W,
+    opcode: u8,
+    payload: &[u8],
+    mask: Option<[u8; 4]>,
+) -> io::Result<()> {
+    let mut frame = vec![0x80 | opcode];
+    let bit = if mask.is_some() { 0x80 } else { 0 };
+    match payload.len() {
+        len if len < 126 => frame.push(bit | len as u8),
+        len if len <= u16::MAX as usize => {
+            frame.push(bit | 126);
+            frame.extend((len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(bit | 127);
+            frame.extend((len as u64).to_be_bytes());
+        }
+    }
+    match mask {
+        Some(mask) => {
+            frame.extend(mask);
+            frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        }
+        None => frame.extend(payload),
+    }
+    stream.write_all(&frame)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    /// A connection reading the given input; what is written to it is kept apart.
+    struct Duplex {
+        input: Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Duplex {
+        fn new(input: &[u8]) -> Duplex {
+            Duplex {
+                input: Cursor::new(input.to_vec()),
+                output: Vec::new(),
+            }
+        }
+    }
+
+    impl Read for Duplex {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for Duplex {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // Tests for success.
+
+    #[test]
+    fn test_accept_key_for_success() {
+        // the example of RFC 6455.
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[test]
+    fn test_handshake_for_success() {
+        let request = "GET /ocpp/CP1 HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\n\
+            Connection: Upgrade\r\nsec-websocket-key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+            Sec-WebSocket-Version: 13\r\nSec-WebSocket-Protocol: ocpp2.0, ocpp1.6\r\n\r\n";
+        let mut input = request.as_bytes().to_vec();
+        input.extend([0x81, 0x00]);
+        let mut stream = Duplex::new(&input);
+        assert_eq!(handshake(&mut stream, "ocpp1.6").unwrap(), "/ocpp/CP1");
+        // the frame following the request is left alone.
+        assert_eq!(read_frame(&mut stream).unwrap(), (TEXT, true, Vec::new()));
+        let response = String::from_utf8(stream.output).unwrap();
+        assert!(response.starts_with("HTTP/1.1 101 Switching Protocols\r\n"));
+        assert!(response.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+        assert!(response.contains("Sec-WebSocket-Protocol: ocpp1.6\r\n"));
+    }
+
+    // Tests for failure.
+
+    #[test]
+    fn test_handshake_for_failure() {
+        let mut stream = Duplex::new(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        assert!(handshake(&mut stream, "ocpp1.6").is_err());
+        let mut stream = Duplex::new(b"ohno\r\n\r\n");
+        assert!(handshake(&mut stream, "ocpp1.6").is_err());
+        assert!(stream.output.is_empty());
+    }
+
+    #[test]
+    fn test_read_frame_for_failure() {
+        // cut off in the middle of the payload.
+        let mut stream = Cursor::new(vec![0x81, 0x05, b'h', b'i']);
+        assert!(read_frame(&mut stream).is_err());
+        let mut stream = Cursor::new(vec![0x81, 0x7F, 0xFF, 0, 0, 0, 0, 0, 0, 0]);
+        assert!(read_frame(&mut stream).is_err());
+    }
+
+    // Tests for sanity.
+
+    #[test]
+    fn test_frames_for_sanity() {
+        let long = "x".repeat(300);
+        let mut buf = Vec::new();
+        write_frame(&mut buf, TEXT, b"hello", Some([1, 2, 3, 4])).unwrap();
+        write_frame(&mut buf, TEXT, long.as_bytes(), None).unwrap();
+        // masked on the wire.
+        assert_eq!(buf[..2], [0x81, 0x85]);
+        assert_ne!(&buf[6..11], b"hello");
+        let mut stream = Cursor::new(buf);
+        assert_eq!(
+            read_frame(&mut stream).unwrap(),
+            (TEXT, true, b"hello".to_vec())
+        );
+        assert_eq!(
+            read_frame(&mut stream).unwrap(),
+            (TEXT, true, long.as_bytes().to_vec())
+        );
+    }
+
+    #[test]
+    fn test_read_message_for_sanity() {
+        // a fragmented message with a ping in between, then a close.
+        let mut stream = Duplex::new(&[
+            0x01, 0x02, b'h', b'e', 0x89, 0x00, 0x80, 0x03, b'l', b'l', b'o', 0x88, 0x00,
+        ]);
+        assert_eq!(
+            read_message(&mut stream).unwrap(),
+            Some("hello".to_string())
+        );
+        assert_eq!(read_message(&mut stream).unwrap(), None);
+        // the ping was answered, so was the close.
+        assert_eq!(stream.output, [0x8A, 0x00, 0x88, 0x00]);
+    }
+}
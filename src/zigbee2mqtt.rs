@@ -0,0 +1,582 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time;
+
+use crate::clock;
+use crate::common;
+use crate::common::{ConfigError, Reading, SensorError};
+use crate::prometheus;
+
+/// The fields recorded by default.
+const FIELDS: [&str; 4] = ["power", "energy", "temperature", "linkquality"];
+
+/// The units of common fields, as Zigbee2MQTT reports them.
+const UNITS: [(&str, &str); 7] = [
+    ("power", "W"),
+    ("energy", "kWh"),
+    ("temperature", "°C"),
+    ("linkquality", ""),
+    ("voltage", "V"),
+    ("current", "A"),
+    ("state", ""),
+];
+
+/// Latest payload per friendly name & when it was received.
+type Cache = Arc<Mutex<HashMap<String, (time::SystemTime, serde_json::Value)>>>;
+
+/// Where messages come from: the broker, or a fixture in tests.
+pub(crate) trait Source: Send {
+    /// The next message as topic and payload, or why there is none for now; None once the source is closed.
+    fn next(&mut self) -> Option<Result<(String, Vec<u8>), String>>;
+
+    /// Something that makes next return soon, to be called from another thread.
+    fn closer(&self) -> Box<dyn Fn() + Send>;
+}
+
+/// Caches the payload of a message if it is the state of one of the devices, i.e. published to <prefix>/<device>.
+pub(crate) fn handle(cache: &Cache, prefix: &str, devices: &[String], topic: &str, payload: &[u8]) {
+    let device = match topic.strip_prefix(prefix).and_then(|t| t.strip_prefix('/')) {
+        // not e.g. <device>/availability or <device>/set.
+        Some(device) if devices.iter().any(|d| d == device) => device,
+        _ => return,
+    };
+    match serde_json::from_slice::<serde_json::Value>(payload) {
+        Ok(value) if value.is_object() => {
+            cache
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .insert(device.to_string(), (time::SystemTime::now(), value));
+        }
+        _ => eprintln!("Ignoring a payload of {} that is not a JSON object.", topic),
+    }
+}
+
+/// The value of a field; switch states like ON and OFF are 1 and 0.
+pub(crate) fn extract(payload: &serde_json::Value, field: &str) -> Option<f64> {
+    match &payload[field] {
+        serde_json::Value::String(text) => match text.as_str() {
+            "ON" => Some(1.0),
+            "OFF" => Some(0.0),
+            text => text.trim().parse().ok(),
+        },
+        serde_json::Value::Bool(flag) => Some(if *flag { 1.0 } else { 0.0 }),
+        value => value.as_f64(),
+    }
+}
+
+/// When a payload was measured (epoch secs) by its last_seen field, if Zigbee2MQTT is set up to send it; as epoch
+/// milliseconds or an ISO 8601 date.
+pub(crate) fn last_seen(payload: &serde_json::Value) -> Option<f64> {
+    match &payload["last_seen"] {
+        serde_json::Value::Number(millis) => millis.as_f64().map(|m| m / 1000.0),
+        serde_json::Value::String(text) => chrono::DateTime::parse_from_rfc3339(text)
+            .ok()
+            .map(|t| t.timestamp_millis() as f64 / 1000.0),
+        _ => None,
+    }
+}
+
+/// The background thread feeding the cache, and how to stop it.
+struct Listener {
+    stop: Arc<AtomicBool>,
+    close: Box<dyn Fn() + Send>,
+    worker: thread::JoinHandle<()>,
+}
+
+impl Listener {
+    fn spawn(
+        mut source: Box<dyn Source>,
+        prefix: String,
+        devices: Vec<String>,
+        cache: Cache,
+    ) -> Listener {
+        let stop = Arc::new(AtomicBool::new(false));
+        let close = source.closer();
+        let stopped = stop.clone();
+        let worker = thread::spawn(move || {
+            while let Some(message) = source.next() {
+                match message {
+                    Ok((topic, payload)) => handle(&cache, &prefix, &devices, &topic, &payload),
+                    Err(err) => {
+                        eprintln!("Lost connection to the MQTT broker; reconnecting: {}", err);
+                        // in steps, so a shutdown does not wait long.
+                        for _ in 0..50 {
+                            if stopped.load(Ordering::Relaxed) {
+                                return;
+                            }
+                            thread::sleep(time::Duration::from_millis(100));
+                        }
+                    }
+                }
+            }
+        });
+        Listener {
+            stop,
+            close,
+            worker,
+        }
+    }
+
+    /// Stops the thread; waits up to 5 secs for it to end.
+    fn stop(self) {
+        self.stop.store(true, Ordering::Relaxed);
+        (self.close)();
+        for _ in 0..50 {
+            if self.worker.is_finished() {
+                if self.worker.join().is_err() {
+                    eprintln!("The MQTT listener ended with a panic.");
+                }
+                return;
+            }
+            thread::sleep(time::Duration::from_millis(100));
+        }
+        eprintln!("The MQTT listener did not stop in time; leaving it.");
+    }
+}
+
+/// Reports fields of Zigbee devices, e.g. the power of plugs, from the messages Zigbee2MQTT publishes for them.
+///
+/// A background thread subscribes to the device topics and keeps the latest message of each; a message older than
+/// the maximum age is not reported, so a device that dropped off the network shows up as missing.
+pub struct Zigbee2MqttSensor {
+    name: String,
+    devices: Vec<String>,
+    fields: Vec<String>,
+    max_age: time::Duration,
+    cache: Cache,
+    listener: Option<Listener>,
+}
+
+impl Zigbee2MqttSensor {
+    pub(crate) fn new(
+        name: String,
+        prefix: String,
+        devices: Vec<String>,
+        fields: Vec<String>,
+        max_age: time::Duration,
+        source: Option<Box<dyn Source>>,
+    ) -> Zigbee2MqttSensor {
+        let cache: Cache = Arc::new(Mutex::new(HashMap::new()));
+        let listener =
+            source.map(|source| Listener::spawn(source, prefix, devices.clone(), cache.clone()));
+        Zigbee2MqttSensor {
+            name,
+            devices,
+            fields,
+            max_age,
+            cache,
+            listener,
+        }
+    }
+}
+
+impl common::Sensor for Zigbee2MqttSensor {
+    fn get_names(&self) -> Vec<String> {
+        self.devices
+            .iter()
+            .flat_map(|device| {
+                self.fields.iter().map(move |field| {
+                    format!(
+                        "{}_{}_{}",
+                        self.name,
+                        prometheus::sanitize(device).to_lowercase(),
+                        field
+                    )
+                })
+            })
+            .collect()
+    }
+
+    fn get_units(&self) -> Vec<String> {
+        self.devices
+            .iter()
+            .flat_map(|_| {
+                self.fields.iter().map(|field| {
+                    UNITS
+                        .iter()
+                        .find(|(f, _)| *f == field.as_str())
+                        .map(|(_, unit)| unit.to_string())
+                        .unwrap_or_else(|| "unknown".to_string())
+                })
+            })
+            .collect()
+    }
+
+    fn measure(&self) -> Result<Vec<Reading>, SensorError> {
+        let names = self.get_names();
+        let cache = self.cache.lock().unwrap_or_else(|e| e.into_inner());
+        let mut res = Vec::new();
+        for (i, device) in self.devices.iter().enumerate() {
+            // stale devices are left out.
+            let (received, payload) = match cache.get(device) {
+                Some((received, payload))
+                    if received.elapsed().unwrap_or_default() <= self.max_age =>
+                {
+                    (received, payload)
+                }
+                _ => continue,
+            };
+            let time = last_seen(payload).unwrap_or_else(|| clock::epoch_secs(*received));
+            for (j, field) in self.fields.iter().enumerate() {
+                if let Some(value) = extract(payload, field) {
+                    res.push(Reading {
+                        time: Some(time),
+                        ..Reading::new(names[i * self.fields.len() + j].clone(), value)
+                    });
+                }
+            }
+        }
+        if res.is_empty() {
+            return Err(SensorError::Protocol(format!(
+                "no message from any device within the last {}s.",
+                self.max_age.as_secs()
+            )));
+        }
+        Ok(res)
+    }
+
+    fn shutdown(&mut self) {
+        if let Some(listener) = self.listener.take() {
+            listener.stop();
+        }
+    }
+}
+
+#[cfg(feature = "mqtt")]
+mod broker {
+    use rumqttc::{Client, Connection, Event, MqttOptions, Packet, QoS};
+
+    use super::Source;
+
+    /// The messages of the device topics; subscribed again whenever the connection is (re)established.
+    pub(super) struct Broker {
+        client: Client,
+        connection: Connection,
+        topics: Vec<String>,
+    }
+
+    impl Broker {
+        pub(super) fn new(options: MqttOptions, topics: Vec<String>) -> Broker {
+            let (client, connection) = Client::new(options, 10);
+            Broker {
+                client,
+                connection,
+                topics,
+            }
+        }
+    }
+
+    impl Source for Broker {
+        fn next(&mut self) -> Option<Result<(String, Vec<u8>), String>> {
+            loop {
+                match self.connection.iter().next()? {
+                    Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                        for topic in &self.topics {
+                            if let Err(err) = self.client.try_subscribe(topic, QoS::AtMostOnce) {
+                                return Some(Err(err.to_string()));
+                            }
+                        }
+                    }
+                    Ok(Event::Incoming(Packet::Publish(publish))) => {
+                        return Some(Ok((publish.topic, publish.payload.to_vec())))
+                    }
+                    Ok(_) => {}
+                    Err(err) => return Some(Err(err.to_string())),
+                }
+            }
+        }
+
+        fn closer(&self) -> Box<dyn Fn() + Send> {
+            let client = self.client.clone();
+            Box::new(move || {
+                let _ = client.clone().try_disconnect();
+            })
+        }
+    }
+}
+
+/// The broker listened to, given the configuration.
+#[cfg(feature = "mqtt")]
+fn get_source(
+    name: &str,
+    sensor_cfg: &toml::value::Table,
+    topics: Vec<String>,
+) -> Result<Option<Box<dyn Source>>, ConfigError> {
+    let text = |key: &str| sensor_cfg.get(key).and_then(|v| v.as_str());
+    let port = match sensor_cfg.get("port").map(|v| v.as_integer()) {
+        None => 1883,
+        Some(Some(port)) if (1..=65535).contains(&port) => port as u16,
+        Some(_) => {
+            return Err(ConfigError::Invalid(
+                "port must be between 1 and 65535.".to_string(),
+            ))
+        }
+    };
+    let mut options = rumqttc::MqttOptions::new(
+        format!("open_green_compute_{}", name),
+        text("host").unwrap_or("localhost"),
+        port,
+    );
+    options.set_keep_alive(time::Duration::from_secs(30));
+    match (text("username"), text("password")) {
+        (Some(username), Some(password)) => {
+            options.set_credentials(username, password);
+        }
+        (None, None) => {}
+        _ => {
+            return Err(ConfigError::Invalid(
+                "set both username and password, or neither.".to_string(),
+            ))
+        }
+    }
+    Ok(Some(Box::new(broker::Broker::new(options, topics))))
+}
+
+#[cfg(not(feature = "mqtt"))]
+fn get_source(
+    name: &str,
+    _: &toml::value::Table,
+    _: Vec<String>,
+) -> Result<Option<Box<dyn Source>>, ConfigError> {
+    Err(ConfigError::Invalid(format!(
+        "zigbee2mqtt sensor {} requires the mqtt feature.",
+        name
+    )))
+}
+
+/// Zigbee devices via Zigbee2MQTT.
+pub(crate) fn sensor_type() -> common::SensorType {
+    common::SensorType {
+        name: "zigbee2mqtt",
+        required: &["devices"],
+        optional: &[
+            "host",
+            "port",
+            "username",
+            "password",
+            "topic",
+            "fields",
+            "max_age_secs",
+        ],
+        create: |name, sensor_cfg, _| {
+            let strings = |key: &str| -> Result<Option<Vec<String>>, ConfigError> {
+                sensor_cfg
+                    .get(key)
+                    .map(|v| {
+                        v.as_array()
+                            .and_then(|a| {
+                                a.iter()
+                                    .map(|s| s.as_str().map(|s| s.to_string()))
+                                    .collect::<Option<Vec<_>>>()
+                            })
+                            .filter(|a| !a.is_empty())
+                            .ok_or_else(|| {
+                                ConfigError::Invalid(format!(
+                                    "{} must be a non-empty array of strings.",
+                                    key
+                                ))
+                            })
+                    })
+                    .transpose()
+            };
+            let devices = strings("devices")?.unwrap_or_default();
+            let fields = strings("fields")?
+                .unwrap_or_else(|| FIELDS.iter().map(|f| f.to_string()).collect());
+            let prefix = match sensor_cfg.get("topic") {
+                Some(v) => v
+                    .as_str()
+                    .ok_or_else(|| ConfigError::Invalid("topic must be a string.".to_string()))?
+                    .trim_end_matches('/')
+                    .to_string(),
+                None => "zigbee2mqtt".to_string(),
+            };
+            let max_age = sensor_cfg
+                .get("max_age_secs")
+                .map(|v| v.as_integer())
+                .unwrap_or(Some(300))
+                .filter(|secs| *secs > 0)
+                .ok_or_else(|| {
+                    ConfigError::Invalid("max_age_secs must be a positive integer.".to_string())
+                })?;
+            let topics = devices
+                .iter()
+                .map(|device| format!("{}/{}", prefix, device))
+                .collect();
+            let source = get_source(name, sensor_cfg, topics)?;
+            Ok(Box::new(Zigbee2MqttSensor::new(
+                name.to_string(),
+                prefix,
+                devices,
+                fields,
+                time::Duration::from_secs(max_age as u64),
+                source,
+            )))
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+
+    use super::*;
+    use crate::common::values;
+    use crate::common::Sensor;
+
+    /// As published by Zigbee2MQTT for a plug.
+    const PLUG: &str = r#"{"child_lock":"UNLOCK","current":0.42,"energy":12.87,"indicator_mode":"off/on",
+    "linkquality":116,"power":87,"power_outage_memory":"restore","state":"ON","voltage":231}"#;
+
+    /// Hands on the messages sent by the test until it hangs up.
+    struct Fixture(mpsc::Receiver<(String, Vec<u8>)>);
+
+    impl Source for Fixture {
+        fn next(&mut self) -> Option<Result<(String, Vec<u8>), String>> {
+            self.0.recv().ok().map(Ok)
+        }
+
+        fn closer(&self) -> Box<dyn Fn() + Send> {
+            Box::new(|| {})
+        }
+    }
+
+    fn z2m(fields: &[&str], source: Option<Box<dyn Source>>) -> Zigbee2MqttSensor {
+        Zigbee2MqttSensor::new(
+            "z2m".to_string(),
+            "zigbee2mqtt".to_string(),
+            vec!["Washing Machine".to_string(), "fridge".to_string()],
+            fields.iter().map(|f| f.to_string()).collect(),
+            time::Duration::from_secs(300),
+            source,
+        )
+    }
+
+    // Tests for success.
+
+    #[test]
+    fn test_measure_for_success() {
+        let (tx, rx) = mpsc::channel();
+        let mut sensor = z2m(&["power", "energy", "state"], Some(Box::new(Fixture(rx))));
+        assert_eq!(sensor.get_units()[..3], ["W", "kWh", ""]);
+        for (topic, payload) in [
+            ("zigbee2mqtt/Washing Machine", PLUG),
+            // not its state.
+            ("zigbee2mqtt/fridge/availability", "{\"state\":\"online\"}"),
+            ("zigbee2mqtt/bridge/state", "{\"state\":\"online\"}"),
+        ] {
+            tx.send((topic.to_string(), payload.as_bytes().to_vec()))
+                .unwrap();
+        }
+        // hung up; the listener has handled all once it stopped.
+        drop(tx);
+        sensor.shutdown();
+        assert_eq!(
+            values(sensor.measure()),
+            vec![
+                ("z2m_washing_machine_power".to_string(), 87.0),
+                ("z2m_washing_machine_energy".to_string(), 12.87),
+                ("z2m_washing_machine_state".to_string(), 1.0)
+            ]
+        );
+    }
+
+    // Tests for failure.
+
+    #[test]
+    fn test_measure_for_failure() {
+        let sensor = z2m(&["power"], None);
+        assert!(matches!(sensor.measure(), Err(SensorError::Protocol(_))));
+        let devices = vec!["fridge".to_string()];
+        handle(
+            &sensor.cache,
+            "zigbee2mqtt",
+            &devices,
+            "zigbee2mqtt/fridge",
+            b"online",
+        );
+        assert!(sensor.measure().is_err());
+    }
+
+    #[test]
+    fn test_sensor_type_for_failure() {
+        let sensor_cfg: toml::value::Table =
+            toml::from_str("devices=['fridge']\nmax_age_secs=0").unwrap();
+        assert_eq!(
+            (sensor_type().create)("z2m", &sensor_cfg, "state").err(),
+            Some(ConfigError::Invalid(
+                "max_age_secs must be a positive integer.".to_string()
+            ))
+        );
+        let sensor_cfg: toml::value::Table = toml::from_str("devices=[]").unwrap();
+        assert!((sensor_type().create)("z2m", &sensor_cfg, "state").is_err());
+    }
+
+    // Tests for sanity.
+
+    #[test]
+    fn test_last_seen_for_sanity() {
+        let sensor = z2m(&["power"], None);
+        let devices = vec!["Washing Machine".to_string(), "fridge".to_string()];
+        let now = clock::epoch_secs(time::SystemTime::now());
+        handle(
+            &sensor.cache,
+            "zigbee2mqtt",
+            &devices,
+            "zigbee2mqtt/fridge",
+            PLUG.as_bytes(),
+        );
+        handle(
+            &sensor.cache,
+            "zigbee2mqtt",
+            &devices,
+            "zigbee2mqtt/Washing Machine",
+            b"{\"power\":1800,\"last_seen\":\"2024-05-01T12:00:00+02:00\"}",
+        );
+        let readings = sensor.measure().unwrap();
+        assert_eq!(readings[0].time, Some(1714557600.0));
+        // w/o last_seen, when it was received.
+        assert!((readings[1].time.unwrap() - now).abs() < 5.0);
+        assert_eq!(
+            last_seen(&serde_json::json!({ "last_seen": 1714557600500u64 })),
+            Some(1714557600.5)
+        );
+    }
+
+    #[test]
+    fn test_stale_for_sanity() {
+        let sensor = z2m(&["power", "linkquality"], None);
+        let devices = vec!["Washing Machine".to_string(), "fridge".to_string()];
+        handle(
+            &sensor.cache,
+            "zigbee2mqtt",
+            &devices,
+            "zigbee2mqtt/fridge",
+            PLUG.as_bytes(),
+        );
+        handle(
+            &sensor.cache,
+            "zigbee2mqtt",
+            &devices,
+            "zigbee2mqtt/Washing Machine",
+            PLUG.as_bytes(),
+        );
+        // the washing machine was last heard of 10 mins ago.
+        let received = time::SystemTime::now() - time::Duration::from_secs(600);
+        sensor
+            .cache
+            .lock()
+            .unwrap()
+            .get_mut("Washing Machine")
+            .unwrap()
+            .0 = received;
+        assert_eq!(
+            values(sensor.measure()),
+            vec![
+                ("z2m_fridge_power".to_string(), 87.0),
+                ("z2m_fridge_linkquality".to_string(), 116.0)
+            ]
+        );
+    }
+}
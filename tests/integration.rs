@@ -0,0 +1,350 @@
+// Runs the loop end to end against fake services; no hardware, no network, no waiting.
+
+use std::cell::RefCell;
+use std::fs;
+use std::io::{BufRead, BufReader, Read};
+use std::process;
+use std::sync::mpsc;
+use std::time;
+
+use open_green_compute::clock::{self, Clock};
+use open_green_compute::common::{Reading, Sensor, SensorError, SensorType};
+use open_green_compute::config;
+use open_green_compute::snapshot;
+
+const START: u64 = 1699920000;
+const HOUR: f64 = 3600.0;
+
+const WEATHER_PATH: &str = r"^/data/2\.5/weather";
+const FOXESS_PATH: &str = "/op/v0/device/real/query";
+
+const FOXESS_DATA: &str = "{\"errno\": 0, \"result\": [{\"datas\": [\
+    {\"variable\": \"pvPower\", \"value\": 1.5}, \
+    {\"variable\": \"loadsPower\", \"value\": 0.5}]}]}";
+
+fn weather_data(temp: f64) -> String {
+    format!(
+        "{{\"weather\": [{{\"id\": 800}}], \
+        \"main\": {{\"temp\": {}, \"pressure\": 1013, \"humidity\": 80}}, \
+        \"visibility\": 10000, \
+        \"clouds\": {{\"all\": 20}}, \
+        \"wind\": {{\"speed\": 3.5, \"deg\": 180}}}}",
+        temp
+    )
+}
+
+/// A simulated clock that lets the test change the world (elapsed secs) and stop the loop.
+struct ScriptedClock<'a> {
+    sim: clock::SimClock,
+    end: f64,
+    script: RefCell<Box<dyn FnMut(f64) + 'a>>,
+    shutdown: mpsc::Sender<()>,
+}
+
+impl Clock for ScriptedClock<'_> {
+    fn now(&self) -> time::SystemTime {
+        self.sim.now()
+    }
+
+    fn sleep_until(&self, deadline: time::SystemTime) {
+        self.sim.sleep_until(deadline);
+        let elapsed = clock::epoch_secs(self.sim.now()) - START as f64;
+        (self.script.borrow_mut())(elapsed);
+        if elapsed >= self.end {
+            self.shutdown.send(()).expect("loop should still listen.");
+        }
+    }
+}
+
+fn write_config(path: &str, url: &str, filename: &str, state_dir: &str) {
+    let content = format!(
+        "[general]\n\
+        fast_loop=[\"fox\", \"dummy\"]\n\
+        slow_loop=[\"weather\"]\n\
+        timeout=30\n\
+        slow_loop_delay=20\n\
+        filename=\"{filename}\"\n\
+        state_dir=\"{state_dir}\"\n\n\
+        [fox]\n\
+        type=\"foxess\"\n\
+        api_key=\"abc\"\n\
+        inverter_id=\"inv0\"\n\
+        variables=[\"pvPower\", \"loadsPower\"]\n\
+        url=\"{url}\"\n\n\
+        [dummy]\n\
+        type=\"dummy\"\n\
+        values={{ power=42.0 }}\n\n\
+        [weather]\n\
+        type=\"weather\"\n\
+        url=\"{url}/data/2.5/weather\"\n\
+        lat=52.0\n\
+        long=4.0\n\
+        app_id=\"foo\"\n"
+    );
+    fs::write(path, content).unwrap();
+}
+
+/// A sensor an embedder brings along; always measures 7 W.
+struct Meter {
+    name: String,
+}
+
+impl Sensor for Meter {
+    fn get_names(&self) -> Vec<String> {
+        vec![format!("{}_power", self.name)]
+    }
+
+    fn get_units(&self) -> Vec<String> {
+        vec!["W".to_string()]
+    }
+
+    fn measure(&self) -> Result<Vec<Reading>, SensorError> {
+        Ok(vec![Reading::new(format!("{}_power", self.name), 7.0)])
+    }
+}
+
+fn meter_type() -> SensorType {
+    SensorType {
+        name: "meter",
+        required: &[],
+        optional: &[],
+        create: |name, _, _| {
+            Ok(Box::new(Meter {
+                name: name.to_string(),
+            }))
+        },
+    }
+}
+
+// Tests for sanity.
+
+#[test]
+fn test_run_loop_for_sanity() {
+    let mut server = mockito::Server::new();
+    let url = server.url();
+    let (cfg_file, filename, state_dir) = (
+        "integration_test0.toml",
+        "integration_test0.csv",
+        "integration_test0_state",
+    );
+    write_config(cfg_file, &url, filename, state_dir);
+    let cfg = config::load_config(cfg_file);
+
+    let mut weather = server
+        .mock("GET", mockito::Matcher::Regex(WEATHER_PATH.to_string()))
+        .with_status(200)
+        .with_body(weather_data(10.5))
+        .create();
+    let mut fox = server
+        .mock("POST", FOXESS_PATH)
+        .with_status(200)
+        .with_body(FOXESS_DATA)
+        .create();
+
+    // the weather changes after 15 minutes; the FoxESS cloud is down from minute 20 to 22:30.
+    let srv = &mut server;
+    let script = move |elapsed: f64| {
+        if elapsed == 900.0 {
+            weather.remove();
+            weather = srv
+                .mock("GET", mockito::Matcher::Regex(WEATHER_PATH.to_string()))
+                .with_status(200)
+                .with_body(weather_data(12.0))
+                .create();
+        } else if elapsed == 1200.0 {
+            fox.remove();
+            fox = srv
+                .mock("POST", FOXESS_PATH)
+                .with_status(500)
+                .with_body("Internal Server Error")
+                .create();
+        } else if elapsed == 1350.0 {
+            fox.remove();
+            fox = srv
+                .mock("POST", FOXESS_PATH)
+                .with_status(200)
+                .with_body(FOXESS_DATA)
+                .create();
+        }
+    };
+    let (tx, rx) = mpsc::channel();
+    let clock = ScriptedClock {
+        sim: clock::SimClock::new(START),
+        end: HOUR,
+        script: RefCell::new(Box::new(script)),
+        shutdown: tx,
+    };
+    let collector = snapshot::Collector::new();
+    open_green_compute::run_loop(&cfg, &clock, rx, &collector);
+
+    let content = fs::read_to_string(filename).unwrap();
+    let lines: Vec<&str> = content.lines().collect();
+
+    // header & column count.
+    assert_eq!(
+        lines[0],
+        "timestamp,fox_pvPower,fox_loadsPower,dummy_power,\
+        weather_temperature,weather_humidity,weather_pressure,weather_visibility,\
+        weather_wind_speed,weather_wind_direction,weather_cloud_coverage,weather_description"
+    );
+    assert!(lines.iter().all(|l| l.split(',').count() == 12));
+
+    // one hour at 30 secs; the last row is written before the loop stops.
+    assert_eq!(lines.len(), 1 + 120);
+    assert!(content.ends_with('\n'));
+    let rows: Vec<Vec<f64>> = lines[1..]
+        .iter()
+        // missing values are empty cells.
+        .map(|l| {
+            l.split(',')
+                .map(|v| {
+                    if v.is_empty() {
+                        f64::NAN
+                    } else {
+                        v.parse().unwrap()
+                    }
+                })
+                .collect()
+        })
+        .collect();
+    for (i, row) in rows.iter().enumerate() {
+        assert_eq!(row[0], (START + 30 * i as u64) as f64);
+        assert_eq!(row[3], 42.0);
+    }
+    assert_eq!(rows[119][0], START as f64 + HOUR - 30.0);
+
+    // slow values only refresh every 20 iterations; the change at 15 minutes shows up at 20.
+    for (i, row) in rows.iter().enumerate() {
+        let expected = if i < 40 { 10.5 } else { 12.0 };
+        assert_eq!(row[4], expected, "temperature of row {}", i);
+        assert_eq!(&row[5..], &[80.0, 1013.0, 10000.0, 3.5, 180.0, 20.0, 800.0]);
+    }
+
+    // values are missing for exactly the iterations the service was down.
+    for (i, row) in rows.iter().enumerate() {
+        if (40..45).contains(&i) {
+            assert!(row[1..3].iter().all(|v| v.is_nan()), "row {}", i);
+        } else {
+            assert_eq!(&row[1..3], &[1.5, 0.5], "row {}", i);
+        }
+    }
+
+    // embedders see the same last row.
+    let latest = collector.latest();
+    assert_eq!(latest.version, 120);
+    assert_eq!(latest.names.join(","), lines[0]);
+    assert_eq!(latest.values, rows[119]);
+    // slow loop values were measured at the last refresh.
+    assert_eq!(latest.timestamps[4], (START + 30 * 100) as f64);
+    assert!(latest.status.iter().all(|s| *s == snapshot::Status::Ok));
+
+    fs::remove_file(cfg_file).unwrap();
+    fs::remove_file(filename).unwrap();
+    if fs::metadata(state_dir).is_ok() {
+        fs::remove_dir_all(state_dir).unwrap();
+    }
+}
+
+#[test]
+fn test_stdout_for_sanity() {
+    let cfg_file = "integration_test1.toml";
+    fs::write(
+        cfg_file,
+        "[general]\n\
+        fast_loop=[\"dummy\"]\n\
+        slow_loop=[]\n\
+        filename=\"-\"\n\
+        state_dir=\"integration_test1_state\"\n\n\
+        [prometheus]\n\
+        listen=\"127.0.0.1:0\"\n\n\
+        [dummy]\n\
+        type=\"dummy\"\n\
+        values={ power=42.0 }\n",
+    )
+    .unwrap();
+    let mut child = process::Command::new(env!("CARGO_BIN_EXE_open_green_compute"))
+        .env("OGC_CONFIG", cfg_file)
+        .stdout(process::Stdio::piped())
+        .stderr(process::Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    // the header and the first row arrive right away; no need to wait for the next one.
+    let mut stdout = BufReader::new(child.stdout.take().unwrap());
+    let mut lines = Vec::new();
+    for _ in 0..2 {
+        let mut line = String::new();
+        stdout.read_line(&mut line).unwrap();
+        lines.push(line);
+    }
+    child.kill().unwrap();
+    child.wait().unwrap();
+    let mut stderr = String::new();
+    child
+        .stderr
+        .take()
+        .unwrap()
+        .read_to_string(&mut stderr)
+        .unwrap();
+
+    // only data on stdout; diagnostics go to stderr.
+    assert_eq!(lines[0], "timestamp,dummy_power\n");
+    let row: Vec<f64> = lines[1]
+        .trim_end()
+        .split(',')
+        .map(|v| v.parse().unwrap())
+        .collect();
+    assert_eq!(row[1], 42.0);
+    assert!(stderr.contains("Serving metrics on"));
+    assert!(fs::metadata("-").is_err());
+
+    fs::remove_file(cfg_file).unwrap();
+    if fs::metadata("integration_test1_state").is_ok() {
+        fs::remove_dir_all("integration_test1_state").unwrap();
+    }
+}
+
+#[test]
+fn test_run_loop_with_for_sanity() {
+    let (cfg_file, filename) = ("integration_test2.toml", "integration_test2.csv");
+    fs::write(
+        cfg_file,
+        format!(
+            "[general]\n\
+            fast_loop=[\"meter\", \"dummy\"]\n\
+            slow_loop=[]\n\
+            filename=\"{filename}\"\n\
+            state_dir=\"integration_test2_state\"\n\n\
+            [meter]\n\
+            type=\"meter\"\n\n\
+            [dummy]\n\
+            type=\"dummy\"\n\
+            values={{ power=42.0 }}\n"
+        ),
+    )
+    .unwrap();
+    let cfg = config::load_config(cfg_file);
+
+    let (tx, rx) = mpsc::channel();
+    let clock = ScriptedClock {
+        sim: clock::SimClock::new(START),
+        end: 60.0,
+        script: RefCell::new(Box::new(|_| {})),
+        shutdown: tx,
+    };
+    let collector = snapshot::Collector::new();
+    open_green_compute::run_loop_with(&cfg, vec![meter_type()], &clock, rx, &collector);
+
+    // sensors of the embedder's type sit next to the built-in ones.
+    let content = fs::read_to_string(filename).unwrap();
+    let lines: Vec<&str> = content.lines().collect();
+    assert_eq!(lines[0], "timestamp,meter_power,dummy_power");
+    assert_eq!(lines.len(), 1 + 2);
+    assert_eq!(collector.latest().values[1..], [7.0, 42.0]);
+
+    fs::remove_file(cfg_file).unwrap();
+    fs::remove_file(filename).unwrap();
+    if fs::metadata("integration_test2_state").is_ok() {
+        fs::remove_dir_all("integration_test2_state").unwrap();
+    }
+}